@@ -0,0 +1,318 @@
+// Address based access control for the tcp server. Rules come from a text file, one
+// per line, checked in order until one matches; addresses matching none are allowed:
+//
+//   # only the lab network and one office machine
+//   allow 192.168.10.0/24
+//   allow 10.1.2.3
+//   deny  all
+//
+// The file is re-read whenever it changes, so rules can be edited without a restart.
+use crate::config_file::AclConfig;
+use arc_swap::ArcSwap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::time::SystemTime;
+use tokio::time::{self, Duration};
+use tracing::{info, warn};
+
+// An address range such as 10.0.0.0/8 or fe80::/10.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // v4 clients of a dual-stack listener show up as ::ffff:a.b.c.d
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let (n_bytes, n_bits) = (prefix_len as usize / 8, prefix_len % 8);
+    if net[..n_bytes] != ip[..n_bytes] {
+        return false;
+    }
+    if n_bits == 0 {
+        return true;
+    }
+    let mask = 0xff_u8 << (8 - n_bits);
+    net[n_bytes] & mask == ip[n_bytes] & mask
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    // A bare address is a range of one.
+    fn from_str(text: &str) -> Result<Cidr, String> {
+        let (addr, prefix_len) = match text.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (text, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("bad address in \"{}\"", text))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("bad prefix length in \"{}\"", text))?,
+            None => max_len,
+        };
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Default)]
+pub struct Rules {
+    // 'None' matches every address
+    rules: Vec<(Action, Option<Cidr>)>,
+}
+
+impl Rules {
+    pub fn parse(text: &str) -> Result<Rules, String> {
+        let mut rules = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let action = match words.next() {
+                Some("allow") => Action::Allow,
+                Some("deny") => Action::Deny,
+                _ => return Err(format!("line {}: expected allow or deny", i + 1)),
+            };
+            let range = match (words.next(), words.next()) {
+                (Some("all"), None) => None,
+                (Some(range), None) => Some(
+                    range
+                        .parse()
+                        .map_err(|err| format!("line {}: {}", i + 1, err))?,
+                ),
+                _ => return Err(format!("line {}: expected one address range", i + 1)),
+            };
+            rules.push((action, range));
+        }
+        Ok(Rules { rules })
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        self.rules
+            .iter()
+            .find(|(_, range)| range.is_none_or(|range| range.contains(ip)))
+            .is_none_or(|(action, _)| *action == Action::Allow)
+    }
+}
+
+pub struct Acl {
+    path: PathBuf,
+    rules: ArcSwap<Rules>,
+}
+
+impl Acl {
+    // Read the rules in 'cfg.file' and keep following changes to it every
+    // 'cfg.reload_interval' seconds, for as long as the returned acl is in use.
+    pub fn load(cfg: &AclConfig) -> crate::Result<Arc<Acl>> {
+        let path = PathBuf::from(&cfg.file);
+        let rules = Rules::parse(&std::fs::read_to_string(&path)?)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        info!("{} acl rules from {}", rules.rules.len(), path.display());
+        let acl = Arc::new(Acl {
+            path,
+            rules: ArcSwap::from_pointee(rules),
+        });
+        let modified = modified(&acl.path);
+        tokio::spawn(watch(
+            Arc::downgrade(&acl),
+            modified,
+            Duration::from_secs(cfg.reload_interval.max(1)),
+        ));
+        Ok(acl)
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        self.rules.load().permits(ip)
+    }
+
+    pub fn reload(&self) {
+        let rules = std::fs::read_to_string(&self.path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| Rules::parse(&text));
+        match rules {
+            Ok(rules) => {
+                info!(
+                    "reloaded {} acl rules from {}",
+                    rules.rules.len(),
+                    self.path.display()
+                );
+                self.rules.store(Arc::new(rules));
+            }
+            // a half-saved file shouldn't open or close the server; keep the old rules
+            Err(err) => warn!("keeping old acl rules; {}: {}", self.path.display(), err),
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+async fn watch(acl: Weak<Acl>, mut last_modified: Option<SystemTime>, interval: Duration) {
+    loop {
+        time::sleep(interval).await;
+        let acl = match acl.upgrade() {
+            Some(acl) => acl,
+            None => return,
+        };
+        let modified = modified(&acl.path);
+        if modified != last_modified {
+            last_modified = modified;
+            acl.reload();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(cidr: &str, ip: &str) -> bool {
+        cidr.parse::<Cidr>().unwrap().contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn v4_ranges_match_their_prefix() {
+        assert!(contains("10.0.0.0/8", "10.255.1.2"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        // prefixes that end inside a byte
+        assert!(contains("172.16.0.0/12", "172.31.255.255"));
+        assert!(!contains("172.16.0.0/12", "172.32.0.0"));
+        assert!(contains("192.168.1.2/31", "192.168.1.3"));
+        assert!(!contains("192.168.1.2/31", "192.168.1.4"));
+        assert!(contains("0.0.0.0/0", "203.0.113.9"));
+        // bits past the prefix don't matter
+        assert!(contains("10.1.2.3/8", "10.9.9.9"));
+    }
+
+    #[test]
+    fn bare_addresses_match_only_themselves() {
+        assert!(contains("10.1.2.3", "10.1.2.3"));
+        assert!(!contains("10.1.2.3", "10.1.2.4"));
+        assert!(contains("::1", "::1"));
+        assert!(!contains("::1", "::2"));
+    }
+
+    #[test]
+    fn v6_ranges_match_their_prefix() {
+        assert!(contains("fe80::/10", "fe80::1"));
+        assert!(contains("fe80::/10", "febf::1"));
+        assert!(!contains("fe80::/10", "fec0::1"));
+        assert!(contains("2001:db8::/127", "2001:db8::1"));
+        assert!(!contains("2001:db8::/127", "2001:db8::2"));
+    }
+
+    #[test]
+    fn mapped_v4_matches_v4_ranges() {
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "::ffff:11.1.2.3"));
+        // but the families don't mix otherwise
+        assert!(!contains("0.0.0.0/0", "2001:db8::1"));
+        assert!(!contains("::/0", "10.1.2.3"));
+    }
+
+    #[test]
+    fn bad_ranges_are_refused() {
+        for text in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "10.0.0.0/x",
+            "",
+        ] {
+            assert!(text.parse::<Cidr>().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = Rules::parse(
+            "# lab network, but not its printer\n\
+             deny 192.168.10.99\n\
+             allow 192.168.10.0/24  # the lab\n\
+             \n\
+             deny all\n",
+        )
+        .unwrap();
+        let permits = |ip: &str| rules.permits(ip.parse().unwrap());
+        assert!(permits("192.168.10.1"));
+        assert!(!permits("192.168.10.99"));
+        assert!(!permits("192.168.11.1"));
+    }
+
+    #[test]
+    fn unmatched_addresses_are_allowed() {
+        let rules = Rules::parse("deny 10.0.0.0/8").unwrap();
+        assert!(!rules.permits("10.0.0.1".parse().unwrap()));
+        assert!(rules.permits("192.168.0.1".parse().unwrap()));
+        assert!(Rules::default().permits("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn bad_lines_name_their_number() {
+        for (text, line) in [
+            ("allow 10.0.0.0/8\npermit 10.0.0.1", 2),
+            ("deny", 1),
+            ("\nallow 10.0.0.1 10.0.0.2", 2),
+            ("allow 10.0.0.0/8\n\ndeny 300.0.0.1", 3),
+        ] {
+            let err = Rules::parse(text).unwrap_err();
+            assert!(err.starts_with(&format!("line {}:", line)), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_old_rules_on_errors() {
+        let path = std::env::temp_dir().join(format!("mic2net-acl-{}", std::process::id()));
+        std::fs::write(&path, "deny 10.0.0.0/8\n").unwrap();
+        let cfg = AclConfig {
+            file: path.display().to_string(),
+            reload_interval: 3600,
+        };
+        let acl = Acl::load(&cfg).unwrap();
+        let ip = "10.0.0.1".parse().unwrap();
+        assert!(!acl.permits(ip));
+
+        std::fs::write(&path, "allow 10.0.0.0/8\ndeny all\n").unwrap();
+        acl.reload();
+        assert!(acl.permits(ip));
+
+        std::fs::write(&path, "allow 10.0.0.0/").unwrap();
+        acl.reload();
+        assert!(acl.permits(ip));
+        assert!(!acl.permits("192.168.0.1".parse().unwrap()));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,357 @@
+// Small HTTP/JSON surface for managing a running server:
+//   GET  /clients                  connected tcp clients and their statistics, with
+//                                  what they last reported to have received
+//   GET  /udp_clients              the same for the clients of the udp server
+//   POST /kick?peer=<ip[:port]>    say goodbye to matching clients of every server and
+//                                  disconnect them
+//   GET  /max_clients[?output=<name>]
+//                                  current client limit of the tcp server, or of the
+//                                  udp, uds or tcp:<listen_port> one
+//   POST /max_clients?value=<n>[&output=<name>]
+//                                  change it
+//   GET  /bandwidth[?output=<name>]
+//                                  bytes per second each client of the tcp server, or
+//                                  of the one named as for max_clients, may be sent, 0
+//                                  for no limit
+//   POST /bandwidth?value=<bytes/s>[&output=<name>]
+//                                  change it, for the clients without a limit of their own
+//   POST /bandwidth?peer=<ip[:port]>&value=<bytes/s>|default[&output=<name>]
+//                                  give matching clients a limit of their own, or the
+//                                  server's again
+//   GET  /mute                     whether silence is sent instead of the microphones
+//   POST /mute?value=on|off        change it
+//   GET  /levels                   rms and peak dBFS per channel of the latest packet
+//   GET  /outputs                  gain (dB) and mute of every stream output
+//   POST /gain?output=<name>&value=<dB>
+//                                  change the gain of one output, e.g. output=tcp
+//   POST /mute?output=<name>&value=on|off
+//                                  mute one output only
+//   GET  /dynamics                 compressor and limiter settings
+//   POST /dynamics?enable=on|off&threshold=<dBFS>&ratio=<n>&attack=<ms>&release=<ms>
+//                 &makeup=<dB>&ceiling=<dBFS>
+//                                  change any of them; all or none are applied
+//   POST /reload                   re-read the config file like SIGHUP does; lists
+//                                  what was applied
+// Requests need 'Authorization: Bearer <token>' when a token is configured.
+use crate::client_stats::ClientRegistry;
+use crate::config_file::Config;
+use crate::dsp::dynamics::DYNAMICS;
+use crate::dsp::level::{OutputLevel, OUTPUT_LEVELS};
+use crate::dsp::meter::Levels;
+use crate::dsp::CONTROLS;
+use crate::http::{read_request, write_response, Request};
+use crate::reload::RELOAD;
+use crate::tcp_server::constant_time_eq;
+use serde_json::json;
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::time::Duration;
+use tracing::{error, info};
+
+struct Response {
+    status: &'static str,
+    body: serde_json::Value,
+}
+
+impl Response {
+    fn ok(body: serde_json::Value) -> Response {
+        Response {
+            status: "200 OK",
+            body,
+        }
+    }
+
+    fn error(status: &'static str, message: &str) -> Response {
+        Response {
+            status,
+            body: json!({ "error": message }),
+        }
+    }
+}
+
+async fn serve_request(
+    mut socket: TcpStream,
+    token: &str,
+    clients: &ClientRegistry,
+    udp_clients: &ClientRegistry,
+    levels: &watch::Receiver<Levels>,
+) -> crate::Result<()> {
+    let request = match read_request(&mut socket).await? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    let response = if authorized(&request, token) {
+        route(&request, clients, udp_clients, levels)
+    } else {
+        Response::error("401 Unauthorized", "missing or wrong token")
+    };
+    let body = serde_json::to_vec(&response.body)?;
+    write_response(&mut socket, response.status, "application/json", &body).await
+}
+
+fn authorized(request: &Request, token: &str) -> bool {
+    token.is_empty()
+        || request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|sent| constant_time_eq(sent.as_bytes(), token.as_bytes()))
+}
+
+fn route(
+    request: &Request,
+    clients: &ClientRegistry,
+    udp_clients: &ClientRegistry,
+    levels: &watch::Receiver<Levels>,
+) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/clients") => Response::ok(json!(clients.snapshot())),
+        ("GET", "/udp_clients") => Response::ok(json!(udp_clients.snapshot())),
+        ("POST", "/kick") => match request.query_param("peer") {
+            Some(peer) => {
+                let kicked: usize = RELOAD
+                    .clients()
+                    .iter()
+                    .map(|(_, clients)| clients.kick(peer))
+                    .sum();
+                info!(peer, kicked, "admin kick");
+                Response::ok(json!({ "kicked": kicked }))
+            }
+            None => Response::error("400 Bad Request", "expected ?peer=<address>"),
+        },
+        ("GET", "/max_clients") => match limited(request) {
+            Some(clients) => Response::ok(json!({ "max_clients": clients.max_clients() })),
+            None => Response::error("404 Not Found", "no such output"),
+        },
+        ("POST", "/max_clients") => match limited(request) {
+            Some(clients) => set_max_clients(request, &clients),
+            None => Response::error("404 Not Found", "no such output"),
+        },
+        ("GET", "/bandwidth") => match limited(request) {
+            Some(clients) => Response::ok(json!({ "bandwidth_limit": clients.bandwidth_limit() })),
+            None => Response::error("404 Not Found", "no such output"),
+        },
+        ("POST", "/bandwidth") => match limited(request) {
+            Some(clients) => set_bandwidth(request, &clients),
+            None => Response::error("404 Not Found", "no such output"),
+        },
+        ("GET", "/levels") => Response::ok(json!(*levels.borrow())),
+        ("GET", "/outputs") => Response::ok(json!(OUTPUT_LEVELS.snapshot())),
+        ("POST", "/gain") => set_output_gain(request),
+        ("POST", "/mute") if request.query_param("output").is_some() => mute_output(request),
+        ("GET", "/mute") => Response::ok(json!({ "mute": CONTROLS.mute.load(Ordering::Relaxed) })),
+        ("POST", "/mute") => match request.query_param("value") {
+            Some(value) => match CONTROLS.apply(&format!("mute {}", value)) {
+                Ok(()) => {
+                    info!("admin mute {}", value);
+                    Response::ok(json!({ "mute": CONTROLS.mute.load(Ordering::Relaxed) }))
+                }
+                Err(err) => Response::error("400 Bad Request", &err),
+            },
+            None => Response::error("400 Bad Request", "expected ?value=on|off"),
+        },
+        ("GET", "/dynamics") => Response::ok(json!(DYNAMICS.state())),
+        ("POST", "/dynamics") => set_dynamics(request),
+        ("POST", "/reload") => match RELOAD.reload() {
+            Ok(applied) => {
+                info!("admin reloaded the config: {:?}", applied);
+                Response::ok(json!({ "applied": applied }))
+            }
+            Err(err) => Response::error("500 Internal Server Error", &err.to_string()),
+        },
+        _ => Response::error("404 Not Found", "not found"),
+    }
+}
+
+// The clients whose limits '?output=' names, the tcp server's by default.
+fn limited(request: &Request) -> Option<Arc<ClientRegistry>> {
+    let output = request.query_param("output").unwrap_or("tcp");
+    RELOAD
+        .clients()
+        .into_iter()
+        .find_map(|(name, clients)| (name == output).then_some(clients))
+}
+
+fn set_max_clients(request: &Request, clients: &ClientRegistry) -> Response {
+    match request.query_param("value").map(str::parse::<u16>) {
+        Some(Ok(max_clients)) => {
+            clients.set_max_clients(max_clients.into());
+            info!(
+                output = request.query_param("output").unwrap_or("tcp"),
+                max_clients, "admin changed max_clients"
+            );
+            Response::ok(json!({ "max_clients": max_clients }))
+        }
+        _ => Response::error("400 Bad Request", "expected ?value=<0-65535>"),
+    }
+}
+
+fn set_bandwidth(request: &Request, clients: &ClientRegistry) -> Response {
+    let value = request.query_param("value");
+    match request.query_param("peer") {
+        Some(peer) => {
+            let limit = match value.map(|value| (value, value.parse())) {
+                Some(("default", _)) => None,
+                Some((_, Ok(limit))) => Some(limit),
+                _ => {
+                    return Response::error("400 Bad Request", "expected ?value=<bytes/s>|default")
+                }
+            };
+            let limited = clients.limit_bandwidth(peer, limit);
+            info!(
+                output = request.query_param("output").unwrap_or("tcp"),
+                peer,
+                ?limit,
+                limited,
+                "admin changed client bandwidth limit"
+            );
+            Response::ok(json!({ "limited": limited }))
+        }
+        None => match value.map(str::parse) {
+            Some(Ok(limit)) => {
+                clients.set_bandwidth_limit(limit);
+                info!(
+                    output = request.query_param("output").unwrap_or("tcp"),
+                    limit, "admin changed bandwidth_limit"
+                );
+                Response::ok(json!({ "bandwidth_limit": limit }))
+            }
+            _ => Response::error("400 Bad Request", "expected ?value=<bytes/s>"),
+        },
+    }
+}
+
+fn set_dynamics(request: &Request) -> Response {
+    let mut enable = None;
+    let mut params = Vec::new();
+    for (key, value) in request
+        .query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+    {
+        match (key, value) {
+            ("enable", "on") => enable = Some(true),
+            ("enable", "off") => enable = Some(false),
+            ("enable", _) => return Response::error("400 Bad Request", "expected enable=on|off"),
+            _ => {
+                let value = match value.parse() {
+                    Ok(value) => value,
+                    Err(_) => {
+                        return Response::error(
+                            "400 Bad Request",
+                            &format!("bad {} {}", key, value),
+                        )
+                    }
+                };
+                if let Err(err) = DYNAMICS.check(key, value) {
+                    return Response::error("400 Bad Request", &err);
+                }
+                params.push((key, value));
+            }
+        }
+    }
+    if let Some(enable) = enable {
+        DYNAMICS.set_enabled(enable);
+    }
+    for (key, value) in params {
+        // checked above
+        _ = DYNAMICS.set(key, value);
+    }
+    let state = DYNAMICS.state();
+    info!(?state, "admin changed dynamics");
+    Response::ok(json!(state))
+}
+
+fn set_output_gain(request: &Request) -> Response {
+    let (name, level) = match output_level(request) {
+        Ok(output) => output,
+        Err(response) => return response,
+    };
+    let gain = match request.query_param("value").map(str::parse) {
+        Some(Ok(gain)) => gain,
+        _ => return Response::error("400 Bad Request", "expected ?value=<dB>"),
+    };
+    match level.set_gain_db(gain) {
+        Ok(()) => {
+            info!(output = name, gain, "admin changed output gain");
+            Response::ok(json!({ name: level.state() }))
+        }
+        Err(err) => Response::error("400 Bad Request", &err),
+    }
+}
+
+fn mute_output(request: &Request) -> Response {
+    let (name, level) = match output_level(request) {
+        Ok(output) => output,
+        Err(response) => return response,
+    };
+    let mute = match request.query_param("value") {
+        Some("on") => true,
+        Some("off") => false,
+        _ => return Response::error("400 Bad Request", "expected ?value=on|off"),
+    };
+    level.set_muted(mute);
+    info!(output = name, mute, "admin muted output");
+    Response::ok(json!({ name: level.state() }))
+}
+
+// The level controls of the output named by '?output='.
+fn output_level(request: &Request) -> Result<(&str, Arc<OutputLevel>), Response> {
+    let name = request
+        .query_param("output")
+        .ok_or_else(|| Response::error("400 Bad Request", "expected ?output=<name>"))?;
+    match OUTPUT_LEVELS.get(name) {
+        Some(level) => Ok((name, level)),
+        None => Err(Response::error("404 Not Found", "no such output")),
+    }
+}
+
+// Serve the admin api for the tcp server's 'clients', the udp server's 'udp_clients' and
+// the capture 'levels'; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown'
+// argument.
+pub async fn start_admin_server(
+    cfg: Arc<Config>,
+    clients: Arc<ClientRegistry>,
+    udp_clients: Arc<ClientRegistry>,
+    levels: watch::Receiver<Levels>,
+    shutdown: impl Future,
+) {
+    let addr = format!("{}:{}", cfg.admin.bind_address, cfg.admin.listen_port);
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("failed to start admin api: {}", err);
+            return;
+        }
+    };
+    info!("admin api on http://{}/", addr);
+    let token: Arc<str> = cfg.admin.token.as_str().into();
+
+    let accept_loop = async {
+        loop {
+            match listener.accept().await {
+                Ok((socket, _)) => {
+                    let (token, clients) = (token.clone(), clients.clone());
+                    let (udp_clients, levels) = (udp_clients.clone(), levels.clone());
+                    tokio::spawn(async move {
+                        let served =
+                            serve_request(socket, &token, &clients, &udp_clients, &levels).await;
+                        if let Err(err) = served {
+                            error!("admin request failed: {}", err);
+                        }
+                    });
+                }
+                Err(err) => {
+                    error!("admin accept failed: {}", err);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    };
+    tokio::select! {
+        _ = accept_loop => {}
+        _ = shutdown => {}
+    }
+}
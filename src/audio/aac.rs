@@ -0,0 +1,101 @@
+use crate::tcp_client::AudioPacket;
+use fdk_aac::enc::{AudioObjectType, BitRate, ChannelMode, Encoder, EncoderParams, Transport};
+
+// AAC-LC always codes 1024 samples per channel and frame
+const FRAME_LEN: usize = 1024;
+// constant bitrate per channel; plenty for speech and fine for music at 48 kHz
+const BITRATE_PER_CHANNEL: u32 = 64000;
+// largest access unit the encoder produces for one frame of 2 channels
+const MAX_AAC_PACKET: usize = 1536 * 2;
+
+// Cuts the stream into frames of its first one or two channels and encodes them with
+// AAC-LC through fdk-aac, the counterpart of 'OpusFramer'. With 'Transport::Adts' every
+// frame carries its own 7-byte header, so a receiver can start decoding anywhere;
+// raw frames need 'audio_specific_config' out of band, as mp4 does.
+pub struct AacFramer {
+    encoder: Encoder,
+    sample_rate: usize,
+    n_ch: usize,
+    // interleaved samples waiting for a full frame
+    pending: Vec<i16>,
+}
+
+impl AacFramer {
+    pub fn new(sample_rate: usize, n_ch: usize, transport: Transport) -> crate::Result<AacFramer> {
+        let channels = match n_ch {
+            1 => ChannelMode::Mono,
+            2 => ChannelMode::Stereo,
+            _ => return Err(crate::Error::Codec("aac supports 1 or 2 channels".into())),
+        };
+        let encoder = Encoder::new(EncoderParams {
+            bit_rate: BitRate::Cbr(BITRATE_PER_CHANNEL * n_ch as u32),
+            sample_rate: sample_rate as u32,
+            transport,
+            channels,
+            audio_object_type: AudioObjectType::Mpeg4LowComplexity,
+        })
+        .map_err(aac_error)?;
+        Ok(AacFramer {
+            encoder,
+            sample_rate,
+            n_ch,
+            pending: Vec::with_capacity(FRAME_LEN * n_ch * 2),
+        })
+    }
+
+    pub fn n_channel(&self) -> usize {
+        self.n_ch
+    }
+
+    pub fn frame_len(&self) -> usize {
+        FRAME_LEN
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    // bits per second
+    pub fn bitrate(&self) -> u32 {
+        BITRATE_PER_CHANNEL * self.n_ch as u32
+    }
+
+    // AudioSpecificConfig (ISO/IEC 14496-3), the decoder setup of raw frames.
+    pub fn audio_specific_config(&self) -> crate::Result<Vec<u8>> {
+        let info = self.encoder.info().map_err(aac_error)?;
+        Ok(info.confBuf[..info.confSize as usize].to_vec())
+    }
+
+    // A mono packet fills both channels of a stereo encoder.
+    pub fn push(&mut self, packet: &AudioPacket) {
+        let len = packet.samples.len() / packet.n_ch;
+        for i in 0..len {
+            for ch in 0..self.n_ch {
+                self.pending
+                    .push(packet.channel(ch.min(packet.n_ch - 1))[i]);
+            }
+        }
+    }
+
+    // The next encoded frame, once enough samples were pushed.
+    pub fn next_frame(&mut self) -> crate::Result<Option<Vec<u8>>> {
+        let frame_len = FRAME_LEN * self.n_ch;
+        let mut encoded = [0_u8; MAX_AAC_PACKET];
+        while self.pending.len() >= frame_len {
+            let info = self
+                .encoder
+                .encode(&self.pending[..frame_len], &mut encoded)
+                .map_err(aac_error)?;
+            self.pending.drain(..info.input_consumed);
+            // the encoder may hold back output while it fills its lookahead
+            if info.output_size > 0 {
+                return Ok(Some(encoded[..info.output_size].to_vec()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn aac_error(err: fdk_aac::enc::EncoderError) -> crate::Error {
+    crate::Error::Codec(format!("aac: {}", err))
+}
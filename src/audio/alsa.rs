@@ -0,0 +1,195 @@
+use crate::audio::convert::deinterleave;
+use crate::audio::format::Quantizer;
+use crate::audio::pool::BufferPool;
+use crate::config_file::Config;
+use crate::metrics::{Metrics, METRICS};
+use crate::packet::Packetizer;
+use crate::PACKET_N_SAMPLE;
+use ::alsa::pcm::{Access, Format, Frames, HwParams, PCM};
+use ::alsa::{Direction, ValueOr};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+// Packets waiting between the reading thread and the packetizer task.
+const CAPTURE_QUEUE_LEN: usize = 16;
+
+// Open 'mic.device_name' ("hw:1,0", "plughw:CARD=Device", "default", ...) with exactly
+// the period size and count of [alsa]; fails rather than settle for others, since what
+// they are is the point. Devices that can't do s16 frames are read as s32.
+fn open_pcm(cfg: &Config) -> crate::Result<(PCM, Format, Frames)> {
+    let pcm = PCM::new(&cfg.mic.device_name, Direction::Capture, false)?;
+    let format = {
+        let hw_params = HwParams::any(&pcm)?;
+        hw_params.set_access(Access::RWInterleaved)?;
+        hw_params.set_channels(cfg.mic.n_channel as u32)?;
+        hw_params.set_rate(cfg.mic.sample_rate as u32, ValueOr::Nearest)?;
+        let format = if hw_params.set_format(Format::s16()).is_ok() {
+            Format::s16()
+        } else {
+            hw_params.set_format(Format::s32())?;
+            Format::s32()
+        };
+        hw_params.set_period_size(cfg.alsa.period_size as Frames, ValueOr::Nearest)?;
+        hw_params.set_periods(cfg.alsa.n_periods as u32, ValueOr::Nearest)?;
+        pcm.hw_params(&hw_params)?;
+        format
+    };
+
+    let hw_params = pcm.hw_params_current()?;
+    let rate = hw_params.get_rate()? as usize;
+    if rate != cfg.mic.sample_rate {
+        return Err(crate::Error::Capture(format!(
+            "{} can't record at {} Hz",
+            cfg.mic.device_name, cfg.mic.sample_rate
+        )));
+    }
+    let period_size = hw_params.get_period_size()?;
+    let n_periods = hw_params.get_periods()?;
+    if period_size as usize != cfg.alsa.period_size || n_periods as usize != cfg.alsa.n_periods {
+        return Err(crate::Error::Capture(format!(
+            "{} offers {} periods of {} frames, not {} of {}",
+            cfg.mic.device_name, n_periods, period_size, cfg.alsa.n_periods, cfg.alsa.period_size
+        )));
+    }
+    let buffer_size = hw_params.get_buffer_size()?;
+    drop(hw_params);
+
+    // wake up for every period, and start with the first
+    let sw_params = pcm.sw_params_current()?;
+    sw_params.set_avail_min(period_size)?;
+    sw_params.set_start_threshold(period_size)?;
+    pcm.sw_params(&sw_params)?;
+    drop(sw_params);
+
+    info!(
+        "capturing from alsa \"{}\": {} channels, {} Hz, {:?}, {} periods of {} frames ({:.1} ms buffered)",
+        cfg.mic.device_name,
+        cfg.mic.n_channel,
+        rate,
+        format,
+        n_periods,
+        period_size,
+        buffer_size as f64 * 1000.0 / rate as f64
+    );
+    Ok((pcm, format, period_size))
+}
+
+// Read periods until 'stop' is set, sending planar packets of 'PACKET_N_SAMPLE' samples
+// per channel to 'tx'. Overruns are counted and recovered from.
+fn read_periods(
+    cfg: &Config,
+    pool: Arc<BufferPool>,
+    tx: mpsc::Sender<Vec<i16>>,
+    stop: &AtomicBool,
+) -> crate::Result<()> {
+    let (pcm, format, period_size) = open_pcm(cfg)?;
+    let n_ch = cfg.mic.n_channel;
+    let mut interleaved = vec![0_i16; period_size as usize * n_ch];
+    let mut wide = vec![
+        0_i32;
+        if format == Format::s32() {
+            interleaved.len()
+        } else {
+            0
+        }
+    ];
+    let mut quantizer = cfg.mic.dither.then(|| Quantizer::new(true));
+    let mut planar = pool.take(n_ch * PACKET_N_SAMPLE);
+    let mut n_frame = 0;
+    while !stop.load(Ordering::Relaxed) {
+        let read = if format == Format::s32() {
+            let read = pcm.io_i32()?.readi(&mut wide);
+            for (narrow, &sample) in interleaved.iter_mut().zip(&wide) {
+                *narrow = match &mut quantizer {
+                    Some(quantizer) => quantizer.quantize(sample as f32 / 2_147_483_648.0),
+                    None => (sample >> 16) as i16,
+                };
+            }
+            read
+        } else {
+            pcm.io_i16()?.readi(&mut interleaved)
+        };
+        let n_frames = match read {
+            Ok(n_frames) => n_frames,
+            Err(err) => {
+                warn!("alsa: {}, recovering", err);
+                Metrics::inc(&METRICS.xruns);
+                pcm.try_recover(err, true)?;
+                continue;
+            }
+        };
+
+        let mut frames = &interleaved[..n_frames * n_ch];
+        while !frames.is_empty() {
+            let len = (PACKET_N_SAMPLE - n_frame).min(frames.len() / n_ch);
+            let (run, rest) = frames.split_at(len * n_ch);
+            deinterleave(run, n_ch, &mut planar[n_frame..], PACKET_N_SAMPLE);
+            frames = rest;
+            n_frame += len;
+            if n_frame == PACKET_N_SAMPLE {
+                n_frame = 0;
+                let packet = std::mem::take(&mut planar);
+                match tx.try_send(packet) {
+                    Ok(()) => planar = pool.take(n_ch * PACKET_N_SAMPLE),
+                    // keep filling the packet that didn't fit
+                    Err(err) => {
+                        planar = err.into_inner();
+                        Metrics::inc(&METRICS.capture_overruns);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Capture 'mic.device_name' straight through alsa, bypassing cpal, with the period
+// size and count of [alsa] pinned, and publish it until 'shutdown' completes. Blocking
+// reads of one period each run on a thread of their own.
+pub async fn start_alsa_capture(
+    cfg: Arc<Config>,
+    mut packetizer: Packetizer,
+    shutdown: impl Future,
+) -> crate::Result<()> {
+    let n_ch = cfg.mic.n_channel;
+    let pool = BufferPool::new(CAPTURE_QUEUE_LEN + 1, n_ch * PACKET_N_SAMPLE);
+    let (tx, mut packets) = mpsc::channel(CAPTURE_QUEUE_LEN);
+    let stop = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let (cfg, pool, stop) = (cfg.clone(), pool.clone(), stop.clone());
+        thread::Builder::new()
+            .name("alsa".to_string())
+            .spawn(move || read_periods(&cfg, pool, tx, &stop))?
+    };
+
+    let mut audio_data = Vec::with_capacity(n_ch * PACKET_N_SAMPLE * 2);
+    let publish = async {
+        while let Some(packet) = packets.recv().await {
+            audio_data.clear();
+            audio_data.extend(packet.iter().flat_map(|sample| sample.to_ne_bytes()));
+            pool.put(packet);
+            packetizer.publish(&audio_data);
+        }
+    };
+    let stopped = tokio::select! {
+        _ = publish => false,
+        _ = shutdown => {
+            info!("shutting down capture");
+            true
+        }
+    };
+    // the reader notices within a period
+    stop.store(true, Ordering::Relaxed);
+    tokio::task::spawn_blocking(move || reader.join())
+        .await?
+        .map_err(|_| crate::Error::Capture("alsa thread panicked".into()))??;
+    if stopped {
+        Ok(())
+    } else {
+        Err(crate::Error::Capture("alsa capture ended".into()))
+    }
+}
@@ -0,0 +1,458 @@
+use crate::audio::convert::{deinterleave, f32_to_i16};
+use crate::audio::format::Quantizer;
+use crate::audio::mixer::{mixer_inputs, Mixer, MAX_QUEUED_PACKETS};
+use crate::audio::pool::BufferPool;
+use crate::audio::spsc::{self, Consumer, Producer};
+use crate::audio::CaptureSource;
+use crate::config_file::{Config, InputConfig};
+use crate::metrics::{Metrics, METRICS};
+use crate::source::{AudioSource, FrameFuture, SourceFormat};
+use crate::PACKET_N_SAMPLE;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{
+    BufferSize, Device, ErrorKind, FromSample, Sample, SampleFormat, SizedSample, Stream,
+    StreamConfig,
+};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+// Packets the ring of an input holds between the audio callback and the packetizer task.
+const CAPTURE_QUEUE_LEN: usize = 16;
+// the callbacks aren't waited for but polled, this often per packet
+const POLLS_PER_PACKET: u32 = 4;
+// how often a failed device is looked for again
+const REOPEN_INTERVAL: Duration = Duration::from_secs(1);
+
+// Input devices of the default host, in the order used for index selection.
+pub fn list_input_devices() -> crate::Result<Vec<String>> {
+    let host = cpal::default_host();
+    Ok(host
+        .input_devices()?
+        .map(|device| device.to_string())
+        .collect())
+}
+
+// 'selector' is "default", an index into 'list_input_devices', or part of a device name.
+pub fn find_input_device(selector: &str) -> crate::Result<Device> {
+    let host = cpal::default_host();
+    if selector.eq_ignore_ascii_case("default") {
+        return host
+            .default_input_device()
+            .ok_or_else(|| crate::Error::Capture("no default input device".into()));
+    }
+    let mut devices = host.input_devices()?;
+    let found = match selector.parse::<usize>() {
+        Ok(index) => devices.nth(index),
+        Err(_) => devices.find(|device| device.to_string().contains(selector)),
+    };
+    found.ok_or_else(|| crate::Error::Capture(format!("no input device matching \"{}\"", selector)))
+}
+
+// The device recording what the system plays, see 'CaptureSource::Loopback', and the
+// sample format to open it with. 'selector' is "default" or part of a device name.
+#[cfg(windows)]
+pub fn find_loopback_device(selector: &str) -> crate::Result<(Device, SampleFormat)> {
+    // wasapi records an output device in loopback mode when it is opened for input
+    let host = cpal::default_host();
+    let device = if selector.eq_ignore_ascii_case("default") {
+        host.default_output_device()
+            .ok_or_else(|| crate::Error::Capture("no default output device".into()))?
+    } else {
+        host.output_devices()?
+            .find(|device| device.to_string().contains(selector))
+            .ok_or_else(|| {
+                crate::Error::Capture(format!("no output device matching \"{}\"", selector))
+            })?
+    };
+    let sample_format = device.default_output_config()?.sample_format();
+    Ok((device, sample_format))
+}
+
+#[cfg(target_os = "linux")]
+pub fn find_loopback_device(_selector: &str) -> crate::Result<(Device, SampleFormat)> {
+    // the alsa pulse plugin opens the source libpulse finds in PULSE_SOURCE, which
+    // 'select_loopback_source' set before the runtime started
+    let source = std::env::var("PULSE_SOURCE").unwrap_or_default();
+    info!("recording pulseaudio source {}", source);
+    let device = find_input_device("pulse").or_else(|_| find_input_device("default"))?;
+    let sample_format = device.default_input_config()?.sample_format();
+    Ok((device, sample_format))
+}
+
+// Point libpulse at the monitor source the first loopback input of 'mic' records, if
+// it has one. The environment is only safe to change while the process has a single
+// thread, so 'main' calls this before the runtime starts. It holds for the whole process,
+// so microphones mixed with the loopback need a hw: device name.
+#[cfg(target_os = "linux")]
+pub fn select_loopback_source(mic: &crate::config_file::MicConfig) {
+    if mic.backend != crate::audio::CaptureBackend::Cpal {
+        return;
+    }
+    let loopback = mixer_inputs(mic)
+        .into_iter()
+        .find(|input| input.source == CaptureSource::Loopback);
+    if let Some(input) = loopback {
+        let source = if input.name.eq_ignore_ascii_case("default") {
+            "@DEFAULT_MONITOR@".to_string()
+        } else if input.name.ends_with(".monitor") {
+            input.name
+        } else {
+            format!("{}.monitor", input.name)
+        };
+        std::env::set_var("PULSE_SOURCE", source);
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+pub fn find_loopback_device(_selector: &str) -> crate::Result<(Device, SampleFormat)> {
+    Err(crate::Error::Capture(
+        "loopback capture needs wasapi (windows) or pulseaudio (linux)".into(),
+    ))
+}
+
+// The audio callback's end of an input: converts each buffer to i16 and writes it to
+// the input's ring, whole or, when the ring is full, not at all.
+struct InputWriter {
+    n_ch: usize,
+    // for devices with wider samples when 'mic.dither' is on
+    quantizer: Option<Quantizer>,
+    ring: Producer<i16>,
+}
+
+impl InputWriter {
+    fn push<T>(&mut self, data: &[T])
+    where
+        T: ToI16,
+        f32: FromSample<T>,
+    {
+        if self.n_ch == 0 {
+            return;
+        }
+        let data = &data[..data.len() / self.n_ch * self.n_ch];
+        let quantizer = &mut self.quantizer;
+        let written = self.ring.write(data.len(), |first, second| {
+            let (data_first, data_second) = data.split_at(first.len());
+            for (dst, src) in [(first, data_first), (second, data_second)] {
+                match quantizer {
+                    Some(quantizer) => {
+                        for (dst, sample) in dst.iter_mut().zip(src) {
+                            *dst = quantizer.quantize(sample.to_sample::<f32>());
+                        }
+                    }
+                    None => T::to_i16(src, dst),
+                }
+            }
+        });
+        if !written {
+            Metrics::inc(&METRICS.capture_overruns);
+        }
+    }
+}
+
+// The task's end of an input: collects what the callback wrote into planar packets of
+// 'PACKET_N_SAMPLE' i16 samples per channel, the layout the jack path produces.
+struct InputReader {
+    n_ch: usize,
+    ring: Consumer<i16>,
+    n_frame: usize,
+    planar: Vec<i16>,
+}
+
+impl InputReader {
+    // The next whole packet, once the ring has the rest of it.
+    fn next_packet(&mut self, pool: &BufferPool) -> Option<Vec<i16>> {
+        while self.n_frame < PACKET_N_SAMPLE {
+            let len = (PACKET_N_SAMPLE - self.n_frame).min(self.ring.len() / self.n_ch);
+            if len == 0 {
+                return None;
+            }
+            let (n_ch, n_frame, planar) = (self.n_ch, self.n_frame, &mut self.planar);
+            // the ring holds whole frames and wraps around between them
+            self.ring.read(len * n_ch, |first, second| {
+                let split = n_frame + first.len() / n_ch;
+                deinterleave(first, n_ch, &mut planar[n_frame..], PACKET_N_SAMPLE);
+                deinterleave(second, n_ch, &mut planar[split..], PACKET_N_SAMPLE);
+            });
+            self.n_frame += len;
+        }
+        self.n_frame = 0;
+        let next = pool.take(self.n_ch * PACKET_N_SAMPLE);
+        Some(std::mem::replace(&mut self.planar, next))
+    }
+}
+
+// Callback samples to i16, a run at a time, for the sample formats 'open_input' takes.
+trait ToI16: SizedSample {
+    fn to_i16(src: &[Self], dst: &mut [i16]);
+}
+
+impl ToI16 for f32 {
+    fn to_i16(src: &[f32], dst: &mut [i16]) {
+        f32_to_i16(src, dst);
+    }
+}
+
+impl ToI16 for i16 {
+    fn to_i16(src: &[i16], dst: &mut [i16]) {
+        dst.copy_from_slice(src);
+    }
+}
+
+impl ToI16 for i32 {
+    fn to_i16(src: &[i32], dst: &mut [i16]) {
+        for (dst, sample) in dst.iter_mut().zip(src) {
+            *dst = sample.to_sample();
+        }
+    }
+}
+
+impl ToI16 for u16 {
+    fn to_i16(src: &[u16], dst: &mut [i16]) {
+        for (dst, sample) in dst.iter_mut().zip(src) {
+            *dst = sample.to_sample();
+        }
+    }
+}
+
+// Errors that leave the stream running are only logged; any other one is reported on
+// 'lost', e.g. when the device was unplugged.
+fn build_stream<T>(
+    device: &Device,
+    config: StreamConfig,
+    mut writer: InputWriter,
+    lost: mpsc::UnboundedSender<()>,
+) -> crate::Result<Stream>
+where
+    T: ToI16,
+    f32: FromSample<T>,
+{
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| writer.push(data),
+        move |err| match err.kind() {
+            ErrorKind::Xrun | ErrorKind::DeviceChanged | ErrorKind::RealtimeDenied => {
+                warn!("capture stream: {}", err)
+            }
+            _ => {
+                error!("capture stream error: {}", err);
+                let _ = lost.send(());
+            }
+        },
+        None,
+    )?;
+    Ok(stream)
+}
+
+// Open one input and start streaming it into its ring, which 'InputReader' reads.
+fn open_input(
+    input: &InputConfig,
+    sample_rate: usize,
+    dither: bool,
+    pool: &BufferPool,
+    lost: mpsc::UnboundedSender<()>,
+) -> crate::Result<(Stream, InputReader)> {
+    let (device, sample_format) = match input.source {
+        CaptureSource::Microphone => {
+            let device = find_input_device(&input.name)?;
+            let sample_format = device.default_input_config()?.sample_format();
+            (device, sample_format)
+        }
+        CaptureSource::Loopback => find_loopback_device(&input.name)?,
+    };
+    let config = StreamConfig {
+        channels: input.n_channel as u16,
+        sample_rate: sample_rate as u32,
+        buffer_size: BufferSize::Default,
+    };
+    info!(
+        "capturing from \"{}\": {} channels, {} Hz, {:?}",
+        device, config.channels, config.sample_rate, sample_format
+    );
+
+    let (producer, consumer) = spsc::channel(CAPTURE_QUEUE_LEN * input.n_channel * PACKET_N_SAMPLE);
+    let wide = matches!(sample_format, SampleFormat::F32 | SampleFormat::I32);
+    let writer = InputWriter {
+        n_ch: input.n_channel,
+        quantizer: (dither && wide).then(|| Quantizer::new(true)),
+        ring: producer,
+    };
+    let stream = match sample_format {
+        SampleFormat::F32 => build_stream::<f32>(&device, config, writer, lost)?,
+        SampleFormat::I16 => build_stream::<i16>(&device, config, writer, lost)?,
+        SampleFormat::I32 => build_stream::<i32>(&device, config, writer, lost)?,
+        SampleFormat::U16 => build_stream::<u16>(&device, config, writer, lost)?,
+        other => {
+            return Err(crate::Error::Capture(format!(
+                "unsupported sample format {:?}",
+                other
+            )))
+        }
+    };
+    stream.play()?;
+    let reader = InputReader {
+        n_ch: input.n_channel.max(1),
+        ring: consumer,
+        n_frame: 0,
+        planar: pool.take(input.n_channel * PACKET_N_SAMPLE),
+    };
+    Ok((stream, reader))
+}
+
+// The running streams of every input.
+struct Capture {
+    // stop when dropped
+    streams: Vec<Stream>,
+    readers: Vec<InputReader>,
+    lost: mpsc::UnboundedReceiver<()>,
+}
+
+fn open_capture(cfg: &Config, inputs: &[InputConfig], pool: &BufferPool) -> crate::Result<Capture> {
+    let (lost_tx, lost) = mpsc::unbounded_channel();
+    let (streams, readers) = inputs
+        .iter()
+        .map(|input| {
+            open_input(
+                input,
+                cfg.mic.sample_rate,
+                cfg.mic.dither,
+                pool,
+                lost_tx.clone(),
+            )
+        })
+        .collect::<crate::Result<Vec<(Stream, InputReader)>>>()?
+        .into_iter()
+        .unzip();
+    Ok(Capture {
+        streams,
+        readers,
+        lost,
+    })
+}
+
+// Capture of 'mic.inputs' (or the single 'mic.device_name' device) at
+// 'mic.sample_rate', combined with 'Mixer'. When a device fails, e.g. is unplugged,
+// silence is produced instead until every input can be opened again.
+pub struct CpalSource {
+    cfg: Arc<Config>,
+    inputs: Vec<InputConfig>,
+    pool: Arc<BufferPool>,
+    mixer: Mixer,
+    // None while the inputs are reopened
+    capture: Option<Capture>,
+    // paces the silence meanwhile
+    ticker: time::Interval,
+    // how often the rings are looked at
+    poll: time::Interval,
+    next_attempt: Instant,
+}
+
+impl CpalSource {
+    pub fn open(cfg: Arc<Config>) -> crate::Result<CpalSource> {
+        let inputs = mixer_inputs(&cfg.mic);
+        // enough for every packet that can be queued in the mixer or be filled, and one
+        // being published
+        let max_n_ch = inputs
+            .iter()
+            .map(|input| input.n_channel)
+            .max()
+            .unwrap_or(0);
+        let pool = BufferPool::new(
+            (MAX_QUEUED_PACKETS + 2) * inputs.len(),
+            max_n_ch * PACKET_N_SAMPLE,
+        );
+        let capture = open_capture(&cfg, &inputs, &pool)?;
+        let mut mixer = Mixer::new(cfg.mic.mix, &inputs);
+        mixer.set_pool(pool.clone());
+        let period =
+            Duration::from_micros((PACKET_N_SAMPLE * 1_000_000 / cfg.mic.sample_rate) as u64);
+        let mut poll = time::interval(period / POLLS_PER_PACKET);
+        poll.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        Ok(CpalSource {
+            cfg,
+            inputs,
+            pool,
+            mixer,
+            capture: Some(capture),
+            ticker: time::interval(period),
+            poll,
+            next_attempt: Instant::now(),
+        })
+    }
+
+    fn lost(&mut self) {
+        warn!("capture device lost, sending silence until it is back");
+        // release the devices before they are opened again
+        if let Some(mut capture) = self.capture.take() {
+            capture.streams.clear();
+        }
+        self.ticker.reset();
+        self.next_attempt = Instant::now() + REOPEN_INTERVAL;
+    }
+
+    // Try to open the inputs every 'REOPEN_INTERVAL' until that succeeds.
+    fn reopen(&mut self) {
+        if Instant::now() < self.next_attempt {
+            return;
+        }
+        match open_capture(&self.cfg, &self.inputs, &self.pool) {
+            Ok(capture) => {
+                self.capture = Some(capture);
+                self.mixer = Mixer::new(self.cfg.mic.mix, &self.inputs);
+                self.mixer.set_pool(self.pool.clone());
+                Metrics::inc(&METRICS.capture_reopens);
+                info!("capture device is back");
+            }
+            Err(err) => {
+                debug!("capture device still unavailable: {}", err);
+                self.next_attempt = Instant::now() + REOPEN_INTERVAL;
+            }
+        }
+    }
+}
+
+impl AudioSource for CpalSource {
+    fn format(&self) -> SourceFormat {
+        SourceFormat {
+            sample_rate: self.cfg.mic.sample_rate,
+            n_channel: self.mixer.n_channel(),
+        }
+    }
+
+    fn next_frame(&mut self) -> FrameFuture<'_> {
+        Box::pin(async move {
+            loop {
+                let Some(capture) = &mut self.capture else {
+                    self.ticker.tick().await;
+                    let silence = self.pool.take(self.mixer.n_channel() * PACKET_N_SAMPLE);
+                    self.reopen();
+                    return Ok(Some(silence));
+                };
+                let mut got_packet = false;
+                for (index, reader) in capture.readers.iter_mut().enumerate() {
+                    if let Some(packet) = reader.next_packet(&self.pool) {
+                        got_packet = true;
+                        if let Some(mixed) = self.mixer.push(index, packet) {
+                            return Ok(Some(mixed));
+                        }
+                    }
+                }
+                if got_packet {
+                    continue;
+                }
+                let lost = tokio::select! {
+                    _ = self.poll.tick() => false,
+                    Some(()) = capture.lost.recv() => true,
+                };
+                if lost {
+                    self.lost();
+                }
+            }
+        })
+    }
+
+    fn recycle(&mut self, frame: Vec<i16>) {
+        self.pool.put(frame);
+    }
+}
@@ -0,0 +1,59 @@
+use crate::audio::encode::mapped;
+use crate::distributor::Distributor;
+use crate::protocol::StreamInfo;
+use crate::tcp_client::AudioPacket;
+use crate::HEADER_LEN;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::sync::Arc;
+use tracing::info;
+
+// Distributor carrying the frames of 'input' with the channels listed in 'map': output
+// channel n is the average of the input channels in map[n], so [[0, 1]] mixes stereo down
+// to mono, [[0], [0]] duplicates mono to stereo and [[2], [3]] picks a pair.
+pub fn channel_mapped(
+    input: &Arc<Distributor>,
+    map: &[Vec<usize>],
+) -> crate::Result<Arc<Distributor>> {
+    let stream = input.stream_info();
+    if map.is_empty() || map.iter().any(|sources| sources.is_empty()) {
+        return Err("every output channel needs at least one input channel".into());
+    }
+    if let Some(ch) = map.iter().flatten().find(|&&ch| ch >= stream.n_ch) {
+        return Err(format!("no input channel {}, the stream has {}", ch, stream.n_ch).into());
+    }
+    info!("mapping {} channels to {:?}", stream.n_ch, map);
+    let map = map.to_vec();
+    Ok(mapped(
+        input,
+        StreamInfo {
+            n_ch: map.len(),
+            ..stream
+        },
+        move |payload| map_packet(payload, &map),
+    ))
+}
+
+// None for payloads that aren't a pcm packet with every channel 'map' refers to.
+fn map_packet(payload: &[u8], map: &[Vec<usize>]) -> Option<Bytes> {
+    let packet = AudioPacket::parse(payload).ok()?;
+    if map.iter().flatten().any(|&ch| ch >= packet.n_ch) {
+        return None;
+    }
+    let len = packet.samples.len() / packet.n_ch;
+    let mut out = BytesMut::with_capacity(HEADER_LEN + len * map.len() * 2);
+    out.extend_from_slice(&payload[..HEADER_LEN]);
+    for sources in map {
+        if let [ch] = sources[..] {
+            for &sample in packet.channel(ch) {
+                out.put_i16_ne(sample);
+            }
+            continue;
+        }
+        // averaged rather than summed, so a mix of loud channels can't clip
+        for i in 0..len {
+            let sum: i32 = sources.iter().map(|&ch| packet.channel(ch)[i] as i32).sum();
+            out.put_i16_ne((sum / sources.len() as i32) as i16);
+        }
+    }
+    Some(out.freeze())
+}
@@ -0,0 +1,310 @@
+// Bulk sample conversions of the capture paths. Next to the scalar versions there are
+// SSE2 and AVX2 paths on x86_64, picked at runtime, and NEON ones on aarch64;
+// 'mic2net bench' times them against each other on the machine at hand.
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+// Float samples at full scale +-1.0 to i16, rounded to the nearest (ties to even, like
+// the simd instructions) and saturated. Converts as many as both slices hold.
+pub fn f32_to_i16(src: &[f32], dst: &mut [i16]) {
+    let len = src.len().min(dst.len());
+    let (src, dst) = (&src[..len], &mut dst[..len]);
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            unsafe { x86::f32_to_i16_avx2(src, dst) }
+        } else {
+            // part of x86_64 itself
+            unsafe { x86::f32_to_i16_sse2(src, dst) }
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        neon::f32_to_i16(src, dst)
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    scalar::f32_to_i16(src, dst)
+}
+
+// Interleaved frames of 'n_ch' samples into one run per channel in 'dst', channel 'ch'
+// starting at 'ch * stride', e.g. into a planar packet with a stride of
+// 'PACKET_N_SAMPLE'. Stereo has simd paths.
+pub fn deinterleave(src: &[i16], n_ch: usize, dst: &mut [i16], stride: usize) {
+    if n_ch == 0 {
+        return;
+    }
+    let n_frames = src.len() / n_ch;
+    assert!(
+        n_frames <= stride && (n_ch - 1) * stride + n_frames <= dst.len(),
+        "{} frames don't fit a stride of {} in {} samples",
+        n_frames,
+        stride,
+        dst.len()
+    );
+    let src = &src[..n_frames * n_ch];
+    match n_ch {
+        #[cfg(target_arch = "x86_64")]
+        2 => unsafe { x86::deinterleave_stereo(src, dst, stride) },
+        #[cfg(target_arch = "aarch64")]
+        2 => unsafe { neon::deinterleave_stereo(src, dst, stride) },
+        _ => scalar::deinterleave(src, n_ch, dst, stride),
+    }
+}
+
+// What 'f32_to_i16' and 'deinterleave' use on this cpu.
+pub fn simd_path() -> &'static str {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            "avx2"
+        } else {
+            "sse2"
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        "neon"
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        "scalar"
+    }
+}
+
+pub struct Timing {
+    pub name: &'static str,
+    pub scalar: Duration,
+    pub simd: Duration,
+}
+
+// The time of every conversion over 'n_samples' samples, 'rounds' times, the scalar
+// version against the one of 'simd_path'.
+pub fn bench(n_samples: usize, rounds: usize) -> Vec<Timing> {
+    let floats: Vec<f32> = (0..n_samples)
+        .map(|i| ((i as f32) * 0.001).sin() * 1.2)
+        .collect();
+    let samples: Vec<i16> = (0..n_samples).map(|i| (i * 7919) as i16).collect();
+    let mut out = vec![0_i16; n_samples];
+    let stride = n_samples / 2;
+
+    vec![
+        Timing {
+            name: "f32 to i16",
+            scalar: time(rounds, &mut out, |out| scalar::f32_to_i16(&floats, out)),
+            simd: time(rounds, &mut out, |out| f32_to_i16(&floats, out)),
+        },
+        Timing {
+            name: "deinterleave stereo",
+            scalar: time(rounds, &mut out, |out| {
+                scalar::deinterleave(&samples, 2, out, stride)
+            }),
+            simd: time(rounds, &mut out, |out| {
+                deinterleave(&samples, 2, out, stride)
+            }),
+        },
+    ]
+}
+
+fn time(rounds: usize, out: &mut [i16], mut convert: impl FnMut(&mut [i16])) -> Duration {
+    let start = Instant::now();
+    for _ in 0..rounds {
+        convert(black_box(&mut *out));
+    }
+    start.elapsed()
+}
+
+mod scalar {
+    pub fn f32_to_i16(src: &[f32], dst: &mut [i16]) {
+        for (dst, sample) in dst.iter_mut().zip(src) {
+            *dst = (sample * 32768.0)
+                .round_ties_even()
+                .clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+    }
+
+    pub fn deinterleave(src: &[i16], n_ch: usize, dst: &mut [i16], stride: usize) {
+        for (i, frame) in src.chunks_exact(n_ch).enumerate() {
+            for (ch, sample) in frame.iter().enumerate() {
+                dst[ch * stride + i] = *sample;
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn f32_to_i16_avx2(src: &[f32], dst: &mut [i16]) {
+        let scale = _mm256_set1_ps(32768.0);
+        let (min, max) = (
+            _mm256_set1_ps(i16::MIN as f32),
+            _mm256_set1_ps(i16::MAX as f32),
+        );
+        let n = src.len() / 16 * 16;
+        for i in (0..n).step_by(16) {
+            let a = _mm256_mul_ps(_mm256_loadu_ps(src.as_ptr().add(i)), scale);
+            let b = _mm256_mul_ps(_mm256_loadu_ps(src.as_ptr().add(i + 8)), scale);
+            let a = _mm256_cvtps_epi32(clamp_avx2(a, min, max));
+            let b = _mm256_cvtps_epi32(clamp_avx2(b, min, max));
+            // packs works within 128-bit lanes; the permute puts the 64-bit quarters
+            // back in order
+            let packed = _mm256_packs_epi32(a, b);
+            let packed = _mm256_permute4x64_epi64(packed, 0b11_01_10_00);
+            _mm256_storeu_si256(dst.as_mut_ptr().add(i).cast(), packed);
+        }
+        super::scalar::f32_to_i16(&src[n..], &mut dst[n..]);
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn f32_to_i16_sse2(src: &[f32], dst: &mut [i16]) {
+        let scale = _mm_set1_ps(32768.0);
+        let (min, max) = (_mm_set1_ps(i16::MIN as f32), _mm_set1_ps(i16::MAX as f32));
+        let n = src.len() / 8 * 8;
+        for i in (0..n).step_by(8) {
+            let a = _mm_mul_ps(_mm_loadu_ps(src.as_ptr().add(i)), scale);
+            let b = _mm_mul_ps(_mm_loadu_ps(src.as_ptr().add(i + 4)), scale);
+            let a = _mm_cvtps_epi32(clamp_sse2(a, min, max));
+            let b = _mm_cvtps_epi32(clamp_sse2(b, min, max));
+            let packed = _mm_packs_epi32(a, b);
+            _mm_storeu_si128(dst.as_mut_ptr().add(i).cast(), packed);
+        }
+        super::scalar::f32_to_i16(&src[n..], &mut dst[n..]);
+    }
+
+    // Saturate to i16, with NaN lanes zeroed first as the scalar cast does; max and min
+    // return their second operand for NaN, which would convert to i16::MIN (or MAX).
+    #[target_feature(enable = "avx2")]
+    unsafe fn clamp_avx2(x: __m256, min: __m256, max: __m256) -> __m256 {
+        let x = _mm256_and_ps(x, _mm256_cmp_ps::<_CMP_ORD_Q>(x, x));
+        _mm256_min_ps(_mm256_max_ps(x, min), max)
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn clamp_sse2(x: __m128, min: __m128, max: __m128) -> __m128 {
+        let x = _mm_and_ps(x, _mm_cmpord_ps(x, x));
+        _mm_min_ps(_mm_max_ps(x, min), max)
+    }
+
+    // 'deinterleave' checked that the channels fit 'dst'.
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn deinterleave_stereo(src: &[i16], dst: &mut [i16], stride: usize) {
+        let n_frames = src.len() / 2;
+        let n = n_frames / 8 * 8;
+        for i in (0..n).step_by(8) {
+            let a = _mm_loadu_si128(src.as_ptr().add(i * 2).cast());
+            let b = _mm_loadu_si128(src.as_ptr().add(i * 2 + 8).cast());
+            // every 32 bits hold a frame, left in the low half
+            let left = _mm_packs_epi32(
+                _mm_srai_epi32(_mm_slli_epi32(a, 16), 16),
+                _mm_srai_epi32(_mm_slli_epi32(b, 16), 16),
+            );
+            let right = _mm_packs_epi32(_mm_srai_epi32(a, 16), _mm_srai_epi32(b, 16));
+            _mm_storeu_si128(dst.as_mut_ptr().add(i).cast(), left);
+            _mm_storeu_si128(dst.as_mut_ptr().add(stride + i).cast(), right);
+        }
+        for i in n..n_frames {
+            dst[i] = src[i * 2];
+            dst[stride + i] = src[i * 2 + 1];
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    pub unsafe fn f32_to_i16(src: &[f32], dst: &mut [i16]) {
+        let n = src.len() / 8 * 8;
+        for i in (0..n).step_by(8) {
+            let a = vmulq_n_f32(vld1q_f32(src.as_ptr().add(i)), 32768.0);
+            let b = vmulq_n_f32(vld1q_f32(src.as_ptr().add(i + 4)), 32768.0);
+            // to nearest, ties to even, then saturating
+            let a = vqmovn_s32(vcvtnq_s32_f32(a));
+            let b = vqmovn_s32(vcvtnq_s32_f32(b));
+            vst1q_s16(dst.as_mut_ptr().add(i), vcombine_s16(a, b));
+        }
+        super::scalar::f32_to_i16(&src[n..], &mut dst[n..]);
+    }
+
+    // 'deinterleave' checked that the channels fit 'dst'.
+    pub unsafe fn deinterleave_stereo(src: &[i16], dst: &mut [i16], stride: usize) {
+        let n_frames = src.len() / 2;
+        let n = n_frames / 8 * 8;
+        for i in (0..n).step_by(8) {
+            let frames = vld2q_s16(src.as_ptr().add(i * 2));
+            vst1q_s16(dst.as_mut_ptr().add(i), frames.0);
+            vst1q_s16(dst.as_mut_ptr().add(stride + i), frames.1);
+        }
+        for i in n..n_frames {
+            dst[i] = src[i * 2];
+            dst[stride + i] = src[i * 2 + 1];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every kind of input, at lengths that leave a scalar tail behind the simd blocks.
+    fn floats(len: usize) -> Vec<f32> {
+        let special = [
+            f32::NAN,
+            -f32::NAN,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            1.5,
+            -1.5,
+            1.0,
+            -1.0,
+            0.0,
+            -0.0,
+            // ties, to even
+            0.5 / 32768.0,
+            1.5 / 32768.0,
+            -2.5 / 32768.0,
+        ];
+        (0..len)
+            .map(|i| match i % 3 {
+                0 => special[i / 3 % special.len()],
+                _ => ((i as f32) * 0.37).sin() * 1.1,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn simd_matches_scalar() {
+        for len in [0, 1, 7, 8, 15, 16, 17, 100, 1000] {
+            let src = floats(len);
+            let mut expected = vec![0_i16; len];
+            scalar::f32_to_i16(&src, &mut expected);
+            let mut out = vec![0_i16; len];
+            f32_to_i16(&src, &mut out);
+            assert_eq!(out, expected, "{} via {}", len, simd_path());
+            #[cfg(target_arch = "x86_64")]
+            {
+                let mut out = vec![0_i16; len];
+                unsafe { x86::f32_to_i16_sse2(&src, &mut out) };
+                assert_eq!(out, expected, "{} via sse2", len);
+            }
+        }
+    }
+
+    #[test]
+    fn nan_converts_to_silence() {
+        let mut out = [1_i16; 16];
+        f32_to_i16(&[f32::NAN; 16], &mut out);
+        assert_eq!(out, [0; 16]);
+    }
+
+    #[test]
+    fn deinterleave_matches_scalar() {
+        let src: Vec<i16> = (0..202).map(|i| (i * 7919) as i16).collect();
+        let (mut expected, mut out) = (vec![0_i16; 202], vec![0_i16; 202]);
+        scalar::deinterleave(&src, 2, &mut expected, 101);
+        deinterleave(&src, 2, &mut out, 101);
+        assert_eq!(out, expected);
+    }
+}
@@ -0,0 +1,257 @@
+use crate::audio::adpcm::AdpcmEncoder;
+use crate::audio::format::{convert_packet, SampleFormat};
+use crate::distributor::{Distributor, DropPolicy};
+use crate::protocol::{Frame, FrameKind, StreamInfo};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+// packets buffered between the input distributor and an encoder
+const ENCODE_QUEUE_LEN: usize = 50;
+
+// How the audio payloads of a stream transport are coded.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum WireCodec {
+    // the packets as captured: header and i16 samples of every channel
+    Pcm,
+    // one ADTS AAC-LC frame of the first 2 channels per audio frame; needs the 'aac' feature
+    Aac,
+    // every channel in 4-bit IMA ADPCM blocks, see 'adpcm'
+    Adpcm,
+    // one 20 ms opus packet of the first 2 channels per audio frame; needs the 'opus'
+    // feature and a rate opus supports (8, 12, 16, 24 or 48 kHz)
+    Opus,
+}
+
+impl WireCodec {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            WireCodec::Pcm => 0,
+            WireCodec::Aac => 1,
+            WireCodec::Adpcm => 2,
+            WireCodec::Opus => 3,
+        }
+    }
+
+    pub(crate) fn from_u8(codec: u8) -> Option<WireCodec> {
+        match codec {
+            0 => Some(WireCodec::Pcm),
+            1 => Some(WireCodec::Aac),
+            2 => Some(WireCodec::Adpcm),
+            3 => Some(WireCodec::Opus),
+            _ => None,
+        }
+    }
+}
+
+// Distributor carrying the frames of 'input' coded as 'codec', with pcm samples in
+// 'sample_format'; 'input' itself when that's what it carries already.
+pub fn encoded(
+    input: &Arc<Distributor>,
+    codec: WireCodec,
+    sample_format: SampleFormat,
+) -> crate::Result<Arc<Distributor>> {
+    let stream = input.stream_info();
+    match codec {
+        WireCodec::Pcm if sample_format == stream.sample_format => Ok(input.clone()),
+        WireCodec::Pcm => Ok(mapped(
+            input,
+            StreamInfo {
+                sample_format,
+                ..stream
+            },
+            move |payload| convert_packet(payload, sample_format),
+        )),
+        WireCodec::Aac => aac::encoded(input),
+        WireCodec::Opus => opus::encoded(input),
+        WireCodec::Adpcm => {
+            let mut encoder = AdpcmEncoder::new(stream.n_ch);
+            Ok(mapped(
+                input,
+                StreamInfo {
+                    codec: WireCodec::Adpcm,
+                    ..stream
+                },
+                move |payload| encoder.encode(payload),
+            ))
+        }
+    }
+}
+
+// One frame of 'stream' per audio frame of 'input', with the same sequence numbers and
+// timestamps; frames 'map' returns None for are skipped. Silence frames pass as they are.
+pub(crate) fn mapped(
+    input: &Arc<Distributor>,
+    stream: StreamInfo,
+    mut map: impl FnMut(&[u8]) -> Option<Bytes> + Send + 'static,
+) -> Arc<Distributor> {
+    let output = Distributor::new(stream, input.history_len());
+    let mut frames = input.subscribe(ENCODE_QUEUE_LEN, DropPolicy::DropNewest);
+
+    let output_cp = output.clone();
+    tokio::spawn(async move {
+        while let Some(frame) = frames.recv().await {
+            if frame.kind != FrameKind::Audio {
+                output_cp.publish(frame).await;
+                continue;
+            }
+            if let Some(payload) = map(&frame.payload) {
+                output_cp
+                    .publish(Frame::audio(frame.seq, frame.timestamp, payload))
+                    .await;
+            }
+        }
+    });
+    output
+}
+
+// Codecs whose frames don't line up with the packets they are fed.
+#[cfg(any(feature = "aac", feature = "opus"))]
+trait FrameEncoder: Send + 'static {
+    fn push(&mut self, packet: &crate::tcp_client::AudioPacket);
+    fn next_frame(&mut self) -> crate::Result<Option<Vec<u8>>>;
+}
+
+// Distributor carrying 'encoder's frames of 'input', described by 'stream'; frames get
+// their own sequence numbers and the timestamp of the packet that completed them.
+#[cfg(any(feature = "aac", feature = "opus"))]
+fn framed(
+    input: &Arc<Distributor>,
+    stream: StreamInfo,
+    mut encoder: impl FrameEncoder,
+) -> Arc<Distributor> {
+    use crate::tcp_client::AudioPacket;
+    use tracing::error;
+
+    // same pre-roll duration as the input
+    let history_len =
+        (input.history_len() * input.stream_info().frame_samples).div_ceil(stream.frame_samples);
+    let output = Distributor::new(stream, history_len);
+    let mut frames = input.subscribe(ENCODE_QUEUE_LEN, DropPolicy::DropNewest);
+
+    let output_cp = output.clone();
+    tokio::spawn(async move {
+        let mut seq = 0_u32;
+        while let Some(frame) = frames.recv().await {
+            if frame.kind == FrameKind::Silence {
+                output_cp.publish(Frame { seq, ..frame }).await;
+                seq = seq.wrapping_add(1);
+                continue;
+            }
+            let packet = match AudioPacket::parse(&frame.payload) {
+                Ok(packet) => packet,
+                Err(_) => continue,
+            };
+            encoder.push(&packet);
+            loop {
+                let encoded = match encoder.next_frame() {
+                    Ok(Some(encoded)) => encoded,
+                    Ok(None) => break,
+                    Err(err) => {
+                        error!("{:?} encoding stopped: {}", stream.codec, err);
+                        return;
+                    }
+                };
+                output_cp
+                    .publish(Frame::audio(seq, frame.timestamp, encoded.into()))
+                    .await;
+                seq = seq.wrapping_add(1);
+            }
+        }
+    });
+    output
+}
+
+#[cfg(not(feature = "aac"))]
+mod aac {
+    use crate::distributor::Distributor;
+    use std::sync::Arc;
+
+    pub fn encoded(_input: &Arc<Distributor>) -> crate::Result<Arc<Distributor>> {
+        Err("aac needs a build with --features aac".into())
+    }
+}
+
+#[cfg(feature = "aac")]
+mod aac {
+    use super::{framed, FrameEncoder, WireCodec};
+    use crate::audio::aac::AacFramer;
+    use crate::distributor::Distributor;
+    use crate::protocol::StreamInfo;
+    use crate::tcp_client::AudioPacket;
+    use fdk_aac::enc::Transport;
+    use std::sync::Arc;
+    use tracing::info;
+
+    impl FrameEncoder for AacFramer {
+        fn push(&mut self, packet: &AudioPacket) {
+            AacFramer::push(self, packet)
+        }
+
+        fn next_frame(&mut self) -> crate::Result<Option<Vec<u8>>> {
+            AacFramer::next_frame(self)
+        }
+    }
+
+    // The first 2 channels of 'input' as ADTS frames.
+    pub fn encoded(input: &Arc<Distributor>) -> crate::Result<Arc<Distributor>> {
+        let stream = input.stream_info();
+        let n_ch = stream.n_ch.min(2);
+        let framer = AacFramer::new(stream.sample_rate, n_ch, Transport::Adts)?;
+        info!("encoding {} channels as aac", n_ch);
+        let stream = StreamInfo {
+            n_ch,
+            frame_samples: framer.frame_len(),
+            codec: WireCodec::Aac,
+            ..stream
+        };
+        Ok(framed(input, stream, framer))
+    }
+}
+
+#[cfg(not(feature = "opus"))]
+mod opus {
+    use crate::distributor::Distributor;
+    use std::sync::Arc;
+
+    pub fn encoded(_input: &Arc<Distributor>) -> crate::Result<Arc<Distributor>> {
+        Err("opus needs a build with --features opus".into())
+    }
+}
+
+#[cfg(feature = "opus")]
+mod opus {
+    use super::{framed, FrameEncoder, WireCodec};
+    use crate::audio::opus::OpusFramer;
+    use crate::distributor::Distributor;
+    use crate::protocol::StreamInfo;
+    use crate::tcp_client::AudioPacket;
+    use std::sync::Arc;
+    use tracing::info;
+
+    impl FrameEncoder for OpusFramer {
+        fn push(&mut self, packet: &AudioPacket) {
+            OpusFramer::push(self, packet)
+        }
+
+        fn next_frame(&mut self) -> crate::Result<Option<Vec<u8>>> {
+            OpusFramer::next_frame(self)
+        }
+    }
+
+    // The first 2 channels of 'input' as 20 ms opus packets.
+    pub fn encoded(input: &Arc<Distributor>) -> crate::Result<Arc<Distributor>> {
+        let stream = input.stream_info();
+        let n_ch = stream.n_ch.min(2);
+        let framer = OpusFramer::new(stream.sample_rate, n_ch, ::opus::Application::Audio)?;
+        info!("encoding {} channels as opus", n_ch);
+        let stream = StreamInfo {
+            n_ch,
+            frame_samples: framer.frame_len(),
+            codec: WireCodec::Opus,
+            ..stream
+        };
+        Ok(framed(input, stream, framer))
+    }
+}
@@ -0,0 +1,133 @@
+use crate::tcp_client::AudioPacket;
+use flacenc::bitsink::ByteSink;
+use flacenc::component::{BitRepr, Stream, StreamInfo};
+use flacenc::config;
+use flacenc::error::{Verified, Verify};
+use flacenc::source::{Context, Fill, FrameBuf};
+
+// Samples per channel in one flac frame (16 packets); short enough for live streams,
+// long enough to compress about as well as the reference encoder's default.
+pub const FLAC_BLOCK_SIZE: usize = 2560;
+const BITS_PER_SAMPLE: usize = 16;
+
+// Lossless encoder turning planar packets into a flac stream, block by block.
+pub struct FlacEncoder {
+    n_ch: usize,
+    sample_rate: usize,
+    config: Verified<config::Encoder>,
+    framebuf: FrameBuf,
+    // md5 and frame numbers over everything encoded so far
+    context: Context,
+    // frame and block sizes seen, for the final header of a file
+    stream_info: StreamInfo,
+    // interleaved samples of the block being collected
+    block: Vec<i32>,
+    sink: ByteSink,
+}
+
+fn flac_error(err: impl std::fmt::Debug) -> crate::Error {
+    crate::Error::Codec(format!("flac: {:?}", err))
+}
+
+impl FlacEncoder {
+    // Encodes the first 'n_ch' channels of the packets.
+    pub fn new(sample_rate: usize, n_ch: usize) -> crate::Result<FlacEncoder> {
+        let mut encoder_config = config::Encoder::default();
+        encoder_config.block_size = FLAC_BLOCK_SIZE;
+        Ok(FlacEncoder {
+            n_ch,
+            sample_rate,
+            config: encoder_config
+                .into_verified()
+                .map_err(|(_, err)| flac_error(err))?,
+            framebuf: FrameBuf::with_size(n_ch, FLAC_BLOCK_SIZE).map_err(flac_error)?,
+            context: Context::new(BITS_PER_SAMPLE, n_ch),
+            stream_info: StreamInfo::new(sample_rate, n_ch, BITS_PER_SAMPLE).map_err(flac_error)?,
+            block: Vec::with_capacity(FLAC_BLOCK_SIZE * n_ch),
+            sink: ByteSink::new(),
+        })
+    }
+
+    pub fn n_ch(&self) -> usize {
+        self.n_ch
+    }
+
+    // "fLaC" and a STREAMINFO block with length and checksum unknown, as a live stream
+    // or a file still being written starts.
+    pub fn header(&mut self) -> crate::Result<Vec<u8>> {
+        let mut stream_info =
+            StreamInfo::new(self.sample_rate, self.n_ch, BITS_PER_SAMPLE).map_err(flac_error)?;
+        stream_info
+            .set_block_sizes(FLAC_BLOCK_SIZE, FLAC_BLOCK_SIZE)
+            .map_err(flac_error)?;
+        stream_info.set_frame_sizes(0, 0).map_err(flac_error)?;
+        self.write_stream(stream_info)
+    }
+
+    // The header again, now with the length, frame sizes and md5 of what was encoded;
+    // same size as 'header', so a file can be patched in place.
+    pub fn final_header(&mut self) -> crate::Result<Vec<u8>> {
+        let mut stream_info = self.stream_info.clone();
+        if stream_info.total_samples() == 0 {
+            return self.header();
+        }
+        stream_info.set_md5_digest(&self.context.md5_digest());
+        self.write_stream(stream_info)
+    }
+
+    fn write_stream(&mut self, stream_info: StreamInfo) -> crate::Result<Vec<u8>> {
+        self.sink.clear();
+        Stream::with_stream_info(stream_info)
+            .write(&mut self.sink)
+            .map_err(flac_error)?;
+        Ok(self.sink.as_slice().to_vec())
+    }
+
+    // Append the flac frames 'packet' completes to 'out'; usually nothing.
+    pub fn encode(&mut self, packet: &AudioPacket, out: &mut Vec<u8>) -> crate::Result<()> {
+        let n_ch = self.n_ch.min(packet.n_ch);
+        let len = packet.samples.len() / packet.n_ch;
+        for i in 0..len {
+            for ch in 0..self.n_ch {
+                // inputs with fewer channels than the stream get silent ones
+                let sample = if ch < n_ch { packet.channel(ch)[i] } else { 0 };
+                self.block.push(sample as i32);
+            }
+            if self.block.len() == FLAC_BLOCK_SIZE * self.n_ch {
+                self.encode_block(out)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Encode the samples of a partial block; for the end of a stream.
+    pub fn flush(&mut self, out: &mut Vec<u8>) -> crate::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.framebuf.resize(self.block.len() / self.n_ch);
+        self.encode_block(out)?;
+        self.framebuf.resize(FLAC_BLOCK_SIZE);
+        Ok(())
+    }
+
+    fn encode_block(&mut self, out: &mut Vec<u8>) -> crate::Result<()> {
+        (&mut self.framebuf, &mut self.context)
+            .fill_interleaved(&self.block)
+            .map_err(flac_error)?;
+        self.block.clear();
+        let frame_number = self.context.current_frame_number().unwrap_or_default();
+        let frame = flacenc::encode_fixed_size_frame(
+            &self.config,
+            &self.framebuf,
+            frame_number,
+            &self.stream_info,
+        )
+        .map_err(flac_error)?;
+        self.stream_info.update_frame_info(&frame);
+        self.sink.clear();
+        frame.write(&mut self.sink).map_err(flac_error)?;
+        out.extend_from_slice(self.sink.as_slice());
+        Ok(())
+    }
+}
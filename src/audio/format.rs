@@ -0,0 +1,117 @@
+use crate::{HEADER_LEN, PACKET_N_SAMPLE};
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+// Sample encoding of pcm packets on the wire. Capture is 16-bit, so i24 and f32 carry
+// no extra resolution; they spare receivers that work in those formats a conversion.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleFormat {
+    // native byte order, as the capture path produces them
+    I16,
+    // 3 bytes little endian
+    I24,
+    // little endian, full scale at +-1.0
+    F32,
+}
+
+impl SampleFormat {
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            SampleFormat::I16 => 2,
+            SampleFormat::I24 => 3,
+            SampleFormat::F32 => 4,
+        }
+    }
+
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            SampleFormat::I16 => 0,
+            SampleFormat::I24 => 1,
+            SampleFormat::F32 => 2,
+        }
+    }
+
+    pub(crate) fn from_u8(format: u8) -> Option<SampleFormat> {
+        match format {
+            0 => Some(SampleFormat::I16),
+            1 => Some(SampleFormat::I24),
+            2 => Some(SampleFormat::F32),
+            _ => None,
+        }
+    }
+}
+
+// Rewrite the i16 samples of a captured packet as 'format', keeping the packet header.
+// None for payloads that aren't whole packets.
+pub fn convert_packet(payload: &[u8], format: SampleFormat) -> Option<Bytes> {
+    let audio_len = payload.len().checked_sub(HEADER_LEN)?;
+    if !audio_len.is_multiple_of(PACKET_N_SAMPLE * 2) {
+        return None;
+    }
+    let n_samples = audio_len / 2;
+    let mut out = BytesMut::with_capacity(HEADER_LEN + n_samples * format.bytes_per_sample());
+    out.extend_from_slice(&payload[..HEADER_LEN]);
+    for b in payload[HEADER_LEN..].chunks_exact(2) {
+        let sample = i16::from_ne_bytes([b[0], b[1]]);
+        match format {
+            SampleFormat::I16 => out.put_i16_ne(sample),
+            SampleFormat::I24 => out.extend_from_slice(&((sample as i32) << 8).to_le_bytes()[..3]),
+            SampleFormat::F32 => out.put_f32_le(sample as f32 / 32768.0),
+        }
+    }
+    Some(out.freeze())
+}
+
+// Samples in 'format' back to i16, e.g. for a receiver that plays them.
+pub fn to_i16(samples: &[u8], format: SampleFormat) -> Vec<i16> {
+    let chunks = samples.chunks_exact(format.bytes_per_sample());
+    match format {
+        SampleFormat::I16 => chunks.map(|b| i16::from_ne_bytes([b[0], b[1]])).collect(),
+        SampleFormat::I24 => chunks
+            .map(|b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 16) as i16)
+            .collect(),
+        SampleFormat::F32 => chunks
+            .map(|b| {
+                let sample = f32::from_le_bytes([b[0], b[1], b[2], b[3]]) * 32768.0;
+                sample.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+            })
+            .collect(),
+    }
+}
+
+// Rounds float samples to i16 for capture devices that deliver more than 16 bits. With
+// 'dither', TPDF noise of up to one step either way is added first, so quiet signals
+// turn into a noise floor instead of distortion that follows the signal.
+pub struct Quantizer {
+    dither: bool,
+    // xorshift
+    state: u32,
+}
+
+impl Quantizer {
+    pub fn new(dither: bool) -> Quantizer {
+        Quantizer {
+            dither,
+            state: 0x2545_f491,
+        }
+    }
+
+    // 'sample' at full scale +-1.0.
+    pub fn quantize(&mut self, sample: f32) -> i16 {
+        let mut sample = sample * 32768.0;
+        if self.dither {
+            // the sum of two uniform values is triangular
+            sample += self.uniform() + self.uniform();
+        }
+        sample.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+    }
+
+    // -0.5 to 0.5
+    fn uniform(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32 - 0.5
+    }
+}
@@ -0,0 +1,119 @@
+use crate::audio::pool::BufferPool;
+use crate::config_file::{InputConfig, MicConfig};
+use crate::metrics::{Metrics, METRICS};
+use crate::PACKET_N_SAMPLE;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+// Packets an input may run ahead of the slowest one before its oldest are dropped;
+// devices on separate clocks drift apart slowly.
+pub const MAX_QUEUED_PACKETS: usize = 4;
+
+// How the channels of several inputs are combined.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MixMode {
+    // all channels side by side: channels of the first input, then the second, ...
+    Interleave,
+    // channel n of every input summed into output channel n
+    Mix,
+}
+
+struct MixerInput {
+    n_channel: usize,
+    // linear
+    gain: f32,
+    queue: VecDeque<Vec<i16>>,
+}
+
+// Combines planar packets of 'PACKET_N_SAMPLE' samples per channel from several
+// capture devices into one packet per period.
+pub struct Mixer {
+    mode: MixMode,
+    inputs: Vec<MixerInput>,
+    pool: Option<Arc<BufferPool>>,
+}
+
+impl Mixer {
+    pub fn new(mode: MixMode, inputs: &[InputConfig]) -> Mixer {
+        Mixer {
+            mode,
+            inputs: inputs
+                .iter()
+                .map(|input| MixerInput {
+                    n_channel: input.n_channel,
+                    gain: 10.0_f32.powf(input.gain / 20.0),
+                    queue: VecDeque::new(),
+                })
+                .collect(),
+            pool: None,
+        }
+    }
+
+    // Give the packets back to 'pool' once they are mixed or dropped.
+    pub fn set_pool(&mut self, pool: Arc<BufferPool>) {
+        self.pool = Some(pool);
+    }
+
+    pub fn n_channel(&self) -> usize {
+        let channels = self.inputs.iter().map(|input| input.n_channel);
+        match self.mode {
+            MixMode::Interleave => channels.sum(),
+            MixMode::Mix => channels.max().unwrap_or(0),
+        }
+    }
+
+    // Queue a packet from input 'index'; returns the combined packet once every input
+    // has delivered one.
+    pub fn push(&mut self, index: usize, packet: Vec<i16>) -> Option<Vec<i16>> {
+        let input = &mut self.inputs[index];
+        if input.queue.len() == MAX_QUEUED_PACKETS {
+            let dropped = input.queue.pop_front().unwrap();
+            input.queue.push_back(packet);
+            Metrics::inc(&METRICS.capture_overruns);
+            recycle(&self.pool, dropped);
+        } else {
+            input.queue.push_back(packet);
+        }
+        if self.inputs.iter().any(|input| input.queue.is_empty()) {
+            return None;
+        }
+
+        let mut out = vec![0_i16; self.n_channel() * PACKET_N_SAMPLE];
+        let mut offset = 0;
+        for input in self.inputs.iter_mut() {
+            let packet = input.queue.pop_front().unwrap();
+            let dst = &mut out[offset..offset + packet.len()];
+            for (dst, &src) in dst.iter_mut().zip(packet.iter()) {
+                let mixed = *dst as f32 + src as f32 * input.gain;
+                *dst = mixed.clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            }
+            if self.mode == MixMode::Interleave {
+                offset += packet.len();
+            }
+            recycle(&self.pool, packet);
+        }
+        Some(out)
+    }
+}
+
+fn recycle(pool: &Option<Arc<BufferPool>>, packet: Vec<i16>) {
+    if let Some(pool) = pool {
+        pool.put(packet);
+    }
+}
+
+// The inputs in 'mic', or the single 'device_name' input when none are listed.
+pub fn mixer_inputs(mic: &MicConfig) -> Vec<InputConfig> {
+    if mic.inputs.is_empty() {
+        vec![InputConfig {
+            name: mic.device_name.clone(),
+            n_channel: mic.n_channel,
+            gain: 0.0,
+            source: mic.source,
+        }]
+    } else {
+        mic.inputs.clone()
+    }
+}
@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "aac")]
+pub mod aac;
+pub mod adpcm;
+#[cfg(feature = "alsa")]
+pub mod alsa;
+#[cfg(feature = "cpal")]
+pub mod capture;
+pub mod channels;
+pub mod convert;
+pub mod encode;
+pub mod flac;
+pub mod format;
+pub mod mixer;
+#[cfg(feature = "opus")]
+pub mod ogg;
+#[cfg(feature = "opus")]
+pub mod opus;
+#[cfg(feature = "pipewire")]
+pub mod pipewire;
+pub mod pool;
+pub mod resample;
+pub mod spsc;
+pub mod vad;
+#[cfg(all(windows, feature = "wasapi"))]
+pub mod wasapi;
+#[cfg(feature = "opus")]
+pub mod webm;
+
+// Where the microphone samples come from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureBackend {
+    // spawn jackd and read its physical capture ports
+    Jack,
+    // open the device directly through cpal; needs the 'cpal' feature
+    Cpal,
+    // a pipewire stream node; needs the 'pipewire' feature
+    Pipewire,
+    // an alsa device with a pinned period size and count; needs the 'alsa' feature
+    Alsa,
+    // a wasapi device, exclusively if [wasapi] says so; needs windows and the 'wasapi'
+    // feature
+    Wasapi,
+}
+
+// What a cpal input records.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureSource {
+    // an input device such as a microphone
+    Microphone,
+    // what the system plays ("what you hear"): the WASAPI loopback of an output device
+    // on windows, the monitor source of a PulseAudio (or pipewire-pulse) sink on linux
+    Loopback,
+}
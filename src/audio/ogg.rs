@@ -0,0 +1,154 @@
+use crate::audio::opus::OpusFramer;
+use crate::tcp_client::AudioPacket;
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use ring::rand::{generate, SystemRandom};
+
+// granule positions are always counted at 48 kHz (RFC 7845)
+const GRANULE_RATE: usize = 48000;
+const VENDOR: &[u8] = b"mic2net";
+
+// Ogg Opus (RFC 7845). Live streams put every frame on a page of its own to keep
+// latency low, files fill a page with 'frames_per_page' frames; 'finish' ends the
+// stream with the encoder's delayed tail and the exact length, so players neither cut
+// the last samples nor play padding.
+pub struct OggOpusEncoder {
+    framer: OpusFramer,
+    writer: PacketWriter<'static, Vec<u8>>,
+    serial: u32,
+    frames_per_page: usize,
+    // encoded frames not yet on a finished page
+    frames_on_page: usize,
+    granule: u64,
+    // samples per channel pushed / encoded, at the stream's rate
+    n_pushed: u64,
+    n_encoded: u64,
+}
+
+impl OggOpusEncoder {
+    pub fn new(
+        sample_rate: usize,
+        n_ch: usize,
+        frames_per_page: usize,
+    ) -> crate::Result<OggOpusEncoder> {
+        // unique among the streams a player may see chained or multiplexed
+        let serial = generate::<[u8; 4]>(&SystemRandom::new())
+            .map_err(|_| "no randomness for an ogg serial")?
+            .expose();
+        Ok(OggOpusEncoder {
+            framer: OpusFramer::new(sample_rate, n_ch, opus::Application::Audio)?,
+            writer: PacketWriter::new(Vec::new()),
+            serial: u32::from_ne_bytes(serial),
+            frames_per_page: frames_per_page.max(1),
+            frames_on_page: 0,
+            granule: 0,
+            n_pushed: 0,
+            n_encoded: 0,
+        })
+    }
+
+    pub fn n_channel(&self) -> usize {
+        self.framer.n_channel()
+    }
+
+    // OpusHead and OpusTags, each on a page of its own.
+    pub fn header(&mut self) -> crate::Result<Vec<u8>> {
+        let pre_skip = self.pre_skip()?;
+        let head = opus_head(self.framer.n_channel(), pre_skip, self.framer.sample_rate());
+        self.writer
+            .write_packet(head, self.serial, PacketWriteEndInfo::EndPage, 0)?;
+
+        let mut tags = Vec::with_capacity(16 + VENDOR.len());
+        tags.extend_from_slice(b"OpusTags");
+        tags.extend_from_slice(&(VENDOR.len() as u32).to_le_bytes());
+        tags.extend_from_slice(VENDOR);
+        // no user comments
+        tags.extend_from_slice(&0_u32.to_le_bytes());
+        self.writer
+            .write_packet(tags, self.serial, PacketWriteEndInfo::EndPage, 0)?;
+        Ok(std::mem::take(self.writer.inner_mut()))
+    }
+
+    // Append the pages 'packet' completed to 'out'.
+    pub fn encode(&mut self, packet: &AudioPacket, out: &mut Vec<u8>) -> crate::Result<()> {
+        self.framer.push(packet);
+        self.n_pushed += (packet.samples.len() / packet.n_ch) as u64;
+        while let Some(encoded) = self.framer.next_frame()? {
+            self.write_frame(encoded)?;
+        }
+        out.append(self.writer.inner_mut());
+        Ok(())
+    }
+
+    // Append the last pages to 'out'; nothing may be encoded after.
+    pub fn finish(&mut self, out: &mut Vec<u8>) -> crate::Result<()> {
+        let frame_len = self.framer.frame_len() as u64;
+        // what is still in the encoder's delay line has to come out too
+        let end = self.n_pushed + self.framer.lookahead()? as u64;
+        let n_frames = end
+            .saturating_sub(self.n_encoded)
+            .div_ceil(frame_len)
+            .max(1);
+        let pending = self.n_pushed - self.n_encoded;
+        self.framer.pad((n_frames * frame_len - pending) as usize);
+        for i in 0..n_frames {
+            let encoded = self
+                .framer
+                .next_frame()?
+                .ok_or_else(|| crate::Error::Codec("opus frame missing".into()))?;
+            if i + 1 < n_frames {
+                self.write_frame(encoded)?;
+                continue;
+            }
+            // end trimming: the last page's position marks where the audio ends
+            let granule = (self.pre_skip()? + self.to_granule(self.n_pushed)) as u64;
+            self.writer.write_packet(
+                encoded,
+                self.serial,
+                PacketWriteEndInfo::EndStream,
+                granule.max(self.granule),
+            )?;
+        }
+        out.append(self.writer.inner_mut());
+        Ok(())
+    }
+
+    fn write_frame(&mut self, encoded: Vec<u8>) -> crate::Result<()> {
+        let frame_len = self.framer.frame_len();
+        self.n_encoded += frame_len as u64;
+        self.granule += self.to_granule(frame_len as u64) as u64;
+        self.frames_on_page += 1;
+        let end_info = if self.frames_on_page >= self.frames_per_page {
+            self.frames_on_page = 0;
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        self.writer
+            .write_packet(encoded, self.serial, end_info, self.granule)?;
+        Ok(())
+    }
+
+    // Encoder delay in 48 kHz samples.
+    fn pre_skip(&mut self) -> crate::Result<usize> {
+        let lookahead = self.framer.lookahead()?;
+        Ok(self.to_granule(lookahead as u64))
+    }
+
+    fn to_granule(&self, n_samples: u64) -> usize {
+        (n_samples * GRANULE_RATE as u64 / self.framer.sample_rate() as u64) as usize
+    }
+}
+
+// The identification header, which webm carries as the track's codec private data too.
+pub fn opus_head(n_ch: usize, pre_skip: usize, sample_rate: usize) -> Vec<u8> {
+    let mut head = Vec::with_capacity(19);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1);
+    head.push(n_ch as u8);
+    head.extend_from_slice(&(pre_skip as u16).to_le_bytes());
+    head.extend_from_slice(&(sample_rate as u32).to_le_bytes());
+    // output gain, channel mapping family
+    head.extend_from_slice(&0_i16.to_le_bytes());
+    head.push(0);
+    head
+}
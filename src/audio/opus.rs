@@ -0,0 +1,89 @@
+use crate::tcp_client::AudioPacket;
+
+// 20 ms, the usual frame length for streaming
+const FRAMES_PER_SECOND: usize = 50;
+// largest packet opus can produce for one frame
+const MAX_OPUS_PACKET: usize = 1275;
+
+// Cuts the stream into 20 ms frames of its first one or two channels and encodes them
+// with opus. Packets of the stream rarely line up with frames, so samples are
+// buffered in between.
+pub struct OpusFramer {
+    encoder: opus::Encoder,
+    sample_rate: usize,
+    n_ch: usize,
+    // samples per channel in one frame
+    frame_len: usize,
+    // interleaved samples waiting for a full frame
+    pending: Vec<i16>,
+}
+
+impl OpusFramer {
+    // 'sample_rate' has to be one opus supports: 8, 12, 16, 24 or 48 kHz.
+    pub fn new(
+        sample_rate: usize,
+        n_ch: usize,
+        application: opus::Application,
+    ) -> crate::Result<OpusFramer> {
+        let channels = match n_ch {
+            1 => opus::Channels::Mono,
+            2 => opus::Channels::Stereo,
+            _ => return Err(crate::Error::Codec("opus supports 1 or 2 channels".into())),
+        };
+        let frame_len = sample_rate / FRAMES_PER_SECOND;
+        Ok(OpusFramer {
+            encoder: opus::Encoder::new(sample_rate as u32, channels, application)?,
+            sample_rate,
+            n_ch,
+            frame_len,
+            pending: Vec::with_capacity(frame_len * n_ch * 2),
+        })
+    }
+
+    pub fn n_channel(&self) -> usize {
+        self.n_ch
+    }
+
+    pub fn frame_len(&self) -> usize {
+        self.frame_len
+    }
+
+    // Encoder delay in samples at 'sample_rate', the pre-skip of container formats.
+    pub fn lookahead(&mut self) -> crate::Result<usize> {
+        Ok(self.encoder.get_lookahead()? as usize)
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    // A mono packet fills both channels of a stereo encoder.
+    pub fn push(&mut self, packet: &AudioPacket) {
+        let len = packet.samples.len() / packet.n_ch;
+        for i in 0..len {
+            for ch in 0..self.n_ch {
+                self.pending
+                    .push(packet.channel(ch.min(packet.n_ch - 1))[i]);
+            }
+        }
+    }
+
+    // Append 'len' samples of silence per channel, e.g. to complete the last frame.
+    pub fn pad(&mut self, len: usize) {
+        self.pending.resize(self.pending.len() + len * self.n_ch, 0);
+    }
+
+    // The next encoded frame, once enough samples were pushed.
+    pub fn next_frame(&mut self) -> crate::Result<Option<Vec<u8>>> {
+        let frame_len = self.frame_len * self.n_ch;
+        if self.pending.len() < frame_len {
+            return Ok(None);
+        }
+        let mut encoded = [0_u8; MAX_OPUS_PACKET];
+        let len = self
+            .encoder
+            .encode(&self.pending[..frame_len], &mut encoded)?;
+        self.pending.drain(..frame_len);
+        Ok(Some(encoded[..len].to_vec()))
+    }
+}
@@ -0,0 +1,224 @@
+use crate::audio::pool::BufferPool;
+use crate::audio::CaptureSource;
+use crate::config_file::Config;
+use crate::metrics::{Metrics, METRICS};
+use crate::packet::Packetizer;
+use crate::PACKET_N_SAMPLE;
+use pipewire as pw;
+use pw::properties::properties;
+use pw::spa;
+use pw::spa::param::audio::{AudioFormat, AudioInfoRaw};
+use pw::spa::pod::{serialize::PodSerializer, Object, Pod, Value};
+use pw::stream::{Stream, StreamFlags, StreamState};
+use std::future::Future;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+// Packets waiting between the process callback and the packetizer task.
+const CAPTURE_QUEUE_LEN: usize = 16;
+
+// Quits the pipewire main loop.
+struct Terminate;
+
+// Collects the interleaved buffers of the process callback into planar packets of
+// 'PACKET_N_SAMPLE' samples per channel.
+struct PacketAssembler {
+    n_ch: usize,
+    n_frame: usize,
+    planar: Vec<i16>,
+    pool: Arc<BufferPool>,
+    tx: mpsc::Sender<Vec<i16>>,
+}
+
+impl PacketAssembler {
+    fn push(&mut self, data: &[u8]) {
+        for frame in data.chunks_exact(self.n_ch * 2) {
+            for (ch, sample) in frame.chunks_exact(2).enumerate() {
+                self.planar[ch * PACKET_N_SAMPLE + self.n_frame] =
+                    i16::from_le_bytes([sample[0], sample[1]]);
+            }
+            self.n_frame += 1;
+            if self.n_frame == PACKET_N_SAMPLE {
+                self.n_frame = 0;
+                let packet = std::mem::take(&mut self.planar);
+                match self.tx.try_send(packet) {
+                    Ok(()) => self.planar = self.pool.take(self.n_ch * PACKET_N_SAMPLE),
+                    // keep filling the packet that didn't fit
+                    Err(err) => {
+                        self.planar = err.into_inner();
+                        Metrics::inc(&METRICS.capture_overruns);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Capture through a pipewire stream node named "mic2net" of 'mic.n_channel' channels at
+// 'mic.sample_rate' (pipewire converts whatever the source has), asking for a quantum of
+// 'mic.period' frames, and publish it until 'shutdown' completes. 'mic.device_name' is
+// the node name or serial of the source to link to, "default" leaves that to the
+// session manager; with the loopback source a sink's monitor is recorded instead. The
+// node can be re-linked in graph editors (qpwgraph, helvum) while it runs, and when its
+// source goes away the session manager moves it to another one.
+pub async fn start_pipewire_capture(
+    cfg: Arc<Config>,
+    mut packetizer: Packetizer,
+    shutdown: impl Future,
+) -> crate::Result<()> {
+    let n_ch = cfg.mic.n_channel;
+    let pool = BufferPool::new(CAPTURE_QUEUE_LEN + 1, n_ch * PACKET_N_SAMPLE);
+    let (tx, mut packets) = mpsc::channel(CAPTURE_QUEUE_LEN);
+    let (quit, quit_receiver) = pw::channel::channel();
+    let assembler = PacketAssembler {
+        n_ch,
+        n_frame: 0,
+        planar: pool.take(n_ch * PACKET_N_SAMPLE),
+        pool: pool.clone(),
+        tx,
+    };
+    // the main loop isn't Send, it lives on a thread of its own
+    let cfg_cp = cfg.clone();
+    let main_loop = thread::Builder::new()
+        .name("pipewire".to_string())
+        .spawn(move || run_main_loop(&cfg_cp, assembler, quit_receiver))?;
+
+    let mut audio_data = Vec::with_capacity(n_ch * PACKET_N_SAMPLE * 2);
+    let publish = async {
+        while let Some(packet) = packets.recv().await {
+            audio_data.clear();
+            audio_data.extend(packet.iter().flat_map(|sample| sample.to_ne_bytes()));
+            pool.put(packet);
+            packetizer.publish(&audio_data);
+        }
+    };
+    let stopped = tokio::select! {
+        _ = publish => false,
+        _ = shutdown => {
+            info!("shutting down capture");
+            true
+        }
+    };
+    // the loop may have ended on its own already, after logging why
+    let _ = quit.send(Terminate);
+    tokio::task::spawn_blocking(move || main_loop.join())
+        .await?
+        .map_err(|_| crate::Error::Capture("pipewire thread panicked".into()))??;
+    if stopped {
+        Ok(())
+    } else {
+        Err(crate::Error::Capture("pipewire stream ended".into()))
+    }
+}
+
+fn run_main_loop(
+    cfg: &Config,
+    assembler: PacketAssembler,
+    quit: pw::channel::Receiver<Terminate>,
+) -> crate::Result<()> {
+    let main_loop = pw::main_loop::MainLoop::new(None)?;
+    let context = pw::context::Context::new(&main_loop)?;
+    let core = context.connect(None)?;
+
+    let mut props = properties! {
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_CATEGORY => "Capture",
+        *pw::keys::MEDIA_ROLE => "Communication",
+        *pw::keys::NODE_NAME => "mic2net",
+        *pw::keys::NODE_DESCRIPTION => "mic2net capture",
+        *pw::keys::APP_NAME => "mic2net",
+        *pw::keys::NODE_LATENCY => format!("{}/{}", cfg.mic.period, cfg.mic.sample_rate),
+    };
+    if !cfg.mic.device_name.eq_ignore_ascii_case("default") {
+        props.insert(*pw::keys::TARGET_OBJECT, cfg.mic.device_name.as_str());
+    }
+    if cfg.mic.source == CaptureSource::Loopback {
+        props.insert(*pw::keys::STREAM_CAPTURE_SINK, "true");
+    }
+    let stream = Stream::new(&core, "mic2net", props)?;
+
+    let _listener = stream
+        .add_local_listener_with_user_data(assembler)
+        .state_changed({
+            let main_loop = main_loop.clone();
+            move |_, _, _, state| match state {
+                StreamState::Streaming => info!("pipewire stream running"),
+                StreamState::Paused => info!("pipewire stream paused"),
+                StreamState::Error(err) => {
+                    error!("pipewire stream error: {}", err);
+                    main_loop.quit();
+                }
+                _ => {}
+            }
+        })
+        .process(|stream, assembler| match stream.dequeue_buffer() {
+            None => warn!("pipewire ran out of buffers"),
+            Some(mut buffer) => {
+                let Some(data) = buffer.datas_mut().first_mut() else {
+                    return;
+                };
+                let offset = data.chunk().offset() as usize;
+                let size = data.chunk().size() as usize;
+                if let Some(samples) = data.data() {
+                    let end = (offset + size).min(samples.len());
+                    assembler.push(&samples[offset.min(end)..end]);
+                }
+            }
+        })
+        .register()?;
+
+    let format = format_param(cfg.mic.n_channel, cfg.mic.sample_rate)?;
+    let mut params = [Pod::from_bytes(&format)
+        .ok_or_else(|| crate::Error::Capture("bad pipewire format".into()))?];
+    stream.connect(
+        spa::utils::Direction::Input,
+        None,
+        StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS | StreamFlags::RT_PROCESS,
+        &mut params,
+    )?;
+    info!(
+        "capturing through pipewire: {} channels, {} Hz",
+        cfg.mic.n_channel, cfg.mic.sample_rate
+    );
+
+    let _quit = quit.attach(main_loop.loop_(), {
+        let main_loop = main_loop.clone();
+        move |_| main_loop.quit()
+    });
+    main_loop.run();
+    Ok(())
+}
+
+// The one format the stream takes: interleaved s16le, 'n_ch' channels at 'sample_rate'.
+fn format_param(n_ch: usize, sample_rate: usize) -> crate::Result<Vec<u8>> {
+    let mut info = AudioInfoRaw::new();
+    info.set_format(AudioFormat::S16LE);
+    info.set_rate(sample_rate as u32);
+    info.set_channels(n_ch as u32);
+    let mut position = [0; 64];
+    match n_ch {
+        1 => position[0] = spa::sys::SPA_AUDIO_CHANNEL_MONO,
+        2 => {
+            position[0] = spa::sys::SPA_AUDIO_CHANNEL_FL;
+            position[1] = spa::sys::SPA_AUDIO_CHANNEL_FR;
+        }
+        // microphone arrays have no speaker positions
+        _ => {
+            for (ch, position) in position.iter_mut().take(n_ch).enumerate() {
+                *position = spa::sys::SPA_AUDIO_CHANNEL_AUX0 + ch as u32;
+            }
+        }
+    }
+    info.set_position(position);
+    let object = Object {
+        type_: spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
+        id: spa::param::ParamType::EnumFormat.as_raw(),
+        properties: info.into(),
+    };
+    let (bytes, _) = PodSerializer::serialize(Cursor::new(Vec::new()), &Value::Object(object))
+        .map_err(|err| crate::Error::Capture(format!("pipewire format: {:?}", err)))?;
+    Ok(bytes.into_inner())
+}
@@ -0,0 +1,48 @@
+use crate::metrics::{Metrics, METRICS};
+use std::sync::{Arc, Mutex};
+
+// Sample buffers going round between the capture callbacks and the task that mixes
+// their packets, so a callback doesn't allocate every period (400 times a second at
+// 2.5 ms periods, per input).
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<i16>>>,
+    // buffers beyond this are freed instead of kept
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    // 'n_buffers' buffers of 'capacity' samples, ready to be taken.
+    pub fn new(n_buffers: usize, capacity: usize) -> Arc<BufferPool> {
+        Arc::new(BufferPool {
+            buffers: Mutex::new(
+                (0..n_buffers)
+                    .map(|_| Vec::with_capacity(capacity))
+                    .collect(),
+            ),
+            max_buffers: n_buffers,
+        })
+    }
+
+    // A zeroed buffer of 'len' samples. Never waits for the lock, since it's called
+    // from audio callbacks; allocates if it is taken or the pool is empty.
+    pub fn take(&self, len: usize) -> Vec<i16> {
+        let pooled = match self.buffers.try_lock() {
+            Ok(mut buffers) => buffers.pop(),
+            Err(_) => None,
+        };
+        let mut buf = pooled.unwrap_or_else(|| {
+            Metrics::inc(&METRICS.buffer_pool_exhausted);
+            Vec::with_capacity(len)
+        });
+        buf.clear();
+        buf.resize(len, 0);
+        buf
+    }
+
+    pub fn put(&self, buf: Vec<i16>) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buf);
+        }
+    }
+}
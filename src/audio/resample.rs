@@ -0,0 +1,270 @@
+use crate::distributor::{Distributor, DropPolicy};
+use crate::protocol::{Frame, FrameKind, StreamInfo};
+use crate::{HEADER_LEN, PACKET_N_SAMPLE};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::f64::consts::PI;
+use std::sync::Arc;
+use tracing::info;
+
+// zero crossings of the sinc kernel on each side of the output sample
+const HALF_TAPS: usize = 16;
+// packets buffered between the capture distributor and the resampler
+const RESAMPLE_QUEUE_LEN: usize = 50;
+
+// Windowed-sinc sample rate converter for planar audio; history is kept between
+// calls so packet boundaries don't click.
+pub struct Resampler {
+    // input samples advanced per output sample
+    step: f64,
+    // lowpass cutoff relative to the input Nyquist frequency; < 1 when downsampling
+    cutoff: f64,
+    // position of the next output sample, in input samples from the start of 'history'
+    pos: f64,
+    history: Vec<Vec<f32>>,
+}
+
+impl Resampler {
+    pub fn new(n_ch: usize, in_rate: usize, out_rate: usize) -> Resampler {
+        Resampler {
+            step: in_rate as f64 / out_rate as f64,
+            cutoff: (out_rate as f64 / in_rate as f64).min(1.0),
+            pos: HALF_TAPS as f64,
+            history: vec![vec![0.0; HALF_TAPS]; n_ch],
+        }
+    }
+
+    // Feed one block per channel; the converted samples are appended to 'output'.
+    pub fn process(&mut self, input: &[&[i16]], output: &mut [Vec<i16>]) {
+        let (cutoff, mut pos) = (self.cutoff, self.pos);
+        for ((history, samples), out) in self.history.iter_mut().zip(input).zip(output) {
+            history.extend(samples.iter().map(|&s| s as f32));
+            pos = self.pos;
+            while pos + (HALF_TAPS as f64) < history.len() as f64 {
+                out.push(interpolate(history, pos, cutoff));
+                pos += self.step;
+            }
+        }
+
+        // keep what the kernel still needs for the next output sample
+        let consumed = (pos.floor() as usize).saturating_sub(HALF_TAPS);
+        for history in self.history.iter_mut() {
+            history.drain(..consumed);
+        }
+        self.pos = pos - consumed as f64;
+    }
+}
+
+fn interpolate(history: &[f32], pos: f64, cutoff: f64) -> i16 {
+    let center = pos.floor() as usize;
+    let first = center + 1 - HALF_TAPS;
+    let acc: f64 = history[first..=center + HALF_TAPS]
+        .iter()
+        .enumerate()
+        .map(|(i, &sample)| sample as f64 * kernel(pos - (first + i) as f64, cutoff))
+        .sum();
+    acc.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+// Hann-windowed sinc scaled to keep unity gain at DC.
+fn kernel(x: f64, cutoff: f64) -> f64 {
+    let width = HALF_TAPS as f64;
+    if x.abs() >= width {
+        return 0.0;
+    }
+    let window = 0.5 * (1.0 + (PI * x / width).cos());
+    let arg = PI * cutoff * x;
+    let sinc = if arg == 0.0 { 1.0 } else { arg.sin() / arg };
+    cutoff * sinc * window
+}
+
+// Turns packets at the capture rate into packets of 'PACKET_N_SAMPLE' samples per
+// channel at the output rate. Header fields come from the newest input packet, except
+// the packet id, which counts output packets.
+struct PacketResampler {
+    n_ch: usize,
+    resampler: Resampler,
+    pending: Vec<Vec<i16>>,
+    pkt_id: u32,
+}
+
+impl PacketResampler {
+    fn new(n_ch: usize, in_rate: usize, out_rate: usize) -> PacketResampler {
+        PacketResampler {
+            n_ch,
+            resampler: Resampler::new(n_ch, in_rate, out_rate),
+            pending: vec![Vec::new(); n_ch],
+            pkt_id: 0,
+        }
+    }
+
+    fn push(&mut self, packet: &[u8]) -> Vec<Bytes> {
+        if packet.len() != HEADER_LEN + PACKET_N_SAMPLE * self.n_ch * 2 {
+            return Vec::new();
+        }
+        let samples: Vec<i16> = packet[HEADER_LEN..]
+            .chunks_exact(2)
+            .map(|b| i16::from_ne_bytes([b[0], b[1]]))
+            .collect();
+        let channels: Vec<&[i16]> = samples.chunks_exact(PACKET_N_SAMPLE).collect();
+        self.resampler.process(&channels, &mut self.pending);
+
+        let mut packets = Vec::new();
+        while self.pending[0].len() >= PACKET_N_SAMPLE {
+            let mut out = BytesMut::with_capacity(packet.len());
+            // device id and capture time
+            out.extend_from_slice(&packet[..8]);
+            out.put_u32(self.pkt_id);
+            for ch in self.pending.iter_mut() {
+                for sample in ch.drain(..PACKET_N_SAMPLE) {
+                    out.extend_from_slice(&sample.to_ne_bytes());
+                }
+            }
+            packets.push(out.freeze());
+            self.pkt_id = self.pkt_id.wrapping_add(1);
+        }
+        packets
+    }
+}
+
+// Distributor carrying the frames of 'input' converted to 'out_rate'; 'input' itself
+// when it has that rate already. Outputs subscribe to it like to the capture one.
+pub fn resampled(input: &Arc<Distributor>, out_rate: usize) -> Arc<Distributor> {
+    let stream = input.stream_info();
+    let in_rate = stream.sample_rate;
+    if in_rate == out_rate {
+        return input.clone();
+    }
+    info!("resampling {} Hz to {} Hz", in_rate, out_rate);
+    // same pre-roll duration as the input
+    let output = Distributor::new(
+        StreamInfo {
+            sample_rate: out_rate,
+            ..stream
+        },
+        input.history_len() * out_rate / in_rate,
+    );
+    let mut frames = input.subscribe(RESAMPLE_QUEUE_LEN, DropPolicy::DropNewest);
+    let mut packet_resampler = PacketResampler::new(stream.n_ch, in_rate, out_rate);
+
+    let output_cp = output.clone();
+    tokio::spawn(async move {
+        let mut seq = 0_u32;
+        while let Some(frame) = frames.recv().await {
+            if frame.kind == FrameKind::Silence {
+                output_cp.publish(Frame { seq, ..frame }).await;
+                seq = seq.wrapping_add(1);
+                continue;
+            }
+            for payload in packet_resampler.push(&frame.payload) {
+                output_cp
+                    .publish(Frame::audio(seq, frame.timestamp, payload))
+                    .await;
+                seq = seq.wrapping_add(1);
+            }
+        }
+    });
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f64, rate: usize, len: usize, amplitude: f64) -> Vec<i16> {
+        (0..len)
+            .map(|i| (amplitude * (2.0 * PI * frequency * i as f64 / rate as f64).sin()) as i16)
+            .collect()
+    }
+
+    // 'input' resampled, fed in blocks of 'block' samples.
+    fn resample(input: &[i16], in_rate: usize, out_rate: usize, block: usize) -> Vec<i16> {
+        let mut resampler = Resampler::new(1, in_rate, out_rate);
+        let mut output = vec![Vec::new()];
+        for block in input.chunks(block) {
+            resampler.process(&[block], &mut output);
+        }
+        output.pop().unwrap()
+    }
+
+    fn rms(samples: &[i16]) -> f64 {
+        let sum: f64 = samples.iter().map(|&s| (s as f64).powi(2)).sum();
+        (sum / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn output_follows_the_rate_ratio() {
+        let input = vec![0; 48_000];
+        for out_rate in [8_000, 16_000, 44_100, 96_000] {
+            let expected = 48_000 * out_rate / 48_000;
+            let len = resample(&input, 48_000, out_rate, 480).len();
+            // short of the end by the kernel's lookahead
+            assert!(
+                len <= expected && len + 2 * HALF_TAPS >= expected,
+                "{}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn block_size_makes_no_difference() {
+        let input = sine(440.0, 48_000, 9_600, 10_000.0);
+        let whole = resample(&input, 48_000, 16_000, input.len());
+        for block in [1, 7, 160, 1000] {
+            assert_eq!(resample(&input, 48_000, 16_000, block), whole, "{}", block);
+        }
+    }
+
+    #[test]
+    fn dc_keeps_its_level() {
+        let output = resample(&[1000; 4800], 48_000, 16_000, 160);
+        for sample in &output[2 * HALF_TAPS..] {
+            assert!((sample - 1000).abs() <= 2, "{}", sample);
+        }
+    }
+
+    #[test]
+    fn passband_sine_survives() {
+        let output = resample(&sine(1000.0, 48_000, 48_000, 10_000.0), 48_000, 16_000, 160);
+        let expected = sine(1000.0, 16_000, output.len(), 10_000.0);
+        let skip = 2 * HALF_TAPS;
+        for (i, (out, expected)) in output.iter().zip(&expected).enumerate().skip(skip) {
+            assert!(
+                (out - expected).abs() <= 100,
+                "{}: {} vs {}",
+                i,
+                out,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn downsampling_filters_what_wont_fit() {
+        // above 8 kHz, the nyquist frequency of 16 kHz
+        let input = sine(12_000.0, 48_000, 48_000, 10_000.0);
+        let output = resample(&input, 48_000, 16_000, 160);
+        assert!(
+            rms(&output[2 * HALF_TAPS..]) < 0.05 * rms(&input),
+            "{}",
+            rms(&output)
+        );
+    }
+
+    #[test]
+    fn packets_come_out_at_the_output_rate() {
+        let n_ch = 2;
+        let mut resampler = PacketResampler::new(n_ch, 48_000, 16_000);
+        let mut packet = vec![0_u8; HEADER_LEN + PACKET_N_SAMPLE * n_ch * 2];
+        packet[..8].copy_from_slice(b"dev-time");
+        let packets: Vec<Bytes> = (0..30).flat_map(|_| resampler.push(&packet)).collect();
+        // 30 packets in, a third of them out, less the kernel's lookahead
+        assert_eq!(packets.len(), 9);
+        for (id, out) in packets.iter().enumerate() {
+            assert_eq!(out.len(), packet.len());
+            assert_eq!(&out[..8], b"dev-time");
+            assert_eq!(out[8..12], (id as u32).to_be_bytes());
+        }
+        assert!(resampler.push(&packet[1..]).is_empty());
+    }
+}
@@ -0,0 +1,244 @@
+// Wait-free single-producer single-consumer ring, for handing samples from a real-time
+// audio callback to a task: neither side allocates, locks or waits for the other, and
+// the consumer polls instead of being woken, which would go through the runtime's
+// scheduler from the audio thread.
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Keeps the two positions on separate cache lines, so the sides don't contend for one.
+#[repr(align(64))]
+struct Padded(AtomicUsize);
+
+// Positions run from 0 to twice the capacity, so a full ring can be told from an empty
+// one without a spare slot and any capacity works.
+struct Shared<T> {
+    buf: Box<[UnsafeCell<T>]>,
+    // next slot to read, written by the consumer only
+    head: Padded,
+    // next slot to write, written by the producer only
+    tail: Padded,
+}
+
+impl<T> Shared<T> {
+    fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn len(&self, head: usize, tail: usize) -> usize {
+        (tail + 2 * self.capacity() - head) % (2 * self.capacity())
+    }
+
+    fn advance(&self, position: usize, n: usize) -> usize {
+        (position + n) % (2 * self.capacity())
+    }
+
+    // The 'n' slots from 'position' on, split where they wrap around. The caller makes
+    // sure the other side doesn't touch them meanwhile.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn slots(&self, position: usize, n: usize) -> (&mut [T], &mut [T]) {
+        let start = position % self.capacity();
+        let first = n.min(self.capacity() - start);
+        // UnsafeCell<T> has the layout of T
+        let base = self.buf.as_ptr() as *mut T;
+        (
+            std::slice::from_raw_parts_mut(base.add(start), first),
+            std::slice::from_raw_parts_mut(base, n - first),
+        )
+    }
+}
+
+// The two ends of a ring of 'capacity' values.
+pub fn channel<T: Copy + Default>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let shared = Arc::new(Shared {
+        buf: (0..capacity.max(1))
+            .map(|_| UnsafeCell::new(T::default()))
+            .collect(),
+        head: Padded(AtomicUsize::new(0)),
+        tail: Padded(AtomicUsize::new(0)),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+            head: 0,
+            tail: 0,
+        },
+        Consumer {
+            shared,
+            head: 0,
+            tail: 0,
+        },
+    )
+}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+    // own position, and the consumer's as last seen
+    tail: usize,
+    head: usize,
+}
+
+// Each end is used by one thread at a time; the positions hand the slots over.
+unsafe impl<T: Send> Send for Producer<T> {}
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+impl<T> Producer<T> {
+    // Hand 'write' the next 'n' slots, in two parts since they may wrap around, and
+    // publish them; false, without calling it, when fewer are free.
+    pub fn write(&mut self, n: usize, write: impl FnOnce(&mut [T], &mut [T])) -> bool {
+        let capacity = self.shared.capacity();
+        if capacity - self.shared.len(self.head, self.tail) < n {
+            self.head = self.shared.head.0.load(Ordering::Acquire);
+            if capacity - self.shared.len(self.head, self.tail) < n {
+                return false;
+            }
+        }
+        // the consumer reads none of them before 'tail' moves past them
+        let (first, second) = unsafe { self.shared.slots(self.tail, n) };
+        write(first, second);
+        self.tail = self.shared.advance(self.tail, n);
+        self.shared.tail.0.store(self.tail, Ordering::Release);
+        true
+    }
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+    // own position, and the producer's as last seen
+    head: usize,
+    tail: usize,
+}
+
+impl<T> Consumer<T> {
+    // Values ready to be read.
+    pub fn len(&mut self) -> usize {
+        self.tail = self.shared.tail.0.load(Ordering::Acquire);
+        self.shared.len(self.head, self.tail)
+    }
+
+    pub fn is_empty(&mut self) -> bool {
+        self.len() == 0
+    }
+
+    // Hand 'read' the next 'n' values, in two parts since they may wrap around, and
+    // free their slots; false, without calling it, when fewer are ready.
+    pub fn read(&mut self, n: usize, read: impl FnOnce(&[T], &[T])) -> bool {
+        if self.shared.len(self.head, self.tail) < n && self.len() < n {
+            return false;
+        }
+        // the producer writes none of them before 'head' moves past them
+        let (first, second) = unsafe { self.shared.slots(self.head, n) };
+        read(first, second);
+        self.head = self.shared.advance(self.head, n);
+        self.shared.head.0.store(self.head, Ordering::Release);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(producer: &mut Producer<u32>, values: &[u32]) -> bool {
+        producer.write(values.len(), |first, second| {
+            let (a, b) = values.split_at(first.len());
+            first.copy_from_slice(a);
+            second.copy_from_slice(b);
+        })
+    }
+
+    fn pop(consumer: &mut Consumer<u32>, n: usize) -> Option<Vec<u32>> {
+        let mut values = Vec::new();
+        consumer
+            .read(n, |first, second| values.extend(first.iter().chain(second)))
+            .then_some(values)
+    }
+
+    #[test]
+    fn full_rings_refuse_writes() {
+        let (mut producer, mut consumer) = channel(4);
+        assert!(consumer.is_empty());
+        assert!(push(&mut producer, &[1, 2, 3]));
+        assert!(!push(&mut producer, &[4, 5]));
+        assert!(push(&mut producer, &[4]));
+        assert_eq!(consumer.len(), 4);
+        assert!(!push(&mut producer, &[5]));
+        assert!(!push(&mut producer, &[0; 5]));
+
+        assert_eq!(pop(&mut consumer, 5), None);
+        assert_eq!(pop(&mut consumer, 2), Some(vec![1, 2]));
+        assert!(push(&mut producer, &[5, 6]));
+        assert_eq!(pop(&mut consumer, 4), Some(vec![3, 4, 5, 6]));
+        assert!(consumer.is_empty());
+    }
+
+    #[test]
+    fn slots_split_where_they_wrap() {
+        let (mut producer, mut consumer) = channel(5);
+        assert!(push(&mut producer, &[1, 2, 3]));
+        assert_eq!(pop(&mut consumer, 3), Some(vec![1, 2, 3]));
+        assert!(producer.write(3, |first, second| {
+            assert_eq!((first.len(), second.len()), (2, 1));
+            first.copy_from_slice(&[4, 5]);
+            second.copy_from_slice(&[6]);
+        }));
+        assert!(consumer.read(3, |first, second| {
+            assert_eq!((first, second), (&[4, 5][..], &[6][..]));
+        }));
+    }
+
+    #[test]
+    fn positions_wrap_around_many_times() {
+        // an odd capacity, so positions and slots wrap at different times
+        let (mut producer, mut consumer) = channel(7);
+        let mut next = 0;
+        for round in 0..1000 {
+            let n = round % 7 + 1;
+            let values: Vec<u32> = (next..next + n as u32).collect();
+            assert!(push(&mut producer, &values));
+            assert_eq!(pop(&mut consumer, n), Some(values));
+            next += n as u32;
+        }
+        assert!(consumer.is_empty());
+    }
+
+    #[test]
+    fn zero_capacity_holds_one() {
+        let (mut producer, mut consumer) = channel(0);
+        assert!(push(&mut producer, &[1]));
+        assert!(!push(&mut producer, &[2]));
+        assert_eq!(pop(&mut consumer, 1), Some(vec![1]));
+    }
+
+    #[test]
+    fn values_cross_threads_in_order() {
+        const N: u32 = 100_000;
+        let (mut producer, mut consumer) = channel(64);
+        let writer = std::thread::spawn(move || {
+            let mut next = 0;
+            while next < N {
+                let n = (N - next).min(5);
+                let values: Vec<u32> = (next..next + n).collect();
+                if push(&mut producer, &values) {
+                    next += n;
+                } else {
+                    std::thread::yield_now();
+                }
+            }
+        });
+        let mut expected = 0;
+        while expected < N {
+            let n = consumer.len().min(3);
+            match pop(&mut consumer, n) {
+                Some(values) if n > 0 => {
+                    for value in values {
+                        assert_eq!(value, expected);
+                        expected += 1;
+                    }
+                }
+                _ => std::thread::yield_now(),
+            }
+        }
+        writer.join().unwrap();
+    }
+}
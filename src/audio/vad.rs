@@ -0,0 +1,106 @@
+use crate::config_file::VadConfig;
+use crate::PACKET_N_SAMPLE;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use tracing::debug;
+
+// The [vad] settings every gate follows, replaced when the config is reloaded.
+pub static VAD: VadSettings = VadSettings {
+    enable: AtomicBool::new(false),
+    on_threshold: AtomicU32::new(0),
+    off_threshold: AtomicU32::new(0),
+    hangover: AtomicU64::new(0),
+};
+
+// thresholds as f32 bits
+pub struct VadSettings {
+    enable: AtomicBool,
+    on_threshold: AtomicU32,
+    off_threshold: AtomicU32,
+    hangover: AtomicU64,
+}
+
+impl VadSettings {
+    pub fn configure(&self, cfg: &VadConfig) {
+        self.enable.store(cfg.enable, Ordering::Relaxed);
+        self.on_threshold
+            .store(cfg.on_threshold.to_bits(), Ordering::Relaxed);
+        self.off_threshold
+            .store(cfg.off_threshold.to_bits(), Ordering::Relaxed);
+        self.hangover.store(cfg.hangover, Ordering::Relaxed);
+    }
+
+    // Whether the capture withholds silent packets, [vad] 'enable'. The "vad" stage of
+    // [pipeline] gates either way.
+    pub fn enabled(&self) -> bool {
+        self.enable.load(Ordering::Relaxed)
+    }
+
+    fn on_threshold(&self) -> f32 {
+        f32::from_bits(self.on_threshold.load(Ordering::Relaxed))
+    }
+
+    fn off_threshold(&self) -> f32 {
+        f32::from_bits(self.off_threshold.load(Ordering::Relaxed)).min(self.on_threshold())
+    }
+}
+
+// Energy-based voice activity gate with hysteresis: opens when a packet reaches
+// 'on_threshold', and closes once packets stay below 'off_threshold' for 'hangover'.
+pub struct Vad {
+    sample_rate: usize,
+    remaining: usize,
+    active: bool,
+}
+
+impl Vad {
+    // Gating packets at 'sample_rate' by the settings in 'VAD'.
+    pub fn new(sample_rate: usize) -> Vad {
+        Vad {
+            sample_rate,
+            remaining: 0,
+            // start open so clients get audio right after connecting
+            active: true,
+        }
+    }
+
+    // Update the gate with one packet of native-endian i16 samples; false means the
+    // packet is part of a silence and doesn't need to be sent.
+    pub fn is_active(&mut self, audio_data: &[u8]) -> bool {
+        let level = level_dbfs(audio_data);
+        if level >= VAD.on_threshold() {
+            if !self.active {
+                debug!(level, "voice activity");
+            }
+            self.active = true;
+            self.remaining = self.hangover_packets();
+        } else if self.active && level < VAD.off_threshold() {
+            if self.remaining == 0 {
+                debug!(level, "silence");
+                self.active = false;
+            } else {
+                self.remaining -= 1;
+            }
+        }
+        self.active
+    }
+
+    fn hangover_packets(&self) -> usize {
+        VAD.hangover.load(Ordering::Relaxed) as usize * self.sample_rate / 1000 / PACKET_N_SAMPLE
+    }
+}
+
+// RMS level of all samples in dB relative to full scale; -inf for digital silence.
+pub fn level_dbfs(audio_data: &[u8]) -> f32 {
+    let n_sample = audio_data.len() / 2;
+    if n_sample == 0 {
+        return f32::NEG_INFINITY;
+    }
+    let sum_sq: f64 = audio_data
+        .chunks_exact(2)
+        .map(|b| {
+            let sample = i16::from_ne_bytes([b[0], b[1]]) as f64 / i16::MAX as f64;
+            sample * sample
+        })
+        .sum();
+    10.0 * (sum_sq / n_sample as f64).log10() as f32
+}
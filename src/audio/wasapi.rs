@@ -0,0 +1,237 @@
+use crate::audio::format::Quantizer;
+use crate::audio::pool::BufferPool;
+use crate::audio::CaptureSource;
+use crate::config_file::Config;
+use crate::metrics::{Metrics, METRICS};
+use crate::packet::Packetizer;
+use crate::PACKET_N_SAMPLE;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use wasapi::{
+    calculate_period_100ns, initialize_mta, Device, DeviceEnumerator, Direction, SampleType,
+    StreamMode, WaveFormat,
+};
+
+// Packets waiting between the capture thread and the packetizer task.
+const CAPTURE_QUEUE_LEN: usize = 16;
+// Sample layouts tried in exclusive mode, where the device has to take one as it is:
+// (bits stored, valid bits). Wider ones are cut down to their top 16 bits.
+const EXCLUSIVE_FORMATS: [(usize, usize); 3] = [(16, 16), (32, 24), (32, 32)];
+
+// "default", an index into the device list or part of a friendly name; loopback picks
+// among output devices.
+fn find_device(selector: &str, direction: &Direction) -> crate::Result<Device> {
+    let enumerator = DeviceEnumerator::new()?;
+    if selector.eq_ignore_ascii_case("default") {
+        return Ok(enumerator.get_default_device(direction)?);
+    }
+    let devices = enumerator.get_device_collection(direction)?;
+    let n_devices = devices.get_nbr_devices()?;
+    if let Ok(idx) = selector.parse::<u32>() {
+        if idx < n_devices {
+            return Ok(devices.get_device_at_index(idx)?);
+        }
+    }
+    for idx in 0..n_devices {
+        let device = devices.get_device_at_index(idx)?;
+        if device.get_friendlyname()?.contains(selector) {
+            return Ok(device);
+        }
+    }
+    Err(crate::Error::Capture(format!(
+        "no wasapi device \"{}\"",
+        selector
+    )))
+}
+
+// Capture until 'stop' is set, sending planar packets of 'PACKET_N_SAMPLE' samples per
+// channel to 'tx'.
+fn read_packets(
+    cfg: &Config,
+    pool: Arc<BufferPool>,
+    tx: mpsc::Sender<Vec<i16>>,
+    stop: &AtomicBool,
+) -> crate::Result<()> {
+    initialize_mta()
+        .ok()
+        .map_err(|err| crate::Error::Capture(err.to_string()))?;
+    let loopback = cfg.mic.source == CaptureSource::Loopback;
+    let direction = if loopback {
+        Direction::Render
+    } else {
+        Direction::Capture
+    };
+    let device = find_device(&cfg.mic.device_name, &direction)?;
+    let name = device.get_friendlyname()?;
+    let mut exclusive = cfg
+        .wasapi
+        .exclusive
+        .iter()
+        .any(|selector| *selector == cfg.mic.device_name || name.contains(selector.as_str()));
+    if exclusive && loopback {
+        warn!(
+            "wasapi can't record a loopback exclusively, sharing \"{}\"",
+            name
+        );
+        exclusive = false;
+    }
+
+    let n_ch = cfg.mic.n_channel;
+    let rate = cfg.mic.sample_rate;
+    let mut client = device.get_iaudioclient()?;
+    let (format, mode) = if exclusive {
+        let format = EXCLUSIVE_FORMATS
+            .iter()
+            .find_map(|(stored, valid)| {
+                let format = WaveFormat::new(*stored, *valid, &SampleType::Int, rate, n_ch, None);
+                client.is_supported_exclusive_with_quirks(&format).ok()
+            })
+            .ok_or_else(|| {
+                crate::Error::Capture(format!(
+                    "\"{}\" takes no {} channel, {} Hz pcm exclusively",
+                    name, n_ch, rate
+                ))
+            })?;
+        // as close to 'mic.period' as the device goes
+        let period = client.calculate_aligned_period_near(
+            calculate_period_100ns(cfg.mic.period as i64, rate as i64),
+            None,
+            &format,
+        )?;
+        (format, StreamMode::EventsExclusive { period_hns: period })
+    } else {
+        // the engine converts to what was asked for, at the period of its own
+        let (_, min_period) = client.get_device_period()?;
+        let format = WaveFormat::new(16, 16, &SampleType::Int, rate, n_ch, None);
+        let mode = StreamMode::EventsShared {
+            autoconvert: true,
+            buffer_duration_hns: min_period,
+        };
+        (format, mode)
+    };
+    client.initialize_client(&format, &Direction::Capture, &mode)?;
+    let event = client.set_get_eventhandle()?;
+    let capture = client.get_audiocaptureclient()?;
+    let buffer_size = client.get_buffer_size()? as usize;
+    let frame_bytes = format.get_blockalign() as usize;
+    let sample_bytes = frame_bytes / n_ch;
+    info!(
+        "capturing from wasapi \"{}\"{}: {} channels, {} Hz, {} bit, {} mode, {:.1} ms buffered",
+        name,
+        if loopback { " (loopback)" } else { "" },
+        n_ch,
+        rate,
+        sample_bytes * 8,
+        if exclusive { "exclusive" } else { "shared" },
+        buffer_size as f64 * 1000.0 / rate as f64
+    );
+
+    let mut data = vec![0_u8; buffer_size * frame_bytes];
+    // 32-bit containers, with 24 or 32 valid bits
+    let mut quantizer = (cfg.mic.dither && sample_bytes == 4).then(|| Quantizer::new(true));
+    let mut planar = pool.take(n_ch * PACKET_N_SAMPLE);
+    let mut n_frame = 0;
+    client.start_stream()?;
+    while !stop.load(Ordering::Relaxed) {
+        // a loopback gets no events while nothing plays
+        if event.wait_for_event(100).is_err() {
+            continue;
+        }
+        loop {
+            let (n_frames, buffer_info) = capture.read_from_device(&mut data)?;
+            if n_frames == 0 {
+                break;
+            }
+            if buffer_info.flags.data_discontinuity {
+                Metrics::inc(&METRICS.xruns);
+            }
+            let frames = &mut data[..n_frames as usize * frame_bytes];
+            if buffer_info.flags.silent {
+                frames.fill(0);
+            }
+
+            for frame in frames.chunks_exact(frame_bytes) {
+                // little endian, the top 16 bits are the last two bytes
+                for (ch, sample) in frame.chunks_exact(sample_bytes).enumerate() {
+                    planar[ch * PACKET_N_SAMPLE + n_frame] = match &mut quantizer {
+                        Some(quantizer) => quantizer.quantize(
+                            i32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]) as f32
+                                / 2_147_483_648.0,
+                        ),
+                        None => {
+                            i16::from_le_bytes([sample[sample_bytes - 2], sample[sample_bytes - 1]])
+                        }
+                    };
+                }
+                n_frame += 1;
+                if n_frame == PACKET_N_SAMPLE {
+                    n_frame = 0;
+                    let packet = std::mem::take(&mut planar);
+                    match tx.try_send(packet) {
+                        Ok(()) => planar = pool.take(n_ch * PACKET_N_SAMPLE),
+                        // keep filling the packet that didn't fit
+                        Err(err) => {
+                            planar = err.into_inner();
+                            Metrics::inc(&METRICS.capture_overruns);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    client.stop_stream()?;
+    Ok(())
+}
+
+// Capture 'mic.device_name' through wasapi, bypassing cpal, and publish it until
+// 'shutdown' completes. Devices matching [wasapi] 'exclusive' are opened in exclusive
+// mode, which skips the engine's mixer and resampler and runs at about 'mic.period'
+// frames per period; the others, and loopbacks, are shared.
+pub async fn start_wasapi_capture(
+    cfg: Arc<Config>,
+    mut packetizer: Packetizer,
+    shutdown: impl Future,
+) -> crate::Result<()> {
+    let n_ch = cfg.mic.n_channel;
+    let pool = BufferPool::new(CAPTURE_QUEUE_LEN + 1, n_ch * PACKET_N_SAMPLE);
+    let (tx, mut packets) = mpsc::channel(CAPTURE_QUEUE_LEN);
+    let stop = Arc::new(AtomicBool::new(false));
+    // com objects stay on the thread that made them
+    let reader = {
+        let (cfg, pool, stop) = (cfg.clone(), pool.clone(), stop.clone());
+        thread::Builder::new()
+            .name("wasapi".to_string())
+            .spawn(move || read_packets(&cfg, pool, tx, &stop))?
+    };
+
+    let mut audio_data = Vec::with_capacity(n_ch * PACKET_N_SAMPLE * 2);
+    let publish = async {
+        while let Some(packet) = packets.recv().await {
+            audio_data.clear();
+            audio_data.extend(packet.iter().flat_map(|sample| sample.to_ne_bytes()));
+            pool.put(packet);
+            packetizer.publish(&audio_data);
+        }
+    };
+    let stopped = tokio::select! {
+        _ = publish => false,
+        _ = shutdown => {
+            info!("shutting down capture");
+            true
+        }
+    };
+    // the reader notices within a period, or after the event wait times out
+    stop.store(true, Ordering::Relaxed);
+    tokio::task::spawn_blocking(move || reader.join())
+        .await?
+        .map_err(|_| crate::Error::Capture("wasapi thread panicked".into()))??;
+    if stopped {
+        Ok(())
+    } else {
+        Err(crate::Error::Capture("wasapi capture ended".into()))
+    }
+}
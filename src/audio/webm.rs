@@ -0,0 +1,647 @@
+use crate::audio::ogg::opus_head;
+use crate::audio::opus::OpusFramer;
+use crate::audio::vad::Vad;
+use crate::tcp_client::AudioPacket;
+use ring::rand::{generate, SystemRandom};
+
+// ebml element ids, with their length marker
+const EBML: u32 = 0x1A45DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const VOID: u32 = 0xEC;
+const SEGMENT: u32 = 0x18538067;
+const SEEK_HEAD: u32 = 0x114D9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+const INFO: u32 = 0x1549A966;
+const TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const DURATION: u32 = 0x4489;
+const TRACKS: u32 = 0x1654AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_LACING: u32 = 0x9C;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const CODEC_DELAY: u32 = 0x56AA;
+const SEEK_PRE_ROLL: u32 = 0x56BB;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+const CLUSTER: u32 = 0x1F43B675;
+const CLUSTER_TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const BLOCK_GROUP: u32 = 0xA0;
+const BLOCK: u32 = 0xA1;
+const DISCARD_PADDING: u32 = 0x75A2;
+const CUES: u32 = 0x1C53BB6B;
+const CUE_POINT: u32 = 0xBB;
+const CUE_TIME: u32 = 0xB3;
+const CUE_TRACK_POSITIONS: u32 = 0xB7;
+const CUE_TRACK: u32 = 0xF7;
+const CUE_CLUSTER_POSITION: u32 = 0xF1;
+
+// timestamps count milliseconds
+const TIMESTAMP_SCALE_NS: u64 = 1_000_000;
+// block timestamps are 16-bit offsets from their cluster's
+const CLUSTER_MS: u64 = 5000;
+const SEEK_PRE_ROLL_NS: u64 = 80_000_000;
+// reserved after the segment start for the seek head of a finished file
+const SEEK_HEAD_SPACE: usize = 96;
+// an 8-byte float element, or the void holding its place
+const DURATION_LEN: usize = 11;
+// all ones in an 8-byte size
+const UNKNOWN_SIZE: u64 = (1 << 56) - 1;
+const TRACK: u64 = 1;
+// what opus always decodes to
+const OPUS_RATE: usize = 48000;
+const APP: &str = "mic2net";
+
+// Opus in WebM, which browsers play natively. Live streams ('live') send every block as
+// soon as it is encoded, in clusters of unknown size; files get sized clusters, cues
+// and, through 'final_header', a seek head and their duration. With a 'vad' each
+// transition between voice and silence starts a cluster and becomes a cue point (to
+// within one opus frame), so players can jump from one utterance to the next; without
+// one every cluster is cued.
+pub struct WebmOpusEncoder {
+    framer: OpusFramer,
+    live: bool,
+    vad: Option<Vad>,
+    // the vad's state after the last packet
+    voice: bool,
+    // the next frame starts a cued cluster
+    cue_pending: bool,
+    track_uid: u64,
+    // samples per channel of encoder delay, at the stream's rate
+    lookahead: u64,
+    // bytes of the segment's body so far, what cue and seek positions count from
+    position: u64,
+    cluster: Option<Cluster>,
+    // cue times and the positions of their clusters
+    cues: Vec<(u64, u64)>,
+    cues_position: Option<u64>,
+    // samples per channel pushed / encoded, at the stream's rate
+    n_pushed: u64,
+    n_encoded: u64,
+}
+
+struct Cluster {
+    timestamp: u64,
+    // what isn't written out yet
+    body: Vec<u8>,
+}
+
+impl WebmOpusEncoder {
+    pub fn new(
+        sample_rate: usize,
+        n_ch: usize,
+        live: bool,
+        vad: Option<Vad>,
+    ) -> crate::Result<WebmOpusEncoder> {
+        let uid = generate::<[u8; 8]>(&SystemRandom::new())
+            .map_err(|_| "no randomness for a track uid")?
+            .expose();
+        let mut framer = OpusFramer::new(sample_rate, n_ch, opus::Application::Audio)?;
+        let lookahead = framer.lookahead()? as u64;
+        Ok(WebmOpusEncoder {
+            framer,
+            live,
+            vad,
+            voice: true,
+            cue_pending: false,
+            track_uid: u64::from_ne_bytes(uid).max(1),
+            lookahead,
+            position: 0,
+            cluster: None,
+            cues: Vec::new(),
+            cues_position: None,
+            n_pushed: 0,
+            n_encoded: 0,
+        })
+    }
+
+    pub fn n_channel(&self) -> usize {
+        self.framer.n_channel()
+    }
+
+    // The ebml header and the start of the segment up to its tracks, of unknown length.
+    pub fn header(&mut self) -> crate::Result<Vec<u8>> {
+        let (header, body_len) = self.segment_header(false);
+        self.position = body_len as u64;
+        Ok(header)
+    }
+
+    // Append the clusters 'packet' completed (any blocks, when live) to 'out'.
+    pub fn encode(&mut self, packet: &AudioPacket, out: &mut Vec<u8>) -> crate::Result<()> {
+        if let Some(vad) = &mut self.vad {
+            let audio_data: Vec<u8> = packet
+                .samples
+                .iter()
+                .flat_map(|s| s.to_ne_bytes())
+                .collect();
+            let voice = vad.is_active(&audio_data);
+            if voice != self.voice {
+                self.voice = voice;
+                self.cue_pending = true;
+            }
+        }
+        self.framer.push(packet);
+        self.n_pushed += (packet.samples.len() / packet.n_ch) as u64;
+        while let Some(encoded) = self.framer.next_frame()? {
+            self.write_frame(&encoded, None, out);
+        }
+        Ok(())
+    }
+
+    // Append the encoder's delayed tail, the last cluster and the cues to 'out'; nothing
+    // may be encoded after.
+    pub fn finish(&mut self, out: &mut Vec<u8>) -> crate::Result<()> {
+        let frame_len = self.framer.frame_len() as u64;
+        let end = self.n_pushed + self.lookahead;
+        let n_frames = end
+            .saturating_sub(self.n_encoded)
+            .div_ceil(frame_len)
+            .max(1);
+        let pending = self.n_pushed - self.n_encoded;
+        self.framer.pad((n_frames * frame_len - pending) as usize);
+        for i in 0..n_frames {
+            let encoded = self
+                .framer
+                .next_frame()?
+                .ok_or_else(|| crate::Error::Codec("opus frame missing".into()))?;
+            if i + 1 < n_frames {
+                self.write_frame(&encoded, None, out);
+                continue;
+            }
+            // players drop what the last block decodes to beyond the end of the audio
+            let padding = (self.n_encoded + frame_len).saturating_sub(end);
+            let padding_ns = padding * 1_000_000_000 / self.framer.sample_rate() as u64;
+            self.write_frame(&encoded, Some(padding_ns), out);
+        }
+        self.close_cluster(out);
+
+        if !self.cues.is_empty() {
+            let mut cues = Vec::new();
+            for (time, position) in &self.cues {
+                let mut track_positions = Vec::new();
+                uint(CUE_TRACK, TRACK, &mut track_positions);
+                uint(CUE_CLUSTER_POSITION, *position, &mut track_positions);
+                let mut cue_point = Vec::new();
+                uint(CUE_TIME, *time, &mut cue_point);
+                element(CUE_TRACK_POSITIONS, &track_positions, &mut cue_point);
+                element(CUE_POINT, &cue_point, &mut cues);
+            }
+            let start = out.len();
+            element(CUES, &cues, out);
+            self.cues_position = Some(self.position);
+            self.position += (out.len() - start) as u64;
+        }
+        Ok(())
+    }
+
+    // What a finished file's header is rewritten with: the same length as 'header', with
+    // the segment's size, a seek head and the duration.
+    pub fn final_header(&self) -> crate::Result<Vec<u8>> {
+        Ok(self.segment_header(true).0)
+    }
+
+    fn write_frame(&mut self, encoded: &[u8], padding_ns: Option<u64>, out: &mut Vec<u8>) {
+        let timestamp = self.to_ms(self.n_encoded);
+        let new_cluster = match &self.cluster {
+            Some(cluster) => self.cue_pending || timestamp - cluster.timestamp >= CLUSTER_MS,
+            None => true,
+        };
+        if new_cluster {
+            self.close_cluster(out);
+            if self.cue_pending || self.vad.is_none() || self.n_encoded == 0 {
+                self.cues.push((timestamp, self.position));
+            }
+            self.cue_pending = false;
+            let mut body = Vec::new();
+            if self.live {
+                id(CLUSTER, out);
+                size8(UNKNOWN_SIZE, out);
+                self.position += 12;
+            }
+            uint(CLUSTER_TIMESTAMP, timestamp, &mut body);
+            self.cluster = Some(Cluster { timestamp, body });
+        }
+        let cluster = self.cluster.as_mut().unwrap();
+
+        let mut block = Vec::with_capacity(encoded.len() + 4);
+        // track number as a 1-byte vint
+        block.push(0x80 | TRACK as u8);
+        block.extend_from_slice(&((timestamp - cluster.timestamp) as i16).to_be_bytes());
+        match padding_ns {
+            None => {
+                // keyframe
+                block.push(0x80);
+                block.extend_from_slice(encoded);
+                element(SIMPLE_BLOCK, &block, &mut cluster.body);
+            }
+            Some(padding_ns) => {
+                block.push(0);
+                block.extend_from_slice(encoded);
+                let mut group = Vec::new();
+                element(BLOCK, &block, &mut group);
+                element(
+                    DISCARD_PADDING,
+                    &(padding_ns as i64).to_be_bytes(),
+                    &mut group,
+                );
+                element(BLOCK_GROUP, &group, &mut cluster.body);
+            }
+        }
+        self.n_encoded += self.framer.frame_len() as u64;
+        if self.live {
+            self.position += cluster.body.len() as u64;
+            out.append(&mut cluster.body);
+        }
+    }
+
+    fn close_cluster(&mut self, out: &mut Vec<u8>) {
+        if let Some(cluster) = self.cluster.take() {
+            if !self.live {
+                let start = out.len();
+                element(CLUSTER, &cluster.body, out);
+                self.position += (out.len() - start) as u64;
+            }
+        }
+    }
+
+    // Returns the header and how much of it belongs to the segment's body.
+    fn segment_header(&self, finished: bool) -> (Vec<u8>, usize) {
+        let mut ebml = Vec::new();
+        uint(EBML_VERSION, 1, &mut ebml);
+        uint(EBML_READ_VERSION, 1, &mut ebml);
+        uint(EBML_MAX_ID_LENGTH, 4, &mut ebml);
+        uint(EBML_MAX_SIZE_LENGTH, 8, &mut ebml);
+        string(DOC_TYPE, "webm", &mut ebml);
+        // discard padding came with version 4
+        uint(DOC_TYPE_VERSION, 4, &mut ebml);
+        uint(DOC_TYPE_READ_VERSION, 2, &mut ebml);
+
+        let mut info = Vec::new();
+        uint(TIMESTAMP_SCALE, TIMESTAMP_SCALE_NS, &mut info);
+        string(MUXING_APP, APP, &mut info);
+        string(WRITING_APP, APP, &mut info);
+        if finished {
+            let duration = self.n_pushed as f64 * 1000.0 / self.framer.sample_rate() as f64;
+            element(DURATION, &duration.to_be_bytes(), &mut info);
+        } else {
+            void(DURATION_LEN, &mut info);
+        }
+        let mut info_element = Vec::new();
+        element(INFO, &info, &mut info_element);
+
+        let sample_rate = self.framer.sample_rate();
+        let mut audio = Vec::new();
+        element(
+            SAMPLING_FREQUENCY,
+            &(OPUS_RATE as f64).to_be_bytes(),
+            &mut audio,
+        );
+        uint(CHANNELS, self.n_channel() as u64, &mut audio);
+        let mut track = Vec::new();
+        uint(TRACK_NUMBER, TRACK, &mut track);
+        uint(TRACK_UID, self.track_uid, &mut track);
+        // audio
+        uint(TRACK_TYPE, 2, &mut track);
+        uint(FLAG_LACING, 0, &mut track);
+        string(CODEC_ID, "A_OPUS", &mut track);
+        let pre_skip = self.lookahead as usize * OPUS_RATE / sample_rate;
+        let head = opus_head(self.n_channel(), pre_skip, sample_rate);
+        element(CODEC_PRIVATE, &head, &mut track);
+        let codec_delay = self.lookahead * 1_000_000_000 / sample_rate as u64;
+        uint(CODEC_DELAY, codec_delay, &mut track);
+        uint(SEEK_PRE_ROLL, SEEK_PRE_ROLL_NS, &mut track);
+        element(AUDIO, &audio, &mut track);
+        let mut tracks = Vec::new();
+        element(TRACK_ENTRY, &track, &mut tracks);
+        let mut tracks_element = Vec::new();
+        element(TRACKS, &tracks, &mut tracks_element);
+
+        let mut seek_head = Vec::new();
+        if finished {
+            let info_position = SEEK_HEAD_SPACE as u64;
+            let tracks_position = info_position + info_element.len() as u64;
+            let mut seeks = Vec::new();
+            let targets = [(INFO, Some(info_position)), (TRACKS, Some(tracks_position))];
+            for (target, position) in targets.into_iter().chain([(CUES, self.cues_position)]) {
+                let Some(position) = position else {
+                    continue;
+                };
+                let mut seek = Vec::new();
+                element(SEEK_ID, &target.to_be_bytes(), &mut seek);
+                uint(SEEK_POSITION, position, &mut seek);
+                element(SEEK, &seek, &mut seeks);
+            }
+            element(SEEK_HEAD, &seeks, &mut seek_head);
+        }
+        void(SEEK_HEAD_SPACE - seek_head.len(), &mut seek_head);
+
+        let mut header = Vec::new();
+        element(EBML, &ebml, &mut header);
+        id(SEGMENT, &mut header);
+        // 8 bytes either way, so the header can be rewritten in place
+        size8(
+            if finished {
+                self.position
+            } else {
+                UNKNOWN_SIZE
+            },
+            &mut header,
+        );
+        let body_start = header.len();
+        header.append(&mut seek_head);
+        header.append(&mut info_element);
+        header.append(&mut tracks_element);
+        let body_len = header.len() - body_start;
+        (header, body_len)
+    }
+
+    fn to_ms(&self, n_samples: u64) -> u64 {
+        n_samples * 1000 / self.framer.sample_rate() as u64
+    }
+}
+
+fn id(id: u32, out: &mut Vec<u8>) {
+    let skip = id.leading_zeros() as usize / 8;
+    out.extend_from_slice(&id.to_be_bytes()[skip..]);
+}
+
+// As a vint of the fewest bytes.
+fn size(len: u64, out: &mut Vec<u8>) {
+    let mut n_bytes = 1;
+    // all ones is reserved for "unknown"
+    while n_bytes < 8 && len >= (1 << (7 * n_bytes)) - 1 {
+        n_bytes += 1;
+    }
+    let vint = len | 1 << (7 * n_bytes);
+    out.extend_from_slice(&vint.to_be_bytes()[8 - n_bytes..]);
+}
+
+// As a vint of 8 bytes, whatever it is.
+fn size8(len: u64, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(len | 1 << 56).to_be_bytes());
+}
+
+fn element(element_id: u32, body: &[u8], out: &mut Vec<u8>) {
+    id(element_id, out);
+    size(body.len() as u64, out);
+    out.extend_from_slice(body);
+}
+
+fn uint(element_id: u32, value: u64, out: &mut Vec<u8>) {
+    let skip = (value.leading_zeros() as usize / 8).min(7);
+    element(element_id, &value.to_be_bytes()[skip..], out);
+}
+
+fn string(element_id: u32, value: &str, out: &mut Vec<u8>) {
+    element(element_id, value.as_bytes(), out);
+}
+
+// 'len' bytes of nothing, 2 to 128 of them.
+fn void(len: usize, out: &mut Vec<u8>) {
+    id(VOID, out);
+    size(len as u64 - 2, out);
+    out.resize(out.len() + len - 2, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PACKET_N_SAMPLE;
+
+    // The vint at the start of 'data' and its length; the marker bit stays for ids.
+    fn vint(data: &[u8], keep_marker: bool) -> (u64, usize) {
+        let len = data[0].leading_zeros() as usize + 1;
+        assert!(len <= 8 && data.len() >= len, "bad vint");
+        let mut value = 0;
+        for byte in &data[..len] {
+            value = value << 8 | *byte as u64;
+        }
+        if !keep_marker {
+            value &= !(1 << (7 * len));
+        }
+        (value, len)
+    }
+
+    // The elements 'data' consists of, as id and body; fails unless their sizes add up
+    // to exactly its length. An element of unknown size runs to the end.
+    fn elements(mut data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut out = Vec::new();
+        while !data.is_empty() {
+            let (element_id, id_len) = vint(data, true);
+            let (len, size_len) = vint(&data[id_len..], false);
+            let start = id_len + size_len;
+            let end = if len == UNKNOWN_SIZE {
+                data.len()
+            } else {
+                start + len as usize
+            };
+            assert!(
+                end <= data.len(),
+                "element {:x} runs past its parent",
+                element_id
+            );
+            out.push((element_id as u32, &data[start..end]));
+            data = &data[end..];
+        }
+        out
+    }
+
+    fn ids(elements: &[(u32, &[u8])]) -> Vec<u32> {
+        elements.iter().map(|(element_id, _)| *element_id).collect()
+    }
+
+    fn child(data: &[u8], element_id: u32) -> &[u8] {
+        elements(data)
+            .into_iter()
+            .find(|(found, _)| *found == element_id)
+            .unwrap_or_else(|| panic!("no element {:x}", element_id))
+            .1
+    }
+
+    fn be_uint(body: &[u8]) -> u64 {
+        body.iter().fold(0, |value, byte| value << 8 | *byte as u64)
+    }
+
+    fn packet(i: usize) -> AudioPacket {
+        AudioPacket {
+            device_id: 0,
+            secs: 0,
+            millis: 0,
+            pkt_id: i as u32,
+            n_ch: 1,
+            samples: (0..PACKET_N_SAMPLE)
+                .map(|n| (((i * PACKET_N_SAMPLE + n) % 64) as i16 - 32) * 256)
+                .collect(),
+            timestamp: Default::default(),
+        }
+    }
+
+    // A finished file of 'n_packets', its header rewritten as the sink does.
+    fn finished_file(n_packets: usize) -> Vec<u8> {
+        let mut encoder = WebmOpusEncoder::new(48000, 1, false, None).unwrap();
+        let mut out = encoder.header().unwrap();
+        let header_len = out.len();
+        for i in 0..n_packets {
+            encoder.encode(&packet(i), &mut out).unwrap();
+        }
+        encoder.finish(&mut out).unwrap();
+        let final_header = encoder.final_header().unwrap();
+        assert_eq!(final_header.len(), header_len);
+        out[..header_len].copy_from_slice(&final_header);
+        out
+    }
+
+    #[test]
+    fn vints_take_the_fewest_bytes() {
+        let sized = |len| {
+            let mut out = Vec::new();
+            size(len, &mut out);
+            out
+        };
+        assert_eq!(sized(0), [0x80]);
+        assert_eq!(sized(126), [0xfe]);
+        // 127 in one byte would read as unknown
+        assert_eq!(sized(127), [0x40, 0x7f]);
+        assert_eq!(sized(16382), [0x7f, 0xfe]);
+        assert_eq!(sized(16383), [0x20, 0x3f, 0xff]);
+
+        let mut out = Vec::new();
+        size8(5, &mut out);
+        assert_eq!(out, [0x01, 0, 0, 0, 0, 0, 0, 5]);
+        out.clear();
+        size8(UNKNOWN_SIZE, &mut out);
+        assert_eq!(out, [0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+
+        out.clear();
+        id(VOID, &mut out);
+        id(SEGMENT, &mut out);
+        assert_eq!(out, [0xec, 0x18, 0x53, 0x80, 0x67]);
+    }
+
+    #[test]
+    fn elements_are_sized_exactly() {
+        let mut out = Vec::new();
+        uint(TRACK_NUMBER, 0, &mut out);
+        assert_eq!(out, [0xd7, 0x81, 0]);
+        out.clear();
+        uint(TIMESTAMP_SCALE, TIMESTAMP_SCALE_NS, &mut out);
+        assert_eq!(out, [0x2a, 0xd7, 0xb1, 0x83, 0x0f, 0x42, 0x40]);
+        for len in 2..=128 {
+            let mut out = Vec::new();
+            void(len, &mut out);
+            assert_eq!(out.len(), len);
+            assert_eq!(ids(&elements(&out)), [VOID]);
+        }
+    }
+
+    #[test]
+    fn finished_file_walks() {
+        // 7 s at 48 kHz: two clusters
+        let file = finished_file(7 * 48000 / PACKET_N_SAMPLE);
+        let top = elements(&file);
+        assert_eq!(ids(&top), [EBML, SEGMENT]);
+        assert_eq!(child(top[0].1, DOC_TYPE), b"webm");
+        let segment = top[1].1;
+        // the size in the rewritten header is the real one
+        assert_eq!(
+            be_uint(&file[file.len() - segment.len() - 7..][..7]),
+            segment.len() as u64
+        );
+
+        let children = elements(segment);
+        assert_eq!(
+            ids(&children),
+            [SEEK_HEAD, VOID, INFO, TRACKS, CLUSTER, CLUSTER, CUES]
+        );
+        let info = child(segment, INFO);
+        let duration = f64::from_be_bytes(child(info, DURATION).try_into().unwrap());
+        assert_eq!(duration, 7000.0);
+        let track = child(child(segment, TRACKS), TRACK_ENTRY);
+        assert_eq!(child(track, CODEC_ID), b"A_OPUS");
+        assert_eq!(&child(track, CODEC_PRIVATE)[..8], b"OpusHead");
+
+        // seek positions count from the start of the segment's body
+        let seeks = elements(child(segment, SEEK_HEAD));
+        assert_eq!(seeks.len(), 3);
+        for (_, seek) in seeks {
+            let target = child(seek, SEEK_ID);
+            let position = be_uint(child(seek, SEEK_POSITION)) as usize;
+            assert_eq!(&segment[position..position + target.len()], target);
+        }
+
+        // every cue points at a cluster of its time
+        let cues = elements(child(segment, CUES));
+        assert_eq!(cues.len(), 2);
+        for (_, cue_point) in cues {
+            let time = be_uint(child(cue_point, CUE_TIME));
+            let positions = child(cue_point, CUE_TRACK_POSITIONS);
+            let position = be_uint(child(positions, CUE_CLUSTER_POSITION)) as usize;
+            let (element_id, cluster) = elements(&segment[position..])[0];
+            assert_eq!(element_id, CLUSTER);
+            assert_eq!(be_uint(child(cluster, CLUSTER_TIMESTAMP)), time);
+        }
+    }
+
+    #[test]
+    fn blocks_follow_on_and_the_last_is_padded() {
+        let file = finished_file(100);
+        let segment = elements(&file)[1].1;
+        let cluster = child(segment, CLUSTER);
+        let blocks = elements(cluster);
+        assert_eq!(blocks[0].0, CLUSTER_TIMESTAMP);
+        let (last, simple) = blocks[1..].split_last().unwrap();
+        for (n, (element_id, block)) in simple.iter().enumerate() {
+            assert_eq!(*element_id, SIMPLE_BLOCK);
+            // track 1, 20 ms a frame, keyframe
+            assert_eq!(block[0], 0x81);
+            assert_eq!(i16::from_be_bytes([block[1], block[2]]), n as i16 * 20);
+            assert_eq!(block[3], 0x80);
+        }
+
+        assert_eq!(last.0, BLOCK_GROUP);
+        let group = last.1;
+        let block = child(group, BLOCK);
+        let timestamp = i16::from_be_bytes([block[1], block[2]]);
+        assert_eq!(timestamp, simple.len() as i16 * 20);
+        let padding = i64::from_be_bytes(child(group, DISCARD_PADDING).try_into().unwrap());
+        assert!((0..20_000_000).contains(&padding), "{}", padding);
+    }
+
+    #[test]
+    fn live_clusters_have_unknown_size() {
+        let mut encoder = WebmOpusEncoder::new(48000, 1, true, None).unwrap();
+        let header = encoder.header().unwrap();
+        let top = elements(&header);
+        assert_eq!(ids(&top), [EBML, SEGMENT]);
+        assert_eq!(ids(&elements(top[1].1)), [VOID, INFO, TRACKS]);
+
+        let mut out = Vec::new();
+        for i in 0..12 {
+            encoder.encode(&packet(i), &mut out).unwrap();
+        }
+        assert_eq!(&out[..4], &CLUSTER.to_be_bytes());
+        assert_eq!(
+            &out[4..12],
+            [0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
+        );
+        let cluster = elements(&out)[0].1;
+        let blocks = elements(cluster);
+        assert_eq!(blocks[0].0, CLUSTER_TIMESTAMP);
+        // 12 packets of 160 samples make 2 frames of 960, sent right away
+        assert_eq!(ids(&blocks[1..]), [SIMPLE_BLOCK, SIMPLE_BLOCK]);
+    }
+}
@@ -0,0 +1,322 @@
+use crate::protocol::ReceiveReport;
+use crate::rate_limit::Bandwidth;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+// Live counters of one connection, updated by its handler after every write.
+pub struct ClientStats {
+    peer: String,
+    connected_at: SystemTime,
+    connected: Instant,
+    bytes_sent: AtomicU64,
+    frames_sent: AtomicU64,
+    frames_dropped: AtomicU64,
+    // µs the last write took; grows once the client's socket buffer is full
+    last_write: AtomicU64,
+    // the client's latest receive report
+    report: Mutex<Option<ReceiveReport>>,
+    bandwidth: Arc<Bandwidth>,
+    kick: Notify,
+    // for handlers that check between sends rather than wait on 'kicked'
+    kicked: AtomicBool,
+}
+
+impl ClientStats {
+    // Resolves once the client was kicked; its handler should hang up.
+    pub async fn kicked(&self) {
+        self.kick.notified().await
+    }
+
+    pub fn is_kicked(&self) -> bool {
+        self.kicked.load(Ordering::Relaxed)
+    }
+
+    pub fn frame_sent(&self, n_bytes: usize, write_time: Duration, frames_dropped: u64) {
+        self.bytes_sent.fetch_add(n_bytes as u64, Ordering::Relaxed);
+        self.frames_sent.fetch_add(1, Ordering::Relaxed);
+        self.frames_dropped.store(frames_dropped, Ordering::Relaxed);
+        self.last_write
+            .store(write_time.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn receive_report(&self, report: ReceiveReport) {
+        *self.report.lock().unwrap() = Some(report);
+    }
+
+    // What the client's writer is held back to.
+    pub fn bandwidth(&self) -> &Arc<Bandwidth> {
+        &self.bandwidth
+    }
+
+    // Whether the client is at 'addr', either ip:port or just the ip.
+    fn is_at(&self, addr: &str) -> bool {
+        let ip = self
+            .peer
+            .rsplit_once(':')
+            .map_or(self.peer.as_str(), |(ip, _)| ip);
+        self.peer == addr || ip.trim_start_matches('[').trim_end_matches(']') == addr
+    }
+
+    pub fn snapshot(&self) -> ClientSnapshot {
+        ClientSnapshot {
+            peer: self.peer.clone(),
+            connected_at: self
+                .connected_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            connected_secs: self.connected.elapsed().as_secs(),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            frames_sent: self.frames_sent.load(Ordering::Relaxed),
+            frames_dropped: self.frames_dropped.load(Ordering::Relaxed),
+            last_write_us: self.last_write.load(Ordering::Relaxed),
+            received: *self.report.lock().unwrap(),
+            bandwidth_limit: self.bandwidth.limit(),
+        }
+    }
+}
+
+// Point-in-time copy of 'ClientStats', e.g. for the admin API.
+#[derive(Serialize, Clone, Debug)]
+pub struct ClientSnapshot {
+    pub peer: String,
+    // unix seconds
+    pub connected_at: u64,
+    pub connected_secs: u64,
+    pub bytes_sent: u64,
+    pub frames_sent: u64,
+    pub frames_dropped: u64,
+    pub last_write_us: u64,
+    // what arrived at the client, as it last reported; None for clients that don't.
+    // Losses the server knows nothing of, e.g. on a flaky wifi link, show up here
+    pub received: Option<ReceiveReport>,
+    // bytes per second; 0 for no limit
+    pub bandwidth_limit: u64,
+}
+
+// The connected clients of one server and how many it may have. Handlers hold a
+// 'ClientEntry', which takes the client off the list when dropped.
+pub struct ClientRegistry {
+    next_id: AtomicU64,
+    clients: Mutex<BTreeMap<u64, Arc<ClientStats>>>,
+    limit_connections: Arc<Semaphore>,
+    limit: Arc<Mutex<Limit>>,
+    // bytes per second for clients without a limit of their own; 0 for none
+    bandwidth_limit: Arc<AtomicU64>,
+}
+
+struct Limit {
+    max_clients: usize,
+    // slots taken away from a lowered limit while the clients in them were still
+    // connected; freed slots pay it off before going back
+    deficit: usize,
+}
+
+// A client slot from 'ClientRegistry::acquire', given back when dropped.
+pub struct ClientSlot {
+    permit: Option<OwnedSemaphorePermit>,
+    limit: Arc<Mutex<Limit>>,
+}
+
+pub struct ClientEntry {
+    id: u64,
+    stats: Arc<ClientStats>,
+    registry: Arc<ClientRegistry>,
+}
+
+impl ClientRegistry {
+    pub fn new(max_clients: usize) -> Arc<ClientRegistry> {
+        Arc::new(ClientRegistry {
+            next_id: AtomicU64::new(0),
+            clients: Mutex::new(BTreeMap::new()),
+            limit_connections: Arc::new(Semaphore::new(max_clients)),
+            limit: Arc::new(Mutex::new(Limit {
+                max_clients,
+                deficit: 0,
+            })),
+            bandwidth_limit: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    // Wait for a free client slot.
+    pub async fn acquire(&self) -> ClientSlot {
+        let permit = self
+            .limit_connections
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap();
+        ClientSlot {
+            permit: Some(permit),
+            limit: self.limit.clone(),
+        }
+    }
+
+    pub fn max_clients(&self) -> usize {
+        self.limit.lock().unwrap().max_clients
+    }
+
+    // Connected clients beyond a lowered limit stay; their slots vanish as they leave.
+    // Limits beyond u16::MAX, the most the config takes, are capped.
+    pub fn set_max_clients(&self, max_clients: usize) {
+        let max_clients = max_clients.min(u16::MAX.into());
+        let mut limit = self.limit.lock().unwrap();
+        if max_clients > limit.max_clients {
+            let added = max_clients - limit.max_clients;
+            let paid = added.min(limit.deficit);
+            limit.deficit -= paid;
+            self.limit_connections.add_permits(added - paid);
+        } else if max_clients < limit.max_clients {
+            let excess = limit.max_clients - max_clients;
+            limit.deficit += excess - self.limit_connections.forget_permits(excess);
+        }
+        limit.max_clients = max_clients;
+    }
+
+    pub fn bandwidth_limit(&self) -> u64 {
+        self.bandwidth_limit.load(Ordering::Relaxed)
+    }
+
+    // Applies to connected clients too, unless they have a limit of their own.
+    pub fn set_bandwidth_limit(&self, bytes_per_sec: u64) {
+        self.bandwidth_limit.store(bytes_per_sec, Ordering::Relaxed);
+    }
+
+    pub fn register(self: &Arc<Self>, peer: &str) -> ClientEntry {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stats = Arc::new(ClientStats {
+            peer: peer.to_string(),
+            connected_at: SystemTime::now(),
+            connected: Instant::now(),
+            bytes_sent: AtomicU64::new(0),
+            frames_sent: AtomicU64::new(0),
+            frames_dropped: AtomicU64::new(0),
+            last_write: AtomicU64::new(0),
+            report: Mutex::new(None),
+            bandwidth: Arc::new(Bandwidth::new(self.bandwidth_limit.clone())),
+            kick: Notify::new(),
+            kicked: AtomicBool::new(false),
+        });
+        self.clients.lock().unwrap().insert(id, stats.clone());
+        ClientEntry {
+            id,
+            stats,
+            registry: self.clone(),
+        }
+    }
+
+    // In connection order.
+    pub fn snapshot(&self) -> Vec<ClientSnapshot> {
+        self.clients
+            .lock()
+            .unwrap()
+            .values()
+            .map(|stats| stats.snapshot())
+            .collect()
+    }
+
+    // Kick the clients at 'addr', either ip:port or just the ip; returns how many.
+    // Their handlers send a goodbye frame and hang up.
+    pub fn kick(&self, addr: &str) -> usize {
+        let clients = self.clients.lock().unwrap();
+        let mut n_kicked = 0;
+        for stats in clients.values().filter(|stats| stats.is_at(addr)) {
+            stats.kicked.store(true, Ordering::Relaxed);
+            stats.kick.notify_one();
+            n_kicked += 1;
+        }
+        n_kicked
+    }
+
+    // Give the clients at 'addr' a bandwidth limit of their own, or None for the
+    // server's again; returns how many.
+    pub fn limit_bandwidth(&self, addr: &str, bytes_per_sec: Option<u64>) -> usize {
+        let clients = self.clients.lock().unwrap();
+        let mut n_limited = 0;
+        for stats in clients.values().filter(|stats| stats.is_at(addr)) {
+            stats.bandwidth.set(bytes_per_sec);
+            n_limited += 1;
+        }
+        n_limited
+    }
+}
+
+impl ClientEntry {
+    pub fn stats(&self) -> &ClientStats {
+        &self.stats
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut limit = self.limit.lock().unwrap();
+        if let Some(permit) = self.permit.take() {
+            if limit.deficit > 0 {
+                limit.deficit -= 1;
+                permit.forget();
+            }
+        }
+    }
+}
+
+impl Drop for ClientEntry {
+    fn drop(&mut self) {
+        self.registry.clients.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn available(registry: &ClientRegistry) -> usize {
+        registry.limit_connections.available_permits()
+    }
+
+    #[tokio::test]
+    async fn lowered_limit_takes_slots_as_clients_leave() {
+        let registry = ClientRegistry::new(3);
+        let slots = vec![
+            registry.acquire().await,
+            registry.acquire().await,
+            registry.acquire().await,
+        ];
+        registry.set_max_clients(1);
+        drop(slots);
+        assert_eq!(available(&registry), 1);
+    }
+
+    #[tokio::test]
+    async fn raised_limit_pays_off_the_deficit() {
+        let registry = ClientRegistry::new(3);
+        let slots = vec![registry.acquire().await, registry.acquire().await];
+        registry.set_max_clients(0);
+        assert_eq!(available(&registry), 0);
+        registry.set_max_clients(4);
+        assert_eq!(available(&registry), 2);
+        drop(slots);
+        assert_eq!(available(&registry), 4);
+    }
+
+    #[tokio::test]
+    async fn huge_limit_is_capped() {
+        let registry = ClientRegistry::new(1);
+        registry.set_max_clients(usize::MAX);
+        assert_eq!(registry.max_clients(), u16::MAX as usize);
+        assert_eq!(available(&registry), u16::MAX as usize);
+    }
+
+    #[test]
+    fn kick_marks_only_matching_clients() {
+        let registry = ClientRegistry::new(2);
+        let a = registry.register("10.0.0.1:4000");
+        let b = registry.register("10.0.0.2:4000");
+        assert_eq!(registry.kick("10.0.0.1"), 1);
+        assert!(a.stats().is_kicked());
+        assert!(!b.stats().is_kicked());
+    }
+}
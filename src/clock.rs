@@ -0,0 +1,84 @@
+// NTP-like estimate of how far a server's clocks are from the local ones, so clients
+// can convert frame timestamps to local time (e.g. to start playback in several rooms
+// at once). Each exchange on the control channel yields four times:
+//
+//   t0  client sends a time request      (the request's header timestamp)
+//   t1  server receives it               (echoed with t0 in the response payload)
+//   t2  server sends the response        (the response's header timestamp)
+//   t3  client receives the response
+//
+// offset = ((t1 - t0) + (t2 - t3)) / 2 and round trip = (t3 - t0) - (t2 - t1), for the
+// wall and the monotonic clock alike. Queueing behind audio frames is what skews an
+// exchange, so of the recent ones the shortest round trip is trusted.
+use crate::protocol::{Frame, FrameKind, Timestamp};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// exchanges the estimate is picked from
+const MAX_SAMPLES: usize = 8;
+
+// Server clock minus local clock, in µs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockOffset {
+    pub wall_us: i64,
+    pub mono_us: i64,
+    pub round_trip: Duration,
+}
+
+impl ClockOffset {
+    // 'server' on the local clocks.
+    pub fn to_local(&self, server: Timestamp) -> Timestamp {
+        Timestamp {
+            wall_us: server.wall_us.saturating_add_signed(-self.wall_us),
+            mono_us: server.mono_us.saturating_add_signed(-self.mono_us),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ClockSync {
+    samples: VecDeque<ClockOffset>,
+}
+
+impl ClockSync {
+    // Add the exchange completed by 'response', which arrived at 'received'.
+    pub fn add_response(&mut self, response: &Frame, received: Timestamp) -> crate::Result<()> {
+        if response.kind != FrameKind::TimeResponse || response.payload.len() != 32 {
+            return Err(crate::Error::Protocol("bad time response".into()));
+        }
+        let field = |i: usize| {
+            u64::from_be_bytes(response.payload[i * 8..(i + 1) * 8].try_into().unwrap()) as i64
+        };
+        let (t0, t1) = ((field(0), field(1)), (field(2), field(3)));
+        let t2 = (
+            response.timestamp.wall_us as i64,
+            response.timestamp.mono_us as i64,
+        );
+        let t3 = (received.wall_us as i64, received.mono_us as i64);
+
+        let offset = |t0: i64, t1: i64, t2: i64, t3: i64| ((t1 - t0) + (t2 - t3)) / 2;
+        // the monotonic clocks don't jump, so they measure the round trip
+        let round_trip = (t3.1 - t0.1) - (t2.1 - t1.1);
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(ClockOffset {
+            wall_us: offset(t0.0, t1.0, t2.0, t3.0),
+            mono_us: offset(t0.1, t1.1, t2.1, t3.1),
+            round_trip: Duration::from_micros(round_trip.max(0) as u64),
+        });
+        Ok(())
+    }
+
+    pub fn n_samples(&self) -> usize {
+        self.samples.len()
+    }
+
+    // Best current estimate; None before the first exchange.
+    pub fn offset(&self) -> Option<ClockOffset> {
+        self.samples
+            .iter()
+            .min_by_key(|sample| sample.round_trip)
+            .copied()
+    }
+}
@@ -1,28 +1,103 @@
+use crate::audio::encode::WireCodec;
+use crate::audio::format::SampleFormat;
+use crate::audio::mixer::MixMode;
+use crate::audio::{CaptureBackend, CaptureSource};
+use crate::distributor::DropPolicy;
+use crate::http_server::HttpFormat;
+use crate::logging::{LogFormat, LogRotation};
+use crate::pipeline::PipelineStage;
+use crate::port_mapping::PortMappingMethod;
+use crate::rtp::RtpFormat;
+use crate::sink::icecast::IcecastProtocol;
+use crate::sink::wav::RecordFormat;
+use crate::sink::HlsCodec;
 use serde::{Deserialize, Serialize};
 use std::{
     fs,
     io::{self, Write},
 };
-use toml;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct Config {
     pub mic: MicConfig,
     pub audio_connection: AudioConnection,
+    pub jack: JackConfig,
+    pub alsa: AlsaConfig,
+    pub wasapi: WasapiConfig,
+    pub vad: VadConfig,
+    pub pipeline: PipelineConfig,
+    pub aec: AecConfig,
+    pub filters: FiltersConfig,
+    pub agc: AgcConfig,
+    pub dynamics: DynamicsConfig,
+    pub denoise: DenoiseConfig,
+    pub plugins: PluginsConfig,
+    pub accept: AcceptConfig,
     pub tcp: TcpConfig,
+    // more tcp servers on other ports, each with its own format
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub listeners: Vec<TcpConfig>,
+    pub udp: UdpConfig,
+    pub multicast: MulticastConfig,
+    pub uds: UdsConfig,
+    pub ws: WsConfig,
+    pub grpc: GrpcConfig,
+    pub quic: QuicConfig,
+    pub srt: SrtConfig,
+    pub zmq: ZmqConfig,
+    pub push: PushConfig,
+    pub http: HttpConfig,
+    pub icecast: IcecastConfig,
+    pub rtp: RtpConfig,
+    pub rtsp: RtspConfig,
+    pub webrtc: WebrtcConfig,
+    pub wav: WavConfig,
+    pub hls: HlsConfig,
+    pub mqtt: MqttConfig,
+    pub kafka: KafkaConfig,
+    pub discovery: DiscoveryConfig,
+    pub port_mapping: PortMappingConfig,
+    pub meter: MeterConfig,
+    pub metrics: MetricsConfig,
+    pub admin: AdminConfig,
+    pub log: LogConfig,
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct MicConfig {
+    pub backend: CaptureBackend,
+    // jack driver; unused by the cpal backend
     pub driver: String,
+    // cpal: "default", an index from 'mic2net devices' or part of the device name
     pub device_name: String,
+    // cpal: record 'device_name' or, with loopback, what the system plays
+    pub source: CaptureSource,
     pub device_id: usize,
     pub sample_rate: usize,
     pub period: usize,
     pub n_channel: usize,
+    // tpdf dither where capture comes in wider than 16 bits
+    pub dither: bool,
+    // cpal: how 'inputs' are combined into one stream
+    pub mix: MixMode,
+    // cpal: capture several devices at once instead of 'device_name'
+    pub inputs: Vec<InputConfig>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InputConfig {
+    // same forms as 'mic.device_name'
+    pub name: String,
+    pub n_channel: usize,
+    // dB
+    pub gain: f32,
+    pub source: CaptureSource,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct AudioConnection {
     pub connect_mic_speaker: bool,
     pub mic_idx: u16,
@@ -30,52 +105,1594 @@ pub struct AudioConnection {
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct JackConfig {
+    // spawn jackd for the device in [mic]; otherwise join the server already running
+    pub start_server: bool,
+    pub client_name: String,
+    // joined server: the output port linked to each input port, in order
+    pub connect: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct AlsaConfig {
+    // frames per period and periods in the buffer; the device has to take them as they
+    // are
+    pub period_size: usize,
+    pub n_periods: usize,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct WasapiConfig {
+    // devices to open in exclusive mode: 'mic.device_name' itself or part of a friendly
+    // name; any other device is shared
+    pub exclusive: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct VadConfig {
+    // stop sending packets during silence
+    pub enable: bool,
+    // dBFS; louder packets open the gate
+    pub on_threshold: f32,
+    // dBFS; the gate closes after 'hangover' ms of quieter packets
+    pub off_threshold: f32,
+    pub hangover: u64,
+    // send small silence frames in place of the packets withheld during silence and of
+    // digitally silent ones (e.g. while muted), for clients to fill in locally
+    pub silence_frames: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct PipelineConfig {
+    // what every output runs the capture through, in this order
+    pub stages: Vec<PipelineStage>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct AecConfig {
+    pub enable: bool,
+    // capture channels carrying what the speakers play
+    pub reference: Vec<usize>,
+    // ms of echo the adaptive filter covers
+    pub tail: usize,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct FiltersConfig {
+    // Hz; e.g. 80 to cut rumble
+    pub high_pass: Option<f32>,
+    pub low_pass: Option<f32>,
+    pub eq: Vec<EqBand>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EqBand {
+    // Hz
+    pub frequency: f32,
+    // dB, negative to cut
+    pub gain: f32,
+    pub q: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct AgcConfig {
+    pub enable: bool,
+    // dBFS RMS the output is steered towards
+    pub target_level: f32,
+    // dB; caps amplification of quiet input
+    pub max_gain: f32,
+    // ms to settle when the input gets louder / quieter
+    pub attack: f32,
+    pub release: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct DynamicsConfig {
+    // initial values; the admin api can change them, see 'dsp::dynamics::DynamicsState'
+    pub enable: bool,
+    pub threshold: f32,
+    pub ratio: f32,
+    pub attack: f32,
+    pub release: f32,
+    pub makeup: f32,
+    pub ceiling: f32,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct DenoiseConfig {
+    // initial state; clients can switch it with "denoise on|off" control frames
+    pub enable: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct PluginsConfig {
+    // stages registered with 'dsp::plugin::PLUGINS', run after denoise, before agc
+    pub stages: Vec<PluginConfig>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PluginConfig {
+    pub name: String,
+    // everything else in the entry, for the plugin's factory
+    #[serde(flatten)]
+    pub params: toml::value::Table,
+}
+
+// How the tcp, ws, http, rtsp and webrtc servers ride out failing accepts.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct AcceptConfig {
+    // ms; the longest wait between accepts while out of file descriptors or memory
+    pub max_backoff: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct TcpConfig {
+    pub bind_address: String,
+    pub listen_port: u16,
+    pub max_clients: u16,
+    // frames buffered per client before drop_policy kicks in
+    pub queue_len: usize,
+    pub drop_policy: DropPolicy,
+    // ms of recent audio sent right after connecting
+    pub preroll: u64,
+    // resample to this rate on the wire; the capture rate when absent
+    pub sample_rate: Option<usize>,
+    // output channels, each the average of the listed capture channels, e.g. [[0, 1]];
+    // every channel as captured when absent
+    pub channels: Option<Vec<Vec<usize>>>,
+    // dB, for this output only; adjustable at runtime through the admin api
+    pub gain: f32,
+    // how audio payloads are coded on the wire
+    pub codec: WireCodec,
+    // samples of pcm audio frames
+    pub sample_format: SampleFormat,
+    // let clients ask for their own rate, channels and codec
+    pub negotiate: bool,
+    // bytes per second written to each client, unless the admin api gives it a limit
+    // of its own; 0 for no limit
+    pub bandwidth_limit: u64,
+    // serve tls instead of cleartext when present
+    pub tls: Option<TlsConfig>,
+    // require clients to send a token before streaming starts when present
+    pub auth: Option<AuthConfig>,
+    // refuse connections by source address when present
+    pub acl: Option<AclConfig>,
+    // throttle repeated connects from one address when present
+    pub rate_limit: Option<RateLimitConfig>,
+    // ping clients and drop the ones that stop answering when present
+    pub heartbeat: Option<HeartbeatConfig>,
+    // encrypt audio payloads with a pre-shared key when present
+    pub psk: Option<PskConfig>,
+    // send several audio frames per write when present
+    pub coalesce: Option<CoalesceConfig>,
+    // kernel options of accepted connections when present
+    pub socket: Option<SocketOptionsConfig>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TlsConfig {
+    // PEM files: certificate chain and private key
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AuthConfig {
+    pub token: String,
+    // seconds a client has to send the token after connecting, within the 10 it has for
+    // all of its handshakes
+    pub timeout: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PskConfig {
+    // 32 bytes as 64 hex digits, the same on the clients
+    pub key: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AclConfig {
+    // "allow <cidr>" / "deny <cidr>" lines, see 'acl'
+    pub file: String,
+    // seconds between checks of the file for changes
+    pub reload_interval: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    // connections per second and source address, after an initial 'burst'
+    pub rate: f64,
+    pub burst: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HeartbeatConfig {
+    // seconds between pings / without a pong before the client is dropped
+    pub interval: u64,
+    pub timeout: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CoalesceConfig {
+    // audio frames per write
+    pub frames: usize,
+    // ms the first frame of a batch may wait for the rest
+    pub max_delay: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SocketOptionsConfig {
+    // bytes of SO_SNDBUF / SO_RCVBUF; the kernel may round or double them
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+    // seconds idle before the first keepalive probe, and between probes
+    pub keepalive_idle: Option<u64>,
+    pub keepalive_interval: Option<u64>,
+    // ms sent data may stay unacknowledged before the connection is dropped (linux)
+    pub user_timeout: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct UdpConfig {
+    pub enable: bool,
+    pub bind_address: String,
+    pub listen_port: u16,
+    pub max_clients: u16,
+    // seconds a client stays registered without sending another datagram
+    pub client_timeout: u64,
+    pub sample_rate: Option<usize>,
+    pub channels: Option<Vec<Vec<usize>>>,
+    pub gain: f32,
+    pub codec: WireCodec,
+    pub sample_format: SampleFormat,
+    // frames per xor parity datagram, so receivers can rebuild one lost frame in each
+    // group; 0 for none
+    pub fec_group: usize,
+    // milliseconds sent frames are kept for clients asking to have them resent; 0 for no
+    // retransmissions
+    pub retransmit_ms: u64,
+    pub nat: Option<NatConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NatConfig {
+    // host:port of each, all asked for the public address of the udp socket
+    pub stun_servers: Vec<String>,
+    // host:port of a 'mic2net rendezvous' reachable by the server and its clients
+    pub rendezvous: String,
+    // what the server and its clients meet under at the rendezvous
+    pub session: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct MulticastConfig {
+    // push the udp datagrams to a multicast group
+    pub enable: bool,
+    // host:port, e.g. 239.255.x.x for a site-local group
+    pub group: String,
+    // hops; 1 stays on the local network
+    pub ttl: u32,
+    // receive our own datagrams on this host too
+    pub loopback: bool,
+    pub sample_rate: Option<usize>,
+    pub channels: Option<Vec<Vec<usize>>>,
+    pub gain: f32,
+    pub codec: WireCodec,
+    pub sample_format: SampleFormat,
+    // as for udp
+    pub fec_group: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct UdsConfig {
+    // serve the tcp protocol on a unix domain socket at 'path' (unix only)
+    pub enable: bool,
+    pub path: String,
+    pub max_clients: u16,
+    pub queue_len: usize,
+    pub drop_policy: DropPolicy,
+    pub preroll: u64,
+    pub sample_rate: Option<usize>,
+    pub channels: Option<Vec<Vec<usize>>>,
+    pub gain: f32,
+    pub codec: WireCodec,
+    pub sample_format: SampleFormat,
+    pub auth: Option<AuthConfig>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct WsConfig {
+    pub enable: bool,
+    pub bind_address: String,
+    pub listen_port: u16,
+    pub max_clients: u16,
+    pub queue_len: usize,
+    pub drop_policy: DropPolicy,
+    pub preroll: u64,
+    pub sample_rate: Option<usize>,
+    pub channels: Option<Vec<Vec<usize>>>,
+    pub gain: f32,
+    pub codec: WireCodec,
+    pub sample_format: SampleFormat,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct GrpcConfig {
+    // the 'StreamAudio' rpc of proto/mic2net.proto; needs the 'grpc' feature
+    pub enable: bool,
+    pub bind_address: String,
+    pub listen_port: u16,
+    pub max_clients: u16,
+    pub queue_len: usize,
+    pub drop_policy: DropPolicy,
+    pub preroll: u64,
+    pub sample_rate: Option<usize>,
+    pub channels: Option<Vec<Vec<usize>>>,
+    pub gain: f32,
+    pub codec: WireCodec,
+    pub sample_format: SampleFormat,
+    // required as 'authorization: Bearer <token>' metadata unless empty
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct QuicConfig {
+    // serve the stream over quic; audio on a unidirectional stream per client, control
+    // frames on a bidirectional stream the client opens
+    pub enable: bool,
+    pub bind_address: String,
+    pub listen_port: u16,
+    pub max_clients: u16,
+    pub queue_len: usize,
+    pub drop_policy: DropPolicy,
+    pub preroll: u64,
+    pub sample_rate: Option<usize>,
+    pub channels: Option<Vec<Vec<usize>>>,
+    pub gain: f32,
+    pub codec: WireCodec,
+    pub sample_format: SampleFormat,
+    // quic always needs a certificate
+    pub tls: TlsConfig,
+    // token sent as the first frame of the control stream when present
+    pub auth: Option<AuthConfig>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct SrtConfig {
+    // serve the stream to srt callers in live mode
+    pub enable: bool,
+    pub bind_address: String,
+    pub listen_port: u16,
+    pub max_clients: u16,
+    pub queue_len: usize,
+    pub drop_policy: DropPolicy,
+    pub preroll: u64,
+    pub sample_rate: Option<usize>,
+    pub channels: Option<Vec<Vec<usize>>>,
+    pub gain: f32,
+    pub codec: WireCodec,
+    pub sample_format: SampleFormat,
+    // ms srt may spend retransmitting lost packets; the receiver plays this much behind
+    pub latency: u64,
+    // 10-79 characters; empty for no encryption
+    pub passphrase: String,
+    // aes key length in bytes: 16, 24 or 32
+    pub key_size: u16,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ZmqConfig {
+    // a zeromq PUB socket for SUB sockets to connect to; messages are a topic and a
+    // frame of the tcp protocol
+    pub enable: bool,
+    pub bind_address: String,
+    pub listen_port: u16,
+    pub max_clients: u16,
+    // per subscriber and stream
+    pub queue_len: usize,
+    pub drop_policy: DropPolicy,
+    // of the stream below
+    pub topic: String,
+    pub sample_rate: Option<usize>,
+    pub channels: Option<Vec<Vec<usize>>>,
+    pub gain: f32,
+    pub codec: WireCodec,
+    pub sample_format: SampleFormat,
+    // more streams on the same socket, each under a topic of its own
+    pub streams: Vec<ZmqStreamConfig>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ZmqStreamConfig {
+    pub topic: String,
+    pub sample_rate: Option<usize>,
+    pub channels: Option<Vec<Vec<usize>>>,
+    pub gain: f32,
+    pub codec: WireCodec,
+    pub sample_format: SampleFormat,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct PushConfig {
+    // dial 'url' and stream there, for devices behind NAT that can't be connected to
+    pub enable: bool,
+    // tcp://host:port for the tcp protocol, ws://host:port/path for websocket messages
+    pub url: String,
+    // tcp: sent as the auth frame, ws: as 'Authorization: Bearer <token>'; none when empty
+    pub token: String,
+    pub queue_len: usize,
+    pub drop_policy: DropPolicy,
+    pub preroll: u64,
+    pub sample_rate: Option<usize>,
+    pub channels: Option<Vec<Vec<usize>>>,
+    pub gain: f32,
+    pub codec: WireCodec,
+    pub sample_format: SampleFormat,
+    // ms before dialing again, doubling while dialing fails up to 'max_retry'
+    pub retry: u64,
+    pub max_retry: u64,
+    // tcp only
+    pub heartbeat: Option<HeartbeatConfig>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    // serve the stream on http://<bind_address>:<listen_port>/stream
+    pub enable: bool,
+    pub bind_address: String,
     pub listen_port: u16,
     pub max_clients: u16,
+    pub queue_len: usize,
+    // default for requests without '?format='
+    pub format: HttpFormat,
+    pub preroll: u64,
+    pub sample_rate: Option<usize>,
+    pub channels: Option<Vec<Vec<usize>>>,
+    pub gain: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct IcecastConfig {
+    // stream to an icecast server as a source; reconnects when the connection drops
+    pub enable: bool,
+    // host:port
+    pub server: String,
+    pub mount: String,
+    pub username: String,
+    pub password: String,
+    pub protocol: IcecastProtocol,
+    // any of the http formats; icecast serves it to its listeners as it is
+    pub format: HttpFormat,
+    // shown by the server and by directories
+    pub name: String,
+    pub description: String,
+    pub genre: String,
+    // ask the server to list the stream in public directories (yp)
+    pub public: bool,
+    pub sample_rate: Option<usize>,
+    pub channels: Option<Vec<Vec<usize>>>,
+    pub gain: f32,
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct RtpConfig {
+    // push the stream as rtp to 'destination'
+    pub enable: bool,
+    // host:port, unicast or multicast; rtcp goes to port + 1
+    pub destination: String,
+    // 96-127 are dynamic
+    pub payload_type: u8,
+    pub format: RtpFormat,
+    // multicast hops
+    pub ttl: u32,
+    // write the session description for receivers here; empty to skip
+    pub sdp_file: String,
+    pub sample_rate: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct RtspConfig {
+    // serve the rtp stream on rtsp://<bind_address>:<listen_port>/<path>, using the
+    // payload settings of [rtp]
+    pub enable: bool,
+    pub bind_address: String,
+    pub listen_port: u16,
+    pub max_clients: u16,
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebrtcConfig {
+    // serve a player page and webrtc signaling on http://<bind_address>:<listen_port>/,
+    // and the media on udp <bind_address>:<listen_port>
+    pub enable: bool,
+    pub bind_address: String,
+    pub listen_port: u16,
+    pub max_clients: u16,
+    // 1 or 2; the first channels of the stream go out as opus
+    pub n_channel: usize,
+    // stun/turn urls for clients outside the LAN
+    pub ice_servers: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct WavConfig {
+    // record the stream to wav or flac files
+    pub enable: bool,
+    pub directory: String,
+    pub format: RecordFormat,
+    // start a new file after this many seconds / MB; 0 for no limit
+    pub max_duration: u64,
+    pub max_size: u64,
+    // delete recordings older than this many days / the oldest of them while they take
+    // more than this many MB in all; 0 for no limit
+    pub max_age_days: u64,
+    pub max_total_size: u64,
+    // cron-like times of day to record at, see 'sink::schedule'; empty records always
+    pub schedule: Vec<String>,
+    // webm only: a cue point wherever the [vad] thresholds see voice start or stop
+    pub vad_cues: bool,
+    // upload finished files when present
+    pub s3: Option<S3Config>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct S3Config {
+    // http(s)://host[:port] of the service, e.g. https://s3.eu-central-1.amazonaws.com
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    // put before the file name to make the object key, e.g. "room-a/"
+    pub prefix: String,
+    pub access_key: String,
+    pub secret_key: String,
+    // delete the local file once it is uploaded
+    pub delete_local: bool,
+    // tries per file, with a growing pause in between
+    pub max_attempts: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct HlsConfig {
+    // write a live hls playlist to 'directory'
+    pub enable: bool,
+    pub directory: String,
+    pub codec: HlsCodec,
+    // seconds per segment
+    pub segment_duration: u64,
+    // segments kept in the playlist and on disk
+    pub window: usize,
+    // 1 or 2
+    pub n_channel: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    // publish the stream and level/vad events to an mqtt broker under 'topic'
+    pub enable: bool,
+    // host:port
+    pub broker: String,
+    pub client_id: String,
+    // prefix of the topics
+    pub topic: String,
+    // no login when empty
+    pub username: String,
+    pub password: String,
+    // seconds
+    pub keep_alive: u64,
+    // audio chunks waiting for the broker; more are dropped
+    pub queue_len: usize,
+    // seconds between level events; 0 for none
+    pub level_interval: u64,
+    pub sample_rate: Option<usize>,
+    pub channels: Option<Vec<Vec<usize>>>,
+    pub gain: f32,
+    pub codec: WireCodec,
+    pub sample_format: SampleFormat,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct KafkaConfig {
+    // produce the stream to 'topic' in records of 'chunk' ms
+    pub enable: bool,
+    // host:port of the bootstrap brokers
+    pub brokers: Vec<String>,
+    pub topic: String,
+    pub partition: i32,
+    // of every record; none when empty
+    pub key: String,
+    pub client_id: String,
+    // 0: don't wait for the broker, 1: for the leader, -1: for all in-sync replicas
+    pub acks: i16,
+    // ms the broker may take for the acks
+    pub timeout: u32,
+    // ms of audio per record
+    pub chunk: u64,
+    // records waiting for the broker; more are dropped
+    pub queue_len: usize,
+    pub sample_rate: Option<usize>,
+    pub channels: Option<Vec<Vec<usize>>>,
+    pub gain: f32,
+    pub codec: WireCodec,
+    pub sample_format: SampleFormat,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    // advertise the server over mDNS/zeroconf
+    pub enable: bool,
+    pub instance_name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct PortMappingConfig {
+    // have the router forward the ports of the enabled listeners
+    pub enable: bool,
+    pub method: PortMappingMethod,
+    // NAT-PMP router; empty for the default gateway
+    pub gateway: String,
+    // seconds a mapping is asked for, renewed halfway
+    pub lifetime: u64,
+    // listeners to map though they stream to anyone: "udp", "http", "tcp:<port>", ...
+    pub public: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct MeterConfig {
+    // log the levels of every channel every 'interval' seconds
+    pub enable: bool,
+    pub interval: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    // serve prometheus metrics on http://<bind_address>:<listen_port>/metrics
+    pub enable: bool,
+    pub bind_address: String,
+    pub listen_port: u16,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    // json api on http://<bind_address>:<listen_port>/ to list and kick tcp clients,
+    // change max_clients and mute the stream at runtime
+    pub enable: bool,
+    pub bind_address: String,
+    pub listen_port: u16,
+    // required as 'Authorization: Bearer <token>' unless empty
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    // tracing filter directive, e.g. "info" or "mic2net=debug"
+    pub level: String,
+    // write to this file instead of stdout when present
+    pub file: Option<LogFileConfig>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LogFileConfig {
+    pub path: String,
+    // MB the file may grow to before it is rotated; 0 for no limit
+    pub max_size: u64,
+    // also rotate when the hour or the day (UTC) is over
+    pub rotate: LogRotation,
+    // rotated files to keep as <path>.1 (the newest) up to <path>.<keep>
+    pub keep: usize,
+}
+
+// Environment variables named MIC2NET_<SECTION>_<KEY> (e.g. MIC2NET_TCP_LISTEN_PORT)
+// override values read from the file. They can set optional keys the file leaves out,
+// such as MIC2NET_TCP_SAMPLE_RATE, but can't add optional sections such as [log.file]
+// or [udp.nat]; those have to be in the file for their keys to be overridden.
+pub const ENV_PREFIX: &str = "MIC2NET_";
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
 impl Config {
+    // Read config.toml, or the file named by MIC2NET_CONFIG.
     pub fn new() -> Config {
-        match Config::read_conf_file() {
+        Config::load(&Config::default_path())
+    }
+
+    // The file 'new' reads.
+    pub fn default_path() -> String {
+        std::env::var("MIC2NET_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.into())
+    }
+
+    // Read 'path' again on a running server; unlike 'load', a bad file is an error.
+    pub fn reload(path: &str) -> crate::Result<Config> {
+        with_env_overrides(Config::read_conf_file(path)?)
+    }
+
+    pub fn load(path: &str) -> Config {
+        let conf = match Config::read_conf_file(path) {
             Ok(conf) => conf,
             Err(err) => {
-                println!("failed reading config.toml! {}", err);
-                println!("create new config file conf.toml; please rename it to config.toml");
-                let conf = Config {
-                    mic: MicConfig {
-                        driver: "alsa".to_string(),
-                        device_name: "hw:seeed8micvoicec".to_string(),
-                        device_id: 0,
-                        sample_rate: 16000,
-                        period: 16,
-                        n_channel: 8,
-                    },
-                    audio_connection: AudioConnection {
-                        connect_mic_speaker: false,
-                        mic_idx: 0,
-                        speaker_idx: 0,
-                    },
-                    tcp: TcpConfig {
-                        listen_port: 2345,
-                        max_clients: 10,
-                    },
-                };
-                let toml = toml::to_string(&conf).unwrap();
-                let mut f = fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open("conf.toml")
-                    .unwrap();
-                f.write_all(toml.as_bytes()).unwrap();
-                conf
+                // logging isn't set up yet; the log section is part of this file
+                println!("failed reading {}! {}", path, err);
+                println!(
+                    "create new config file conf.toml; please rename it to {}",
+                    path
+                );
+                if let Err(err) = Config::write_default(&Config::default()) {
+                    println!("failed writing conf.toml! {}", err);
+                }
+                toml::Value::Table(toml::value::Table::new())
+            }
+        };
+        with_env_overrides(conf).unwrap_or_else(|err| {
+            println!("bad environment override! {}", err);
+            std::process::exit(1);
+        })
+    }
+
+    fn write_default(conf: &Config) -> Result<(), io::Error> {
+        let toml =
+            toml::to_string(conf).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("conf.toml")?;
+        f.write_all(toml.as_bytes())
+    }
+
+    // The file's keys, once they are known to make a valid config.
+    fn read_conf_file(path: &str) -> Result<toml::Value, io::Error> {
+        let contents = fs::read_to_string(path)?;
+        let file: toml::Value = toml::from_str(&contents)?;
+        file.clone().try_into::<Config>()?;
+        Ok(file)
+    }
+}
+
+fn with_env_overrides(file: toml::Value) -> crate::Result<Config> {
+    overridden(file, std::env::vars())
+}
+
+// 'file' over the defaults, so keys missing from it can be overridden too, and 'vars'
+// over both.
+fn overridden(
+    file: toml::Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> crate::Result<Config> {
+    let mut value = toml::Value::try_from(Config::default()).map_err(|err| err.to_string())?;
+    merge(&mut value, file);
+    apply_overrides(&mut value, vars)?;
+    Ok(value.try_into::<Config>().map_err(|err| err.to_string())?)
+}
+
+// Put the keys of 'over' into 'base', table into table.
+fn merge(base: &mut toml::Value, over: toml::Value) {
+    match (base, over) {
+        (toml::Value::Table(base), toml::Value::Table(over)) => {
+            for (key, value) in over {
+                match base.get_mut(&key) {
+                    Some(entry) => merge(entry, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+// Set the keys the MIC2NET_ ones among 'vars' name; a value that doesn't fit its key
+// is an error, as the file's would be.
+fn apply_overrides(
+    value: &mut toml::Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> crate::Result<()> {
+    for (name, raw) in vars {
+        let path = match name.strip_prefix(ENV_PREFIX) {
+            Some(path) if path != "CONFIG" => path.to_lowercase(),
+            _ => continue,
+        };
+        if !set_by_path(value, &path, &raw) && !add_by_path(value, &path, &raw) {
+            println!("ignoring {}: no such config key", name);
+            continue;
+        }
+        // checked one at a time, so the error names the variable
+        if let Err(err) = value.clone().try_into::<Config>() {
+            return Err(format!("{}={}: {}", name, raw, err).into());
+        }
+    }
+    Ok(())
+}
+
+// Section and key names contain underscores themselves, so match 'path' against the
+// keys present at each level instead of splitting it blindly.
+fn set_by_path(value: &mut toml::Value, path: &str, raw: &str) -> bool {
+    let table = match value.as_table_mut() {
+        Some(table) => table,
+        None => return false,
+    };
+    for (key, entry) in table.iter_mut() {
+        if key.as_str() == path {
+            // what doesn't parse as the key's type is kept as a string, for the
+            // check against the config to refuse
+            let parsed = match entry {
+                toml::Value::Integer(_) => raw.parse().map(toml::Value::Integer).ok(),
+                toml::Value::Float(_) => raw.parse().map(toml::Value::Float).ok(),
+                toml::Value::Boolean(_) => raw.parse().map(toml::Value::Boolean).ok(),
+                _ => None,
+            };
+            *entry = parsed.unwrap_or_else(|| toml::Value::String(raw.to_string()));
+            return true;
+        }
+        if let Some(rest) = path
+            .strip_prefix(key.as_str())
+            .and_then(|p| p.strip_prefix('_'))
+        {
+            if entry.is_table() && set_by_path(entry, rest, raw) {
+                return true;
             }
         }
     }
+    false
+}
+
+// The keys 'path' could name that 'value' doesn't have yet, each as the tables leading
+// to it and the key itself, e.g. ["tcp", "sample_rate"].
+fn new_keys(value: &toml::Value, path: &str) -> Vec<Vec<String>> {
+    let Some(table) = value.as_table() else {
+        return Vec::new();
+    };
+    let mut keys = Vec::new();
+    if !table.contains_key(path) {
+        keys.push(vec![path.to_string()]);
+    }
+    for (key, entry) in table {
+        if let Some(rest) = path
+            .strip_prefix(key.as_str())
+            .and_then(|p| p.strip_prefix('_'))
+        {
+            for mut inner in new_keys(entry, rest) {
+                inner.insert(0, key.clone());
+                keys.push(inner);
+            }
+        }
+    }
+    keys
+}
+
+// Add the optional key 'path' names, left out of the file; false if the config has no
+// such key. A value that doesn't fit it is added anyway, for the check against the
+// config to refuse.
+fn add_by_path(value: &mut toml::Value, path: &str, raw: &str) -> bool {
+    let mut refused = None;
+    for keys in new_keys(value, path) {
+        let (key, tables) = keys.split_last().unwrap();
+        for typed in typed(raw) {
+            let mut added = value.clone();
+            let table = tables
+                .iter()
+                .fold(&mut added, |value, key| value.get_mut(key).unwrap());
+            table.as_table_mut().unwrap().insert(key.clone(), typed);
+            match added.clone().try_into::<Config>() {
+                // keys the config doesn't have are dropped on the way back
+                Ok(conf) => {
+                    let kept = toml::Value::try_from(conf).is_ok_and(|conf| {
+                        keys.iter()
+                            .try_fold(&conf, |value, key| value.get(key))
+                            .is_some()
+                    });
+                    if kept {
+                        *value = added;
+                        return true;
+                    }
+                }
+                Err(_) => refused = Some(added),
+            }
+        }
+    }
+    match refused {
+        Some(refused) => {
+            *value = refused;
+            true
+        }
+        None => false,
+    }
+}
+
+// 'raw' as each type it parses as, the string last.
+fn typed(raw: &str) -> Vec<toml::Value> {
+    let mut values: Vec<toml::Value> = [
+        raw.parse().map(toml::Value::Integer).ok(),
+        raw.parse().map(toml::Value::Float).ok(),
+        raw.parse().map(toml::Value::Boolean).ok(),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    values.push(toml::Value::String(raw.to_string()));
+    values
+}
+
+impl Default for MicConfig {
+    fn default() -> MicConfig {
+        MicConfig {
+            backend: CaptureBackend::Jack,
+            driver: "alsa".to_string(),
+            device_name: "hw:seeed8micvoicec".to_string(),
+            source: CaptureSource::Microphone,
+            device_id: 0,
+            sample_rate: 16000,
+            period: 16,
+            n_channel: 8,
+            dither: false,
+            mix: MixMode::Interleave,
+            inputs: Vec::new(),
+        }
+    }
+}
+
+impl Default for JackConfig {
+    fn default() -> JackConfig {
+        JackConfig {
+            start_server: true,
+            client_name: "mic2net".to_string(),
+            connect: Vec::new(),
+        }
+    }
+}
+
+impl Default for AlsaConfig {
+    fn default() -> AlsaConfig {
+        AlsaConfig {
+            period_size: 128,
+            n_periods: 3,
+        }
+    }
+}
+
+impl Default for VadConfig {
+    fn default() -> VadConfig {
+        VadConfig {
+            enable: false,
+            on_threshold: -40.0,
+            off_threshold: -45.0,
+            hangover: 500,
+            silence_frames: false,
+        }
+    }
+}
+
+impl Default for PipelineConfig {
+    fn default() -> PipelineConfig {
+        PipelineConfig {
+            stages: vec![
+                PipelineStage::Channels,
+                PipelineStage::Resample,
+                PipelineStage::Gain,
+                PipelineStage::Encode,
+            ],
+        }
+    }
+}
+
+impl Default for AecConfig {
+    fn default() -> AecConfig {
+        AecConfig {
+            enable: false,
+            reference: Vec::new(),
+            tail: 200,
+        }
+    }
+}
+
+impl Default for AgcConfig {
+    fn default() -> AgcConfig {
+        AgcConfig {
+            enable: false,
+            target_level: -20.0,
+            max_gain: 30.0,
+            attack: 10.0,
+            release: 500.0,
+        }
+    }
+}
+
+impl Default for DynamicsConfig {
+    fn default() -> DynamicsConfig {
+        DynamicsConfig {
+            enable: false,
+            threshold: -18.0,
+            ratio: 4.0,
+            attack: 5.0,
+            release: 100.0,
+            makeup: 6.0,
+            ceiling: -1.0,
+        }
+    }
+}
+
+impl Default for AcceptConfig {
+    fn default() -> AcceptConfig {
+        AcceptConfig { max_backoff: 5000 }
+    }
+}
+
+impl Default for TcpConfig {
+    fn default() -> TcpConfig {
+        TcpConfig {
+            bind_address: "0.0.0.0".to_string(),
+            listen_port: 2345,
+            max_clients: 10,
+            queue_len: 50,
+            drop_policy: DropPolicy::DropNewest,
+            preroll: 0,
+            sample_rate: None,
+            channels: None,
+            gain: 0.0,
+            codec: WireCodec::Pcm,
+            sample_format: SampleFormat::I16,
+            negotiate: true,
+            bandwidth_limit: 0,
+            tls: None,
+            auth: None,
+            acl: None,
+            rate_limit: None,
+            heartbeat: None,
+            psk: None,
+            coalesce: None,
+            socket: None,
+        }
+    }
+}
+
+impl Default for UdpConfig {
+    fn default() -> UdpConfig {
+        UdpConfig {
+            enable: false,
+            bind_address: "0.0.0.0".to_string(),
+            listen_port: 2346,
+            max_clients: 10,
+            client_timeout: 10,
+            sample_rate: None,
+            channels: None,
+            gain: 0.0,
+            codec: WireCodec::Pcm,
+            sample_format: SampleFormat::I16,
+            fec_group: 0,
+            retransmit_ms: 200,
+            nat: None,
+        }
+    }
+}
+
+impl Default for MulticastConfig {
+    fn default() -> MulticastConfig {
+        MulticastConfig {
+            enable: false,
+            group: "239.255.77.77:2350".to_string(),
+            ttl: 1,
+            loopback: true,
+            sample_rate: None,
+            channels: None,
+            gain: 0.0,
+            codec: WireCodec::Pcm,
+            sample_format: SampleFormat::I16,
+            fec_group: 0,
+        }
+    }
+}
+
+impl Default for UdsConfig {
+    fn default() -> UdsConfig {
+        UdsConfig {
+            enable: false,
+            path: "/tmp/mic2net.sock".to_string(),
+            max_clients: 10,
+            queue_len: 50,
+            drop_policy: DropPolicy::DropNewest,
+            preroll: 0,
+            sample_rate: None,
+            channels: None,
+            gain: 0.0,
+            codec: WireCodec::Pcm,
+            sample_format: SampleFormat::I16,
+            auth: None,
+        }
+    }
+}
+
+impl Default for WsConfig {
+    fn default() -> WsConfig {
+        WsConfig {
+            enable: false,
+            bind_address: "0.0.0.0".to_string(),
+            listen_port: 2347,
+            max_clients: 10,
+            queue_len: 50,
+            drop_policy: DropPolicy::DropNewest,
+            preroll: 0,
+            sample_rate: None,
+            channels: None,
+            gain: 0.0,
+            codec: WireCodec::Pcm,
+            sample_format: SampleFormat::I16,
+        }
+    }
+}
+
+impl Default for GrpcConfig {
+    fn default() -> GrpcConfig {
+        GrpcConfig {
+            enable: false,
+            bind_address: "0.0.0.0".to_string(),
+            listen_port: 2350,
+            max_clients: 10,
+            queue_len: 50,
+            drop_policy: DropPolicy::DropNewest,
+            preroll: 0,
+            sample_rate: None,
+            channels: None,
+            gain: 0.0,
+            codec: WireCodec::Pcm,
+            sample_format: SampleFormat::I16,
+            token: String::new(),
+        }
+    }
+}
+
+impl Default for QuicConfig {
+    fn default() -> QuicConfig {
+        QuicConfig {
+            enable: false,
+            bind_address: "0.0.0.0".to_string(),
+            listen_port: 2348,
+            max_clients: 10,
+            queue_len: 50,
+            drop_policy: DropPolicy::DropNewest,
+            preroll: 0,
+            sample_rate: None,
+            channels: None,
+            gain: 0.0,
+            codec: WireCodec::Pcm,
+            sample_format: SampleFormat::I16,
+            tls: TlsConfig {
+                cert: "cert.pem".to_string(),
+                key: "key.pem".to_string(),
+            },
+            auth: None,
+        }
+    }
+}
+
+impl Default for SrtConfig {
+    fn default() -> SrtConfig {
+        SrtConfig {
+            enable: false,
+            bind_address: "0.0.0.0".to_string(),
+            listen_port: 2349,
+            max_clients: 10,
+            queue_len: 50,
+            drop_policy: DropPolicy::DropNewest,
+            preroll: 0,
+            sample_rate: None,
+            channels: None,
+            gain: 0.0,
+            codec: WireCodec::Pcm,
+            sample_format: SampleFormat::I16,
+            latency: 120,
+            passphrase: String::new(),
+            key_size: 16,
+        }
+    }
+}
+
+impl Default for ZmqConfig {
+    fn default() -> ZmqConfig {
+        ZmqConfig {
+            enable: false,
+            bind_address: "0.0.0.0".to_string(),
+            listen_port: 2351,
+            max_clients: 10,
+            queue_len: 50,
+            drop_policy: DropPolicy::DropNewest,
+            topic: "mic2net".to_string(),
+            sample_rate: None,
+            channels: None,
+            gain: 0.0,
+            codec: WireCodec::Pcm,
+            sample_format: SampleFormat::I16,
+            streams: Vec::new(),
+        }
+    }
+}
+
+impl Default for PushConfig {
+    fn default() -> PushConfig {
+        PushConfig {
+            enable: false,
+            url: "tcp://127.0.0.1:2345".to_string(),
+            token: String::new(),
+            queue_len: 50,
+            drop_policy: DropPolicy::DropNewest,
+            preroll: 0,
+            sample_rate: None,
+            channels: None,
+            gain: 0.0,
+            codec: WireCodec::Pcm,
+            sample_format: SampleFormat::I16,
+            retry: 1000,
+            max_retry: 30000,
+            heartbeat: None,
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> HttpConfig {
+        HttpConfig {
+            enable: false,
+            bind_address: "0.0.0.0".to_string(),
+            listen_port: 8000,
+            max_clients: 10,
+            queue_len: 50,
+            format: HttpFormat::Wav,
+            preroll: 0,
+            sample_rate: None,
+            channels: None,
+            gain: 0.0,
+        }
+    }
+}
+
+impl Default for IcecastConfig {
+    fn default() -> IcecastConfig {
+        IcecastConfig {
+            enable: false,
+            server: "127.0.0.1:8000".to_string(),
+            mount: "/mic2net.ogg".to_string(),
+            username: "source".to_string(),
+            password: "hackme".to_string(),
+            protocol: IcecastProtocol::Put,
+            format: HttpFormat::Ogg,
+            name: "mic2net".to_string(),
+            description: String::new(),
+            genre: String::new(),
+            public: false,
+            sample_rate: None,
+            channels: None,
+            gain: 0.0,
+        }
+    }
+}
+
+impl Default for RtpConfig {
+    fn default() -> RtpConfig {
+        RtpConfig {
+            enable: false,
+            destination: "239.255.77.77:5004".to_string(),
+            payload_type: 96,
+            format: RtpFormat::L16,
+            ttl: 1,
+            sdp_file: "mic2net.sdp".to_string(),
+            sample_rate: None,
+        }
+    }
+}
+
+impl Default for RtspConfig {
+    fn default() -> RtspConfig {
+        RtspConfig {
+            enable: false,
+            bind_address: "0.0.0.0".to_string(),
+            listen_port: 8554,
+            max_clients: 10,
+            path: "mic".to_string(),
+        }
+    }
+}
+
+impl Default for WebrtcConfig {
+    fn default() -> WebrtcConfig {
+        WebrtcConfig {
+            enable: false,
+            bind_address: "0.0.0.0".to_string(),
+            listen_port: 8080,
+            max_clients: 10,
+            n_channel: 1,
+            ice_servers: Vec::new(),
+        }
+    }
+}
+
+impl Default for WavConfig {
+    fn default() -> WavConfig {
+        WavConfig {
+            enable: false,
+            directory: "recordings".to_string(),
+            format: RecordFormat::Wav,
+            max_duration: 3600,
+            max_size: 0,
+            max_age_days: 0,
+            max_total_size: 0,
+            schedule: Vec::new(),
+            vad_cues: false,
+            s3: None,
+        }
+    }
+}
+
+impl Default for HlsConfig {
+    fn default() -> HlsConfig {
+        HlsConfig {
+            enable: false,
+            directory: "hls".to_string(),
+            codec: HlsCodec::Opus,
+            segment_duration: 2,
+            window: 6,
+            n_channel: 1,
+        }
+    }
+}
+
+impl Default for MqttConfig {
+    fn default() -> MqttConfig {
+        MqttConfig {
+            enable: false,
+            broker: "127.0.0.1:1883".to_string(),
+            client_id: "mic2net".to_string(),
+            topic: "mic2net".to_string(),
+            username: String::new(),
+            password: String::new(),
+            keep_alive: 30,
+            queue_len: 50,
+            level_interval: 5,
+            sample_rate: None,
+            channels: None,
+            gain: 0.0,
+            codec: WireCodec::Pcm,
+            sample_format: SampleFormat::I16,
+        }
+    }
+}
+
+impl Default for KafkaConfig {
+    fn default() -> KafkaConfig {
+        KafkaConfig {
+            enable: false,
+            brokers: vec!["127.0.0.1:9092".to_string()],
+            topic: "mic2net".to_string(),
+            partition: 0,
+            key: String::new(),
+            client_id: "mic2net".to_string(),
+            acks: -1,
+            timeout: 5000,
+            chunk: 1000,
+            queue_len: 60,
+            sample_rate: None,
+            channels: None,
+            gain: 0.0,
+            codec: WireCodec::Pcm,
+            sample_format: SampleFormat::I16,
+        }
+    }
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> DiscoveryConfig {
+        DiscoveryConfig {
+            enable: false,
+            instance_name: "mic2net".to_string(),
+        }
+    }
+}
+
+impl Default for PortMappingConfig {
+    fn default() -> PortMappingConfig {
+        PortMappingConfig {
+            enable: false,
+            method: PortMappingMethod::Auto,
+            gateway: String::new(),
+            lifetime: 3600,
+            public: Vec::new(),
+        }
+    }
+}
+
+impl Default for MeterConfig {
+    fn default() -> MeterConfig {
+        MeterConfig {
+            enable: false,
+            interval: 10,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> MetricsConfig {
+        MetricsConfig {
+            enable: false,
+            bind_address: "0.0.0.0".to_string(),
+            listen_port: 9345,
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> AdminConfig {
+        AdminConfig {
+            enable: false,
+            bind_address: "127.0.0.1".to_string(),
+            listen_port: 9346,
+            token: String::new(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            format: LogFormat::Pretty,
+            level: "info".to_string(),
+            file: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_roundtrips() {
+        let toml = toml::to_string(&Config::default()).unwrap();
+        let conf: Config = toml::from_str(&toml).unwrap();
+        assert_eq!(conf.tcp.listen_port, Config::default().tcp.listen_port);
+        assert!(conf.listeners.is_empty());
+    }
+
+    #[test]
+    fn listeners_roundtrip() {
+        let mut conf = Config::default();
+        conf.listeners.push(Config::default().tcp);
+        conf.listeners[0].listen_port = 2346;
+        let toml = toml::to_string(&conf).unwrap();
+        let conf: Config = toml::from_str(&toml).unwrap();
+        assert_eq!(conf.listeners.len(), 1);
+        assert_eq!(conf.listeners[0].listen_port, 2346);
+    }
+
+    // config.toml as it was before most sections and keys existed
+    const BASELINE: &str = r#"
+[mic]
+driver = "coreaudio"
+device_name = "default"
+device_id = 0
+sample_rate = 16000
+period = 16
+n_channel = 8
+
+[audio_connection]
+connect_mic_speaker = false
+mic_idx = 0
+speaker_idx = 0
+
+[tcp]
+listen_port = 2345
+max_clients = 10
+"#;
+
+    #[test]
+    fn old_config_gets_defaults() {
+        let conf: Config = toml::from_str(BASELINE).unwrap();
+        assert_eq!(conf.mic.driver, "coreaudio");
+        assert_eq!(conf.mic.device_name, "default");
+        assert_eq!(conf.tcp.max_clients, 10);
+        assert_eq!(conf.tcp.queue_len, Config::default().tcp.queue_len);
+        assert!(!conf.udp.enable);
+    }
+
+    fn overridden(vars: &[(&str, &str)]) -> crate::Result<Config> {
+        overridden_file("", vars)
+    }
+
+    fn overridden_file(file: &str, vars: &[(&str, &str)]) -> crate::Result<Config> {
+        let vars = vars
+            .iter()
+            .map(|(name, raw)| (name.to_string(), raw.to_string()));
+        super::overridden(toml::from_str(file).unwrap(), vars)
+    }
+
+    #[test]
+    fn env_overrides_set_nested_keys() {
+        let conf = overridden(&[
+            ("MIC2NET_TCP_LISTEN_PORT", "2350"),
+            ("MIC2NET_UDP_ENABLE", "true"),
+            ("MIC2NET_MIC_DEVICE_NAME", "hw:1"),
+            ("MIC2NET_CONFIG", "other.toml"),
+            ("PATH", "/bin"),
+        ])
+        .unwrap();
+        assert_eq!(conf.tcp.listen_port, 2350);
+        assert!(conf.udp.enable);
+        assert_eq!(conf.mic.device_name, "hw:1");
+    }
+
+    #[test]
+    fn env_override_of_the_wrong_type_is_an_error() {
+        let err = overridden(&[("MIC2NET_TCP_LISTEN_PORT", "many")])
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("MIC2NET_TCP_LISTEN_PORT"));
+        assert!(overridden(&[("MIC2NET_MIC_BACKEND", "nope")]).is_err());
+        assert!(overridden(&[("MIC2NET_TCP_MAX_CLIENTS", "70000")]).is_err());
+    }
+
+    #[test]
+    fn env_overrides_set_unset_optional_keys() {
+        let conf = overridden_file(
+            "[tcp]\nlisten_port = 2000\n",
+            &[
+                ("MIC2NET_TCP_SAMPLE_RATE", "48000"),
+                ("MIC2NET_TCP_BOGUS", "1"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(conf.tcp.listen_port, 2000);
+        assert_eq!(conf.tcp.sample_rate, Some(48000));
+        assert_eq!(conf.tcp.queue_len, Config::default().tcp.queue_len);
+
+        let err = overridden(&[("MIC2NET_TCP_SAMPLE_RATE", "fast")])
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("MIC2NET_TCP_SAMPLE_RATE"));
+    }
+
+    #[test]
+    fn env_overrides_skip_keys_that_arent_there() {
+        // [log.file] is None unless the file has it
+        let conf = overridden(&[("MIC2NET_LOG_FILE_PATH", "/tmp/mic2net.log")]).unwrap();
+        assert!(conf.log.file.is_none());
+    }
 
-    fn read_conf_file() -> Result<Config, io::Error> {
-        let contents = fs::read_to_string("config.toml")?;
-        let conf: Config = toml::from_str(&contents)?;
-        Ok(conf)
+    #[test]
+    fn shipped_config_parses() {
+        let _: Config = toml::from_str(include_str!("../config.toml")).unwrap();
     }
 }
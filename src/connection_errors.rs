@@ -0,0 +1,47 @@
+// Failed client connections of the tcp and unix socket servers, as they happen, for
+// embedders that want more than the log: an alerting hook, a per-peer ban list, a
+// dashboard. Subscribers that don't keep up miss the oldest events.
+use crate::error::{Error, Phase};
+use std::sync::{Arc, LazyLock};
+use tokio::sync::broadcast;
+use tracing::{error, warn};
+
+const BACKLOG: usize = 64;
+
+pub static CONNECTION_ERRORS: LazyLock<ConnectionErrors> = LazyLock::new(|| ConnectionErrors {
+    events: broadcast::channel(BACKLOG).0,
+});
+
+pub struct ConnectionErrors {
+    events: broadcast::Sender<ConnectionEvent>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectionEvent {
+    // "tcp:<listen_port>" or "uds"
+    pub server: String,
+    // an 'Error::Connection', with the peer and phase
+    pub error: Arc<Error>,
+}
+
+impl ConnectionErrors {
+    pub fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    // Log 'error' and pass it on to the subscribers, if there are any. Failed
+    // handshakes are mostly scanners and misconfigured clients, so only a warning.
+    pub fn report(&self, server: &str, error: Error) {
+        match &error {
+            Error::Connection {
+                phase: Phase::Handshake,
+                ..
+            } => warn!("{}", error),
+            _ => error!("connection error: {}", error),
+        }
+        let _ = self.events.send(ConnectionEvent {
+            server: server.to_string(),
+            error: Arc::new(error),
+        });
+    }
+}
@@ -0,0 +1,419 @@
+// Running in the background: detaching from the terminal, or running as a windows
+// service, and keeping a pidfile that init scripts and 'kill $(cat ...)' can go by.
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+#[cfg(windows)]
+use windows_sys::Win32::Foundation::{
+    ERROR_CALL_NOT_IMPLEMENTED, ERROR_FAILED_SERVICE_CONTROLLER_CONNECT, NO_ERROR,
+};
+#[cfg(windows)]
+use windows_sys::Win32::Storage::FileSystem::DELETE;
+#[cfg(windows)]
+use windows_sys::Win32::System::Console::{
+    AllocConsole, GenerateConsoleCtrlEvent, SetConsoleCtrlHandler, CTRL_C_EVENT,
+};
+#[cfg(windows)]
+use windows_sys::Win32::System::Services::{
+    CloseServiceHandle, CreateServiceW, DeleteService, OpenSCManagerW, OpenServiceW,
+    RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW, SC_HANDLE,
+    SC_MANAGER_CONNECT, SC_MANAGER_CREATE_SERVICE, SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP,
+    SERVICE_AUTO_START, SERVICE_CONTROL_INTERROGATE, SERVICE_CONTROL_SHUTDOWN,
+    SERVICE_CONTROL_STOP, SERVICE_ERROR_NORMAL, SERVICE_QUERY_STATUS, SERVICE_RUNNING,
+    SERVICE_STATUS, SERVICE_STATUS_CURRENT_STATE, SERVICE_STOPPED, SERVICE_STOP_PENDING,
+    SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+};
+
+// Fork twice and start a new session, so the process outlives the shell and can never
+// get a controlling terminal again. Must run before any thread is started, the tokio
+// runtime's included, since only the calling thread survives a fork.
+//
+// The working directory stays, as relative paths in the config (tls keys, the acl
+// file, wav recordings) are resolved against it. stdin goes to /dev/null, and stdout
+// and stderr do too when they are a terminal; redirect them to keep the log.
+#[cfg(unix)]
+pub fn daemonize() -> crate::Result<()> {
+    unsafe {
+        fork_and_leave()?;
+        if libc::setsid() < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        // the session leader could acquire a terminal by opening one
+        fork_and_leave()?;
+        libc::umask(0o022);
+    }
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    redirect(&null, libc::STDIN_FILENO)?;
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::isatty(fd) } == 1 {
+            redirect(&null, fd)?;
+        }
+    }
+    Ok(())
+}
+
+// Carry on in the child; the parent exits without running anything else.
+#[cfg(unix)]
+unsafe fn fork_and_leave() -> crate::Result<()> {
+    match libc::fork() {
+        -1 => Err(std::io::Error::last_os_error().into()),
+        0 => Ok(()),
+        _ => libc::_exit(0),
+    }
+}
+
+#[cfg(unix)]
+fn redirect(file: &fs::File, fd: libc::c_int) -> crate::Result<()> {
+    use std::os::fd::AsRawFd;
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+// Run as the service registered by 'install_service': connect to the service control
+// manager, which starts the service main on a thread of its own, and report running
+// once it has. Stopping the service raises ctrl-c in a console of our own, so it shuts
+// down the same way as from a terminal; call 'service_stopped' on the way out.
+//
+// Services start in the system directory, which is why 'install_service' registers
+// the config by its absolute path; paths in the config should be absolute too.
+#[cfg(windows)]
+pub fn daemonize() -> crate::Result<()> {
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    STARTED
+        .set(std::sync::Mutex::new(started_tx.clone()))
+        .map_err(|_| "--daemon started twice")?;
+    std::thread::spawn(move || {
+        let mut name = wide(SERVICE_NAME);
+        let table = [
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: name.as_mut_ptr(),
+                lpServiceProc: Some(service_main),
+            },
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: std::ptr::null_mut(),
+                lpServiceProc: None,
+            },
+        ];
+        // returns once the service reported stopped
+        if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+            let err = std::io::Error::last_os_error();
+            let err = if err.raw_os_error() == Some(ERROR_FAILED_SERVICE_CONTROLLER_CONNECT as i32)
+            {
+                "--daemon on windows runs only as a service; register one with \
+                 --install-service"
+                    .into()
+            } else {
+                crate::Error::from(err)
+            };
+            let _ = started_tx.send(Err(err));
+        }
+    });
+    started_rx
+        .recv()
+        .map_err(|_| "the service control manager never started the service")?
+}
+
+#[cfg(windows)]
+const SERVICE_NAME: &str = "mic2net";
+
+// Where 'service_main' reports back to 'daemonize'.
+#[cfg(windows)]
+static STARTED: std::sync::OnceLock<std::sync::Mutex<std::sync::mpsc::Sender<crate::Result<()>>>> =
+    std::sync::OnceLock::new();
+
+#[cfg(windows)]
+static STATUS_HANDLE: std::sync::atomic::AtomicPtr<std::ffi::c_void> =
+    std::sync::atomic::AtomicPtr::new(std::ptr::null_mut());
+
+#[cfg(windows)]
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+    let name = wide(SERVICE_NAME);
+    let result = (|| {
+        let handle =
+            RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(control_handler), std::ptr::null());
+        if handle.is_null() {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        STATUS_HANDLE.store(handle, std::sync::atomic::Ordering::SeqCst);
+        // services get no console, and without one there's no ctrl-c to stop on
+        if AllocConsole() == 0 || SetConsoleCtrlHandler(None, 0) == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        report_status(
+            SERVICE_RUNNING,
+            SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN,
+        );
+        Ok(())
+    })();
+    if result.is_err() {
+        report_status(SERVICE_STOPPED, 0);
+    }
+    if let Some(started) = STARTED.get() {
+        let _ = started.lock().unwrap().send(result);
+    }
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn control_handler(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut std::ffi::c_void,
+    _context: *mut std::ffi::c_void,
+) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            report_status(SERVICE_STOP_PENDING, 0);
+            GenerateConsoleCtrlEvent(CTRL_C_EVENT, 0);
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+// Tell the service control manager the service is gone, as the process is about to
+// exit; does nothing unless running as a service.
+#[cfg(windows)]
+pub fn service_stopped() {
+    report_status(SERVICE_STOPPED, 0);
+}
+
+#[cfg(windows)]
+fn report_status(state: SERVICE_STATUS_CURRENT_STATE, controls_accepted: u32) {
+    let handle = STATUS_HANDLE.load(std::sync::atomic::Ordering::SeqCst);
+    if handle.is_null() {
+        return;
+    }
+    let status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: controls_accepted,
+        dwWin32ExitCode: NO_ERROR,
+        dwServiceSpecificExitCode: 0,
+        dwCheckPoint: 0,
+        // what stopping may take, with every server draining its clients
+        dwWaitHint: if state == SERVICE_STOP_PENDING {
+            10_000
+        } else {
+            0
+        },
+    };
+    unsafe { SetServiceStatus(handle, &status) };
+}
+
+// Register mic2net as a service started at boot, running with '--daemon' and the
+// config at 'config' (and 'pidfile', if given).
+#[cfg(windows)]
+pub fn install_service(config: &Path, pidfile: Option<&Path>) -> crate::Result<()> {
+    let exe = std::env::current_exe()?;
+    let mut command = format!(
+        "\"{}\" --daemon --config \"{}\"",
+        exe.display(),
+        std::path::absolute(config)?.display()
+    );
+    if let Some(pidfile) = pidfile {
+        command += &format!(" --pidfile \"{}\"", std::path::absolute(pidfile)?.display());
+    }
+    let manager = ServiceManager::open(SC_MANAGER_CREATE_SERVICE)?;
+    let (name, command) = (wide(SERVICE_NAME), wide(&command));
+    let service = unsafe {
+        CreateServiceW(
+            manager.0,
+            name.as_ptr(),
+            name.as_ptr(),
+            SERVICE_QUERY_STATUS,
+            SERVICE_WIN32_OWN_PROCESS,
+            SERVICE_AUTO_START,
+            SERVICE_ERROR_NORMAL,
+            command.as_ptr(),
+            std::ptr::null(),
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    if service.is_null() {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    unsafe { CloseServiceHandle(service) };
+    Ok(())
+}
+
+// Remove the service 'install_service' registered; a running one goes once stopped.
+#[cfg(windows)]
+pub fn uninstall_service() -> crate::Result<()> {
+    let manager = ServiceManager::open(SC_MANAGER_CONNECT)?;
+    let name = wide(SERVICE_NAME);
+    let service = unsafe { OpenServiceW(manager.0, name.as_ptr(), DELETE) };
+    if service.is_null() {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let deleted = unsafe { DeleteService(service) } != 0;
+    let err = std::io::Error::last_os_error();
+    unsafe { CloseServiceHandle(service) };
+    if !deleted {
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+// A handle to the service control manager, closed when dropped.
+#[cfg(windows)]
+struct ServiceManager(SC_HANDLE);
+
+#[cfg(windows)]
+impl ServiceManager {
+    fn open(access: u32) -> crate::Result<ServiceManager> {
+        let handle = unsafe { OpenSCManagerW(std::ptr::null(), std::ptr::null(), access) };
+        if handle.is_null() {
+            // most likely not run as administrator
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(ServiceManager(handle))
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ServiceManager {
+    fn drop(&mut self) {
+        unsafe { CloseServiceHandle(self.0) };
+    }
+}
+
+// 's' as the nul terminated utf-16 the api takes.
+#[cfg(windows)]
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+// Holds the process id in 'path' while alive and removes the file when dropped.
+pub struct Pidfile {
+    path: PathBuf,
+    // locked for as long as we hold the pidfile
+    _file: File,
+}
+
+impl Pidfile {
+    // Fails when another instance holds the file. One left behind by a crash isn't
+    // locked by anyone and is taken over, so of two instances starting at once just
+    // one gets it, whatever the file held before.
+    pub fn create(path: &Path) -> crate::Result<Pidfile> {
+        for _ in 0..PIDFILE_ATTEMPTS {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            if !lock(&file)? {
+                return Err(match read_pid(path) {
+                    Some(pid) => format!("{} is held by pid {}", path.display(), pid),
+                    None => format!("{} is held by another instance", path.display()),
+                }
+                .into());
+            }
+            // the instance we waited for removes the file before letting go of it, so
+            // what we locked may no longer be at 'path'
+            if !is_at(&file, path) {
+                continue;
+            }
+            file.set_len(0)?;
+            writeln!(file, "{}", std::process::id())?;
+            return Ok(Pidfile {
+                path: path.to_path_buf(),
+                _file: file,
+            });
+        }
+        Err(format!("{} keeps reappearing", path.display()).into())
+    }
+}
+
+// Enough for other instances to remove their pidfile as we open it.
+const PIDFILE_ATTEMPTS: usize = 3;
+
+fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+impl Drop for Pidfile {
+    // removed while still locked, so nobody can lock it in between and lose it to us
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Lock 'file' for as long as it is open; false when someone else has it locked.
+#[cfg(unix)]
+fn lock(file: &File) -> std::io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let err = std::io::Error::last_os_error();
+    match err.kind() {
+        std::io::ErrorKind::WouldBlock => Ok(false),
+        _ => Err(err),
+    }
+}
+
+// Whether 'file' is still the one at 'path'.
+#[cfg(unix)]
+fn is_at(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(file), Ok(path)) => file.dev() == path.dev() && file.ino() == path.ino(),
+        _ => false,
+    }
+}
+
+// Can't lock without a process api; an existing pidfile is taken over.
+#[cfg(not(unix))]
+fn lock(_file: &File) -> std::io::Result<bool> {
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+fn is_at(_file: &File, _path: &Path) -> bool {
+    true
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("mic2net-{}-{}.pid", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn stale_pidfile_is_replaced() {
+        let path = scratch("stale");
+        // a pid far beyond pid_max, never running
+        fs::write(&path, "999999999\n").unwrap();
+        let pidfile = Pidfile::create(&path).unwrap();
+        assert_eq!(read_pid(&path), Some(std::process::id()));
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn held_pidfile_is_refused() {
+        let path = scratch("held");
+        let pidfile = Pidfile::create(&path).unwrap();
+        assert!(Pidfile::create(&path).is_err());
+        assert_eq!(read_pid(&path), Some(std::process::id()));
+        drop(pidfile);
+        let pidfile = Pidfile::create(&path).unwrap();
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,161 @@
+use crate::audio::encode::WireCodec;
+use crate::audio::format::SampleFormat;
+use crate::config_file::Config;
+use crate::protocol::PROTOCOL_VERSION;
+use crate::PACKET_N_SAMPLE;
+use mdns_sd::{ResolvedService, ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+pub const SERVICE_TYPE: &str = "_mic2net._tcp.local.";
+
+// Names of the payload format and channel layout, as advertised in the TXT records.
+pub fn format_names(codec: WireCodec, sample_format: SampleFormat) -> (&'static str, &'static str) {
+    match codec {
+        WireCodec::Pcm => match sample_format {
+            SampleFormat::I16 => ("s16le", "planar"),
+            SampleFormat::I24 => ("s24le", "planar"),
+            SampleFormat::F32 => ("f32le", "planar"),
+        },
+        WireCodec::Aac => ("aac", "adts"),
+        WireCodec::Opus => ("opus", "packets"),
+        WireCodec::Adpcm => ("ima_adpcm", "planar"),
+    }
+}
+
+// Announce the tcp server on the LAN as '_mic2net._tcp.local' with the stream format in
+// TXT records; the returned daemon keeps answering queries until it is shut down.
+pub fn advertise(cfg: &Config, n_channel: usize) -> crate::Result<ServiceDaemon> {
+    let instance_name = &cfg.discovery.instance_name;
+    let host_name = format!("{}.local.", instance_name.replace(' ', "-"));
+
+    let (format, layout) = format_names(cfg.tcp.codec, cfg.tcp.sample_format);
+    let n_channel = cfg.tcp.channels.as_ref().map_or(n_channel, Vec::len);
+    let n_channel = match cfg.tcp.codec {
+        WireCodec::Aac | WireCodec::Opus => n_channel.min(2),
+        _ => n_channel,
+    };
+    let mut properties = vec![
+        ("version".to_string(), PROTOCOL_VERSION.to_string()),
+        (
+            "sample_rate".to_string(),
+            cfg.tcp
+                .sample_rate
+                .unwrap_or(cfg.mic.sample_rate)
+                .to_string(),
+        ),
+        ("channels".to_string(), n_channel.to_string()),
+        ("samples".to_string(), PACKET_N_SAMPLE.to_string()),
+        ("format".to_string(), format.to_string()),
+        ("layout".to_string(), layout.to_string()),
+        ("device_id".to_string(), cfg.mic.device_id.to_string()),
+        ("tls".to_string(), cfg.tcp.tls.is_some().to_string()),
+        ("auth".to_string(), cfg.tcp.auth.is_some().to_string()),
+    ];
+    if cfg.udp.enable {
+        properties.push(("udp_port".to_string(), cfg.udp.listen_port.to_string()));
+    }
+    if cfg.ws.enable {
+        properties.push(("ws_port".to_string(), cfg.ws.listen_port.to_string()));
+    }
+
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        instance_name,
+        &host_name,
+        "",
+        cfg.tcp.listen_port,
+        properties.as_slice(),
+    )?
+    .enable_addr_auto();
+
+    let daemon = ServiceDaemon::new()?;
+    daemon.register(service)?;
+    info!("advertising {} as {}", SERVICE_TYPE, instance_name);
+    Ok(daemon)
+}
+
+// A server found by 'browse', with what its TXT records say about the stream; fields
+// are None where a server didn't say, an older one for instance.
+#[derive(Clone, Debug)]
+pub struct DiscoveredServer {
+    pub name: String,
+    pub host: String,
+    // of the tcp server, one per address the host answered with
+    pub addresses: Vec<SocketAddr>,
+    pub version: Option<u8>,
+    pub sample_rate: Option<usize>,
+    pub channels: Option<usize>,
+    // "s16le", "s24le", "f32le", "aac", "opus" or "ima_adpcm"
+    pub format: Option<String>,
+    pub tls: bool,
+    pub auth: bool,
+    pub udp_port: Option<u16>,
+    pub ws_port: Option<u16>,
+}
+
+impl DiscoveredServer {
+    fn of(service: &ResolvedService) -> DiscoveredServer {
+        let property = |key: &str| service.get_property_val_str(key);
+        let mut addresses: Vec<SocketAddr> = service
+            .get_addresses()
+            .iter()
+            .map(|ip| SocketAddr::new(ip.to_ip_addr(), service.port))
+            .collect();
+        // ipv4 first and loopback last, what most clients will want comes first
+        addresses.sort_by_key(|addr| (addr.is_ipv6(), addr.ip().is_loopback(), *addr));
+        DiscoveredServer {
+            name: service
+                .fullname
+                .strip_suffix(SERVICE_TYPE)
+                .and_then(|name| name.strip_suffix('.'))
+                .unwrap_or(&service.fullname)
+                .to_string(),
+            host: service.host.clone(),
+            addresses,
+            version: parsed(service, "version"),
+            sample_rate: parsed(service, "sample_rate"),
+            channels: parsed(service, "channels"),
+            format: property("format").map(str::to_string),
+            tls: property("tls") == Some("true"),
+            auth: property("auth") == Some("true"),
+            udp_port: parsed(service, "udp_port"),
+            ws_port: parsed(service, "ws_port"),
+        }
+    }
+}
+
+fn parsed<T: FromStr>(service: &ResolvedService, key: &str) -> Option<T> {
+    service.get_property_val_str(key)?.parse().ok()
+}
+
+// The servers that answered within 'timeout', by name. Blocks meanwhile; async code
+// can run it with 'tokio::task::spawn_blocking'.
+pub fn browse(timeout: Duration) -> crate::Result<Vec<DiscoveredServer>> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    let deadline = Instant::now() + timeout;
+    let mut servers = BTreeMap::new();
+    // answers come once per network interface; the last one wins
+    while let Ok(event) = events.recv_deadline(deadline) {
+        match event {
+            ServiceEvent::ServiceResolved(service) => {
+                let server = DiscoveredServer::of(&service);
+                servers.insert(server.name.clone(), server);
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                servers.retain(|_, server: &mut DiscoveredServer| {
+                    !fullname.starts_with(&format!("{}.", server.name))
+                });
+            }
+            _ => {}
+        }
+    }
+    if let Err(err) = daemon.shutdown() {
+        warn!("failed to stop browsing: {}", err);
+    }
+    Ok(servers.into_values().collect())
+}
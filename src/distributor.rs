@@ -0,0 +1,524 @@
+use crate::metrics::{Metrics, METRICS};
+use crate::protocol::{Frame, StreamInfo, Timestamp};
+use crate::{HEADER_LEN, PACKET_N_SAMPLE};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{watch, Notify};
+use tracing::{info, warn};
+
+// longest silence frame sent for a silent stretch of capture
+const SILENCE_FRAME_MS: u64 = 100;
+
+// What to do with a client whose queue is full when a new frame arrives.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+    // skip the new frame for this client only
+    DropNewest,
+    // skip the oldest queued frame instead, so the client stays close to live
+    DropOldest,
+    // close the client's queue; its handler sees the end of stream and hangs up
+    Disconnect,
+    // hold back every client of the distributor until this one has room again; the
+    // capture side then loses packets for all of them, but the client none
+    Block,
+}
+
+// Frames waiting for one client. Unlike a channel the publishing side can also pop
+// from it, which 'DropOldest' needs.
+struct ClientQueue {
+    frames: Mutex<VecDeque<Frame>>,
+    capacity: usize,
+    policy: DropPolicy,
+    // set by either side; the handler still gets the frames queued before
+    closed: AtomicBool,
+    // a frame was queued or the queue closed
+    readable: Notify,
+    // a frame was taken or the queue closed, for a blocked 'publish'
+    writable: Notify,
+    dropped: AtomicU64,
+}
+
+impl ClientQueue {
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.readable.notify_one();
+        self.writable.notify_one();
+    }
+
+    fn push(&self, frames: &mut VecDeque<Frame>, frame: Frame) {
+        frames.push_back(frame);
+        self.readable.notify_one();
+    }
+
+    fn drop_frame(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        Metrics::inc(&METRICS.frames_dropped);
+    }
+
+    // Queue 'frame' as the policy says; false if the client is to be dropped, and
+    // the frame back if it has to wait for room.
+    fn offer(&self, id: u64, frame: Frame) -> Result<bool, Frame> {
+        if self.closed.load(Ordering::Acquire) {
+            return Ok(false);
+        }
+        let mut frames = self.frames.lock().unwrap();
+        if frames.len() < self.capacity {
+            self.push(&mut frames, frame);
+            return Ok(true);
+        }
+        match self.policy {
+            DropPolicy::DropNewest => self.drop_frame(),
+            DropPolicy::DropOldest => {
+                frames.pop_front();
+                self.drop_frame();
+                self.push(&mut frames, frame);
+            }
+            DropPolicy::Disconnect => {
+                drop(frames);
+                Metrics::inc(&METRICS.frames_dropped);
+                warn!(client = id, "client too slow; disconnecting");
+                self.close();
+                return Ok(false);
+            }
+            DropPolicy::Block => return Err(frame),
+        }
+        Ok(true)
+    }
+
+    // Wait until 'frame' fits; gives up if the client goes away meanwhile.
+    async fn push_blocking(&self, frame: Frame) {
+        loop {
+            if self.closed.load(Ordering::Acquire) {
+                return;
+            }
+            {
+                let mut frames = self.frames.lock().unwrap();
+                if frames.len() < self.capacity {
+                    self.push(&mut frames, frame);
+                    return;
+                }
+            }
+            self.writable.notified().await;
+        }
+    }
+}
+
+// Fans every captured packet out to one bounded queue per connected client, so a
+// slow client only loses its own frames instead of stalling or confusing others.
+// The last 'history_len' frames are kept as pre-roll for clients that join late.
+pub struct Distributor {
+    // what the frames carry, for clients to be told when they connect
+    stream: StreamInfo,
+    next_id: AtomicU64,
+    clients: Mutex<HashMap<u64, Arc<ClientQueue>>>,
+    history_len: usize,
+    history: Mutex<VecDeque<Frame>>,
+}
+
+pub struct Subscription {
+    id: u64,
+    // as subscribed with, before any preroll
+    queue_len: usize,
+    queue: Arc<ClientQueue>,
+    distributor: Arc<Distributor>,
+}
+
+impl Distributor {
+    pub fn new(stream: StreamInfo, history_len: usize) -> Arc<Distributor> {
+        Arc::new(Distributor {
+            stream,
+            next_id: AtomicU64::new(0),
+            clients: Mutex::new(HashMap::new()),
+            history_len,
+            history: Mutex::new(VecDeque::with_capacity(history_len)),
+        })
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.history_len
+    }
+
+    pub fn stream_info(&self) -> StreamInfo {
+        self.stream
+    }
+
+    pub fn subscribe(self: &Arc<Self>, queue_len: usize, policy: DropPolicy) -> Subscription {
+        self.subscribe_with_preroll(queue_len, policy, 0)
+    }
+
+    // Like 'subscribe', but the queue starts out with up to 'preroll' of the most recent
+    // frames, so the client has audio to play right away.
+    pub fn subscribe_with_preroll(
+        self: &Arc<Self>,
+        queue_len: usize,
+        policy: DropPolicy,
+        preroll: usize,
+    ) -> Subscription {
+        self.subscribe_with(queue_len, policy, |history| preroll.min(history.len()))
+    }
+
+    // Whether frame 'seq' is still in the history.
+    pub fn holds(&self, seq: u32) -> bool {
+        self.history
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|frame| frame.seq == seq)
+    }
+
+    // Like 'subscribe', but the queue starts out with the frames after 'seq' that are
+    // still in the history, for a client that lost its connection after receiving it.
+    pub fn subscribe_after(
+        self: &Arc<Self>,
+        queue_len: usize,
+        policy: DropPolicy,
+        seq: u32,
+    ) -> Subscription {
+        self.subscribe_with(queue_len, policy, |history| {
+            history
+                .iter()
+                .rev()
+                .take_while(|frame| is_after(frame.seq, seq))
+                .count()
+        })
+    }
+
+    // 'n_preroll' picks how many of the newest frames in the history to start with.
+    fn subscribe_with(
+        self: &Arc<Self>,
+        queue_len: usize,
+        policy: DropPolicy,
+        n_preroll: impl FnOnce(&VecDeque<Frame>) -> usize,
+    ) -> Subscription {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        // same lock order as 'publish' so no frame is missed or sent twice
+        let mut clients = self.clients.lock().unwrap();
+        let history = self.history.lock().unwrap();
+        let preroll = n_preroll(&history);
+        let queue = Arc::new(ClientQueue {
+            frames: Mutex::new(
+                history
+                    .iter()
+                    .skip(history.len() - preroll)
+                    .cloned()
+                    .collect(),
+            ),
+            capacity: queue_len.max(1) + preroll,
+            policy,
+            closed: AtomicBool::new(false),
+            readable: Notify::new(),
+            writable: Notify::new(),
+            dropped: AtomicU64::new(0),
+        });
+        drop(history);
+        clients.insert(id, queue.clone());
+        Subscription {
+            id,
+            queue_len,
+            queue,
+            distributor: self.clone(),
+        }
+    }
+
+    fn unsubscribe(&self, id: u64) {
+        if let Some(queue) = self.clients.lock().unwrap().remove(&id) {
+            queue.close();
+            let dropped = queue.dropped.load(Ordering::Relaxed);
+            if dropped > 0 {
+                info!(client = id, dropped, "client dropped frames");
+            }
+        }
+    }
+
+    // Hand 'frame' to every client; only waits if a 'DropPolicy::Block' client is full.
+    pub async fn publish(&self, frame: Frame) {
+        let mut blocked = Vec::new();
+        {
+            let mut clients = self.clients.lock().unwrap();
+            if self.history_len > 0 {
+                let mut history = self.history.lock().unwrap();
+                if history.len() == self.history_len {
+                    history.pop_front();
+                }
+                history.push_back(frame.clone());
+            }
+            clients.retain(|id, queue| match queue.offer(*id, frame.clone()) {
+                Ok(keep) => keep,
+                Err(frame) => {
+                    blocked.push((queue.clone(), frame));
+                    true
+                }
+            });
+        }
+        for (queue, frame) in blocked {
+            queue.push_blocking(frame).await;
+        }
+    }
+
+    // Wait for each new packet from the packetizer and hand it to every subscriber as
+    // an audio frame; the sequence number is shared by all clients so gaps reveal drops.
+    // Header-only packets stand for silence, reported as silence frames of up to
+    // 'SILENCE_FRAME_MS'.
+    pub async fn run(self: Arc<Self>, mut packets: watch::Receiver<(Timestamp, Bytes)>) {
+        let mut seq = 0_u32;
+        let mut silence = SilenceRun::default();
+        let sample_rate = self.stream.sample_rate as u64;
+        while packets.changed().await.is_ok() {
+            let (captured, payload) = packets.borrow_and_update().clone();
+            let silence_frame = if payload.len() == HEADER_LEN {
+                silence.push(captured, sample_rate)
+            } else {
+                silence.finish(sample_rate)
+            };
+            if let Some((start, ms)) = silence_frame {
+                self.publish(Frame::silence(seq, start, ms)).await;
+                seq = seq.wrapping_add(1);
+            }
+            if payload.len() > HEADER_LEN {
+                self.publish(Frame::audio(seq, captured, payload)).await;
+                seq = seq.wrapping_add(1);
+            }
+        }
+    }
+}
+
+// Silent packets since the last audio one, not all reported yet.
+#[derive(Default)]
+pub(crate) struct SilenceRun {
+    samples: u64,
+    reported_ms: u64,
+    // capture time of the first unreported packet
+    start: Option<Timestamp>,
+}
+
+impl SilenceRun {
+    // The silence to report once a frame's worth has come together.
+    pub(crate) fn push(
+        &mut self,
+        captured: Timestamp,
+        sample_rate: u64,
+    ) -> Option<(Timestamp, u32)> {
+        self.start.get_or_insert(captured);
+        self.samples += PACKET_N_SAMPLE as u64;
+        if self.pending_ms(sample_rate) < SILENCE_FRAME_MS {
+            return None;
+        }
+        self.take(sample_rate)
+    }
+
+    // What's left to report when audio resumes.
+    pub(crate) fn finish(&mut self, sample_rate: u64) -> Option<(Timestamp, u32)> {
+        let rest = self.take(sample_rate);
+        *self = SilenceRun::default();
+        rest
+    }
+
+    fn pending_ms(&self, sample_rate: u64) -> u64 {
+        self.samples * 1000 / sample_rate - self.reported_ms
+    }
+
+    fn take(&mut self, sample_rate: u64) -> Option<(Timestamp, u32)> {
+        let ms = self.pending_ms(sample_rate);
+        if ms == 0 {
+            return None;
+        }
+        self.reported_ms += ms;
+        Some((self.start.take()?, ms as u32))
+    }
+}
+
+// Number of packets covering 'ms' milliseconds of audio at 'sample_rate'.
+pub fn frames_in(ms: u64, sample_rate: usize) -> usize {
+    (ms as usize * sample_rate).div_ceil(1000 * PACKET_N_SAMPLE)
+}
+
+impl Subscription {
+    pub fn stream_info(&self) -> StreamInfo {
+        self.distributor.stream
+    }
+
+    // The first frame to send a client, before any audio.
+    pub fn stream_info_frame(&self) -> Frame {
+        Frame::stream_info(&self.distributor.stream)
+    }
+
+    // Next frame for this client; None once the distributor has dropped the client.
+    pub async fn recv(&mut self) -> Option<Frame> {
+        loop {
+            let frame = self.queue.frames.lock().unwrap().pop_front();
+            if let Some(frame) = frame {
+                self.queue.writable.notify_one();
+                return Some(frame);
+            }
+            if self.queue.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            self.queue.readable.notified().await;
+        }
+    }
+
+    // Frames skipped for this client because its queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped.load(Ordering::Relaxed)
+    }
+
+    // Whether the stream can resume after frame 'seq'.
+    pub fn holds(&self, seq: u32) -> bool {
+        self.distributor.holds(seq)
+    }
+
+    // A subscription like this one to the same stream, starting after frame 'seq' as
+    // 'Distributor::subscribe_after' does; this one's queued frames aren't carried over.
+    pub fn resume_after(&self, seq: u32) -> Subscription {
+        self.distributor
+            .subscribe_after(self.queue_len, self.queue.policy, seq)
+    }
+}
+
+// Whether 'seq' comes after 'other', allowing for wraparound.
+pub(crate) fn is_after(seq: u32, other: u32) -> bool {
+    let diff = seq.wrapping_sub(other);
+    diff != 0 && diff < u32::MAX / 2
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.distributor.unsubscribe(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time;
+
+    fn distributor(history_len: usize) -> Arc<Distributor> {
+        Distributor::new(StreamInfo::pcm(16000, 1), history_len)
+    }
+
+    async fn publish(distributor: &Distributor, seqs: std::ops::Range<u32>) {
+        for seq in seqs {
+            distributor
+                .publish(Frame::silence(seq, Timestamp::default(), 10))
+                .await;
+        }
+    }
+
+    // What is queued for 'subscription' right now, without waiting for more.
+    async fn queued(subscription: &mut Subscription) -> Vec<u32> {
+        let mut seqs = Vec::new();
+        while let Ok(Some(frame)) = time::timeout(Duration::ZERO, subscription.recv()).await {
+            seqs.push(frame.seq);
+        }
+        seqs
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_the_first_frames() {
+        let distributor = distributor(0);
+        let mut client = distributor.subscribe(3, DropPolicy::DropNewest);
+        publish(&distributor, 0..5).await;
+        assert_eq!(queued(&mut client).await, [0, 1, 2]);
+        assert_eq!(client.dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_latest_frames() {
+        let distributor = distributor(0);
+        let mut client = distributor.subscribe(3, DropPolicy::DropOldest);
+        publish(&distributor, 0..5).await;
+        assert_eq!(queued(&mut client).await, [2, 3, 4]);
+        assert_eq!(client.dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_only_the_full_queue() {
+        let distributor = distributor(0);
+        let mut slow = distributor.subscribe(2, DropPolicy::Disconnect);
+        let mut other = distributor.subscribe(8, DropPolicy::Disconnect);
+        publish(&distributor, 0..4).await;
+        // what was queued before still arrives, then the end of stream
+        assert_eq!(slow.recv().await.map(|frame| frame.seq), Some(0));
+        assert_eq!(slow.recv().await.map(|frame| frame.seq), Some(1));
+        assert!(slow.recv().await.is_none());
+        assert_eq!(queued(&mut other).await, [0, 1, 2, 3]);
+        assert_eq!(distributor.clients.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let distributor = distributor(0);
+        let mut client = distributor.subscribe(1, DropPolicy::Block);
+        publish(&distributor, 0..1).await;
+        let publisher = tokio::spawn({
+            let distributor = distributor.clone();
+            async move { publish(&distributor, 1..2).await }
+        });
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(!publisher.is_finished());
+        assert_eq!(client.recv().await.map(|frame| frame.seq), Some(0));
+        time::timeout(Duration::from_secs(1), publisher)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(queued(&mut client).await, [1]);
+        assert_eq!(client.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn block_gives_up_on_a_client_that_leaves() {
+        let distributor = distributor(0);
+        let client = distributor.subscribe(1, DropPolicy::Block);
+        publish(&distributor, 0..1).await;
+        let publisher = tokio::spawn({
+            let distributor = distributor.clone();
+            async move { publish(&distributor, 1..2).await }
+        });
+        time::sleep(Duration::from_millis(20)).await;
+        drop(client);
+        time::timeout(Duration::from_secs(1), publisher)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn preroll_starts_with_the_newest_history() {
+        let distributor = distributor(4);
+        publish(&distributor, 0..6).await;
+        assert!(!distributor.holds(1));
+        assert!(distributor.holds(2));
+        let mut client = distributor.subscribe_with_preroll(2, DropPolicy::DropNewest, 3);
+        publish(&distributor, 6..9).await;
+        // the preroll doesn't eat into the queue's own room
+        assert_eq!(queued(&mut client).await, [3, 4, 5, 6, 7]);
+        let mut late = distributor.subscribe_with_preroll(2, DropPolicy::DropNewest, 10);
+        assert_eq!(queued(&mut late).await, [5, 6, 7, 8]);
+    }
+
+    #[tokio::test]
+    async fn subscribe_after_resumes_after_the_last_frame() {
+        let distributor = distributor(4);
+        publish(&distributor, 0..6).await;
+        let mut resumed = distributor.subscribe_after(8, DropPolicy::DropNewest, 3);
+        assert_eq!(queued(&mut resumed).await, [4, 5]);
+        // nothing newer than the last frame received
+        let mut current = distributor.subscribe_after(8, DropPolicy::DropNewest, 5);
+        assert!(queued(&mut current).await.is_empty());
+        publish(&distributor, 6..7).await;
+        assert_eq!(queued(&mut current).await, [6]);
+    }
+
+    #[test]
+    fn seqs_wrap_around() {
+        assert!(is_after(0, u32::MAX));
+        assert!(is_after(5, 3));
+        assert!(!is_after(3, 5));
+        assert!(!is_after(3, 3));
+    }
+}
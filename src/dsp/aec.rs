@@ -0,0 +1,82 @@
+use super::Stage;
+use crate::config_file::AecConfig;
+use crate::PACKET_N_SAMPLE;
+use aec_rs::{Aec as SpeexAec, AecConfig as SpeexConfig};
+use tracing::warn;
+
+// Acoustic echo cancellation with speexdsp, for machines that also play the remote side
+// of a call. The 'aec.reference' channels of the capture, e.g. the loopback input of
+// an interleaved mix, are what the speakers play; their echo is taken out of all the
+// other channels, each with its own adaptive filter. The reference channels pass through.
+pub struct Aec {
+    reference: Vec<usize>,
+    tail: usize,
+    sample_rate: usize,
+    // per channel, None for the reference channels; built on the first packet
+    cancellers: Vec<Option<Canceller>>,
+    far_end: Vec<i16>,
+    near_end: Vec<i16>,
+}
+
+// The speex state is only used by one thread at a time, the one running the chain.
+struct Canceller(SpeexAec);
+
+unsafe impl Send for Canceller {}
+
+impl Aec {
+    pub fn new(cfg: &AecConfig, sample_rate: usize) -> Aec {
+        Aec {
+            reference: cfg.reference.clone(),
+            tail: cfg.tail * sample_rate / 1000,
+            sample_rate,
+            cancellers: Vec::new(),
+            far_end: vec![0; PACKET_N_SAMPLE],
+            near_end: vec![0; PACKET_N_SAMPLE],
+        }
+    }
+
+    fn canceller(&self) -> Canceller {
+        Canceller(SpeexAec::new(&SpeexConfig {
+            frame_size: PACKET_N_SAMPLE,
+            filter_length: self.tail as i32,
+            sample_rate: self.sample_rate as u32,
+            // speex's residual echo suppression
+            enable_preprocess: true,
+        }))
+    }
+}
+
+impl Stage for Aec {
+    fn process(&mut self, audio: &mut [i16]) {
+        let n_ch = audio.len() / PACKET_N_SAMPLE;
+        if self.cancellers.len() != n_ch {
+            if let Some(ch) = self.reference.iter().find(|&&ch| ch >= n_ch) {
+                warn!(
+                    "aec reference channel {} is outside the {} captured ones",
+                    ch, n_ch
+                );
+            }
+            self.cancellers = (0..n_ch)
+                .map(|ch| (!self.reference.contains(&ch)).then(|| self.canceller()))
+                .collect();
+        }
+
+        // several reference channels are summed
+        self.far_end.fill(0);
+        for ch in self.reference.iter().filter(|&&ch| ch < n_ch) {
+            let samples = &audio[ch * PACKET_N_SAMPLE..(ch + 1) * PACKET_N_SAMPLE];
+            for (far, &sample) in self.far_end.iter_mut().zip(samples) {
+                *far = far.saturating_add(sample);
+            }
+        }
+        for (samples, canceller) in audio
+            .chunks_exact_mut(PACKET_N_SAMPLE)
+            .zip(&self.cancellers)
+        {
+            if let Some(Canceller(aec)) = canceller {
+                self.near_end.copy_from_slice(samples);
+                aec.cancel_echo(&self.near_end, &self.far_end, samples);
+            }
+        }
+    }
+}
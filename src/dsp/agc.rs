@@ -0,0 +1,65 @@
+use super::{packet_duration, rms_dbfs, Stage};
+use crate::config_file::AgcConfig;
+use crate::PACKET_N_SAMPLE;
+
+// Automatic gain control. One gain is shared by all channels so the level differences
+// between microphones, which beamforming clients rely on, are preserved.
+pub struct Agc {
+    target_level: f32,
+    max_gain: f32,
+    // per-packet smoothing factors for decreasing and increasing the gain
+    attack: f32,
+    release: f32,
+    // dB
+    gain: f32,
+}
+
+impl Agc {
+    pub fn new(cfg: &AgcConfig, sample_rate: usize) -> Agc {
+        let dt = packet_duration(sample_rate);
+        Agc {
+            target_level: cfg.target_level,
+            max_gain: cfg.max_gain,
+            attack: smoothing(cfg.attack, dt),
+            release: smoothing(cfg.release, dt),
+            gain: 0.0,
+        }
+    }
+}
+
+// Fraction of the remaining distance covered per packet for a time constant in ms.
+fn smoothing(time_ms: f32, dt: f32) -> f32 {
+    if time_ms <= 0.0 {
+        return 1.0;
+    }
+    1.0 - (-dt * 1000.0 / time_ms).exp()
+}
+
+fn db_to_linear(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+impl Stage for Agc {
+    fn process(&mut self, audio: &mut [i16]) {
+        let level = rms_dbfs(audio);
+        let prev_gain = self.gain;
+        if level.is_finite() {
+            let wanted = (self.target_level - level).min(self.max_gain);
+            let rate = if wanted < self.gain {
+                self.attack
+            } else {
+                self.release
+            };
+            self.gain += (wanted - self.gain) * rate;
+        }
+
+        // ramp across the packet so gain changes don't click
+        let (from, to) = (db_to_linear(prev_gain), db_to_linear(self.gain));
+        for ch in audio.chunks_exact_mut(PACKET_N_SAMPLE) {
+            for (i, sample) in ch.iter_mut().enumerate() {
+                let gain = from + (to - from) * (i + 1) as f32 / PACKET_N_SAMPLE as f32;
+                *sample = (*sample as f32 * gain).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use super::{Stage, CONTROLS};
+use crate::audio::resample::Resampler;
+use crate::PACKET_N_SAMPLE;
+use nnnoiseless::DenoiseState;
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+
+// RNNoise only runs on 48 kHz audio in frames of 'DenoiseState::FRAME_SIZE' samples.
+const RNNOISE_RATE: usize = 48000;
+// covers the resampler kernels on both sides
+const RESAMPLE_DELAY: usize = 64;
+
+struct ChannelDenoiser {
+    state: Box<DenoiseState<'static>>,
+    upsampler: Resampler,
+    downsampler: Resampler,
+    // samples at 48 kHz waiting for a full rnnoise frame
+    frame_buf: Vec<i16>,
+    // denoised samples at the capture rate
+    output: VecDeque<i16>,
+}
+
+impl ChannelDenoiser {
+    fn new(sample_rate: usize) -> ChannelDenoiser {
+        // enough delay that every packet can be served from samples processed earlier
+        let delay = DenoiseState::FRAME_SIZE * sample_rate / RNNOISE_RATE + RESAMPLE_DELAY;
+        ChannelDenoiser {
+            state: DenoiseState::new(),
+            upsampler: Resampler::new(1, sample_rate, RNNOISE_RATE),
+            downsampler: Resampler::new(1, RNNOISE_RATE, sample_rate),
+            frame_buf: Vec::new(),
+            output: VecDeque::from(vec![0; delay]),
+        }
+    }
+
+    fn process(&mut self, samples: &mut [i16]) {
+        let mut upsampled = [Vec::new()];
+        self.upsampler.process(&[samples], &mut upsampled);
+        self.frame_buf.extend_from_slice(&upsampled[0]);
+
+        let mut input = [0.0_f32; DenoiseState::FRAME_SIZE];
+        let mut denoised = [0.0_f32; DenoiseState::FRAME_SIZE];
+        let mut frame = [0_i16; DenoiseState::FRAME_SIZE];
+        while self.frame_buf.len() >= DenoiseState::FRAME_SIZE {
+            for (dst, &src) in input.iter_mut().zip(self.frame_buf.iter()) {
+                *dst = src as f32;
+            }
+            self.frame_buf.drain(..DenoiseState::FRAME_SIZE);
+            self.state.process_frame(&mut denoised, &input);
+            for (dst, &src) in frame.iter_mut().zip(denoised.iter()) {
+                *dst = src.clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            }
+            let mut downsampled = [Vec::new()];
+            self.downsampler.process(&[&frame], &mut downsampled);
+            self.output.extend(downsampled[0].iter());
+        }
+
+        for sample in samples.iter_mut() {
+            *sample = self.output.pop_front().unwrap_or(0);
+        }
+    }
+}
+
+// RNNoise noise suppression, each channel on its own. Adds about one rnnoise frame
+// (10 ms) of latency, and can be switched on and off at runtime through 'CONTROLS'.
+pub struct Denoise {
+    sample_rate: usize,
+    channels: Vec<ChannelDenoiser>,
+}
+
+impl Denoise {
+    pub fn new(sample_rate: usize) -> Denoise {
+        Denoise {
+            sample_rate,
+            channels: Vec::new(),
+        }
+    }
+}
+
+impl Stage for Denoise {
+    fn process(&mut self, audio: &mut [i16]) {
+        if !CONTROLS.denoise.load(Ordering::Relaxed) {
+            // start from a clean state when switched back on
+            self.channels.clear();
+            return;
+        }
+        let n_ch = audio.len() / PACKET_N_SAMPLE;
+        if self.channels.len() != n_ch {
+            self.channels = (0..n_ch)
+                .map(|_| ChannelDenoiser::new(self.sample_rate))
+                .collect();
+        }
+        for (ch, samples) in self
+            .channels
+            .iter_mut()
+            .zip(audio.chunks_exact_mut(PACKET_N_SAMPLE))
+        {
+            ch.process(samples);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+// Compressor with make-up gain, then a brick-wall limiter, so hot microphones don't clip
+// the 16-bit wire format. Like the agc, one gain for all channels.
+use super::Stage;
+use crate::config_file::DynamicsConfig;
+use crate::PACKET_N_SAMPLE;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use tracing::error;
+
+// ms for the limiter to let go after a peak
+const LIMITER_RELEASE: f32 = 50.0;
+
+// The parameters, from [dynamics] at startup and changeable through the admin api.
+pub static DYNAMICS: Dynamics = Dynamics {
+    enable: AtomicBool::new(false),
+    threshold: AtomicU32::new(0),
+    ratio: AtomicU32::new(0),
+    attack: AtomicU32::new(0),
+    release: AtomicU32::new(0),
+    makeup: AtomicU32::new(0),
+    ceiling: AtomicU32::new(0),
+};
+
+// f32 bits, see 'DynamicsState'
+pub struct Dynamics {
+    enable: AtomicBool,
+    threshold: AtomicU32,
+    ratio: AtomicU32,
+    attack: AtomicU32,
+    release: AtomicU32,
+    makeup: AtomicU32,
+    ceiling: AtomicU32,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct DynamicsState {
+    pub enable: bool,
+    // dBFS above which the compressor reduces the gain
+    pub threshold: f32,
+    // dB over the threshold for each dB out
+    pub ratio: f32,
+    // ms
+    pub attack: f32,
+    pub release: f32,
+    // dB added after compression
+    pub makeup: f32,
+    // dBFS no sample exceeds
+    pub ceiling: f32,
+}
+
+impl Dynamics {
+    pub fn configure(&self, cfg: &DynamicsConfig) {
+        self.enable.store(cfg.enable, Ordering::Relaxed);
+        for (key, value) in [
+            ("threshold", cfg.threshold),
+            ("ratio", cfg.ratio),
+            ("attack", cfg.attack),
+            ("release", cfg.release),
+            ("makeup", cfg.makeup),
+            ("ceiling", cfg.ceiling),
+        ] {
+            if let Err(err) = self.set(key, value) {
+                error!("dynamics: {}", err);
+            }
+        }
+    }
+
+    pub fn set_enabled(&self, enable: bool) {
+        self.enable.store(enable, Ordering::Relaxed);
+    }
+
+    // Change one parameter by its name in 'DynamicsState'; the error explains a bad one.
+    pub fn set(&self, key: &str, value: f32) -> Result<(), String> {
+        self.check(key, value)?
+            .store(value.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    // What 'set' would make of 'value', without setting it.
+    pub fn check(&self, key: &str, value: f32) -> Result<&AtomicU32, String> {
+        let param = self
+            .param(key)
+            .ok_or_else(|| format!("unknown dynamics parameter \"{}\"", key))?;
+        let valid = value.is_finite()
+            && match key {
+                "ratio" => value >= 1.0,
+                "attack" | "release" => value >= 0.0,
+                "ceiling" => value <= 0.0,
+                _ => true,
+            };
+        if !valid {
+            return Err(format!("bad {} {}", key, value));
+        }
+        Ok(param)
+    }
+
+    pub fn state(&self) -> DynamicsState {
+        DynamicsState {
+            enable: self.enable.load(Ordering::Relaxed),
+            threshold: self.get("threshold"),
+            ratio: self.get("ratio"),
+            attack: self.get("attack"),
+            release: self.get("release"),
+            makeup: self.get("makeup"),
+            ceiling: self.get("ceiling"),
+        }
+    }
+
+    fn get(&self, key: &str) -> f32 {
+        self.param(key)
+            .map(|param| f32::from_bits(param.load(Ordering::Relaxed)))
+            .unwrap_or_default()
+    }
+
+    fn param(&self, key: &str) -> Option<&AtomicU32> {
+        match key {
+            "threshold" => Some(&self.threshold),
+            "ratio" => Some(&self.ratio),
+            "attack" => Some(&self.attack),
+            "release" => Some(&self.release),
+            "makeup" => Some(&self.makeup),
+            "ceiling" => Some(&self.ceiling),
+            _ => None,
+        }
+    }
+}
+
+// Always in the chain so it can be switched on later; idle while off.
+pub struct Compressor {
+    sample_rate: f32,
+    // dB the compressor takes off, smoothed
+    reduction: f32,
+    // linear, 1 while the limiter is idle
+    limiter_gain: f32,
+}
+
+impl Compressor {
+    pub fn new(sample_rate: usize) -> Compressor {
+        Compressor {
+            sample_rate: sample_rate as f32,
+            reduction: 0.0,
+            limiter_gain: 1.0,
+        }
+    }
+
+    // Per-sample smoothing factor for a time constant in ms.
+    fn coefficient(&self, time_ms: f32) -> f32 {
+        if time_ms <= 0.0 {
+            return 0.0;
+        }
+        (-1000.0 / (time_ms * self.sample_rate)).exp()
+    }
+}
+
+impl Stage for Compressor {
+    fn process(&mut self, audio: &mut [i16]) {
+        let params = DYNAMICS.state();
+        if !params.enable {
+            self.reduction = 0.0;
+            self.limiter_gain = 1.0;
+            return;
+        }
+        let attack = self.coefficient(params.attack);
+        let release = self.coefficient(params.release);
+        let limiter_release = self.coefficient(LIMITER_RELEASE);
+        // guards against a bad [dynamics] section, which 'configure' only reports
+        let slope = 1.0 - 1.0 / params.ratio.max(1.0);
+        let ceiling = 10.0_f32.powf(params.ceiling.min(0.0) / 20.0) * i16::MAX as f32;
+
+        let n_ch = audio.len() / PACKET_N_SAMPLE;
+        for i in 0..PACKET_N_SAMPLE {
+            let peak = (0..n_ch)
+                .map(|ch| (audio[ch * PACKET_N_SAMPLE + i] as f32).abs())
+                .fold(0.0, f32::max);
+            let level = 20.0 * (peak / i16::MAX as f32).max(1e-6).log10();
+            let wanted = (level - params.threshold).max(0.0) * slope;
+            let coefficient = if wanted > self.reduction {
+                attack
+            } else {
+                release
+            };
+            self.reduction = wanted + (self.reduction - wanted) * coefficient;
+            let gain = 10.0_f32.powf((params.makeup - self.reduction) / 20.0);
+
+            // the limiter clamps down at once and recovers over 'LIMITER_RELEASE'
+            self.limiter_gain = 1.0 + (self.limiter_gain - 1.0) * limiter_release;
+            if peak * gain * self.limiter_gain > ceiling {
+                self.limiter_gain = ceiling / (peak * gain);
+            }
+            let gain = gain * self.limiter_gain;
+            for ch in 0..n_ch {
+                let sample = &mut audio[ch * PACKET_N_SAMPLE + i];
+                *sample = (*sample as f32 * gain).clamp(-ceiling, ceiling) as i16;
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use super::Stage;
+use crate::config_file::FiltersConfig;
+use crate::PACKET_N_SAMPLE;
+use std::f32::consts::{FRAC_1_SQRT_2, TAU};
+use tracing::warn;
+
+// Second-order sections after the Audio EQ Cookbook, normalized to a0 = 1.
+#[derive(Clone, Copy, Debug)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Biquad {
+    fn high_pass(sample_rate: usize, frequency: f32, q: f32) -> Biquad {
+        let (cos, alpha) = cos_alpha(sample_rate, frequency, q);
+        Biquad::normalized(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn low_pass(sample_rate: usize, frequency: f32, q: f32) -> Biquad {
+        let (cos, alpha) = cos_alpha(sample_rate, frequency, q);
+        Biquad::normalized(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    // 'gain' dB at 'frequency', falling off to none at a width set by 'q'.
+    fn peaking(sample_rate: usize, frequency: f32, gain: f32, q: f32) -> Biquad {
+        let (cos, alpha) = cos_alpha(sample_rate, frequency, q);
+        let a = 10.0_f32.powf(gain / 40.0);
+        Biquad::normalized(
+            [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+        )
+    }
+
+    fn normalized(b: [f32; 3], a: [f32; 3]) -> Biquad {
+        Biquad {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+        }
+    }
+
+    // Transposed direct form II; 'state' carries over between packets.
+    fn run(&self, state: &mut [f32; 2], x: f32) -> f32 {
+        let y = self.b0 * x + state[0];
+        state[0] = self.b1 * x - self.a1 * y + state[1];
+        state[1] = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+fn cos_alpha(sample_rate: usize, frequency: f32, q: f32) -> (f32, f32) {
+    let w0 = TAU * frequency / sample_rate as f32;
+    (w0.cos(), w0.sin() / (2.0 * q))
+}
+
+// The high-pass, low-pass and eq bands of [filters], in that order, on every channel.
+pub struct Filters {
+    sections: Vec<Biquad>,
+    // per channel, per section
+    states: Vec<Vec<[f32; 2]>>,
+}
+
+impl Filters {
+    // None when [filters] sets none that the sample rate can carry.
+    pub fn new(cfg: &FiltersConfig, sample_rate: usize) -> Option<Filters> {
+        let usable = |what: &str, frequency: f32| {
+            let ok = frequency > 0.0 && frequency < sample_rate as f32 / 2.0;
+            if !ok {
+                warn!(
+                    "leaving out the {} filter: {} Hz is outside 0 to {} Hz",
+                    what,
+                    frequency,
+                    sample_rate / 2
+                );
+            }
+            ok
+        };
+        let mut sections = Vec::new();
+        if let Some(frequency) = cfg.high_pass.filter(|&f| usable("high-pass", f)) {
+            sections.push(Biquad::high_pass(sample_rate, frequency, FRAC_1_SQRT_2));
+        }
+        if let Some(frequency) = cfg.low_pass.filter(|&f| usable("low-pass", f)) {
+            sections.push(Biquad::low_pass(sample_rate, frequency, FRAC_1_SQRT_2));
+        }
+        for band in &cfg.eq {
+            if !usable("eq", band.frequency) {
+                continue;
+            }
+            if band.q <= 0.0 {
+                warn!(
+                    "leaving out the eq band at {} Hz: q must be above 0",
+                    band.frequency
+                );
+                continue;
+            }
+            sections.push(Biquad::peaking(
+                sample_rate,
+                band.frequency,
+                band.gain,
+                band.q,
+            ));
+        }
+        if sections.is_empty() {
+            return None;
+        }
+        Some(Filters {
+            sections,
+            states: Vec::new(),
+        })
+    }
+}
+
+impl Stage for Filters {
+    fn process(&mut self, audio: &mut [i16]) {
+        let n_ch = audio.len() / PACKET_N_SAMPLE;
+        if self.states.len() != n_ch {
+            self.states = vec![vec![[0.0; 2]; self.sections.len()]; n_ch];
+        }
+        for (ch, states) in audio
+            .chunks_exact_mut(PACKET_N_SAMPLE)
+            .zip(&mut self.states)
+        {
+            for sample in ch.iter_mut() {
+                let mut x = *sample as f32;
+                for (section, state) in self.sections.iter().zip(states.iter_mut()) {
+                    x = section.run(state, x);
+                }
+                *sample = x.clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+// Gain and mute of single outputs, on top of the capture-wide 'CONTROLS.mute'.
+use crate::audio::encode::mapped;
+use crate::distributor::Distributor;
+use crate::HEADER_LEN;
+use bytes::{BufMut, BytesMut};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Level controls of the running outputs by name ("tcp", "udp", ...), for the admin api.
+pub static OUTPUT_LEVELS: OutputLevels = OutputLevels {
+    levels: Mutex::new(BTreeMap::new()),
+};
+
+pub struct OutputLevels {
+    levels: Mutex<BTreeMap<String, Arc<OutputLevel>>>,
+}
+
+impl OutputLevels {
+    // Streams registering under the same name share one level.
+    fn register(&self, name: &str, gain_db: f32) -> Arc<OutputLevel> {
+        let mut levels = self.levels.lock().unwrap();
+        let level = levels.entry(name.to_string()).or_insert_with(|| {
+            Arc::new(OutputLevel {
+                gain_db: AtomicU32::new(gain_db.to_bits()),
+                mute: AtomicBool::new(false),
+            })
+        });
+        level.clone()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<OutputLevel>> {
+        self.levels.lock().unwrap().get(name).cloned()
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, LevelState> {
+        let levels = self.levels.lock().unwrap();
+        levels
+            .iter()
+            .map(|(name, level)| (name.clone(), level.state()))
+            .collect()
+    }
+}
+
+pub struct OutputLevel {
+    // f32 bits
+    gain_db: AtomicU32,
+    mute: AtomicBool,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct LevelState {
+    pub gain: f32,
+    pub mute: bool,
+}
+
+impl OutputLevel {
+    pub fn gain_db(&self) -> f32 {
+        f32::from_bits(self.gain_db.load(Ordering::Relaxed))
+    }
+
+    pub fn set_gain_db(&self, gain_db: f32) -> Result<(), String> {
+        if !gain_db.is_finite() {
+            return Err(format!("bad gain {}", gain_db));
+        }
+        self.gain_db.store(gain_db.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn muted(&self) -> bool {
+        self.mute.load(Ordering::Relaxed)
+    }
+
+    pub fn set_muted(&self, mute: bool) {
+        self.mute.store(mute, Ordering::Relaxed);
+    }
+
+    pub fn state(&self) -> LevelState {
+        LevelState {
+            gain: self.gain_db(),
+            mute: self.muted(),
+        }
+    }
+}
+
+// Distributor carrying the pcm frames of 'input' at the level of output 'name', which
+// starts at 'gain_db' and unmuted unless 'name' has one already; samples that the gain pushes over full scale clip.
+pub fn leveled(input: &Arc<Distributor>, name: &str, gain_db: f32) -> Arc<Distributor> {
+    let level = OUTPUT_LEVELS.register(name, gain_db);
+    mapped(input, input.stream_info(), move |payload| {
+        let samples = payload.get(HEADER_LEN..)?;
+        let mut out = BytesMut::with_capacity(payload.len());
+        out.extend_from_slice(&payload[..HEADER_LEN]);
+        if level.muted() {
+            out.put_bytes(0, samples.len());
+            return Some(out.freeze());
+        }
+        let gain = 10_f32.powf(level.gain_db() / 20.0);
+        if gain == 1.0 {
+            out.extend_from_slice(samples);
+            return Some(out.freeze());
+        }
+        for b in samples.chunks_exact(2) {
+            let sample = i16::from_ne_bytes([b[0], b[1]]) as f32 * gain;
+            out.put_i16_ne(sample.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+        }
+        Some(out.freeze())
+    })
+}
@@ -0,0 +1,27 @@
+use crate::dsp::rms_dbfs;
+use crate::PACKET_N_SAMPLE;
+use serde::Serialize;
+
+// Levels of the latest captured packet per channel, in dBFS; -inf (null in json) for
+// digital silence.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Levels {
+    pub rms: Vec<f32>,
+    pub peak: Vec<f32>,
+}
+
+impl Levels {
+    // 'audio' holds 'PACKET_N_SAMPLE' samples per channel, one channel after another.
+    pub fn measure(audio: &[i16]) -> Levels {
+        let channels = audio.chunks_exact(PACKET_N_SAMPLE);
+        Levels {
+            rms: channels.clone().map(rms_dbfs).collect(),
+            peak: channels.map(peak_dbfs).collect(),
+        }
+    }
+}
+
+fn peak_dbfs(samples: &[i16]) -> f32 {
+    let peak = samples.iter().map(|s| s.unsigned_abs()).max().unwrap_or(0);
+    20.0 * (peak as f32 / i16::MAX as f32).log10()
+}
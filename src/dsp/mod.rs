@@ -0,0 +1,137 @@
+// Processing applied to captured audio before it is packetized and sent.
+use crate::config_file::Config;
+use crate::PACKET_N_SAMPLE;
+use plugin::PLUGINS;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::error;
+
+#[cfg(feature = "aec")]
+pub mod aec;
+pub mod agc;
+pub mod denoise;
+pub mod dynamics;
+pub mod filters;
+pub mod level;
+pub mod meter;
+pub mod plugin;
+
+// Switches that clients can flip at runtime with control frames.
+pub static CONTROLS: Controls = Controls {
+    denoise: AtomicBool::new(false),
+    mute: AtomicBool::new(false),
+};
+
+pub struct Controls {
+    pub denoise: AtomicBool,
+    // send silence instead of the microphones
+    pub mute: AtomicBool,
+}
+
+impl Controls {
+    // Apply a control command such as "denoise on"; the error explains a bad command.
+    pub fn apply(&self, command: &str) -> Result<(), String> {
+        let mut words = command.split_whitespace();
+        let switch = match words.next() {
+            Some("denoise") => &self.denoise,
+            Some("mute") => &self.mute,
+            _ => return Err(format!("unknown control \"{}\"", command)),
+        };
+        match (words.next(), words.next()) {
+            (Some("on"), None) => switch.store(true, Ordering::Relaxed),
+            (Some("off"), None) => switch.store(false, Ordering::Relaxed),
+            _ => return Err(format!("expected on or off in \"{}\"", command)),
+        }
+        Ok(())
+    }
+}
+
+// One processing step. 'audio' holds 'PACKET_N_SAMPLE' samples per channel, one
+// channel after another, like the packet payload.
+pub trait Stage: Send {
+    fn process(&mut self, audio: &mut [i16]);
+}
+
+// Stages run in the order they were pushed.
+#[derive(Default)]
+pub struct Chain {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Chain {
+    pub fn push(&mut self, stage: Box<dyn Stage>) {
+        self.stages.push(stage);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    pub fn process(&mut self, audio: &mut [i16]) {
+        for stage in self.stages.iter_mut() {
+            stage.process(audio);
+        }
+    }
+}
+
+// The stages enabled in 'cfg'.
+pub fn build_chain(cfg: &Config) -> Chain {
+    let mut chain = Chain::default();
+    if cfg.aec.enable {
+        #[cfg(feature = "aec")]
+        chain.push(Box::new(aec::Aec::new(&cfg.aec, cfg.mic.sample_rate)));
+        #[cfg(not(feature = "aec"))]
+        error!("aec needs a build with --features aec");
+    }
+    if let Some(filters) = filters::Filters::new(&cfg.filters, cfg.mic.sample_rate) {
+        chain.push(Box::new(filters));
+    }
+    // always in the chain so it can be switched on later; idle while off
+    CONTROLS
+        .denoise
+        .store(cfg.denoise.enable, Ordering::Relaxed);
+    chain.push(Box::new(denoise::Denoise::new(cfg.mic.sample_rate)));
+    for plugin in &cfg.plugins.stages {
+        match PLUGINS.create(plugin, cfg.mic.sample_rate) {
+            Ok(stage) => chain.push(stage),
+            Err(err) => error!("leaving out dsp plugin {}: {}", plugin.name, err),
+        }
+    }
+    if cfg.agc.enable {
+        chain.push(Box::new(agc::Agc::new(&cfg.agc, cfg.mic.sample_rate)));
+    }
+    dynamics::DYNAMICS.configure(&cfg.dynamics);
+    chain.push(Box::new(dynamics::Compressor::new(cfg.mic.sample_rate)));
+    chain.push(Box::new(Mute));
+    chain
+}
+
+// Last in the chain, so the other stages keep adapting to the real input while muted.
+struct Mute;
+
+impl Stage for Mute {
+    fn process(&mut self, audio: &mut [i16]) {
+        if CONTROLS.mute.load(Ordering::Relaxed) {
+            audio.fill(0);
+        }
+    }
+}
+
+// RMS level of 'samples' in dB relative to full scale; -inf for digital silence.
+pub fn rms_dbfs(samples: &[i16]) -> f32 {
+    if samples.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+    let sum_sq: f64 = samples
+        .iter()
+        .map(|&s| {
+            let s = s as f64 / i16::MAX as f64;
+            s * s
+        })
+        .sum();
+    10.0 * (sum_sq / samples.len() as f64).log10() as f32
+}
+
+// Seconds of audio in one packet.
+pub fn packet_duration(sample_rate: usize) -> f32 {
+    PACKET_N_SAMPLE as f32 / sample_rate as f32
+}
@@ -0,0 +1,152 @@
+// Stages from outside the crate, e.g. a proprietary denoiser. An application embedding
+// mic2net registers a factory under a name before starting capture:
+//
+//     PLUGINS.register("acme_denoise", |ctx| {
+//         let strength = ctx.param("strength").and_then(|v| v.as_float()).unwrap_or(1.0);
+//         Ok(Box::new(AcmeDenoise::new(ctx.sample_rate, strength)))
+//     });
+//
+// and '[plugins] stages' names the ones 'build_chain' puts into the chain, in order,
+// with their settings.
+use crate::config_file::PluginConfig;
+use crate::dsp::Stage;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+pub static PLUGINS: Plugins = Plugins {
+    factories: Mutex::new(BTreeMap::new()),
+};
+
+// What a factory gets to build its stage from. The channel count isn't fixed until the
+// first packet: 'process' gets 'PACKET_N_SAMPLE' samples of each channel.
+pub struct PluginContext<'a> {
+    pub sample_rate: usize,
+    // the entry of 'plugins.stages' without its 'name'
+    pub params: &'a toml::value::Table,
+}
+
+impl PluginContext<'_> {
+    pub fn param(&self, key: &str) -> Option<&toml::Value> {
+        self.params.get(key)
+    }
+}
+
+type Factory = Box<dyn Fn(&PluginContext) -> crate::Result<Box<dyn Stage>> + Send + Sync>;
+
+pub struct Plugins {
+    factories: Mutex<BTreeMap<String, Factory>>,
+}
+
+impl Plugins {
+    // A later registration under the same name replaces the earlier one.
+    pub fn register<F>(&self, name: &str, factory: F)
+    where
+        F: Fn(&PluginContext) -> crate::Result<Box<dyn Stage>> + Send + Sync + 'static,
+    {
+        let mut factories = self.factories.lock().unwrap();
+        factories.insert(name.to_string(), Box::new(factory));
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.factories.lock().unwrap().keys().cloned().collect()
+    }
+
+    // The stage 'plugin' configures, built at 'sample_rate'.
+    pub fn create(
+        &self,
+        plugin: &PluginConfig,
+        sample_rate: usize,
+    ) -> crate::Result<Box<dyn Stage>> {
+        let factories = self.factories.lock().unwrap();
+        let factory = factories.get(&plugin.name).ok_or_else(|| {
+            format!(
+                "not registered, have {:?}",
+                factories.keys().collect::<Vec<_>>()
+            )
+        })?;
+        factory(&PluginContext {
+            sample_rate,
+            params: &plugin.params,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // adds its 'offset' to every sample, and the sample rate it was built at to the
+    // first one
+    struct Offset {
+        offset: i16,
+        sample_rate: i16,
+    }
+
+    impl Stage for Offset {
+        fn process(&mut self, audio: &mut [i16]) {
+            audio.iter_mut().for_each(|sample| *sample += self.offset);
+            audio[0] += self.sample_rate;
+        }
+    }
+
+    fn offset(offset: i64) -> impl Fn(&PluginContext) -> crate::Result<Box<dyn Stage>> {
+        move |ctx| {
+            Ok(Box::new(Offset {
+                offset: ctx
+                    .param("offset")
+                    .and_then(toml::Value::as_integer)
+                    .unwrap_or(offset) as i16,
+                sample_rate: ctx.sample_rate as i16,
+            }))
+        }
+    }
+
+    fn plugins() -> Plugins {
+        Plugins {
+            factories: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn config(name: &str, params: &str) -> PluginConfig {
+        PluginConfig {
+            name: name.to_string(),
+            params: toml::from_str(params).unwrap(),
+        }
+    }
+
+    fn run(stage: &mut dyn Stage) -> Vec<i16> {
+        let mut audio = vec![0; 3];
+        stage.process(&mut audio);
+        audio
+    }
+
+    #[test]
+    fn creates_with_the_config() {
+        let plugins = plugins();
+        plugins.register("offset", offset(1));
+        let mut stage = plugins
+            .create(&config("offset", "offset = 5"), 100)
+            .unwrap();
+        assert_eq!(run(stage.as_mut()), [105, 5, 5]);
+        let mut stage = plugins.create(&config("offset", ""), 200).unwrap();
+        assert_eq!(run(stage.as_mut()), [201, 1, 1]);
+    }
+
+    #[test]
+    fn unknown_name_is_an_error() {
+        let plugins = plugins();
+        plugins.register("offset", offset(1));
+        let err = plugins.create(&config("denoise", ""), 100).err().unwrap();
+        assert!(err.to_string().contains("offset"));
+    }
+
+    #[test]
+    fn later_registration_replaces() {
+        let plugins = plugins();
+        plugins.register("offset", offset(1));
+        plugins.register("offset", offset(2));
+        assert_eq!(plugins.names(), ["offset"]);
+        let mut stage = plugins.create(&config("offset", ""), 0).unwrap();
+        assert_eq!(run(stage.as_mut()), [2, 2, 2]);
+    }
+}
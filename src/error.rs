@@ -0,0 +1,156 @@
+// What the library fails with, by kind of failure, so embedders can tell a port that is
+// taken from a broken audio device or a misbehaving peer; the message has the details.
+use std::fmt;
+use std::io;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    // a listening socket couldn't be set up
+    #[error("can't bind {addr}: {source}")]
+    Bind {
+        addr: String,
+        #[source]
+        source: io::Error,
+    },
+    // accepting connections failed for good, after retrying
+    #[error("accept failed: {0}")]
+    Accept(#[source] io::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    // encoding or decoding audio
+    #[error("{0}")]
+    Codec(String),
+    // the audio device, server or file being captured
+    #[error("{0}")]
+    Capture(String),
+    // a peer that breaks the protocol, or a frame, message or request that doesn't parse
+    #[error("{0}")]
+    Protocol(String),
+    // one client connection failed; the server and its other clients carry on
+    #[error("{peer}: {phase} failed: {source}")]
+    Connection {
+        peer: String,
+        phase: Phase,
+        #[source]
+        source: Box<Error>,
+    },
+    // the rest: configuration, tls, and the errors of other libraries
+    #[error(transparent)]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl Error {
+    // For 'map_err' on binding 'addr'.
+    pub fn bind(addr: impl fmt::Display) -> impl FnOnce(io::Error) -> Error {
+        move |source| Error::Bind {
+            addr: addr.to_string(),
+            source,
+        }
+    }
+
+    // For 'map_err' on what 'peer' did in 'phase'; errors that already say where they
+    // came from keep that.
+    pub fn connection(peer: impl fmt::Display, phase: Phase) -> impl FnOnce(Error) -> Error {
+        move |source| match source {
+            Error::Connection { .. } => source,
+            source => Error::Connection {
+                peer: peer.to_string(),
+                phase,
+                source: Box::new(source),
+            },
+        }
+    }
+}
+
+// Where in a client connection an error came up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    // tls, authentication, or whatever else comes before the stream
+    Handshake,
+    Read,
+    Write,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Handshake => "handshake",
+            Phase::Read => "read",
+            Phase::Write => "write",
+        })
+    }
+}
+
+// Messages of setup errors, such as a config value that doesn't do; failures of one of
+// the kinds above are built as their variant.
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error::Other(message.into())
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Error {
+        Error::Other(message.into())
+    }
+}
+
+// The errors of other libraries, as the kind of failure they are here.
+macro_rules! from_errors {
+    (other: $($error:ty),* $(,)?) => {
+        $(
+            impl From<$error> for Error {
+                fn from(err: $error) -> Error {
+                    Error::Other(Box::new(err))
+                }
+            }
+        )*
+    };
+    ($variant:ident: $($error:ty),* $(,)?) => {
+        $(
+            impl From<$error> for Error {
+                fn from(err: $error) -> Error {
+                    Error::$variant(err.to_string())
+                }
+            }
+        )*
+    };
+}
+
+from_errors!(Capture: jack::Error);
+#[cfg(feature = "cpal")]
+from_errors!(Capture: cpal::Error);
+#[cfg(feature = "alsa")]
+from_errors!(Capture: alsa::Error);
+#[cfg(feature = "pipewire")]
+from_errors!(Capture: pipewire::Error);
+#[cfg(all(windows, feature = "wasapi"))]
+from_errors!(Capture: wasapi::WasapiError);
+from_errors!(Codec: hound::Error);
+#[cfg(feature = "opus")]
+from_errors!(Codec: opus::Error);
+#[cfg(feature = "lz4")]
+from_errors!(Codec: lz4_flex::block::DecompressError);
+from_errors!(
+    Protocol: tokio_tungstenite::tungstenite::Error,
+    serde_json::Error,
+    std::str::Utf8Error,
+    std::string::FromUtf8Error,
+    std::num::ParseIntError,
+);
+from_errors!(
+    other: std::net::AddrParseError,
+    std::time::SystemTimeError,
+    mdns_sd::Error,
+    quinn::ConnectionError,
+    quinn::crypto::rustls::NoInitialCipherSuite,
+    tokio_rustls::rustls::Error,
+    tokio_rustls::rustls::pki_types::InvalidDnsNameError,
+    tokio_rustls::rustls::pki_types::pem::Error,
+    srt_tokio::options::OptionsError,
+    tokio::task::JoinError,
+);
+#[cfg(feature = "webrtc")]
+from_errors!(other: webrtc::Error);
+#[cfg(feature = "grpc")]
+from_errors!(other: tonic::transport::Error);
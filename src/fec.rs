@@ -0,0 +1,363 @@
+// XOR forward error correction for the udp transports: after every 'group' stream frames
+// the sender adds a parity frame from which a receiver rebuilds any single one of them
+// that got lost. Parity payload:
+//
+//   count    u8           frames in the group
+//   seqs     [u32; count] their sequence numbers, the sender may have skipped some
+//   len_xor  u32          xor of their datagram lengths
+//   data                  xor of their datagrams, each zero-padded to the longest
+use crate::protocol::{Frame, FrameKind, Timestamp};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::VecDeque;
+
+pub const MAX_GROUP: usize = 32;
+// datagrams kept to rebuild from; covers the largest group with room for reordering
+const RECENT_LEN: usize = 2 * MAX_GROUP;
+
+pub struct FecEncoder {
+    group: usize,
+    seqs: Vec<u32>,
+    len_xor: u32,
+    xor: Vec<u8>,
+}
+
+impl FecEncoder {
+    // One parity frame per 'group' frames, at most 'MAX_GROUP'; None for 0.
+    pub fn new(group: usize) -> Option<FecEncoder> {
+        let group = group.min(MAX_GROUP);
+        (group > 0).then(|| FecEncoder {
+            group,
+            seqs: Vec::with_capacity(group),
+            len_xor: 0,
+            xor: Vec::new(),
+        })
+    }
+
+    // Add the datagram carrying 'frame'; returns the parity frame when that completed
+    // a group. Only audio and silence frames are protected.
+    pub fn push(&mut self, frame: &Frame, datagram: &[u8]) -> Option<Frame> {
+        if !matches!(frame.kind, FrameKind::Audio | FrameKind::Silence) {
+            return None;
+        }
+        self.seqs.push(frame.seq);
+        self.len_xor ^= datagram.len() as u32;
+        xor_into(&mut self.xor, datagram);
+        if self.seqs.len() < self.group {
+            return None;
+        }
+        let mut payload = BytesMut::with_capacity(5 + 4 * self.seqs.len() + self.xor.len());
+        payload.put_u8(self.seqs.len() as u8);
+        for &seq in &self.seqs {
+            payload.put_u32(seq);
+        }
+        payload.put_u32(self.len_xor);
+        payload.put_slice(&self.xor);
+        let parity = Frame {
+            kind: FrameKind::Parity,
+            seq: self.seqs[0],
+            timestamp: Timestamp::now(),
+            payload: payload.freeze(),
+        };
+        self.seqs.clear();
+        self.len_xor = 0;
+        self.xor.clear();
+        Some(parity)
+    }
+}
+
+// Puts the datagrams of a stream back in order, rebuilding lost ones from parity frames.
+// Only once parity frames have been seen, it holds back the datagrams after a gap until
+// the gap's group is complete; without loss nothing waits. Receivers having lost frames
+// resent make it hold from the start instead, until the gap is filled or flushed.
+#[derive(Default)]
+pub struct FecDecoder {
+    enabled: bool,
+    // gaps wait for retransmissions, not just for parity
+    retransmits: bool,
+    // seq of the first held datagram
+    next: Option<u32>,
+    // datagrams of seq 'next', 'next' + 1...; None for the missing ones
+    held: VecDeque<Option<Bytes>>,
+    recent: VecDeque<(u32, Bytes)>,
+    recovered: u64,
+}
+
+impl FecDecoder {
+    // Datagrams rebuilt so far.
+    pub fn recovered(&self) -> u64 {
+        self.recovered
+    }
+
+    // Hold back the datagrams after any gap until it is filled with a retransmission, a
+    // rebuilt datagram or by 'flush'.
+    pub fn hold(&mut self) {
+        self.enabled = true;
+        self.retransmits = true;
+    }
+
+    // Sequence numbers of the gaps datagrams are held back behind.
+    pub fn missing(&self) -> Vec<u32> {
+        let next = self.next.unwrap_or_default();
+        (0..self.held.len())
+            .filter(|&i| self.held[i].is_none())
+            .map(|i| next.wrapping_add(i as u32))
+            .collect()
+    }
+
+    // 'datagram' carries the stream frame 'seq'; returns the datagrams to pass on, in
+    // order.
+    pub fn push(&mut self, seq: u32, datagram: Bytes) -> Vec<Bytes> {
+        self.remember(seq, &datagram);
+        let next = match self.next {
+            Some(next) if self.enabled => next,
+            _ => {
+                self.next = Some(seq.wrapping_add(1));
+                return vec![datagram];
+            }
+        };
+        let offset = seq.wrapping_sub(next) as usize;
+        // late ones pass at once, the receiver decides what to do with them
+        if offset > u32::MAX as usize / 2 {
+            return vec![datagram];
+        }
+        if offset >= RECENT_LEN {
+            // too far ahead to ever be filled in: give up on the gap
+            let mut released = self.flush();
+            self.next = Some(seq.wrapping_add(1));
+            released.push(datagram);
+            return released;
+        }
+        if self.held.len() <= offset {
+            self.held.resize(offset + 1, None);
+        }
+        self.held[offset] = Some(datagram);
+        self.release()
+    }
+
+    // A parity frame; returns the datagrams it lets through, in order.
+    pub fn parity(&mut self, frame: &Frame) -> crate::Result<Vec<Bytes>> {
+        self.enabled = true;
+        let mut payload = frame.payload.clone();
+        if payload.remaining() < 1 {
+            return Err(crate::Error::Protocol("empty parity frame".into()));
+        }
+        let count = payload.get_u8() as usize;
+        if payload.remaining() < 4 * count + 4 {
+            return Err(crate::Error::Protocol("truncated parity frame".into()));
+        }
+        let seqs: Vec<u32> = (0..count).map(|_| payload.get_u32()).collect();
+        let mut len = payload.get_u32();
+        let mut data = payload.to_vec();
+
+        let missing: Vec<u32> = seqs
+            .iter()
+            .copied()
+            .filter(|&seq| !self.recent.iter().any(|(recent, _)| *recent == seq))
+            .collect();
+        let mut released = Vec::new();
+        if let [lost] = missing[..] {
+            for (_, datagram) in self.recent.iter().filter(|(seq, _)| seqs.contains(seq)) {
+                len ^= datagram.len() as u32;
+                xor_into(&mut data, datagram);
+            }
+            if len as usize <= data.len() {
+                data.truncate(len as usize);
+                self.recovered += 1;
+                released = self.push(lost, data.into());
+            }
+        }
+        // whatever of the group is still missing won't come from here anymore; a
+        // retransmission still might
+        if let (Some(&last), Some(next), false) = (seqs.last(), self.next, self.retransmits) {
+            if !self.held.is_empty() && last.wrapping_sub(next) < RECENT_LEN as u32 {
+                let passed = (last.wrapping_sub(next) as usize + 1).min(self.held.len());
+                released.extend(self.skip(passed));
+            }
+        }
+        Ok(released)
+    }
+
+    fn remember(&mut self, seq: u32, datagram: &Bytes) {
+        if self.recent.len() == RECENT_LEN {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((seq, datagram.clone()));
+    }
+
+    // The held datagrams from 'next' on up to the first missing one.
+    fn release(&mut self) -> Vec<Bytes> {
+        let mut released = Vec::new();
+        while let Some(Some(_)) = self.held.front() {
+            released.push(self.held.pop_front().flatten().unwrap());
+            self.next = self.next.map(|next| next.wrapping_add(1));
+        }
+        released
+    }
+
+    // Pass the first 'n' held places, missing ones and all, then what follows them.
+    fn skip(&mut self, n: usize) -> Vec<Bytes> {
+        let mut released: Vec<Bytes> = self.held.drain(..n).flatten().collect();
+        self.next = self.next.map(|next| next.wrapping_add(n as u32));
+        released.extend(self.release());
+        released
+    }
+
+    // Give up on the gaps; returns everything held, in order.
+    pub fn flush(&mut self) -> Vec<Bytes> {
+        let n = self.held.len();
+        self.skip(n)
+    }
+}
+
+fn xor_into(acc: &mut Vec<u8>, datagram: &[u8]) {
+    if acc.len() < datagram.len() {
+        acc.resize(datagram.len(), 0);
+    }
+    for (a, b) in acc.iter_mut().zip(datagram) {
+        *a ^= b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A stream frame and a datagram whose length differs from its neighbours'.
+    fn frame(seq: u32) -> (Frame, Bytes) {
+        let datagram = vec![seq as u8; 10 + seq as usize % 5];
+        let frame = Frame::audio(seq, Timestamp::now(), Bytes::new());
+        (frame, datagram.into())
+    }
+
+    // The frames 'seqs' through an encoder of 'group': each datagram, then the parity
+    // frame whenever one is due.
+    fn encode(
+        group: usize,
+        seqs: impl IntoIterator<Item = u32>,
+    ) -> Vec<(u32, Bytes, Option<Frame>)> {
+        let mut encoder = FecEncoder::new(group).unwrap();
+        seqs.into_iter()
+            .map(|seq| {
+                let (frame, datagram) = frame(seq);
+                let parity = encoder.push(&frame, &datagram);
+                (seq, datagram, parity)
+            })
+            .collect()
+    }
+
+    // What a decoder passes on when 'lost' frames don't arrive.
+    fn decode(
+        decoder: &mut FecDecoder,
+        sent: &[(u32, Bytes, Option<Frame>)],
+        lost: &[u32],
+    ) -> Vec<Bytes> {
+        let mut released = Vec::new();
+        for (seq, datagram, parity) in sent {
+            if !lost.contains(seq) {
+                released.extend(decoder.push(*seq, datagram.clone()));
+            }
+            if let Some(parity) = parity {
+                released.extend(decoder.parity(parity).unwrap());
+            }
+        }
+        released
+    }
+
+    fn datagrams(seqs: impl IntoIterator<Item = u32>) -> Vec<Bytes> {
+        seqs.into_iter().map(|seq| frame(seq).1).collect()
+    }
+
+    #[test]
+    fn parity_follows_every_group() {
+        assert!(FecEncoder::new(0).is_none());
+        let sent = encode(4, 0..12);
+        let parities: Vec<u32> = sent
+            .iter()
+            .filter_map(|(_, _, parity)| parity.as_ref().map(|parity| parity.seq))
+            .collect();
+        assert_eq!(parities, [0, 4, 8]);
+
+        let mut encoder = FecEncoder::new(1).unwrap();
+        let ping = Frame::ping(0);
+        assert!(encoder.push(&ping, b"ping").is_none());
+    }
+
+    #[test]
+    fn single_losses_are_rebuilt() {
+        let mut decoder = FecDecoder::default();
+        let sent = encode(4, 0..16);
+        // the first in a group, one in the middle, the last
+        let released = decode(&mut decoder, &sent, &[4, 9, 15]);
+        assert_eq!(released, datagrams(0..16));
+        assert_eq!(decoder.recovered(), 3);
+    }
+
+    #[test]
+    fn nothing_waits_before_the_first_parity() {
+        let mut decoder = FecDecoder::default();
+        let sent = encode(4, 0..4);
+        // rebuilt all the same, but only after the ones that came
+        let released = decode(&mut decoder, &sent, &[0]);
+        assert_eq!(released, datagrams([1, 2, 3, 0]));
+    }
+
+    #[test]
+    fn double_losses_are_skipped() {
+        let mut decoder = FecDecoder::default();
+        let sent = encode(4, 0..12);
+        let released = decode(&mut decoder, &sent, &[5, 6]);
+        assert_eq!(
+            released,
+            datagrams((0..12).filter(|seq| ![5, 6].contains(seq)))
+        );
+        assert_eq!(decoder.recovered(), 0);
+    }
+
+    #[test]
+    fn groups_span_the_wraparound() {
+        let mut decoder = FecDecoder::default();
+        let seqs: Vec<u32> = (u32::MAX - 5..=u32::MAX).chain(0..6).collect();
+        let sent = encode(4, seqs.clone());
+        let released = decode(&mut decoder, &sent, &[u32::MAX, 2]);
+        assert_eq!(released, datagrams(seqs));
+        assert_eq!(decoder.recovered(), 2);
+    }
+
+    #[test]
+    fn held_gaps_wait_for_retransmissions() {
+        let mut decoder = FecDecoder::default();
+        decoder.hold();
+        assert_eq!(decoder.push(0, frame(0).1), datagrams([0]));
+        assert!(decoder.push(2, frame(2).1).is_empty());
+        assert!(decoder.push(4, frame(4).1).is_empty());
+        assert_eq!(decoder.missing(), [1, 3]);
+        assert_eq!(decoder.push(1, frame(1).1), datagrams([1, 2]));
+        assert_eq!(decoder.flush(), datagrams([4]));
+        assert!(decoder.missing().is_empty());
+    }
+
+    #[test]
+    fn gaps_too_far_behind_are_given_up() {
+        let mut decoder = FecDecoder::default();
+        decoder.hold();
+        decoder.push(0, frame(0).1);
+        assert!(decoder.push(2, frame(2).1).is_empty());
+        let far = RECENT_LEN as u32 + 1;
+        assert_eq!(decoder.push(far, frame(far).1), datagrams([2, far]));
+        // and late ones pass at once
+        assert_eq!(decoder.push(1, frame(1).1), datagrams([1]));
+    }
+
+    #[test]
+    fn truncated_parity_is_refused() {
+        let mut decoder = FecDecoder::default();
+        let parity = |payload: &'static [u8]| Frame {
+            kind: FrameKind::Parity,
+            seq: 0,
+            timestamp: Timestamp::now(),
+            payload: Bytes::from_static(payload),
+        };
+        assert!(decoder.parity(&parity(b"")).is_err());
+        assert!(decoder.parity(&parity(&[2, 0, 0, 0, 0, 0, 0, 0])).is_err());
+    }
+}
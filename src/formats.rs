@@ -0,0 +1,95 @@
+// Streams in the format single clients ask for, next to the one a server is set up with.
+use crate::audio::channels::channel_mapped;
+use crate::audio::encode::encoded;
+use crate::audio::resample::resampled;
+use crate::config_file::TcpConfig;
+use crate::distributor::{Distributor, DropPolicy, Subscription};
+use crate::dsp::level::leveled;
+use crate::protocol::StreamInfo;
+use crate::transform::Compression;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use tracing::info;
+
+// every chain keeps its tasks running for good, so clients can't create them at will
+const MAX_FORMATS: usize = 8;
+const SAMPLE_RATES: RangeInclusive<usize> = 8000..=192000;
+
+// Encode chains from the capture to the formats clients of one server requested, built
+// on the first request for a format and shared by every client asking for it later.
+pub struct FormatChains {
+    capture: Arc<Distributor>,
+    // level of the server's own output, so gain and mute apply to every format
+    name: String,
+    gain: f32,
+    queue_len: usize,
+    drop_policy: DropPolicy,
+    // by requested format, 'frame_samples' left 0
+    chains: Mutex<HashMap<StreamInfo, Arc<Distributor>>>,
+}
+
+impl FormatChains {
+    pub fn new(capture: Arc<Distributor>, name: &str, tcp: &TcpConfig) -> FormatChains {
+        FormatChains {
+            capture,
+            name: name.to_string(),
+            gain: tcp.gain,
+            queue_len: tcp.queue_len,
+            drop_policy: tcp.drop_policy,
+            chains: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Frames in 'request's rate and codec, with its sample format for pcm, of the first
+    // 'request.n_ch' capture channels; 'frame_samples' is up to the codec and compression
+    // up to the connection. Aac and opus carry at most 2 channels, the stream info at the
+    // start of the subscription tells.
+    pub fn subscribe(&self, request: &StreamInfo) -> crate::Result<Subscription> {
+        let n_ch = self.capture.stream_info().n_ch;
+        if request.n_ch == 0 || request.n_ch > n_ch {
+            return Err(format!(
+                "{} channels requested, the capture has {}",
+                request.n_ch, n_ch
+            )
+            .into());
+        }
+        if !SAMPLE_RATES.contains(&request.sample_rate) {
+            return Err(format!("unsupported sample rate {}", request.sample_rate).into());
+        }
+        let key = StreamInfo {
+            frame_samples: 0,
+            compression: Compression::None,
+            ..*request
+        };
+        let mut chains = self.chains.lock().unwrap();
+        let chain = match chains.get(&key) {
+            Some(chain) => chain.clone(),
+            None if chains.len() >= MAX_FORMATS => {
+                return Err(format!("already serving {} formats", MAX_FORMATS).into());
+            }
+            None => {
+                info!("building a chain for {:?}", key);
+                let chain = self.build(&key)?;
+                chains.insert(key, chain.clone());
+                chain
+            }
+        };
+        Ok(chain.subscribe(self.queue_len, self.drop_policy))
+    }
+
+    fn build(&self, format: &StreamInfo) -> crate::Result<Arc<Distributor>> {
+        let stream = if format.n_ch == self.capture.stream_info().n_ch {
+            self.capture.clone()
+        } else {
+            let map: Vec<Vec<usize>> = (0..format.n_ch).map(|ch| vec![ch]).collect();
+            channel_mapped(&self.capture, &map)?
+        };
+        let stream = leveled(
+            &resampled(&stream, format.sample_rate),
+            &self.name,
+            self.gain,
+        );
+        encoded(&stream, format.codec, format.sample_format)
+    }
+}
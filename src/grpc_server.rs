@@ -0,0 +1,370 @@
+// Serves the stream to backend services as the server-streaming 'StreamAudio' rpc of
+// proto/mic2net.proto, so any language with a grpc code generator has a client.
+use crate::audio::encode::WireCodec;
+use crate::config_file::Config;
+use crate::discovery::format_names;
+use crate::distributor::{frames_in, Distributor, DropPolicy, Subscription};
+use crate::metrics::METRICS;
+use crate::protocol::{Frame, FrameKind, StreamInfo};
+use crate::tcp_server::constant_time_eq;
+use crate::HEADER_LEN;
+use bytes::Bytes;
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, OwnedSemaphorePermit, Semaphore};
+use tonic::codegen::{http, Body, BoxFuture, BoxStream, Context, Poll, Service, StdError};
+use tonic::server::{Grpc, NamedService, ServerStreamingService};
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status};
+use tonic_prost::ProstCodec;
+use tracing::{error, info, info_span, warn, Instrument};
+
+// The messages of proto/mic2net.proto as prost-build would generate them, and below
+// them the service tonic-build would; written out so that builds don't need protoc.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StreamRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AudioMessage {
+    #[prost(oneof = "audio_message::Message", tags = "1, 2, 3")]
+    pub message: Option<audio_message::Message>,
+}
+
+pub mod audio_message {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Message {
+        #[prost(message, tag = "1")]
+        Format(super::StreamFormat),
+        #[prost(message, tag = "2")]
+        Frame(super::AudioFrame),
+        #[prost(message, tag = "3")]
+        Silence(super::Silence),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StreamFormat {
+    #[prost(uint32, tag = "1")]
+    pub sample_rate: u32,
+    #[prost(uint32, tag = "2")]
+    pub channels: u32,
+    #[prost(uint32, tag = "3")]
+    pub frame_samples: u32,
+    #[prost(string, tag = "4")]
+    pub format: String,
+    #[prost(string, tag = "5")]
+    pub layout: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AudioFrame {
+    #[prost(uint32, tag = "1")]
+    pub seq: u32,
+    #[prost(uint64, tag = "2")]
+    pub timestamp_us: u64,
+    #[prost(uint32, tag = "3")]
+    pub device_id: u32,
+    #[prost(uint32, tag = "4")]
+    pub packet_id: u32,
+    #[prost(bytes = "bytes", tag = "5")]
+    pub data: Bytes,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Silence {
+    #[prost(uint32, tag = "1")]
+    pub seq: u32,
+    #[prost(uint64, tag = "2")]
+    pub timestamp_us: u64,
+    #[prost(uint32, tag = "3")]
+    pub ms: u32,
+}
+
+impl AudioMessage {
+    fn format(info: &StreamInfo) -> AudioMessage {
+        let (format, layout) = format_names(info.codec, info.sample_format);
+        AudioMessage {
+            message: Some(audio_message::Message::Format(StreamFormat {
+                sample_rate: info.sample_rate as u32,
+                channels: info.n_ch as u32,
+                frame_samples: info.frame_samples as u32,
+                format: format.to_string(),
+                layout: layout.to_string(),
+            })),
+        }
+    }
+
+    // None for the frames grpc clients have no use for.
+    fn of(frame: &Frame, codec: WireCodec) -> crate::Result<Option<AudioMessage>> {
+        let message = match frame.kind {
+            FrameKind::Audio => audio_message::Message::Frame(audio_frame(frame, codec)?),
+            FrameKind::Silence => audio_message::Message::Silence(Silence {
+                seq: frame.seq,
+                timestamp_us: frame.timestamp.wall_us,
+                ms: frame.silence_ms()?,
+            }),
+            FrameKind::StreamInfo => {
+                return Ok(Some(AudioMessage::format(&StreamInfo::parse(
+                    &frame.payload,
+                )?)))
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(AudioMessage {
+            message: Some(message),
+        }))
+    }
+}
+
+// Pcm and adpcm payloads start with the packet header, which goes into fields of its own.
+fn audio_frame(frame: &Frame, codec: WireCodec) -> crate::Result<AudioFrame> {
+    let payload = &frame.payload;
+    let (device_id, packet_id, data) = match codec {
+        WireCodec::Pcm | WireCodec::Adpcm => {
+            if payload.len() < HEADER_LEN {
+                return Err(crate::Error::Protocol(format!(
+                    "bad audio packet of {} bytes",
+                    payload.len()
+                )));
+            }
+            (
+                u16::from_be_bytes([payload[0], payload[1]]).into(),
+                u32::from_be_bytes(payload[8..12].try_into().unwrap()),
+                payload.slice(HEADER_LEN..),
+            )
+        }
+        WireCodec::Aac | WireCodec::Opus => (0, 0, payload.clone()),
+    };
+    Ok(AudioFrame {
+        seq: frame.seq,
+        timestamp_us: frame.timestamp.wall_us,
+        device_id,
+        packet_id,
+        data,
+    })
+}
+
+// The 'Mic2net' service; cheap to clone, every connection gets a copy.
+#[derive(Clone)]
+pub struct Mic2netService {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    distributor: Arc<Distributor>,
+    limit_connections: Arc<Semaphore>,
+    queue_len: usize,
+    drop_policy: DropPolicy,
+    // frames of pre-roll per new client
+    preroll: usize,
+    token: Option<String>,
+    notify_shutdown: broadcast::Sender<()>,
+}
+
+impl Mic2netService {
+    pub fn new(cfg: &Config, distributor: Arc<Distributor>) -> Mic2netService {
+        Mic2netService {
+            inner: Arc::new(Inner {
+                distributor,
+                limit_connections: Arc::new(Semaphore::new(cfg.grpc.max_clients.into())),
+                queue_len: cfg.grpc.queue_len,
+                drop_policy: cfg.grpc.drop_policy,
+                preroll: frames_in(
+                    cfg.grpc.preroll,
+                    cfg.grpc.sample_rate.unwrap_or(cfg.mic.sample_rate),
+                ),
+                token: Some(cfg.grpc.token.clone()).filter(|token| !token.is_empty()),
+                notify_shutdown: broadcast::channel(1).0,
+            }),
+        }
+    }
+
+    // End every open stream, so a graceful shutdown of the server can complete.
+    pub fn shutdown(&self) {
+        let _ = self.inner.notify_shutdown.send(());
+    }
+}
+
+impl NamedService for Mic2netService {
+    const NAME: &'static str = "mic2net.Mic2net";
+}
+
+impl<B> Service<http::Request<B>> for Mic2netService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        match req.uri().path() {
+            "/mic2net.Mic2net/StreamAudio" => {
+                let service = StreamAudio(self.inner.clone());
+                Box::pin(async move {
+                    let mut grpc = Grpc::new(ProstCodec::default());
+                    Ok(grpc.server_streaming(service, req).await)
+                })
+            }
+            _ => Box::pin(async { Ok(Status::unimplemented("").into_http()) }),
+        }
+    }
+}
+
+struct StreamAudio(Arc<Inner>);
+
+impl ServerStreamingService<StreamRequest> for StreamAudio {
+    type Response = AudioMessage;
+    type ResponseStream = BoxStream<AudioMessage>;
+    type Future = BoxFuture<Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, request: Request<StreamRequest>) -> Self::Future {
+        let inner = self.0.clone();
+        Box::pin(async move {
+            let peer = request
+                .remote_addr()
+                .map_or_else(|| "unknown".to_string(), |addr| addr.to_string());
+            if let Some(token) = &inner.token {
+                let sent = request
+                    .metadata()
+                    .get("authorization")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "));
+                if !sent.is_some_and(|sent| constant_time_eq(sent.as_bytes(), token.as_bytes())) {
+                    warn!("grpc client {} failed to authenticate", peer);
+                    return Err(Status::unauthenticated("bad or missing token"));
+                }
+            }
+            let permit = inner
+                .limit_connections
+                .clone()
+                .try_acquire_owned()
+                .map_err(|_| Status::resource_exhausted("too many clients"))?;
+            let frames = inner.distributor.subscribe_with_preroll(
+                inner.queue_len,
+                inner.drop_policy,
+                inner.preroll,
+            );
+            let client = GrpcClient::new(peer, frames, inner.notify_shutdown.subscribe(), permit);
+            Ok(Response::new(client.messages()))
+        })
+    }
+}
+
+// One open 'StreamAudio' call.
+struct GrpcClient {
+    ip_addr: String,
+    frames: Subscription,
+    codec: WireCodec,
+    // the format, before any frame
+    first: Option<AudioMessage>,
+    shutdown_signal: broadcast::Receiver<()>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl GrpcClient {
+    fn new(
+        ip_addr: String,
+        frames: Subscription,
+        shutdown_signal: broadcast::Receiver<()>,
+        permit: OwnedSemaphorePermit,
+    ) -> GrpcClient {
+        METRICS.client_connected(&ip_addr);
+        info!("grpc client {} connected", ip_addr);
+        let info = frames.stream_info();
+        GrpcClient {
+            ip_addr,
+            frames,
+            codec: info.codec,
+            first: Some(AudioMessage::format(&info)),
+            shutdown_signal,
+            _permit: permit,
+        }
+    }
+
+    // The stream ends when the client falls behind or the server shuts down; dropped
+    // when the client goes away.
+    fn messages(self) -> BoxStream<AudioMessage> {
+        let span = info_span!("grpc_client", peer = %self.ip_addr);
+        Box::pin(futures_util::stream::unfold(self, move |mut client| {
+            async move { client.next().await.map(|message| (message, client)) }
+                .instrument(span.clone())
+        }))
+    }
+
+    async fn next(&mut self) -> Option<Result<AudioMessage, Status>> {
+        if let Some(format) = self.first.take() {
+            return Some(Ok(format));
+        }
+        loop {
+            let frame = tokio::select! {
+                frame = self.frames.recv() => match frame {
+                    Some(frame) => frame,
+                    None => {
+                        warn!("fell behind the stream");
+                        return Some(Err(Status::data_loss("fell behind the stream")));
+                    }
+                },
+                _ = self.shutdown_signal.recv() => {
+                    return Some(Err(Status::unavailable("server shutting down")));
+                }
+            };
+            match AudioMessage::of(&frame, self.codec) {
+                Ok(Some(message)) => {
+                    METRICS.frame_sent(&self.ip_addr, frame.encoded_len());
+                    return Some(Ok(message));
+                }
+                Ok(None) => {}
+                Err(err) => error!("can't send frame {} over grpc: {}", frame.seq, err),
+            }
+        }
+    }
+}
+
+impl Drop for GrpcClient {
+    fn drop(&mut self) {
+        METRICS.client_disconnected(&self.ip_addr);
+        info!("grpc client {} disconnected", self.ip_addr);
+    }
+}
+
+// Run grpc server; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_grpc_server(
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    shutdown: impl Future,
+) {
+    if let Err(err) = serve(cfg, distributor, shutdown).await {
+        error!("grpc server failed: {}", err);
+    }
+    info!("cleaning up grpc server");
+}
+
+async fn serve(
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    shutdown: impl Future,
+) -> crate::Result<()> {
+    let address = format!("{}:{}", cfg.grpc.bind_address, cfg.grpc.listen_port);
+    let listener = TcpListener::bind(&address)
+        .await
+        .map_err(crate::Error::bind(&address))?;
+    let incoming = TcpIncoming::from(listener).with_nodelay(Some(true));
+    let service = Mic2netService::new(&cfg, distributor);
+    info!("grpc listen on port: {}", cfg.grpc.listen_port);
+    let streams = service.clone();
+    tonic::transport::Server::builder()
+        .serve_with_incoming_shutdown(service, incoming, async move {
+            shutdown.await;
+            streams.shutdown();
+        })
+        .await?;
+    Ok(())
+}
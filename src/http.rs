@@ -0,0 +1,255 @@
+// Just enough HTTP/1.1 for the small built-in endpoints: one request per connection
+// (several, one after the other, for rtsp), no chunked request bodies. Clients of
+// other servers (s3, icecast) only read the head of the response.
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+
+const MAX_HEAD_LEN: usize = 8192;
+const MAX_BODY_LEN: usize = 1 << 16;
+// a peer that connects and then stalls mustn't hold a connection slot forever
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Value of 'name' in the query string, as sent; no percent-decoding.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+// Read one request; None if the peer closed the connection before sending one.
+pub async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> crate::Result<Option<Request>> {
+    read_request_within(stream, REQUEST_TIMEOUT).await
+}
+
+// As read_request, for callers that keep the connection open and wait longer between
+// requests.
+pub async fn read_request_within<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    limit: Duration,
+) -> crate::Result<Option<Request>> {
+    read_request_from(stream, &mut Vec::with_capacity(1024), limit).await
+}
+
+// As read_request_within, for connections carrying a request after the other: what the
+// peer sent beyond the request, such as the next one of a player that pipelines them,
+// stays in 'buf' and is read first by the next call.
+pub async fn read_request_from<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    limit: Duration,
+) -> crate::Result<Option<Request>> {
+    time::timeout(limit, read(stream, buf))
+        .await
+        .map_err(|_| crate::Error::Protocol("request timed out".into()))?
+}
+
+async fn read<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+) -> crate::Result<Option<Request>> {
+    let head_end = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        if buf.len() > MAX_HEAD_LEN {
+            return Err(crate::Error::Protocol("request head too long".into()));
+        }
+        let mut chunk = [0_u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = std::str::from_utf8(&buf[..head_end])?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let mut request = Request {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+        body: Vec::new(),
+    };
+    let content_length: usize = request
+        .header("content-length")
+        .and_then(|len| len.parse().ok())
+        .unwrap_or(0);
+    if content_length > MAX_BODY_LEN {
+        return Err(crate::Error::Protocol("request body too long".into()));
+    }
+    let end = head_end + 4 + content_length;
+    while buf.len() < end {
+        let mut chunk = [0_u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(crate::Error::Protocol(
+                "connection closed before the request body".into(),
+            ));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    request.body = buf[head_end + 4..end].to_vec();
+    buf.drain(..end);
+    Ok(Some(request))
+}
+
+pub async fn write_response<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> crate::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+// Response head for a body that runs until the connection closes, e.g. a live stream.
+pub async fn write_stream_head<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    content_type: &str,
+) -> crate::Result<()> {
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        content_type
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+// The status line and headers of a response to a request of ours.
+pub async fn read_response_head<S: AsyncRead + Unpin>(stream: &mut S) -> crate::Result<String> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_HEAD_LEN {
+            return Err(crate::Error::Protocol("oversized response head".into()));
+        }
+        let n_bytes = stream.read(&mut chunk).await?;
+        if n_bytes == 0 {
+            return Err(crate::Error::Protocol(
+                "connection closed before a response".into(),
+            ));
+        }
+        buf.extend_from_slice(&chunk[..n_bytes]);
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_head_and_body() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"POST /gain?ch=2 HTTP/1.1\r\nContent-Length: 4\r\n\r\n-6.0")
+            .await
+            .unwrap();
+        let request = read_request(&mut server).await.unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/gain");
+        assert_eq!(request.query_param("ch"), Some("2"));
+        assert_eq!(request.header("content-length"), Some("4"));
+        assert_eq!(request.body, b"-6.0");
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_are_kept() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(
+                b"SETUP rtsp://host/live RTSP/1.0\r\nCSeq: 2\r\nContent-Length: 2\r\n\r\nab\
+                  PLAY rtsp://host/live RTSP/1.0\r\nCSeq: 3\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        drop(client);
+        let mut buf = Vec::new();
+        let setup = read_request_from(&mut server, &mut buf, REQUEST_TIMEOUT)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(setup.method, "SETUP");
+        assert_eq!(setup.body, b"ab");
+        let play = read_request_from(&mut server, &mut buf, REQUEST_TIMEOUT)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(play.method, "PLAY");
+        assert_eq!(play.header("cseq"), Some("3"));
+        assert!(read_request_from(&mut server, &mut buf, REQUEST_TIMEOUT)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn closed_before_a_request() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        drop(client);
+        assert!(read_request(&mut server).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn closed_before_the_body() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"POST /gain HTTP/1.1\r\nContent-Length: 4\r\n\r\n-6")
+            .await
+            .unwrap();
+        drop(client);
+        let result = read_request(&mut server).await;
+        assert!(matches!(result, Err(crate::Error::Protocol(_))));
+    }
+
+    #[tokio::test]
+    async fn stalled_request_times_out() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\n")
+            .await
+            .unwrap();
+        let result = read_request_within(&mut server, Duration::from_millis(50)).await;
+        assert!(matches!(result, Err(crate::Error::Protocol(_))));
+    }
+}
@@ -0,0 +1,431 @@
+// Plain HTTP streaming, Icecast style: 'GET /stream' answers with an endless body in
+// the configured format, so 'curl http://host:8000/stream | aplay' or any media player
+// can listen. '?format=wav', '?format=flac', '?format=ogg', '?format=webm' or '?format=mp3'
+// overrides the format per request.
+// With the hls sink enabled, its playlist and segments are served under '/hls/'.
+use crate::audio::flac::FlacEncoder;
+#[cfg(feature = "opus")]
+use crate::audio::ogg::OggOpusEncoder;
+#[cfg(feature = "opus")]
+use crate::audio::webm::WebmOpusEncoder;
+use crate::config_file::Config;
+use crate::distributor::{frames_in, Distributor, DropPolicy};
+use crate::http::{read_request, write_response, write_stream_head};
+use crate::protocol::FrameKind;
+use crate::tcp_client::{AudioPacket, SilenceFill};
+use crate::tcp_server::accept_with_backoff;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tracing::{error, info, info_span, warn, Instrument};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpFormat {
+    // 16-bit pcm with an open-ended header, every channel
+    Wav,
+    // lossless, every channel (at most 8)
+    Flac,
+    // ogg opus; needs the 'opus' feature, at most the first 2 channels
+    Ogg,
+    // the same opus in webm, for browsers
+    Webm,
+    // mp3 for players that know nothing else; needs the 'mp3' feature, at most the
+    // first 2 channels
+    Mp3,
+}
+
+impl HttpFormat {
+    fn parse(name: &str) -> Option<HttpFormat> {
+        match name {
+            "wav" => Some(HttpFormat::Wav),
+            "flac" => Some(HttpFormat::Flac),
+            "ogg" => Some(HttpFormat::Ogg),
+            "webm" => Some(HttpFormat::Webm),
+            "mp3" => Some(HttpFormat::Mp3),
+            _ => None,
+        }
+    }
+}
+
+pub struct HttpServer {
+    cfg: Arc<Config>,
+    listener: TcpListener,
+    limit_connections: Arc<Semaphore>,
+    distributor: Arc<Distributor>,
+    sample_rate: usize,
+    // frames of pre-roll per new client
+    preroll: usize,
+    next_client_id: u64,
+}
+
+impl HttpServer {
+    pub async fn new(cfg: Arc<Config>, distributor: Arc<Distributor>) -> crate::Result<HttpServer> {
+        let listener = TcpListener::bind((cfg.http.bind_address.as_str(), cfg.http.listen_port))
+            .await
+            .map_err(crate::Error::bind(format!(
+                "{}:{}",
+                cfg.http.bind_address, cfg.http.listen_port
+            )))?;
+        let sample_rate = cfg.http.sample_rate.unwrap_or(cfg.mic.sample_rate);
+        Ok(HttpServer {
+            limit_connections: Arc::new(Semaphore::new(cfg.http.max_clients.into())),
+            preroll: frames_in(cfg.http.preroll, sample_rate),
+            sample_rate,
+            cfg,
+            listener,
+            distributor,
+            next_client_id: 0,
+        })
+    }
+
+    async fn run(&mut self) -> crate::Result<()> {
+        info!(
+            "http stream on http://{}:{}/stream",
+            self.cfg.http.bind_address, self.cfg.http.listen_port
+        );
+        loop {
+            let permit = self
+                .limit_connections
+                .clone()
+                .acquire_owned()
+                .await
+                .unwrap();
+            let (socket, peer) = accept_with_backoff(&self.listener).await?;
+            let listener = Listener {
+                cfg: self.cfg.clone(),
+                distributor: self.distributor.clone(),
+                sample_rate: self.sample_rate,
+                preroll: self.preroll,
+            };
+            let span = info_span!("http_client", peer = %peer, id = self.next_client_id);
+            self.next_client_id += 1;
+
+            let connection = async move {
+                if let Err(err) = listener.serve(socket).await {
+                    error!("http connection error: {}", err);
+                }
+                drop(permit);
+            };
+            tokio::spawn(connection.instrument(span));
+        }
+    }
+}
+
+// One http client.
+struct Listener {
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    sample_rate: usize,
+    preroll: usize,
+}
+
+impl Listener {
+    async fn serve(self, mut socket: TcpStream) -> crate::Result<()> {
+        let request = match read_request(&mut socket).await? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        if request.method == "GET" && request.path.starts_with("/hls/") && self.cfg.hls.enable {
+            return serve_hls_file(&mut socket, &self.cfg.hls.directory, &request.path[5..]).await;
+        }
+        if request.method != "GET" || request.path != "/stream" {
+            return write_response(&mut socket, "404 Not Found", "text/plain", b"not found\n")
+                .await;
+        }
+        let format = match request.query_param("format") {
+            Some(name) => match HttpFormat::parse(name) {
+                Some(format) => format,
+                None => {
+                    return write_response(
+                        &mut socket,
+                        "400 Bad Request",
+                        "text/plain",
+                        b"unknown format\n",
+                    )
+                    .await
+                }
+            },
+            None => self.cfg.http.format,
+        };
+        let mut frames = self.distributor.subscribe_with_preroll(
+            self.cfg.http.queue_len,
+            DropPolicy::DropNewest,
+            self.preroll,
+        );
+
+        // the channel count is only known from the first packet
+        let first = loop {
+            match frames.recv().await {
+                Some(frame) if frame.kind == FrameKind::Audio => {
+                    break AudioPacket::parse(&frame.payload)?
+                }
+                Some(_) => continue,
+                None => return Ok(()),
+            }
+        };
+        let mut encoder = match StreamEncoder::new(format, self.sample_rate, first.n_ch) {
+            Ok(encoder) => encoder,
+            Err(err) => {
+                warn!("can't stream {:?}: {}", format, err);
+                let body = format!("{}\n", err);
+                return write_response(
+                    &mut socket,
+                    "501 Not Implemented",
+                    "text/plain",
+                    body.as_bytes(),
+                )
+                .await;
+            }
+        };
+        write_stream_head(&mut socket, encoder.content_type()).await?;
+        info!("streaming {:?}", format);
+
+        let mut out = encoder.header()?;
+        encoder.encode(&first, &mut out)?;
+        socket.write_all(&out).await?;
+        // players get zeros for silence frames, the stream has no way to skip time
+        let mut silence = SilenceFill::default();
+        let stream = frames.stream_info();
+        while let Some(frame) = frames.recv().await {
+            out.clear();
+            let packets = match frame.kind {
+                FrameKind::Silence => silence.packets(&frame, &stream)?,
+                _ => vec![AudioPacket::parse(&frame.payload)?],
+            };
+            for packet in &packets {
+                encoder.encode(packet, &mut out)?;
+            }
+            if !out.is_empty() {
+                // a player hanging up is how streams normally end
+                if socket.write_all(&out).await.is_err() {
+                    break;
+                }
+            }
+        }
+        info!("disconnected");
+        Ok(())
+    }
+}
+
+// Files written by the hls sink; anything that could leave the directory is refused.
+async fn serve_hls_file(socket: &mut TcpStream, directory: &str, name: &str) -> crate::Result<()> {
+    let content_type = match name.rsplit_once('.').map(|(_, ext)| ext) {
+        Some("m3u8") => "application/vnd.apple.mpegurl",
+        Some("mp4") | Some("m4s") => "audio/mp4",
+        _ => "",
+    };
+    if content_type.is_empty() || name.contains('/') || name.contains("..") {
+        return write_response(socket, "404 Not Found", "text/plain", b"not found\n").await;
+    }
+    match tokio::fs::read(Path::new(directory).join(name)).await {
+        Ok(contents) => write_response(socket, "200 OK", content_type, &contents).await,
+        Err(_) => write_response(socket, "404 Not Found", "text/plain", b"not found\n").await,
+    }
+}
+
+pub(crate) enum StreamEncoder {
+    Wav {
+        sample_rate: usize,
+        n_ch: usize,
+    },
+    Flac(Box<FlacEncoder>),
+    #[cfg(feature = "opus")]
+    Ogg(OggOpusEncoder),
+    #[cfg(feature = "opus")]
+    Webm(Box<WebmOpusEncoder>),
+    #[cfg(feature = "mp3")]
+    Mp3(mp3::Mp3Encoder),
+}
+
+impl StreamEncoder {
+    pub(crate) fn new(
+        format: HttpFormat,
+        sample_rate: usize,
+        n_ch: usize,
+    ) -> crate::Result<StreamEncoder> {
+        match format {
+            HttpFormat::Wav => Ok(StreamEncoder::Wav { sample_rate, n_ch }),
+            HttpFormat::Flac => Ok(StreamEncoder::Flac(Box::new(FlacEncoder::new(
+                sample_rate,
+                n_ch.min(8),
+            )?))),
+            #[cfg(feature = "opus")]
+            HttpFormat::Ogg => Ok(StreamEncoder::Ogg(OggOpusEncoder::new(
+                sample_rate,
+                n_ch.min(2),
+                1,
+            )?)),
+            #[cfg(not(feature = "opus"))]
+            HttpFormat::Ogg => Err("ogg needs a build with --features opus".into()),
+            #[cfg(feature = "opus")]
+            HttpFormat::Webm => Ok(StreamEncoder::Webm(Box::new(WebmOpusEncoder::new(
+                sample_rate,
+                n_ch.min(2),
+                true,
+                None,
+            )?))),
+            #[cfg(not(feature = "opus"))]
+            HttpFormat::Webm => Err("webm needs a build with --features opus".into()),
+            #[cfg(feature = "mp3")]
+            HttpFormat::Mp3 => Ok(StreamEncoder::Mp3(mp3::Mp3Encoder::new(
+                sample_rate,
+                n_ch.min(2),
+            )?)),
+            #[cfg(not(feature = "mp3"))]
+            HttpFormat::Mp3 => Err("mp3 needs a build with --features mp3".into()),
+        }
+    }
+
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            StreamEncoder::Wav { .. } => "audio/wav",
+            StreamEncoder::Flac(_) => "audio/flac",
+            #[cfg(feature = "opus")]
+            StreamEncoder::Ogg(_) => "audio/ogg",
+            #[cfg(feature = "opus")]
+            StreamEncoder::Webm(_) => "audio/webm",
+            #[cfg(feature = "mp3")]
+            StreamEncoder::Mp3(_) => "audio/mpeg",
+        }
+    }
+
+    // Bytes that start the stream.
+    pub(crate) fn header(&mut self) -> crate::Result<Vec<u8>> {
+        match self {
+            StreamEncoder::Wav { sample_rate, n_ch } => Ok(wav_header(*sample_rate, *n_ch)),
+            StreamEncoder::Flac(encoder) => encoder.header(),
+            #[cfg(feature = "opus")]
+            StreamEncoder::Ogg(encoder) => encoder.header(),
+            #[cfg(feature = "opus")]
+            StreamEncoder::Webm(encoder) => encoder.header(),
+            // mp3 frames stand on their own; players can tune in at any of them
+            #[cfg(feature = "mp3")]
+            StreamEncoder::Mp3(_) => Ok(Vec::new()),
+        }
+    }
+
+    // Append the encoding of 'packet' to 'out'; may be nothing until a full frame is buffered.
+    pub(crate) fn encode(&mut self, packet: &AudioPacket, out: &mut Vec<u8>) -> crate::Result<()> {
+        match self {
+            StreamEncoder::Wav { n_ch, .. } => {
+                let n_ch = (*n_ch).min(packet.n_ch);
+                let len = packet.samples.len() / packet.n_ch;
+                for i in 0..len {
+                    for ch in 0..n_ch {
+                        out.extend_from_slice(&packet.channel(ch)[i].to_le_bytes());
+                    }
+                }
+                Ok(())
+            }
+            StreamEncoder::Flac(encoder) => encoder.encode(packet, out),
+            #[cfg(feature = "opus")]
+            StreamEncoder::Ogg(encoder) => encoder.encode(packet, out),
+            #[cfg(feature = "opus")]
+            StreamEncoder::Webm(encoder) => encoder.encode(packet, out),
+            #[cfg(feature = "mp3")]
+            StreamEncoder::Mp3(encoder) => encoder.encode(packet, out),
+        }
+    }
+}
+
+// RIFF header with the sizes set to the maximum, which players take as "until EOF".
+fn wav_header(sample_rate: usize, n_ch: usize) -> Vec<u8> {
+    let block_align = n_ch as u16 * 2;
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16_u32.to_le_bytes());
+    // pcm
+    header.extend_from_slice(&1_u16.to_le_bytes());
+    header.extend_from_slice(&(n_ch as u16).to_le_bytes());
+    header.extend_from_slice(&(sample_rate as u32).to_le_bytes());
+    header.extend_from_slice(&(sample_rate as u32 * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&16_u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header
+}
+
+#[cfg(feature = "mp3")]
+mod mp3 {
+    use crate::tcp_client::AudioPacket;
+    use mp3lame_encoder::{Bitrate, Builder, DualPcm, Encoder, MonoPcm, Quality};
+
+    // constant bitrate keeps old decoders happy; lame lowers it where the sample rate
+    // doesn't allow this much (160 kbps at most below 32 kHz)
+    const BITRATE: Bitrate = Bitrate::Kbps128;
+
+    // MPEG-1/2 layer III through lame. Sample rates other than 8-48 kHz are resampled.
+    pub struct Mp3Encoder {
+        encoder: Encoder,
+        n_ch: usize,
+    }
+
+    impl Mp3Encoder {
+        pub fn new(sample_rate: usize, n_ch: usize) -> crate::Result<Mp3Encoder> {
+            let mut builder = Builder::new().ok_or("failed to allocate an mp3 encoder")?;
+            builder
+                .set_sample_rate(sample_rate as u32)
+                .map_err(mp3_error)?;
+            builder.set_num_channels(n_ch as u8).map_err(mp3_error)?;
+            builder.set_brate(BITRATE).map_err(mp3_error)?;
+            builder.set_quality(Quality::Good).map_err(mp3_error)?;
+            Ok(Mp3Encoder {
+                encoder: builder.build().map_err(mp3_error)?,
+                n_ch,
+            })
+        }
+
+        pub fn encode(&mut self, packet: &AudioPacket, out: &mut Vec<u8>) -> crate::Result<()> {
+            let len = packet.samples.len() / packet.n_ch;
+            // worst case from lame.h
+            out.reserve(len * 5 / 4 + 7200);
+            let left = packet.channel(0);
+            // a mono source goes out on both sides of a stereo stream
+            let right = packet.channel(1.min(packet.n_ch - 1));
+            let written = if self.n_ch == 1 {
+                self.encoder.encode_to_vec(MonoPcm(left), out)
+            } else {
+                self.encoder.encode_to_vec(DualPcm { left, right }, out)
+            };
+            written.map_err(mp3_error)?;
+            Ok(())
+        }
+    }
+
+    fn mp3_error(err: impl std::fmt::Debug) -> crate::Error {
+        crate::Error::Codec(format!("mp3: {:?}", err))
+    }
+}
+
+// Run the http stream server; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_http_server(
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    shutdown: impl Future,
+) {
+    let mut server = match HttpServer::new(cfg, distributor).await {
+        Ok(server) => server,
+        Err(err) => {
+            error!("failed to start http server: {}", err);
+            return;
+        }
+    };
+    tokio::select! {
+        res = server.run() => {
+            if let Err(err) = res {
+                error!("failed to accept http connection: {}", err);
+            }
+        }
+        _ = shutdown => {
+            info!("cleaning up http server");
+        }
+    }
+}
@@ -1,38 +1,42 @@
+use crate::audio::convert::f32_to_i16;
+use crate::audio::format::Quantizer;
 use crate::config_file::Config;
+use crate::metrics::{Metrics, METRICS};
 use crate::PACKET_N_SAMPLE;
 use jack::RingBufferWriter;
 use std::future::Future;
 use std::sync::Arc;
 use tokio::sync::Notify;
+use tracing::{info, warn};
 
 struct Notifications;
 
 impl jack::NotificationHandler for Notifications {
     fn thread_init(&self, _: &jack::Client) {
-        println!("JACK: thread init");
+        info!("JACK: thread init");
     }
 
     fn shutdown(&mut self, status: jack::ClientStatus, reason: &str) {
-        println!(
+        info!(
             "JACK: shutdown with status {:?} because \"{}\"",
             status, reason
         );
     }
 
     fn freewheel(&mut self, _: &jack::Client, is_enabled: bool) {
-        println!(
+        info!(
             "JACK: freewheel mode is {}",
             if is_enabled { "on" } else { "off" }
         );
     }
 
     fn sample_rate(&mut self, _: &jack::Client, srate: jack::Frames) -> jack::Control {
-        println!("JACK: sample rate changed to {}", srate);
+        info!("JACK: sample rate changed to {}", srate);
         jack::Control::Continue
     }
 
     fn client_registration(&mut self, _: &jack::Client, name: &str, is_reg: bool) {
-        println!(
+        info!(
             "JACK: {} client with name \"{}\"",
             if is_reg { "registered" } else { "unregistered" },
             name
@@ -40,7 +44,7 @@ impl jack::NotificationHandler for Notifications {
     }
 
     fn port_registration(&mut self, _: &jack::Client, port_id: jack::PortId, is_reg: bool) {
-        println!(
+        info!(
             "JACK: {} port with id {}",
             if is_reg { "registered" } else { "unregistered" },
             port_id
@@ -54,7 +58,7 @@ impl jack::NotificationHandler for Notifications {
         old_name: &str,
         new_name: &str,
     ) -> jack::Control {
-        println!(
+        info!(
             "JACK: port with id {} renamed from {} to {}",
             port_id, old_name, new_name
         );
@@ -68,7 +72,7 @@ impl jack::NotificationHandler for Notifications {
         port_id_b: jack::PortId,
         are_connected: bool,
     ) {
-        println!(
+        info!(
             "JACK: ports with id {} and {} are {}",
             port_id_a,
             port_id_b,
@@ -81,104 +85,155 @@ impl jack::NotificationHandler for Notifications {
     }
 
     fn graph_reorder(&mut self, _: &jack::Client) -> jack::Control {
-        println!("JACK: graph reordered");
+        info!("JACK: graph reordered");
         jack::Control::Continue
     }
 
     fn xrun(&mut self, _: &jack::Client) -> jack::Control {
-        println!("JACK: xrun occurred! consider increasing period");
+        warn!("JACK: xrun occurred! consider increasing period");
+        Metrics::inc(&METRICS.xruns);
         jack::Control::Continue
     }
 }
 
-pub fn inspect_device() -> (jack::Client, usize) {
+// Connect to the jack server. Spawned servers are read from their physical capture
+// ports, at most 'mic.n_channel' of them; a server mic2net joins gets 'mic.n_channel'
+// input ports and has to run at 'mic.sample_rate'. Returns the channel count too.
+pub fn open_client(cfg: &Config) -> crate::Result<(jack::Client, usize)> {
     let (client, _status) =
-        jack::Client::new("rust_client", jack::ClientOptions::NO_START_SERVER).unwrap();
+        jack::Client::new(&cfg.jack.client_name, jack::ClientOptions::NO_START_SERVER)?;
+    if !cfg.jack.start_server {
+        if client.sample_rate() != cfg.mic.sample_rate {
+            return Err(format!(
+                "jack runs at {} Hz, mic.sample_rate is {}",
+                client.sample_rate(),
+                cfg.mic.sample_rate
+            )
+            .into());
+        }
+        return Ok((client, cfg.mic.n_channel));
+    }
 
     let in_ports_name = client.ports(Some("capture"), None, jack::PortFlags::IS_PHYSICAL);
     let out_ports_name = client.ports(Some("playback"), None, jack::PortFlags::IS_PHYSICAL);
-    println!("physical input: {:?}", in_ports_name);
-    println!("physical output: {:?}", out_ports_name);
-    (client, in_ports_name.len())
+    info!("physical input: {:?}", in_ports_name);
+    info!("physical output: {:?}", out_ports_name);
+    if in_ports_name.len() != cfg.mic.n_channel {
+        info!("n_channel set to {}", in_ports_name.len());
+    }
+    let n_ch = std::cmp::min(in_ports_name.len(), cfg.mic.n_channel);
+    Ok((client, n_ch))
 }
 
+// Register 'n_ch' input ports in_0, in_1, ... and write every 'PACKET_N_SAMPLE' frames
+// they receive to 'buf_writer' as a planar packet, whatever the jack period. They are
+// linked to the physical capture ports of a spawned server, or to the ports in
+// 'jack.connect' of a joined one; either way they can be re-linked while running.
 pub async fn start_jack_client(
     cfg: Arc<Config>,
     client: jack::Client,
+    n_ch: usize,
     notifier: Arc<Notify>,
     mut buf_writer: RingBufferWriter,
     shutdown: impl Future,
-) {
+) -> crate::Result<()> {
     let mut i16_buf = [0_i16; PACKET_N_SAMPLE];
-    let mut i_period = 0_usize;
-    let period = cfg.mic.period;
-    let n_period = PACKET_N_SAMPLE / cfg.mic.period;
-    let in_ports_name = client.ports(Some("capture"), None, jack::PortFlags::IS_PHYSICAL);
-    let out_ports_name = client.ports(Some("playback"), None, jack::PortFlags::IS_PHYSICAL);
-    let n_ch = std::cmp::min(in_ports_name.len(), cfg.mic.n_channel);
+    // frames of the packet being filled
+    let mut n_frame = 0_usize;
     let mut n_ch_buf = vec![[0.0_f32; PACKET_N_SAMPLE]; n_ch];
+    let mut quantizer = cfg.mic.dither.then(|| Quantizer::new(true));
 
     let mut in_ports = Vec::<jack::Port<jack::AudioIn>>::new();
-    for i in 0..in_ports_name.len() {
-        in_ports.push(
-            client
-                .register_port(format!("in_{i}").as_str(), jack::AudioIn::default())
-                .unwrap(),
-        );
+    for i in 0..n_ch {
+        in_ports.push(client.register_port(format!("in_{i}").as_str(), jack::AudioIn)?);
     }
+    let in_ports_name: Vec<String> = in_ports
+        .iter()
+        .filter_map(|port| port.name().ok())
+        .collect();
 
-    // {
     let process_callback = move |_: &jack::Client, ps: &jack::ProcessScope| -> jack::Control {
-        for (i, port) in in_ports.iter().enumerate() {
-            let in_data = port.as_slice(ps);
-            let write_pos = i_period * period as usize;
-            n_ch_buf[i][write_pos..write_pos + period as usize].copy_from_slice(in_data);
-        }
-        i_period += 1;
-        if i_period == n_period {
-            i_period = 0;
-            for i in 0..n_ch_buf.len() {
-                for j in 0..n_ch_buf[i].len() {
-                    i16_buf[j] = pcm_f32_to_i16(n_ch_buf[i][j]);
+        let n_frames = ps.n_frames() as usize;
+        let mut done = 0;
+        while done < n_frames {
+            let len = (PACKET_N_SAMPLE - n_frame).min(n_frames - done);
+            for (port, ch_buf) in in_ports.iter().zip(n_ch_buf.iter_mut()) {
+                ch_buf[n_frame..n_frame + len]
+                    .copy_from_slice(&port.as_slice(ps)[done..done + len]);
+            }
+            n_frame += len;
+            done += len;
+            if n_frame < PACKET_N_SAMPLE {
+                continue;
+            }
+            n_frame = 0;
+            // whole packets only, a partial one would shift every channel after it
+            if buf_writer.space() < n_ch * PACKET_N_SAMPLE * 2 {
+                Metrics::inc(&METRICS.capture_overruns);
+                continue;
+            }
+            for ch_buf in n_ch_buf.iter() {
+                match &mut quantizer {
+                    Some(quantizer) => {
+                        for (dst, src) in i16_buf.iter_mut().zip(ch_buf.iter()) {
+                            *dst = quantizer.quantize(*src);
+                        }
+                    }
+                    None => f32_to_i16(ch_buf, &mut i16_buf),
                 }
                 buf_writer.write_buffer(slice_i16_to_u8(i16_buf.as_ref()));
-                // emit reading signal here
-                notifier.notify_one();
             }
+            // emit reading signal here
+            notifier.notify_one();
         }
 
         jack::Control::Continue
     };
     let process = jack::ClosureProcessHandler::new(process_callback);
-    let active_client = client.activate_async(Notifications, process).unwrap();
-
-    for (i, port_name) in in_ports_name.iter().enumerate() {
-        active_client
-            .as_client()
-            .connect_ports_by_name(port_name, format!("rust_client:in_{i}").as_str())
-            .unwrap();
-    }
-    let (mic_idx, speaker_idx) = (
-        cfg.audio_connection.mic_idx as usize,
-        cfg.audio_connection.speaker_idx as usize,
-    );
-    if cfg.audio_connection.connect_mic_speaker
-        && in_ports_name.len() > mic_idx
-        && out_ports_name.len() > speaker_idx
-    {
-        active_client
-            .as_client()
-            .connect_ports_by_name(
-                in_ports_name[mic_idx].as_str(),
-                out_ports_name[speaker_idx].as_str(),
-            )
-            .unwrap();
+    let active_client = client.activate_async(Notifications, process)?;
+    let client = active_client.as_client();
+
+    if cfg.jack.start_server {
+        let capture_ports = client.ports(Some("capture"), None, jack::PortFlags::IS_PHYSICAL);
+        let playback_ports = client.ports(Some("playback"), None, jack::PortFlags::IS_PHYSICAL);
+        for (source, port) in capture_ports.iter().zip(&in_ports_name) {
+            client.connect_ports_by_name(source, port)?;
+        }
+        let (mic_idx, speaker_idx) = (
+            cfg.audio_connection.mic_idx as usize,
+            cfg.audio_connection.speaker_idx as usize,
+        );
+        if cfg.audio_connection.connect_mic_speaker
+            && capture_ports.len() > mic_idx
+            && playback_ports.len() > speaker_idx
+        {
+            client.connect_ports_by_name(
+                capture_ports[mic_idx].as_str(),
+                playback_ports[speaker_idx].as_str(),
+            )?;
+        }
+    } else {
+        if cfg.jack.connect.len() > n_ch {
+            warn!("jack.connect lists more ports than the {} channels", n_ch);
+        }
+        for (source, port) in cfg.jack.connect.iter().zip(&in_ports_name) {
+            if let Err(err) = client.connect_ports_by_name(source, port) {
+                warn!("failed to connect {} to {}: {}", source, port, err);
+            }
+        }
+        info!(
+            "jack client {} with {} input ports at {} Hz, {} frames per period",
+            client.name(),
+            n_ch,
+            client.sample_rate(),
+            client.buffer_size()
+        );
     }
 
     shutdown.await;
-    println!("shutting down jack client");
-    active_client.deactivate().unwrap();
-    // }
+    info!("shutting down jack client");
+    active_client.deactivate()?;
+    Ok(())
 }
 
 #[inline(always)]
@@ -186,15 +241,3 @@ fn slice_i16_to_u8(slice: &[i16]) -> &[u8] {
     let byte_len = slice.len() * 2;
     unsafe { std::slice::from_raw_parts(slice.as_ptr().cast::<u8>(), byte_len) }
 }
-
-#[inline(always)]
-fn pcm_f32_to_i16(s: f32) -> i16 {
-    let mut i = (s * 32768.0).round() as i32;
-    if i > 32767 {
-        i = 32767;
-    }
-    if i < -32768 {
-        i = -32768;
-    }
-    i as i16
-}
@@ -0,0 +1,61 @@
+pub use error::Error;
+pub type Result<T> = std::result::Result<T, Error>;
+
+// frameo per packet
+pub const HEADER_LEN: usize = 12;
+pub const PACKET_N_SAMPLE: usize = 160;
+
+pub mod acl;
+pub mod admin;
+pub mod audio;
+pub mod client_stats;
+pub mod clock;
+pub mod config_file;
+pub mod connection_errors;
+pub mod daemon;
+pub mod discovery;
+pub mod distributor;
+pub mod dsp;
+pub mod error;
+pub mod fec;
+pub mod formats;
+#[cfg(feature = "grpc")]
+pub mod grpc_server;
+pub mod http;
+pub mod http_server;
+pub mod jack_client;
+pub mod logging;
+pub mod metrics;
+pub mod nat;
+pub mod packet;
+pub mod pipeline;
+pub mod port_mapping;
+pub mod protocol;
+pub mod psk;
+pub mod push;
+pub mod quic_server;
+pub mod rate_limit;
+pub mod reload;
+pub mod ring_buf;
+pub mod rtp;
+pub mod rtsp;
+pub mod sink;
+pub mod socket;
+pub mod socket_options;
+pub mod source;
+pub mod srt_server;
+pub mod system_call;
+#[cfg(unix)]
+pub mod systemd;
+pub mod tcp_client;
+pub mod tcp_server;
+pub mod tls;
+pub mod transform;
+pub mod udp_client;
+pub mod udp_server;
+#[cfg(unix)]
+pub mod uds_server;
+#[cfg(feature = "webrtc")]
+pub mod webrtc;
+pub mod ws_server;
+pub mod zmq_server;
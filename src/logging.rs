@@ -0,0 +1,298 @@
+use crate::config_file::{LogConfig, LogFileConfig};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    // human readable, one line per event
+    Pretty,
+    // one json object per line, for log collectors
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    // by size only
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl LogRotation {
+    // Seconds per period, counted from the unix epoch, so days end at midnight UTC.
+    fn period(self) -> Option<u64> {
+        match self {
+            LogRotation::Never => None,
+            LogRotation::Hourly => Some(3600),
+            LogRotation::Daily => Some(86400),
+        }
+    }
+}
+
+// Install the global subscriber. RUST_LOG, when set, overrides the configured level.
+pub fn init_logging(cfg: &LogConfig) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&cfg.level));
+    let file = cfg
+        .file
+        .as_ref()
+        .and_then(|file| match RotatingFile::open(file) {
+            Ok(file) => Some(file),
+            Err(err) => {
+                // nowhere else to report it yet
+                eprintln!("logging to stdout, can't open {}: {}", file.path, err);
+                None
+            }
+        });
+    // no color codes in files
+    let ansi = file.is_none();
+    let writer = match file {
+        Some(file) => BoxMakeWriter::new(Mutex::new(file)),
+        None => BoxMakeWriter::new(io::stdout),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match cfg.format {
+        LogFormat::Pretty => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+// Log file moved aside to <path>.1 when it gets too big or its period is over, the
+// older ones shifting up to <path>.<keep>. The subscriber writes one event at a time,
+// so rotations fall between lines.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    // bytes; 0 for no limit
+    max_size: u64,
+    rotation: LogRotation,
+    // period the file was started in
+    started: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    // Append to 'cfg.path', creating it and its directory as needed.
+    pub fn open(cfg: &LogFileConfig) -> io::Result<RotatingFile> {
+        let path = PathBuf::from(&cfg.path);
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = open_append(&path)?;
+        let meta = file.metadata()?;
+        let modified = meta.modified().unwrap_or_else(|_| SystemTime::now());
+        let mut file = RotatingFile {
+            path,
+            file,
+            size: meta.len(),
+            max_size: cfg.max_size * 1024 * 1024,
+            rotation: cfg.rotate,
+            started: 0,
+            keep: cfg.keep,
+        };
+        // a file left from an earlier period is rotated with the first line
+        file.started = file.period_of(modified);
+        Ok(file)
+    }
+
+    fn period_of(&self, time: SystemTime) -> u64 {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.rotation.period().map_or(0, |period| secs / period)
+    }
+
+    fn is_due(&self, n_bytes: usize) -> bool {
+        let too_big =
+            self.max_size > 0 && self.size > 0 && self.size + n_bytes as u64 > self.max_size;
+        too_big || self.period_of(SystemTime::now()) != self.started
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            let _ = fs::remove_file(numbered(&self.path, self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(numbered(&self.path, n), numbered(&self.path, n + 1));
+            }
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+            self.file = open_append(&self.path)?;
+        }
+        self.size = 0;
+        self.started = self.period_of(SystemTime::now());
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.is_due(buf.len()) {
+            // keeps writing to the current file when it can't be moved, trying again
+            // after another period or 'max_size'
+            if let Err(err) = self.rotate() {
+                eprintln!("can't rotate {}: {}", self.path.display(), err);
+                self.size = 0;
+                self.started = self.period_of(SystemTime::now());
+            }
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mic2net-log-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("mic2net.log")
+    }
+
+    fn open(path: &Path, rotate: LogRotation, keep: usize) -> RotatingFile {
+        RotatingFile::open(&LogFileConfig {
+            path: path.to_string_lossy().into_owned(),
+            max_size: 0,
+            rotate,
+            keep,
+        })
+        .unwrap()
+    }
+
+    fn contents(path: &Path, n: usize) -> String {
+        let path = if n == 0 {
+            path.to_path_buf()
+        } else {
+            numbered(path, n)
+        };
+        fs::read_to_string(path).unwrap_or_default()
+    }
+
+    #[test]
+    fn rotated_files_are_numbered_after_the_log() {
+        assert_eq!(
+            numbered(Path::new("/var/log/mic2net.log"), 3),
+            Path::new("/var/log/mic2net.log.3")
+        );
+    }
+
+    #[test]
+    fn rotates_before_a_line_that_would_overflow() {
+        let path = scratch("size");
+        let mut file = open(&path, LogRotation::Never, 2);
+        file.max_size = 100;
+        let line = |c: char| format!("{}\n", c.to_string().repeat(59));
+        file.write_all(line('a').as_bytes()).unwrap();
+        assert!(!numbered(&path, 1).exists());
+        file.write_all(line('b').as_bytes()).unwrap();
+        assert_eq!(contents(&path, 1), line('a'));
+        assert_eq!(contents(&path, 0), line('b'));
+        file.write_all(line('c').as_bytes()).unwrap();
+        file.write_all(line('d').as_bytes()).unwrap();
+        // only 'keep' of them stay
+        assert_eq!(contents(&path, 0), line('d'));
+        assert_eq!(contents(&path, 1), line('c'));
+        assert_eq!(contents(&path, 2), line('b'));
+        assert!(!numbered(&path, 3).exists());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn long_line_in_an_empty_file_stays() {
+        let path = scratch("long");
+        let mut file = open(&path, LogRotation::Never, 2);
+        file.max_size = 10;
+        file.write_all(b"longer than the limit\n").unwrap();
+        assert!(!numbered(&path, 1).exists());
+        file.write_all(b"next\n").unwrap();
+        assert_eq!(contents(&path, 1), "longer than the limit\n");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn keep_0_truncates_in_place() {
+        let path = scratch("keep0");
+        let mut file = open(&path, LogRotation::Never, 0);
+        file.max_size = 10;
+        file.write_all(b"first\n").unwrap();
+        file.write_all(b"second\n").unwrap();
+        assert_eq!(contents(&path, 0), "second\n");
+        assert!(!numbered(&path, 1).exists());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn file_from_an_earlier_day_rotates_with_the_first_line() {
+        let path = scratch("daily");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "yesterday\n").unwrap();
+        let old = File::options().write(true).open(&path).unwrap();
+        old.set_modified(SystemTime::now() - Duration::from_secs(2 * 86400))
+            .unwrap();
+        let mut file = open(&path, LogRotation::Daily, 3);
+        file.write_all(b"today\n").unwrap();
+        file.write_all(b"still today\n").unwrap();
+        assert_eq!(contents(&path, 1), "yesterday\n");
+        assert_eq!(contents(&path, 0), "today\nstill today\n");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rotates_when_the_period_is_over() {
+        let path = scratch("hourly");
+        let mut file = open(&path, LogRotation::Hourly, 3);
+        file.write_all(b"this hour\n").unwrap();
+        assert!(!numbered(&path, 1).exists());
+        // as if the hour had ended since
+        file.started -= 1;
+        file.write_all(b"the next\n").unwrap();
+        assert_eq!(contents(&path, 1), "this hour\n");
+        assert_eq!(contents(&path, 0), "the next\n");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn never_rotates_by_time() {
+        let path = scratch("never");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "last year\n").unwrap();
+        let old = File::options().write(true).open(&path).unwrap();
+        old.set_modified(SystemTime::now() - Duration::from_secs(365 * 86400))
+            .unwrap();
+        let mut file = open(&path, LogRotation::Never, 3);
+        file.write_all(b"line\n").unwrap();
+        assert!(!numbered(&path, 1).exists());
+        assert_eq!(contents(&path, 0), "last year\nline\n");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -1,112 +1,1217 @@
-type Error = Box<dyn std::error::Error + Send + Sync>;
-type Result<T> = std::result::Result<T, Error>;
-
-mod system_call;
-use system_call::start_jack;
-mod jack_client;
-use jack_client::{inspect_device, start_jack_client};
-mod config_file;
-use config_file::Config;
-mod tcp_server;
-use tcp_server::start_server;
-mod ring_buf;
-mod socket;
-
-use arc_swap::ArcSwap;
-use bytes::{BufMut, BytesMut};
+use bytes::BytesMut;
+use clap::{Args, Parser, Subcommand};
+use mdns_sd::ServiceDaemon;
+use mic2net::admin::start_admin_server;
+use mic2net::audio::convert;
+use mic2net::audio::encode::WireCodec;
+use mic2net::audio::format::SampleFormat;
+use mic2net::audio::resample::resampled;
+use mic2net::audio::vad::{Vad, VAD};
+use mic2net::audio::CaptureBackend;
+use mic2net::client_stats::ClientRegistry;
+use mic2net::config_file::Config;
+use mic2net::daemon::{daemonize, Pidfile};
+use mic2net::discovery::{self, advertise};
+use mic2net::distributor::{frames_in, Distributor};
+use mic2net::dsp::build_chain;
+use mic2net::formats::FormatChains;
+use mic2net::http_server::start_http_server;
+use mic2net::jack_client::{open_client, start_jack_client};
+use mic2net::logging::init_logging;
+use mic2net::metrics::start_metrics_server;
+use mic2net::nat::{self, start_rendezvous_server};
+use mic2net::packet::Packetizer;
+use mic2net::pipeline::{OutputStages, Pipeline};
+use mic2net::port_mapping::start_port_mapping;
+use mic2net::protocol::StreamInfo;
+use mic2net::push::start_push;
+use mic2net::quic_server::start_quic_server;
+#[cfg(unix)]
+use mic2net::reload::reload_on_hangup;
+use mic2net::reload::RELOAD;
+use mic2net::rtp::start_rtp_sender;
+use mic2net::rtsp::start_rtsp_server;
+use mic2net::sink::icecast::start_icecast_source;
+use mic2net::sink::meter::MeterSink;
+use mic2net::sink::mqtt::MqttSink;
+use mic2net::sink::wav::WavSink;
+use mic2net::sink::AudioSink;
+use mic2net::source::file::FileSource;
+use mic2net::source::tone::{Tone, ToneSource, Waveform};
+use mic2net::source::{start_source, AudioSource};
+use mic2net::srt_server::start_srt_server;
+use mic2net::system_call::start_jack;
+use mic2net::tcp_server::{configure_accept, start_listener, start_server};
+use mic2net::udp_server::{start_multicast_sender, start_udp_server};
+#[cfg(unix)]
+use mic2net::uds_server::start_uds_server;
+use mic2net::ws_server::start_ws_server;
+use mic2net::zmq_server::{start_zmq_server, ZmqStream};
+use mic2net::{HEADER_LEN, PACKET_N_SAMPLE};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio;
 use tokio::sync::Notify;
+use tokio::task::JoinHandle;
 use tokio::time::{sleep, Duration};
+use tracing::{error, warn};
 
-// frameo per packet
-const HEADER_LEN: usize = 12;
-const PACKET_N_SAMPLE: usize = 160;
+#[derive(Parser)]
+#[command(version, about = "Stream a microphone array to network clients")]
+struct Cli {
+    /// Config file; defaults to $MIC2NET_CONFIG or config.toml
+    #[arg(short, long, global = true)]
+    config: Option<String>,
+    /// Detach from the terminal and run in the background (unix); stdout and stderr
+    /// are dropped unless redirected. On windows, run as the service registered with
+    /// --install-service
+    #[arg(long, global = true)]
+    daemon: bool,
+    /// Register a service that starts at boot and runs with --daemon, this config and
+    /// --pidfile; paths in the config should be absolute
+    #[cfg(windows)]
+    #[arg(long)]
+    install_service: bool,
+    /// Remove the service registered with --install-service
+    #[cfg(windows)]
+    #[arg(long)]
+    uninstall_service: bool,
+    /// Keep the process id in this file while running
+    #[arg(long, global = true)]
+    pidfile: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-#[tokio::main]
-async fn main() {
-    let cfg = Arc::new(Config::new());
-    let cfg_cp = cfg.clone();
-    let device_id = cfg.mic.device_id;
-    let mut jack_server = start_jack(cfg_cp);
+#[derive(Subcommand)]
+enum Command {
+    /// Capture from the microphone and serve clients (default)
+    Serve(ServeArgs),
+    /// List capture devices: JACK ports, or cpal input devices with the cpal backend
+    Devices(DeviceArgs),
+    /// Serve a generated sine, white noise or chirp instead of the microphone
+    TestTone(TestToneArgs),
+    /// Serve a wav file, at its own sample rate and channel count, instead of the
+    /// microphone
+    Replay(ReplayArgs),
+    /// Connect to a server and play one channel on the default output device
+    Play(PlayArgs),
+    /// List the servers advertising themselves on the LAN
+    Browse(BrowseArgs),
+    /// Introduce udp servers and clients behind NAT to each other ([udp.nat])
+    Rendezvous(RendezvousArgs),
+    /// Time the sample conversions of the capture paths, scalar against simd
+    Bench(BenchArgs),
+}
+
+#[derive(Args, Default)]
+struct DeviceArgs {
+    /// JACK driver, e.g. alsa or coreaudio
+    #[arg(long)]
+    driver: Option<String>,
+    /// Capture device name
+    #[arg(short, long)]
+    device: Option<String>,
+}
+
+#[derive(Args, Default)]
+struct FormatArgs {
+    /// Sample rate in Hz
+    #[arg(short = 'r', long)]
+    sample_rate: Option<usize>,
+    /// Number of channels to stream
+    #[arg(short = 'n', long)]
+    channels: Option<usize>,
+    /// TCP listen port
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// Maximum number of TCP clients
+    #[arg(short, long)]
+    max_clients: Option<u16>,
+}
+
+#[derive(Args, Default)]
+struct ServeArgs {
+    #[command(flatten)]
+    device: DeviceArgs,
+    #[command(flatten)]
+    format: FormatArgs,
+}
+
+#[derive(Args)]
+struct TestToneArgs {
+    /// sine, noise or chirp
+    #[arg(short, long, default_value = "sine")]
+    waveform: Waveform,
+    /// Tone frequency in Hz, where a chirp starts
+    #[arg(short, long, default_value_t = 440.0)]
+    frequency: f32,
+    /// Where a chirp ends, in Hz
+    #[arg(long, default_value_t = 4000.0)]
+    end_frequency: f32,
+    /// Seconds a chirp takes before it starts over
+    #[arg(long, default_value_t = 1.0)]
+    sweep: f32,
+    #[command(flatten)]
+    format: FormatArgs,
+}
+
+#[derive(Args)]
+struct ReplayArgs {
+    /// The wav file
+    path: PathBuf,
+    /// Start over at the end instead of stopping
+    #[arg(short, long = "loop")]
+    looping: bool,
+    #[command(flatten)]
+    format: FormatArgs,
+}
+
+#[derive(Args)]
+struct PlayArgs {
+    /// Server address
+    #[arg(default_value = "127.0.0.1:2345")]
+    address: String,
+    /// Channel to play
+    #[arg(long, default_value_t = 0)]
+    channel: usize,
+    /// Auth token, for servers that require one
+    #[arg(long)]
+    token: Option<String>,
+    /// Pre-shared key as 64 hex digits, for servers that seal their stream with one
+    #[arg(long)]
+    psk: Option<String>,
+    /// Sample rate of the stream; defaults to mic.sample_rate from the config
+    #[arg(short = 'r', long)]
+    sample_rate: Option<usize>,
+    /// Ask the server for the stream at this rate, as 16-bit pcm of the channels up to
+    /// the played one; overrides --sample-rate
+    #[arg(long)]
+    request_rate: Option<usize>,
+    /// Jitter buffer depth in ms to start playback with
+    #[arg(long, default_value_t = 60)]
+    jitter_target: u64,
+    /// Jitter buffer depth in ms beyond which old audio is dropped
+    #[arg(long, default_value_t = 500)]
+    jitter_max: u64,
+    /// Connect again when the connection drops, resuming the stream where it broke off
+    #[arg(long)]
+    reconnect: bool,
+}
+
+#[derive(Args)]
+struct BrowseArgs {
+    /// Seconds to wait for answers
+    #[arg(long, default_value_t = 3.0)]
+    timeout: f32,
+}
+
+#[derive(Args)]
+struct RendezvousArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "0.0.0.0")]
+    bind_address: String,
+    /// UDP listen port
+    #[arg(short, long, default_value_t = nat::RENDEZVOUS_PORT)]
+    port: u16,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    /// Samples per conversion
+    #[arg(short = 'n', long, default_value_t = 1 << 16)]
+    samples: usize,
+    /// Conversions to time
+    #[arg(long, default_value_t = 1000)]
+    rounds: usize,
+}
+
+impl DeviceArgs {
+    fn apply(&self, cfg: &mut Config) {
+        if let Some(driver) = &self.driver {
+            cfg.mic.driver = driver.clone();
+        }
+        if let Some(device) = &self.device {
+            cfg.mic.device_name = device.clone();
+        }
+    }
+}
 
-    sleep(Duration::from_secs(1)).await;
-    let (client, n_mic) = inspect_device();
-    if n_mic != cfg.mic.n_channel {
-        println!("n_channel set to {}", n_mic);
+impl FormatArgs {
+    fn apply(&self, cfg: &mut Config) {
+        if let Some(sample_rate) = self.sample_rate {
+            cfg.mic.sample_rate = sample_rate;
+        }
+        if let Some(channels) = self.channels {
+            cfg.mic.n_channel = channels;
+        }
+        if let Some(port) = self.port {
+            cfg.tcp.listen_port = port;
+        }
+        if let Some(max_clients) = self.max_clients {
+            cfg.tcp.max_clients = max_clients;
+        }
     }
-    let n_ch = std::cmp::min(n_mic, cfg.mic.n_channel);
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let path = cli.config.clone().unwrap_or_else(Config::default_path);
+    let mut cfg = Config::load(&path);
+    // as in the file, before the command line overrides it
+    RELOAD.init(&path, &cfg);
+    if let Some(Command::Serve(args)) = &cli.command {
+        // here rather than in 'run', for the loopback source to follow it
+        args.device.apply(&mut cfg);
+    }
+    configure_accept(&cfg.accept);
+    #[cfg(windows)]
+    if cli.install_service || cli.uninstall_service {
+        let result = if cli.install_service {
+            mic2net::daemon::install_service(Path::new(&path), cli.pidfile.as_deref())
+        } else {
+            mic2net::daemon::uninstall_service()
+        };
+        if let Err(err) = result {
+            eprintln!("can't change the service: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    // before the runtime starts its threads
+    #[cfg(all(feature = "cpal", target_os = "linux"))]
+    mic2net::audio::capture::select_loopback_source(&cfg.mic);
+    if cli.daemon {
+        if let Err(err) = daemonize() {
+            eprintln!("can't run as a daemon: {}", err);
+            std::process::exit(1);
+        }
+    }
+    let _pidfile = cli.pidfile.as_deref().map(|path| {
+        Pidfile::create(path).unwrap_or_else(|err| {
+            eprintln!("can't write the pidfile: {}", err);
+            std::process::exit(1);
+        })
+    });
+    init_logging(&cfg.log);
+
+    let runtime = tokio::runtime::Runtime::new().expect("failed to start the tokio runtime");
+    runtime.block_on(run(cli.command, cfg));
+    // the last thing, as the service control manager may end the process once told
+    #[cfg(windows)]
+    {
+        drop(runtime);
+        drop(_pidfile);
+        mic2net::daemon::service_stopped();
+    }
+}
+
+async fn run(command: Option<Command>, mut cfg: Config) {
+    match command.unwrap_or(Command::Serve(ServeArgs::default())) {
+        Command::Serve(args) => {
+            args.format.apply(&mut cfg);
+            serve(Arc::new(cfg)).await;
+        }
+        Command::Devices(args) => {
+            args.apply(&mut cfg);
+            list_devices(Arc::new(cfg)).await;
+        }
+        Command::TestTone(args) => {
+            args.format.apply(&mut cfg);
+            let tone = Tone {
+                waveform: args.waveform,
+                frequency: args.frequency,
+                end_frequency: args.end_frequency,
+                sweep: Duration::from_secs_f32(args.sweep),
+            };
+            test_tone(Arc::new(cfg), tone).await;
+        }
+        Command::Replay(args) => {
+            args.format.apply(&mut cfg);
+            replay(cfg, &args.path, args.looping).await;
+        }
+        Command::Play(args) => {
+            let sample_rate = args
+                .request_rate
+                .or(args.sample_rate)
+                .unwrap_or(cfg.mic.sample_rate);
+            play(args, sample_rate).await;
+        }
+        Command::Browse(args) => browse(&args).await,
+        Command::Rendezvous(args) => {
+            start_rendezvous_server(&args.bind_address, args.port, tokio::signal::ctrl_c()).await
+        }
+        Command::Bench(args) => bench(&args),
+    }
+}
+
+async fn browse(args: &BrowseArgs) {
+    let timeout = Duration::from_secs_f32(args.timeout);
+    let servers = match tokio::task::spawn_blocking(move || discovery::browse(timeout)).await {
+        Ok(Ok(servers)) => servers,
+        Ok(Err(err)) => return error!("failed to browse: {}", err),
+        Err(err) => return error!("failed to browse: {}", err),
+    };
+    if servers.is_empty() {
+        println!("no servers found");
+    }
+    for server in servers {
+        let address = server
+            .addresses
+            .first()
+            .map_or_else(|| server.host.clone(), |addr| addr.to_string());
+        let format = match (server.sample_rate, server.channels, &server.format) {
+            (Some(rate), Some(n_ch), Some(format)) => {
+                format!("{} Hz, {} ch, {}", rate, n_ch, format)
+            }
+            _ => "format unknown".to_string(),
+        };
+        let mut flags = Vec::new();
+        if server.tls {
+            flags.push("tls");
+        }
+        if server.auth {
+            flags.push("auth");
+        }
+        println!(
+            "{:<24} {:<24} {}{}",
+            server.name,
+            address,
+            format,
+            if flags.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", flags.join(", "))
+            }
+        );
+    }
+}
+
+fn bench(args: &BenchArgs) {
+    println!(
+        "{} rounds of {} samples, simd: {}",
+        args.rounds,
+        args.samples,
+        convert::simd_path()
+    );
+    let per_sample =
+        |time: Duration| time.as_secs_f64() * 1e9 / (args.rounds * args.samples) as f64;
+    for timing in convert::bench(args.samples, args.rounds) {
+        println!(
+            "{:<20} scalar {:>6.3} ns/sample   simd {:>6.3} ns/sample   {:.1}x",
+            timing.name,
+            per_sample(timing.scalar),
+            per_sample(timing.simd),
+            timing.scalar.as_secs_f64() / timing.simd.as_secs_f64()
+        );
+    }
+}
+
+async fn serve(cfg: Arc<Config>) {
+    match cfg.mic.backend {
+        CaptureBackend::Jack => serve_jack(cfg).await,
+        CaptureBackend::Cpal => serve_cpal(cfg).await,
+        CaptureBackend::Pipewire => serve_pipewire(cfg).await,
+        CaptureBackend::Alsa => serve_alsa(cfg).await,
+        CaptureBackend::Wasapi => serve_wasapi(cfg).await,
+    }
+}
+
+#[cfg(feature = "cpal")]
+async fn serve_cpal(cfg: Arc<Config>) {
+    use mic2net::audio::mixer::{mixer_inputs, Mixer};
+    let n_ch = Mixer::new(cfg.mic.mix, &mixer_inputs(&cfg.mic)).n_channel();
+    let packetizer = new_packetizer(&cfg, n_ch);
+    let transports = start_transports(cfg.clone(), &packetizer, n_ch);
+
+    // the cpal stream isn't Send, so capture runs on this task instead of a spawned one
+    let res = match mic2net::audio::capture::CpalSource::open(cfg) {
+        Ok(source) => start_source(source, packetizer, tokio::signal::ctrl_c()).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = res {
+        error!("capture failed: {}", err);
+        std::process::exit(1);
+    }
+
+    transports.stop().await;
+}
+
+#[cfg(not(feature = "cpal"))]
+async fn serve_cpal(_cfg: Arc<Config>) {
+    error!("backend \"cpal\" needs a build with --features cpal");
+}
+
+#[cfg(feature = "pipewire")]
+async fn serve_pipewire(cfg: Arc<Config>) {
+    if !cfg.mic.inputs.is_empty() {
+        warn!("mic.inputs is only used by the cpal backend");
+    }
+    let n_ch = cfg.mic.n_channel;
+    let packetizer = new_packetizer(&cfg, n_ch);
+    let transports = start_transports(cfg.clone(), &packetizer, n_ch);
+
+    if let Err(err) =
+        mic2net::audio::pipewire::start_pipewire_capture(cfg, packetizer, tokio::signal::ctrl_c())
+            .await
+    {
+        error!("capture failed: {}", err);
+        std::process::exit(1);
+    }
+
+    transports.stop().await;
+}
+
+#[cfg(not(feature = "pipewire"))]
+async fn serve_pipewire(_cfg: Arc<Config>) {
+    error!("backend \"pipewire\" needs a build with --features pipewire");
+}
+
+#[cfg(feature = "alsa")]
+async fn serve_alsa(cfg: Arc<Config>) {
+    if !cfg.mic.inputs.is_empty() {
+        warn!("mic.inputs is only used by the cpal backend");
+    }
+    let n_ch = cfg.mic.n_channel;
+    let packetizer = new_packetizer(&cfg, n_ch);
+    let transports = start_transports(cfg.clone(), &packetizer, n_ch);
+
+    if let Err(err) =
+        mic2net::audio::alsa::start_alsa_capture(cfg, packetizer, tokio::signal::ctrl_c()).await
+    {
+        error!("capture failed: {}", err);
+        std::process::exit(1);
+    }
+
+    transports.stop().await;
+}
+
+#[cfg(not(feature = "alsa"))]
+async fn serve_alsa(_cfg: Arc<Config>) {
+    error!("backend \"alsa\" needs a build with --features alsa");
+}
+
+#[cfg(all(windows, feature = "wasapi"))]
+async fn serve_wasapi(cfg: Arc<Config>) {
+    if !cfg.mic.inputs.is_empty() {
+        warn!("mic.inputs is only used by the cpal backend");
+    }
+    let n_ch = cfg.mic.n_channel;
+    let packetizer = new_packetizer(&cfg, n_ch);
+    let transports = start_transports(cfg.clone(), &packetizer, n_ch);
+
+    if let Err(err) =
+        mic2net::audio::wasapi::start_wasapi_capture(cfg, packetizer, tokio::signal::ctrl_c()).await
+    {
+        error!("capture failed: {}", err);
+        std::process::exit(1);
+    }
+
+    transports.stop().await;
+}
+
+#[cfg(not(all(windows, feature = "wasapi")))]
+async fn serve_wasapi(_cfg: Arc<Config>) {
+    error!("backend \"wasapi\" needs a windows build with --features wasapi");
+}
+
+async fn serve_jack(cfg: Arc<Config>) {
+    if !cfg.mic.inputs.is_empty() {
+        warn!("mic.inputs is only used by the cpal backend");
+    }
+    let mut jack_server = cfg.jack.start_server.then(|| start_jack(cfg.clone()));
+    if jack_server.is_some() {
+        sleep(Duration::from_secs(1)).await;
+    }
+    let (client, n_ch) = match open_client(&cfg) {
+        Ok(client) => client,
+        Err(err) => {
+            error!("failed to open jack client: {}", err);
+            std::process::exit(1);
+        }
+    };
 
-    let pkt_len = HEADER_LEN + PACKET_N_SAMPLE * n_ch * 2;
     let mut audio_data_buf = BytesMut::zeroed(PACKET_N_SAMPLE * n_ch * 2);
-    let mut swap_buf = Arc::new(BytesMut::zeroed(pkt_len));
-    // let pkt_buf = ;
-    let atomic_pkt_buf = Arc::new(ArcSwap::new(Arc::new(BytesMut::zeroed(pkt_len))));
-    let ringbuf = jack::RingBuffer::new(cfg.mic.sample_rate as usize * n_ch * 2).unwrap();
+    let mut packetizer = new_packetizer(&cfg, n_ch);
+    let ringbuf = jack::RingBuffer::new(cfg.mic.sample_rate * n_ch * 2).unwrap();
     let (mut ringbuf_reader, ringbuf_writer) = ringbuf.into_reader_writer();
 
     let notify_dump_data = Arc::new(Notify::new());
-    let notify_data_ready = Arc::new(Notify::new());
+    let transports = start_transports(cfg.clone(), &packetizer, n_ch);
 
-    let pkt_buf_mut = atomic_pkt_buf.clone();
     let notify_dump_data_cp = notify_dump_data.clone();
-    let notify_data_ready_cp = notify_data_ready.clone();
     let _buf_thread = tokio::spawn(async move {
-        let mut pkt_id = 0_u32;
         loop {
             notify_dump_data_cp.notified().await;
-            // println!("ringbuf len: {}", ringbuf_reader.space());
-            let swap_buf_mut = Arc::get_mut(&mut swap_buf).unwrap();
-            swap_buf_mut.clear();
-            let unix_time_in_millis = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis()
-                - 10;
-            let secs = (unix_time_in_millis / 1000) as u32;
-            let millis = (unix_time_in_millis % 1000) as u16;
-            swap_buf_mut.put_u16(device_id as u16);
-            swap_buf_mut.put_u32(secs);
-            swap_buf_mut.put_u16(millis);
-            swap_buf_mut.put_u32(pkt_id);
-
             let _read_size = ringbuf_reader.read_buffer(audio_data_buf.as_mut());
-            swap_buf_mut.extend_from_slice(audio_data_buf.as_ref());
+            packetizer.publish(audio_data_buf.as_ref());
+        }
+    });
 
-            swap_buf = pkt_buf_mut.swap(swap_buf);
-            notify_data_ready_cp.notify_waiters();
+    let cfg_cp = cfg.clone();
+    if let Err(err) = start_jack_client(
+        cfg_cp,
+        client,
+        n_ch,
+        notify_dump_data,
+        ringbuf_writer,
+        tokio::signal::ctrl_c(),
+    )
+    .await
+    {
+        error!("jack client failed: {}", err);
+    }
 
-            pkt_id += 1;
-            if pkt_id == std::u32::MAX {
-                pkt_id = 0;
+    transports.stop().await;
+    if let Some(jack_server) = &mut jack_server {
+        sleep(Duration::from_secs(1)).await;
+        jack_server.kill().await.unwrap();
+    }
+}
+
+async fn test_tone(cfg: Arc<Config>, tone: Tone) {
+    let n_ch = cfg.mic.n_channel;
+    let packetizer = new_packetizer(&cfg, n_ch);
+    let transports = start_transports(cfg.clone(), &packetizer, n_ch);
+
+    let source = ToneSource::new(tone, cfg.mic.sample_rate, n_ch);
+    if let Err(err) = start_source(source, packetizer, tokio::signal::ctrl_c()).await {
+        error!("test tone failed: {}", err);
+    }
+
+    transports.stop().await;
+}
+
+async fn replay(mut cfg: Config, path: &Path, looping: bool) {
+    let source = match FileSource::open(path, looping) {
+        Ok(source) => source,
+        Err(err) => {
+            error!("can't replay {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    };
+    // the transports announce what the file holds
+    let format = source.format();
+    cfg.mic.sample_rate = format.sample_rate;
+    cfg.mic.n_channel = format.n_channel;
+    let cfg = Arc::new(cfg);
+    let packetizer = new_packetizer(&cfg, format.n_channel);
+    let transports = start_transports(cfg.clone(), &packetizer, format.n_channel);
+
+    if let Err(err) = start_source(source, packetizer, tokio::signal::ctrl_c()).await {
+        error!("replay failed: {}", err);
+    }
+
+    transports.stop().await;
+}
+
+#[cfg(feature = "cpal")]
+async fn play(args: PlayArgs, sample_rate: usize) {
+    use mic2net::psk::Psk;
+    use mic2net::tcp_client::{play, JitterConfig, ReconnectConfig, TcpClient};
+    let jitter = JitterConfig {
+        target: args.jitter_target,
+        max: args.jitter_max,
+    };
+    let res = async {
+        let mut client = TcpClient::connect(args.address.as_str(), args.token.as_deref()).await?;
+        if let Some(psk) = &args.psk {
+            client.set_psk(Psk::parse(psk)?);
+        }
+        if args.reconnect {
+            client.set_reconnect(ReconnectConfig::default());
+        }
+        if let Some(rate) = args.request_rate {
+            client
+                .request_format(&StreamInfo::pcm(rate, args.channel + 1))
+                .await?;
+        }
+        play(
+            client,
+            sample_rate,
+            args.channel,
+            jitter,
+            tokio::signal::ctrl_c(),
+        )
+        .await
+    };
+    if let Err(err) = res.await {
+        error!("playback failed: {}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(not(feature = "cpal"))]
+async fn play(_args: PlayArgs, _sample_rate: usize) {
+    error!("playback needs a build with --features cpal");
+}
+
+async fn list_devices(cfg: Arc<Config>) {
+    #[cfg(feature = "cpal")]
+    if cfg.mic.backend == CaptureBackend::Cpal {
+        match mic2net::audio::capture::list_input_devices() {
+            Ok(devices) => {
+                for (index, name) in devices.iter().enumerate() {
+                    println!("{:>3}  {}", index, name);
+                }
             }
+            Err(err) => error!("failed to list input devices: {}", err),
         }
-    });
+        return;
+    }
+
+    let mut jack_server = cfg.jack.start_server.then(|| start_jack(cfg.clone()));
+    if jack_server.is_some() {
+        sleep(Duration::from_secs(1)).await;
+    }
+    let client =
+        match jack::Client::new(&cfg.jack.client_name, jack::ClientOptions::NO_START_SERVER) {
+            Ok((client, _)) => client,
+            Err(err) => {
+                error!("failed to open jack client: {}", err);
+                return;
+            }
+        };
+    if cfg.jack.start_server {
+        for port in client.ports(Some("capture"), None, jack::PortFlags::IS_PHYSICAL) {
+            println!("capture   {}", port);
+        }
+        for port in client.ports(Some("playback"), None, jack::PortFlags::IS_PHYSICAL) {
+            println!("playback  {}", port);
+        }
+    } else {
+        // what 'jack.connect' can name
+        for port in client.ports(None, Some("audio"), jack::PortFlags::IS_OUTPUT) {
+            println!("output    {}", port);
+        }
+    }
+    drop(client);
+    if let Some(jack_server) = &mut jack_server {
+        jack_server.kill().await.unwrap();
+    }
+}
+
+fn new_packetizer(cfg: &Config, n_ch: usize) -> Packetizer {
+    let pkt_len = HEADER_LEN + PACKET_N_SAMPLE * n_ch * 2;
+    let mut packetizer = Packetizer::new(cfg.mic.device_id as u16, pkt_len, cfg.mic.sample_rate);
+    // always there, since a config reload may enable it
+    packetizer.set_vad(Vad::new(cfg.mic.sample_rate));
+    VAD.configure(&cfg.vad);
+    packetizer.set_silence_frames(cfg.vad.silence_frames);
+    packetizer.set_dsp(build_chain(cfg));
+    packetizer
+}
+
+// Start every enabled transport on the packets coming out of 'packetizer'.
+fn start_transports(cfg: Arc<Config>, packetizer: &Packetizer, n_ch: usize) -> Transports {
+    // tasks that must finish before the process exits
+    let mut threads = Vec::new();
+    // enough history for the longest pre-roll of any output
+    let preroll = [
+        cfg.tcp.preroll,
+        cfg.uds.preroll,
+        cfg.ws.preroll,
+        cfg.grpc.preroll,
+        cfg.quic.preroll,
+        cfg.srt.preroll,
+        cfg.http.preroll,
+        cfg.push.preroll,
+    ]
+    .into_iter()
+    .chain(cfg.listeners.iter().map(|listener| listener.preroll))
+    .max()
+    .unwrap_or(0);
+    let distributor = Distributor::new(
+        StreamInfo::pcm(cfg.mic.sample_rate, n_ch),
+        frames_in(preroll, cfg.mic.sample_rate),
+    );
+    let _distributor_thread = tokio::spawn(distributor.clone().run(packetizer.packets()));
+
+    // each output gets the stream at its own wire rate
+    let capture_rate = cfg.mic.sample_rate;
+    let output = |rate: Option<usize>| resampled(&distributor, rate.unwrap_or(capture_rate));
+    // ... and with its own channels, level, codec and sample format, through the stages
+    // of [pipeline]; None after logging why when that isn't available
+    let wire = |name: &str,
+                channels: &Option<Vec<Vec<usize>>>,
+                rate: Option<usize>,
+                gain: f32,
+                codec: WireCodec,
+                format: SampleFormat| {
+        let stages = OutputStages {
+            name,
+            channels,
+            sample_rate: rate,
+            gain,
+            codec,
+            sample_format: format,
+        };
+        match Pipeline::for_output(&cfg, &distributor, &stages) {
+            Ok(pipeline) => Some(pipeline.stream()),
+            Err(err) => {
+                error!("failed to start {} output: {}", name, err);
+                None
+            }
+        }
+    };
+
+    // shared with the admin api
+    let udp_clients = ClientRegistry::new(cfg.udp.max_clients.into());
+    RELOAD.add_clients("udp", &udp_clients);
+    if cfg.udp.enable {
+        if let Some(distributor_cp) = wire(
+            "udp",
+            &cfg.udp.channels,
+            cfg.udp.sample_rate,
+            cfg.udp.gain,
+            cfg.udp.codec,
+            cfg.udp.sample_format,
+        ) {
+            let cfg_cp = cfg.clone();
+            let clients = udp_clients.clone();
+            tokio::spawn(async move {
+                start_udp_server(cfg_cp, distributor_cp, clients, tokio::signal::ctrl_c()).await;
+            });
+        }
+    }
+
+    if cfg.multicast.enable {
+        if let Some(distributor_cp) = wire(
+            "multicast",
+            &cfg.multicast.channels,
+            cfg.multicast.sample_rate,
+            cfg.multicast.gain,
+            cfg.multicast.codec,
+            cfg.multicast.sample_format,
+        ) {
+            let cfg_cp = cfg.clone();
+            tokio::spawn(async move {
+                start_multicast_sender(cfg_cp, distributor_cp, tokio::signal::ctrl_c()).await;
+            });
+        }
+    }
+
+    #[cfg(unix)]
+    if cfg.uds.enable {
+        if let Some(distributor_cp) = wire(
+            "uds",
+            &cfg.uds.channels,
+            cfg.uds.sample_rate,
+            cfg.uds.gain,
+            cfg.uds.codec,
+            cfg.uds.sample_format,
+        ) {
+            let cfg_cp = cfg.clone();
+            let clients = ClientRegistry::new(cfg.uds.max_clients.into());
+            RELOAD.add_clients("uds", &clients);
+            threads.push(tokio::spawn(async move {
+                start_uds_server(cfg_cp, distributor_cp, clients, tokio::signal::ctrl_c()).await;
+            }));
+        }
+    }
+
+    if cfg.ws.enable {
+        if let Some(distributor_cp) = wire(
+            "ws",
+            &cfg.ws.channels,
+            cfg.ws.sample_rate,
+            cfg.ws.gain,
+            cfg.ws.codec,
+            cfg.ws.sample_format,
+        ) {
+            let cfg_cp = cfg.clone();
+            tokio::spawn(async move {
+                start_ws_server(cfg_cp, distributor_cp, tokio::signal::ctrl_c()).await;
+            });
+        }
+    }
+
+    if cfg.grpc.enable {
+        if let Some(distributor_cp) = wire(
+            "grpc",
+            &cfg.grpc.channels,
+            cfg.grpc.sample_rate,
+            cfg.grpc.gain,
+            cfg.grpc.codec,
+            cfg.grpc.sample_format,
+        ) {
+            start_grpc(cfg.clone(), distributor_cp);
+        }
+    }
+
+    if cfg.quic.enable {
+        if let Some(distributor_cp) = wire(
+            "quic",
+            &cfg.quic.channels,
+            cfg.quic.sample_rate,
+            cfg.quic.gain,
+            cfg.quic.codec,
+            cfg.quic.sample_format,
+        ) {
+            let cfg_cp = cfg.clone();
+            threads.push(tokio::spawn(async move {
+                start_quic_server(cfg_cp, distributor_cp, tokio::signal::ctrl_c()).await;
+            }));
+        }
+    }
+
+    if cfg.srt.enable {
+        if let Some(distributor_cp) = wire(
+            "srt",
+            &cfg.srt.channels,
+            cfg.srt.sample_rate,
+            cfg.srt.gain,
+            cfg.srt.codec,
+            cfg.srt.sample_format,
+        ) {
+            let cfg_cp = cfg.clone();
+            tokio::spawn(async move {
+                start_srt_server(cfg_cp, distributor_cp, tokio::signal::ctrl_c()).await;
+            });
+        }
+    }
+
+    if cfg.zmq.enable {
+        let zmq = &cfg.zmq;
+        let main = (
+            "zmq".to_string(),
+            &zmq.topic,
+            &zmq.channels,
+            zmq.sample_rate,
+            zmq.gain,
+            zmq.codec,
+            zmq.sample_format,
+        );
+        let more = zmq.streams.iter().map(|stream| {
+            (
+                format!("zmq:{}", stream.topic),
+                &stream.topic,
+                &stream.channels,
+                stream.sample_rate,
+                stream.gain,
+                stream.codec,
+                stream.sample_format,
+            )
+        });
+        // a stream that can't be set up is left out, the others go on
+        let streams: Vec<ZmqStream> = std::iter::once(main)
+            .chain(more)
+            .filter_map(|(name, topic, channels, rate, gain, codec, format)| {
+                let distributor = wire(&name, channels, rate, gain, codec, format)?;
+                Some(ZmqStream {
+                    topic: topic.clone(),
+                    distributor,
+                })
+            })
+            .collect();
+        let cfg_cp = cfg.clone();
+        tokio::spawn(async move {
+            start_zmq_server(cfg_cp, streams, tokio::signal::ctrl_c()).await;
+        });
+    }
+
+    if cfg.push.enable {
+        if let Some(distributor_cp) = wire(
+            "push",
+            &cfg.push.channels,
+            cfg.push.sample_rate,
+            cfg.push.gain,
+            cfg.push.codec,
+            cfg.push.sample_format,
+        ) {
+            let cfg_cp = cfg.clone();
+            threads.push(tokio::spawn(async move {
+                start_push(cfg_cp, distributor_cp, tokio::signal::ctrl_c()).await;
+            }));
+        }
+    }
+
+    if cfg.http.enable {
+        // the http formats do their own encoding
+        if let Some(distributor_cp) = wire(
+            "http",
+            &cfg.http.channels,
+            cfg.http.sample_rate,
+            cfg.http.gain,
+            WireCodec::Pcm,
+            SampleFormat::I16,
+        ) {
+            let cfg_cp = cfg.clone();
+            tokio::spawn(async move {
+                start_http_server(cfg_cp, distributor_cp, tokio::signal::ctrl_c()).await;
+            });
+        }
+    }
+
+    if cfg.icecast.enable {
+        // encoded like the http formats
+        if let Some(distributor_cp) = wire(
+            "icecast",
+            &cfg.icecast.channels,
+            cfg.icecast.sample_rate,
+            cfg.icecast.gain,
+            WireCodec::Pcm,
+            SampleFormat::I16,
+        ) {
+            let cfg_cp = cfg.clone();
+            tokio::spawn(async move {
+                start_icecast_source(cfg_cp, distributor_cp, tokio::signal::ctrl_c()).await;
+            });
+        }
+    }
+
+    if cfg.rtp.enable {
+        let cfg_cp = cfg.clone();
+        let distributor_cp = output(cfg.rtp.sample_rate);
+        tokio::spawn(async move {
+            start_rtp_sender(cfg_cp, distributor_cp, n_ch, tokio::signal::ctrl_c()).await;
+        });
+    }
+
+    if cfg.rtsp.enable {
+        let cfg_cp = cfg.clone();
+        let distributor_cp = output(cfg.rtp.sample_rate);
+        tokio::spawn(async move {
+            start_rtsp_server(cfg_cp, distributor_cp, n_ch, tokio::signal::ctrl_c()).await;
+        });
+    }
 
-    let (listen_port, max_clients) = (cfg.tcp.listen_port, cfg.tcp.max_clients);
-    let tcp_thread = tokio::spawn(async move {
+    if cfg.webrtc.enable {
+        start_webrtc(cfg.clone(), output, n_ch);
+    }
+
+    // sinks of the capture stream, each with a queue of its own
+    let mut sinks = Pipeline::new(&distributor);
+    if cfg.wav.enable {
+        match WavSink::new(cfg.clone()) {
+            Ok(sink) => sinks = sinks.sink(Box::new(sink)),
+            Err(err) => error!("failed to start recording: {}", err),
+        }
+    }
+    if cfg.meter.enable {
+        sinks = sinks.sink(Box::new(MeterSink::new(&cfg)));
+    }
+    threads.extend(sinks.start(tokio::signal::ctrl_c).1);
+
+    if cfg.mqtt.enable {
+        if let Some(stream) = wire(
+            "mqtt",
+            &cfg.mqtt.channels,
+            cfg.mqtt.sample_rate,
+            cfg.mqtt.gain,
+            cfg.mqtt.codec,
+            cfg.mqtt.sample_format,
+        ) {
+            let sink = MqttSink::new(&cfg, &stream.stream_info(), packetizer.levels());
+            let sinks = Pipeline::new(&stream).sink(Box::new(sink));
+            threads.extend(sinks.start(tokio::signal::ctrl_c).1);
+        }
+    }
+
+    if cfg.kafka.enable {
+        if let Some(sink) = kafka_sink(&cfg) {
+            if let Some(stream) = wire(
+                "kafka",
+                &cfg.kafka.channels,
+                cfg.kafka.sample_rate,
+                cfg.kafka.gain,
+                cfg.kafka.codec,
+                cfg.kafka.sample_format,
+            ) {
+                let sinks = Pipeline::new(&stream).sink(sink);
+                threads.extend(sinks.start(tokio::signal::ctrl_c).1);
+            }
+        }
+    }
+
+    if cfg.hls.enable {
+        start_hls(cfg.clone(), output, n_ch);
+    }
+
+    // shared with the admin api
+    let tcp_clients = ClientRegistry::new(cfg.tcp.max_clients.into());
+    tcp_clients.set_bandwidth_limit(cfg.tcp.bandwidth_limit);
+    RELOAD.add_clients("tcp", &tcp_clients);
+    if cfg.admin.enable {
+        let cfg_cp = cfg.clone();
+        let clients = tcp_clients.clone();
+        let levels = packetizer.levels();
+        tokio::spawn(async move {
+            start_admin_server(
+                cfg_cp,
+                clients,
+                udp_clients,
+                levels,
+                tokio::signal::ctrl_c(),
+            )
+            .await;
+        });
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(tokio::signal::ctrl_c()));
+
+    if cfg.metrics.enable {
+        let cfg_cp = cfg.clone();
+        tokio::spawn(async move {
+            start_metrics_server(cfg_cp, tokio::signal::ctrl_c()).await;
+        });
+    }
+
+    if cfg.port_mapping.enable {
+        threads.push(tokio::spawn(start_port_mapping(
+            cfg.clone(),
+            tokio::signal::ctrl_c(),
+        )));
+    }
+
+    let mdns = if cfg.discovery.enable {
+        match advertise(&cfg, n_ch) {
+            Ok(daemon) => Some(daemon),
+            Err(err) => {
+                error!("failed to advertise over mDNS: {}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    for (index, listener) in cfg.listeners.iter().enumerate() {
+        let name = format!("tcp:{}", listener.listen_port);
+        if let Some(distributor_cp) = wire(
+            &name,
+            &listener.channels,
+            listener.sample_rate,
+            listener.gain,
+            listener.codec,
+            listener.sample_format,
+        ) {
+            let cfg_cp = cfg.clone();
+            let formats = listener
+                .negotiate
+                .then(|| FormatChains::new(distributor.clone(), &name, listener));
+            let clients = ClientRegistry::new(listener.max_clients.into());
+            clients.set_bandwidth_limit(listener.bandwidth_limit);
+            RELOAD.add_clients(&name, &clients);
+            threads.push(tokio::spawn(async move {
+                start_listener(
+                    cfg_cp,
+                    index,
+                    distributor_cp,
+                    formats,
+                    clients,
+                    tokio::signal::ctrl_c(),
+                )
+                .await;
+            }));
+        }
+    }
+
+    let cfg_cp = cfg.clone();
+    let tcp_distributor = match wire(
+        "tcp",
+        &cfg.tcp.channels,
+        cfg.tcp.sample_rate,
+        cfg.tcp.gain,
+        cfg.tcp.codec,
+        cfg.tcp.sample_format,
+    ) {
+        Some(distributor) => distributor,
+        None => return Transports { threads, mdns },
+    };
+    let formats = cfg
+        .tcp
+        .negotiate
+        .then(|| FormatChains::new(distributor.clone(), "tcp", &cfg.tcp));
+    threads.push(tokio::spawn(async move {
         start_server(
-            listen_port,
-            max_clients,
-            notify_data_ready,
-            atomic_pkt_buf,
+            cfg_cp,
+            tcp_distributor,
+            formats,
+            tcp_clients,
             tokio::signal::ctrl_c(),
         )
         .await;
+    }));
+    Transports { threads, mdns }
+}
+
+#[cfg(any(feature = "opus", feature = "aac"))]
+fn start_hls(cfg: Arc<Config>, output: impl Fn(Option<usize>) -> Arc<Distributor>, n_ch: usize) {
+    use mic2net::sink::hls::{start_hls_sink, HLS_SAMPLE_RATE};
+    let distributor = output(Some(HLS_SAMPLE_RATE));
+    tokio::spawn(async move {
+        start_hls_sink(cfg, distributor, n_ch, tokio::signal::ctrl_c()).await;
     });
+}
 
-    let cfg_cp = cfg.clone();
-    start_jack_client(
-        cfg_cp,
-        client,
-        notify_dump_data,
-        ringbuf_writer,
-        tokio::signal::ctrl_c(),
-    )
-    .await;
+#[cfg(not(any(feature = "opus", feature = "aac")))]
+fn start_hls(_cfg: Arc<Config>, _output: impl Fn(Option<usize>) -> Arc<Distributor>, _n_ch: usize) {
+    error!("hls output needs a build with --features opus or aac");
+}
+
+#[cfg(feature = "kafka")]
+fn kafka_sink(cfg: &Config) -> Option<Box<dyn AudioSink>> {
+    use mic2net::sink::kafka::KafkaSink;
+    match KafkaSink::new(&cfg.kafka) {
+        Ok(sink) => Some(Box::new(sink)),
+        Err(err) => {
+            error!("failed to start the kafka producer: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "kafka"))]
+fn kafka_sink(_cfg: &Config) -> Option<Box<dyn AudioSink>> {
+    error!("kafka output needs a build with --features kafka");
+    None
+}
 
-    tcp_thread.await.unwrap();
-    sleep(Duration::from_secs(1)).await;
-    jack_server.kill().await.unwrap();
+#[cfg(feature = "grpc")]
+fn start_grpc(cfg: Arc<Config>, distributor: Arc<Distributor>) {
+    use mic2net::grpc_server::start_grpc_server;
+    tokio::spawn(async move {
+        start_grpc_server(cfg, distributor, tokio::signal::ctrl_c()).await;
+    });
+}
+
+#[cfg(not(feature = "grpc"))]
+fn start_grpc(_cfg: Arc<Config>, _distributor: Arc<Distributor>) {
+    error!("grpc output needs a build with --features grpc");
+}
+
+#[cfg(feature = "webrtc")]
+fn start_webrtc(cfg: Arc<Config>, output: impl Fn(Option<usize>) -> Arc<Distributor>, n_ch: usize) {
+    use mic2net::webrtc::{start_webrtc_server, WEBRTC_SAMPLE_RATE};
+    let distributor = output(Some(WEBRTC_SAMPLE_RATE));
+    tokio::spawn(async move {
+        start_webrtc_server(cfg, distributor, n_ch, tokio::signal::ctrl_c()).await;
+    });
+}
+
+#[cfg(not(feature = "webrtc"))]
+fn start_webrtc(
+    _cfg: Arc<Config>,
+    _output: impl Fn(Option<usize>) -> Arc<Distributor>,
+    _n_ch: usize,
+) {
+    error!("webrtc output needs a build with --features webrtc");
+}
+
+struct Transports {
+    threads: Vec<JoinHandle<()>>,
+    mdns: Option<ServiceDaemon>,
+}
+
+impl Transports {
+    // Wait for the servers and sinks to wind down after the shutdown signal.
+    async fn stop(self) {
+        for thread in self.threads {
+            thread.await.unwrap();
+        }
+        if let Some(daemon) = self.mdns {
+            let _ = daemon.shutdown();
+        }
+    }
 }
@@ -0,0 +1,224 @@
+use crate::config_file::Config;
+use crate::http::{read_request, write_response};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Duration;
+use tracing::{error, info};
+
+// Process-wide counters; updated from the capture callback and every transport.
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    pub connections_accepted: AtomicU64,
+    pub active_clients: AtomicU64,
+    pub frames_sent: AtomicU64,
+    pub frames_dropped: AtomicU64,
+    pub auth_failures: AtomicU64,
+    pub acl_rejections: AtomicU64,
+    pub connections_throttled: AtomicU64,
+    // capture ring buffer was full and samples were lost
+    pub capture_overruns: AtomicU64,
+    // capture devices that failed and were opened again
+    pub capture_reopens: AtomicU64,
+    // capture callbacks that found no free buffer and allocated one
+    pub buffer_pool_exhausted: AtomicU64,
+    pub xruns: AtomicU64,
+    // packets withheld by the voice activity gate
+    pub vad_suppressed: AtomicU64,
+    bytes_sent: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            connections_accepted: AtomicU64::new(0),
+            active_clients: AtomicU64::new(0),
+            frames_sent: AtomicU64::new(0),
+            frames_dropped: AtomicU64::new(0),
+            auth_failures: AtomicU64::new(0),
+            acl_rejections: AtomicU64::new(0),
+            connections_throttled: AtomicU64::new(0),
+            capture_overruns: AtomicU64::new(0),
+            capture_reopens: AtomicU64::new(0),
+            buffer_pool_exhausted: AtomicU64::new(0),
+            xruns: AtomicU64::new(0),
+            vad_suppressed: AtomicU64::new(0),
+            bytes_sent: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn client_connected(&self, client: &str) {
+        Metrics::inc(&self.active_clients);
+        self.bytes_sent
+            .lock()
+            .unwrap()
+            .insert(client.to_string(), 0);
+    }
+
+    pub fn client_disconnected(&self, client: &str) {
+        self.active_clients.fetch_sub(1, Ordering::Relaxed);
+        self.bytes_sent.lock().unwrap().remove(client);
+    }
+
+    pub fn frame_sent(&self, client: &str, n_bytes: usize) {
+        Metrics::inc(&self.frames_sent);
+        if let Some(bytes) = self.bytes_sent.lock().unwrap().get_mut(client) {
+            *bytes += n_bytes as u64;
+        }
+    }
+
+    // Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "mic2net_connections_accepted_total",
+                "counter",
+                "Connections accepted by the tcp and websocket servers.",
+                &self.connections_accepted,
+            ),
+            (
+                "mic2net_active_clients",
+                "gauge",
+                "Clients currently receiving the stream.",
+                &self.active_clients,
+            ),
+            (
+                "mic2net_frames_sent_total",
+                "counter",
+                "Frames written to clients.",
+                &self.frames_sent,
+            ),
+            (
+                "mic2net_frames_dropped_total",
+                "counter",
+                "Frames skipped because a client queue was full.",
+                &self.frames_dropped,
+            ),
+            (
+                "mic2net_auth_failures_total",
+                "counter",
+                "Connections closed for failing authentication.",
+                &self.auth_failures,
+            ),
+            (
+                "mic2net_acl_rejections_total",
+                "counter",
+                "Connections refused by the access control rules.",
+                &self.acl_rejections,
+            ),
+            (
+                "mic2net_connections_throttled_total",
+                "counter",
+                "Connections refused by the per address rate limit.",
+                &self.connections_throttled,
+            ),
+            (
+                "mic2net_capture_overruns_total",
+                "counter",
+                "Capture periods lost because the ring buffer was full.",
+                &self.capture_overruns,
+            ),
+            (
+                "mic2net_capture_reopens_total",
+                "counter",
+                "Times the capture devices were reopened after one of them failed.",
+                &self.capture_reopens,
+            ),
+            (
+                "mic2net_buffer_pool_exhausted_total",
+                "counter",
+                "Capture periods that had to allocate because the buffer pool was empty.",
+                &self.buffer_pool_exhausted,
+            ),
+            ("mic2net_xruns_total", "counter", "JACK xruns.", &self.xruns),
+            (
+                "mic2net_vad_suppressed_total",
+                "counter",
+                "Packets not sent because the voice activity gate was closed.",
+                &self.vad_suppressed,
+            ),
+        ];
+        for (name, kind, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP mic2net_client_bytes_sent_total Bytes written per client."
+        );
+        let _ = writeln!(out, "# TYPE mic2net_client_bytes_sent_total counter");
+        for (client, bytes) in self.bytes_sent.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "mic2net_client_bytes_sent_total{{client=\"{}\"}} {}",
+                client, bytes
+            );
+        }
+        out
+    }
+}
+
+async fn serve_request(mut socket: TcpStream) -> crate::Result<()> {
+    let request = match read_request(&mut socket).await? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    if request.method == "GET" && request.path == "/metrics" {
+        let body = METRICS.render();
+        write_response(
+            &mut socket,
+            "200 OK",
+            "text/plain; version=0.0.4",
+            body.as_bytes(),
+        )
+        .await
+    } else {
+        write_response(&mut socket, "404 Not Found", "text/plain", b"not found\n").await
+    }
+}
+
+// Serve '/metrics' over http; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_metrics_server(cfg: Arc<Config>, shutdown: impl Future) {
+    let addr = format!("{}:{}", cfg.metrics.bind_address, cfg.metrics.listen_port);
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("failed to start metrics endpoint: {}", err);
+            return;
+        }
+    };
+    info!("metrics on http://{}/metrics", addr);
+
+    let accept_loop = async {
+        loop {
+            match listener.accept().await {
+                Ok((socket, _)) => {
+                    tokio::spawn(async move {
+                        if let Err(err) = serve_request(socket).await {
+                            error!("metrics request failed: {}", err);
+                        }
+                    });
+                }
+                Err(err) => {
+                    error!("metrics accept failed: {}", err);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    };
+    tokio::select! {
+        _ = accept_loop => {}
+        _ = shutdown => {}
+    }
+}
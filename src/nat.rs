@@ -0,0 +1,774 @@
+// NAT traversal for the udp transport when both the server and its clients sit behind
+// NAT. STUN binding requests (RFC 5389) tell a socket the public address its NAT maps
+// it to. Peers register that address with a rendezvous server ('mic2net rendezvous')
+// under a session name, and the rendezvous hands each one the addresses of the other
+// side. Both then send datagrams to each other until their NATs let the other's through.
+// A registration only counts once it carries a cookie the rendezvous handed to its
+// source address, so spoofed ones neither take up the table nor get anything but a
+// cookie, never longer than what they sent, back.
+// This works behind any NAT that maps one socket to one public port for every
+// destination, i.e. not behind a symmetric one. Asking two STUN servers tells them apart.
+use crate::config_file::NatConfig;
+use crate::udp_server::{Cookies, COOKIE_LEN};
+use ring::rand::{generate, SystemRandom};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+pub const RENDEZVOUS_PORT: u16 = 3479;
+// how often peers register again, which also keeps their mapping to the rendezvous open
+pub const REGISTER_INTERVAL: Duration = Duration::from_secs(5);
+// how long the rendezvous keeps a peer that stopped registering
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+// peers over all sessions, so a flood of registrations can't grow the table without bound
+const MAX_PEERS: usize = 1024;
+// addresses per answer, at most 47 bytes each, so answers fit 'MAX_MESSAGE_LEN'
+const MAX_ADDRS: usize = 16;
+const MAX_SESSION_LEN: usize = 64;
+pub const MAX_MESSAGE_LEN: usize = 1024;
+const PROTOCOL: &str = "mic2net-rendezvous/1";
+
+const STUN_MAGIC: u32 = 0x2112_a442;
+const STUN_PORT: u16 = 3478;
+const STUN_HEADER_LEN: usize = 20;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const STUN_ATTEMPTS: u32 = 3;
+const STUN_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+impl Role {
+    fn name(self) -> &'static str {
+        match self {
+            Role::Server => "server",
+            Role::Client => "client",
+        }
+    }
+}
+
+// What peers and the rendezvous say to each other, one line of text per datagram:
+// "mic2net-rendezvous/1 register <session> <role> <cookie> [<addr>...]" with the
+// addresses the peer knows itself by, answered by "mic2net-rendezvous/1 peers <session>
+// [<addr>...]" with those of the other side. Until it has one, a peer sends a cookie of
+// zeros and is answered by "mic2net-rendezvous/1 cookie <session> <cookie>" instead,
+// which is always shorter. Cookies are hex.
+#[derive(PartialEq, Debug)]
+enum Message {
+    Register {
+        session: String,
+        role: Role,
+        cookie: [u8; COOKIE_LEN],
+        addrs: Vec<SocketAddr>,
+    },
+    Peers {
+        session: String,
+        addrs: Vec<SocketAddr>,
+    },
+    Cookie {
+        session: String,
+        cookie: [u8; COOKIE_LEN],
+    },
+}
+
+impl Message {
+    fn encode(&self) -> String {
+        let (mut line, addrs) = match self {
+            Message::Register {
+                session,
+                role,
+                cookie,
+                addrs,
+            } => (
+                format!(
+                    "{} register {} {} {}",
+                    PROTOCOL,
+                    session,
+                    role.name(),
+                    hex(cookie)
+                ),
+                &addrs[..],
+            ),
+            Message::Peers { session, addrs } => {
+                (format!("{} peers {}", PROTOCOL, session), &addrs[..])
+            }
+            Message::Cookie { session, cookie } => (
+                format!("{} cookie {} {}", PROTOCOL, session, hex(cookie)),
+                &[][..],
+            ),
+        };
+        for addr in addrs {
+            line.push(' ');
+            line.push_str(&addr.to_string());
+        }
+        line
+    }
+
+    fn parse(datagram: &[u8]) -> Option<Message> {
+        let mut words = std::str::from_utf8(datagram).ok()?.split_whitespace();
+        if words.next()? != PROTOCOL {
+            return None;
+        }
+        let kind = words.next()?;
+        let session = words
+            .next()
+            .filter(|session| session.len() <= MAX_SESSION_LEN)?;
+        let session = session.to_string();
+        let message = match kind {
+            "register" => {
+                let role = match words.next()? {
+                    "server" => Role::Server,
+                    "client" => Role::Client,
+                    _ => return None,
+                };
+                let cookie = parse_hex(words.next()?)?;
+                let addrs = words.map(str::parse).collect::<Result<_, _>>().ok()?;
+                Message::Register {
+                    session,
+                    role,
+                    cookie,
+                    addrs,
+                }
+            }
+            "peers" => {
+                let addrs = words.map(str::parse).collect::<Result<_, _>>().ok()?;
+                Message::Peers { session, addrs }
+            }
+            "cookie" => {
+                let cookie = parse_hex(words.next()?)?;
+                if words.next().is_some() {
+                    return None;
+                }
+                Message::Cookie { session, cookie }
+            }
+            _ => return None,
+        };
+        Some(message)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(word: &str) -> Option<[u8; COOKIE_LEN]> {
+    if word.len() != 2 * COOKIE_LEN {
+        return None;
+    }
+    let mut cookie = [0_u8; COOKIE_LEN];
+    for (i, byte) in cookie.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(word.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(cookie)
+}
+
+// A peer's side of the rendezvous: the addresses STUN found for its socket, registered
+// with the rendezvous every 'REGISTER_INTERVAL'.
+pub struct Rendezvous {
+    pub server: SocketAddr,
+    session: String,
+    role: Role,
+    // the last the rendezvous handed out, zeros before the first
+    cookie: [u8; COOKIE_LEN],
+    addrs: Vec<SocketAddr>,
+}
+
+impl Rendezvous {
+    // Resolve the rendezvous and ask the STUN servers for the public address of 'socket';
+    // must be called before anything else reads from it.
+    pub async fn new(cfg: &NatConfig, socket: &UdpSocket, role: Role) -> crate::Result<Rendezvous> {
+        if cfg.session.is_empty()
+            || cfg.session.len() > MAX_SESSION_LEN
+            || cfg.session.contains(char::is_whitespace)
+        {
+            return Err(format!(
+                "nat session {:?} isn't 1 to 64 characters without spaces",
+                cfg.session
+            )
+            .into());
+        }
+        let server = resolve(&cfg.rendezvous, RENDEZVOUS_PORT, socket).await?;
+        let addrs = discover(socket, &cfg.stun_servers)
+            .await
+            .into_iter()
+            .collect();
+        Ok(Rendezvous {
+            server,
+            session: cfg.session.clone(),
+            role,
+            cookie: [0; COOKIE_LEN],
+            addrs,
+        })
+    }
+
+    pub async fn register(&self, socket: &UdpSocket) {
+        let message = Message::Register {
+            session: self.session.clone(),
+            role: self.role,
+            cookie: self.cookie,
+            addrs: self.addrs.clone(),
+        };
+        if let Err(err) = socket
+            .send_to(message.encode().as_bytes(), self.server)
+            .await
+        {
+            warn!("can't register with rendezvous {}: {}", self.server, err);
+        }
+    }
+
+    // The addresses of the other side, if 'datagram' is the rendezvous' answer. A cookie
+    // from the rendezvous is kept for later registrations and sent back right away.
+    pub async fn peers(
+        &mut self,
+        socket: &UdpSocket,
+        datagram: &[u8],
+        from: SocketAddr,
+    ) -> Option<Vec<SocketAddr>> {
+        if from != self.server {
+            return None;
+        }
+        match Message::parse(datagram)? {
+            Message::Peers { session, addrs } if session == self.session => Some(addrs),
+            Message::Cookie { session, cookie } if session == self.session => {
+                self.cookie = cookie;
+                self.register(socket).await;
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+// The public address 'socket' is seen from, asking 'servers' (host:port) in turn; None
+// if none answers. Warns when two of them see different ones: the NAT is symmetric and
+// holes punched for the rendezvous are no use to peers.
+pub async fn discover(socket: &UdpSocket, servers: &[String]) -> Option<SocketAddr> {
+    let mut public: Option<SocketAddr> = None;
+    for server in servers {
+        let mapped = match resolve(server, STUN_PORT, socket).await {
+            Ok(addr) => binding(socket, addr).await,
+            Err(err) => Err(err),
+        };
+        match mapped {
+            Ok(mapped) => match public {
+                None => {
+                    info!("public udp address {}, from {}", mapped, server);
+                    public = Some(mapped);
+                }
+                Some(first) if first != mapped => {
+                    warn!(
+                        "{} sees {} instead of {}: symmetric nat, hole punching will likely fail",
+                        server, mapped, first
+                    );
+                }
+                Some(_) => {}
+            },
+            Err(err) => warn!("stun server {} failed: {}", server, err),
+        }
+    }
+    public
+}
+
+// Whether 'datagram' is a STUN message, e.g. an answer to a retransmitted request
+// arriving after 'discover' gave up waiting and the socket went on to other things.
+pub fn is_stun(datagram: &[u8]) -> bool {
+    datagram.len() >= STUN_HEADER_LEN && datagram[4..8] == STUN_MAGIC.to_be_bytes()
+}
+
+async fn binding(socket: &UdpSocket, server: SocketAddr) -> crate::Result<SocketAddr> {
+    let transaction: [u8; 12] = generate(&SystemRandom::new())
+        .map_err(|_| "no randomness for a stun transaction")?
+        .expose();
+    let mut request = Vec::with_capacity(STUN_HEADER_LEN);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0_u16.to_be_bytes());
+    request.extend_from_slice(&STUN_MAGIC.to_be_bytes());
+    request.extend_from_slice(&transaction);
+
+    let mut buf = [0_u8; 512];
+    for _ in 0..STUN_ATTEMPTS {
+        socket.send_to(&request, server).await?;
+        let deadline = Instant::now() + STUN_TIMEOUT;
+        while let Ok(res) = time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (n_bytes, from) = res?;
+            if from != server {
+                continue;
+            }
+            if let Some(mapped) = parse_binding_response(&buf[..n_bytes], &transaction) {
+                return Ok(mapped);
+            }
+        }
+    }
+    Err("no answer".into())
+}
+
+fn parse_binding_response(msg: &[u8], transaction: &[u8; 12]) -> Option<SocketAddr> {
+    if msg.len() < STUN_HEADER_LEN
+        || u16::from_be_bytes([msg[0], msg[1]]) != BINDING_RESPONSE
+        || !is_stun(msg)
+        || msg[8..20] != transaction[..]
+    {
+        return None;
+    }
+    let mut attrs = &msg[STUN_HEADER_LEN..];
+    let mut mapped = None;
+    while attrs.len() >= 4 {
+        let kind = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let len = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+        let value = attrs.get(4..4 + len)?;
+        match kind {
+            // preferred: some NATs rewrite addresses they find in payloads
+            XOR_MAPPED_ADDRESS => return parse_address(value, Some(transaction)),
+            MAPPED_ADDRESS => mapped = parse_address(value, None),
+            _ => {}
+        }
+        // attributes are padded to 4 bytes
+        attrs = attrs.get(4 + len.next_multiple_of(4)..).unwrap_or_default();
+    }
+    mapped
+}
+
+fn parse_address(value: &[u8], xor: Option<&[u8; 12]>) -> Option<SocketAddr> {
+    let mut mask = [0_u8; 16];
+    if let Some(transaction) = xor {
+        mask[..4].copy_from_slice(&STUN_MAGIC.to_be_bytes());
+        mask[4..].copy_from_slice(transaction);
+    }
+    let port = u16::from_be_bytes([value.get(2)? ^ mask[0], value.get(3)? ^ mask[1]]);
+    let ip = match value[1] {
+        1 => {
+            let mut ip = [0_u8; 4];
+            for (i, byte) in ip.iter_mut().enumerate() {
+                *byte = value.get(4 + i)? ^ mask[i];
+            }
+            IpAddr::V4(Ipv4Addr::from(ip))
+        }
+        2 => {
+            let mut ip = [0_u8; 16];
+            for (i, byte) in ip.iter_mut().enumerate() {
+                *byte = value.get(4 + i)? ^ mask[i];
+            }
+            IpAddr::V6(Ipv6Addr::from(ip))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+// host:port, or host alone for 'default_port', resolved to the family 'socket' is bound to.
+async fn resolve(addr: &str, default_port: u16, socket: &UdpSocket) -> crate::Result<SocketAddr> {
+    let v4 = socket.local_addr()?.is_ipv4();
+    let addrs: Vec<SocketAddr> = match lookup_host(addr).await {
+        Ok(addrs) => addrs.collect(),
+        Err(_) => lookup_host((addr, default_port)).await?.collect(),
+    };
+    addrs
+        .into_iter()
+        .find(|addr| addr.is_ipv4() == v4)
+        .ok_or_else(|| {
+            format!(
+                "{} has no {} address",
+                addr,
+                if v4 { "ipv4" } else { "ipv6" }
+            )
+            .into()
+        })
+}
+
+struct Registration {
+    session: String,
+    role: Role,
+    // where its datagrams came from first, then what STUN told it
+    addrs: Vec<SocketAddr>,
+    last_seen: Instant,
+}
+
+// Introduces the server and clients of a session to each other. Answers every
+// registration with the addresses of the other side, and passes a client's addresses on
+// to the session's servers whenever it registers, so they punch towards it right away.
+// A registration without a valid cookie for its source is answered with one and
+// otherwise ignored.
+pub struct RendezvousServer {
+    socket: UdpSocket,
+    peers: HashMap<SocketAddr, Registration>,
+    cookies: Cookies,
+}
+
+impl RendezvousServer {
+    pub async fn new(bind_address: &str, port: u16) -> crate::Result<RendezvousServer> {
+        let socket = UdpSocket::bind((bind_address, port))
+            .await
+            .map_err(crate::Error::bind(format!("{}:{}", bind_address, port)))?;
+        Ok(RendezvousServer {
+            socket,
+            peers: HashMap::new(),
+            cookies: Cookies::new()?,
+        })
+    }
+
+    async fn run(&mut self) -> crate::Result<()> {
+        info!("rendezvous on {}", self.socket.local_addr()?);
+        let mut buf = [0_u8; MAX_MESSAGE_LEN];
+        loop {
+            let (n_bytes, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(res) => res,
+                Err(err) => {
+                    warn!("rendezvous receive error: {}", err);
+                    continue;
+                }
+            };
+            self.received(&buf[..n_bytes], from).await;
+        }
+    }
+
+    async fn received(&mut self, datagram: &[u8], from: SocketAddr) {
+        let Some(Message::Register {
+            session,
+            role,
+            cookie,
+            addrs,
+        }) = Message::parse(datagram)
+        else {
+            debug!(peer = %from, "not a rendezvous registration");
+            return;
+        };
+        if !self.cookies.valid(&cookie, from) {
+            self.send_cookie(from, session, datagram.len()).await;
+            return;
+        }
+        self.register(from, session, role, addrs);
+        self.introduce(from).await;
+    }
+
+    // Hand an unverified address its cookie, in no more bytes than it sent.
+    async fn send_cookie(&self, to: SocketAddr, session: String, request_len: usize) {
+        let message = Message::Cookie {
+            session,
+            cookie: self.cookies.issue(to),
+        };
+        let message = message.encode();
+        if message.len() > request_len {
+            return;
+        }
+        if let Err(err) = self.socket.send_to(message.as_bytes(), to).await {
+            warn!(peer = %to, "failed to send cookie: {}", err);
+        }
+    }
+
+    fn register(
+        &mut self,
+        from: SocketAddr,
+        session: String,
+        role: Role,
+        reported: Vec<SocketAddr>,
+    ) {
+        let now = Instant::now();
+        self.peers
+            .retain(|_, peer| now.duration_since(peer.last_seen) < PEER_TIMEOUT);
+        if !self.peers.contains_key(&from) {
+            if self.peers.len() >= MAX_PEERS {
+                warn!(peer = %from, "rendezvous full, registration ignored");
+                return;
+            }
+            info!(peer = %from, "{} registered for session {}", role.name(), session);
+        }
+        let mut addrs = vec![from];
+        addrs.extend(reported.into_iter().filter(|addr| *addr != from).take(3));
+        self.peers.insert(
+            from,
+            Registration {
+                session,
+                role,
+                addrs,
+                last_seen: now,
+            },
+        );
+    }
+
+    async fn introduce(&self, from: SocketAddr) {
+        let Some(peer) = self.peers.get(&from) else {
+            return;
+        };
+        let other = match peer.role {
+            Role::Server => Role::Client,
+            Role::Client => Role::Server,
+        };
+        let addrs = self
+            .peers
+            .values()
+            .filter(|other_peer| other_peer.role == other && other_peer.session == peer.session)
+            .flat_map(|other_peer| other_peer.addrs.iter().copied())
+            .take(MAX_ADDRS)
+            .collect();
+        self.send(from, &peer.session, addrs).await;
+        if peer.role == Role::Client {
+            for (addr, server) in &self.peers {
+                if server.role == Role::Server && server.session == peer.session {
+                    self.send(*addr, &peer.session, peer.addrs.clone()).await;
+                }
+            }
+        }
+    }
+
+    async fn send(&self, to: SocketAddr, session: &str, addrs: Vec<SocketAddr>) {
+        let message = Message::Peers {
+            session: session.to_string(),
+            addrs,
+        };
+        if let Err(err) = self.socket.send_to(message.encode().as_bytes(), to).await {
+            warn!(peer = %to, "failed to answer registration: {}", err);
+        }
+    }
+}
+
+// Run rendezvous server; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_rendezvous_server(bind_address: &str, port: u16, shutdown: impl Future) {
+    let mut server = match RendezvousServer::new(bind_address, port).await {
+        Ok(server) => server,
+        Err(err) => {
+            error!("failed to start rendezvous server: {}", err);
+            return;
+        }
+    };
+    tokio::select! {
+        res = server.run() => {
+            if let Err(err) = res {
+                error!("rendezvous server stopped: {}", err);
+            }
+        }
+        _ = shutdown => {
+            info!("stopping rendezvous server");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The sample responses of RFC 5769, software, integrity and fingerprint included.
+    const TRANSACTION: [u8; 12] = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+
+    // A binding response header, for 'len' bytes of attributes.
+    fn header(len: u8) -> Vec<u8> {
+        [
+            &[0x01, 0x01, 0x00, len, 0x21, 0x12, 0xa4, 0x42][..],
+            &TRANSACTION,
+        ]
+        .concat()
+    }
+
+    const SOFTWARE: [u8; 16] = [
+        0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76, 0x65, 0x63, 0x74, 0x6f, 0x72,
+        0x20,
+    ];
+
+    fn v4_response() -> Vec<u8> {
+        [
+            &header(0x3c)[..],
+            &SOFTWARE,
+            // xor-mapped-address
+            &[
+                0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+            ],
+            // message-integrity
+            &[
+                0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3, 0x8c, 0x74,
+                0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7,
+            ],
+            // fingerprint
+            &[0x80, 0x28, 0x00, 0x04, 0xc0, 0x7d, 0x4c, 0x96],
+        ]
+        .concat()
+    }
+
+    fn v6_response() -> Vec<u8> {
+        [
+            &header(0x48)[..],
+            &SOFTWARE,
+            &[
+                0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa, 0xa5, 0xd3,
+                0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+            ],
+            &[
+                0x00, 0x08, 0x00, 0x14, 0xa3, 0x82, 0x95, 0x4e, 0x4b, 0xe6, 0x7b, 0xf1, 0x17, 0x84,
+                0xc9, 0x7c, 0x82, 0x92, 0xc2, 0x75, 0xbf, 0xe3, 0xed, 0x41,
+            ],
+            &[0x80, 0x28, 0x00, 0x04, 0xc8, 0xfb, 0x0b, 0x4c],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn rfc_5769_responses_parse() {
+        let mapped = parse_binding_response(&v4_response(), &TRANSACTION);
+        assert_eq!(mapped, Some("192.0.2.1:32853".parse().unwrap()));
+        let mapped = parse_binding_response(&v6_response(), &TRANSACTION);
+        let expected = "[2001:db8:1234:5678:11:2233:4455:6677]:32853";
+        assert_eq!(mapped, Some(expected.parse().unwrap()));
+        assert!(is_stun(&v4_response()));
+    }
+
+    #[test]
+    fn plain_mapped_address_is_the_fallback() {
+        let mut response = header(0x0c);
+        response.extend_from_slice(&[0x00, 0x01, 0x00, 0x08, 0x00, 0x01, 0x80, 0x55]);
+        response.extend_from_slice(&[192, 0, 2, 1]);
+        let mapped = parse_binding_response(&response, &TRANSACTION);
+        assert_eq!(mapped, Some("192.0.2.1:32853".parse().unwrap()));
+    }
+
+    #[test]
+    fn other_messages_are_ignored() {
+        let response = v4_response();
+        let mut other = TRANSACTION;
+        other[0] ^= 1;
+        assert_eq!(parse_binding_response(&response, &other), None);
+        // a request, not a response
+        let mut request = response.clone();
+        request[..2].copy_from_slice(&[0x00, 0x01]);
+        assert_eq!(parse_binding_response(&request, &TRANSACTION), None);
+        let mut no_magic = response.clone();
+        no_magic[4] ^= 1;
+        assert!(!is_stun(&no_magic));
+        assert_eq!(parse_binding_response(&no_magic, &TRANSACTION), None);
+    }
+
+    #[test]
+    fn truncated_responses_fail() {
+        let response = v4_response();
+        // cut inside the software attribute or the address
+        for len in [0, 19, 24, 35, 40, 44] {
+            assert_eq!(
+                parse_binding_response(&response[..len], &TRANSACTION),
+                None,
+                "{}",
+                len
+            );
+        }
+        // an address of an unknown family
+        let mut response = v4_response();
+        response[41] = 3;
+        assert_eq!(parse_binding_response(&response, &TRANSACTION), None);
+    }
+
+    #[test]
+    fn messages_roundtrip() {
+        let register = Message::Register {
+            session: "lab".into(),
+            role: Role::Server,
+            cookie: [0xa5; COOKIE_LEN],
+            addrs: vec![
+                "203.0.113.5:4000".parse().unwrap(),
+                "[2001:db8::1]:4000".parse().unwrap(),
+            ],
+        };
+        let peers = Message::Peers {
+            session: "lab".into(),
+            addrs: Vec::new(),
+        };
+        let cookie = Message::Cookie {
+            session: "lab".into(),
+            cookie: [7; COOKIE_LEN],
+        };
+        for message in [register, peers, cookie] {
+            assert_eq!(Message::parse(message.encode().as_bytes()), Some(message));
+        }
+    }
+
+    #[test]
+    fn bad_messages_are_ignored() {
+        let long_session = format!("{} peers {}", PROTOCOL, "s".repeat(MAX_SESSION_LEN + 1));
+        for text in [
+            "mic2net-rendezvous/0 peers lab",
+            "mic2net-rendezvous/1 hello lab",
+            "mic2net-rendezvous/1 register lab relay",
+            "mic2net-rendezvous/1 peers lab 203.0.113.5",
+            "mic2net-rendezvous/1 register lab server",
+            "mic2net-rendezvous/1 register lab server 00ff",
+            "mic2net-rendezvous/1 cookie lab 0123456789abcdef0123456789abcdeg",
+            &long_session,
+        ] {
+            assert_eq!(Message::parse(text.as_bytes()), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn cookies_are_shorter_than_registrations() {
+        for session in ["s".to_string(), "s".repeat(MAX_SESSION_LEN)] {
+            let register = Message::Register {
+                session: session.clone(),
+                role: Role::Client,
+                cookie: [0; COOKIE_LEN],
+                addrs: Vec::new(),
+            };
+            let cookie = Message::Cookie {
+                session,
+                cookie: [0xff; COOKIE_LEN],
+            };
+            assert!(cookie.encode().len() < register.encode().len());
+        }
+    }
+
+    fn register(session: &str, role: Role, cookie: [u8; COOKIE_LEN]) -> Vec<u8> {
+        let message = Message::Register {
+            session: session.into(),
+            role,
+            cookie,
+            addrs: Vec::new(),
+        };
+        message.encode().into_bytes()
+    }
+
+    async fn answer(socket: &UdpSocket) -> Option<Message> {
+        let mut buf = [0_u8; MAX_MESSAGE_LEN];
+        let (n_bytes, _) = time::timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+            .await
+            .ok()?
+            .unwrap();
+        Message::parse(&buf[..n_bytes])
+    }
+
+    #[tokio::test]
+    async fn registrations_count_once_the_cookie_comes_back() {
+        let mut server = RendezvousServer::new("127.0.0.1", 0).await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let from = client.local_addr().unwrap();
+
+        let request = register("lab", Role::Client, [0; COOKIE_LEN]);
+        server.received(&request, from).await;
+        assert!(server.peers.is_empty());
+        let Some(Message::Cookie { session, cookie }) = answer(&client).await else {
+            panic!("expected a cookie");
+        };
+        assert_eq!(session, "lab");
+
+        // a cookie for another address doesn't do
+        let other: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        server
+            .received(&register("lab", Role::Server, cookie), other)
+            .await;
+        assert!(server.peers.is_empty());
+
+        server
+            .received(&register("lab", Role::Client, cookie), from)
+            .await;
+        assert!(server.peers.contains_key(&from));
+        let peers = Message::Peers {
+            session: "lab".into(),
+            addrs: Vec::new(),
+        };
+        assert_eq!(answer(&client).await, Some(peers));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::audio::vad::{Vad, VAD};
+use crate::dsp::meter::Levels;
+use crate::dsp::Chain;
+use crate::metrics::{Metrics, METRICS};
+use crate::protocol::Timestamp;
+use crate::PACKET_N_SAMPLE;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::time::Duration;
+use tokio::sync::watch;
+
+// Packets split off one allocation before the next is made.
+const PACKETS_PER_BUF: usize = 32;
+
+// Builds the packets handed to the transports: device id (u16), capture time as unix
+// seconds (u32) and milliseconds (u16), packet id (u32), then 'PACKET_N_SAMPLE' i16
+// samples per channel, one channel after another.
+pub struct Packetizer {
+    device_id: u16,
+    pkt_id: u32,
+    pkt_len: usize,
+    // how long a packet takes to capture; it is published as its last sample arrives
+    pkt_duration: Duration,
+    // packets are written here and split off as 'Bytes', which every reader shares
+    buf: BytesMut,
+    packets: watch::Sender<(Timestamp, Bytes)>,
+    levels: watch::Sender<Levels>,
+    vad: Option<Vad>,
+    dsp: Chain,
+    dsp_buf: Vec<i16>,
+    silence_frames: bool,
+}
+
+impl Packetizer {
+    pub fn new(device_id: u16, pkt_len: usize, sample_rate: usize) -> Packetizer {
+        Packetizer {
+            device_id,
+            pkt_id: 0,
+            pkt_len,
+            pkt_duration: Duration::from_secs_f64(PACKET_N_SAMPLE as f64 / sample_rate as f64),
+            buf: BytesMut::with_capacity(pkt_len * PACKETS_PER_BUF),
+            packets: watch::channel((Timestamp::default(), Bytes::new())).0,
+            levels: watch::channel(Levels::default()).0,
+            vad: None,
+            dsp: Chain::default(),
+            dsp_buf: Vec::new(),
+            silence_frames: false,
+        }
+    }
+
+    // Withhold packets while 'vad' reports silence and 'VAD' is enabled; their packet
+    // ids are skipped.
+    pub fn set_vad(&mut self, vad: Vad) {
+        self.vad = Some(vad);
+    }
+
+    // Run 'dsp' on the samples of every packet that gets sent.
+    pub fn set_dsp(&mut self, dsp: Chain) {
+        self.dsp = dsp;
+    }
+
+    // The latest packet and when its first sample was captured; a reader that falls
+    // behind skips to it.
+    pub fn packets(&self) -> watch::Receiver<(Timestamp, Bytes)> {
+        self.packets.subscribe()
+    }
+
+    // Levels of the latest packet as sent, or as captured while 'vad' withholds packets.
+    pub fn levels(&self) -> watch::Receiver<Levels> {
+        self.levels.subscribe()
+    }
+
+    // Send silent packets as the header alone, which 'Distributor::run' turns into
+    // silence frames, instead of withholding them (vad) or sending zeros.
+    pub fn set_silence_frames(&mut self, enable: bool) {
+        self.silence_frames = enable;
+    }
+
+    // Wrap one packet worth of audio and hand it to the 'packets' readers.
+    pub fn publish(&mut self, audio_data: &[u8]) {
+        self.load_samples(audio_data);
+        let mut silent = false;
+        if let Some(vad) = self.vad.as_mut().filter(|_| VAD.enabled()) {
+            if !vad.is_active(audio_data) {
+                Metrics::inc(&METRICS.vad_suppressed);
+                self.levels.send_replace(Levels::measure(&self.dsp_buf));
+                if !self.silence_frames {
+                    self.next_pkt_id();
+                    return;
+                }
+                silent = true;
+            }
+        }
+
+        // reuses the allocation once all packets split off it are dropped
+        if self.buf.capacity() < self.pkt_len {
+            self.buf.reserve(self.pkt_len * PACKETS_PER_BUF);
+        }
+        let captured = Timestamp::now().before(self.pkt_duration);
+        let secs = (captured.wall_us / 1_000_000) as u32;
+        let millis = (captured.wall_us / 1000 % 1000) as u16;
+        self.buf.put_u16(self.device_id);
+        self.buf.put_u32(secs);
+        self.buf.put_u16(millis);
+        self.buf.put_u32(self.pkt_id);
+        if !silent {
+            self.dsp.process(&mut self.dsp_buf);
+            self.levels.send_replace(Levels::measure(&self.dsp_buf));
+            silent = self.silence_frames && self.dsp_buf.iter().all(|&sample| sample == 0);
+        }
+        if !silent {
+            if self.dsp.is_empty() {
+                self.buf.extend_from_slice(audio_data);
+            } else {
+                for sample in self.dsp_buf.iter() {
+                    self.buf.extend_from_slice(&sample.to_ne_bytes());
+                }
+            }
+        }
+
+        self.packets
+            .send_replace((captured, self.buf.split().freeze()));
+        self.next_pkt_id();
+    }
+
+    fn load_samples(&mut self, audio_data: &[u8]) {
+        self.dsp_buf.clear();
+        self.dsp_buf.extend(
+            audio_data
+                .chunks_exact(2)
+                .map(|b| i16::from_ne_bytes([b[0], b[1]])),
+        );
+    }
+
+    fn next_pkt_id(&mut self) {
+        self.pkt_id += 1;
+        if self.pkt_id == u32::MAX {
+            self.pkt_id = 0;
+        }
+    }
+}
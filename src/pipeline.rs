@@ -0,0 +1,204 @@
+// The way from the capture stream to one output: a source feeds the packetizer and the
+// capture distributor, 'Pipeline' runs that through transform stages, each a
+// distributor of its own, and hands the result to the output's transports and sinks.
+use crate::audio::channels::channel_mapped;
+use crate::audio::encode::{encoded, WireCodec};
+use crate::audio::format::SampleFormat;
+use crate::audio::resample::resampled;
+use crate::audio::vad::Vad;
+use crate::config_file::Config;
+use crate::distributor::{Distributor, DropPolicy, SilenceRun};
+use crate::dsp::level::leveled;
+use crate::protocol::{Frame, FrameKind};
+use crate::sink::{AudioSink, FanOut};
+use crate::HEADER_LEN;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+const VAD_QUEUE_LEN: usize = 50;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PipelineStage {
+    // the output's 'channels' map
+    Channels,
+    // to the output's 'sample_rate'
+    Resample,
+    // the output's 'gain', adjustable at runtime through the admin api
+    Gain,
+    // silence frames in place of what the [vad] thresholds call silent, at the rate
+    // and level the output has by then
+    Vad,
+    // to the output's codec and sample format; nothing but the connections' compression
+    // comes after
+    Encode,
+}
+
+// Settings from one output's config section that the stages use.
+pub struct OutputStages<'a> {
+    pub name: &'a str,
+    pub channels: &'a Option<Vec<Vec<usize>>>,
+    pub sample_rate: Option<usize>,
+    pub gain: f32,
+    pub codec: WireCodec,
+    pub sample_format: SampleFormat,
+}
+
+pub struct Pipeline {
+    stream: Arc<Distributor>,
+    sinks: Vec<Box<dyn AudioSink>>,
+}
+
+impl Pipeline {
+    // Starting at the frames of 'source', e.g. the capture distributor.
+    pub fn new(source: &Arc<Distributor>) -> Pipeline {
+        Pipeline {
+            stream: source.clone(),
+            sinks: Vec::new(),
+        }
+    }
+
+    // The capture through the stages of 'cfg.pipeline', in their order, with the
+    // settings of one output.
+    pub fn for_output(
+        cfg: &Config,
+        source: &Arc<Distributor>,
+        output: &OutputStages,
+    ) -> crate::Result<Pipeline> {
+        let mut pipeline = Pipeline::new(source);
+        for stage in &cfg.pipeline.stages {
+            pipeline = match stage {
+                PipelineStage::Channels => match output.channels {
+                    Some(map) => pipeline.channels(map)?,
+                    None => pipeline,
+                },
+                PipelineStage::Resample => match output.sample_rate {
+                    Some(rate) => pipeline.resample(rate)?,
+                    None => pipeline,
+                },
+                PipelineStage::Gain => pipeline.gain(output.name, output.gain)?,
+                PipelineStage::Vad => pipeline.vad()?,
+                PipelineStage::Encode => pipeline.encode(output.codec, output.sample_format)?,
+            };
+        }
+        Ok(pipeline)
+    }
+
+    pub fn channels(self, map: &[Vec<usize>]) -> crate::Result<Pipeline> {
+        self.expect_pcm("channel mapping")?;
+        let stream = channel_mapped(&self.stream, map)?;
+        Ok(self.then(stream))
+    }
+
+    pub fn resample(self, sample_rate: usize) -> crate::Result<Pipeline> {
+        self.expect_pcm("resampling")?;
+        let stream = resampled(&self.stream, sample_rate);
+        Ok(self.then(stream))
+    }
+
+    // At the level of output 'name', see 'leveled'.
+    pub fn gain(self, name: &str, gain_db: f32) -> crate::Result<Pipeline> {
+        self.expect_pcm("gain")?;
+        let stream = leveled(&self.stream, name, gain_db);
+        Ok(self.then(stream))
+    }
+
+    // Gated by the settings in 'VAD', from [vad].
+    pub fn vad(self) -> crate::Result<Pipeline> {
+        self.expect_pcm("vad")?;
+        let stream = gated(
+            &self.stream,
+            Vad::new(self.stream.stream_info().sample_rate),
+        );
+        Ok(self.then(stream))
+    }
+
+    pub fn encode(self, codec: WireCodec, sample_format: SampleFormat) -> crate::Result<Pipeline> {
+        self.expect_pcm("encoding")?;
+        let stream = encoded(&self.stream, codec, sample_format)?;
+        Ok(self.then(stream))
+    }
+
+    // Feed the frames at this point to 'sink' as well, see 'start'.
+    pub fn sink(mut self, sink: Box<dyn AudioSink>) -> Pipeline {
+        self.sinks.push(sink);
+        self
+    }
+
+    // The frames at the end, for transports to subscribe to.
+    pub fn stream(&self) -> Arc<Distributor> {
+        self.stream.clone()
+    }
+
+    // Start the sinks, each stopping when its copy of 'shutdown' completes; their tasks
+    // to wait for, and the frames at the end.
+    pub fn start<F>(self, shutdown: impl Fn() -> F) -> (Arc<Distributor>, Vec<JoinHandle<()>>)
+    where
+        F: Future + Send + 'static,
+    {
+        let mut fan_out = FanOut::new(self.stream.clone());
+        for sink in self.sinks {
+            fan_out.add(sink, shutdown());
+        }
+        (self.stream, fan_out.into_tasks())
+    }
+
+    fn then(self, stream: Arc<Distributor>) -> Pipeline {
+        Pipeline { stream, ..self }
+    }
+
+    fn expect_pcm(&self, stage: &str) -> crate::Result<()> {
+        let stream = self.stream.stream_info();
+        if stream.codec != WireCodec::Pcm || stream.sample_format != SampleFormat::I16 {
+            return Err(format!("{} needs 16-bit pcm, put it before encode", stage).into());
+        }
+        Ok(())
+    }
+}
+
+// Distributor carrying the frames of 'input' with silence frames where 'vad' finds
+// none; frames get their own sequence numbers, since silent packets are merged.
+fn gated(input: &Arc<Distributor>, mut vad: Vad) -> Arc<Distributor> {
+    let stream = input.stream_info();
+    let sample_rate = stream.sample_rate as u64;
+    let output = Distributor::new(stream, input.history_len());
+    let mut frames = input.subscribe(VAD_QUEUE_LEN, DropPolicy::DropNewest);
+
+    let output_cp = output.clone();
+    tokio::spawn(async move {
+        let mut seq = 0_u32;
+        let mut silence = SilenceRun::default();
+        while let Some(frame) = frames.recv().await {
+            let silent = match frame.kind {
+                FrameKind::Audio => {
+                    !vad.is_active(frame.payload.get(HEADER_LEN..).unwrap_or_default())
+                }
+                _ => false,
+            };
+            let silence_frame = if silent {
+                silence.push(frame.timestamp, sample_rate)
+            } else {
+                silence.finish(sample_rate)
+            };
+            if let Some((start, ms)) = silence_frame {
+                output_cp.publish(Frame::silence(seq, start, ms)).await;
+                seq = seq.wrapping_add(1);
+            }
+            if !silent {
+                let frame = match frame.kind {
+                    FrameKind::Audio => Frame::audio(seq, frame.timestamp, frame.payload),
+                    FrameKind::Silence => match frame.silence_ms() {
+                        Ok(ms) => Frame::silence(seq, frame.timestamp, ms),
+                        Err(_) => continue,
+                    },
+                    _ => frame,
+                };
+                output_cp.publish(frame).await;
+                seq = seq.wrapping_add(1);
+            }
+        }
+    });
+    output
+}
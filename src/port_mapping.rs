@@ -0,0 +1,877 @@
+// Asks the home router to forward the ports of the enabled listeners that require a
+// token, and of those the config names, so the server can be reached from outside
+// without touching the router's settings: NAT-PMP (RFC 6886)
+// where the router speaks it, UPnP IGD otherwise. Mappings are renewed halfway through
+// their lease and removed again at shutdown. The admin and metrics endpoints are never
+// mapped; webrtc gets its udp media port mapped along with its signaling one.
+use crate::config_file::{Config, PortMappingConfig};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{self, Duration, Instant};
+use tracing::{error, info, warn};
+
+const NATPMP_PORT: u16 = 5351;
+// first wait for an answer, doubled for every retransmission
+const NATPMP_TIMEOUT: Duration = Duration::from_millis(250);
+const NATPMP_ATTEMPTS: u32 = 4;
+const SSDP_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+const SSDP_TIMEOUT: Duration = Duration::from_secs(3);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_RESPONSE_LEN: u64 = 1 << 16;
+// the services able to forward ports, preferred first
+const UPNP_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+// UPnP error of routers that only take mappings without a lease
+const ONLY_PERMANENT_LEASES: &str = "725";
+// after a renewal failed
+const RETRY: Duration = Duration::from_secs(60);
+const MIN_LIFETIME: u64 = 120;
+const DESCRIPTION: &str = "mic2net";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PortMappingMethod {
+    // NAT-PMP if the router answers it, UPnP otherwise
+    Auto,
+    Natpmp,
+    Upnp,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    fn name(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        }
+    }
+}
+
+struct Mapping {
+    name: String,
+    protocol: Protocol,
+    port: u16,
+    // what the router forwards to 'port'; None until it agreed
+    external: Option<u16>,
+}
+
+enum Gateway {
+    NatPmp(SocketAddr),
+    Upnp {
+        // host:port and path of the service's control url
+        host: String,
+        path: String,
+        service: &'static str,
+        // our address on the router's side
+        local_ip: IpAddr,
+        // false once the router turned down leases
+        leases: bool,
+    },
+}
+
+pub struct PortMapper {
+    gateway: Gateway,
+    mappings: Vec<Mapping>,
+    lifetime: Duration,
+}
+
+impl PortMapper {
+    pub async fn new(cfg: &Config) -> crate::Result<PortMapper> {
+        let mapping = &cfg.port_mapping;
+        let mappings = listeners(cfg);
+        if mappings.is_empty() {
+            return Err("no listener to map".into());
+        }
+        let gateway = match mapping.method {
+            PortMappingMethod::Natpmp => Gateway::NatPmp(natpmp_gateway(mapping)?),
+            PortMappingMethod::Upnp => upnp_gateway().await?,
+            PortMappingMethod::Auto => match natpmp_gateway(mapping) {
+                Ok(gateway) if natpmp_public_address(gateway).await.is_ok() => {
+                    Gateway::NatPmp(gateway)
+                }
+                _ => upnp_gateway().await?,
+            },
+        };
+        Ok(PortMapper {
+            gateway,
+            mappings,
+            lifetime: Duration::from_secs(mapping.lifetime.max(MIN_LIFETIME)),
+        })
+    }
+
+    // Map every listener and renew them halfway through the shortest lease granted.
+    async fn run(&mut self) {
+        match self.public_address().await {
+            Ok(ip) => info!("public address {}", ip),
+            Err(err) => warn!("router didn't tell the public address: {}", err),
+        }
+        loop {
+            let mut renew = self.lifetime / 2;
+            for index in 0..self.mappings.len() {
+                match self.map(index).await {
+                    Ok(lease) => renew = renew.min(lease / 2),
+                    Err(err) => {
+                        let mapping = &self.mappings[index];
+                        warn!(
+                            "can't map {} port {}/{}: {}",
+                            mapping.name,
+                            mapping.port,
+                            mapping.protocol.name(),
+                            err
+                        );
+                        renew = renew.min(RETRY);
+                    }
+                }
+            }
+            time::sleep(renew.max(Duration::from_secs(1))).await;
+        }
+    }
+
+    // Ask for mapping 'index' or renew it; the lease the router granted.
+    async fn map(&mut self, index: usize) -> crate::Result<Duration> {
+        let lifetime = self.lifetime;
+        let mapping = &self.mappings[index];
+        let (external, lease) = match &mut self.gateway {
+            Gateway::NatPmp(gateway) => natpmp_map(*gateway, mapping, lifetime).await?,
+            Gateway::Upnp {
+                host,
+                path,
+                service,
+                local_ip,
+                leases,
+            } => {
+                let mut args = [
+                    ("NewRemoteHost", String::new()),
+                    ("NewExternalPort", mapping.port.to_string()),
+                    ("NewProtocol", mapping.protocol.name().to_string()),
+                    ("NewInternalPort", mapping.port.to_string()),
+                    ("NewInternalClient", local_ip.to_string()),
+                    ("NewEnabled", "1".to_string()),
+                    ("NewPortMappingDescription", DESCRIPTION.to_string()),
+                    ("NewLeaseDuration", lifetime.as_secs().to_string()),
+                ];
+                let mut added = false;
+                if *leases {
+                    match soap(host, path, service, "AddPortMapping", &args).await {
+                        Err(UpnpError::Fault(code)) if code == ONLY_PERMANENT_LEASES => {
+                            info!("router only maps ports without a lease; removed at shutdown");
+                            *leases = false;
+                        }
+                        res => {
+                            res?;
+                            added = true;
+                        }
+                    }
+                }
+                if !added {
+                    args[7].1 = "0".to_string();
+                    soap(host, path, service, "AddPortMapping", &args).await?;
+                }
+                // mappings without a lease are added again now and then, for routers
+                // that forget them when they restart
+                (mapping.port, if *leases { lifetime } else { RETRY * 2 })
+            }
+        };
+        let mapping = &mut self.mappings[index];
+        if mapping.external != Some(external) {
+            info!(
+                "router forwards port {}/{} to {} port {}",
+                external,
+                mapping.protocol.name(),
+                mapping.name,
+                mapping.port
+            );
+            mapping.external = Some(external);
+        }
+        Ok(lease)
+    }
+
+    async fn public_address(&self) -> crate::Result<IpAddr> {
+        match &self.gateway {
+            Gateway::NatPmp(gateway) => natpmp_public_address(*gateway).await,
+            Gateway::Upnp {
+                host,
+                path,
+                service,
+                ..
+            } => {
+                let body = soap(host, path, service, "GetExternalIPAddress", &[]).await?;
+                let ip = element(&body, "NewExternalIPAddress").ok_or("no address in answer")?;
+                Ok(ip.trim().parse()?)
+            }
+        }
+    }
+
+    // Remove the mappings made, so the ports don't stay open once nothing listens.
+    async fn unmap(&mut self) {
+        for mapping in &self.mappings {
+            let Some(external) = mapping.external else {
+                continue;
+            };
+            let res = match &self.gateway {
+                Gateway::NatPmp(gateway) => natpmp_map(*gateway, mapping, Duration::ZERO)
+                    .await
+                    .map(drop),
+                Gateway::Upnp {
+                    host,
+                    path,
+                    service,
+                    ..
+                } => {
+                    let args = [
+                        ("NewRemoteHost", String::new()),
+                        ("NewExternalPort", external.to_string()),
+                        ("NewProtocol", mapping.protocol.name().to_string()),
+                    ];
+                    soap(host, path, service, "DeletePortMapping", &args)
+                        .await
+                        .map(drop)
+                        .map_err(crate::Error::from)
+                }
+            };
+            if let Err(err) = res {
+                warn!(
+                    "can't remove mapping of {} port {}: {}",
+                    mapping.name, mapping.port, err
+                );
+            }
+        }
+    }
+}
+
+// The listeners of enabled transports that only stream to clients with a token or
+// passphrase, and those named in 'public'; the port the same outside as inside.
+fn listeners(cfg: &Config) -> Vec<Mapping> {
+    let mut ports = vec![(
+        "tcp".to_string(),
+        Protocol::Tcp,
+        cfg.tcp.listen_port,
+        cfg.tcp.auth.is_some(),
+    )];
+    for listener in &cfg.listeners {
+        ports.push((
+            format!("tcp:{}", listener.listen_port),
+            Protocol::Tcp,
+            listener.listen_port,
+            listener.auth.is_some(),
+        ));
+    }
+    let enabled = [
+        (
+            cfg.udp.enable,
+            "udp",
+            Protocol::Udp,
+            cfg.udp.listen_port,
+            false,
+        ),
+        (
+            cfg.ws.enable,
+            "ws",
+            Protocol::Tcp,
+            cfg.ws.listen_port,
+            false,
+        ),
+        (
+            cfg.grpc.enable,
+            "grpc",
+            Protocol::Tcp,
+            cfg.grpc.listen_port,
+            !cfg.grpc.token.is_empty(),
+        ),
+        (
+            cfg.quic.enable,
+            "quic",
+            Protocol::Udp,
+            cfg.quic.listen_port,
+            cfg.quic.auth.is_some(),
+        ),
+        (
+            cfg.srt.enable,
+            "srt",
+            Protocol::Udp,
+            cfg.srt.listen_port,
+            !cfg.srt.passphrase.is_empty(),
+        ),
+        (
+            cfg.zmq.enable,
+            "zmq",
+            Protocol::Tcp,
+            cfg.zmq.listen_port,
+            false,
+        ),
+        (
+            cfg.http.enable,
+            "http",
+            Protocol::Tcp,
+            cfg.http.listen_port,
+            false,
+        ),
+        (
+            cfg.rtsp.enable,
+            "rtsp",
+            Protocol::Tcp,
+            cfg.rtsp.listen_port,
+            false,
+        ),
+        (
+            cfg.webrtc.enable,
+            "webrtc",
+            Protocol::Tcp,
+            cfg.webrtc.listen_port,
+            false,
+        ),
+        (
+            cfg.webrtc.enable,
+            "webrtc",
+            Protocol::Udp,
+            cfg.webrtc.listen_port,
+            false,
+        ),
+    ];
+    for (enable, name, protocol, port, authenticated) in enabled {
+        if enable {
+            ports.push((name.to_string(), protocol, port, authenticated));
+        }
+    }
+    let public = &cfg.port_mapping.public;
+    // once for listeners on both tcp and udp
+    let mut logged = Vec::new();
+    for name in public {
+        if !ports.iter().any(|(listener, ..)| listener == name) {
+            warn!("port_mapping.public: no enabled listener \"{}\"", name);
+        }
+    }
+    ports
+        .into_iter()
+        .filter(|(name, .., authenticated)| {
+            let mapped = *authenticated || public.contains(name);
+            if !mapped && !logged.contains(name) {
+                logged.push(name.clone());
+                info!("not mapping {}: it streams to anyone; list it in port_mapping.public to map it", name);
+            }
+            mapped
+        })
+        .map(|(name, protocol, port, _)| Mapping {
+            name,
+            protocol,
+            port,
+            external: None,
+        })
+        .collect()
+}
+
+// 'gateway' from the config, or the default route's (linux only).
+fn natpmp_gateway(cfg: &PortMappingConfig) -> crate::Result<SocketAddr> {
+    if !cfg.gateway.is_empty() {
+        let ip: IpAddr = cfg.gateway.parse()?;
+        return Ok(SocketAddr::new(ip, NATPMP_PORT));
+    }
+    // Iface Destination Gateway Flags ..., addresses in little endian hex
+    let routes = std::fs::read_to_string("/proc/net/route")
+        .map_err(|_| "no default gateway known; set port_mapping.gateway")?;
+    for route in routes.lines().skip(1) {
+        let fields: Vec<&str> = route.split_whitespace().collect();
+        if fields.len() > 2 && fields[1] == "00000000" {
+            let gateway = u32::from_str_radix(fields[2], 16)?;
+            let ip = Ipv4Addr::from(gateway.swap_bytes());
+            return Ok(SocketAddr::new(ip.into(), NATPMP_PORT));
+        }
+    }
+    Err("no default route; set port_mapping.gateway".into())
+}
+
+// Send 'request' to the NAT-PMP 'gateway' until it answers with an 'answer_len' long
+// success, retransmitted with longer and longer waits.
+async fn natpmp_request(
+    gateway: SocketAddr,
+    request: &[u8],
+    answer_len: usize,
+) -> crate::Result<Vec<u8>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(gateway).await?;
+    let mut buf = [0_u8; 16];
+    let mut wait = NATPMP_TIMEOUT;
+    for _ in 0..NATPMP_ATTEMPTS {
+        socket.send(request).await?;
+        let deadline = Instant::now() + wait;
+        while let Ok(res) = time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let n_bytes = res?;
+            // version 0, our opcode + 128
+            if n_bytes < answer_len || buf[0] != 0 || buf[1] != request[1] | 0x80 {
+                continue;
+            }
+            return match u16::from_be_bytes([buf[2], buf[3]]) {
+                0 => Ok(buf[..answer_len].to_vec()),
+                code => Err(format!("nat-pmp result code {}", code).into()),
+            };
+        }
+        wait *= 2;
+    }
+    Err(format!("no nat-pmp answer from {}", gateway.ip()).into())
+}
+
+async fn natpmp_public_address(gateway: SocketAddr) -> crate::Result<IpAddr> {
+    let answer = natpmp_request(gateway, &[0, 0], 12).await?;
+    let ip: [u8; 4] = answer[8..12].try_into().unwrap();
+    Ok(Ipv4Addr::from(ip).into())
+}
+
+// The external port and the lease the gateway granted; a 'lifetime' of zero removes.
+async fn natpmp_map(
+    gateway: SocketAddr,
+    mapping: &Mapping,
+    lifetime: Duration,
+) -> crate::Result<(u16, Duration)> {
+    let opcode = match mapping.protocol {
+        Protocol::Udp => 1,
+        Protocol::Tcp => 2,
+    };
+    let mut request = vec![0, opcode, 0, 0];
+    request.extend_from_slice(&mapping.port.to_be_bytes());
+    let suggested = if lifetime.is_zero() {
+        0
+    } else {
+        mapping.external.unwrap_or(mapping.port)
+    };
+    request.extend_from_slice(&suggested.to_be_bytes());
+    request.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    let answer = natpmp_request(gateway, &request, 16).await?;
+    let external = u16::from_be_bytes([answer[10], answer[11]]);
+    let lease = u32::from_be_bytes(answer[12..16].try_into().unwrap());
+    Ok((external, Duration::from_secs(lease.into())))
+}
+
+// Find the router with SSDP and the control url of its port forwarding service in its
+// device description.
+async fn upnp_gateway() -> crate::Result<Gateway> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\
+         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+        SSDP_GROUP
+    );
+    socket.send_to(search.as_bytes(), SSDP_GROUP).await?;
+    let deadline = Instant::now() + SSDP_TIMEOUT;
+    let mut buf = [0_u8; 2048];
+    while let Ok(res) = time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (n_bytes, from) = res?;
+        let answer = String::from_utf8_lossy(&buf[..n_bytes]);
+        let Some(location) = header(&answer, "location") else {
+            continue;
+        };
+        match upnp_service(location, from.ip()).await {
+            Ok(gateway) => return Ok(gateway),
+            Err(err) => warn!("upnp device at {} is of no use: {}", location, err),
+        }
+    }
+    Err("no upnp router answered".into())
+}
+
+async fn upnp_service(location: &str, router: IpAddr) -> crate::Result<Gateway> {
+    let (host, path) = split_url(location).ok_or("not an http url")?;
+    let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, host);
+    let (status, description) = http_request(&host, &request).await?;
+    if status != 200 {
+        return Err(format!("description answered with status {}", status).into());
+    }
+    let (service, control) = UPNP_SERVICES
+        .iter()
+        .find_map(|service| {
+            let at = description.find(&format!("<serviceType>{}</serviceType>", service))?;
+            Some((*service, element(&description[at..], "controlURL")?))
+        })
+        .ok_or("no port forwarding service")?;
+    let (host, path) = match split_url(control) {
+        Some(url) => url,
+        None => (host, format!("/{}", control.trim_start_matches('/'))),
+    };
+    // the address the router sees us by, without sending anything
+    let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    probe.connect((router, 1)).await?;
+    let local_ip = probe.local_addr()?.ip();
+    info!("using {} of upnp router {}", service, router);
+    Ok(Gateway::Upnp {
+        host,
+        path,
+        service,
+        local_ip,
+        leases: true,
+    })
+}
+
+enum UpnpError {
+    // the router's errorCode
+    Fault(String),
+    Other(crate::Error),
+}
+
+impl From<crate::Error> for UpnpError {
+    fn from(err: crate::Error) -> UpnpError {
+        UpnpError::Other(err)
+    }
+}
+
+impl From<UpnpError> for crate::Error {
+    fn from(err: UpnpError) -> crate::Error {
+        match err {
+            UpnpError::Fault(code) => format!("upnp error {}", code).into(),
+            UpnpError::Other(err) => err,
+        }
+    }
+}
+
+// Call 'action' of 'service'; the answer's body.
+async fn soap(
+    host: &str,
+    path: &str,
+    service: &str,
+    action: &str,
+    args: &[(&str, String)],
+) -> Result<String, UpnpError> {
+    let args: String = args
+        .iter()
+        .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+        .collect();
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>",
+        action, service, args
+    );
+    let request = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\n\
+         SOAPAction: \"{}#{}\"\r\nContent-Length: {}\r\n\r\n{}",
+        path,
+        host,
+        service,
+        action,
+        body.len(),
+        body
+    );
+    let (status, answer) = http_request(host, &request).await?;
+    match status {
+        200 => Ok(answer),
+        _ => match element(&answer, "errorCode") {
+            Some(code) => Err(UpnpError::Fault(code.trim().to_string())),
+            None => Err(UpnpError::Other(
+                format!("{} answered with status {}", action, status).into(),
+            )),
+        },
+    }
+}
+
+// One HTTP/1.0 request, so the answer is neither chunked nor kept alive; the status and
+// the body.
+async fn http_request(host: &str, request: &str) -> crate::Result<(u16, String)> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(host).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut answer = Vec::new();
+        (&mut stream)
+            .take(MAX_RESPONSE_LEN)
+            .read_to_end(&mut answer)
+            .await?;
+        Ok::<_, crate::Error>(answer)
+    };
+    let answer = time::timeout(HTTP_TIMEOUT, exchange)
+        .await
+        .map_err(|_| format!("{} timed out", host))??;
+    let answer = String::from_utf8_lossy(&answer);
+    let (head, body) = answer
+        .split_once("\r\n\r\n")
+        .ok_or("truncated http answer")?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or("bad http status line")?;
+    Ok((status, body.to_string()))
+}
+
+// host:port and path of an http url.
+fn split_url(url: &str) -> Option<(String, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(at) => (&rest[..at], &rest[at..]),
+        None => (rest, "/"),
+    };
+    let host = match authority.contains(':') {
+        true => authority.to_string(),
+        false => format!("{}:80", authority),
+    };
+    Some((host, path.to_string()))
+}
+
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+// Text of the first <'name'> element, namespace prefixes ignored.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("{}>", name))? + name.len() + 1;
+    let end = start + xml[start..].find("</")?;
+    Some(&xml[start..end])
+}
+
+// Run port mapper; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_port_mapping(cfg: Arc<Config>, shutdown: impl Future) {
+    let mut mapper = match PortMapper::new(&cfg).await {
+        Ok(mapper) => mapper,
+        Err(err) => {
+            error!("failed to set up port mapping: {}", err);
+            return;
+        }
+    };
+    tokio::select! {
+        _ = mapper.run() => {}
+        _ = shutdown => {
+            info!("removing port mappings");
+        }
+    }
+    mapper.unmap().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_file::AuthConfig;
+
+    fn names(cfg: &Config) -> Vec<String> {
+        listeners(cfg).into_iter().map(|m| m.name).collect()
+    }
+
+    #[test]
+    fn maps_only_listeners_with_a_token() {
+        let mut cfg = Config::default();
+        cfg.udp.enable = true;
+        cfg.http.enable = true;
+        assert!(names(&cfg).is_empty());
+
+        cfg.tcp.auth = Some(AuthConfig {
+            token: "secret".to_string(),
+            timeout: 5,
+        });
+        assert_eq!(names(&cfg), ["tcp"]);
+    }
+
+    #[test]
+    fn maps_public_listeners() {
+        let mut cfg = Config::default();
+        cfg.udp.enable = true;
+        cfg.port_mapping.public = vec!["udp".to_string(), "http".to_string()];
+        // http isn't enabled
+        assert_eq!(names(&cfg), ["udp"]);
+    }
+
+    #[test]
+    fn maps_both_webrtc_ports() {
+        let mut cfg = Config::default();
+        cfg.webrtc.enable = true;
+        cfg.port_mapping.public = vec!["webrtc".to_string()];
+        let ports: Vec<_> = listeners(&cfg)
+            .into_iter()
+            .map(|m| (m.protocol, m.port))
+            .collect();
+        let port = cfg.webrtc.listen_port;
+        assert_eq!(ports, [(Protocol::Tcp, port), (Protocol::Udp, port)]);
+    }
+
+    // A NAT-PMP gateway on loopback giving 'answer' to the one request it expects.
+    async fn natpmp_gateway(request: &[u8], answer: &[u8]) -> SocketAddr {
+        let (request, answer) = (request.to_vec(), answer.to_vec());
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let gateway = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0_u8; 16];
+            let (n_bytes, from) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n_bytes], request);
+            socket.send_to(&answer, from).await.unwrap();
+        });
+        gateway
+    }
+
+    #[tokio::test]
+    async fn natpmp_public_address_answer() {
+        // as sent by a miniupnpd router, 9 days up
+        let answer: &[u8] = &[
+            0x00, 0x80, 0x00, 0x00, 0x00, 0x0b, 0xde, 0x2a, 0x5d, 0xb8, 0x14, 0x07,
+        ];
+        let gateway = natpmp_gateway(&[0, 0], answer).await;
+        let ip = natpmp_public_address(gateway).await.unwrap();
+        assert_eq!(ip, Ipv4Addr::new(93, 184, 20, 7));
+    }
+
+    #[tokio::test]
+    async fn natpmp_map_answer() {
+        let request: &[u8] = &[
+            0x00, 0x02, 0x00, 0x00, 0x1f, 0x40, 0x1f, 0x40, 0x00, 0x00, 0x0e, 0x10,
+        ];
+        // the router picked another external port and halved the lease
+        let answer: &[u8] = &[
+            0x00, 0x82, 0x00, 0x00, 0x00, 0x0b, 0xde, 0x2a, 0x1f, 0x40, 0x1f, 0x41, 0x00, 0x00,
+            0x07, 0x08,
+        ];
+        let gateway = natpmp_gateway(request, answer).await;
+        let mapping = Mapping {
+            name: "tcp".to_string(),
+            protocol: Protocol::Tcp,
+            port: 8000,
+            external: None,
+        };
+        let (external, lease) = natpmp_map(gateway, &mapping, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(external, 8001);
+        assert_eq!(lease, Duration::from_secs(1800));
+    }
+
+    #[tokio::test]
+    async fn natpmp_refusal() {
+        // result code 2, not authorized: mapping turned off in the router's settings
+        let answer: &[u8] = &[
+            0x00, 0x80, 0x00, 0x02, 0x00, 0x0b, 0xde, 0x2a, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let gateway = natpmp_gateway(&[0, 0], answer).await;
+        let err = natpmp_public_address(gateway).await.unwrap_err();
+        assert_eq!(err.to_string(), "nat-pmp result code 2");
+    }
+
+    #[test]
+    fn split_router_urls() {
+        // miniupnpd, a FRITZ!Box and a router leaving out the port
+        assert_eq!(
+            split_url("http://192.168.1.1:49152/rootDesc.xml"),
+            Some(("192.168.1.1:49152".to_string(), "/rootDesc.xml".to_string()))
+        );
+        assert_eq!(
+            split_url("http://192.168.178.1:49000/igddesc.xml"),
+            Some((
+                "192.168.178.1:49000".to_string(),
+                "/igddesc.xml".to_string()
+            ))
+        );
+        assert_eq!(
+            split_url("http://10.0.0.138"),
+            Some(("10.0.0.138:80".to_string(), "/".to_string()))
+        );
+        assert_eq!(split_url("https://192.168.1.1/desc.xml"), None);
+    }
+
+    // From the device description of a miniupnpd router.
+    const DESCRIPTION_XML: &str = "<?xml version=\"1.0\"?>\r\n\
+        <root xmlns=\"urn:schemas-upnp-org:device-1-0\"><specVersion><major>1</major>\
+        <minor>1</minor></specVersion><device>\
+        <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>\
+        <friendlyName>OpenWrt router</friendlyName><serviceList><service>\
+        <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+        <serviceId>urn:upnp-org:serviceId:L3Forwarding1</serviceId>\
+        <controlURL>/ctl/L3F</controlURL><eventSubURL>/evt/L3F</eventSubURL>\
+        <SCPDURL>/L3F.xml</SCPDURL></service></serviceList><deviceList><device>\
+        <deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType><deviceList><device>\
+        <deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>\
+        <serviceList><service>\
+        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+        <serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId>\
+        <controlURL>/ctl/IPConn</controlURL><eventSubURL>/evt/IPConn</eventSubURL>\
+        <SCPDURL>/WANIPCn.xml</SCPDURL></service></serviceList></device></deviceList>\
+        </device></deviceList></device></root>";
+
+    #[test]
+    fn elements_of_answers() {
+        let answer = "<?xml version=\"1.0\"?>\r\n\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+            s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+            <u:GetExternalIPAddressResponse \
+            xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">\
+            <NewExternalIPAddress>93.184.20.7</NewExternalIPAddress>\
+            </u:GetExternalIPAddressResponse></s:Body></s:Envelope>\r\n";
+        assert_eq!(element(answer, "NewExternalIPAddress"), Some("93.184.20.7"));
+        assert_eq!(
+            element(DESCRIPTION_XML, "friendlyName"),
+            Some("OpenWrt router")
+        );
+        assert_eq!(element(DESCRIPTION_XML, "presentationURL"), None);
+    }
+
+    // An http server on loopback giving 'answer' to one request.
+    async fn http_server(answer: String) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0_u8; 4096];
+            let _ = socket.read(&mut buf).await.unwrap();
+            socket.write_all(answer.as_bytes()).await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn finds_the_forwarding_service() {
+        let addr = http_server(format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nConnection: close\r\n\
+             Content-Length: {}\r\nServer: OpenWRT/OpenWrt UPnP/1.1 MiniUPnPd/2.2.1\r\n\r\n{}",
+            DESCRIPTION_XML.len(),
+            DESCRIPTION_XML
+        ))
+        .await;
+        let location = format!("http://{}/rootDesc.xml", addr);
+        let Gateway::Upnp {
+            host,
+            path,
+            service,
+            ..
+        } = upnp_service(&location, addr.ip()).await.unwrap()
+        else {
+            panic!("not upnp");
+        };
+        assert_eq!(host, addr.to_string());
+        assert_eq!(path, "/ctl/IPConn");
+        assert_eq!(service, "urn:schemas-upnp-org:service:WANIPConnection:1");
+    }
+
+    #[tokio::test]
+    async fn soap_fault_code() {
+        let fault = "<?xml version=\"1.0\"?>\r\n\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+            s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><s:Fault>\
+            <faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail>\
+            <UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">\
+            <errorCode>725</errorCode><errorDescription>OnlyPermanentLeasesSupported\
+            </errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>\r\n";
+        let addr = http_server(format!(
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Type: text/xml\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            fault.len(),
+            fault
+        ))
+        .await;
+        let res = soap(
+            &addr.to_string(),
+            "/ctl/IPConn",
+            UPNP_SERVICES[1],
+            "AddPortMapping",
+            &[],
+        )
+        .await;
+        assert!(matches!(res, Err(UpnpError::Fault(code)) if code == ONLY_PERMANENT_LEASES));
+    }
+}
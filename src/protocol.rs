@@ -0,0 +1,701 @@
+// Wire framing shared by the server transports and by clients.
+//
+// Every frame is a 30-byte big-endian header followed by the payload:
+//
+//   magic   [u8; 4]  b"M2NF"
+//   version u8       PROTOCOL_VERSION
+//   kind    u8       FrameKind
+//   seq     u32      stream-wide sequence number; a gap means frames were dropped
+//   wall    u64      capture time of audio frames, creation time of others: unix µs
+//   mono    u64      the same in µs of the server's monotonic clock, see 'Timestamp'
+//   length  u32      payload length in bytes
+//
+// The first frame a server sends on every connection is a 'StreamInfo' describing
+// the audio frames that follow. Audio payloads are the packets built in main: device
+// id, capture time, packet id and 'PACKET_N_SAMPLE' samples per channel in the
+// stream's 'SampleFormat'; or, on transports set to another 'WireCodec', one frame of
+// that codec (an ADTS frame for aac, a packet for opus). Servers set to send silence
+// frames replace silent stretches of audio frames with 'Silence' frames. A client may
+// ask for another rate, channel count or codec with a 'FormatRequest'; the server
+// answers with a new 'StreamInfo', and the frames after it are in that format and
+// numbered afresh. Servers with a pre-shared key precede every 'StreamInfo' with a
+// 'Nonce' and seal the audio payloads that follow, with another 'Nonce' before
+// sequence numbers would repeat under the last one. Udp servers hand a new address a
+// 'Cookie' to echo before they stream to it. Udp clients may ask for lost frames once
+// more with a 'Nack'. Clients tell the server how the stream arrives with a
+// 'ReceiveReport' now and then, and one that reconnects asks it to go on where the
+// last connection broke off with a 'Resume'.
+use crate::audio::encode::WireCodec;
+use crate::audio::format::SampleFormat;
+use crate::transform::Compression;
+use crate::PACKET_N_SAMPLE;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::Serialize;
+use std::io::{Error, ErrorKind};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_util::codec::{Decoder, Encoder};
+
+pub const MAGIC: [u8; 4] = *b"M2NF";
+// 2: timestamps in the header, 3: stream info at the start of a connection,
+// 4: silence frames, 5: format requests, 6: compression, 7: psk encryption,
+// 8: parity frames, 9: nacks, 10: receive reports, 11: resume, 12: psk sealing of
+// silence frames and of the header, 13: udp cookies
+pub const PROTOCOL_VERSION: u8 = 13;
+pub const FRAME_HEADER_LEN: usize = 30;
+// far above any audio packet; protects decoders from garbage lengths
+pub const MAX_PAYLOAD_LEN: usize = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameKind {
+    Audio,
+    // client -> server: shared secret, the first frame on servers with auth enabled
+    Auth,
+    // client -> server: utf-8 command such as "denoise on", see 'dsp::Controls'
+    Control,
+    // server -> client: utf-8 reason, the last frame before the server hangs up
+    Goodbye,
+    // server -> client, on servers with a heartbeat; answered with a pong of the same seq
+    Ping,
+    Pong,
+    // client -> server, answered right away with a response carrying the request's
+    // timestamp and when it arrived; see 'clock'
+    TimeRequest,
+    TimeResponse,
+    // server -> client: 'StreamInfo', the first frame of a connection and the answer to
+    // every format request
+    StreamInfo,
+    // server -> client: u32 milliseconds of silence in place of audio frames, counted
+    // from the frame's timestamp; shares the sequence numbers with audio frames
+    Silence,
+    // client -> server: 'StreamInfo' of the stream the client would rather have, with
+    // frame_samples 0; on servers that allow it the frames after the answer have it
+    FormatRequest,
+    // server -> client, on servers with a psk: nonce prefix for the sealed audio and
+    // silence frames of the stream its stream info frame announces; see 'psk'
+    Nonce,
+    // server -> client, on udp transports with fec: xor of the frames of a group, see 'fec'
+    Parity,
+    // client -> udp server: u32 sequence numbers of frames to send again, see
+    // 'udp_server::UdpServer'; answered with the ones still at hand
+    Nack,
+    // client -> server: 'ReceiveReport' of the stream so far, for the server's stats
+    ReceiveReport,
+    // client -> server, right after reconnecting: the seq of the last frame it got, so
+    // the stream goes on with the frames after it the server still has; answered with
+    // a stream info frame when the server no longer has that frame
+    Resume,
+    // udp server -> client: bytes a new client sends back in a cookie frame of its own
+    // to be registered, proving it receives at its address; see 'udp_server::UdpServer'
+    Cookie,
+}
+
+impl FrameKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            FrameKind::Audio => 0,
+            FrameKind::Auth => 1,
+            FrameKind::Control => 2,
+            FrameKind::Goodbye => 3,
+            FrameKind::Ping => 4,
+            FrameKind::Pong => 5,
+            FrameKind::TimeRequest => 6,
+            FrameKind::TimeResponse => 7,
+            FrameKind::StreamInfo => 8,
+            FrameKind::Silence => 9,
+            FrameKind::FormatRequest => 10,
+            FrameKind::Nonce => 11,
+            FrameKind::Parity => 12,
+            FrameKind::Nack => 13,
+            FrameKind::ReceiveReport => 14,
+            FrameKind::Resume => 15,
+            FrameKind::Cookie => 16,
+        }
+    }
+
+    fn from_u8(kind: u8) -> Option<FrameKind> {
+        match kind {
+            0 => Some(FrameKind::Audio),
+            1 => Some(FrameKind::Auth),
+            2 => Some(FrameKind::Control),
+            3 => Some(FrameKind::Goodbye),
+            4 => Some(FrameKind::Ping),
+            5 => Some(FrameKind::Pong),
+            6 => Some(FrameKind::TimeRequest),
+            7 => Some(FrameKind::TimeResponse),
+            8 => Some(FrameKind::StreamInfo),
+            9 => Some(FrameKind::Silence),
+            10 => Some(FrameKind::FormatRequest),
+            11 => Some(FrameKind::Nonce),
+            12 => Some(FrameKind::Parity),
+            13 => Some(FrameKind::Nack),
+            14 => Some(FrameKind::ReceiveReport),
+            15 => Some(FrameKind::Resume),
+            16 => Some(FrameKind::Cookie),
+            _ => None,
+        }
+    }
+}
+
+// start of the monotonic clock, the first time anything asks for it
+static MONO_EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+// A point in time on two clocks. 'wall_us' is unix time, comparable between machines
+// whose clocks are synced, so receivers can tell latency; 'mono_us' counts from the
+// server's start and never jumps, so it is the one to space and align recordings by.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timestamp {
+    pub wall_us: u64,
+    pub mono_us: u64,
+}
+
+impl Timestamp {
+    pub fn now() -> Timestamp {
+        Timestamp {
+            wall_us: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64,
+            mono_us: MONO_EPOCH.elapsed().as_micros() as u64,
+        }
+    }
+
+    // 'duration' later, e.g. the start of the next packet.
+    pub fn after(self, duration: Duration) -> Timestamp {
+        let us = duration.as_micros() as u64;
+        Timestamp {
+            wall_us: self.wall_us + us,
+            mono_us: self.mono_us + us,
+        }
+    }
+
+    // 'duration' earlier, e.g. the start of a packet that was just completed.
+    pub fn before(self, duration: Duration) -> Timestamp {
+        let us = duration.as_micros() as u64;
+        Timestamp {
+            wall_us: self.wall_us.saturating_sub(us),
+            mono_us: self.mono_us.saturating_sub(us),
+        }
+    }
+
+    pub fn wall_clock(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_micros(self.wall_us)
+    }
+
+    // How long ago this was by the local clock; None if it seems to lie in the future,
+    // i.e. the clocks of sender and receiver disagree.
+    pub fn latency(&self) -> Option<Duration> {
+        SystemTime::now().duration_since(self.wall_clock()).ok()
+    }
+}
+
+// How the audio frames of a stream are to be read. Sent as a frame payload of
+//
+//   sample_rate    u32
+//   channels       u16
+//   frame_samples  u16  samples per channel in one audio frame
+//   codec          u8   WireCodec: 0 pcm, 1 aac, 2 adpcm, 3 opus
+//   sample_format  u8   SampleFormat of pcm: 0 i16, 1 i24, 2 f32
+//   compression    u8   Compression of audio payloads: 0 none, 1 zstd, 2 lz4
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StreamInfo {
+    pub sample_rate: usize,
+    pub n_ch: usize,
+    pub frame_samples: usize,
+    pub codec: WireCodec,
+    pub sample_format: SampleFormat,
+    // of a single connection's frames, see 'transform'; None for distributors
+    pub compression: Compression,
+}
+
+const STREAM_INFO_LEN: usize = 11;
+
+impl StreamInfo {
+    // Captured packets: pcm, 16-bit.
+    pub fn pcm(sample_rate: usize, n_ch: usize) -> StreamInfo {
+        StreamInfo {
+            sample_rate,
+            n_ch,
+            frame_samples: PACKET_N_SAMPLE,
+            codec: WireCodec::Pcm,
+            sample_format: SampleFormat::I16,
+            compression: Compression::None,
+        }
+    }
+
+    pub fn parse(payload: &[u8]) -> crate::Result<StreamInfo> {
+        if payload.len() < STREAM_INFO_LEN {
+            return Err(crate::Error::Protocol(format!(
+                "stream info of {} bytes",
+                payload.len()
+            )));
+        }
+        let codec = WireCodec::from_u8(payload[8])
+            .ok_or_else(|| crate::Error::Protocol(format!("unknown codec {}", payload[8])))?;
+        let sample_format = SampleFormat::from_u8(payload[9]).ok_or_else(|| {
+            crate::Error::Protocol(format!("unknown sample format {}", payload[9]))
+        })?;
+        let compression = Compression::from_u8(payload[10]).ok_or_else(|| {
+            crate::Error::Protocol(format!("unknown compression {}", payload[10]))
+        })?;
+        Ok(StreamInfo {
+            sample_rate: u32::from_be_bytes(payload[..4].try_into().unwrap()) as usize,
+            n_ch: u16::from_be_bytes([payload[4], payload[5]]) as usize,
+            frame_samples: u16::from_be_bytes([payload[6], payload[7]]) as usize,
+            codec,
+            sample_format,
+            compression,
+        })
+    }
+
+    fn to_payload(self) -> Bytes {
+        let mut payload = BytesMut::with_capacity(STREAM_INFO_LEN);
+        payload.put_u32(self.sample_rate as u32);
+        payload.put_u16(self.n_ch as u16);
+        payload.put_u16(self.frame_samples as u16);
+        payload.put_u8(self.codec.to_u8());
+        payload.put_u8(self.sample_format.to_u8());
+        payload.put_u8(self.compression.to_u8());
+        payload.freeze()
+    }
+}
+
+// Payload of receive reports, counts since the client connected:
+//
+//   received   u64  stream frames that arrived, in order or rebuilt
+//   lost       u64  gaps in the sequence numbers that were never filled
+//   reordered  u64  frames that came after later ones, too late to play
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReceiveReport {
+    pub received: u64,
+    pub lost: u64,
+    pub reordered: u64,
+}
+
+const RECEIVE_REPORT_LEN: usize = 24;
+
+impl ReceiveReport {
+    pub fn parse(payload: &[u8]) -> crate::Result<ReceiveReport> {
+        if payload.len() < RECEIVE_REPORT_LEN {
+            return Err(crate::Error::Protocol(format!(
+                "receive report of {} bytes",
+                payload.len()
+            )));
+        }
+        let count = |i: usize| u64::from_be_bytes(payload[8 * i..8 * (i + 1)].try_into().unwrap());
+        Ok(ReceiveReport {
+            received: count(0),
+            lost: count(1),
+            reordered: count(2),
+        })
+    }
+
+    fn to_payload(self) -> Bytes {
+        let mut payload = BytesMut::with_capacity(RECEIVE_REPORT_LEN);
+        payload.put_u64(self.received);
+        payload.put_u64(self.lost);
+        payload.put_u64(self.reordered);
+        payload.freeze()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub seq: u32,
+    pub timestamp: Timestamp,
+    pub payload: Bytes,
+}
+
+impl Frame {
+    pub fn audio(seq: u32, timestamp: Timestamp, payload: Bytes) -> Frame {
+        Frame {
+            kind: FrameKind::Audio,
+            seq,
+            timestamp,
+            payload,
+        }
+    }
+
+    pub fn auth(token: &str) -> Frame {
+        Frame {
+            kind: FrameKind::Auth,
+            seq: 0,
+            timestamp: Timestamp::now(),
+            payload: Bytes::copy_from_slice(token.as_bytes()),
+        }
+    }
+
+    pub fn control(command: &str) -> Frame {
+        Frame {
+            kind: FrameKind::Control,
+            seq: 0,
+            timestamp: Timestamp::now(),
+            payload: Bytes::copy_from_slice(command.as_bytes()),
+        }
+    }
+
+    pub fn ping(seq: u32) -> Frame {
+        Frame {
+            kind: FrameKind::Ping,
+            seq,
+            timestamp: Timestamp::now(),
+            payload: Bytes::new(),
+        }
+    }
+
+    pub fn pong(seq: u32) -> Frame {
+        Frame {
+            kind: FrameKind::Pong,
+            seq,
+            timestamp: Timestamp::now(),
+            payload: Bytes::new(),
+        }
+    }
+
+    pub fn time_request() -> Frame {
+        Frame {
+            kind: FrameKind::TimeRequest,
+            seq: 0,
+            timestamp: Timestamp::now(),
+            payload: Bytes::new(),
+        }
+    }
+
+    // Payload: wall and mono µs of the request's timestamp, then of 'received'.
+    pub fn time_response(request: &Frame, received: Timestamp) -> Frame {
+        let mut payload = BytesMut::with_capacity(32);
+        for timestamp in [request.timestamp, received] {
+            payload.put_u64(timestamp.wall_us);
+            payload.put_u64(timestamp.mono_us);
+        }
+        Frame {
+            kind: FrameKind::TimeResponse,
+            seq: request.seq,
+            timestamp: Timestamp::now(),
+            payload: payload.freeze(),
+        }
+    }
+
+    pub fn stream_info(info: &StreamInfo) -> Frame {
+        Frame {
+            kind: FrameKind::StreamInfo,
+            seq: 0,
+            timestamp: Timestamp::now(),
+            payload: info.to_payload(),
+        }
+    }
+
+    pub fn format_request(format: &StreamInfo) -> Frame {
+        Frame {
+            kind: FrameKind::FormatRequest,
+            seq: 0,
+            timestamp: Timestamp::now(),
+            payload: StreamInfo {
+                frame_samples: 0,
+                ..*format
+            }
+            .to_payload(),
+        }
+    }
+
+    pub fn nonce(prefix: &[u8]) -> Frame {
+        Frame {
+            kind: FrameKind::Nonce,
+            seq: 0,
+            timestamp: Timestamp::now(),
+            payload: Bytes::copy_from_slice(prefix),
+        }
+    }
+
+    pub fn cookie(cookie: &[u8]) -> Frame {
+        Frame {
+            kind: FrameKind::Cookie,
+            seq: 0,
+            timestamp: Timestamp::now(),
+            payload: Bytes::copy_from_slice(cookie),
+        }
+    }
+
+    pub fn nack(seqs: &[u32]) -> Frame {
+        let mut payload = BytesMut::with_capacity(4 * seqs.len());
+        for &seq in seqs {
+            payload.put_u32(seq);
+        }
+        Frame {
+            kind: FrameKind::Nack,
+            seq: 0,
+            timestamp: Timestamp::now(),
+            payload: payload.freeze(),
+        }
+    }
+
+    pub fn receive_report(report: &ReceiveReport) -> Frame {
+        Frame {
+            kind: FrameKind::ReceiveReport,
+            seq: 0,
+            timestamp: Timestamp::now(),
+            payload: report.to_payload(),
+        }
+    }
+
+    pub fn resume(last_seq: u32) -> Frame {
+        Frame {
+            kind: FrameKind::Resume,
+            seq: last_seq,
+            timestamp: Timestamp::now(),
+            payload: Bytes::new(),
+        }
+    }
+
+    pub fn silence(seq: u32, timestamp: Timestamp, ms: u32) -> Frame {
+        Frame {
+            kind: FrameKind::Silence,
+            seq,
+            timestamp,
+            payload: Bytes::copy_from_slice(&ms.to_be_bytes()),
+        }
+    }
+
+    // Milliseconds of a silence frame.
+    pub fn silence_ms(&self) -> crate::Result<u32> {
+        match self.payload[..] {
+            [a, b, c, d] if self.kind == FrameKind::Silence => Ok(u32::from_be_bytes([a, b, c, d])),
+            _ => Err(crate::Error::Protocol("bad silence frame".into())),
+        }
+    }
+
+    pub fn goodbye(reason: &str) -> Frame {
+        Frame {
+            kind: FrameKind::Goodbye,
+            seq: 0,
+            timestamp: Timestamp::now(),
+            payload: Bytes::copy_from_slice(reason.as_bytes()),
+        }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        FRAME_HEADER_LEN + self.payload.len()
+    }
+
+    // What 'FrameCodec' puts in front of the payload, for writers that send the
+    // payload without copying it.
+    pub fn header(&self) -> Result<[u8; FRAME_HEADER_LEN], Error> {
+        if self.payload.len() > MAX_PAYLOAD_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "frame payload too long",
+            ));
+        }
+        let mut header = [0; FRAME_HEADER_LEN];
+        let mut dst = &mut header[..];
+        dst.put_slice(&MAGIC);
+        dst.put_u8(PROTOCOL_VERSION);
+        dst.put_u8(self.kind.to_u8());
+        dst.put_u32(self.seq);
+        dst.put_u64(self.timestamp.wall_us);
+        dst.put_u64(self.timestamp.mono_us);
+        dst.put_u32(self.payload.len() as u32);
+        Ok(header)
+    }
+}
+
+// Encoder/decoder for 'tokio_util::codec::{FramedRead, FramedWrite}'.
+#[derive(Default)]
+pub struct FrameCodec;
+
+impl Encoder<&Frame> for FrameCodec {
+    type Error = Error;
+
+    fn encode(&mut self, frame: &Frame, dst: &mut BytesMut) -> Result<(), Error> {
+        let header = frame.header()?;
+        dst.reserve(frame.encoded_len());
+        dst.put_slice(&header);
+        dst.put_slice(&frame.payload);
+        Ok(())
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        if src.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+        if src[..4] != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "bad frame magic"));
+        }
+        if src[4] != PROTOCOL_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported protocol version {}", src[4]),
+            ));
+        }
+        let kind = FrameKind::from_u8(src[5])
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "unknown frame kind"))?;
+        let seq = u32::from_be_bytes(src[6..10].try_into().unwrap());
+        let timestamp = Timestamp {
+            wall_us: u64::from_be_bytes(src[10..18].try_into().unwrap()),
+            mono_us: u64::from_be_bytes(src[18..26].try_into().unwrap()),
+        };
+        let length = u32::from_be_bytes(src[26..30].try_into().unwrap()) as usize;
+        if length > MAX_PAYLOAD_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "frame payload too long"));
+        }
+        if src.len() < FRAME_HEADER_LEN + length {
+            src.reserve(FRAME_HEADER_LEN + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(FRAME_HEADER_LEN);
+        let payload = src.split_to(length).freeze();
+        Ok(Some(Frame {
+            kind,
+            seq,
+            timestamp,
+            payload,
+        }))
+    }
+}
+
+// Encode a single frame, e.g. for one UDP datagram or WebSocket message.
+pub fn encode_frame(frame: &Frame) -> Bytes {
+    let mut buf = BytesMut::with_capacity(frame.encoded_len());
+    // only fails on oversized payloads, which never come out of the capture path
+    FrameCodec.encode(frame, &mut buf).unwrap();
+    buf.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_frame() -> Frame {
+        let timestamp = Timestamp {
+            wall_us: 1_700_000_000_000_000,
+            mono_us: 42,
+        };
+        Frame::audio(7, timestamp, Bytes::from_static(b"samples"))
+    }
+
+    fn assert_same(a: &Frame, b: &Frame) {
+        assert_eq!(a.kind, b.kind);
+        assert_eq!(a.seq, b.seq);
+        assert_eq!(a.timestamp, b.timestamp);
+        assert_eq!(a.payload, b.payload);
+    }
+
+    #[test]
+    fn frame_kinds_roundtrip() {
+        for kind in 0..=u8::MAX {
+            if let Some(frame_kind) = FrameKind::from_u8(kind) {
+                assert_eq!(frame_kind.to_u8(), kind);
+            }
+        }
+        assert_eq!(FrameKind::from_u8(FrameKind::Cookie.to_u8() + 1), None);
+    }
+
+    #[test]
+    fn frames_roundtrip() {
+        let frame = sample_frame();
+        let encoded = encode_frame(&frame);
+        assert_eq!(encoded.len(), frame.encoded_len());
+        assert_eq!(encoded[..FRAME_HEADER_LEN], frame.header().unwrap());
+
+        let mut src = BytesMut::from(&encoded[..]);
+        let decoded = FrameCodec.decode(&mut src).unwrap().unwrap();
+        assert_same(&decoded, &frame);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn partial_frames_wait_for_the_rest() {
+        let frame = sample_frame();
+        let encoded = encode_frame(&frame);
+        let mut src = BytesMut::new();
+        for (i, byte) in encoded.iter().enumerate() {
+            assert!(FrameCodec.decode(&mut src).unwrap().is_none(), "{}", i);
+            src.put_u8(*byte);
+        }
+        assert_same(&FrameCodec.decode(&mut src).unwrap().unwrap(), &frame);
+    }
+
+    #[test]
+    fn back_to_back_frames_decode_in_order() {
+        let mut src = BytesMut::new();
+        for seq in 0..3 {
+            let frame = Frame::ping(seq);
+            FrameCodec.encode(&frame, &mut src).unwrap();
+        }
+        for seq in 0..3 {
+            let frame = FrameCodec.decode(&mut src).unwrap().unwrap();
+            assert_eq!((frame.kind, frame.seq), (FrameKind::Ping, seq));
+        }
+        assert!(FrameCodec.decode(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_headers_are_refused() {
+        let encoded = encode_frame(&sample_frame());
+        let corrupt = |at: usize, bytes: &[u8]| {
+            let mut src = BytesMut::from(&encoded[..]);
+            src[at..at + bytes.len()].copy_from_slice(bytes);
+            FrameCodec.decode(&mut src)
+        };
+        assert!(corrupt(0, b"M2NX").is_err());
+        assert!(corrupt(4, &[PROTOCOL_VERSION - 1]).is_err());
+        assert!(corrupt(5, &[FrameKind::Cookie.to_u8() + 1]).is_err());
+        let too_long = (MAX_PAYLOAD_LEN as u32 + 1).to_be_bytes();
+        assert!(corrupt(26, &too_long).is_err());
+    }
+
+    #[test]
+    fn oversized_payloads_are_not_encoded() {
+        let mut frame = sample_frame();
+        frame.payload = Bytes::from(vec![0; MAX_PAYLOAD_LEN + 1]);
+        assert!(FrameCodec.encode(&frame, &mut BytesMut::new()).is_err());
+    }
+
+    #[test]
+    fn stream_info_roundtrips() {
+        let info = StreamInfo {
+            sample_rate: 48_000,
+            n_ch: 8,
+            frame_samples: PACKET_N_SAMPLE,
+            codec: WireCodec::Adpcm,
+            sample_format: SampleFormat::F32,
+            compression: Compression::Lz4,
+        };
+        let frame = Frame::stream_info(&info);
+        assert_eq!(StreamInfo::parse(&frame.payload).unwrap(), info);
+        assert!(StreamInfo::parse(&frame.payload[..STREAM_INFO_LEN - 1]).is_err());
+
+        for field in 8..=10 {
+            let mut payload = frame.payload.to_vec();
+            payload[field] = u8::MAX;
+            let err = StreamInfo::parse(&payload).unwrap_err();
+            assert!(matches!(err, crate::Error::Protocol(_)));
+        }
+    }
+
+    #[test]
+    fn receive_reports_roundtrip() {
+        let report = ReceiveReport {
+            received: 1000,
+            lost: 3,
+            reordered: 1,
+        };
+        let frame = Frame::receive_report(&report);
+        assert_eq!(ReceiveReport::parse(&frame.payload).unwrap(), report);
+        assert!(ReceiveReport::parse(&frame.payload[..RECEIVE_REPORT_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn silence_frames_carry_their_length() {
+        let frame = Frame::silence(3, Timestamp::now(), 250);
+        assert_eq!(frame.silence_ms().unwrap(), 250);
+        assert!(Frame::ping(3).silence_ms().is_err());
+    }
+}
@@ -0,0 +1,302 @@
+// ChaCha20-Poly1305 sealing of audio payloads with a pre-shared key, for receivers that
+// can't do tls. Nonces are 8 random bytes the server announces in a 'Nonce' frame before
+// every stream info, then the frame's sequence number: sequence numbers restart with
+// every stream and wrap around, so the server announces a new prefix before they come
+// round again under the same one; the prefix makes sure a nonce is never used twice.
+// The frame header but for the length is authenticated along with the payload, and a
+// receiver takes each sequence number once per prefix, so frames can't be replayed or
+// relabeled.
+use crate::distributor::is_after;
+use crate::protocol::{Frame, FrameKind, FRAME_HEADER_LEN};
+use crate::transform::Transform;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::rand::{SecureRandom, SystemRandom};
+
+pub const KEY_LEN: usize = 32;
+pub const PREFIX_LEN: usize = 8;
+// the header up to the payload length, which sealing changes
+const AAD_LEN: usize = FRAME_HEADER_LEN - 4;
+// sequence numbers sealed under one prefix, half of them so receivers can still tell
+// which of two comes after the other
+const SEQS_PER_PREFIX: u32 = u32::MAX / 2;
+
+// Whether frames of 'kind' are sealed: every kind that stands for audio.
+pub fn seals(kind: FrameKind) -> bool {
+    matches!(kind, FrameKind::Audio | FrameKind::Silence)
+}
+
+#[derive(Clone)]
+pub struct Psk {
+    key: [u8; KEY_LEN],
+}
+
+impl Psk {
+    // 'hex' holds the 32 bytes of the key as 64 hex digits.
+    pub fn parse(hex: &str) -> crate::Result<Psk> {
+        let hex = hex.trim();
+        if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+            return Err(format!("psk needs {} hex digits", KEY_LEN * 2).into());
+        }
+        let mut key = [0; KEY_LEN];
+        for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+            let digits = std::str::from_utf8(digits)?;
+            *byte = u8::from_str_radix(digits, 16)
+                .map_err(|_| format!("bad hex digits \"{}\" in psk", digits))?;
+        }
+        Ok(Psk { key })
+    }
+
+    // Seals the audio frames of a new stream; returns the prefix to announce with it.
+    pub fn sealer(&self) -> crate::Result<([u8; PREFIX_LEN], Sealer)> {
+        let mut prefix = [0; PREFIX_LEN];
+        SystemRandom::new()
+            .fill(&mut prefix)
+            .map_err(|_| "no randomness for a nonce")?;
+        let sealer = Sealer {
+            key: self.unbound_key()?,
+            prefix,
+            first_seq: None,
+        };
+        Ok((prefix, sealer))
+    }
+
+    // Opens the audio frames following the nonce frame 'nonce'.
+    pub fn opener(&self, nonce: &Frame) -> crate::Result<Box<dyn Transform>> {
+        let prefix = nonce
+            .payload
+            .as_ref()
+            .try_into()
+            .map_err(|_| crate::Error::Protocol("bad nonce frame".into()))?;
+        Ok(Box::new(Opener {
+            key: self.unbound_key()?,
+            prefix,
+            last_seq: None,
+        }))
+    }
+
+    fn unbound_key(&self) -> crate::Result<LessSafeKey> {
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &self.key).map_err(|_| "bad psk")?;
+        Ok(LessSafeKey::new(key))
+    }
+}
+
+fn nonce(prefix: &[u8; PREFIX_LEN], seq: u32) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[..PREFIX_LEN].copy_from_slice(prefix);
+    nonce[PREFIX_LEN..].copy_from_slice(&seq.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn aad(frame: &Frame) -> crate::Result<Aad<[u8; AAD_LEN]>> {
+    let header = frame.header()?;
+    Ok(Aad::from(header[..AAD_LEN].try_into().unwrap()))
+}
+
+pub struct Sealer {
+    key: LessSafeKey,
+    prefix: [u8; PREFIX_LEN],
+    // of the first frame sealed
+    first_seq: Option<u32>,
+}
+
+impl Sealer {
+    // Whether sealing 'seq' could use a nonce again; a new sealer, and its prefix
+    // announced, has to take over before.
+    pub fn exhausted(&self, seq: u32) -> bool {
+        self.first_seq
+            .is_some_and(|first| seq.wrapping_sub(first) >= SEQS_PER_PREFIX)
+    }
+}
+
+impl Transform for Sealer {
+    fn apply(&mut self, frame: Frame) -> crate::Result<Frame> {
+        if !seals(frame.kind) {
+            return Ok(frame);
+        }
+        if self.exhausted(frame.seq) {
+            return Err(crate::Error::Protocol(format!(
+                "frame {} would reuse a nonce",
+                frame.seq
+            )));
+        }
+        self.first_seq.get_or_insert(frame.seq);
+        let mut payload = frame.payload.to_vec();
+        self.key
+            .seal_in_place_append_tag(nonce(&self.prefix, frame.seq), aad(&frame)?, &mut payload)
+            .map_err(|_| "failed to seal a frame")?;
+        Ok(Frame {
+            payload: payload.into(),
+            ..frame
+        })
+    }
+}
+
+struct Opener {
+    key: LessSafeKey,
+    prefix: [u8; PREFIX_LEN],
+    // of the last frame that opened
+    last_seq: Option<u32>,
+}
+
+// Frames that don't open were changed on the way or sealed with another key; frames
+// that aren't newer than the last one are replayed.
+impl Transform for Opener {
+    fn apply(&mut self, frame: Frame) -> crate::Result<Frame> {
+        if !seals(frame.kind) {
+            return Ok(frame);
+        }
+        if self.last_seq.is_some_and(|last| !is_after(frame.seq, last)) {
+            return Err(crate::Error::Protocol(format!(
+                "replayed frame {}",
+                frame.seq
+            )));
+        }
+        let mut payload = frame.payload.to_vec();
+        let len = self
+            .key
+            .open_in_place(nonce(&self.prefix, frame.seq), aad(&frame)?, &mut payload)
+            .map_err(|_| crate::Error::Protocol("frame failed to authenticate".into()))?
+            .len();
+        payload.truncate(len);
+        self.last_seq = Some(frame.seq);
+        Ok(Frame {
+            payload: payload.into(),
+            ..frame
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Timestamp;
+    use bytes::Bytes;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn pair() -> (Sealer, Box<dyn Transform>) {
+        let psk = Psk::parse(KEY).unwrap();
+        let (prefix, sealer) = psk.sealer().unwrap();
+        (sealer, psk.opener(&Frame::nonce(&prefix)).unwrap())
+    }
+
+    fn audio(seq: u32) -> Frame {
+        Frame::audio(seq, Timestamp::now(), Bytes::from_static(b"some samples"))
+    }
+
+    #[test]
+    fn sealed_frames_open() {
+        let (mut sealer, mut opener) = pair();
+        let sealed = sealer.apply(audio(1)).unwrap();
+        assert_ne!(sealed.payload, audio(1).payload);
+        assert_eq!(opener.apply(sealed).unwrap().payload, audio(1).payload);
+
+        let silence = Frame::silence(2, Timestamp::now(), 500);
+        let sealed = sealer.apply(silence).unwrap();
+        assert!(sealed.silence_ms().is_err());
+        assert_eq!(opener.apply(sealed).unwrap().silence_ms().unwrap(), 500);
+    }
+
+    #[test]
+    fn changed_header_fails() {
+        let (mut sealer, mut opener) = pair();
+        let mut sealed = sealer.apply(audio(1)).unwrap();
+        sealed.timestamp.wall_us += 1;
+        assert!(opener.apply(sealed).is_err());
+    }
+
+    #[test]
+    fn changed_payload_fails() {
+        let (mut sealer, mut opener) = pair();
+        let sealed = sealer.apply(audio(1)).unwrap();
+        let mut payload = sealed.payload.to_vec();
+        payload[0] ^= 1;
+        let changed = Frame {
+            payload: payload.into(),
+            ..sealed.clone()
+        };
+        assert!(opener.apply(changed).is_err());
+        // nor may audio pass as silence
+        let relabeled = Frame {
+            kind: FrameKind::Silence,
+            ..sealed
+        };
+        assert!(opener.apply(relabeled).is_err());
+    }
+
+    #[test]
+    fn other_keys_and_prefixes_fail() {
+        let (mut sealer, _) = pair();
+        let sealed = sealer.apply(audio(1)).unwrap();
+        let other = Psk::parse(&KEY.replace('0', "f")).unwrap();
+        let (prefix, _) = other.sealer().unwrap();
+        let mut opener = other.opener(&Frame::nonce(&prefix)).unwrap();
+        assert!(opener.apply(sealed.clone()).is_err());
+
+        // the right key, but the nonce of another stream
+        let psk = Psk::parse(KEY).unwrap();
+        let mut opener = psk.opener(&Frame::nonce(&prefix)).unwrap();
+        assert!(opener.apply(sealed).is_err());
+        assert!(psk.opener(&Frame::nonce(&prefix[1..])).is_err());
+    }
+
+    #[test]
+    fn other_frames_pass_untouched() {
+        let (mut sealer, mut opener) = pair();
+        let ping = sealer.apply(Frame::ping(1)).unwrap();
+        assert_eq!(ping.payload, Frame::ping(1).payload);
+        assert!(opener.apply(ping).is_ok());
+        // and don't count as the last sequence number
+        assert!(opener.apply(sealer.apply(audio(1)).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn replayed_frame_fails() {
+        let (mut sealer, mut opener) = pair();
+        let first = sealer.apply(audio(1)).unwrap();
+        let second = sealer.apply(audio(2)).unwrap();
+        opener.apply(first.clone()).unwrap();
+        opener.apply(second).unwrap();
+        assert!(opener.apply(first).is_err());
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let (mut sealer, mut opener) = pair();
+        opener
+            .apply(sealer.apply(audio(u32::MAX - 1)).unwrap())
+            .unwrap();
+        let last = sealer.apply(audio(u32::MAX)).unwrap();
+        opener.apply(last.clone()).unwrap();
+        opener.apply(sealer.apply(audio(0)).unwrap()).unwrap();
+        opener.apply(sealer.apply(audio(1)).unwrap()).unwrap();
+        let err = opener.apply(last).unwrap_err();
+        assert!(matches!(err, crate::Error::Protocol(_)));
+    }
+
+    #[test]
+    fn sealer_runs_out_before_nonces_repeat() {
+        let (mut sealer, _) = pair();
+        assert!(!sealer.exhausted(u32::MAX - 10));
+        sealer.apply(audio(u32::MAX - 10)).unwrap();
+        let last = (u32::MAX - 10).wrapping_add(SEQS_PER_PREFIX - 1);
+        assert!(!sealer.exhausted(last));
+        sealer.apply(audio(last)).unwrap();
+        assert!(sealer.exhausted(last.wrapping_add(1)));
+        assert!(sealer.apply(audio(last.wrapping_add(1))).is_err());
+        // nor can an earlier one come round again
+        assert!(sealer.exhausted(u32::MAX - 11));
+        // the next prefix starts afresh
+        let psk = Psk::parse(KEY).unwrap();
+        let (prefix, mut sealer) = psk.sealer().unwrap();
+        let mut opener = psk.opener(&Frame::nonce(&prefix)).unwrap();
+        let sealed = sealer.apply(audio(last.wrapping_add(1))).unwrap();
+        opener.apply(sealed).unwrap();
+    }
+
+    #[test]
+    fn bad_keys_are_refused() {
+        assert!(Psk::parse("00").is_err());
+        assert!(Psk::parse(&KEY.replace('0', "g")).is_err());
+    }
+}
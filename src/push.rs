@@ -0,0 +1,214 @@
+// Pushes the stream to a remote collector instead of waiting for clients, for devices
+// behind NAT that can't take inbound connections: mic2net dials 'push.url' and serves
+// the connection as the tcp or websocket server would serve a client, so the collector
+// reads it like any other. The connection is dialed again when it fails or ends.
+use crate::client_stats::ClientRegistry;
+use crate::config_file::{Config, HeartbeatConfig};
+use crate::distributor::{frames_in, Distributor, DropPolicy};
+use crate::protocol::Frame;
+use crate::socket::{SocketReader, SocketWriter};
+use crate::tcp_server::SocketHandler;
+use crate::ws_server::WsHandler;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{self, Duration};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, AUTHORIZATION};
+use tracing::{error, info, info_span, warn, Instrument};
+
+const DEFAULT_TCP_PORT: u16 = 2345;
+const DEFAULT_WS_PORT: u16 = 80;
+// for the tcp connection and the websocket handshake
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Pusher {
+    url: String,
+    host: String,
+    port: u16,
+    websocket: bool,
+    token: Option<String>,
+    distributor: Arc<Distributor>,
+    queue_len: usize,
+    drop_policy: DropPolicy,
+    // frames of pre-roll per connection
+    preroll: usize,
+    heartbeat: Option<HeartbeatConfig>,
+    retry: Duration,
+    max_retry: Duration,
+    // the collector, while connected
+    clients: Arc<ClientRegistry>,
+    next_connection_id: u64,
+    notify_shutdown: broadcast::Sender<()>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+    shutdown_complete_rx: mpsc::Receiver<()>,
+}
+
+impl Pusher {
+    pub fn new(cfg: &Config, distributor: Arc<Distributor>) -> crate::Result<Pusher> {
+        let push = &cfg.push;
+        let (websocket, rest) = match push.url.split_once("://") {
+            Some(("tcp", rest)) => (false, rest),
+            Some(("ws", rest)) => (true, rest),
+            _ => return Err(format!("push url {} isn't tcp:// or ws://", push.url).into()),
+        };
+        let authority = rest.split('/').next().unwrap_or_default();
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse()?),
+            None if websocket => (authority, DEFAULT_WS_PORT),
+            None => (authority, DEFAULT_TCP_PORT),
+        };
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
+        Ok(Pusher {
+            url: push.url.clone(),
+            host: host.to_string(),
+            port,
+            websocket,
+            token: (!push.token.is_empty()).then(|| push.token.clone()),
+            distributor,
+            queue_len: push.queue_len,
+            drop_policy: push.drop_policy,
+            preroll: frames_in(
+                push.preroll,
+                push.sample_rate.unwrap_or(cfg.mic.sample_rate),
+            ),
+            heartbeat: push.heartbeat.clone(),
+            retry: Duration::from_millis(push.retry.max(1)),
+            max_retry: Duration::from_millis(push.max_retry.max(push.retry)),
+            clients: ClientRegistry::new(1),
+            next_connection_id: 0,
+            notify_shutdown,
+            shutdown_complete_tx,
+            shutdown_complete_rx,
+        })
+    }
+
+    // Dial, stream until the connection ends, and dial again after 'retry'; the wait
+    // doubles with every attempt that fails, up to 'max_retry'.
+    async fn run(&mut self) {
+        let mut delay = self.retry;
+        loop {
+            match self.push().await {
+                Ok(()) => {
+                    delay = self.retry;
+                    info!(
+                        "push connection to {} ended; dialing again in {:?}",
+                        self.url, delay
+                    );
+                    time::sleep(delay).await;
+                }
+                Err(err) => {
+                    warn!(
+                        "can't push to {}: {}; retrying in {:?}",
+                        self.url, err, delay
+                    );
+                    time::sleep(delay).await;
+                    delay = (delay * 2).min(self.max_retry);
+                }
+            }
+        }
+    }
+
+    // One connection, from dialing until it ends. The connection runs on a task of its
+    // own, which says goodbye to the collector when the pusher is shut down.
+    async fn push(&mut self) -> crate::Result<()> {
+        let socket = time::timeout(
+            CONNECT_TIMEOUT,
+            TcpStream::connect((self.host.as_str(), self.port)),
+        )
+        .await
+        .map_err(|_| "timed out connecting")??;
+        socket.set_nodelay(true)?;
+        let peer = socket.peer_addr()?.to_string();
+        let span = info_span!("push", peer = %peer, id = self.next_connection_id);
+        self.next_connection_id += 1;
+
+        let shutdown_signal = self.notify_shutdown.subscribe();
+        let shutdown_complete = self.shutdown_complete_tx.clone();
+        let connection = if self.websocket {
+            let ws = time::timeout(CONNECT_TIMEOUT, self.handshake(socket))
+                .await
+                .map_err(|_| "websocket handshake timed out")??;
+            info!("pushing to {}", self.url);
+            // subscribe only now so frames don't pile up during the handshake
+            let frames = self.distributor.subscribe_with_preroll(
+                self.queue_len,
+                self.drop_policy,
+                self.preroll,
+            );
+            let mut handler = WsHandler::new(peer, ws, frames, shutdown_signal, shutdown_complete);
+            tokio::spawn(async move { handler.run().await }.instrument(span))
+        } else {
+            let (read_half, write_half) = socket.into_split();
+            let socket_reader = SocketReader::new(Box::new(read_half));
+            let mut socket_writer = SocketWriter::new(Box::new(write_half));
+            if let Some(token) = &self.token {
+                socket_writer.write_packet(&Frame::auth(token)).await?;
+            }
+            info!("pushing to {}", self.url);
+            let client = self.clients.register(&peer);
+            let mut handler = SocketHandler::new(
+                peer,
+                client,
+                socket_reader,
+                socket_writer,
+                self.distributor.subscribe_with_preroll(
+                    self.queue_len,
+                    self.drop_policy,
+                    self.preroll,
+                ),
+                shutdown_signal,
+                shutdown_complete,
+            );
+            if let Some(heartbeat) = &self.heartbeat {
+                handler.set_heartbeat(heartbeat);
+            }
+            tokio::spawn(async move { handler.run().await }.instrument(span))
+        };
+        // a failed connection is dialed again after a longer wait than one that ended
+        connection.await?
+    }
+
+    async fn handshake(
+        &self,
+        socket: TcpStream,
+    ) -> crate::Result<tokio_tungstenite::WebSocketStream<TcpStream>> {
+        let mut request = self.url.as_str().into_client_request()?;
+        if let Some(token) = &self.token {
+            let value = HeaderValue::from_str(&format!("Bearer {}", token))
+                .map_err(|_| "push token isn't a valid header value")?;
+            request.headers_mut().insert(AUTHORIZATION, value);
+        }
+        let (ws, _) = tokio_tungstenite::client_async(request, socket).await?;
+        Ok(ws)
+    }
+}
+
+// Run pusher; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_push(cfg: Arc<Config>, distributor: Arc<Distributor>, shutdown: impl Future) {
+    let mut pusher = match Pusher::new(&cfg, distributor) {
+        Ok(pusher) => pusher,
+        Err(err) => {
+            error!("failed to start pushing: {}", err);
+            return;
+        }
+    };
+    tokio::select! {
+        _ = pusher.run() => {}
+        _ = shutdown => {
+            info!("stopping push to {}", pusher.url);
+        }
+    }
+
+    let Pusher {
+        mut shutdown_complete_rx,
+        shutdown_complete_tx,
+        notify_shutdown,
+        ..
+    } = pusher;
+    drop(notify_shutdown);
+    drop(shutdown_complete_tx);
+    shutdown_complete_rx.recv().await;
+}
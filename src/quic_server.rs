@@ -0,0 +1,300 @@
+// The tcp protocol over QUIC: each client gets its audio frames on a unidirectional
+// stream opened by the server, and may open one bidirectional stream for control
+// frames (and the auth frame, when auth is enabled).
+use crate::config_file::{AuthConfig, Config};
+use crate::distributor::{frames_in, Distributor, DropPolicy, Subscription};
+use crate::metrics::{Metrics, METRICS};
+use crate::protocol::{Frame, FrameKind, Timestamp};
+use crate::socket::{SocketReader, SocketWriter};
+use crate::tcp_server::{authenticate, handle_control};
+use crate::tls::load_server_config;
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{Connection, ConnectionError, Endpoint, ServerConfig};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::time::{self, Duration};
+use tracing::{error, info, info_span, warn, Instrument};
+
+pub const QUIC_ALPN: &[u8] = b"mic2net";
+
+pub struct QuicServer {
+    addr: SocketAddr,
+    endpoint: Endpoint,
+    limit_connections: Arc<Semaphore>,
+    distributor: Arc<Distributor>,
+    queue_len: usize,
+    drop_policy: DropPolicy,
+    // frames of pre-roll per new client
+    preroll: usize,
+    auth: Option<Arc<AuthConfig>>,
+    next_client_id: u64,
+    notify_shutdown: broadcast::Sender<()>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+    shutdown_complete_rx: mpsc::Receiver<()>,
+}
+
+impl QuicServer {
+    pub async fn new(cfg: Arc<Config>, distributor: Arc<Distributor>) -> crate::Result<QuicServer> {
+        let addr = SocketAddr::new(cfg.quic.bind_address.parse()?, cfg.quic.listen_port);
+        let mut tls_config = load_server_config(&cfg.quic.tls)?;
+        tls_config.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+        let server_config =
+            ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls_config)?));
+        let endpoint = Endpoint::server(server_config, addr).map_err(crate::Error::bind(addr))?;
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
+
+        let server = QuicServer {
+            addr,
+            endpoint,
+            limit_connections: Arc::new(Semaphore::new(cfg.quic.max_clients.into())),
+            distributor,
+            queue_len: cfg.quic.queue_len,
+            drop_policy: cfg.quic.drop_policy,
+            preroll: frames_in(
+                cfg.quic.preroll,
+                cfg.quic.sample_rate.unwrap_or(cfg.mic.sample_rate),
+            ),
+            auth: cfg.quic.auth.clone().map(Arc::new),
+            next_client_id: 0,
+            notify_shutdown,
+            shutdown_complete_tx,
+            shutdown_complete_rx,
+        };
+        Ok(server)
+    }
+
+    async fn run(&mut self) -> crate::Result<()> {
+        info!("quic listen on {}", self.addr);
+
+        loop {
+            let permit = self
+                .limit_connections
+                .clone()
+                .acquire_owned()
+                .await
+                .unwrap();
+            let incoming = match self.endpoint.accept().await {
+                Some(incoming) => incoming,
+                None => return Ok(()),
+            };
+            let peer = incoming.remote_address();
+            info!("connection from {}", peer);
+            Metrics::inc(&METRICS.connections_accepted);
+
+            let distributor = self.distributor.clone();
+            let (queue_len, drop_policy, preroll) =
+                (self.queue_len, self.drop_policy, self.preroll);
+            let shutdown_signal = self.notify_shutdown.subscribe();
+            let shutdown_complete = self.shutdown_complete_tx.clone();
+            let auth = self.auth.clone();
+            let span = info_span!("quic_client", peer = %peer, id = self.next_client_id);
+            self.next_client_id += 1;
+
+            let connection = async move {
+                // handshake in the task so a stalled client can't block the accept loop
+                let connection = match incoming.await {
+                    Ok(connection) => connection,
+                    Err(err) => {
+                        warn!("quic handshake failed: {}", err);
+                        return;
+                    }
+                };
+                let control = match auth {
+                    Some(auth) => match accept_authenticated(&connection, &auth).await {
+                        Some(control) => Some(control),
+                        None => {
+                            warn!("failed to authenticate");
+                            Metrics::inc(&METRICS.auth_failures);
+                            connection.close(1_u32.into(), b"unauthorized");
+                            return;
+                        }
+                    },
+                    None => None,
+                };
+                let (control, control_reply) = control.unzip();
+                let audio = match connection.open_uni().await {
+                    Ok(send) => SocketWriter::new(Box::new(send)),
+                    Err(err) => {
+                        warn!("failed to open audio stream: {}", err);
+                        return;
+                    }
+                };
+
+                // subscribe only now so frames don't pile up during the handshakes
+                let ip_addr = peer.to_string();
+                METRICS.client_connected(&ip_addr);
+                let mut handler = QuicHandler {
+                    ip_addr,
+                    connection,
+                    audio,
+                    control,
+                    control_reply,
+                    frames: distributor.subscribe_with_preroll(queue_len, drop_policy, preroll),
+                    shutdown_signal,
+                    _shutdown_complete: shutdown_complete,
+                };
+                if let Err(err) = handler.run().await {
+                    error!("connection error: {}", err);
+                }
+                drop(permit);
+            };
+            tokio::spawn(connection.instrument(span));
+        }
+    }
+}
+
+// Accept the client's control stream and check the auth frame it has to start with.
+async fn accept_authenticated(
+    connection: &Connection,
+    auth: &AuthConfig,
+) -> Option<(SocketReader, SocketWriter)> {
+    let timeout = Duration::from_secs(auth.timeout);
+    let (send, recv) = time::timeout(timeout, connection.accept_bi())
+        .await
+        .ok()?
+        .ok()?;
+    let mut control = SocketReader::new(Box::new(recv));
+    if authenticate(&mut control, auth).await {
+        Some((control, SocketWriter::new(Box::new(send))))
+    } else {
+        None
+    }
+}
+
+pub struct QuicHandler {
+    ip_addr: String,
+    connection: Connection,
+    audio: SocketWriter,
+    // the client's bidirectional stream, once it opened one; the server only writes
+    // time responses to it
+    control: Option<SocketReader>,
+    control_reply: Option<SocketWriter>,
+    frames: Subscription,
+    shutdown_signal: broadcast::Receiver<()>,
+    _shutdown_complete: mpsc::Sender<()>,
+}
+
+impl QuicHandler {
+    async fn run(&mut self) -> crate::Result<()> {
+        let stream_info = self.frames.stream_info_frame();
+        if let Err(err) = self.audio.write_packet(&stream_info).await {
+            return self.stream_failed(err);
+        }
+        loop {
+            tokio::select! {
+                frame = self.frames.recv() => match frame {
+                    Some(frame) => {
+                        if let Err(err) = self.audio.write_packet(&frame).await {
+                            return self.stream_failed(err);
+                        }
+                        METRICS.frame_sent(&self.ip_addr, frame.encoded_len());
+                    }
+                    None => {
+                        warn!("fell behind the stream");
+                        self.connection.close(0_u32.into(), b"fell behind");
+                        return Ok(());
+                    }
+                },
+                stream = self.connection.accept_bi(), if self.control.is_none() => match stream {
+                    Ok((send, recv)) => {
+                        self.control = Some(SocketReader::new(Box::new(recv)));
+                        self.control_reply = Some(SocketWriter::new(Box::new(send)));
+                    }
+                    Err(err) => return closed(err),
+                },
+                res = read_control(&mut self.control) => match res {
+                    Ok(Some(frame)) if frame.kind == FrameKind::Control => handle_control(&frame.payload),
+                    Ok(Some(frame)) if frame.kind == FrameKind::TimeRequest => {
+                        let response = Frame::time_response(&frame, Timestamp::now());
+                        if let Some(reply) = &mut self.control_reply {
+                            if let Err(err) = reply.write_packet(&response).await {
+                                return self.stream_failed(err);
+                            }
+                        }
+                    }
+                    Ok(Some(frame)) => warn!("unexpected {:?} frame", frame.kind),
+                    // the client finished its control stream; audio keeps flowing
+                    Ok(None) => self.control = None,
+                    Err(err) => return self.stream_failed(err),
+                },
+                err = self.connection.closed() => return closed(err),
+                _ = self.shutdown_signal.recv() => {
+                    self.connection.close(0_u32.into(), b"shutdown");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Streams fail when the connection goes away, which is fine if the client closed it.
+    fn stream_failed(&self, err: crate::Error) -> crate::Result<()> {
+        match self.connection.close_reason() {
+            Some(reason) => closed(reason),
+            None => Err(err),
+        }
+    }
+}
+
+// Next frame on the control stream; never resolves while there is none.
+async fn read_control(control: &mut Option<SocketReader>) -> crate::Result<Option<Frame>> {
+    match control {
+        Some(control) => control.read_frame().await,
+        None => std::future::pending().await,
+    }
+}
+
+// A client closing its connection is the normal way to leave.
+fn closed(err: ConnectionError) -> crate::Result<()> {
+    match err {
+        ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed => Ok(()),
+        err => Err(err.into()),
+    }
+}
+
+impl Drop for QuicHandler {
+    fn drop(&mut self) {
+        METRICS.client_disconnected(&self.ip_addr);
+        info!("disconnected");
+    }
+}
+
+// Run quic server; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_quic_server(
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    shutdown: impl Future,
+) {
+    let mut server = match QuicServer::new(cfg, distributor).await {
+        Ok(server) => server,
+        Err(err) => {
+            error!("failed to start quic server: {}", err);
+            return;
+        }
+    };
+    tokio::select! {
+        res = server.run() => {
+            if let Err(err) = res {
+                error!("quic server stopped: {}", err);
+            }
+        }
+        _ = shutdown => {
+            info!("cleaning up quic server");
+        }
+    }
+
+    let QuicServer {
+        endpoint,
+        mut shutdown_complete_rx,
+        shutdown_complete_tx,
+        notify_shutdown,
+        ..
+    } = server;
+    drop(notify_shutdown);
+    drop(shutdown_complete_tx);
+    shutdown_complete_rx.recv().await;
+    // lets the close frames of the connections go out
+    endpoint.wait_idle().await;
+}
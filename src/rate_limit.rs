@@ -0,0 +1,263 @@
+// Per source address token buckets for the accept loop, so one host reconnecting in
+// a tight loop (or scanning the port) can't keep it busy, and per client ones for the
+// bytes written to it.
+use crate::config_file::RateLimitConfig;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// buckets kept at most; once full, those that refilled are forgotten, and the quarter
+// least recently used if that doesn't make room
+const MAX_TRACKED: usize = 4096;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    // connections refused since the address was last let through
+    refused: u64,
+}
+
+pub struct RateLimiter {
+    // connections per second, and how many may come at once
+    rate: f64,
+    burst: f64,
+    // by 'source'
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(cfg: &RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            rate: cfg.rate,
+            burst: cfg.burst.max(1) as f64,
+            buckets: HashMap::new(),
+        }
+    }
+
+    // Whether a connection from 'ip' may go ahead now. Only the first refusal of a
+    // run is logged, and how many followed once the address is let through again.
+    pub fn check(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let source = source(ip);
+        if self.buckets.len() >= MAX_TRACKED && !self.buckets.contains_key(&source) {
+            self.make_room(now);
+        }
+        let (rate, burst) = (self.rate, self.burst);
+        let bucket = self.buckets.entry(source).or_insert(Bucket {
+            tokens: burst,
+            last_refill: now,
+            refused: 0,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            if bucket.refused > 0 {
+                info!(peer = %ip, refused = bucket.refused, "connections no longer throttled");
+                bucket.refused = 0;
+            }
+            true
+        } else {
+            if bucket.refused == 0 {
+                warn!(peer = %ip, "throttling connections");
+            }
+            bucket.refused += 1;
+            false
+        }
+    }
+
+    // Drop buckets that have refilled completely, as they behave like new ones, then
+    // the least recently used while that leaves too many; a quarter of them at once, so
+    // the next new addresses find room without going through all the buckets again.
+    fn make_room(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens + elapsed * rate < burst
+        });
+        if self.buckets.len() < MAX_TRACKED {
+            return;
+        }
+        let mut used: Vec<Instant> = self.buckets.values().map(|b| b.last_refill).collect();
+        let (_, &mut cutoff, _) = used.select_nth_unstable(MAX_TRACKED / 4);
+        self.buckets.retain(|_, bucket| bucket.last_refill > cutoff);
+    }
+}
+
+// Where connections from 'ip' count: a host usually has a whole /64 of ipv6 addresses
+// to pick from, so those share a bucket.
+fn source(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) if v6.to_ipv4_mapped().is_none() => {
+            Ipv6Addr::from(u128::from(v6) & (u128::MAX << 64)).into()
+        }
+        IpAddr::V6(v6) => v6.to_canonical(),
+        ip => ip,
+    }
+}
+
+// a quarter second's worth of a bandwidth limit may go out at once
+const BURST_SECS: f64 = 0.25;
+// in 'Bandwidth::own' for clients following the server's limit
+const NO_OWN_LIMIT: u64 = u64::MAX;
+
+// Bytes per second one client may be sent, shared by its handler and the admin api:
+// its own limit where one was set, its server's otherwise; 0 for no limit.
+pub struct Bandwidth {
+    server: Arc<AtomicU64>,
+    own: AtomicU64,
+}
+
+impl Bandwidth {
+    pub fn new(server: Arc<AtomicU64>) -> Bandwidth {
+        Bandwidth {
+            server,
+            own: AtomicU64::new(NO_OWN_LIMIT),
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        match self.own.load(Ordering::Relaxed) {
+            NO_OWN_LIMIT => self.server.load(Ordering::Relaxed),
+            own => own,
+        }
+    }
+
+    // None to follow the server's limit again.
+    pub fn set(&self, limit: Option<u64>) {
+        self.own
+            .store(limit.unwrap_or(NO_OWN_LIMIT), Ordering::Relaxed);
+    }
+}
+
+// Holds the writes to one client back to its bandwidth limit, so a client that can
+// take everything doesn't saturate a slow uplink for the others. Frames pile up in
+// the client's queue instead, where its drop policy applies.
+pub struct TokenBucket {
+    bandwidth: Arc<Bandwidth>,
+    // bytes that may go out now; negative while a write is paid off
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bandwidth: Arc<Bandwidth>) -> TokenBucket {
+        TokenBucket {
+            bandwidth,
+            tokens: f64::INFINITY,
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Count 'n_bytes' written; returns until when nothing more may go out once that
+    // ran into debt. The limit is read every time, so changes through the admin api
+    // apply to open connections.
+    pub fn charge(&mut self, n_bytes: usize) -> Option<Instant> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        let rate = match self.bandwidth.limit() {
+            0 => {
+                self.tokens = f64::INFINITY;
+                return None;
+            }
+            limit => limit as f64,
+        };
+        self.tokens = (self.tokens + elapsed * rate).min(rate * BURST_SECS);
+        self.tokens -= n_bytes as f64;
+        if self.tokens < 0.0 {
+            Some(now + Duration::from_secs_f64(-self.tokens / rate))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(rate: f64, burst: u32) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig { rate, burst })
+    }
+
+    fn ip(n: u32) -> IpAddr {
+        std::net::Ipv4Addr::from(0x0a00_0000 + n).into()
+    }
+
+    #[test]
+    fn connects_beyond_the_burst_are_refused() {
+        let mut limiter = limiter(0.0, 3);
+        assert!((0..3).all(|_| limiter.check(ip(1))));
+        assert!(!limiter.check(ip(1)));
+        // other addresses have buckets of their own
+        assert!(limiter.check(ip(2)));
+    }
+
+    #[test]
+    fn buckets_refill_at_the_rate() {
+        let mut limiter = limiter(50.0, 1);
+        assert!(limiter.check(ip(1)));
+        assert!(!limiter.check(ip(1)));
+        // a token every 20 ms
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(limiter.check(ip(1)));
+    }
+
+    #[test]
+    fn full_buckets_are_forgotten() {
+        let mut refilling = limiter(1e9, 1);
+        for n in 0..MAX_TRACKED as u32 {
+            refilling.check(ip(n));
+        }
+        assert!(refilling.check(ip(u32::MAX >> 8)));
+        assert!(refilling.buckets.len() < MAX_TRACKED);
+
+        // ones still paying off a burst go once there are too many, the oldest first
+        let mut stuck = limiter(0.0, 1);
+        for n in 0..2 * MAX_TRACKED as u32 {
+            stuck.check(ip(n));
+            assert!(stuck.buckets.len() <= MAX_TRACKED);
+        }
+        assert!(!stuck.check(ip(2 * MAX_TRACKED as u32 - 1)));
+    }
+
+    #[test]
+    fn ipv6_hosts_share_their_64() {
+        let mut limiter = limiter(0.0, 2);
+        let host = |n: u16| IpAddr::from([0x2001, 0xdb8, 0, 1, 0, 0, 0, n]);
+        assert!(limiter.check(host(1)));
+        assert!(limiter.check(host(2)));
+        assert!(!limiter.check(host(3)));
+        assert!(limiter.check(IpAddr::from([0x2001, 0xdb8, 0, 2, 0, 0, 0, 1])));
+        // mapped ipv4 addresses are the ipv4 ones
+        assert!(limiter.check(ip(1)));
+        let mapped = std::net::Ipv4Addr::from(0x0a00_0001).to_ipv6_mapped();
+        assert!(limiter.check(mapped.into()));
+        assert!(!limiter.check(ip(1)));
+    }
+
+    #[test]
+    fn bucket_throttles_beyond_the_limit() {
+        let server = Arc::new(AtomicU64::new(1000));
+        let bandwidth = Arc::new(Bandwidth::new(server.clone()));
+        let mut bucket = TokenBucket::new(bandwidth.clone());
+        // the burst of a quarter second is free
+        assert!(bucket.charge(250).is_none());
+        let until = bucket.charge(500).unwrap();
+        let wait = until.duration_since(Instant::now());
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+
+        // an own limit of 0 lifts the server's
+        bandwidth.set(Some(0));
+        assert!(bucket.charge(1 << 20).is_none());
+        bandwidth.set(None);
+        server.store(0, Ordering::Relaxed);
+        assert!(bucket.charge(1 << 20).is_none());
+    }
+}
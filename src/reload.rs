@@ -0,0 +1,202 @@
+// Re-reading the config file of a running server, on SIGHUP or through the admin api.
+// Only what can change under open connections is applied: output gains, the [vad]
+// settings, client and bandwidth limits and acl rules. Everything else keeps its value until a
+// restart. Of those, only settings that changed in the file since it was last read
+// are applied, so a gain set through the admin api stays unless the file changes it.
+use crate::acl::Acl;
+use crate::audio::vad::VAD;
+use crate::client_stats::ClientRegistry;
+use crate::config_file::{Config, VadConfig};
+use crate::dsp::level::OUTPUT_LEVELS;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, Weak};
+use tracing::{error, info};
+
+pub static RELOAD: Reload = Reload {
+    path: Mutex::new(None),
+    settings: Mutex::new(None),
+    clients: Mutex::new(BTreeMap::new()),
+    acls: Mutex::new(Vec::new()),
+};
+
+pub struct Reload {
+    path: Mutex<Option<String>>,
+    // as last read from the file
+    settings: Mutex<Option<Settings>>,
+    // client limits by the name of their output, "tcp", "udp", "uds" or "tcp:<listen_port>"
+    clients: Mutex<BTreeMap<String, Weak<ClientRegistry>>>,
+    acls: Mutex<Vec<Weak<Acl>>>,
+}
+
+#[derive(PartialEq)]
+struct Settings {
+    // by output name, as in 'OUTPUT_LEVELS'
+    gains: BTreeMap<String, f32>,
+    max_clients: BTreeMap<String, usize>,
+    bandwidth_limits: BTreeMap<String, u64>,
+    vad: VadConfig,
+}
+
+impl Settings {
+    fn of(cfg: &Config) -> Settings {
+        let mut gains = BTreeMap::from([
+            ("tcp".to_string(), cfg.tcp.gain),
+            ("udp".to_string(), cfg.udp.gain),
+            ("multicast".to_string(), cfg.multicast.gain),
+            ("uds".to_string(), cfg.uds.gain),
+            ("ws".to_string(), cfg.ws.gain),
+            ("grpc".to_string(), cfg.grpc.gain),
+            ("quic".to_string(), cfg.quic.gain),
+            ("srt".to_string(), cfg.srt.gain),
+            ("zmq".to_string(), cfg.zmq.gain),
+            ("push".to_string(), cfg.push.gain),
+            ("http".to_string(), cfg.http.gain),
+            ("icecast".to_string(), cfg.icecast.gain),
+            ("mqtt".to_string(), cfg.mqtt.gain),
+            ("kafka".to_string(), cfg.kafka.gain),
+        ]);
+        let mut max_clients = BTreeMap::from([
+            ("tcp".to_string(), cfg.tcp.max_clients.into()),
+            ("udp".to_string(), cfg.udp.max_clients.into()),
+            ("uds".to_string(), cfg.uds.max_clients.into()),
+        ]);
+        let mut bandwidth_limits = BTreeMap::from([("tcp".to_string(), cfg.tcp.bandwidth_limit)]);
+        for listener in &cfg.listeners {
+            let name = format!("tcp:{}", listener.listen_port);
+            gains.insert(name.clone(), listener.gain);
+            max_clients.insert(name.clone(), listener.max_clients.into());
+            bandwidth_limits.insert(name, listener.bandwidth_limit);
+        }
+        for stream in &cfg.zmq.streams {
+            gains.insert(format!("zmq:{}", stream.topic), stream.gain);
+        }
+        Settings {
+            gains,
+            max_clients,
+            bandwidth_limits,
+            vad: cfg.vad.clone(),
+        }
+    }
+}
+
+impl Reload {
+    // Reload from 'path' later on, starting out from 'cfg' as read from it.
+    pub fn init(&self, path: &str, cfg: &Config) {
+        *self.path.lock().unwrap() = Some(path.to_string());
+        *self.settings.lock().unwrap() = Some(Settings::of(cfg));
+    }
+
+    // Follow the 'max_clients' and 'bandwidth_limit' of output 'name' in 'clients'.
+    pub fn add_clients(&self, name: &str, clients: &Arc<ClientRegistry>) {
+        self.clients
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), Arc::downgrade(clients));
+    }
+
+    // The clients of every output still running, by name.
+    pub fn clients(&self) -> Vec<(String, Arc<ClientRegistry>)> {
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(name, clients)| Some((name.clone(), clients.upgrade()?)))
+            .collect()
+    }
+
+    // Re-read the rules of 'acl' on every reload, besides when its file changes.
+    pub fn add_acl(&self, acl: &Arc<Acl>) {
+        let mut acls = self.acls.lock().unwrap();
+        acls.retain(|acl| acl.strong_count() > 0);
+        acls.push(Arc::downgrade(acl));
+    }
+
+    // Re-read the acl files, then the config file, and apply what changed in it; what
+    // was applied, for the log and the admin api. A config file that can't be read or
+    // parsed changes nothing.
+    pub fn reload(&self) -> crate::Result<Vec<String>> {
+        let path = self
+            .path
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or("the server isn't running")?;
+        for acl in self.acls.lock().unwrap().iter().filter_map(Weak::upgrade) {
+            acl.reload();
+        }
+        let new = Settings::of(&Config::reload(&path).map_err(|err| format!("{}: {}", path, err))?);
+        let mut settings = self.settings.lock().unwrap();
+        let mut applied = Vec::new();
+        let old = match settings.as_ref() {
+            Some(old) if *old == new => return Ok(applied),
+            Some(old) => old,
+            None => return Err("the server isn't running".into()),
+        };
+
+        for (name, &gain) in &new.gains {
+            if old.gains.get(name) == Some(&gain) {
+                continue;
+            }
+            if let Some(level) = OUTPUT_LEVELS.get(name) {
+                match level.set_gain_db(gain) {
+                    Ok(()) => applied.push(format!("{} gain {} dB", name, gain)),
+                    Err(err) => error!("{}: {}", name, err),
+                }
+            }
+        }
+        let clients = self.clients.lock().unwrap();
+        for (name, &max_clients) in &new.max_clients {
+            if old.max_clients.get(name) == Some(&max_clients) {
+                continue;
+            }
+            if let Some(registry) = clients.get(name).and_then(Weak::upgrade) {
+                registry.set_max_clients(max_clients);
+                applied.push(format!("{} max_clients {}", name, max_clients));
+            }
+        }
+        for (name, &limit) in &new.bandwidth_limits {
+            if old.bandwidth_limits.get(name) == Some(&limit) {
+                continue;
+            }
+            if let Some(registry) = clients.get(name).and_then(Weak::upgrade) {
+                registry.set_bandwidth_limit(limit);
+                applied.push(format!("{} bandwidth_limit {}", name, limit));
+            }
+        }
+        if new.vad != old.vad {
+            VAD.configure(&new.vad);
+            applied.push("vad".to_string());
+        }
+        *settings = Some(new);
+        Ok(applied)
+    }
+
+    // 'reload', logging the outcome.
+    pub fn apply(&self) {
+        match self.reload() {
+            Ok(applied) if applied.is_empty() => info!("config reloaded, nothing changed"),
+            Ok(applied) => info!("config reloaded: {}", applied.join(", ")),
+            Err(err) => error!("failed to reload the config: {}", err),
+        }
+    }
+}
+
+// Reload whenever the process gets a SIGHUP, until 'shutdown' completes.
+#[cfg(unix)]
+pub async fn reload_on_hangup(shutdown: impl std::future::Future) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            error!("can't handle SIGHUP: {}", err);
+            return;
+        }
+    };
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            Some(()) = hangups.recv() => RELOAD.apply(),
+            _ = &mut shutdown => return,
+        }
+    }
+}
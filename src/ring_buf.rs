@@ -27,6 +27,10 @@ impl<T: Clone + Copy> RingBuf<T> {
         self.buf_length
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.buf_length == 0
+    }
+
     pub fn append(&mut self, new_slice: &[T]) {
         let mut length_to_copy = new_slice.len();
         let mut copied_length = 0usize;
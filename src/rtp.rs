@@ -0,0 +1,345 @@
+// RTP (RFC 3550) output, so standard receivers like ffmpeg, GStreamer and VLC can
+// play the stream with the SDP description from 'RtpSender::sdp'.
+use crate::config_file::Config;
+use crate::distributor::{Distributor, DropPolicy, Subscription};
+use crate::protocol::FrameKind;
+use crate::{HEADER_LEN, PACKET_N_SAMPLE};
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration};
+use tracing::{error, info, warn};
+
+const RTP_VERSION: u8 = 2;
+const RTP_HEADER_LEN: usize = 12;
+// keeps datagrams under a typical 1500 byte MTU
+const MAX_RTP_PAYLOAD: usize = 1400;
+const RTP_QUEUE_LEN: usize = 8;
+const RTCP_INTERVAL: Duration = Duration::from_secs(5);
+// seconds between the NTP (1900) and unix (1970) epochs
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RtpFormat {
+    // uncompressed 16-bit big-endian, channels interleaved (RFC 3551)
+    L16,
+    // RFC 7587; needs the 'opus' feature and at most 2 channels
+    Opus,
+}
+
+enum Encoder {
+    L16,
+    #[cfg(feature = "opus")]
+    Opus(opus::Encoder),
+}
+
+// Sends each packet of the stream as one or more RTP packets to a unicast or
+// multicast destination, with an RTCP sender report to the next port every few seconds.
+pub struct RtpSender {
+    socket: UdpSocket,
+    destination: SocketAddr,
+    rtcp_destination: SocketAddr,
+    payload_type: u8,
+    format: RtpFormat,
+    sample_rate: usize,
+    n_ch: usize,
+    ssrc: u32,
+    seq: u16,
+    timestamp: u32,
+    packets_sent: u32,
+    octets_sent: u32,
+    encoder: Encoder,
+    frames: Subscription,
+}
+
+impl RtpSender {
+    // Send to 'rtp.destination'.
+    pub async fn new(
+        cfg: Arc<Config>,
+        distributor: Arc<Distributor>,
+        n_ch: usize,
+    ) -> crate::Result<RtpSender> {
+        let destination: SocketAddr = cfg.rtp.destination.parse()?;
+        RtpSender::with_destination(cfg, distributor, n_ch, destination).await
+    }
+
+    pub async fn with_destination(
+        cfg: Arc<Config>,
+        distributor: Arc<Distributor>,
+        n_ch: usize,
+        destination: SocketAddr,
+    ) -> crate::Result<RtpSender> {
+        let mut rtcp_destination = destination;
+        rtcp_destination.set_port(destination.port() + 1);
+        let bind_address = if destination.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_address).await?;
+        if destination.ip().is_multicast() {
+            socket.set_multicast_ttl_v4(cfg.rtp.ttl)?;
+        }
+        let sample_rate = cfg.rtp.sample_rate.unwrap_or(cfg.mic.sample_rate);
+
+        let encoder = match cfg.rtp.format {
+            RtpFormat::L16 => Encoder::L16,
+            #[cfg(feature = "opus")]
+            RtpFormat::Opus => {
+                let channels = match n_ch {
+                    1 => opus::Channels::Mono,
+                    2 => opus::Channels::Stereo,
+                    _ => return Err("rtp opus supports 1 or 2 channels".into()),
+                };
+                let encoder =
+                    opus::Encoder::new(sample_rate as u32, channels, opus::Application::Voip)?;
+                Encoder::Opus(encoder)
+            }
+            #[cfg(not(feature = "opus"))]
+            RtpFormat::Opus => return Err("rtp opus needs a build with --features opus".into()),
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        Ok(RtpSender {
+            socket,
+            destination,
+            rtcp_destination,
+            payload_type: cfg.rtp.payload_type,
+            format: cfg.rtp.format,
+            sample_rate,
+            n_ch,
+            // only has to be unlikely to collide with another sender on the session
+            ssrc: now.subsec_nanos() ^ std::process::id().rotate_left(16),
+            seq: now.subsec_micros() as u16,
+            timestamp: now.as_secs() as u32,
+            packets_sent: 0,
+            octets_sent: 0,
+            encoder,
+            frames: distributor.subscribe(RTP_QUEUE_LEN, DropPolicy::DropNewest),
+        })
+    }
+
+    // Session description for receivers, e.g. 'ffplay -protocol_whitelist file,udp,rtp stream.sdp'.
+    pub fn sdp(&self) -> String {
+        sdp(
+            &self.destination,
+            self.payload_type,
+            self.format,
+            self.sample_rate,
+            self.n_ch,
+        )
+    }
+
+    pub fn local_port(&self) -> crate::Result<u16> {
+        Ok(self.socket.local_addr()?.port())
+    }
+
+    // RTP clock rate; opus always uses 48 kHz
+    fn clock_rate(&self) -> usize {
+        match self.format {
+            RtpFormat::L16 => self.sample_rate,
+            RtpFormat::Opus => 48000,
+        }
+    }
+
+    pub async fn run(&mut self) -> crate::Result<()> {
+        info!(
+            "rtp to {} ({:?}, payload type {})",
+            self.destination, self.format, self.payload_type
+        );
+        let mut rtcp_ticker = time::interval(RTCP_INTERVAL);
+        let mut datagram = BytesMut::with_capacity(RTP_HEADER_LEN + MAX_RTP_PAYLOAD);
+        loop {
+            tokio::select! {
+                frame = self.frames.recv() => {
+                    let frame = match frame {
+                        Some(frame) if frame.kind == FrameKind::Audio => frame,
+                        Some(_) => continue,
+                        None => return Ok(()),
+                    };
+                    self.send_packet(&frame.payload, &mut datagram).await;
+                }
+                _ = rtcp_ticker.tick() => {
+                    if self.packets_sent > 0 {
+                        let report = self.sender_report();
+                        if let Err(err) = self.socket.send_to(&report, self.rtcp_destination).await {
+                            warn!("rtcp send failed: {}", err);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send_packet(&mut self, packet: &[u8], datagram: &mut BytesMut) {
+        if packet.len() != HEADER_LEN + PACKET_N_SAMPLE * self.n_ch * 2 {
+            return;
+        }
+        let planar = &packet[HEADER_LEN..];
+        let sample = |ch: usize, i: usize| {
+            let at = (ch * PACKET_N_SAMPLE + i) * 2;
+            i16::from_ne_bytes([planar[at], planar[at + 1]])
+        };
+        let ticks = (PACKET_N_SAMPLE * self.clock_rate() / self.sample_rate) as u32;
+
+        match &mut self.encoder {
+            Encoder::L16 => {
+                // split so every datagram fits the MTU; the marker stays clear, audio is continuous
+                let frames_per_datagram = (MAX_RTP_PAYLOAD / (2 * self.n_ch)).max(1);
+                let mut start = 0;
+                while start < PACKET_N_SAMPLE {
+                    let end = (start + frames_per_datagram).min(PACKET_N_SAMPLE);
+                    let timestamp = self.timestamp.wrapping_add(start as u32);
+                    self.put_header(datagram, timestamp);
+                    for i in start..end {
+                        for ch in 0..self.n_ch {
+                            datagram.put_i16(sample(ch, i));
+                        }
+                    }
+                    self.send(datagram).await;
+                    start = end;
+                }
+            }
+            #[cfg(feature = "opus")]
+            Encoder::Opus(encoder) => {
+                let mut interleaved = Vec::with_capacity(PACKET_N_SAMPLE * self.n_ch);
+                for i in 0..PACKET_N_SAMPLE {
+                    for ch in 0..self.n_ch {
+                        interleaved.push(sample(ch, i));
+                    }
+                }
+                let mut encoded = [0_u8; MAX_RTP_PAYLOAD];
+                match encoder.encode(&interleaved, &mut encoded) {
+                    Ok(len) => {
+                        let timestamp = self.timestamp;
+                        self.put_header(datagram, timestamp);
+                        datagram.extend_from_slice(&encoded[..len]);
+                        self.send(datagram).await;
+                    }
+                    Err(err) => warn!("opus encoding failed: {}", err),
+                }
+            }
+        }
+        self.timestamp = self.timestamp.wrapping_add(ticks);
+    }
+
+    fn put_header(&mut self, datagram: &mut BytesMut, timestamp: u32) {
+        datagram.clear();
+        datagram.put_u8(RTP_VERSION << 6);
+        datagram.put_u8(self.payload_type & 0x7f);
+        datagram.put_u16(self.seq);
+        datagram.put_u32(timestamp);
+        datagram.put_u32(self.ssrc);
+        self.seq = self.seq.wrapping_add(1);
+    }
+
+    async fn send(&mut self, datagram: &[u8]) {
+        match self.socket.send_to(datagram, self.destination).await {
+            Ok(_) => {
+                self.packets_sent = self.packets_sent.wrapping_add(1);
+                self.octets_sent = self
+                    .octets_sent
+                    .wrapping_add((datagram.len() - RTP_HEADER_LEN) as u32);
+            }
+            Err(err) => warn!("rtp send failed: {}", err),
+        }
+    }
+
+    // RTCP sender report (RFC 3550 6.4.1) tying the RTP timestamp to wall clock time.
+    fn sender_report(&self) -> BytesMut {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let ntp_secs = (now.as_secs() + NTP_UNIX_OFFSET) as u32;
+        let ntp_frac = ((now.subsec_nanos() as u64) << 32) / 1_000_000_000;
+
+        let mut report = BytesMut::with_capacity(28);
+        report.put_u8(RTP_VERSION << 6);
+        // packet type SR
+        report.put_u8(200);
+        // length in 32-bit words minus one
+        report.put_u16(6);
+        report.put_u32(self.ssrc);
+        report.put_u32(ntp_secs);
+        report.put_u32(ntp_frac as u32);
+        report.put_u32(self.timestamp);
+        report.put_u32(self.packets_sent);
+        report.put_u32(self.octets_sent);
+        report
+    }
+}
+
+// SDP (RFC 4566) for a stream sent to 'destination'.
+pub fn sdp(
+    destination: &SocketAddr,
+    payload_type: u8,
+    format: RtpFormat,
+    sample_rate: usize,
+    n_ch: usize,
+) -> String {
+    let ip_version = if destination.is_ipv4() { "IP4" } else { "IP6" };
+    let multicast_ttl = if destination.ip().is_multicast() && destination.is_ipv4() {
+        "/16"
+    } else {
+        ""
+    };
+    let rtpmap = match format {
+        RtpFormat::L16 => format!("L16/{}/{}", sample_rate, n_ch),
+        // opus is always announced as 48 kHz stereo, whatever is actually sent
+        RtpFormat::Opus => "opus/48000/2".to_string(),
+    };
+    let ptime = PACKET_N_SAMPLE * 1000 / sample_rate;
+    format!(
+        "v=0\r\n\
+         o=- 0 0 IN {ip} {addr}\r\n\
+         s=mic2net\r\n\
+         c=IN {ip} {addr}{ttl}\r\n\
+         t=0 0\r\n\
+         m=audio {port} RTP/AVP {pt}\r\n\
+         a=rtpmap:{pt} {rtpmap}\r\n\
+         a=ptime:{ptime}\r\n",
+        ip = ip_version,
+        addr = destination.ip(),
+        ttl = multicast_ttl,
+        port = destination.port(),
+        pt = payload_type,
+        rtpmap = rtpmap,
+        ptime = ptime,
+    )
+}
+
+// Run the rtp sender; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_rtp_sender(
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    n_ch: usize,
+    shutdown: impl Future,
+) {
+    let sdp_file = cfg.rtp.sdp_file.clone();
+    let mut sender = match RtpSender::new(cfg, distributor, n_ch).await {
+        Ok(sender) => sender,
+        Err(err) => {
+            error!("failed to start rtp sender: {}", err);
+            return;
+        }
+    };
+    if !sdp_file.is_empty() {
+        match std::fs::write(&sdp_file, sender.sdp()) {
+            Ok(()) => info!("wrote {}", sdp_file),
+            Err(err) => warn!("failed to write {}: {}", sdp_file, err),
+        }
+    }
+    tokio::select! {
+        res = sender.run() => {
+            if let Err(err) = res {
+                error!("rtp sender stopped: {}", err);
+            }
+        }
+        _ = shutdown => {
+            info!("stopping rtp sender");
+        }
+    }
+}
@@ -0,0 +1,357 @@
+// Minimal RTSP (RFC 2326) control server for the RTP output: players open
+// rtsp://host:port/<path>, get the SDP with DESCRIBE and start a unicast RTP/UDP
+// session with SETUP and PLAY. Interleaved (RTP over the RTSP connection) isn't supported.
+use crate::config_file::Config;
+use crate::distributor::Distributor;
+use crate::http::{read_request_from, Request};
+use crate::rtp::{sdp, RtpSender};
+use crate::tcp_server::accept_with_backoff;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tracing::{error, info, info_span, warn, Instrument};
+
+const PUBLIC_METHODS: &str = "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN";
+// RFC 2326's default session timeout; players send a keep-alive request well within it
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct RtspServer {
+    cfg: Arc<Config>,
+    listener: TcpListener,
+    limit_connections: Arc<Semaphore>,
+    distributor: Arc<Distributor>,
+    n_ch: usize,
+    next_client_id: u64,
+}
+
+impl RtspServer {
+    pub async fn new(
+        cfg: Arc<Config>,
+        distributor: Arc<Distributor>,
+        n_ch: usize,
+    ) -> crate::Result<RtspServer> {
+        let listener = TcpListener::bind((cfg.rtsp.bind_address.as_str(), cfg.rtsp.listen_port))
+            .await
+            .map_err(crate::Error::bind(format!(
+                "{}:{}",
+                cfg.rtsp.bind_address, cfg.rtsp.listen_port
+            )))?;
+        Ok(RtspServer {
+            limit_connections: Arc::new(Semaphore::new(cfg.rtsp.max_clients.into())),
+            cfg,
+            listener,
+            distributor,
+            n_ch,
+            next_client_id: 0,
+        })
+    }
+
+    async fn run(&mut self) -> crate::Result<()> {
+        info!(
+            "rtsp on rtsp://{}:{}/{}",
+            self.cfg.rtsp.bind_address, self.cfg.rtsp.listen_port, self.cfg.rtsp.path
+        );
+        loop {
+            let permit = self
+                .limit_connections
+                .clone()
+                .acquire_owned()
+                .await
+                .unwrap();
+            let (socket, peer) = accept_with_backoff(&self.listener).await?;
+            let mut session = RtspSession {
+                cfg: self.cfg.clone(),
+                distributor: self.distributor.clone(),
+                n_ch: self.n_ch,
+                peer: peer.ip(),
+                id: session_id(),
+                sender: None,
+                playing: None,
+            };
+            let span = info_span!("rtsp_client", peer = %peer, id = self.next_client_id);
+            self.next_client_id += 1;
+
+            let connection = async move {
+                if let Err(err) = session.run(socket).await {
+                    error!("rtsp connection error: {}", err);
+                }
+                session.stop();
+                drop(permit);
+            };
+            tokio::spawn(connection.instrument(span));
+        }
+    }
+}
+
+// State of one RTSP connection; the RTP session ends with the connection.
+struct RtspSession {
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    n_ch: usize,
+    peer: IpAddr,
+    id: String,
+    // set up but not playing yet
+    sender: Option<RtpSender>,
+    playing: Option<JoinHandle<()>>,
+}
+
+impl RtspSession {
+    async fn run(&mut self, mut socket: TcpStream) -> crate::Result<()> {
+        // pipelined requests wait here for their turn
+        let mut buf = Vec::with_capacity(1024);
+        while let Some(request) = read_request_from(&mut socket, &mut buf, SESSION_TIMEOUT).await? {
+            let cseq = request.header("cseq").unwrap_or("0").to_string();
+            let (status, headers, body) = match self.handle(&request).await {
+                Ok(response) => response,
+                Err(err) => {
+                    warn!("{} failed: {}", request.method, err);
+                    ("400 Bad Request", Vec::new(), String::new())
+                }
+            };
+            let mut response = format!("RTSP/1.0 {}\r\nCSeq: {}\r\n", status, cseq);
+            for (name, value) in headers {
+                response.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            if !body.is_empty() {
+                response.push_str(&format!("Content-Length: {}\r\n", body.len()));
+            }
+            response.push_str("\r\n");
+            response.push_str(&body);
+            socket.write_all(response.as_bytes()).await?;
+            if request.method == "TEARDOWN" {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle(
+        &mut self,
+        request: &Request,
+    ) -> crate::Result<(&'static str, Vec<(&'static str, String)>, String)> {
+        if request.method != "OPTIONS" && !is_our_url(&request.path, &self.cfg.rtsp.path) {
+            return Ok(("404 Not Found", Vec::new(), String::new()));
+        }
+        let session = ("Session", self.id.clone());
+        match request.method.as_str() {
+            "OPTIONS" => Ok((
+                "200 OK",
+                vec![("Public", PUBLIC_METHODS.to_string())],
+                String::new(),
+            )),
+            "DESCRIBE" => {
+                let sample_rate = self.cfg.rtp.sample_rate.unwrap_or(self.cfg.mic.sample_rate);
+                let unspecified = SocketAddr::new(IpAddr::from([0, 0, 0, 0]), 0);
+                let mut body = sdp(
+                    &unspecified,
+                    self.cfg.rtp.payload_type,
+                    self.cfg.rtp.format,
+                    sample_rate,
+                    self.n_ch,
+                );
+                body.push_str("a=control:*\r\n");
+                Ok((
+                    "200 OK",
+                    vec![
+                        ("Content-Type", "application/sdp".to_string()),
+                        ("Content-Base", format!("{}/", request.path)),
+                    ],
+                    body,
+                ))
+            }
+            "SETUP" => {
+                let transport = request.header("transport").unwrap_or_default();
+                let client_port = match client_port(transport) {
+                    Some(port) => port,
+                    None => return Ok(("461 Unsupported Transport", vec![session], String::new())),
+                };
+                let destination = SocketAddr::new(self.peer, client_port);
+                let sender = RtpSender::with_destination(
+                    self.cfg.clone(),
+                    self.distributor.clone(),
+                    self.n_ch,
+                    destination,
+                )
+                .await?;
+                let server_port = sender.local_port()?;
+                self.sender = Some(sender);
+                let transport = format!(
+                    "RTP/AVP;unicast;client_port={}-{};server_port={}-{}",
+                    client_port,
+                    client_port + 1,
+                    server_port,
+                    server_port + 1
+                );
+                Ok((
+                    "200 OK",
+                    vec![("Transport", transport), session],
+                    String::new(),
+                ))
+            }
+            "PLAY" => {
+                let mut sender = match self.sender.take() {
+                    Some(sender) => sender,
+                    None if self.playing.is_some() => {
+                        return Ok(("200 OK", vec![session], String::new()))
+                    }
+                    None => {
+                        return Ok((
+                            "455 Method Not Valid in This State",
+                            vec![session],
+                            String::new(),
+                        ))
+                    }
+                };
+                info!("playing to {}", self.peer);
+                self.playing = Some(tokio::spawn(
+                    async move {
+                        if let Err(err) = sender.run().await {
+                            error!("rtp session stopped: {}", err);
+                        }
+                    }
+                    .in_current_span(),
+                ));
+                Ok((
+                    "200 OK",
+                    vec![session, ("Range", "npt=now-".to_string())],
+                    String::new(),
+                ))
+            }
+            "TEARDOWN" => {
+                self.stop();
+                Ok(("200 OK", vec![session], String::new()))
+            }
+            _ => Ok(("501 Not Implemented", Vec::new(), String::new())),
+        }
+    }
+
+    fn stop(&mut self) {
+        self.sender = None;
+        if let Some(playing) = self.playing.take() {
+            playing.abort();
+            info!("rtp session ended");
+        }
+    }
+}
+
+// Whether 'url' is rtsp://host:port/<path>, optionally with a trailing track control
+// suffix.
+fn is_our_url(url: &str, path: &str) -> bool {
+    let requested = url
+        .strip_prefix("rtsp://")
+        .and_then(|rest| rest.split_once('/'))
+        .map(|(_, path)| path)
+        .unwrap_or(url.trim_start_matches('/'));
+    let requested = requested.trim_end_matches('/');
+    requested == path || requested.trim_end_matches("/*") == path
+}
+
+// RTP port of the first "RTP/AVP;unicast;client_port=5000-5001" transport among those
+// the player offers; RTCP goes to the odd port after it, so it has to be even.
+fn client_port(transports: &str) -> Option<u16> {
+    transports
+        .split(',')
+        .filter(|transport| !transport.trim().starts_with("RTP/AVP/TCP"))
+        .find_map(|transport| {
+            let port: u16 = transport
+                .split(';')
+                .find_map(|param| param.trim().strip_prefix("client_port="))?
+                .split('-')
+                .next()?
+                .parse()
+                .ok()?;
+            port.is_multiple_of(2).then_some(port)
+        })
+}
+
+fn session_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    format!("{:016x}", nanos as u64 ^ 0x6d69_6332_6e65_7400)
+}
+
+// Run the rtsp server; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_rtsp_server(
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    n_ch: usize,
+    shutdown: impl Future,
+) {
+    let mut server = match RtspServer::new(cfg, distributor, n_ch).await {
+        Ok(server) => server,
+        Err(err) => {
+            error!("failed to start rtsp server: {}", err);
+            return;
+        }
+    };
+    tokio::select! {
+        res = server.run() => {
+            if let Err(err) = res {
+                error!("failed to accept rtsp connection: {}", err);
+            }
+        }
+        _ = shutdown => {
+            info!("cleaning up rtsp server");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_ports() {
+        assert_eq!(
+            client_port("RTP/AVP;unicast;client_port=5000-5001"),
+            Some(5000)
+        );
+        assert_eq!(client_port("RTP/AVP;unicast;client_port=5000"), Some(5000));
+        assert_eq!(
+            client_port("RTP/AVP/UDP;unicast;client_port=65534-65535"),
+            Some(65534)
+        );
+        // rtcp wouldn't fit after it
+        assert_eq!(client_port("RTP/AVP;unicast;client_port=65535"), None);
+        assert_eq!(client_port("RTP/AVP;unicast;client_port=5001-5002"), None);
+        assert_eq!(client_port("RTP/AVP;unicast;client_port=70000-70001"), None);
+        assert_eq!(client_port("RTP/AVP;unicast;client_port=-5001"), None);
+        assert_eq!(client_port("RTP/AVP;unicast"), None);
+        assert_eq!(client_port(""), None);
+    }
+
+    #[test]
+    fn client_port_of_the_first_udp_transport() {
+        // as ffmpeg offers them when told to prefer tcp
+        let transports = "RTP/AVP/TCP;unicast;interleaved=0-1,\
+                          RTP/AVP;unicast;client_port=6970-6971";
+        assert_eq!(client_port(transports), Some(6970));
+        let transports = "RTP/AVP;unicast;client_port=6972-6973,\
+                          RTP/AVP;unicast;client_port=6970-6971";
+        assert_eq!(client_port(transports), Some(6972));
+        assert_eq!(client_port("RTP/AVP/TCP;unicast;interleaved=0-1"), None);
+    }
+
+    #[test]
+    fn our_urls() {
+        assert!(is_our_url("rtsp://192.168.1.5:8554/live", "live"));
+        assert!(is_our_url("rtsp://192.168.1.5:8554/live/", "live"));
+        assert!(is_our_url("rtsp://192.168.1.5:8554/live/*", "live"));
+        assert!(is_our_url("/live", "live"));
+        assert!(is_our_url("rtsp://host/room/a", "room/a"));
+        assert!(!is_our_url("rtsp://192.168.1.5:8554/other", "live"));
+        assert!(!is_our_url("rtsp://192.168.1.5:8554/live2", "live"));
+        assert!(!is_our_url(
+            "rtsp://192.168.1.5:8554/live/trackID=1",
+            "live"
+        ));
+        assert!(!is_our_url("rtsp://192.168.1.5:8554", "live"));
+    }
+}
@@ -0,0 +1,650 @@
+#[cfg(feature = "aac")]
+use crate::audio::aac::AacFramer;
+#[cfg(feature = "opus")]
+use crate::audio::opus::OpusFramer;
+use crate::config_file::Config;
+use crate::distributor::{Distributor, DropPolicy, Subscription};
+use crate::protocol::FrameKind;
+use crate::sink::HlsCodec;
+use crate::tcp_client::AudioPacket;
+use std::collections::VecDeque;
+use std::fs;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+// opus in mp4 always uses a 48 kHz timescale; aac runs at the same rate
+pub const HLS_SAMPLE_RATE: usize = 48000;
+const HLS_QUEUE_LEN: usize = 200;
+const PLAYLIST: &str = "index.m3u8";
+const INIT_SEGMENT: &str = "init.mp4";
+const TRACK_ID: u32 = 1;
+
+// Writes the stream as HLS to 'hls.directory': an fMP4 init segment, Opus or AAC media
+// segments of 'segment_duration' seconds and a live playlist of the last 'window'
+// of them. Older segments are deleted.
+pub struct HlsSink {
+    directory: PathBuf,
+    encoder: SegmentEncoder,
+    // encoded frames per segment
+    frames_per_segment: usize,
+    frames: Subscription,
+    // encoded frames of the segment in progress
+    pending: Vec<Vec<u8>>,
+    playlist: Playlist,
+}
+
+impl HlsSink {
+    // 'distributor' has to carry the stream at 'HLS_SAMPLE_RATE'.
+    pub fn new(
+        cfg: Arc<Config>,
+        distributor: Arc<Distributor>,
+        n_ch: usize,
+    ) -> crate::Result<HlsSink> {
+        let directory = PathBuf::from(&cfg.hls.directory);
+        fs::create_dir_all(&directory)?;
+        let encoder = SegmentEncoder::new(cfg.hls.codec, cfg.hls.n_channel.min(n_ch))?;
+        let frames_per_segment =
+            (cfg.hls.segment_duration as usize * HLS_SAMPLE_RATE / encoder.frame_len()).max(1);
+        Ok(HlsSink {
+            directory,
+            encoder,
+            frames_per_segment,
+            frames: distributor.subscribe(HLS_QUEUE_LEN, DropPolicy::DropNewest),
+            pending: Vec::with_capacity(frames_per_segment),
+            playlist: Playlist::new(cfg.hls.window),
+        })
+    }
+
+    async fn run(&mut self) -> crate::Result<()> {
+        info!("hls to {}", self.directory.join(PLAYLIST).display());
+        let sample_entry = self.encoder.sample_entry()?;
+        let init = init_segment(&sample_entry, self.encoder.frame_len());
+        self.write_file(INIT_SEGMENT, &init)?;
+
+        while let Some(frame) = self.frames.recv().await {
+            if frame.kind != FrameKind::Audio {
+                continue;
+            }
+            let packet = match AudioPacket::parse(&frame.payload) {
+                Ok(packet) => packet,
+                Err(_) => continue,
+            };
+            self.encoder.push(&packet);
+            while let Some(encoded) = self.encoder.next_frame()? {
+                self.pending.push(encoded);
+                if self.pending.len() >= self.frames_per_segment {
+                    self.finish_segment()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish_segment(&mut self) -> crate::Result<()> {
+        let (seq, decode_time) = (self.playlist.next_segment, self.playlist.decode_time);
+        let frame_len = self.encoder.frame_len();
+        let segment = media_segment(seq as u32 + 1, decode_time, frame_len, &self.pending);
+        self.write_file(&segment_name(seq), &segment)?;
+
+        let n_samples = (self.pending.len() * frame_len) as u64;
+        self.pending.clear();
+        for old in self.playlist.push(n_samples) {
+            if let Err(err) = fs::remove_file(self.directory.join(segment_name(old))) {
+                warn!("failed to remove old segment: {}", err);
+            }
+        }
+        let playlist = self.playlist.render();
+        self.write_file(PLAYLIST, playlist.as_bytes())
+    }
+
+    // Write through a temporary file so players never fetch a half-written one.
+    fn write_file(&self, name: &str, contents: &[u8]) -> crate::Result<()> {
+        let path = self.directory.join(name);
+        let tmp = self.directory.join(format!("{}.tmp", name));
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+// The live playlist: the last 'window' segments, and where the next one starts.
+struct Playlist {
+    window: usize,
+    // sequence numbers and durations of the segments in the playlist
+    segments: VecDeque<(u64, f64)>,
+    next_segment: u64,
+    // in samples at 'HLS_SAMPLE_RATE'
+    decode_time: u64,
+}
+
+impl Playlist {
+    fn new(window: usize) -> Playlist {
+        Playlist {
+            window: window.max(1),
+            segments: VecDeque::new(),
+            next_segment: 0,
+            decode_time: 0,
+        }
+    }
+
+    // Add the next segment, 'n_samples' long; returns the segments that fell out of the
+    // window.
+    fn push(&mut self, n_samples: u64) -> Vec<u64> {
+        self.segments
+            .push_back((self.next_segment, n_samples as f64 / HLS_SAMPLE_RATE as f64));
+        self.next_segment += 1;
+        self.decode_time += n_samples;
+        let n_old = self.segments.len().saturating_sub(self.window);
+        self.segments.drain(..n_old).map(|(seq, _)| seq).collect()
+    }
+
+    fn render(&self) -> String {
+        let target = self
+            .segments
+            .iter()
+            .map(|(_, duration)| duration.ceil() as u64)
+            .max()
+            .unwrap_or(1);
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n\
+             #EXT-X-INDEPENDENT-SEGMENTS\n#EXT-X-MAP:URI=\"{}\"\n",
+            target,
+            self.segments.front().map(|(seq, _)| *seq).unwrap_or(0),
+            INIT_SEGMENT
+        );
+        for (seq, duration) in &self.segments {
+            playlist.push_str(&format!(
+                "#EXTINF:{:.3},\n{}\n",
+                duration,
+                segment_name(*seq)
+            ));
+        }
+        playlist
+    }
+}
+
+enum SegmentEncoder {
+    #[cfg(feature = "opus")]
+    Opus(OpusFramer),
+    #[cfg(feature = "aac")]
+    Aac(AacFramer),
+}
+
+impl SegmentEncoder {
+    fn new(codec: HlsCodec, n_ch: usize) -> crate::Result<SegmentEncoder> {
+        match codec {
+            #[cfg(feature = "opus")]
+            HlsCodec::Opus => Ok(SegmentEncoder::Opus(OpusFramer::new(
+                HLS_SAMPLE_RATE,
+                n_ch,
+                opus::Application::Audio,
+            )?)),
+            #[cfg(not(feature = "opus"))]
+            HlsCodec::Opus => Err("hls opus needs a build with --features opus".into()),
+            #[cfg(feature = "aac")]
+            HlsCodec::Aac => Ok(SegmentEncoder::Aac(AacFramer::new(
+                HLS_SAMPLE_RATE,
+                n_ch,
+                fdk_aac::enc::Transport::Raw,
+            )?)),
+            #[cfg(not(feature = "aac"))]
+            HlsCodec::Aac => Err("hls aac needs a build with --features aac".into()),
+        }
+    }
+
+    fn frame_len(&self) -> usize {
+        match self {
+            #[cfg(feature = "opus")]
+            SegmentEncoder::Opus(framer) => framer.frame_len(),
+            #[cfg(feature = "aac")]
+            SegmentEncoder::Aac(framer) => framer.frame_len(),
+        }
+    }
+
+    fn push(&mut self, packet: &AudioPacket) {
+        match self {
+            #[cfg(feature = "opus")]
+            SegmentEncoder::Opus(framer) => framer.push(packet),
+            #[cfg(feature = "aac")]
+            SegmentEncoder::Aac(framer) => framer.push(packet),
+        }
+    }
+
+    fn next_frame(&mut self) -> crate::Result<Option<Vec<u8>>> {
+        match self {
+            #[cfg(feature = "opus")]
+            SegmentEncoder::Opus(framer) => framer.next_frame(),
+            #[cfg(feature = "aac")]
+            SegmentEncoder::Aac(framer) => framer.next_frame(),
+        }
+    }
+
+    // The stsd entry describing the track.
+    fn sample_entry(&mut self) -> crate::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "opus")]
+            SegmentEncoder::Opus(framer) => {
+                let n_ch = framer.n_channel();
+                Ok(opus_sample_entry(n_ch, framer.lookahead()?))
+            }
+            #[cfg(feature = "aac")]
+            SegmentEncoder::Aac(framer) => Ok(aac_sample_entry(
+                framer.n_channel(),
+                framer.bitrate(),
+                &framer.audio_specific_config()?,
+            )),
+        }
+    }
+}
+
+fn segment_name(seq: u64) -> String {
+    format!("segment-{}.m4s", seq)
+}
+
+// ISO BMFF box: size, type, contents.
+fn mp4_box(kind: &[u8; 4], parts: &[&[u8]]) -> Vec<u8> {
+    let len: usize = 8 + parts.iter().map(|part| part.len()).sum::<usize>();
+    let mut out = Vec::with_capacity(len);
+    out.extend_from_slice(&(len as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    for part in parts {
+        out.extend_from_slice(part);
+    }
+    out
+}
+
+// Full box: a box starting with version and flags.
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, parts: &[&[u8]]) -> Vec<u8> {
+    let mut header = flags.to_be_bytes();
+    header[0] = version;
+    let mut all = vec![header.as_slice()];
+    all.extend_from_slice(parts);
+    mp4_box(kind, &all)
+}
+
+const IDENTITY_MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+fn be32(values: &[u32]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .collect()
+}
+
+// Generic audio sample entry of type 'kind', followed by its codec configuration box.
+fn audio_sample_entry(kind: &[u8; 4], n_ch: usize, config: &[u8]) -> Vec<u8> {
+    let mut sample_entry = vec![0; 6];
+    // data reference index
+    sample_entry.extend_from_slice(&1_u16.to_be_bytes());
+    sample_entry.extend_from_slice(&[0; 8]);
+    sample_entry.extend_from_slice(&(n_ch as u16).to_be_bytes());
+    sample_entry.extend_from_slice(&16_u16.to_be_bytes());
+    sample_entry.extend_from_slice(&[0; 4]);
+    sample_entry.extend_from_slice(&((HLS_SAMPLE_RATE as u32) << 16).to_be_bytes());
+    mp4_box(kind, &[&sample_entry, config])
+}
+
+// "Encapsulation of Opus in ISO Base Media File Format".
+#[cfg(feature = "opus")]
+fn opus_sample_entry(n_ch: usize, pre_skip: usize) -> Vec<u8> {
+    let mut d_ops = vec![0, n_ch as u8];
+    d_ops.extend_from_slice(&(pre_skip as u16).to_be_bytes());
+    d_ops.extend_from_slice(&(HLS_SAMPLE_RATE as u32).to_be_bytes());
+    // output gain, channel mapping family
+    d_ops.extend_from_slice(&[0, 0, 0]);
+    let d_ops = mp4_box(b"dOps", &[&d_ops]);
+    audio_sample_entry(b"Opus", n_ch, &d_ops)
+}
+
+// MPEG-4 audio (ISO/IEC 14496-14): an esds box wrapping the AudioSpecificConfig.
+#[cfg(feature = "aac")]
+fn aac_sample_entry(n_ch: usize, bitrate: u32, audio_specific_config: &[u8]) -> Vec<u8> {
+    // descriptors are short enough for one-byte lengths
+    let descriptor = |tag: u8, parts: &[&[u8]]| {
+        let contents = parts.concat();
+        let mut out = vec![tag, contents.len() as u8];
+        out.extend_from_slice(&contents);
+        out
+    };
+    let decoder_specific = descriptor(0x05, &[audio_specific_config]);
+    // object type mpeg-4 audio, stream type audio, buffer size, max and average bitrate
+    let decoder_config = descriptor(
+        0x04,
+        &[
+            &[0x40, 0x15, 0, 0, 0],
+            &be32(&[bitrate, bitrate]),
+            &decoder_specific,
+        ],
+    );
+    // predefined "mp4" sync layer config
+    let sync_layer = descriptor(0x06, &[&[0x02]]);
+    let es = descriptor(
+        0x03,
+        &[
+            &(TRACK_ID as u16).to_be_bytes(),
+            &[0],
+            &decoder_config,
+            &sync_layer,
+        ],
+    );
+    let esds = full_box(b"esds", 0, 0, &[&es]);
+    audio_sample_entry(b"mp4a", n_ch, &esds)
+}
+
+// ftyp + moov describing one audio track with 'sample_entry' as its only stsd entry.
+fn init_segment(sample_entry: &[u8], frame_len: usize) -> Vec<u8> {
+    let timescale = HLS_SAMPLE_RATE as u32;
+    let ftyp = mp4_box(b"ftyp", &[b"iso6", &0_u32.to_be_bytes(), b"iso6mp41"]);
+
+    let mut mvhd = be32(&[0, 0, timescale, 0, 0x0001_0000]);
+    mvhd.extend_from_slice(&0x0100_u16.to_be_bytes());
+    mvhd.extend_from_slice(&[0; 10]);
+    mvhd.extend_from_slice(&be32(&IDENTITY_MATRIX));
+    mvhd.extend_from_slice(&[0; 24]);
+    mvhd.extend_from_slice(&(TRACK_ID + 1).to_be_bytes());
+    let mvhd = full_box(b"mvhd", 0, 0, &[&mvhd]);
+
+    let mut tkhd = be32(&[0, 0, TRACK_ID, 0, 0, 0, 0]);
+    // layer, alternate group
+    tkhd.extend_from_slice(&[0; 4]);
+    tkhd.extend_from_slice(&0x0100_u16.to_be_bytes());
+    tkhd.extend_from_slice(&[0; 2]);
+    tkhd.extend_from_slice(&be32(&IDENTITY_MATRIX));
+    tkhd.extend_from_slice(&be32(&[0, 0]));
+    // enabled, in movie
+    let tkhd = full_box(b"tkhd", 0, 3, &[&tkhd]);
+
+    let mut mdhd = be32(&[0, 0, timescale, 0]);
+    // language "und"
+    mdhd.extend_from_slice(&0x55c4_u16.to_be_bytes());
+    mdhd.extend_from_slice(&[0; 2]);
+    let mdhd = full_box(b"mdhd", 0, 0, &[&mdhd]);
+    let hdlr = full_box(
+        b"hdlr",
+        0,
+        0,
+        &[&[0; 4], b"soun", &[0; 12], b"SoundHandler\0"],
+    );
+
+    let stsd = full_box(b"stsd", 0, 0, &[&1_u32.to_be_bytes(), sample_entry]);
+    // fragmented: the sample tables of the init segment stay empty
+    let stts = full_box(b"stts", 0, 0, &[&be32(&[0])]);
+    let stsc = full_box(b"stsc", 0, 0, &[&be32(&[0])]);
+    let stsz = full_box(b"stsz", 0, 0, &[&be32(&[0, 0])]);
+    let stco = full_box(b"stco", 0, 0, &[&be32(&[0])]);
+    let stbl = mp4_box(b"stbl", &[&stsd, &stts, &stsc, &stsz, &stco]);
+    let smhd = full_box(b"smhd", 0, 0, &[&[0; 4]]);
+    let url = full_box(b"url ", 0, 1, &[]);
+    let dref = full_box(b"dref", 0, 0, &[&1_u32.to_be_bytes(), &url]);
+    let dinf = mp4_box(b"dinf", &[&dref]);
+    let minf = mp4_box(b"minf", &[&smhd, &dinf, &stbl]);
+    let mdia = mp4_box(b"mdia", &[&mdhd, &hdlr, &minf]);
+    let trak = mp4_box(b"trak", &[&tkhd, &mdia]);
+
+    let trex = full_box(
+        b"trex",
+        0,
+        0,
+        &[&be32(&[TRACK_ID, 1, frame_len as u32, 0, 0])],
+    );
+    let mvex = mp4_box(b"mvex", &[&trex]);
+    let moov = mp4_box(b"moov", &[&mvhd, &trak, &mvex]);
+    [ftyp, moov].concat()
+}
+
+// moof + mdat holding 'frames', which start at 'decode_time' samples.
+fn media_segment(sequence: u32, decode_time: u64, frame_len: usize, frames: &[Vec<u8>]) -> Vec<u8> {
+    let mfhd = full_box(b"mfhd", 0, 0, &[&sequence.to_be_bytes()]);
+    // default-base-is-moof
+    let tfhd = full_box(b"tfhd", 0, 0x02_0000, &[&TRACK_ID.to_be_bytes()]);
+    let tfdt = full_box(b"tfdt", 1, 0, &[&decode_time.to_be_bytes()]);
+
+    let mut samples = Vec::with_capacity(frames.len() * 8);
+    for frame in frames {
+        samples.extend_from_slice(&be32(&[frame_len as u32, frame.len() as u32]));
+    }
+    // the data offset points past the moof and the mdat header; trun's size doesn't
+    // depend on its value, so build it once with a placeholder to learn the moof size
+    let trun = |data_offset: u32| {
+        full_box(
+            b"trun",
+            0,
+            // data offset, sample duration and size present
+            0x00_0301,
+            &[
+                &(frames.len() as u32).to_be_bytes(),
+                &data_offset.to_be_bytes(),
+                &samples,
+            ],
+        )
+    };
+    let moof_len = 8 + mfhd.len() + 8 + tfhd.len() + tfdt.len() + trun(0).len();
+    let traf = mp4_box(b"traf", &[&tfhd, &tfdt, &trun(moof_len as u32 + 8)]);
+    let moof = mp4_box(b"moof", &[&mfhd, &traf]);
+
+    let data = frames.concat();
+    let mdat = mp4_box(b"mdat", &[&data]);
+    [moof, mdat].concat()
+}
+
+// Run the hls sink; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_hls_sink(
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    n_ch: usize,
+    shutdown: impl Future,
+) {
+    let mut sink = match HlsSink::new(cfg, distributor, n_ch) {
+        Ok(sink) => sink,
+        Err(err) => {
+            error!("failed to start hls sink: {}", err);
+            return;
+        }
+    };
+    tokio::select! {
+        res = sink.run() => {
+            if let Err(err) = res {
+                error!("hls sink stopped: {}", err);
+            }
+        }
+        _ = shutdown => {
+            info!("cleaning up hls sink");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_LEN: usize = 960;
+
+    // The boxes 'data' consists of, as type and contents; fails unless their sizes add
+    // up to exactly its length.
+    fn boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut out = Vec::new();
+        while !data.is_empty() {
+            assert!(data.len() >= 8, "box header cut short");
+            let len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            assert!(
+                len >= 8 && len <= data.len(),
+                "box size {} of {}",
+                len,
+                data.len()
+            );
+            out.push((data[4..8].try_into().unwrap(), &data[8..len]));
+            data = &data[len..];
+        }
+        out
+    }
+
+    fn kinds(boxes: &[([u8; 4], &[u8])]) -> Vec<String> {
+        boxes
+            .iter()
+            .map(|(kind, _)| String::from_utf8_lossy(kind).into_owned())
+            .collect()
+    }
+
+    fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> &'a [u8] {
+        boxes(data)
+            .into_iter()
+            .find(|(found, _)| found == kind)
+            .unwrap_or_else(|| panic!("no {}", String::from_utf8_lossy(kind)))
+            .1
+    }
+
+    fn be32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    fn sample_entry() -> Vec<u8> {
+        let d_ops = mp4_box(b"dOps", &[&[0, 2, 0x01, 0x38, 0, 0, 0xbb, 0x80, 0, 0, 0]]);
+        audio_sample_entry(b"Opus", 2, &d_ops)
+    }
+
+    fn frames() -> Vec<Vec<u8>> {
+        vec![vec![1; 100], vec![2; 37], vec![3; 255]]
+    }
+
+    #[test]
+    fn init_segment_boxes() {
+        let init = init_segment(&sample_entry(), FRAME_LEN);
+        let top = boxes(&init);
+        assert_eq!(kinds(&top), ["ftyp", "moov"]);
+        let moov = top[1].1;
+        assert_eq!(kinds(&boxes(moov)), ["mvhd", "trak", "mvex"]);
+
+        let trak = child(moov, b"trak");
+        assert_eq!(kinds(&boxes(trak)), ["tkhd", "mdia"]);
+        let mdia = child(trak, b"mdia");
+        assert_eq!(kinds(&boxes(mdia)), ["mdhd", "hdlr", "minf"]);
+        // timescale
+        assert_eq!(be32_at(child(mdia, b"mdhd"), 12), HLS_SAMPLE_RATE as u32);
+        let stbl = child(child(mdia, b"minf"), b"stbl");
+        assert_eq!(
+            kinds(&boxes(stbl)),
+            ["stsd", "stts", "stsc", "stsz", "stco"]
+        );
+        let stsd = child(stbl, b"stsd");
+        assert_eq!(be32_at(stsd, 4), 1);
+        assert_eq!(&stsd[8..], &sample_entry()[..]);
+        let entries = boxes(&stsd[8..]);
+        assert_eq!(kinds(&entries), ["Opus"]);
+        // 28 bytes of audio sample entry, then the codec configuration
+        assert_eq!(kinds(&boxes(&entries[0].1[28..])), ["dOps"]);
+
+        let trex = child(child(moov, b"mvex"), b"trex");
+        // track id, description index, default duration
+        assert_eq!(be32_at(trex, 4), TRACK_ID);
+        assert_eq!(be32_at(trex, 12), FRAME_LEN as u32);
+    }
+
+    #[cfg(feature = "aac")]
+    #[test]
+    fn esds_descriptor_lengths() {
+        // AAC-LC, 48 kHz, stereo
+        let entry = aac_sample_entry(2, 128_000, &[0x11, 0x90]);
+        let entries = boxes(&entry);
+        assert_eq!(kinds(&entries), ["mp4a"]);
+        let esds = child(&entries[0].1[28..], b"esds");
+        let es = &esds[4..];
+        // every descriptor's length covers exactly what follows it
+        assert_eq!((es[0], es[1] as usize), (0x03, es.len() - 2));
+        let decoder_config = &es[5..];
+        assert_eq!(decoder_config[0], 0x04);
+        let decoder_config_len = decoder_config[1] as usize;
+        assert_eq!(&decoder_config[2..4], [0x40, 0x15]);
+        let decoder_specific = &decoder_config[2 + 13..];
+        assert_eq!(decoder_specific[..4], [0x05, 2, 0x11, 0x90]);
+        assert_eq!(decoder_config_len, 13 + 4);
+        let sync_layer = &decoder_config[2 + decoder_config_len..];
+        assert_eq!(sync_layer, [0x06, 1, 0x02]);
+    }
+
+    #[test]
+    fn media_segment_boxes_and_offsets() {
+        let frames = frames();
+        let segment = media_segment(7, 123_456, FRAME_LEN, &frames);
+        let top = boxes(&segment);
+        assert_eq!(kinds(&top), ["moof", "mdat"]);
+        let (moof, mdat) = (top[0].1, top[1].1);
+        assert_eq!(mdat, &frames.concat()[..]);
+
+        assert_eq!(kinds(&boxes(moof)), ["mfhd", "traf"]);
+        assert_eq!(be32_at(child(moof, b"mfhd"), 4), 7);
+        let traf = child(moof, b"traf");
+        assert_eq!(kinds(&boxes(traf)), ["tfhd", "tfdt", "trun"]);
+        let tfdt = child(traf, b"tfdt");
+        assert_eq!(tfdt[0], 1);
+        assert_eq!(u64::from_be_bytes(tfdt[4..12].try_into().unwrap()), 123_456);
+
+        let trun = child(traf, b"trun");
+        assert_eq!(be32_at(trun, 0), 0x00_0301);
+        assert_eq!(be32_at(trun, 4), frames.len() as u32);
+        // relative to the start of the moof: the first byte of the mdat's contents
+        let data_offset = be32_at(trun, 8) as usize;
+        assert_eq!(data_offset, 8 + moof.len() + 8);
+        let mut at = data_offset;
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(be32_at(trun, 12 + 8 * i), FRAME_LEN as u32);
+            let size = be32_at(trun, 16 + 8 * i) as usize;
+            assert_eq!(&segment[at..at + size], &frame[..]);
+            at += size;
+        }
+        assert_eq!(at, segment.len());
+        assert_eq!(trun.len(), 12 + 8 * frames.len());
+    }
+
+    #[test]
+    fn long_frames_keep_sizes_exact() {
+        let frames = vec![vec![9; 70_000]];
+        let segment = media_segment(1, 0, FRAME_LEN, &frames);
+        let top = boxes(&segment);
+        assert_eq!(top[1].1.len(), 70_000);
+    }
+
+    #[test]
+    fn decode_times_follow_on() {
+        let mut playlist = Playlist::new(3);
+        let mut last = None;
+        for n_frames in [50, 49, 51, 50] {
+            let decode_time = playlist.decode_time;
+            let frames = vec![vec![0; 10]; n_frames];
+            let segment = media_segment(1, decode_time, FRAME_LEN, &frames);
+            let traf = child(boxes(&segment)[0].1, b"traf");
+            let tfdt = child(traf, b"tfdt");
+            let time = u64::from_be_bytes(tfdt[4..12].try_into().unwrap());
+            if let Some((last_time, last_samples)) = last {
+                assert_eq!(time, last_time + last_samples);
+            }
+            let n_samples = (n_frames * FRAME_LEN) as u64;
+            playlist.push(n_samples);
+            last = Some((time, n_samples));
+        }
+        assert_eq!(playlist.decode_time, 200 * FRAME_LEN as u64);
+    }
+
+    #[test]
+    fn playlist_window_rolls_over() {
+        let mut playlist = Playlist::new(3);
+        let segment = (2 * HLS_SAMPLE_RATE) as u64;
+        for _ in 0..3 {
+            assert!(playlist.push(segment).is_empty());
+        }
+        assert!(playlist.render().contains("#EXT-X-MEDIA-SEQUENCE:0\n"));
+        assert_eq!(playlist.push(segment), [0]);
+        assert_eq!(playlist.push(segment), [1]);
+
+        let rendered = playlist.render();
+        assert!(rendered.contains("#EXT-X-MEDIA-SEQUENCE:2\n"));
+        assert!(rendered.contains("#EXT-X-TARGETDURATION:2\n"));
+        let listed: Vec<_> = rendered
+            .lines()
+            .filter(|line| line.ends_with(".m4s"))
+            .collect();
+        assert_eq!(listed, ["segment-2.m4s", "segment-3.m4s", "segment-4.m4s"]);
+        assert_eq!(rendered.matches("#EXTINF:2.000,").count(), 3);
+    }
+}
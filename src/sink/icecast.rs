@@ -0,0 +1,201 @@
+// Source client for icecast: one long request whose body is the encoded stream, which
+// the server relays to its listeners. Newer servers take an HTTP PUT, older ones the
+// SOURCE method of their own; both authenticate with basic auth.
+use crate::config_file::{Config, IcecastConfig};
+use crate::distributor::{Distributor, DropPolicy, Subscription};
+use crate::http::read_response_head;
+use crate::http_server::StreamEncoder;
+use crate::protocol::FrameKind;
+use crate::tcp_client::{AudioPacket, SilenceFill};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::{self, Duration, Instant};
+use tracing::{error, info, warn};
+
+const ICECAST_QUEUE_LEN: usize = 100;
+// doubled after every failed connection, back to the first after a long enough one
+const FIRST_RETRY: Duration = Duration::from_secs(2);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IcecastProtocol {
+    // icecast 2.4.0 and later
+    Put,
+    // before 2.4.0
+    Source,
+}
+
+pub struct IcecastSource {
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    sample_rate: usize,
+}
+
+impl IcecastSource {
+    pub fn new(cfg: Arc<Config>, distributor: Arc<Distributor>) -> IcecastSource {
+        let sample_rate = cfg.icecast.sample_rate.unwrap_or(cfg.mic.sample_rate);
+        IcecastSource {
+            cfg,
+            distributor,
+            sample_rate,
+        }
+    }
+
+    async fn run(&self) -> crate::Result<()> {
+        let icecast = &self.cfg.icecast;
+        let mut retry = FIRST_RETRY;
+        loop {
+            // a fresh subscription every time, no stale audio after a reconnect
+            let mut frames = self
+                .distributor
+                .subscribe(ICECAST_QUEUE_LEN, DropPolicy::DropNewest);
+            // the channel count is only known from the first packet
+            let first = loop {
+                match frames.recv().await {
+                    Some(frame) if frame.kind == FrameKind::Audio => {
+                        break AudioPacket::parse(&frame.payload)?
+                    }
+                    Some(_) => continue,
+                    None => return Ok(()),
+                }
+            };
+            // can't get any better by retrying
+            let encoder = StreamEncoder::new(icecast.format, self.sample_rate, first.n_ch)?;
+
+            let started = Instant::now();
+            match self.push(&mut frames, encoder, first).await {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    if started.elapsed() > MAX_RETRY {
+                        retry = FIRST_RETRY;
+                    }
+                    warn!(
+                        "icecast {}{}: {}; reconnecting in {:?}",
+                        icecast.server,
+                        mount(icecast),
+                        err,
+                        retry
+                    );
+                    drop(frames);
+                    time::sleep(retry).await;
+                    retry = (retry * 2).min(MAX_RETRY);
+                }
+            }
+        }
+    }
+
+    async fn push(
+        &self,
+        frames: &mut Subscription,
+        mut encoder: StreamEncoder,
+        first: AudioPacket,
+    ) -> crate::Result<()> {
+        let icecast = &self.cfg.icecast;
+        let mut socket = TcpStream::connect(icecast.server.as_str()).await?;
+        socket.set_nodelay(true)?;
+        socket
+            .write_all(request_head(icecast, encoder.content_type()).as_bytes())
+            .await?;
+        // "100 Continue" to a put, "200 OK" to a source request
+        let response = read_response_head(&mut socket).await?;
+        let status = response.split(' ').nth(1).unwrap_or_default();
+        if !status.starts_with('1') && !status.starts_with('2') {
+            let status_line = response.lines().next().unwrap_or_default();
+            return Err(format!("server answered {}", status_line).into());
+        }
+        info!(
+            "icecast source for {}{} ({:?})",
+            icecast.server,
+            mount(icecast),
+            icecast.format
+        );
+
+        let mut out = encoder.header()?;
+        encoder.encode(&first, &mut out)?;
+        socket.write_all(&out).await?;
+        // listeners get zeros for silence frames, like http clients
+        let mut silence = SilenceFill::default();
+        let stream = frames.stream_info();
+        while let Some(frame) = frames.recv().await {
+            out.clear();
+            let packets = match frame.kind {
+                FrameKind::Silence => silence.packets(&frame, &stream)?,
+                FrameKind::Audio => vec![AudioPacket::parse(&frame.payload)?],
+                _ => continue,
+            };
+            for packet in &packets {
+                encoder.encode(packet, &mut out)?;
+            }
+            if !out.is_empty() {
+                socket.write_all(&out).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn mount(icecast: &IcecastConfig) -> String {
+    if icecast.mount.starts_with('/') {
+        icecast.mount.clone()
+    } else {
+        format!("/{}", icecast.mount)
+    }
+}
+
+fn request_head(icecast: &IcecastConfig, content_type: &str) -> String {
+    let credentials = STANDARD.encode(format!("{}:{}", icecast.username, icecast.password));
+    let request_line = match icecast.protocol {
+        IcecastProtocol::Put => format!(
+            "PUT {} HTTP/1.1\r\nHost: {}\r\nExpect: 100-continue\r\n",
+            mount(icecast),
+            icecast.server
+        ),
+        IcecastProtocol::Source => format!("SOURCE {} HTTP/1.0\r\n", mount(icecast)),
+    };
+    let mut head = format!(
+        "{}Authorization: Basic {}\r\nUser-Agent: mic2net/{}\r\nContent-Type: {}\r\n\
+         Ice-Name: {}\r\nIce-Public: {}\r\n",
+        request_line,
+        credentials,
+        env!("CARGO_PKG_VERSION"),
+        content_type,
+        icecast.name,
+        icecast.public as u8
+    );
+    for (header, value) in [
+        ("Ice-Description", &icecast.description),
+        ("Ice-Genre", &icecast.genre),
+    ] {
+        if !value.is_empty() {
+            head.push_str(&format!("{}: {}\r\n", header, value));
+        }
+    }
+    head.push_str("\r\n");
+    head
+}
+
+// Run the icecast source; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown'
+// argument.
+pub async fn start_icecast_source(
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    shutdown: impl Future,
+) {
+    let source = IcecastSource::new(cfg, distributor);
+    tokio::select! {
+        res = source.run() => {
+            if let Err(err) = res {
+                error!("icecast source stopped: {}", err);
+            }
+        }
+        _ = shutdown => {
+            info!("stopping icecast source");
+        }
+    }
+}
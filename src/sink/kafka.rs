@@ -0,0 +1,218 @@
+// Produces the stream to a kafka topic for archiving and meeting pipelines built on
+// kafka: one record per 'kafka.chunk' ms of audio, holding the stream info frame and the
+// frames of the chunk as on the tcp stream, timestamped with the capture time of its
+// first frame. A task of its own hands the records to librdkafka (rdkafka), which finds
+// the leader of the partition and retries until 'message.timeout.ms'.
+use crate::config_file::KafkaConfig;
+use crate::distributor::DropPolicy;
+use crate::protocol::{Frame, FrameCodec, FrameKind, StreamInfo};
+use crate::sink::{AudioSink, SinkFuture};
+use bytes::{Bytes, BytesMut};
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::util::Timeout;
+use tokio::sync::mpsc;
+use tokio_util::codec::Encoder;
+use tracing::{info, warn};
+
+// frames waiting for the sink; 'write' never waits for the broker
+const FRAME_QUEUE: usize = 50;
+
+struct Chunk {
+    // ms since the epoch
+    timestamp: i64,
+    value: Bytes,
+}
+
+pub struct KafkaSink {
+    chunks: mpsc::Sender<Chunk>,
+    chunk_us: u64,
+    buf: BytesMut,
+    // capture time of the first frame in 'buf', in us since the epoch
+    start: Option<u64>,
+    // whether chunks are being dropped, to warn once per stretch
+    behind: bool,
+}
+
+impl KafkaSink {
+    // Produces from a task of its own; fails on settings librdkafka refuses.
+    pub fn new(cfg: &KafkaConfig) -> crate::Result<KafkaSink> {
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", cfg.brokers.join(","))
+            .set("client.id", &cfg.client_id)
+            .set("acks", cfg.acks.to_string())
+            .set("request.timeout.ms", cfg.timeout.to_string())
+            .create()
+            .map_err(|err| format!("bad kafka settings: {}", err))?;
+        let (sink, chunks) = KafkaSink::chunking(cfg.chunk, cfg.queue_len);
+        info!(
+            "producing to kafka topic {} partition {} via {}",
+            cfg.topic,
+            cfg.partition,
+            cfg.brokers.join(",")
+        );
+        tokio::spawn(produce(
+            producer,
+            cfg.topic.clone(),
+            cfg.partition,
+            (!cfg.key.is_empty()).then(|| cfg.key.clone()),
+            chunks,
+        ));
+        Ok(sink)
+    }
+
+    // The sink without a producer; chunks of 'chunk_ms' come out of the receiver.
+    fn chunking(chunk_ms: u64, queue_len: usize) -> (KafkaSink, mpsc::Receiver<Chunk>) {
+        let (chunks, receiver) = mpsc::channel(queue_len.max(1));
+        let sink = KafkaSink {
+            chunks,
+            chunk_us: chunk_ms.max(1) * 1000,
+            buf: BytesMut::new(),
+            start: None,
+            behind: false,
+        };
+        (sink, receiver)
+    }
+
+    fn add(&mut self, frame: &Frame, stream: &StreamInfo) {
+        if !matches!(frame.kind, FrameKind::Audio | FrameKind::Silence) {
+            return;
+        }
+        let wall_us = frame.timestamp.wall_us;
+        if let Some(start) = self.start {
+            if wall_us.saturating_sub(start) >= self.chunk_us {
+                self.flush();
+            }
+        }
+        if self.start.is_none() {
+            self.start = Some(wall_us);
+            // every record decodes on its own
+            let _ = FrameCodec.encode(&Frame::stream_info(stream), &mut self.buf);
+        }
+        // only fails on oversized payloads, which never come out of the capture path
+        let _ = FrameCodec.encode(frame, &mut self.buf);
+    }
+
+    fn flush(&mut self) {
+        let Some(start) = self.start.take() else {
+            return;
+        };
+        let chunk = Chunk {
+            timestamp: (start / 1000) as i64,
+            value: self.buf.split().freeze(),
+        };
+        match self.chunks.try_send(chunk) {
+            Ok(()) => self.behind = false,
+            Err(_) if !self.behind => {
+                self.behind = true;
+                warn!("kafka broker unreachable or too slow, dropping audio");
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+impl AudioSink for KafkaSink {
+    fn name(&self) -> &str {
+        "kafka"
+    }
+
+    fn queue(&self) -> (usize, DropPolicy) {
+        (FRAME_QUEUE, DropPolicy::DropOldest)
+    }
+
+    fn write<'a>(&'a mut self, frame: Frame, stream: &'a StreamInfo) -> SinkFuture<'a> {
+        self.add(&frame, stream);
+        Box::pin(std::future::ready(Ok(())))
+    }
+
+    // The last, shorter chunk; produced unless the process exits first.
+    fn finish(&mut self) -> crate::Result<()> {
+        self.flush();
+        Ok(())
+    }
+}
+
+// Produce the chunks one after the other, until the sink is gone; the chunks behind
+// one librdkafka is still retrying wait in the queue of the sink.
+async fn produce(
+    producer: FutureProducer,
+    topic: String,
+    partition: i32,
+    key: Option<String>,
+    mut chunks: mpsc::Receiver<Chunk>,
+) {
+    while let Some(chunk) = chunks.recv().await {
+        let mut record = FutureRecord::<str, [u8]>::to(&topic)
+            .partition(partition)
+            .payload(&chunk.value)
+            .timestamp(chunk.timestamp);
+        if let Some(key) = &key {
+            record = record.key(key);
+        }
+        if let Err((err, _)) = producer.send(record, Timeout::Never).await {
+            warn!("failed to produce to kafka topic {}: {}", topic, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Timestamp;
+    use bytes::Buf;
+    use tokio_util::codec::Decoder;
+
+    fn frame(seq: u32, ms: u64) -> Frame {
+        let timestamp = Timestamp {
+            wall_us: 1_700_000_000_000_000 + ms * 1000,
+            mono_us: ms * 1000,
+        };
+        Frame::silence(seq, timestamp, 10)
+    }
+
+    fn decode(mut value: BytesMut) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while let Some(frame) = FrameCodec.decode(&mut value).unwrap() {
+            frames.push(frame);
+        }
+        assert!(!value.has_remaining());
+        frames
+    }
+
+    #[test]
+    fn records_hold_a_chunk_each() {
+        let stream = StreamInfo::pcm(16000, 1);
+        let (mut sink, mut chunks) = KafkaSink::chunking(30, 4);
+        for seq in 0..7 {
+            sink.add(&frame(seq, seq as u64 * 10), &stream);
+        }
+        sink.finish().unwrap();
+
+        let mut seqs = Vec::new();
+        while let Ok(chunk) = chunks.try_recv() {
+            let frames = decode(BytesMut::from(&chunk.value[..]));
+            assert_eq!(frames[0].kind, FrameKind::StreamInfo);
+            assert_eq!(chunk.timestamp, (frames[1].timestamp.wall_us / 1000) as i64);
+            seqs.push(frames[1..].iter().map(|f| f.seq).collect::<Vec<_>>());
+        }
+        assert_eq!(seqs, [vec![0, 1, 2], vec![3, 4, 5], vec![6]]);
+    }
+
+    #[test]
+    fn full_queue_drops_newest() {
+        let stream = StreamInfo::pcm(16000, 1);
+        let (mut sink, mut chunks) = KafkaSink::chunking(10, 2);
+        for seq in 0..5 {
+            sink.add(&frame(seq, seq as u64 * 10), &stream);
+        }
+        sink.finish().unwrap();
+        assert!(sink.behind);
+
+        let mut first = Vec::new();
+        while let Ok(chunk) = chunks.try_recv() {
+            first.push(decode(BytesMut::from(&chunk.value[..]))[1].seq);
+        }
+        assert_eq!(first, [0, 1]);
+    }
+}
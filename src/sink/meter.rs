@@ -0,0 +1,79 @@
+use crate::config_file::Config;
+use crate::distributor::DropPolicy;
+use crate::dsp::meter::Levels;
+use crate::protocol::{Frame, FrameKind, StreamInfo};
+use crate::sink::{AudioSink, SinkFuture};
+use crate::tcp_client::AudioPacket;
+use std::time::Instant;
+use tokio::time::Duration;
+use tracing::info;
+
+// Metering can lose a packet now and then without anyone noticing.
+const METER_QUEUE_LEN: usize = 20;
+
+// Logs the levels of every channel each 'meter.interval' seconds: the highest rms and
+// peak of any packet since the last report, in dBFS. Silence frames leave them at -inf.
+pub struct MeterSink {
+    interval: Duration,
+    last_report: Instant,
+    loudest: Option<Levels>,
+}
+
+impl MeterSink {
+    pub fn new(cfg: &Config) -> MeterSink {
+        MeterSink {
+            interval: Duration::from_secs(cfg.meter.interval.max(1)),
+            last_report: Instant::now(),
+            loudest: None,
+        }
+    }
+
+    fn measure(&mut self, frame: &Frame) -> crate::Result<()> {
+        if frame.kind != FrameKind::Audio {
+            return Ok(());
+        }
+        let levels = Levels::measure(&AudioPacket::parse(&frame.payload)?.samples);
+        match &mut self.loudest {
+            Some(loudest) if loudest.rms.len() == levels.rms.len() => {
+                for (max, level) in loudest.rms.iter_mut().zip(&levels.rms) {
+                    *max = max.max(*level);
+                }
+                for (max, level) in loudest.peak.iter_mut().zip(&levels.peak) {
+                    *max = max.max(*level);
+                }
+            }
+            // the first packet, or the channel count changed
+            _ => self.loudest = Some(levels),
+        }
+        Ok(())
+    }
+}
+
+impl AudioSink for MeterSink {
+    fn name(&self) -> &str {
+        "meter"
+    }
+
+    fn queue(&self) -> (usize, DropPolicy) {
+        (METER_QUEUE_LEN, DropPolicy::DropOldest)
+    }
+
+    fn write<'a>(&'a mut self, frame: Frame, _stream: &'a StreamInfo) -> SinkFuture<'a> {
+        Box::pin(std::future::ready(self.measure(&frame)))
+    }
+
+    fn tick(&mut self) -> crate::Result<()> {
+        if self.last_report.elapsed() < self.interval {
+            return Ok(());
+        }
+        self.last_report = Instant::now();
+        match self.loudest.take() {
+            Some(levels) => info!(
+                "levels (dBFS): rms {:.1?}, peak {:.1?}",
+                levels.rms, levels.peak
+            ),
+            None => info!("levels: silence"),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+// Consumers of the capture stream that run next to the network transports.
+use crate::distributor::{Distributor, DropPolicy};
+use crate::protocol::{Frame, StreamInfo};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
+use tracing::{error, info, warn};
+
+#[cfg(any(feature = "opus", feature = "aac"))]
+pub mod hls;
+pub mod icecast;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod meter;
+pub mod mqtt;
+pub mod s3;
+pub mod schedule;
+pub mod wav;
+
+// What the hls segments carry.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HlsCodec {
+    // needs the 'opus' feature; plays in current browsers but not on older Apple devices
+    Opus,
+    // AAC-LC, needs the 'aac' feature; plays on about every phone and tv
+    Aac,
+}
+
+// how often 'AudioSink::tick' runs
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub type SinkFuture<'a> = Pin<Box<dyn Future<Output = crate::Result<()>> + Send + 'a>>;
+
+// A consumer of the stream frames. 'start_sink' feeds every sink from a subscription of
+// its own, so one that falls behind only loses its own frames, as 'queue' says, while
+// the transports and the other sinks go on.
+pub trait AudioSink: Send {
+    fn name(&self) -> &str;
+
+    // How many frames may wait for the sink, and what happens beyond that.
+    fn queue(&self) -> (usize, DropPolicy);
+
+    // 'stream' is what the frames carry.
+    fn write<'a>(&'a mut self, frame: Frame, stream: &'a StreamInfo) -> SinkFuture<'a>;
+
+    // Housekeeping, every 'TICK_INTERVAL' between frames.
+    fn tick(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    // The stream ended or the sink is stopped; nothing is written after.
+    fn finish(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+// Run 'sink' on the frames of 'distributor' until the stream ends or 'shutdown'
+// completes; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_sink(
+    mut sink: Box<dyn AudioSink>,
+    distributor: Arc<Distributor>,
+    shutdown: impl Future,
+) {
+    let (queue_len, policy) = sink.queue();
+    let mut frames = distributor.subscribe(queue_len, policy);
+    let stream = frames.stream_info();
+    let name = sink.name().to_string();
+    let mut ticker = time::interval(TICK_INTERVAL);
+    let run = async {
+        loop {
+            tokio::select! {
+                frame = frames.recv() => match frame {
+                    Some(frame) => sink.write(frame, &stream).await?,
+                    None => return Ok::<(), crate::Error>(()),
+                },
+                _ = ticker.tick() => sink.tick()?,
+            }
+        }
+    };
+    tokio::select! {
+        res = run => {
+            if let Err(err) = res {
+                error!("{} sink stopped: {}", name, err);
+            }
+        }
+        _ = shutdown => {
+            info!("stopping {} sink", name);
+        }
+    }
+    if let Err(err) = sink.finish() {
+        warn!("failed to finish {} sink: {}", name, err);
+    }
+}
+
+// Sinks side by side on the frames of one distributor, each on a task of its own.
+pub struct FanOut {
+    distributor: Arc<Distributor>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl FanOut {
+    pub fn new(distributor: Arc<Distributor>) -> FanOut {
+        FanOut {
+            distributor,
+            tasks: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, sink: Box<dyn AudioSink>, shutdown: impl Future + Send + 'static) {
+        let distributor = self.distributor.clone();
+        self.tasks
+            .push(tokio::spawn(start_sink(sink, distributor, shutdown)));
+    }
+
+    // To wait for after the shutdown signal; sinks are finished before their task ends.
+    pub fn into_tasks(self) -> Vec<JoinHandle<()>> {
+        self.tasks
+    }
+}
@@ -0,0 +1,203 @@
+// Publishes the stream to an mqtt broker for home automation setups that route
+// everything through one: the audio frames of the [mqtt] output, the capture levels
+// and the vad state, each on a topic of its own under 'mqtt.topic'. The connection is
+// kept by a task of its own, which reconnects when the broker goes away.
+use crate::config_file::{Config, MqttConfig};
+use crate::discovery::format_names;
+use crate::distributor::DropPolicy;
+use crate::dsp::meter::Levels;
+use crate::protocol::{Frame, FrameKind, StreamInfo, MAX_PAYLOAD_LEN};
+use crate::sink::{AudioSink, SinkFuture};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde_json::json;
+use std::time::Instant;
+use tokio::sync::watch;
+use tokio::time::{self, Duration};
+use tracing::{info, warn};
+
+const DEFAULT_PORT: u16 = 1883;
+// between attempts while the broker can't be reached
+const RETRY: Duration = Duration::from_secs(5);
+
+pub struct MqttSink {
+    client: AsyncClient,
+    topic: String,
+    queue_len: usize,
+    levels: watch::Receiver<Levels>,
+    level_interval: Option<Duration>,
+    last_level: Instant,
+    // of the last frame; None before the first
+    silent: Option<bool>,
+    // whether audio is being dropped, to warn once per stretch
+    behind: bool,
+}
+
+impl MqttSink {
+    // Connects from a task of its own; 'stream' is what the frames written carry.
+    pub fn new(cfg: &Config, stream: &StreamInfo, levels: watch::Receiver<Levels>) -> MqttSink {
+        let mqtt = &cfg.mqtt;
+        let status = format!("{}/status", mqtt.topic);
+        let mut options = options(mqtt);
+        options.set_last_will(LastWill::new(&status, "offline", QoS::AtLeastOnce, true));
+        let (client, events) = AsyncClient::new(options, mqtt.queue_len.max(1));
+
+        let (format, layout) = format_names(stream.codec, stream.sample_format);
+        let format = json!({
+            "sample_rate": stream.sample_rate,
+            "channels": stream.n_ch,
+            "frame_samples": stream.frame_samples,
+            "format": format,
+            "layout": layout,
+        });
+        let announcements = vec![
+            (status, "online".to_string()),
+            (format!("{}/format", mqtt.topic), format.to_string()),
+        ];
+        tokio::spawn(run_connection(
+            events,
+            client.clone(),
+            mqtt.broker.clone(),
+            announcements,
+        ));
+
+        MqttSink {
+            client,
+            topic: mqtt.topic.clone(),
+            queue_len: mqtt.queue_len,
+            levels,
+            level_interval: (mqtt.level_interval > 0)
+                .then(|| Duration::from_secs(mqtt.level_interval)),
+            last_level: Instant::now(),
+            silent: None,
+            behind: false,
+        }
+    }
+
+    fn publish(&mut self, frame: &Frame) {
+        let silent = match frame.kind {
+            FrameKind::Audio => false,
+            FrameKind::Silence => true,
+            _ => return,
+        };
+        if self.silent != Some(silent) {
+            self.silent = Some(silent);
+            let state = if silent { "off" } else { "on" };
+            self.event("vad", state, true);
+        }
+        if silent {
+            return;
+        }
+        let topic = format!("{}/audio", self.topic);
+        match self
+            .client
+            .try_publish(topic, QoS::AtMostOnce, false, frame.payload.to_vec())
+        {
+            Ok(()) => self.behind = false,
+            Err(_) if !self.behind => {
+                self.behind = true;
+                warn!("mqtt broker unreachable or too slow, dropping audio");
+            }
+            Err(_) => {}
+        }
+    }
+
+    // Events go out once at least, the vad state retained for clients that come later.
+    // Lost like the audio while the broker is away, without another warning.
+    fn event(&self, name: &str, payload: &str, retain: bool) {
+        let topic = format!("{}/{}", self.topic, name);
+        let sent = self
+            .client
+            .try_publish(topic, QoS::AtLeastOnce, retain, payload);
+        if let Err(err) = sent {
+            if !self.behind {
+                warn!("can't publish mqtt {} event: {}", name, err);
+            }
+        }
+    }
+}
+
+impl AudioSink for MqttSink {
+    fn name(&self) -> &str {
+        "mqtt"
+    }
+
+    fn queue(&self) -> (usize, DropPolicy) {
+        (self.queue_len, DropPolicy::DropOldest)
+    }
+
+    fn write<'a>(&'a mut self, frame: Frame, _stream: &'a StreamInfo) -> SinkFuture<'a> {
+        self.publish(&frame);
+        Box::pin(std::future::ready(Ok(())))
+    }
+
+    fn tick(&mut self) -> crate::Result<()> {
+        let Some(interval) = self.level_interval else {
+            return Ok(());
+        };
+        if self.last_level.elapsed() < interval {
+            return Ok(());
+        }
+        self.last_level = Instant::now();
+        let levels = serde_json::to_string(&*self.levels.borrow())?;
+        self.event("level", &levels, false);
+        Ok(())
+    }
+
+    // Says goodbye itself; the broker sends the last will only for lost connections.
+    fn finish(&mut self) -> crate::Result<()> {
+        self.event("status", "offline", true);
+        let _ = self.client.try_disconnect();
+        Ok(())
+    }
+}
+
+fn options(mqtt: &MqttConfig) -> MqttOptions {
+    let (host, port) = match mqtt.broker.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().unwrap_or(DEFAULT_PORT)),
+        None => (mqtt.broker.as_str(), DEFAULT_PORT),
+    };
+    let mut options = MqttOptions::new(&mqtt.client_id, host, port);
+    options.set_keep_alive(Duration::from_secs(mqtt.keep_alive.max(1)));
+    options.set_max_packet_size(MAX_PAYLOAD_LEN, MAX_PAYLOAD_LEN);
+    if !mqtt.username.is_empty() {
+        options.set_credentials(&mqtt.username, &mqtt.password);
+    }
+    options
+}
+
+// Drive the connection until the sink disconnects, publishing 'announcements' (topic
+// and retained payload) whenever the broker accepts it.
+async fn run_connection(
+    mut events: EventLoop,
+    client: AsyncClient,
+    broker: String,
+    announcements: Vec<(String, String)>,
+) {
+    let mut connected = false;
+    loop {
+        match events.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("connected to mqtt broker {}", broker);
+                connected = true;
+                // queued behind the requests polled here, so never waited for
+                for (topic, payload) in &announcements {
+                    let _ = client.try_publish(topic, QoS::AtLeastOnce, true, payload.as_str());
+                }
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => return,
+            Ok(_) => {}
+            Err(err) => {
+                if connected {
+                    warn!("lost mqtt broker {}: {}", broker, err);
+                } else {
+                    warn!(
+                        "can't reach mqtt broker {}: {}; retrying in {:?}",
+                        broker, err, RETRY
+                    );
+                }
+                connected = false;
+                time::sleep(RETRY).await;
+            }
+        }
+    }
+}
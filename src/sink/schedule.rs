@@ -0,0 +1,234 @@
+// Times of day a sink is active, as cron-like entries of five fields matched against
+// local time every minute:
+//
+//   minute  hour  day-of-month  month  day-of-week
+//   0-59    0-23  1-31          1-12   0-7, 0 and 7 are sunday
+//
+// Every field is '*', a number, a range 'a-b', any of those followed by a step '/n', or
+// a comma separated list of them. A minute is in the schedule if all five fields of an
+// entry match it, so "* 9-17 * * 1-5" is active on weekdays from 9:00 to 17:59 and
+// "30-59 8 * * *" adds 8:30 to 8:59. Unlike cron, both day fields have to match.
+use chrono::{Datelike, Local, NaiveDateTime, Timelike};
+use std::ops::RangeInclusive;
+
+const FIELDS: [(&str, RangeInclusive<u32>); 5] = [
+    ("minute", 0..=59),
+    ("hour", 0..=23),
+    ("day of month", 1..=31),
+    ("month", 1..=12),
+    ("day of week", 0..=7),
+];
+
+#[derive(Clone, Debug, Default)]
+pub struct Schedule {
+    // one bit set per allowed value of each field; none for always
+    entries: Vec<[u64; 5]>,
+}
+
+impl Schedule {
+    // An empty list is always active.
+    pub fn parse(entries: &[String]) -> crate::Result<Schedule> {
+        let entries = entries
+            .iter()
+            .map(|entry| {
+                parse_entry(entry).map_err(|err| format!("schedule \"{}\": {}", entry, err))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Schedule { entries })
+    }
+
+    pub fn is_always(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn active_now(&self) -> bool {
+        self.active_at(&Local::now().naive_local())
+    }
+
+    pub fn active_at(&self, at: &NaiveDateTime) -> bool {
+        let values = [
+            at.minute(),
+            at.hour(),
+            at.day(),
+            at.month(),
+            at.weekday().num_days_from_sunday(),
+        ];
+        self.is_always()
+            || self.entries.iter().any(|fields| {
+                fields
+                    .iter()
+                    .zip(values)
+                    .all(|(allowed, value)| allowed & (1 << value) != 0)
+            })
+    }
+}
+
+fn parse_entry(entry: &str) -> crate::Result<[u64; 5]> {
+    let fields: Vec<&str> = entry.split_whitespace().collect();
+    if fields.len() != FIELDS.len() {
+        return Err(format!("{} fields, expected 5", fields.len()).into());
+    }
+    let mut parsed = [0; 5];
+    for ((allowed, field), (name, range)) in parsed.iter_mut().zip(fields).zip(FIELDS) {
+        *allowed = parse_field(field, range).map_err(|err| format!("{}: {}", name, err))?;
+    }
+    // sunday either way
+    if parsed[4] & (1 << 7) != 0 {
+        parsed[4] |= 1;
+    }
+    Ok(parsed)
+}
+
+fn parse_field(field: &str, range: RangeInclusive<u32>) -> crate::Result<u64> {
+    let mut allowed = 0;
+    for part in field.split(',') {
+        let (span, step) = match part.split_once('/') {
+            Some((span, step)) => (span, step.parse::<u32>().map_err(|_| "bad step")?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err("step of 0".into());
+        }
+        let (first, last) = match span {
+            "*" => (*range.start(), *range.end()),
+            _ => match span.split_once('-') {
+                Some((first, last)) => (parse_value(first, &range)?, parse_value(last, &range)?),
+                None => {
+                    let value = parse_value(span, &range)?;
+                    // "5/15" runs from 5 to the end, as in cron
+                    (value, if step > 1 { *range.end() } else { value })
+                }
+            },
+        };
+        if first > last {
+            return Err(format!("empty range {}", span).into());
+        }
+        for value in (first..=last).step_by(step as usize) {
+            allowed |= 1 << value;
+        }
+    }
+    Ok(allowed)
+}
+
+fn parse_value(value: &str, range: &RangeInclusive<u32>) -> crate::Result<u32> {
+    match value.parse() {
+        Ok(value) if range.contains(&value) => Ok(value),
+        _ => Err(format!("{} is not in {}-{}", value, range.start(), range.end()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn schedule(entries: &[&str]) -> Schedule {
+        let entries: Vec<String> = entries.iter().map(|entry| entry.to_string()).collect();
+        Schedule::parse(&entries).unwrap()
+    }
+
+    // 2024-01-01 is a monday
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn office_hours_on_weekdays() {
+        let schedule = schedule(&["* 9-17 * * 1-5"]);
+        assert!(!schedule.active_at(&at(1, 8, 59)));
+        assert!(schedule.active_at(&at(1, 9, 0)));
+        assert!(schedule.active_at(&at(5, 17, 59)));
+        assert!(!schedule.active_at(&at(5, 18, 0)));
+        // saturday and sunday
+        assert!(!schedule.active_at(&at(6, 10, 0)));
+        assert!(!schedule.active_at(&at(7, 10, 0)));
+    }
+
+    #[test]
+    fn window_across_midnight() {
+        let schedule = schedule(&["* 22-23,0-5 * * *"]);
+        assert!(!schedule.active_at(&at(1, 21, 59)));
+        assert!(schedule.active_at(&at(1, 22, 0)));
+        assert!(schedule.active_at(&at(1, 23, 59)));
+        assert!(schedule.active_at(&at(2, 0, 0)));
+        assert!(schedule.active_at(&at(2, 5, 59)));
+        assert!(!schedule.active_at(&at(2, 6, 0)));
+        // a range has to run forwards, midnight or not
+        assert!(Schedule::parse(&["* 22-5 * * *".to_string()]).is_err());
+    }
+
+    #[test]
+    fn day_of_week_is_checked_on_each_side_of_midnight() {
+        // friday 22:00 to saturday 0:59
+        let schedule = schedule(&["* 22-23 * * 5", "0-59 0 * * 6"]);
+        assert!(schedule.active_at(&at(5, 23, 59)));
+        assert!(schedule.active_at(&at(6, 0, 30)));
+        assert!(!schedule.active_at(&at(6, 1, 0)));
+        assert!(!schedule.active_at(&at(6, 23, 0)));
+        assert!(!schedule.active_at(&at(4, 23, 0)));
+    }
+
+    #[test]
+    fn sunday_is_0_or_7() {
+        for entry in ["* * * * 0", "* * * * 7"] {
+            let schedule = schedule(&[entry]);
+            assert!(schedule.active_at(&at(7, 12, 0)), "{}", entry);
+            assert!(!schedule.active_at(&at(6, 12, 0)), "{}", entry);
+        }
+    }
+
+    #[test]
+    fn both_day_fields_have_to_match() {
+        // only on a friday the 13th, such as 2024-09-13
+        let schedule = schedule(&["* * 13 * 5"]);
+        let friday_13th = NaiveDate::from_ymd_opt(2024, 9, 13).unwrap();
+        assert!(schedule.active_at(&friday_13th.and_hms_opt(12, 0, 0).unwrap()));
+        assert!(!schedule.active_at(&at(13, 12, 0)));
+        assert!(!schedule.active_at(&at(5, 12, 0)));
+    }
+
+    #[test]
+    fn steps_and_lists() {
+        let minutes = |entry: &str| -> Vec<u32> {
+            let schedule = schedule(&[entry]);
+            (0..60)
+                .filter(|&minute| schedule.active_at(&at(1, 12, minute)))
+                .collect()
+        };
+        assert_eq!(minutes("*/15,7 * * * *"), [0, 7, 15, 30, 45]);
+        // a start with a step runs to the end
+        assert_eq!(minutes("5/20 * * * *"), [5, 25, 45]);
+        assert_eq!(minutes("10-20/5 * * * *"), [10, 15, 20]);
+    }
+
+    #[test]
+    fn no_entries_is_always_active() {
+        let schedule = schedule(&[]);
+        assert!(schedule.is_always());
+        assert!(schedule.active_at(&at(6, 3, 0)));
+    }
+
+    #[test]
+    fn malformed_entries_are_refused() {
+        for entry in [
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "*/x * * * *",
+            "5-3 * * * *",
+            "a * * * *",
+        ] {
+            assert!(Schedule::parse(&[entry.to_string()]).is_err(), "{}", entry);
+        }
+        let err = Schedule::parse(&["* 25 * * *".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("hour"));
+    }
+}
@@ -0,0 +1,755 @@
+use crate::audio::flac::FlacEncoder;
+#[cfg(feature = "opus")]
+use crate::audio::ogg::OggOpusEncoder;
+#[cfg(feature = "opus")]
+use crate::audio::vad::Vad;
+#[cfg(feature = "opus")]
+use crate::audio::webm::WebmOpusEncoder;
+use crate::config_file::Config;
+use crate::distributor::DropPolicy;
+use crate::protocol::{Frame, FrameKind, StreamInfo};
+use crate::sink::s3::S3Uploader;
+use crate::sink::schedule::Schedule;
+use crate::sink::{AudioSink, SinkFuture};
+use crate::tcp_client::{AudioPacket, SilenceFill};
+use crate::{HEADER_LEN, PACKET_N_SAMPLE};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+use tracing::{info, warn};
+
+// A slow disk shouldn't lose audio to short stalls; about 2 s of packets.
+const WAV_QUEUE_LEN: usize = 200;
+// old recordings also go while a long one is being written
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordFormat {
+    Wav,
+    // lossless and about half the size, at most 8 channels
+    Flac,
+    // ogg opus, the first 2 channels only and far smaller still; needs the 'opus'
+    // feature and a sample rate opus supports
+    Ogg,
+    // the same opus in webm, which browsers play and which can carry cue points
+    Webm,
+}
+
+// A flac file being written. Its header is rewritten with the length and checksum
+// when it is finished, like hound does for wav.
+struct FlacWriter {
+    file: BufWriter<File>,
+    encoder: FlacEncoder,
+    n_samples: u64,
+    n_bytes: u64,
+    buf: Vec<u8>,
+}
+
+impl FlacWriter {
+    fn create(path: &Path, sample_rate: usize, n_ch: usize) -> crate::Result<FlacWriter> {
+        let mut encoder = FlacEncoder::new(sample_rate, n_ch)?;
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&encoder.header()?)?;
+        Ok(FlacWriter {
+            file,
+            encoder,
+            n_samples: 0,
+            n_bytes: 0,
+            buf: Vec::new(),
+        })
+    }
+
+    fn write_packet(&mut self, packet: &[u8]) -> crate::Result<()> {
+        let packet = AudioPacket::parse(packet)?;
+        self.buf.clear();
+        self.encoder.encode(&packet, &mut self.buf)?;
+        self.file.write_all(&self.buf)?;
+        self.n_samples += PACKET_N_SAMPLE as u64;
+        self.n_bytes += self.buf.len() as u64;
+        Ok(())
+    }
+
+    fn finalize(mut self) -> crate::Result<()> {
+        self.buf.clear();
+        self.encoder.flush(&mut self.buf)?;
+        self.file.write_all(&self.buf)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&self.encoder.final_header()?)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+// An ogg opus file being written, in pages of about a second.
+#[cfg(feature = "opus")]
+struct OggWriter {
+    file: BufWriter<File>,
+    encoder: OggOpusEncoder,
+    // of the stream, which may be more than the file carries
+    n_ch: usize,
+    n_samples: u64,
+    n_bytes: u64,
+    buf: Vec<u8>,
+}
+
+#[cfg(feature = "opus")]
+impl OggWriter {
+    // frames of 20 ms
+    const FRAMES_PER_PAGE: usize = 50;
+
+    fn create(path: &Path, sample_rate: usize, n_ch: usize) -> crate::Result<OggWriter> {
+        let mut encoder = OggOpusEncoder::new(sample_rate, n_ch.min(2), Self::FRAMES_PER_PAGE)?;
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&encoder.header()?)?;
+        Ok(OggWriter {
+            file,
+            encoder,
+            n_ch,
+            n_samples: 0,
+            n_bytes: 0,
+            buf: Vec::new(),
+        })
+    }
+
+    fn write_packet(&mut self, packet: &[u8]) -> crate::Result<()> {
+        let packet = AudioPacket::parse(packet)?;
+        self.buf.clear();
+        self.encoder.encode(&packet, &mut self.buf)?;
+        self.file.write_all(&self.buf)?;
+        self.n_samples += PACKET_N_SAMPLE as u64;
+        self.n_bytes += self.buf.len() as u64;
+        Ok(())
+    }
+
+    fn finalize(mut self) -> crate::Result<()> {
+        self.buf.clear();
+        self.encoder.finish(&mut self.buf)?;
+        self.file.write_all(&self.buf)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+// A webm file being written. Its header is rewritten with the length, duration and
+// where the cues are when it is finished.
+#[cfg(feature = "opus")]
+struct WebmWriter {
+    file: BufWriter<File>,
+    encoder: WebmOpusEncoder,
+    // of the stream, which may be more than the file carries
+    n_ch: usize,
+    n_samples: u64,
+    n_bytes: u64,
+    buf: Vec<u8>,
+}
+
+#[cfg(feature = "opus")]
+impl WebmWriter {
+    fn create(
+        path: &Path,
+        sample_rate: usize,
+        n_ch: usize,
+        vad: Option<Vad>,
+    ) -> crate::Result<WebmWriter> {
+        let mut encoder = WebmOpusEncoder::new(sample_rate, n_ch.min(2), false, vad)?;
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&encoder.header()?)?;
+        Ok(WebmWriter {
+            file,
+            encoder,
+            n_ch,
+            n_samples: 0,
+            n_bytes: 0,
+            buf: Vec::new(),
+        })
+    }
+
+    fn write_packet(&mut self, packet: &[u8]) -> crate::Result<()> {
+        let packet = AudioPacket::parse(packet)?;
+        self.buf.clear();
+        self.encoder.encode(&packet, &mut self.buf)?;
+        self.file.write_all(&self.buf)?;
+        self.n_samples += PACKET_N_SAMPLE as u64;
+        self.n_bytes += self.buf.len() as u64;
+        Ok(())
+    }
+
+    fn finalize(mut self) -> crate::Result<()> {
+        self.buf.clear();
+        self.encoder.finish(&mut self.buf)?;
+        self.file.write_all(&self.buf)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&self.encoder.final_header()?)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+// Deletes the oldest recordings in a directory once they are older than 'max_age' or
+// together take more than 'max_bytes'. Only files named like recordings are touched,
+// never the one being written.
+struct Retention {
+    max_age: Option<Duration>,
+    max_bytes: Option<u64>,
+}
+
+impl Retention {
+    fn enforce(&self, directory: &Path, current: Option<&Path>) {
+        if self.max_age.is_none() && self.max_bytes.is_none() {
+            return;
+        }
+        let mut recordings = match recordings(directory) {
+            Ok(recordings) => recordings,
+            Err(err) => {
+                warn!("failed to list recordings: {}", err);
+                return;
+            }
+        };
+        // oldest first
+        recordings.sort_by_key(|(_, modified, _)| *modified);
+        let mut total: u64 = recordings.iter().map(|(_, _, len)| len).sum();
+        let now = SystemTime::now();
+        for (path, modified, len) in recordings {
+            let expired = self
+                .max_age
+                .is_some_and(|max_age| now.duration_since(modified).unwrap_or_default() > max_age);
+            let over_budget = self.max_bytes.is_some_and(|max_bytes| total > max_bytes);
+            if !(expired || over_budget) {
+                continue;
+            }
+            if Some(path.as_path()) == current {
+                continue;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    info!("deleted old recording {}", path.display());
+                    total -= len;
+                }
+                Err(err) => warn!("failed to delete {}: {}", path.display(), err),
+            }
+        }
+    }
+}
+
+// Path, modification time and size of the recordings in 'directory'.
+fn recordings(directory: &Path) -> crate::Result<Vec<(PathBuf, SystemTime, u64)>> {
+    let mut recordings = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let extension = name.rsplit_once('.').map(|(_, extension)| extension);
+        if !(name.starts_with("mic2net-")
+            && matches!(extension, Some("wav" | "flac" | "opus" | "webm")))
+        {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            recordings.push((entry.path(), metadata.modified()?, metadata.len()));
+        }
+    }
+    Ok(recordings)
+}
+
+enum Recording {
+    Wav(WavWriter<BufWriter<File>>),
+    Flac(Box<FlacWriter>),
+    #[cfg(feature = "opus")]
+    Ogg(Box<OggWriter>),
+    #[cfg(feature = "opus")]
+    Webm(Box<WebmWriter>),
+}
+
+impl Recording {
+    fn n_ch(&self) -> usize {
+        match self {
+            Recording::Wav(writer) => writer.spec().channels as usize,
+            Recording::Flac(writer) => writer.encoder.n_ch(),
+            #[cfg(feature = "opus")]
+            Recording::Ogg(writer) => writer.n_ch,
+            #[cfg(feature = "opus")]
+            Recording::Webm(writer) => writer.n_ch,
+        }
+    }
+
+    // samples per channel
+    fn duration(&self) -> u64 {
+        match self {
+            Recording::Wav(writer) => writer.duration() as u64,
+            Recording::Flac(writer) => writer.n_samples,
+            #[cfg(feature = "opus")]
+            Recording::Ogg(writer) => writer.n_samples,
+            #[cfg(feature = "opus")]
+            Recording::Webm(writer) => writer.n_samples,
+        }
+    }
+
+    fn n_bytes(&self) -> u64 {
+        match self {
+            Recording::Wav(writer) => writer.len() as u64 * 2,
+            Recording::Flac(writer) => writer.n_bytes,
+            #[cfg(feature = "opus")]
+            Recording::Ogg(writer) => writer.n_bytes,
+            #[cfg(feature = "opus")]
+            Recording::Webm(writer) => writer.n_bytes,
+        }
+    }
+
+    fn flush(&mut self) -> crate::Result<()> {
+        match self {
+            Recording::Wav(writer) => writer.flush()?,
+            Recording::Flac(writer) => writer.file.flush()?,
+            #[cfg(feature = "opus")]
+            Recording::Ogg(writer) => writer.file.flush()?,
+            #[cfg(feature = "opus")]
+            Recording::Webm(writer) => writer.file.flush()?,
+        }
+        Ok(())
+    }
+
+    fn finalize(self) -> crate::Result<()> {
+        match self {
+            Recording::Wav(writer) => writer.finalize()?,
+            Recording::Flac(writer) => writer.finalize()?,
+            #[cfg(feature = "opus")]
+            Recording::Ogg(writer) => writer.finalize()?,
+            #[cfg(feature = "opus")]
+            Recording::Webm(writer) => writer.finalize()?,
+        }
+        Ok(())
+    }
+}
+
+// Writes the stream to 'wav.directory' as 16-bit WAV (FLAC, Ogg or WebM) files named after the
+// capture time of their first packet, starting a new file when one reaches
+// 'max_duration' seconds or 'max_size' MB. Outside the 'schedule' nothing is written and
+// the file is finished, so every recording window gets files of its own. Recordings
+// older than 'max_age_days' or beyond 'max_total_size' MB in all are deleted. With an
+// 's3' section every finished file is uploaded, in the background.
+pub struct WavSink {
+    directory: PathBuf,
+    format: RecordFormat,
+    sample_rate: u32,
+    max_samples: u64,
+    max_bytes: u64,
+    writer: Option<Recording>,
+    // of 'writer'
+    path: Option<PathBuf>,
+    retention: Retention,
+    last_retention: Instant,
+    // finished files to upload
+    uploads: Option<mpsc::UnboundedSender<PathBuf>>,
+    silence: SilenceFill,
+    schedule: Schedule,
+    // within the schedule at the last check
+    active: bool,
+    // cue points in webm files where the [vad] thresholds see voice start or stop
+    #[cfg(feature = "opus")]
+    vad_cues: bool,
+}
+
+impl WavSink {
+    pub fn new(cfg: Arc<Config>) -> crate::Result<WavSink> {
+        let directory = PathBuf::from(&cfg.wav.directory);
+        fs::create_dir_all(&directory)?;
+        let sample_rate = cfg.mic.sample_rate as u32;
+        let limit = |value: u64| if value == 0 { u64::MAX } else { value };
+        let schedule = Schedule::parse(&cfg.wav.schedule)?;
+        let uploader = cfg.wav.s3.as_ref().map(S3Uploader::new).transpose()?;
+        info!("recording to {}", directory.display());
+        let active = schedule.active_now();
+        if !active {
+            info!("waiting for the recording schedule");
+        }
+
+        Ok(WavSink {
+            directory,
+            format: cfg.wav.format,
+            sample_rate,
+            max_samples: limit(cfg.wav.max_duration * sample_rate as u64).min(u32::MAX as u64),
+            max_bytes: limit(cfg.wav.max_size).saturating_mul(1 << 20),
+            writer: None,
+            path: None,
+            retention: Retention {
+                max_age: (cfg.wav.max_age_days > 0)
+                    .then(|| Duration::from_secs(cfg.wav.max_age_days * 24 * 3600)),
+                max_bytes: (cfg.wav.max_total_size > 0)
+                    .then(|| cfg.wav.max_total_size.saturating_mul(1 << 20)),
+            },
+            last_retention: Instant::now(),
+            uploads: uploader.map(S3Uploader::spawn),
+            silence: SilenceFill::default(),
+            active,
+            schedule,
+            #[cfg(feature = "opus")]
+            vad_cues: cfg.wav.vad_cues,
+        })
+    }
+
+    fn write_frame(&mut self, frame: Frame, stream: &StreamInfo) -> crate::Result<()> {
+        match frame.kind {
+            _ if !self.active => {}
+            // silence frames are written out, so recordings keep their timing
+            FrameKind::Silence => {
+                for packet in self.silence.packets(&frame, stream)? {
+                    self.write_packet(&packet.to_payload())?;
+                }
+            }
+            FrameKind::Audio => self.write_packet(&frame.payload)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn follow_schedule(&mut self) -> crate::Result<()> {
+        let active = self.schedule.active_now();
+        if active != self.active {
+            self.active = active;
+            if active {
+                info!("recording window opened");
+            } else {
+                info!("recording window closed");
+                self.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_packet(&mut self, packet: &[u8]) -> crate::Result<()> {
+        let n_ch = (packet.len() - HEADER_LEN) / (PACKET_N_SAMPLE * 2);
+        if n_ch == 0 {
+            return Ok(());
+        }
+        let rotate = match &self.writer {
+            Some(writer) => {
+                writer.n_ch() != n_ch
+                    || writer.duration() >= self.max_samples
+                    || writer.n_bytes() >= self.max_bytes
+            }
+            None => true,
+        };
+        if rotate {
+            self.finish()?;
+            let secs = u32::from_be_bytes(packet[2..6].try_into().unwrap());
+            let millis = u16::from_be_bytes(packet[6..8].try_into().unwrap());
+            let extension = match self.format {
+                RecordFormat::Wav => "wav",
+                RecordFormat::Flac => "flac",
+                RecordFormat::Ogg => "opus",
+                RecordFormat::Webm => "webm",
+            };
+            let path = self
+                .directory
+                .join(format!("mic2net-{}{:03}.{}", secs, millis, extension));
+            info!("new recording {}", path.display());
+            self.path = Some(path.clone());
+            self.retention.enforce(&self.directory, Some(&path));
+            self.writer = Some(match self.format {
+                RecordFormat::Wav => {
+                    let spec = WavSpec {
+                        channels: n_ch as u16,
+                        sample_rate: self.sample_rate,
+                        bits_per_sample: 16,
+                        sample_format: SampleFormat::Int,
+                    };
+                    Recording::Wav(WavWriter::create(path, spec)?)
+                }
+                RecordFormat::Flac => {
+                    if n_ch > 8 {
+                        return Err(crate::Error::Codec(format!(
+                            "flac can't hold {} channels",
+                            n_ch
+                        )));
+                    }
+                    Recording::Flac(Box::new(FlacWriter::create(
+                        &path,
+                        self.sample_rate as usize,
+                        n_ch,
+                    )?))
+                }
+                #[cfg(feature = "opus")]
+                RecordFormat::Ogg => Recording::Ogg(Box::new(OggWriter::create(
+                    &path,
+                    self.sample_rate as usize,
+                    n_ch,
+                )?)),
+                #[cfg(not(feature = "opus"))]
+                RecordFormat::Ogg => return Err("ogg needs a build with --features opus".into()),
+                #[cfg(feature = "opus")]
+                RecordFormat::Webm => Recording::Webm(Box::new(WebmWriter::create(
+                    &path,
+                    self.sample_rate as usize,
+                    n_ch,
+                    self.vad_cues.then(|| Vad::new(self.sample_rate as usize)),
+                )?)),
+                #[cfg(not(feature = "opus"))]
+                RecordFormat::Webm => return Err("webm needs a build with --features opus".into()),
+            });
+        }
+
+        let writer = match self.writer.as_mut().unwrap() {
+            Recording::Wav(writer) => writer,
+            Recording::Flac(writer) => return writer.write_packet(packet),
+            #[cfg(feature = "opus")]
+            Recording::Ogg(writer) => return writer.write_packet(packet),
+            #[cfg(feature = "opus")]
+            Recording::Webm(writer) => return writer.write_packet(packet),
+        };
+        // packets are planar, wav is interleaved
+        let samples = &packet[HEADER_LEN..];
+        for i in 0..PACKET_N_SAMPLE {
+            for ch in 0..n_ch {
+                let at = (ch * PACKET_N_SAMPLE + i) * 2;
+                writer.write_sample(i16::from_ne_bytes([samples[at], samples[at + 1]]))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Finalize the current file so its header holds the right length.
+    fn finish(&mut self) -> crate::Result<()> {
+        let path = self.path.take();
+        if let Some(writer) = self.writer.take() {
+            writer.finalize()?;
+            if let (Some(uploads), Some(path)) = (&self.uploads, path) {
+                // the uploader runs as long as the runtime
+                let _ = uploads.send(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AudioSink for WavSink {
+    fn name(&self) -> &str {
+        "wav"
+    }
+
+    fn queue(&self) -> (usize, DropPolicy) {
+        (WAV_QUEUE_LEN, DropPolicy::DropNewest)
+    }
+
+    fn write<'a>(&'a mut self, frame: Frame, stream: &'a StreamInfo) -> SinkFuture<'a> {
+        Box::pin(std::future::ready(self.write_frame(frame, stream)))
+    }
+
+    fn tick(&mut self) -> crate::Result<()> {
+        self.follow_schedule()?;
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+        }
+        if self.last_retention.elapsed() >= RETENTION_INTERVAL {
+            self.last_retention = Instant::now();
+            self.retention
+                .enforce(&self.directory, self.path.as_deref());
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> crate::Result<()> {
+        WavSink::finish(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("mic2net-wav-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn names(directory: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    // A file of 'len' bytes, last modified 'age' ago.
+    fn file(directory: &Path, name: &str, len: usize, age: Duration) {
+        let path = directory.join(name);
+        fs::write(&path, vec![0; len]).unwrap();
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    #[test]
+    fn over_budget_deletes_the_oldest_first() {
+        let directory = scratch("budget");
+        for (i, name) in [
+            "mic2net-1.wav",
+            "mic2net-2.flac",
+            "mic2net-3.opus",
+            "mic2net-4.webm",
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            file(&directory, name, 100, HOUR * (4 - i as u32));
+        }
+        // not recordings, whatever their age
+        file(&directory, "notes.wav", 1000, HOUR * 100);
+        file(&directory, "mic2net-0.txt", 1000, HOUR * 100);
+        let retention = Retention {
+            max_age: None,
+            max_bytes: Some(250),
+        };
+        retention.enforce(&directory, None);
+        assert_eq!(
+            names(&directory),
+            [
+                "mic2net-0.txt",
+                "mic2net-3.opus",
+                "mic2net-4.webm",
+                "notes.wav"
+            ]
+        );
+        // at the budget is within it
+        let retention = Retention {
+            max_age: None,
+            max_bytes: Some(200),
+        };
+        retention.enforce(&directory, None);
+        assert_eq!(names(&directory).len(), 4);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn recording_being_written_is_kept() {
+        let directory = scratch("current");
+        file(&directory, "mic2net-1.wav", 100, HOUR * 2);
+        file(&directory, "mic2net-2.wav", 100, HOUR);
+        // the newest, and over the budget on its own
+        file(&directory, "mic2net-3.wav", 300, Duration::ZERO);
+        let retention = Retention {
+            max_age: None,
+            max_bytes: Some(250),
+        };
+        retention.enforce(&directory, Some(&directory.join("mic2net-3.wav")));
+        assert_eq!(names(&directory), ["mic2net-3.wav"]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn expired_recordings_are_deleted() {
+        let directory = scratch("age");
+        file(&directory, "mic2net-1.wav", 100, HOUR * 72);
+        file(&directory, "mic2net-2.wav", 100, HOUR * 25);
+        file(&directory, "mic2net-3.wav", 100, HOUR * 23);
+        file(&directory, "mic2net-4.wav", 100, Duration::ZERO);
+        let retention = Retention {
+            max_age: Some(HOUR * 24),
+            max_bytes: None,
+        };
+        retention.enforce(&directory, None);
+        assert_eq!(names(&directory), ["mic2net-3.wav", "mic2net-4.wav"]);
+        // without limits nothing goes
+        let retention = Retention {
+            max_age: None,
+            max_bytes: None,
+        };
+        retention.enforce(&directory, None);
+        assert_eq!(names(&directory).len(), 2);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    fn sink(directory: &Path, max_duration: u64) -> WavSink {
+        let mut cfg = Config::default();
+        cfg.mic.sample_rate = 16000;
+        cfg.wav.directory = directory.to_string_lossy().into_owned();
+        cfg.wav.format = RecordFormat::Wav;
+        cfg.wav.max_duration = max_duration;
+        cfg.wav.max_size = 0;
+        cfg.wav.schedule.clear();
+        cfg.wav.s3 = None;
+        WavSink::new(Arc::new(cfg)).unwrap()
+    }
+
+    // Mono packet 'i' of a stream starting at 1000 s, a packet every 10 ms.
+    fn packet(i: u32) -> Vec<u8> {
+        AudioPacket {
+            device_id: 0,
+            secs: 1000 + i / 100,
+            millis: (i % 100 * 10) as u16,
+            pkt_id: i,
+            n_ch: 1,
+            samples: vec![i as i16; PACKET_N_SAMPLE],
+            timestamp: Default::default(),
+        }
+        .to_payload()
+    }
+
+    fn durations(directory: &Path) -> Vec<u32> {
+        names(directory)
+            .iter()
+            .map(|name| {
+                hound::WavReader::open(directory.join(name))
+                    .unwrap()
+                    .duration()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rotates_after_max_duration() {
+        let directory = scratch("duration");
+        let mut sink = sink(&directory, 1);
+        for i in 0..250 {
+            sink.write_packet(&packet(i)).unwrap();
+        }
+        WavSink::finish(&mut sink).unwrap();
+        assert_eq!(
+            names(&directory),
+            [
+                "mic2net-1000000.wav",
+                "mic2net-1001000.wav",
+                "mic2net-1002000.wav"
+            ]
+        );
+        assert_eq!(durations(&directory), [16000, 16000, 8000]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn rotates_after_max_size() {
+        let directory = scratch("size");
+        let mut sink = sink(&directory, 0);
+        // a packet is 320 bytes of samples, so files end after the fourth
+        sink.max_bytes = 1000;
+        for i in 0..10 {
+            sink.write_packet(&packet(i)).unwrap();
+        }
+        WavSink::finish(&mut sink).unwrap();
+        assert_eq!(
+            names(&directory),
+            [
+                "mic2net-1000000.wav",
+                "mic2net-1000040.wav",
+                "mic2net-1000080.wav"
+            ]
+        );
+        let n_samples = PACKET_N_SAMPLE as u32;
+        assert_eq!(
+            durations(&directory),
+            [4 * n_samples, 4 * n_samples, 2 * n_samples]
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,43 +1,90 @@
-use arc_swap::ArcSwap;
-use bytes::BytesMut;
-use std::io::Error;
-use std::result::Result;
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use crate::protocol::{Frame, FrameCodec};
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::Decoder;
+
+// Boxed so plain TCP and TLS streams share the same reader/writer types.
+pub type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+// Split any duplex stream (e.g. a 'TlsStream' or 'UnixStream') into socket halves.
+pub fn split_stream<S>(stream: S) -> (SocketReader, SocketWriter)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read_half, write_half) = tokio::io::split(stream);
+    (
+        SocketReader::new(Box::new(read_half)),
+        SocketWriter::new(Box::new(write_half)),
+    )
+}
 
 pub struct SocketWriter {
-    pub(crate) writer: OwnedWriteHalf,
-    pub(crate) data_to_send: Arc<ArcSwap<BytesMut>>,
-    // _read_buffer: BytesMut,
+    pub(crate) writer: BoxedWriter,
+    // reused by 'write_packets'
+    batch: BytesMut,
 }
 
 impl SocketWriter {
-    pub async fn write_packet(&mut self) -> crate::Result<()> {
-        self.writer
-            .write(
-                (self.data_to_send.as_ref() as &ArcSwap<BytesMut>)
-                    .load()
-                    .as_ref(),
-            )
-            .await?;
+    pub fn new(writer: BoxedWriter) -> SocketWriter {
+        SocketWriter {
+            writer,
+            batch: BytesMut::new(),
+        }
+    }
+
+    // The payload is shared by all clients, so it goes out next to the header (one
+    // vectored write where the stream supports it) instead of being copied.
+    pub async fn write_packet(&mut self, frame: &Frame) -> crate::Result<()> {
+        let header = frame.header()?;
+        let mut packet = Buf::chain(header.as_slice(), frame.payload.as_ref());
+        self.writer.write_all_buf(&mut packet).await?;
         // self.stream.flush().await?;
         Ok(())
     }
+
+    // Several frames in one write. Small payloads are cheaper copied together than sent
+    // in a write each.
+    pub async fn write_packets(&mut self, frames: &[Frame]) -> crate::Result<()> {
+        self.batch.clear();
+        for frame in frames {
+            self.batch.extend_from_slice(&frame.header()?);
+            self.batch.extend_from_slice(&frame.payload);
+        }
+        self.writer.write_all(&self.batch).await?;
+        Ok(())
+    }
+
+    // Flush and close the write direction; the peer reads end of stream.
+    pub async fn close(&mut self) -> crate::Result<()> {
+        self.writer.shutdown().await?;
+        Ok(())
+    }
 }
 
 pub struct SocketReader {
-    pub(crate) reader: OwnedReadHalf,
+    pub(crate) reader: BoxedReader,
+    read_buf: BytesMut,
 }
 
 impl SocketReader {
-    pub async fn read_packet(&mut self) -> Result<usize, Error> {
-        let mut read_buffer = BytesMut::with_capacity(1024);
-        // self.reader.readable().await?;
-        let read_size = self.reader.read(&mut read_buffer).await?;
-        if read_size != 0 {
-            println!("unexpected incoming socket: {:?}", &read_buffer);
+    pub fn new(reader: BoxedReader) -> SocketReader {
+        SocketReader {
+            reader,
+            read_buf: BytesMut::with_capacity(1024),
+        }
+    }
+
+    // Next frame sent by the client; None once the client closed the connection.
+    // Cancel safe: partial frames stay buffered until the next call.
+    pub async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
+        loop {
+            if let Some(frame) = FrameCodec.decode(&mut self.read_buf)? {
+                return Ok(Some(frame));
+            }
+            if self.reader.read_buf(&mut self.read_buf).await? == 0 {
+                return Ok(None);
+            }
         }
-        Ok(read_size)
     }
 }
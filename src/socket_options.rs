@@ -0,0 +1,57 @@
+// Kernel options for accepted tcp connections. The defaults suit neither end well: a LAN
+// client wants small buffers so little audio queues up, a WAN one big enough buffers
+// for the bandwidth-delay product, and a half-open connection otherwise lingers for
+// the kernel's two hours of keepalive or fifteen minutes of retransmits.
+use crate::config_file::SocketOptionsConfig;
+use socket2::{SockRef, TcpKeepalive};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tracing::warn;
+
+// Apply what 'cfg' sets; an option the system refuses is reported, not fatal.
+pub fn apply(socket: &TcpStream, cfg: &SocketOptionsConfig) {
+    let socket = SockRef::from(socket);
+    if let Some(size) = cfg.send_buffer {
+        if let Err(err) = socket.set_send_buffer_size(size) {
+            warn!("can't set the send buffer to {} bytes: {}", size, err);
+        }
+    }
+    if let Some(size) = cfg.recv_buffer {
+        if let Err(err) = socket.set_recv_buffer_size(size) {
+            warn!("can't set the receive buffer to {} bytes: {}", size, err);
+        }
+    }
+    if let Some(idle) = cfg.keepalive_idle {
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(idle));
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "windows",
+        ))]
+        let keepalive = match cfg.keepalive_interval {
+            Some(interval) => keepalive.with_interval(Duration::from_secs(interval)),
+            None => keepalive,
+        };
+        if let Err(err) = socket.set_tcp_keepalive(&keepalive) {
+            warn!("can't enable keepalive: {}", err);
+        }
+    }
+    if let Some(timeout) = cfg.user_timeout {
+        set_user_timeout(&socket, Duration::from_millis(timeout));
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn set_user_timeout(socket: &SockRef, timeout: Duration) {
+    if let Err(err) = socket.set_tcp_user_timeout(Some(timeout)) {
+        warn!("can't set the user timeout to {:?}: {}", timeout, err);
+    }
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn set_user_timeout(_socket: &SockRef, _timeout: Duration) {
+    warn!("tcp user_timeout is only supported on linux");
+}
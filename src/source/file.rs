@@ -0,0 +1,125 @@
+use crate::source::{AudioSource, FrameFuture, SourceFormat};
+use crate::PACKET_N_SAMPLE;
+use hound::{SampleFormat, WavReader};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use tokio::time::{self, Duration};
+use tracing::info;
+
+// Replays a wav file in real time at its own rate and channel count, e.g. one the wav
+// sink recorded, to reproduce a session without the microphones. Samples wider than
+// 16 bits are cut down to their top 16, float ones scaled.
+pub struct FileSource {
+    reader: WavReader<BufReader<File>>,
+    looping: bool,
+    ended: bool,
+    ticker: time::Interval,
+    spare: Option<Vec<i16>>,
+}
+
+impl FileSource {
+    // With 'looping' the file starts over at its end instead of ending the source.
+    pub fn open(path: &Path, looping: bool) -> crate::Result<FileSource> {
+        let reader = WavReader::open(path)?;
+        let spec = reader.spec();
+        if spec.channels == 0 || spec.sample_rate == 0 || reader.len() == 0 {
+            return Err(crate::Error::Capture(format!(
+                "{} holds no audio",
+                path.display()
+            )));
+        }
+        info!(
+            "replaying {}: {} channels, {} Hz, {} bit{}",
+            path.display(),
+            spec.channels,
+            spec.sample_rate,
+            spec.bits_per_sample,
+            if looping { ", looping" } else { "" }
+        );
+        let packet_duration =
+            Duration::from_micros(PACKET_N_SAMPLE as u64 * 1_000_000 / spec.sample_rate as u64);
+        Ok(FileSource {
+            reader,
+            looping,
+            ended: false,
+            ticker: time::interval(packet_duration),
+            spare: None,
+        })
+    }
+
+    fn read_sample(&mut self) -> crate::Result<Option<i16>> {
+        let spec = self.reader.spec();
+        let sample = match (spec.sample_format, spec.bits_per_sample) {
+            (SampleFormat::Int, bits) if bits <= 16 => self
+                .reader
+                .samples::<i16>()
+                .next()
+                .transpose()?
+                .map(|sample| sample << (16 - bits)),
+            (SampleFormat::Int, bits) => self
+                .reader
+                .samples::<i32>()
+                .next()
+                .transpose()?
+                .map(|sample| (sample >> (bits - 16)) as i16),
+            (SampleFormat::Float, _) => self
+                .reader
+                .samples::<f32>()
+                .next()
+                .transpose()?
+                .map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16),
+        };
+        Ok(sample)
+    }
+}
+
+impl AudioSource for FileSource {
+    fn format(&self) -> SourceFormat {
+        let spec = self.reader.spec();
+        SourceFormat {
+            sample_rate: spec.sample_rate as usize,
+            n_channel: spec.channels as usize,
+        }
+    }
+
+    fn next_frame(&mut self) -> FrameFuture<'_> {
+        Box::pin(async move {
+            if self.ended {
+                return Ok(None);
+            }
+            self.ticker.tick().await;
+            let n_ch = self.reader.spec().channels as usize;
+            let mut frame = self
+                .spare
+                .take()
+                .unwrap_or_else(|| vec![0; PACKET_N_SAMPLE * n_ch]);
+            // the last packet is padded with silence
+            frame.fill(0);
+            'frames: for n_frame in 0..PACKET_N_SAMPLE {
+                for ch in 0..n_ch {
+                    let sample = match self.read_sample()? {
+                        Some(sample) => sample,
+                        None if self.looping => {
+                            self.reader.seek(0)?;
+                            self.read_sample()?.unwrap_or(0)
+                        }
+                        None => {
+                            self.ended = true;
+                            if n_frame == 0 && ch == 0 {
+                                return Ok(None);
+                            }
+                            break 'frames;
+                        }
+                    };
+                    frame[ch * PACKET_N_SAMPLE + n_frame] = sample;
+                }
+            }
+            Ok(Some(frame))
+        })
+    }
+
+    fn recycle(&mut self, frame: Vec<i16>) {
+        self.spare = Some(frame);
+    }
+}
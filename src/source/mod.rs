@@ -0,0 +1,61 @@
+// Producers of the capture stream. The cpal capture, the file replayer and the test
+// tone implement 'AudioSource', and so can sources of an embedding application, which
+// 'start_source' then publishes like the microphone. The alsa, pipewire and wasapi
+// backends publish from loops of their own.
+use crate::packet::Packetizer;
+use std::future::Future;
+use std::pin::Pin;
+use tracing::info;
+
+pub mod file;
+pub mod tone;
+
+// What a source produces; the packetizer and the transports are set up for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceFormat {
+    pub sample_rate: usize,
+    pub n_channel: usize,
+}
+
+pub type FrameFuture<'a> = Pin<Box<dyn Future<Output = crate::Result<Option<Vec<i16>>>> + 'a>>;
+
+// Sources pace themselves: 'next_frame' completes when the next packet is due, as
+// capture would. Not Send, since some device streams aren't; 'start_source' runs on the
+// calling task.
+pub trait AudioSource {
+    fn format(&self) -> SourceFormat;
+
+    // The next packet, 'PACKET_N_SAMPLE' samples per channel, one channel after another
+    // like the packet payload; None once the source has ended.
+    fn next_frame(&mut self) -> FrameFuture<'_>;
+
+    // A packet that was published, back for reuse.
+    fn recycle(&mut self, _frame: Vec<i16>) {}
+}
+
+// Publish what 'source' produces until it ends or 'shutdown' completes; SIGINT
+// ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_source(
+    mut source: impl AudioSource,
+    mut packetizer: Packetizer,
+    shutdown: impl Future,
+) -> crate::Result<()> {
+    let mut audio_data = Vec::new();
+    let publish = async {
+        while let Some(frame) = source.next_frame().await? {
+            audio_data.clear();
+            audio_data.extend(frame.iter().flat_map(|sample| sample.to_ne_bytes()));
+            source.recycle(frame);
+            packetizer.publish(&audio_data);
+        }
+        info!("source ended");
+        Ok(())
+    };
+    tokio::select! {
+        res = publish => res,
+        _ = shutdown => {
+            info!("shutting down capture");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use crate::source::{AudioSource, FrameFuture, SourceFormat};
+use crate::PACKET_N_SAMPLE;
+use std::f32::consts::TAU;
+use std::str::FromStr;
+use tokio::time::{self, Duration};
+use tracing::info;
+
+// half of full scale, well clear of clipping after gain stages
+const AMPLITUDE: f32 = 0.5 * i16::MAX as f32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Waveform {
+    // 'frequency' on every channel
+    Sine,
+    // white noise, uncorrelated between channels
+    Noise,
+    // a linear sweep from 'frequency' to 'end_frequency' over 'sweep', over and over
+    Chirp,
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Waveform, String> {
+        match s {
+            "sine" => Ok(Waveform::Sine),
+            "noise" => Ok(Waveform::Noise),
+            "chirp" => Ok(Waveform::Chirp),
+            _ => Err(format!(
+                "unknown waveform {:?}, use sine, noise or chirp",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Tone {
+    pub waveform: Waveform,
+    // Hz
+    pub frequency: f32,
+    pub end_frequency: f32,
+    pub sweep: Duration,
+}
+
+// Planar packets of 'PACKET_N_SAMPLE' samples per channel of a 'Tone'.
+pub struct ToneGenerator {
+    tone: Tone,
+    sample_rate: usize,
+    n_ch: usize,
+    phase: f32,
+    // samples into the current sweep
+    n_swept: usize,
+    n_sweep: usize,
+    // xorshift state of each channel's noise
+    noise: Vec<u64>,
+}
+
+impl ToneGenerator {
+    pub fn new(tone: Tone, sample_rate: usize, n_ch: usize) -> ToneGenerator {
+        ToneGenerator {
+            tone,
+            sample_rate,
+            n_ch,
+            phase: 0.0,
+            n_swept: 0,
+            n_sweep: ((tone.sweep.as_secs_f64() * sample_rate as f64) as usize).max(1),
+            noise: (0..n_ch as u64)
+                .map(|ch| 0x9e37_79b9_7f4a_7c15 ^ (ch + 1).wrapping_mul(0xbf58_476d_1ce4_e5b9))
+                .collect(),
+        }
+    }
+
+    pub fn n_channel(&self) -> usize {
+        self.n_ch
+    }
+
+    // Fill 'planar' with the next packet, 'PACKET_N_SAMPLE' samples of every channel.
+    pub fn next_packet(&mut self, planar: &mut [i16]) {
+        let (first, rest) = planar.split_at_mut(PACKET_N_SAMPLE);
+        match self.tone.waveform {
+            Waveform::Sine | Waveform::Chirp => {
+                for sample in first.iter_mut() {
+                    *sample = (self.phase.sin() * AMPLITUDE) as i16;
+                    self.phase =
+                        (self.phase + TAU * self.frequency() / self.sample_rate as f32) % TAU;
+                    self.n_swept = (self.n_swept + 1) % self.n_sweep;
+                }
+                for ch_samples in rest.chunks_exact_mut(PACKET_N_SAMPLE) {
+                    ch_samples.copy_from_slice(first);
+                }
+            }
+            Waveform::Noise => {
+                for (ch_samples, state) in planar
+                    .chunks_exact_mut(PACKET_N_SAMPLE)
+                    .zip(self.noise.iter_mut())
+                {
+                    for sample in ch_samples.iter_mut() {
+                        *state ^= *state << 13;
+                        *state ^= *state >> 7;
+                        *state ^= *state << 17;
+                        // top 16 bits, halved
+                        *sample = ((*state >> 48) as u16 as i16) / 2;
+                    }
+                }
+            }
+        }
+    }
+
+    // The instantaneous frequency.
+    fn frequency(&self) -> f32 {
+        match self.tone.waveform {
+            Waveform::Chirp => {
+                let progress = self.n_swept as f32 / self.n_sweep as f32;
+                self.tone.frequency + (self.tone.end_frequency - self.tone.frequency) * progress
+            }
+            _ => self.tone.frequency,
+        }
+    }
+}
+
+// Stand-in for the microphone: a 'ToneGenerator' paced like real capture, so the
+// transports can be exercised and benchmarked on machines without audio hardware.
+pub struct ToneSource {
+    generator: ToneGenerator,
+    sample_rate: usize,
+    ticker: time::Interval,
+    spare: Option<Vec<i16>>,
+}
+
+impl ToneSource {
+    pub fn new(tone: Tone, sample_rate: usize, n_ch: usize) -> ToneSource {
+        let packet_duration =
+            Duration::from_micros((PACKET_N_SAMPLE * 1_000_000 / sample_rate) as u64);
+        match tone.waveform {
+            Waveform::Sine => info!("test tone {} Hz on {} channels", tone.frequency, n_ch),
+            Waveform::Noise => info!("test noise on {} channels", n_ch),
+            Waveform::Chirp => info!(
+                "test chirp {} to {} Hz in {:?} on {} channels",
+                tone.frequency, tone.end_frequency, tone.sweep, n_ch
+            ),
+        }
+        ToneSource {
+            generator: ToneGenerator::new(tone, sample_rate, n_ch),
+            sample_rate,
+            ticker: time::interval(packet_duration),
+            spare: None,
+        }
+    }
+}
+
+impl AudioSource for ToneSource {
+    fn format(&self) -> SourceFormat {
+        SourceFormat {
+            sample_rate: self.sample_rate,
+            n_channel: self.generator.n_channel(),
+        }
+    }
+
+    fn next_frame(&mut self) -> FrameFuture<'_> {
+        Box::pin(async move {
+            self.ticker.tick().await;
+            let mut frame = self
+                .spare
+                .take()
+                .unwrap_or_else(|| vec![0; PACKET_N_SAMPLE * self.generator.n_channel()]);
+            self.generator.next_packet(&mut frame);
+            Ok(Some(frame))
+        })
+    }
+
+    fn recycle(&mut self, frame: Vec<i16>) {
+        self.spare = Some(frame);
+    }
+}
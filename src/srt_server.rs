@@ -0,0 +1,242 @@
+// Serves the frame stream to SRT callers. SRT retransmits lost packets within the
+// latency budget, so the stream survives lossy links without TCP's head-of-line stalls.
+use crate::config_file::{Config, SrtConfig};
+use crate::distributor::{frames_in, Distributor, DropPolicy, Subscription};
+use crate::metrics::{Metrics, METRICS};
+use crate::protocol::{encode_frame, Frame};
+use futures_util::{SinkExt, StreamExt};
+use srt_tokio::access::{RejectReason, ServerRejectReason};
+use srt_tokio::options::{KeySize, Passphrase};
+use srt_tokio::{SrtIncoming, SrtListener, SrtSocket};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tracing::{error, info, info_span, warn, Instrument};
+
+// Live mode payload size of srt and its mpeg-ts users (7 * 188). Frames of more
+// channels are larger, so they go out split over several messages.
+const SRT_LIVE_PAYLOAD: usize = 1316;
+
+pub struct SrtServer {
+    port: u16,
+    listener: SrtListener,
+    incoming: SrtIncoming,
+    limit_connections: Arc<Semaphore>,
+    distributor: Arc<Distributor>,
+    queue_len: usize,
+    drop_policy: DropPolicy,
+    // frames of pre-roll per new client
+    preroll: usize,
+    next_client_id: u64,
+    notify_shutdown: broadcast::Sender<()>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+    shutdown_complete_rx: mpsc::Receiver<()>,
+}
+
+impl SrtServer {
+    pub async fn new(cfg: Arc<Config>, distributor: Arc<Distributor>) -> crate::Result<SrtServer> {
+        let port = cfg.srt.listen_port;
+        let addr = SocketAddr::new(cfg.srt.bind_address.parse()?, port);
+        let encryption = encryption(&cfg.srt)?;
+        let (listener, incoming) = SrtListener::builder()
+            .latency(Duration::from_millis(cfg.srt.latency))
+            .set(|options| {
+                if let Some((key_size, passphrase)) = encryption {
+                    options.encryption.key_size = key_size;
+                    options.encryption.passphrase = Some(passphrase);
+                }
+            })
+            .bind(addr)
+            .await
+            .map_err(crate::Error::bind(addr))?;
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
+
+        let server = SrtServer {
+            port,
+            listener,
+            incoming,
+            limit_connections: Arc::new(Semaphore::new(cfg.srt.max_clients.into())),
+            distributor,
+            queue_len: cfg.srt.queue_len,
+            drop_policy: cfg.srt.drop_policy,
+            preroll: frames_in(
+                cfg.srt.preroll,
+                cfg.srt.sample_rate.unwrap_or(cfg.mic.sample_rate),
+            ),
+            next_client_id: 0,
+            notify_shutdown,
+            shutdown_complete_tx,
+            shutdown_complete_rx,
+        };
+        Ok(server)
+    }
+
+    async fn run(&mut self) -> crate::Result<()> {
+        info!("srt listen on port: {}", self.port);
+
+        loop {
+            let request = match self.incoming.incoming().next().await {
+                Some(request) => request,
+                None => return Ok(()),
+            };
+            let peer = request.remote();
+            info!("connection from {}", peer);
+            Metrics::inc(&METRICS.connections_accepted);
+            // callers retry on their own, so turn them away instead of queueing them
+            let permit = match self.limit_connections.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    warn!(peer = %peer, "srt caller rejected; max_clients reached");
+                    let _ = request
+                        .reject(RejectReason::Server(ServerRejectReason::Overload))
+                        .await;
+                    continue;
+                }
+            };
+
+            let distributor = self.distributor.clone();
+            let (queue_len, drop_policy, preroll) =
+                (self.queue_len, self.drop_policy, self.preroll);
+            let shutdown_signal = self.notify_shutdown.subscribe();
+            let shutdown_complete = self.shutdown_complete_tx.clone();
+            let span = info_span!("srt_client", peer = %peer, id = self.next_client_id);
+            self.next_client_id += 1;
+
+            let connection = async move {
+                // handshake in the task so a stalled caller can't block the accept loop
+                match request.accept(None).await {
+                    Ok(socket) => {
+                        let ip_addr = peer.to_string();
+                        METRICS.client_connected(&ip_addr);
+                        let mut handler = SrtHandler {
+                            ip_addr,
+                            socket,
+                            frames: distributor.subscribe_with_preroll(
+                                queue_len,
+                                drop_policy,
+                                preroll,
+                            ),
+                            shutdown_signal,
+                            _shutdown_complete: shutdown_complete,
+                        };
+                        if let Err(err) = handler.run().await {
+                            error!("srt connection error: {}", err);
+                        }
+                    }
+                    Err(err) => warn!("srt handshake failed: {}", err),
+                }
+                drop(permit);
+            };
+            tokio::spawn(connection.instrument(span));
+        }
+    }
+}
+
+// Key size and passphrase when encryption is configured.
+fn encryption(cfg: &SrtConfig) -> crate::Result<Option<(KeySize, Passphrase)>> {
+    if cfg.passphrase.is_empty() {
+        return Ok(None);
+    }
+    let key_size = KeySize::try_from(cfg.key_size)?;
+    let passphrase = Passphrase::try_from(cfg.passphrase.clone())?;
+    Ok(Some((key_size, passphrase)))
+}
+
+pub struct SrtHandler {
+    ip_addr: String,
+    socket: SrtSocket,
+    frames: Subscription,
+    shutdown_signal: broadcast::Receiver<()>,
+    _shutdown_complete: mpsc::Sender<()>,
+}
+
+impl SrtHandler {
+    async fn run(&mut self) -> crate::Result<()> {
+        let stream_info = self.frames.stream_info_frame();
+        self.send(&stream_info).await?;
+        loop {
+            tokio::select! {
+                frame = self.frames.recv() => match frame {
+                    Some(frame) => {
+                        self.send(&frame).await?;
+                        METRICS.frame_sent(&self.ip_addr, frame.encoded_len());
+                    }
+                    None => {
+                        warn!("fell behind the stream");
+                        self.socket.close().await?;
+                        return Ok(());
+                    }
+                },
+                // callers have nothing to say; this only notices them leaving
+                msg = self.socket.next() => match msg {
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => return Err(err.into()),
+                    None => return Ok(()),
+                },
+                _ = self.shutdown_signal.recv() => {
+                    self.socket.close().await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // One frame, split into live-mode messages.
+    async fn send(&mut self, frame: &Frame) -> crate::Result<()> {
+        let mut encoded = encode_frame(frame);
+        let now = Instant::now();
+        while !encoded.is_empty() {
+            let len = encoded.len().min(SRT_LIVE_PAYLOAD);
+            self.socket.feed((now, encoded.split_to(len))).await?;
+        }
+        self.socket.flush().await?;
+        Ok(())
+    }
+}
+
+impl Drop for SrtHandler {
+    fn drop(&mut self) {
+        METRICS.client_disconnected(&self.ip_addr);
+        info!("disconnected");
+    }
+}
+
+// Run srt server; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_srt_server(
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    shutdown: impl Future,
+) {
+    let mut server = match SrtServer::new(cfg, distributor).await {
+        Ok(server) => server,
+        Err(err) => {
+            error!("failed to start srt server: {}", err);
+            return;
+        }
+    };
+    tokio::select! {
+        res = server.run() => {
+            if let Err(err) = res {
+                error!("srt server stopped: {}", err);
+            }
+        }
+        _ = shutdown => {
+            info!("cleaning up srt server");
+        }
+    }
+
+    let SrtServer {
+        mut listener,
+        mut shutdown_complete_rx,
+        shutdown_complete_tx,
+        notify_shutdown,
+        ..
+    } = server;
+    drop(notify_shutdown);
+    drop(shutdown_complete_tx);
+    shutdown_complete_rx.recv().await;
+    listener.close().await;
+}
@@ -0,0 +1,58 @@
+// Socket activation: systemd binds the ports of a .socket unit, e.g.
+//
+//     [Socket]
+//     ListenStream=2345
+//
+// and hands them to the service as fds 3 and up, named in LISTEN_FDS. The port stays
+// open across restarts of the service; connects in between wait in the backlog.
+use socket2::{Socket, Type};
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+const LISTEN_FDS_START: RawFd = 3;
+
+// None until the first lookup collects the passed sockets; taken ones are removed.
+static LISTENERS: Mutex<Option<Vec<TcpListener>>> = Mutex::new(None);
+
+// The socket systemd passed in for tcp 'port', if any; each is handed out once.
+pub fn listener(port: u16) -> Option<TcpListener> {
+    let mut listeners = LISTENERS.lock().unwrap();
+    let listeners = listeners.get_or_insert_with(passed_listeners);
+    let index = listeners
+        .iter()
+        .position(|listener| matches!(listener.local_addr(), Ok(addr) if addr.port() == port))?;
+    info!("using the socket passed in by systemd for port {}", port);
+    Some(listeners.swap_remove(index))
+}
+
+fn passed_listeners() -> Vec<TcpListener> {
+    // the variables are inherited by children too, which must leave the fds alone
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let n_fds = match std::env::var("LISTEN_FDS") {
+        Ok(n_fds) if for_us => n_fds.parse::<RawFd>().unwrap_or(0),
+        _ => return Vec::new(),
+    };
+    (LISTEN_FDS_START..LISTEN_FDS_START + n_fds)
+        .filter_map(|fd| {
+            // systemd passes these fds to us alone, and they are taken only once
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            let is_tcp = matches!(socket.r#type(), Ok(Type::STREAM))
+                && matches!(socket.local_addr(), Ok(addr) if addr.is_ipv4() || addr.is_ipv6());
+            if !is_tcp {
+                warn!("ignoring fd {} from systemd: not a tcp socket", fd);
+                // not ours to close
+                std::mem::forget(socket);
+                return None;
+            }
+            if let Err(err) = socket.set_cloexec(true) {
+                warn!("can't set close-on-exec on fd {}: {}", fd, err);
+            }
+            Some(socket.into())
+        })
+        .collect()
+}
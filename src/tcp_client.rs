@@ -0,0 +1,1135 @@
+use crate::audio::encode::WireCodec;
+use crate::audio::format::{to_i16, SampleFormat};
+use crate::clock::{ClockOffset, ClockSync};
+use crate::protocol::{Frame, FrameKind, ReceiveReport, StreamInfo, Timestamp};
+use crate::psk::{self, Psk};
+use crate::socket::{SocketReader, SocketWriter};
+use crate::transform::{decompressor, Transform};
+use crate::{HEADER_LEN, PACKET_N_SAMPLE};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, TcpStream, ToSocketAddrs};
+use tokio::sync::watch;
+use tokio::time;
+use tracing::{info, warn};
+
+// One audio packet as sent by the server, samples still planar.
+#[derive(Clone)]
+pub struct AudioPacket {
+    pub device_id: u16,
+    // capture time
+    pub secs: u32,
+    pub millis: u16,
+    pub pkt_id: u32,
+    pub n_ch: usize,
+    pub samples: Vec<i16>,
+    // capture time from the frame header, see 'Timestamp'; default for bare payloads
+    pub timestamp: Timestamp,
+}
+
+impl AudioPacket {
+    pub fn parse(payload: &[u8]) -> crate::Result<AudioPacket> {
+        AudioPacket::parse_as(payload, SampleFormat::I16)
+    }
+
+    // Parse a packet whose samples are in 'format'; they are converted to i16.
+    pub fn parse_as(payload: &[u8], format: SampleFormat) -> crate::Result<AudioPacket> {
+        let audio_len = payload.len().saturating_sub(HEADER_LEN);
+        let channel_len = PACKET_N_SAMPLE * format.bytes_per_sample();
+        if payload.len() < HEADER_LEN || !audio_len.is_multiple_of(channel_len) {
+            return Err(crate::Error::Protocol(format!(
+                "bad audio packet of {} bytes",
+                payload.len()
+            )));
+        }
+        Ok(AudioPacket {
+            device_id: u16::from_be_bytes([payload[0], payload[1]]),
+            secs: u32::from_be_bytes(payload[2..6].try_into().unwrap()),
+            millis: u16::from_be_bytes([payload[6], payload[7]]),
+            pkt_id: u32::from_be_bytes(payload[8..12].try_into().unwrap()),
+            n_ch: audio_len / channel_len,
+            samples: to_i16(&payload[HEADER_LEN..], format),
+            timestamp: Timestamp::default(),
+        })
+    }
+
+    // Parse an audio frame, keeping the timestamps of its header.
+    pub fn from_frame(frame: &Frame, format: SampleFormat) -> crate::Result<AudioPacket> {
+        let mut packet = AudioPacket::parse_as(&frame.payload, format)?;
+        packet.timestamp = frame.timestamp;
+        Ok(packet)
+    }
+
+    // 'PACKET_N_SAMPLE' samples of channel 'ch'.
+    pub fn channel(&self, ch: usize) -> &[i16] {
+        &self.samples[ch * PACKET_N_SAMPLE..(ch + 1) * PACKET_N_SAMPLE]
+    }
+
+    // The packet as the server sends it, with i16 samples.
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(HEADER_LEN + self.samples.len() * 2);
+        payload.extend_from_slice(&self.device_id.to_be_bytes());
+        payload.extend_from_slice(&self.secs.to_be_bytes());
+        payload.extend_from_slice(&self.millis.to_be_bytes());
+        payload.extend_from_slice(&self.pkt_id.to_be_bytes());
+        for sample in &self.samples {
+            payload.extend_from_slice(&sample.to_ne_bytes());
+        }
+        payload
+    }
+
+    // Same shape as 'template', all zero; stands in for frames the server dropped.
+    fn silence(template: &AudioPacket) -> AudioPacket {
+        AudioPacket {
+            samples: vec![0; template.samples.len()],
+            ..*template
+        }
+    }
+}
+
+// Servers report silence 100 ms at a time; a frame of more than this doesn't come from
+// one and would take the memory of that many packets.
+const MAX_SILENCE_MS: u32 = 5000;
+
+// Turns silence frames back into silent packets of the stream; samples that don't
+// fill a whole packet are carried over to the next frame.
+#[derive(Default)]
+pub struct SilenceFill {
+    carry: usize,
+}
+
+impl SilenceFill {
+    pub fn packets(
+        &mut self,
+        frame: &Frame,
+        stream: &StreamInfo,
+    ) -> crate::Result<Vec<AudioPacket>> {
+        let ms = frame.silence_ms()?;
+        if ms > MAX_SILENCE_MS {
+            return Err(crate::Error::Protocol(format!(
+                "silence frame of {} ms",
+                ms
+            )));
+        }
+        let ms = ms as usize;
+        let samples = self.carry + ms * stream.sample_rate / 1000;
+        self.carry = samples % PACKET_N_SAMPLE;
+        let packets = (0..samples / PACKET_N_SAMPLE)
+            .map(|i| {
+                let offset = i * PACKET_N_SAMPLE * 1_000_000 / stream.sample_rate;
+                let timestamp = frame.timestamp.after(Duration::from_micros(offset as u64));
+                AudioPacket {
+                    device_id: 0,
+                    secs: (timestamp.wall_us / 1_000_000) as u32,
+                    millis: (timestamp.wall_us / 1000 % 1000) as u16,
+                    pkt_id: 0,
+                    n_ch: stream.n_ch,
+                    samples: vec![0; PACKET_N_SAMPLE * stream.n_ch],
+                    timestamp,
+                }
+            })
+            .collect();
+        Ok(packets)
+    }
+}
+
+// Counts of the frames a client received; lost frames are the gaps in the sequence
+// numbers, concealed ones those of them filled in from the audio before the gap.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReceiveStats {
+    pub received: u64,
+    pub lost: u64,
+    pub concealed: u64,
+    // arrived after later ones and dropped, their place had been concealed already
+    pub late: u64,
+    // lost, but rebuilt from parity frames before they could count as lost; udp only
+    pub recovered: u64,
+    // lost, but sent again on request in time; udp only
+    pub resent: u64,
+}
+
+impl ReceiveStats {
+    // What clients tell the server.
+    pub fn report(&self) -> ReceiveReport {
+        ReceiveReport {
+            received: self.received,
+            lost: self.lost,
+            reordered: self.late,
+        }
+    }
+}
+
+// Longer gaps are treated as a restart of the stream and not filled in.
+const MAX_FILLED_GAP: u32 = 50;
+// After a resume, older frames than this many behind mean the stream started over.
+const MAX_REPLAYED: u32 = 3000;
+// packets over which concealment fades the last received one out, 30 ms
+const FADE_PACKETS: u32 = 3;
+
+// Turns the frames of a stream into a continuous run of packets: silence frames become
+// silent packets and gaps in the sequence numbers are concealed, for every client.
+#[derive(Default)]
+pub struct StreamReceiver {
+    // from the last stream info frame
+    stream: Option<StreamInfo>,
+    next_seq: Option<u32>,
+    silence: SilenceFill,
+    concealment: Concealment,
+    queued: VecDeque<AudioPacket>,
+    stats: ReceiveStats,
+    // stream and next seq as the connection was lost, until the stream is taken up again
+    resuming: Option<(StreamInfo, u32)>,
+    // resumed and not yet past the frames already received, which may come again
+    skip_received: bool,
+}
+
+impl StreamReceiver {
+    pub fn stream_info(&self) -> Option<StreamInfo> {
+        self.stream
+    }
+
+    pub fn stats(&self) -> ReceiveStats {
+        self.stats
+    }
+
+    // A stream info frame: the following frames belong to a new stream, numbered afresh.
+    pub fn start(&mut self, frame: &Frame) -> crate::Result<StreamInfo> {
+        let stream = StreamInfo::parse(&frame.payload)?;
+        if stream.codec != WireCodec::Pcm {
+            return Err(crate::Error::Codec(format!(
+                "can't play a {:?} stream",
+                stream.codec
+            )));
+        }
+        if self.stream != Some(stream) {
+            info!(
+                "{} Hz, {} channels of {:?}",
+                stream.sample_rate, stream.n_ch, stream.sample_format
+            );
+        }
+        self.stream = Some(stream);
+        // a stream that resumes goes on where it broke off, gap concealed
+        if let Some((_, next_seq)) = self.resuming.filter(|(resumed, _)| *resumed == stream) {
+            self.resuming = None;
+            self.next_seq = Some(next_seq);
+            self.skip_received = true;
+            return Ok(stream);
+        }
+        self.next_seq = None;
+        self.skip_received = false;
+        self.silence = SilenceFill::default();
+        self.concealment = Concealment::default();
+        Ok(stream)
+    }
+
+    // The connection was lost; the stream may resume on the next one. The seq of the
+    // last frame received, for the server to go on after.
+    pub fn reconnected(&mut self) -> Option<u32> {
+        if self.resuming.is_none() {
+            self.resuming = self.stream.zip(self.next_seq);
+        }
+        self.resuming.map(|(_, next_seq)| next_seq.wrapping_sub(1))
+    }
+
+    // An audio or silence frame; its packets, and those standing in for the frames
+    // missing before it, queue up for 'pop'.
+    pub fn push(&mut self, frame: &Frame) -> crate::Result<()> {
+        let mut gap = match self.next_seq {
+            Some(expected) => frame.seq.wrapping_sub(expected),
+            None => 0,
+        };
+        if gap > u32::MAX / 2 {
+            // further back than any server keeps: a restarted server numbering afresh
+            if self.skip_received && gap.wrapping_neg() > MAX_REPLAYED {
+                info!("the stream started over");
+                gap = 0;
+                self.concealment.restart();
+            } else {
+                if !self.skip_received {
+                    self.stats.late += 1;
+                }
+                return Ok(());
+            }
+        }
+        self.skip_received = false;
+        // frames of another stream came first
+        self.resuming = None;
+        let mut packets = match (frame.kind, &self.stream) {
+            (FrameKind::Silence, Some(stream)) => self.silence.packets(frame, stream)?,
+            (FrameKind::Silence, None) => {
+                return Err(crate::Error::Protocol("silence before stream info".into()))
+            }
+            (_, stream) => {
+                let format = stream.map_or(SampleFormat::I16, |stream| stream.sample_format);
+                vec![AudioPacket::from_frame(frame, format)?]
+            }
+        };
+        self.next_seq = Some(frame.seq.wrapping_add(1));
+        self.stats.received += 1;
+        if gap != 0 {
+            warn!("lost {} frames", gap);
+            self.stats.lost += gap as u64;
+        }
+        if let Some(next) = packets.first() {
+            if gap <= MAX_FILLED_GAP {
+                let filled = self.concealment.conceal(gap, next);
+                self.stats.concealed +=
+                    filled.iter().filter(|(_, concealed)| *concealed).count() as u64;
+                self.queued
+                    .extend(filled.into_iter().map(|(packet, _)| packet));
+            } else {
+                self.concealment.restart();
+            }
+        }
+        for packet in &mut packets {
+            self.concealment.received(packet);
+        }
+        self.queued.extend(packets);
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<AudioPacket> {
+        self.queued.pop_front()
+    }
+}
+
+// Stands in for lost packets with the last one received, fading it out over
+// 'FADE_PACKETS', and fades the stream back in where it resumes; packets that don't
+// follow on from the one before start with a short blend from where it ended, so
+// losses don't click.
+struct Concealment {
+    last: Option<AudioPacket>,
+    // last sample of every channel handed out
+    tail: Vec<i16>,
+    // gain the next received packet fades in from
+    resume_gain: f32,
+}
+
+impl Default for Concealment {
+    fn default() -> Concealment {
+        Concealment {
+            last: None,
+            tail: Vec::new(),
+            resume_gain: 1.0,
+        }
+    }
+}
+
+// samples of a blend at a seam, 1 ms at 16 kHz
+const SEAM_LEN: usize = 16;
+
+impl Concealment {
+    // 'gap' packets shaped like 'next', the first packet after the gap; true for those
+    // carrying concealed audio rather than silence.
+    fn conceal(&mut self, gap: u32, next: &AudioPacket) -> Vec<(AudioPacket, bool)> {
+        let gain = |k: u32| (1.0 - k as f32 / FADE_PACKETS as f32).max(0.0);
+        let mut filled = Vec::with_capacity(gap as usize);
+        for k in 0..gap {
+            let (packet, concealed) = match &self.last {
+                Some(last) if k < FADE_PACKETS && last.n_ch == next.n_ch => {
+                    let mut packet = AudioPacket {
+                        samples: last.samples.clone(),
+                        ..AudioPacket::silence(next)
+                    };
+                    fade(&mut packet, gain(k), gain(k + 1));
+                    self.blend_seam(&mut packet);
+                    (packet, true)
+                }
+                _ => (AudioPacket::silence(next), false),
+            };
+            self.set_tail(&packet);
+            filled.push((packet, concealed));
+        }
+        if gap > 0 {
+            self.resume_gain = gain(gap);
+        }
+        filled
+    }
+
+    // After a gap too long to fill.
+    fn restart(&mut self) {
+        self.last = None;
+        self.tail.clear();
+        self.resume_gain = 0.0;
+    }
+
+    fn received(&mut self, packet: &mut AudioPacket) {
+        if self.resume_gain < 1.0 {
+            fade(packet, self.resume_gain, 1.0);
+            self.blend_seam(packet);
+            self.resume_gain = 1.0;
+        }
+        self.set_tail(packet);
+        self.last = Some(packet.clone());
+    }
+
+    fn blend_seam(&self, packet: &mut AudioPacket) {
+        if self.tail.len() != packet.n_ch {
+            return;
+        }
+        for (channel, &from) in packet
+            .samples
+            .chunks_exact_mut(PACKET_N_SAMPLE)
+            .zip(&self.tail)
+        {
+            for (i, sample) in channel.iter_mut().take(SEAM_LEN).enumerate() {
+                let t = (i + 1) as f32 / (SEAM_LEN + 1) as f32;
+                *sample = (from as f32 * (1.0 - t) + *sample as f32 * t).round() as i16;
+            }
+        }
+    }
+
+    fn set_tail(&mut self, packet: &AudioPacket) {
+        self.tail.clear();
+        self.tail.extend(
+            packet
+                .samples
+                .chunks_exact(PACKET_N_SAMPLE)
+                .map(|ch| ch[PACKET_N_SAMPLE - 1]),
+        );
+    }
+}
+
+// Ramp every channel of 'packet' linearly from gain 'from' to 'to'.
+fn fade(packet: &mut AudioPacket, from: f32, to: f32) {
+    for channel in packet.samples.chunks_exact_mut(PACKET_N_SAMPLE) {
+        for (i, sample) in channel.iter_mut().enumerate() {
+            let gain = from + (to - from) * i as f32 / PACKET_N_SAMPLE as f32;
+            *sample = (*sample as f32 * gain).round() as i16;
+        }
+    }
+}
+
+// Client for the tcp server. Frames dropped by the server for this client show up as
+// gaps in the sequence number and are concealed, silence frames are filled in, so
+// consumers always see a continuous stream.
+pub struct TcpClient {
+    // as resolved when connecting, for reconnects
+    addrs: Vec<SocketAddr>,
+    token: Option<String>,
+    socket_reader: SocketReader,
+    // kept so the connection stays open in both directions
+    socket_writer: SocketWriter,
+    receiver: StreamReceiver,
+    clock: ClockSync,
+    last_time_request: Instant,
+    last_report: Instant,
+    // undoing the server's, for the compression of the stream
+    transforms: Vec<Box<dyn Transform>>,
+    // set with 'set_psk'; audio frames then have to open with it
+    psk: Option<Psk>,
+    opener: Option<Box<dyn Transform>>,
+    // the last one asked for, asked for again on every reconnect
+    format: Option<StreamInfo>,
+    reconnect: Option<ReconnectConfig>,
+    // why the server hung up, if it said
+    goodbye: Option<String>,
+    state: watch::Sender<ConnectionState>,
+}
+
+// How often the server's clocks are sampled: quickly until there is a first estimate
+// to pick from, then just enough to follow drift.
+const CLOCK_SYNC_STARTUP: (usize, Duration) = (4, Duration::from_secs(1));
+const CLOCK_SYNC_INTERVAL: Duration = Duration::from_secs(15);
+// how often the server hears how the stream arrives
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+// Connecting again when the connection drops, see 'TcpClient::set_reconnect'.
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectConfig {
+    // wait before the first attempt, doubling with every failed one up to 'max_delay'
+    pub delay: Duration,
+    pub max_delay: Duration,
+    // None to keep trying
+    pub max_attempts: Option<usize>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> ReconnectConfig {
+        ReconnectConfig {
+            delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            max_attempts: None,
+        }
+    }
+}
+
+// Where the connection to the server stands, for 'TcpClient::state'.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    // the connection was lost; attempt 'attempt' follows after 'delay'
+    Reconnecting { attempt: usize, delay: Duration },
+    // for good: the server hung up or kicked the client, or reconnecting gave up
+    Closed,
+}
+
+// Resolved 'addrs', tried in turn.
+async fn open(addrs: &[SocketAddr]) -> crate::Result<(SocketReader, SocketWriter)> {
+    let socket = TcpStream::connect(addrs).await?;
+    socket.set_nodelay(true)?;
+    info!("connected to {}", socket.peer_addr()?);
+    let (read_half, write_half) = socket.into_split();
+    Ok((
+        SocketReader::new(Box::new(read_half)),
+        SocketWriter::new(Box::new(write_half)),
+    ))
+}
+
+impl TcpClient {
+    // Connect and, for servers with auth enabled, send 'token' first.
+    pub async fn connect(
+        addr: impl ToSocketAddrs,
+        token: Option<&str>,
+    ) -> crate::Result<TcpClient> {
+        let addrs: Vec<SocketAddr> = lookup_host(addr).await?.collect();
+        let (socket_reader, socket_writer) = open(&addrs).await?;
+        let mut client = TcpClient {
+            addrs,
+            token: token.map(str::to_string),
+            socket_reader,
+            socket_writer,
+            receiver: StreamReceiver::default(),
+            clock: ClockSync::default(),
+            last_time_request: Instant::now(),
+            last_report: Instant::now(),
+            transforms: Vec::new(),
+            psk: None,
+            opener: None,
+            format: None,
+            reconnect: None,
+            goodbye: None,
+            state: watch::channel(ConnectionState::Connected).0,
+        };
+        client.hello(None).await?;
+        Ok(client)
+    }
+
+    // What opens every connection: the token, the format asked for and where to
+    // resume, if any, then a first clock sample.
+    async fn hello(&mut self, resume: Option<u32>) -> crate::Result<()> {
+        if let Some(token) = &self.token {
+            self.socket_writer.write_packet(&Frame::auth(token)).await?;
+        }
+        if let Some(format) = &self.format {
+            self.socket_writer
+                .write_packet(&Frame::format_request(format))
+                .await?;
+        }
+        if let Some(seq) = resume {
+            self.socket_writer.write_packet(&Frame::resume(seq)).await?;
+        }
+        self.last_time_request = Instant::now();
+        self.socket_writer
+            .write_packet(&Frame::time_request())
+            .await
+    }
+
+    // Connect again whenever the connection drops or the server hangs up, other than
+    // by kicking the client, asking the server to resume the stream after the last
+    // frame received; what it no longer has is concealed like any other gap.
+    pub fn set_reconnect(&mut self, cfg: ReconnectConfig) {
+        self.reconnect = Some(cfg);
+    }
+
+    // Follows the connection through reconnects.
+    pub fn state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    // Rate, channels and format of the stream; None until the server sent them.
+    pub fn stream_info(&self) -> Option<StreamInfo> {
+        self.receiver.stream_info()
+    }
+
+    // Frames received, lost and concealed so far.
+    pub fn stats(&self) -> ReceiveStats {
+        self.receiver.stats()
+    }
+
+    // Server clock minus ours, to turn the timestamps of packets into local time;
+    // None until the first exchange completed.
+    pub fn clock_offset(&self) -> Option<ClockOffset> {
+        self.clock.offset()
+    }
+
+    async fn sync_clock(&mut self) -> crate::Result<()> {
+        let (n_startup, startup_interval) = CLOCK_SYNC_STARTUP;
+        let interval = if self.clock.n_samples() < n_startup {
+            startup_interval
+        } else {
+            CLOCK_SYNC_INTERVAL
+        };
+        if self.last_time_request.elapsed() >= interval {
+            self.last_time_request = Instant::now();
+            self.socket_writer
+                .write_packet(&Frame::time_request())
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn report(&mut self) -> crate::Result<()> {
+        if self.last_report.elapsed() >= REPORT_INTERVAL {
+            self.last_report = Instant::now();
+            let report = self.receiver.stats().report();
+            self.socket_writer
+                .write_packet(&Frame::receive_report(&report))
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Ask for the stream in 'format' instead of the server's, compressed as
+    // 'format.compression'; 'frame_samples' is ignored. Servers that can't provide it
+    // keep sending what they did, either way the next stream info tells.
+    pub async fn request_format(&mut self, format: &StreamInfo) -> crate::Result<()> {
+        self.format = Some(*format);
+        self.socket_writer
+            .write_packet(&Frame::format_request(format))
+            .await
+    }
+
+    // Open the sealed audio frames of servers with this psk, and refuse unsealed ones.
+    pub fn set_psk(&mut self, psk: Psk) {
+        self.psk = Some(psk);
+    }
+
+    // Send a control command such as "denoise on".
+    pub async fn send_control(&mut self, command: &str) -> crate::Result<()> {
+        self.socket_writer
+            .write_packet(&Frame::control(command))
+            .await
+    }
+
+    // Next packet of the stream; None once the server closed the connection, for good
+    // with 'set_reconnect'.
+    pub async fn next_packet(&mut self) -> crate::Result<Option<AudioPacket>> {
+        loop {
+            let res = self.receive().await;
+            let reason = match (&res, &self.goodbye) {
+                (Ok(Some(_)), _) => return res,
+                _ if self.reconnect.is_none() => None,
+                (Ok(None), Some(reason)) if reason == "kicked" => None,
+                (Ok(None), Some(reason)) => Some(format!("server said goodbye: {}", reason)),
+                (Ok(None), None) => Some("server closed the connection".to_string()),
+                (Err(crate::Error::Io(err)), _) => Some(err.to_string()),
+                // a broken stream won't get better by connecting again
+                (Err(_), _) => None,
+            };
+            match reason {
+                Some(reason) => self.reconnect_after(&reason).await?,
+                None => {
+                    self.state.send_replace(ConnectionState::Closed);
+                    return res;
+                }
+            }
+        }
+    }
+
+    // Connect again, 'reason' being why the last connection ended, until an attempt
+    // succeeds or the attempts run out.
+    async fn reconnect_after(&mut self, reason: &str) -> crate::Result<()> {
+        let cfg = self.reconnect.unwrap_or_default();
+        warn!("{}; reconnecting", reason);
+        let mut delay = cfg.delay;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            if cfg.max_attempts.is_some_and(|max| attempt > max) {
+                return Err(format!("no connection after {} attempts", attempt - 1).into());
+            }
+            self.state
+                .send_replace(ConnectionState::Reconnecting { attempt, delay });
+            time::sleep(delay).await;
+            match self.connect_again().await {
+                Ok(()) => {
+                    self.state.send_replace(ConnectionState::Connected);
+                    return Ok(());
+                }
+                Err(err) => warn!("reconnect attempt {} failed: {}", attempt, err),
+            }
+            delay = (delay * 2).min(cfg.max_delay);
+        }
+    }
+
+    async fn connect_again(&mut self) -> crate::Result<()> {
+        let (socket_reader, socket_writer) = open(&self.addrs).await?;
+        self.socket_reader = socket_reader;
+        self.socket_writer = socket_writer;
+        // the stream info and nonce of the new connection set them up again
+        self.transforms.clear();
+        self.opener = None;
+        self.goodbye = None;
+        let resume = self.receiver.reconnected();
+        self.hello(resume).await
+    }
+
+    async fn receive(&mut self) -> crate::Result<Option<AudioPacket>> {
+        loop {
+            if let Some(packet) = self.receiver.pop() {
+                return Ok(Some(packet));
+            }
+            let frame = match self.socket_reader.read_frame().await? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            match frame.kind {
+                FrameKind::Audio | FrameKind::Silence => {
+                    self.sync_clock().await?;
+                    self.report().await?;
+                }
+                FrameKind::StreamInfo => {
+                    // a new stream after a format request, with its own sequence numbers
+                    let stream = self.receiver.start(&frame)?;
+                    self.transforms = decompressor(stream.compression)?.into_iter().collect();
+                    continue;
+                }
+                FrameKind::TimeResponse => {
+                    self.clock.add_response(&frame, Timestamp::now())?;
+                    if self.clock.n_samples() == CLOCK_SYNC_STARTUP.0 {
+                        if let Some(offset) = self.clock.offset() {
+                            info!(
+                                "server clock {} µs ahead, round trip {:?}",
+                                offset.wall_us, offset.round_trip
+                            );
+                        }
+                    }
+                    continue;
+                }
+                FrameKind::Nonce => {
+                    let psk = self.psk.as_ref().ok_or_else(|| {
+                        crate::Error::Protocol("the server seals its stream, no psk set".into())
+                    })?;
+                    self.opener = Some(psk.opener(&frame)?);
+                    continue;
+                }
+                FrameKind::Ping => {
+                    self.socket_writer
+                        .write_packet(&Frame::pong(frame.seq))
+                        .await?;
+                    continue;
+                }
+                FrameKind::Goodbye => {
+                    let reason = String::from_utf8_lossy(&frame.payload).into_owned();
+                    info!("server said goodbye: {}", reason);
+                    self.goodbye = Some(reason);
+                    return Ok(None);
+                }
+                kind => {
+                    warn!("unexpected {:?} frame", kind);
+                    continue;
+                }
+            }
+            let frame = match (&mut self.opener, &self.psk) {
+                (Some(opener), _) => opener.apply(frame)?,
+                (None, Some(_)) if psk::seals(frame.kind) => {
+                    return Err(crate::Error::Protocol(
+                        "unsealed audio frame from a server expected to seal them".into(),
+                    ));
+                }
+                (None, _) => frame,
+            };
+            let frame = self
+                .transforms
+                .iter_mut()
+                .try_fold(frame, |frame, transform| transform.apply(frame))?;
+            self.receiver.push(&frame)?;
+        }
+    }
+}
+
+// Playback timing of a 'JitterBuffer'.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JitterStats {
+    // times playback ran dry and had to wait for the buffer to refill
+    pub underruns: u64,
+    // times the buffer overflowed and the oldest samples were dropped
+    pub overruns: u64,
+    // samples currently buffered / aimed for
+    pub depth: usize,
+    pub target: usize,
+}
+
+// Absorbs network timing variance between received packets and a steadily pulling
+// output device. Playback starts once 'target' samples are buffered; every underrun
+// grows the target, and a long stretch without one shrinks it back towards the minimum.
+pub struct JitterBuffer {
+    samples: VecDeque<i16>,
+    min_target: usize,
+    max_depth: usize,
+    // waiting to reach 'target' before handing out samples
+    priming: bool,
+    since_underrun: usize,
+    // samples played without underrun before the target is lowered
+    adapt_after: usize,
+    stats: JitterStats,
+}
+
+impl JitterBuffer {
+    // 'target' and 'max_depth' in samples; 'adapt_after' is how long a stable stretch is.
+    pub fn new(target: usize, max_depth: usize, adapt_after: usize) -> JitterBuffer {
+        let max_depth = max_depth.max(target * 2);
+        JitterBuffer {
+            samples: VecDeque::with_capacity(max_depth),
+            min_target: target,
+            max_depth,
+            priming: true,
+            since_underrun: 0,
+            adapt_after,
+            stats: JitterStats {
+                target,
+                ..JitterStats::default()
+            },
+        }
+    }
+
+    pub fn push(&mut self, samples: &[i16]) {
+        self.samples.extend(samples);
+        if self.samples.len() > self.max_depth {
+            // far behind the sender; skip ahead to the target depth
+            let excess = self.samples.len() - self.stats.target;
+            self.samples.drain(..excess);
+            self.stats.overruns += 1;
+        }
+        if self.priming && self.samples.len() >= self.stats.target {
+            self.priming = false;
+        }
+    }
+
+    // Next sample to play; silence while priming.
+    pub fn pop(&mut self) -> i16 {
+        if self.priming {
+            return 0;
+        }
+        match self.samples.pop_front() {
+            Some(sample) => {
+                self.since_underrun += 1;
+                if self.since_underrun >= self.adapt_after && self.stats.target > self.min_target {
+                    self.stats.target = (self.stats.target * 9 / 10).max(self.min_target);
+                    self.since_underrun = 0;
+                }
+                sample
+            }
+            None => {
+                self.stats.underruns += 1;
+                self.stats.target = (self.stats.target * 3 / 2).min(self.max_depth / 2);
+                self.since_underrun = 0;
+                self.priming = true;
+                0
+            }
+        }
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        JitterStats {
+            depth: self.samples.len(),
+            ..self.stats
+        }
+    }
+}
+
+#[cfg(feature = "cpal")]
+mod playback {
+    use super::{JitterBuffer, TcpClient};
+    use crate::audio::resample::Resampler;
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use cpal::{Device, FromSample, SampleFormat, SizedSample, Stream, StreamConfig};
+    use std::future::Future;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tracing::{error, info};
+
+    // a target that held this long without underrun is lowered
+    const ADAPT_AFTER: Duration = Duration::from_secs(10);
+    const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+    type PlaybackBuffer = Arc<Mutex<JitterBuffer>>;
+
+    // Jitter buffer depths for 'play', in milliseconds.
+    pub struct JitterConfig {
+        pub target: u64,
+        pub max: u64,
+    }
+
+    fn build_stream<T>(
+        device: &Device,
+        config: StreamConfig,
+        buffer: PlaybackBuffer,
+    ) -> crate::Result<Stream>
+    where
+        T: SizedSample + FromSample<i16>,
+    {
+        let n_ch = config.channels as usize;
+        let stream = device.build_output_stream(
+            config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                let mut buffer = buffer.lock().unwrap();
+                for frame in data.chunks_exact_mut(n_ch) {
+                    // mono source on every output channel; silence on underrun
+                    let sample = T::from_sample(buffer.pop());
+                    frame.fill(sample);
+                }
+            },
+            |err| error!("playback stream error: {}", err),
+            None,
+        )?;
+        Ok(stream)
+    }
+
+    // Play channel 'channel' of the stream, sent at 'sample_rate', through the default
+    // output device until the server hangs up or 'shutdown' completes.
+    pub async fn play(
+        mut client: TcpClient,
+        sample_rate: usize,
+        channel: usize,
+        jitter: JitterConfig,
+        shutdown: impl Future,
+    ) -> crate::Result<()> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("no default output device")?;
+        let supported = device.default_output_config()?;
+        let out_rate = supported.sample_rate() as usize;
+        let samples_in = |ms: u64| ms as usize * out_rate / 1000;
+        let buffer: PlaybackBuffer = Arc::new(Mutex::new(JitterBuffer::new(
+            samples_in(jitter.target),
+            samples_in(jitter.max),
+            samples_in(ADAPT_AFTER.as_millis() as u64),
+        )));
+        let stream = match supported.sample_format() {
+            SampleFormat::F32 => build_stream::<f32>(&device, supported.config(), buffer.clone())?,
+            SampleFormat::I16 => build_stream::<i16>(&device, supported.config(), buffer.clone())?,
+            SampleFormat::I32 => build_stream::<i32>(&device, supported.config(), buffer.clone())?,
+            SampleFormat::U16 => build_stream::<u16>(&device, supported.config(), buffer.clone())?,
+            other => return Err(format!("unsupported sample format {:?}", other).into()),
+        };
+        stream.play()?;
+        info!(
+            "playing channel {} on \"{}\" at {} Hz",
+            channel, device, out_rate
+        );
+
+        let mut resampler = Resampler::new(1, sample_rate, out_rate);
+        // logged between packets rather than raced against 'next_packet', which
+        // mustn't be cancelled part way through a reconnect
+        let mut last_stats = Instant::now();
+        let receive = async {
+            let mut resampled = [Vec::new()];
+            while let Some(packet) = client.next_packet().await? {
+                if last_stats.elapsed() >= STATS_INTERVAL {
+                    last_stats = Instant::now();
+                    let stats = buffer.lock().unwrap().stats();
+                    let received = client.stats();
+                    info!(
+                        lost = received.lost,
+                        concealed = received.concealed,
+                        underruns = stats.underruns,
+                        overruns = stats.overruns,
+                        depth_ms = stats.depth * 1000 / out_rate,
+                        target_ms = stats.target * 1000 / out_rate,
+                        "jitter buffer"
+                    );
+                }
+                if channel >= packet.n_ch {
+                    return Err(format!("stream has only {} channels", packet.n_ch).into());
+                }
+                resampled[0].clear();
+                resampler.process(&[packet.channel(channel)], &mut resampled);
+                buffer.lock().unwrap().push(&resampled[0]);
+            }
+            info!("server closed the connection");
+            Ok::<(), crate::Error>(())
+        };
+        let res = tokio::select! {
+            res = receive => res,
+            _ = shutdown => Ok(()),
+        };
+        drop(stream);
+        res
+    }
+}
+
+#[cfg(feature = "cpal")]
+pub use playback::{play, JitterConfig};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pop_n(buffer: &mut JitterBuffer, n: usize) -> Vec<i16> {
+        (0..n).map(|_| buffer.pop()).collect()
+    }
+
+    #[test]
+    fn playback_waits_for_the_target() {
+        let mut buffer = JitterBuffer::new(4, 16, 1000);
+        buffer.push(&[1, 2, 3]);
+        assert_eq!(pop_n(&mut buffer, 2), [0, 0]);
+        assert_eq!(buffer.stats().depth, 3);
+        buffer.push(&[4]);
+        assert_eq!(pop_n(&mut buffer, 4), [1, 2, 3, 4]);
+        assert_eq!(buffer.stats().underruns, 0);
+    }
+
+    #[test]
+    fn underrun_grows_the_target_and_primes_again() {
+        let mut buffer = JitterBuffer::new(4, 16, 1000);
+        buffer.push(&[1, 2, 3, 4]);
+        pop_n(&mut buffer, 4);
+        // the next packet is late
+        assert_eq!(buffer.pop(), 0);
+        let stats = buffer.stats();
+        assert_eq!((stats.underruns, stats.target), (1, 6));
+        // it arrives with the one after, but playback holds off until 6 are in
+        buffer.push(&[5, 6, 7, 8, 9]);
+        assert_eq!(buffer.pop(), 0);
+        assert_eq!(buffer.stats().depth, 5);
+        buffer.push(&[10]);
+        assert_eq!(pop_n(&mut buffer, 6), [5, 6, 7, 8, 9, 10]);
+        // never beyond half the maximum depth
+        for _ in 0..4 {
+            buffer.pop();
+            buffer.push(&[0; 8]);
+            pop_n(&mut buffer, 8);
+        }
+        assert_eq!(buffer.stats().target, 8);
+    }
+
+    #[test]
+    fn overrun_skips_ahead_to_the_target() {
+        let mut buffer = JitterBuffer::new(4, 16, 1000);
+        let burst: Vec<i16> = (0..20).collect();
+        buffer.push(&burst);
+        let stats = buffer.stats();
+        assert_eq!((stats.overruns, stats.depth), (1, 4));
+        assert_eq!(pop_n(&mut buffer, 4), [16, 17, 18, 19]);
+        buffer.push(&[0; 16]);
+        assert_eq!(buffer.stats().overruns, 1);
+    }
+
+    #[test]
+    fn stable_stretch_lowers_the_target_to_the_minimum() {
+        let mut buffer = JitterBuffer::new(4, 16, 8);
+        buffer.push(&[1; 4]);
+        pop_n(&mut buffer, 5);
+        assert_eq!(buffer.stats().target, 6);
+        buffer.push(&[1; 6]);
+        // packets of 3 arriving as fast as they play
+        for _ in 0..4 {
+            pop_n(&mut buffer, 3);
+            buffer.push(&[1; 3]);
+        }
+        assert_eq!(buffer.stats().target, 5);
+        for _ in 0..8 {
+            pop_n(&mut buffer, 3);
+            buffer.push(&[1; 3]);
+        }
+        let stats = buffer.stats();
+        assert_eq!(stats.target, 4);
+        assert_eq!(stats.underruns, 1);
+    }
+
+    fn receiver() -> StreamReceiver {
+        let mut receiver = StreamReceiver::default();
+        receiver
+            .start(&Frame::stream_info(&StreamInfo::pcm(16000, 1)))
+            .unwrap();
+        receiver
+    }
+
+    // A mono packet of constant 'level'.
+    fn audio(seq: u32, level: i16) -> Frame {
+        let packet = AudioPacket {
+            device_id: 0,
+            secs: 0,
+            millis: 0,
+            pkt_id: seq,
+            n_ch: 1,
+            samples: vec![level; PACKET_N_SAMPLE],
+            timestamp: Timestamp::default(),
+        };
+        Frame::audio(seq, Timestamp::default(), packet.to_payload().into())
+    }
+
+    fn queued(receiver: &mut StreamReceiver) -> Vec<Vec<i16>> {
+        std::iter::from_fn(|| receiver.pop())
+            .map(|packet| packet.samples)
+            .collect()
+    }
+
+    // Falling steadily from at most 'from' to just above 'to'.
+    fn fades_out(samples: &[i16], from: i16, to: i16) -> bool {
+        let last = samples[PACKET_N_SAMPLE - 1];
+        samples.windows(2).all(|pair| pair[1] <= pair[0])
+            && samples[0] <= from
+            && (to..=to + 10).contains(&last)
+    }
+
+    #[test]
+    fn lost_packet_repeats_the_last_one_fading_out() {
+        let mut receiver = receiver();
+        receiver.push(&audio(0, 1000)).unwrap();
+        receiver.push(&audio(2, 1000)).unwrap();
+        let packets = queued(&mut receiver);
+        assert_eq!(packets.len(), 3);
+        assert!(packets[0].iter().all(|&sample| sample == 1000));
+        // from full level to two thirds of it, then fading back in from there
+        assert!(fades_out(&packets[1], 1000, 660));
+        assert!(packets[2][0] < 700 && packets[2][PACKET_N_SAMPLE - 1] > 990);
+        let stats = receiver.stats();
+        assert_eq!((stats.received, stats.lost, stats.concealed), (2, 1, 1));
+    }
+
+    #[test]
+    fn longer_losses_fade_to_silence() {
+        let mut receiver = receiver();
+        receiver.push(&audio(0, 1000)).unwrap();
+        receiver.push(&audio(5, 1000)).unwrap();
+        let packets = queued(&mut receiver);
+        assert_eq!(packets.len(), 6);
+        assert!(fades_out(&packets[1], 1000, 660));
+        assert!(fades_out(&packets[2], 670, 330));
+        assert!(fades_out(&packets[3], 340, 0));
+        assert!(packets[4].iter().all(|&sample| sample == 0));
+        assert!(packets[5][0] < 10);
+        let stats = receiver.stats();
+        assert_eq!((stats.lost, stats.concealed), (4, 3));
+    }
+
+    #[test]
+    fn gaps_too_long_to_fill_are_not_concealed() {
+        let mut receiver = receiver();
+        receiver.push(&audio(0, 1000)).unwrap();
+        receiver.push(&audio(MAX_FILLED_GAP + 2, 1000)).unwrap();
+        let packets = queued(&mut receiver);
+        assert_eq!(packets.len(), 2);
+        assert!(packets[1][0] < 10);
+        let stats = receiver.stats();
+        assert_eq!(
+            (stats.lost, stats.concealed),
+            (MAX_FILLED_GAP as u64 + 1, 0)
+        );
+    }
+
+    #[test]
+    fn silence_frames_become_silent_packets() {
+        let mut receiver = receiver();
+        receiver
+            .push(&Frame::silence(0, Timestamp::default(), 100))
+            .unwrap();
+        let packets = queued(&mut receiver);
+        assert_eq!(packets.len(), 100 * 16000 / 1000 / PACKET_N_SAMPLE);
+        assert!(packets.iter().flatten().all(|&sample| sample == 0));
+    }
+
+    #[test]
+    fn overlong_silence_frame_is_refused() {
+        let mut receiver = receiver();
+        let frame = Frame::silence(0, Timestamp::default(), u32::MAX);
+        let err = receiver.push(&frame).unwrap_err();
+        assert!(matches!(err, crate::Error::Protocol(_)));
+        assert!(receiver.pop().is_none());
+    }
+}
@@ -1,165 +1,927 @@
-use crate::socket::{SocketReader, SocketWriter};
-use arc_swap::ArcSwap;
-use bytes::BytesMut;
+use crate::acl::Acl;
+use crate::client_stats::{ClientEntry, ClientRegistry, ClientSnapshot};
+use crate::config_file::{
+    AcceptConfig, AuthConfig, CoalesceConfig, Config, HeartbeatConfig, SocketOptionsConfig,
+    TcpConfig,
+};
+use crate::connection_errors::CONNECTION_ERRORS;
+use crate::distributor::{frames_in, Distributor, DropPolicy, Subscription};
+use crate::dsp::CONTROLS;
+use crate::error::Phase;
+use crate::formats::FormatChains;
+use crate::metrics::{Metrics, METRICS};
+use crate::protocol::{Frame, FrameKind, ReceiveReport, StreamInfo, Timestamp};
+use crate::psk::{Psk, Sealer};
+use crate::rate_limit::{RateLimiter, TokenBucket};
+use crate::reload::RELOAD;
+use crate::socket::{split_stream, SocketReader, SocketWriter};
+use crate::socket_options;
+use crate::tls::load_tls_acceptor;
+use crate::transform::{compressor, Compression, Transform};
 use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, mpsc, Notify, Semaphore};
-use tokio::time::{self, Duration};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::time::{self, Duration, Instant};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 pub struct TcpServer {
     port: u16,
     listener: TcpListener,
-    limit_connections: Arc<Semaphore>,
-    notify_data_ready: Arc<Notify>,
-    data_to_send: Arc<ArcSwap<BytesMut>>,
+    distributor: Arc<Distributor>,
+    queue_len: usize,
+    drop_policy: DropPolicy,
+    // frames of pre-roll per new client
+    preroll: usize,
+    // lets clients ask for a format of their own when present
+    formats: Option<Arc<FormatChains>>,
+    tls_acceptor: Option<TlsAcceptor>,
+    auth: Option<Arc<AuthConfig>>,
+    acl: Option<Arc<Acl>>,
+    rate_limit: Option<RateLimiter>,
+    heartbeat: Option<Arc<HeartbeatConfig>>,
+    psk: Option<Psk>,
+    coalesce: Option<CoalesceConfig>,
+    socket_options: Option<SocketOptionsConfig>,
+    clients: Arc<ClientRegistry>,
+    // connections still in their handshakes or waiting for a client slot
+    pending: Arc<Semaphore>,
+    next_client_id: u64,
     notify_shutdown: broadcast::Sender<()>,
     shutdown_complete_tx: mpsc::Sender<()>,
     shutdown_complete_rx: mpsc::Receiver<()>,
 }
 
 impl TcpServer {
+    // Serve 'tcp', '[tcp]' or one of the 'listeners'. 'clients' starts out allowing
+    // 'max_clients'; the admin api may change that.
     pub async fn new(
-        port: u16,
-        max_clients: u16,
-        notify_data_ready: Arc<Notify>,
-        data_to_send: Arc<ArcSwap<BytesMut>>,
+        tcp: &TcpConfig,
+        distributor: Arc<Distributor>,
+        formats: Option<FormatChains>,
+        clients: Arc<ClientRegistry>,
     ) -> crate::Result<TcpServer> {
-        let addr = format!("{}:{}", "0.0.0.0", port);
-        let listener = TcpListener::bind(&addr).await?;
+        let port = tcp.listen_port;
+        let listener = match activated_listener(port)? {
+            Some(listener) => listener,
+            None => TcpListener::bind((tcp.bind_address.as_str(), port))
+                .await
+                .map_err(crate::Error::bind(format!("{}:{}", tcp.bind_address, port)))?,
+        };
+        let tls_acceptor = match &tcp.tls {
+            Some(tls) => Some(load_tls_acceptor(tls)?),
+            None => None,
+        };
+        let acl = match &tcp.acl {
+            Some(acl) => Some(Acl::load(acl)?),
+            None => None,
+        };
+        if let Some(acl) = &acl {
+            RELOAD.add_acl(acl);
+        }
+        let psk = match &tcp.psk {
+            Some(psk) => Some(Psk::parse(&psk.key)?),
+            None => None,
+        };
         let (notify_shutdown, _) = broadcast::channel(1);
         let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
 
         let server = TcpServer {
             port,
             listener,
-            limit_connections: Arc::new(Semaphore::new(max_clients.into())),
-            notify_data_ready,
-            data_to_send,
+            queue_len: tcp.queue_len,
+            drop_policy: tcp.drop_policy,
+            preroll: frames_in(tcp.preroll, distributor.stream_info().sample_rate),
+            distributor,
+            formats: formats.map(Arc::new),
+            tls_acceptor,
+            auth: tcp.auth.clone().map(Arc::new),
+            acl,
+            rate_limit: tcp.rate_limit.as_ref().map(RateLimiter::new),
+            heartbeat: tcp.heartbeat.clone().map(Arc::new),
+            psk,
+            coalesce: tcp.coalesce.clone(),
+            socket_options: tcp.socket.clone(),
+            clients,
+            pending: Arc::new(Semaphore::new(MAX_PENDING)),
+            next_client_id: 0,
             notify_shutdown,
             shutdown_complete_tx,
             shutdown_complete_rx,
         };
         Ok(server)
     }
+
+    // Connected clients and how well each keeps up.
+    pub fn stats(&self) -> Vec<ClientSnapshot> {
+        self.clients.snapshot()
+    }
+
+    // Say goodbye to and disconnect the clients at 'addr', ip:port or just the ip;
+    // returns how many there were.
+    pub fn kick(&self, addr: &str) -> usize {
+        self.clients.kick(addr)
+    }
+
     async fn run(&mut self) -> crate::Result<()> {
-        println!("listen on port: {}", self.port);
+        info!(
+            tls = self.tls_acceptor.is_some(),
+            "listen on port: {}", self.port
+        );
 
         loop {
-            let permit = self
-                .limit_connections
-                .clone()
-                .acquire_owned()
-                .await
-                .unwrap();
-            let socket = self.accept().await?;
-            socket.set_nodelay(true)?;
-            let ip_addr = socket.peer_addr().unwrap().to_string();
-            let (read_half, write_half) = socket.into_split();
-
-            let mut handler = SocketHandler {
-                // socket,
-                ip_addr,
-                socket_reader: SocketReader { reader: read_half },
-                socket_writer: SocketWriter {
-                    writer: write_half,
-                    data_to_send: self.data_to_send.clone(),
-                },
-                notified_data_ready: self.notify_data_ready.clone(),
-                shutdown: false,
-                shutdown_signal: self.notify_shutdown.subscribe(),
-                _shutdown_complete: self.shutdown_complete_tx.clone(),
+            let (socket, peer) = self.accept().await?;
+            // before taking a permit, so refused peers can't crowd out allowed ones
+            if let Some(acl) = &self.acl {
+                if !acl.permits(peer.ip()) {
+                    warn!(peer = %peer, "connection refused by acl");
+                    Metrics::inc(&METRICS.acl_rejections);
+                    continue;
+                }
+            }
+            if let Err(err) = socket.set_nodelay(true) {
+                warn!(peer = %peer, "dropping connection: {}", err);
+                continue;
+            }
+            let Ok(pending) = self.pending.clone().try_acquire_owned() else {
+                warn!(peer = %peer, "too many pending connections, dropping connection");
+                continue;
             };
+            let ip_addr = peer.to_string();
+
+            let distributor = self.distributor.clone();
+            let (queue_len, drop_policy, preroll) =
+                (self.queue_len, self.drop_policy, self.preroll);
+            let mut shutdown_signal = self.notify_shutdown.subscribe();
+            let shutdown_complete = self.shutdown_complete_tx.clone();
+            let tls_acceptor = self.tls_acceptor.clone();
+            let auth = self.auth.clone();
+            let heartbeat = self.heartbeat.clone();
+            let psk = self.psk.clone();
+            let coalesce = self.coalesce.clone();
+            let formats = self.formats.clone();
+            let clients = self.clients.clone();
+            let server = format!("tcp:{}", self.port);
+            let span = info_span!("client", peer = %ip_addr, id = self.next_client_id);
+            self.next_client_id += 1;
 
-            tokio::spawn(async move {
+            let connection = async move {
+                let handshake_failed = |err: crate::Error| {
+                    CONNECTION_ERRORS.report(
+                        &server,
+                        crate::Error::connection(peer, Phase::Handshake)(err),
+                    )
+                };
+                // handshake in the task so a stalled client can't block the accept loop
+                let handshake = async {
+                    let (mut socket_reader, socket_writer) = match tls_acceptor {
+                        Some(acceptor) => match acceptor.accept(socket).await {
+                            Ok(tls_stream) => split_stream(tls_stream),
+                            Err(err) => return Err(format!("tls: {}", err).into()),
+                        },
+                        None => {
+                            let (read_half, write_half) = socket.into_split();
+                            (
+                                SocketReader::new(Box::new(read_half)),
+                                SocketWriter::new(Box::new(write_half)),
+                            )
+                        }
+                    };
+                    if let Some(auth) = auth {
+                        if !authenticate(&mut socket_reader, &auth).await {
+                            Metrics::inc(&METRICS.auth_failures);
+                            return Err(crate::Error::Protocol("failed to authenticate".into()));
+                        }
+                    }
+                    // a slot only once the handshakes are done, so connections that never
+                    // finish them can't lock out clients
+                    let permit = clients.acquire().await;
+                    Ok((socket_reader, socket_writer, permit))
+                };
+                let (socket_reader, socket_writer, permit) = tokio::select! {
+                    res = time::timeout(HANDSHAKE_TIMEOUT, handshake) => match res {
+                        Ok(Ok(connection)) => connection,
+                        Ok(Err(err)) => {
+                            handshake_failed(err);
+                            return;
+                        }
+                        Err(_) => {
+                            handshake_failed("handshake timed out".into());
+                            return;
+                        }
+                    },
+                    _ = shutdown_signal.recv() => return,
+                };
+                drop(pending);
+
+                // subscribe only now so frames don't pile up during the handshakes
+                let client = clients.register(&ip_addr);
+                let mut handler = SocketHandler::new(
+                    ip_addr,
+                    client,
+                    socket_reader,
+                    socket_writer,
+                    distributor.subscribe_with_preroll(queue_len, drop_policy, preroll),
+                    shutdown_signal,
+                    shutdown_complete,
+                );
+                if let Some(heartbeat) = &heartbeat {
+                    handler.set_heartbeat(heartbeat);
+                }
+                if let Some(formats) = formats {
+                    handler.set_formats(formats);
+                }
+                if let Some(psk) = psk {
+                    handler.set_psk(psk);
+                }
+                if let Some(coalesce) = &coalesce {
+                    handler.set_coalesce(coalesce);
+                }
                 if let Err(err) = handler.run().await {
-                    println!("Error! Connection error. {}", err);
+                    CONNECTION_ERRORS.report(&server, err);
                 }
                 drop(permit);
-            });
+            };
+            tokio::spawn(connection.instrument(span));
         }
     }
 
-    async fn accept(&mut self) -> crate::Result<TcpStream> {
-        let mut backoff = 1;
-
+    // Accept the next connection the rate limit lets through, with its peer's address.
+    async fn accept(&mut self) -> crate::Result<(TcpStream, SocketAddr)> {
         loop {
-            match self.listener.accept().await {
-                Ok((socket, addr)) => {
-                    println!("connection from {}", addr);
-                    return Ok(socket);
-                }
-                Err(err) => {
-                    if backoff > 64 {
-                        return Err(err.into());
-                    }
+            let (socket, addr) = accept_retrying(&self.listener).await?;
+            if let Some(rate_limit) = &mut self.rate_limit {
+                if !rate_limit.check(addr.ip()) {
+                    Metrics::inc(&METRICS.connections_throttled);
+                    continue;
                 }
             }
+            info!("connection from {}", addr);
+            Metrics::inc(&METRICS.connections_accepted);
+            if let Some(options) = &self.socket_options {
+                socket_options::apply(&socket, options);
+            }
+            return Ok((socket, addr));
+        }
+    }
+}
+
+// The listener systemd bound for 'port' with socket activation, if it did.
+#[cfg(unix)]
+fn activated_listener(port: u16) -> crate::Result<Option<TcpListener>> {
+    match crate::systemd::listener(port) {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            Ok(Some(TcpListener::from_std(listener)?))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(not(unix))]
+fn activated_listener(_port: u16) -> crate::Result<Option<TcpListener>> {
+    Ok(None)
+}
+
+// Accept one connection, retrying failed accepts as 'accept_retrying' does. The peer's
+// address comes along, as asking the socket for it fails once the peer reset.
+pub(crate) async fn accept_with_backoff(
+    listener: &TcpListener,
+) -> crate::Result<(TcpStream, SocketAddr)> {
+    let (socket, addr) = accept_retrying(listener).await?;
+    info!("connection from {}", addr);
+    Metrics::inc(&METRICS.connections_accepted);
+    Ok((socket, addr))
+}
 
-            time::sleep(Duration::from_secs(backoff)).await;
-            backoff *= 2;
+// Accept, riding out errors that don't mean the listener is broken: a connection
+// dropped during its handshake is skipped at once, while running out of descriptors or
+// memory pauses accepting, for longer the longer it lasts, instead of spinning on an
+// error that stays until connections close.
+async fn accept_retrying(listener: &TcpListener) -> crate::Result<(TcpStream, SocketAddr)> {
+    let mut backoff = MIN_ACCEPT_BACKOFF;
+    loop {
+        let err = match listener.accept().await {
+            Ok(accepted) => return Ok(accepted),
+            Err(err) => err,
+        };
+        match AcceptFailure::of(&err) {
+            AcceptFailure::Transient => debug!("accept: {}", err),
+            AcceptFailure::Exhausted => {
+                let max = Duration::from_millis(MAX_ACCEPT_BACKOFF.load(Ordering::Relaxed));
+                backoff = backoff.min(max);
+                warn!("accept: {}; retrying in {:?}", err, backoff);
+                time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            AcceptFailure::Fatal => return Err(crate::Error::Accept(err)),
         }
     }
 }
 
+// for the tls handshake, authentication and waiting for a slot of a new connection
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// connections between accept and getting a slot, so idle sockets can't pile up
+const MAX_PENDING: usize = 64;
+
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// ms, from '[accept] max_backoff'.
+pub static MAX_ACCEPT_BACKOFF: AtomicU64 = AtomicU64::new(5000);
+
+pub fn configure_accept(cfg: &AcceptConfig) {
+    MAX_ACCEPT_BACKOFF.store(cfg.max_backoff, Ordering::Relaxed);
+}
+
+enum AcceptFailure {
+    // this connection failed, the next one may well not
+    Transient,
+    // the process or system is out of a resource; retried later
+    Exhausted,
+    // the listener itself is unusable
+    Fatal,
+}
+
+impl AcceptFailure {
+    fn of(err: &io::Error) -> AcceptFailure {
+        if is_exhausted(err) {
+            return AcceptFailure::Exhausted;
+        }
+        match err.kind() {
+            io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock => AcceptFailure::Transient,
+            io::ErrorKind::OutOfMemory => AcceptFailure::Exhausted,
+            _ if is_network_error(err) => AcceptFailure::Transient,
+            _ => AcceptFailure::Fatal,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_exhausted(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    )
+}
+
+// WSAEMFILE and WSAENOBUFS
+#[cfg(not(unix))]
+fn is_exhausted(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(10024 | 10055))
+}
+
+// Linux hands accept pending network errors of the new connection, which say nothing
+// about the listener; accept(2) says to retry on them like on EAGAIN. EPERM is a
+// firewall rule refusing the connection.
+#[cfg(target_os = "linux")]
+fn is_network_error(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(
+            libc::EPROTO
+                | libc::EPERM
+                | libc::ENETDOWN
+                | libc::ENOPROTOOPT
+                | libc::EHOSTDOWN
+                | libc::ENONET
+                | libc::EHOSTUNREACH
+                | libc::EOPNOTSUPP
+                | libc::ENETUNREACH
+        )
+    )
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_network_error(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EPROTO)
+}
+
+#[cfg(not(unix))]
+fn is_network_error(_err: &io::Error) -> bool {
+    false
+}
+
+// A vanished client can take long to be noticed otherwise: the writes only fail once
+// its socket buffer is full and tcp gives up retransmitting.
+struct Heartbeat {
+    ping: time::Interval,
+    next_seq: u32,
+    // also the longest a single write may block
+    timeout: Duration,
+    last_pong: Instant,
+}
+
+// Audio frames waiting to go out together, trading up to 'max_delay' of latency for
+// fewer writes.
+struct Batch {
+    frames: Vec<Frame>,
+    len: usize,
+    max_delay: Duration,
+    // when the oldest frame has waited long enough
+    deadline: Instant,
+}
+
+// A stalled client shouldn't hold up a shutdown.
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub struct SocketHandler {
     ip_addr: String,
+    client: ClientEntry,
     socket_reader: SocketReader,
     socket_writer: SocketWriter,
-    notified_data_ready: Arc<Notify>,
+    frames: Subscription,
+    // by subscriptions before a format switch
+    frames_dropped: u64,
+    formats: Option<Arc<FormatChains>>,
+    // applied to the stream's frames in order, as the client asked
+    transforms: Vec<Box<dyn Transform>>,
+    compression: Compression,
+    psk: Option<Psk>,
+    // last of the transforms, kept apart for a new prefix before it runs out
+    sealer: Option<Sealer>,
+    heartbeat: Option<Heartbeat>,
+    batch: Option<Batch>,
+    // the client's bandwidth limit; no audio goes out before 'throttled_until'
+    bucket: TokenBucket,
+    throttled_until: Option<Instant>,
+    // of the first audio frame written on this connection, if any
+    first_sent: Option<u32>,
     shutdown: bool,
     shutdown_signal: broadcast::Receiver<()>,
     _shutdown_complete: mpsc::Sender<()>,
 }
 
 impl SocketHandler {
-    // todo: return Result<()>
-    async fn run(&mut self) -> crate::Result<()> {
+    // Counts as a connected client from here until dropped.
+    pub(crate) fn new(
+        ip_addr: String,
+        client: ClientEntry,
+        socket_reader: SocketReader,
+        socket_writer: SocketWriter,
+        frames: Subscription,
+        shutdown_signal: broadcast::Receiver<()>,
+        shutdown_complete: mpsc::Sender<()>,
+    ) -> SocketHandler {
+        METRICS.client_connected(&ip_addr);
+        let bucket = TokenBucket::new(client.stats().bandwidth().clone());
+        SocketHandler {
+            ip_addr,
+            client,
+            socket_reader,
+            socket_writer,
+            frames,
+            frames_dropped: 0,
+            formats: None,
+            transforms: Vec::new(),
+            compression: Compression::None,
+            psk: None,
+            sealer: None,
+            heartbeat: None,
+            batch: None,
+            bucket,
+            throttled_until: None,
+            first_sent: None,
+            shutdown: false,
+            shutdown_signal,
+            _shutdown_complete: shutdown_complete,
+        }
+    }
+
+    // Ping the client every 'cfg.interval' seconds and hang up on it when no pong
+    // came back for 'cfg.timeout' seconds.
+    pub(crate) fn set_heartbeat(&mut self, cfg: &HeartbeatConfig) {
+        let interval = Duration::from_secs(cfg.interval.max(1));
+        self.heartbeat = Some(Heartbeat {
+            ping: time::interval_at(Instant::now() + interval, interval),
+            next_seq: 0,
+            timeout: Duration::from_secs(cfg.timeout),
+            last_pong: Instant::now(),
+        });
+    }
+
+    // Send the audio frames 'cfg.frames' at a time, or fewer once the first of them is
+    // 'cfg.max_delay' ms old.
+    pub(crate) fn set_coalesce(&mut self, cfg: &CoalesceConfig) {
+        let len = cfg.frames.max(1);
+        self.batch = Some(Batch {
+            frames: Vec::with_capacity(len),
+            len,
+            max_delay: Duration::from_millis(cfg.max_delay),
+            deadline: Instant::now(),
+        });
+    }
+
+    // Accept format requests, moving the client to a stream from 'formats'.
+    pub(crate) fn set_formats(&mut self, formats: Arc<FormatChains>) {
+        self.formats = Some(formats);
+    }
+
+    // Seal the audio payloads with 'psk'.
+    pub(crate) fn set_psk(&mut self, psk: Psk) {
+        self.psk = Some(psk);
+    }
+
+    // Stream to the client until it leaves, is dropped or the server shuts down. Errors
+    // are an 'Error::Connection' with the client's address; reading what the client
+    // sent fails the read phase, anything else the write phase.
+    pub(crate) async fn run(&mut self) -> crate::Result<()> {
+        let peer = self.ip_addr.clone();
+        self.serve()
+            .await
+            .map_err(crate::Error::connection(peer, Phase::Write))
+    }
+
+    async fn serve(&mut self) -> crate::Result<()> {
+        self.announce_stream().await?;
         while !self.shutdown {
-            self.notified_data_ready.notified().await;
             tokio::select! {
-                _ = self.socket_writer.write_packet() => {}
-                Ok(read_size) = self.socket_reader.read_packet() => {
-                    if read_size == 0 {
+                frame = self.frames.recv(), if self.throttled_until.is_none() => match frame {
+                    Some(frame) => {
+                        self.reseal(frame.seq).await?;
+                        let frame = self.transformed(frame)?;
+                        self.send_audio(frame).await?;
+                    }
+                    None => {
+                        warn!("fell behind the stream");
                         return Ok(());
                     }
+                },
+                res = self.socket_reader.read_frame() => match res.map_err(crate::Error::connection(&self.ip_addr, Phase::Read))? {
+                    Some(frame) => match (frame.kind, &mut self.heartbeat) {
+                        (FrameKind::Control, _) => handle_control(&frame.payload),
+                        (FrameKind::TimeRequest, _) => {
+                            let response = Frame::time_response(&frame, Timestamp::now());
+                            self.write(&response).await?;
+                        }
+                        (FrameKind::Pong, Some(heartbeat)) => heartbeat.last_pong = Instant::now(),
+                        (FrameKind::FormatRequest, _) => self.switch_format(&frame.payload).await?,
+                        (FrameKind::Resume, _) => self.resume(frame.seq).await?,
+                        (FrameKind::ReceiveReport, _) => match ReceiveReport::parse(&frame.payload) {
+                            Ok(report) => self.client.stats().receive_report(report),
+                            Err(err) => warn!("bad receive report: {}", err),
+                        },
+                        (kind, _) => warn!("unexpected {:?} frame", kind),
+                    },
+                    None => return Ok(()),
+                },
+                _ = batch_due(&self.batch), if self.throttled_until.is_none() => self.flush().await?,
+                _ = throttle_over(self.throttled_until) => self.throttled_until = None,
+                seq = next_ping(&mut self.heartbeat) => {
+                    let heartbeat = self.heartbeat.as_ref().unwrap();
+                    if heartbeat.last_pong.elapsed() > heartbeat.timeout {
+                        warn!("no pong for {:?}; closing", heartbeat.timeout);
+                        return self.goodbye("heartbeat timeout").await;
+                    }
+                    self.write(&Frame::ping(seq)).await?;
+                }
+                _ = self.client.stats().kicked() => {
+                    info!("kicked");
+                    return self.goodbye("kicked").await;
                 }
                 _ = self.shutdown_signal.recv() => {
                     self.shutdown = true;
-                    // drop(self.socket_writer.writer);
-                    return Ok(());
+                    return self.goodbye("server shutting down").await;
                 }
             };
         }
         Ok(())
     }
+
+    // Move to the stream and compression the client asked for as far as there can be
+    // those, and tell it what it gets from here on either way.
+    async fn switch_format(&mut self, request: &[u8]) -> crate::Result<()> {
+        match StreamInfo::parse(request) {
+            Ok(request) => {
+                self.switch_stream(&request);
+                match compressor(request.compression) {
+                    Ok(_) => self.compression = request.compression,
+                    Err(err) => warn!("compression request refused: {}", err),
+                }
+            }
+            Err(err) => warn!("bad format request: {}", err),
+        }
+        self.announce_stream().await
+    }
+
+    // Tell the client what the frames from here on are like, and set up the transforms
+    // that make them so.
+    async fn announce_stream(&mut self) -> crate::Result<()> {
+        self.transforms = compressor(self.compression)?.into_iter().collect();
+        self.sealer = None;
+        if let Some(psk) = &self.psk {
+            let (prefix, sealer) = psk.sealer()?;
+            self.write(&Frame::nonce(&prefix)).await?;
+            self.sealer = Some(sealer);
+        }
+        let stream_info = StreamInfo {
+            compression: self.compression,
+            ..self.frames.stream_info()
+        };
+        self.write(&Frame::stream_info(&stream_info)).await
+    }
+
+    fn switch_stream(&mut self, request: &StreamInfo) {
+        let current = self.frames.stream_info();
+        let unchanged = StreamInfo {
+            frame_samples: current.frame_samples,
+            compression: current.compression,
+            ..*request
+        } == current;
+        if unchanged {
+            return;
+        }
+        let frames = match &self.formats {
+            Some(formats) => formats.subscribe(request),
+            None => Err("this server doesn't negotiate formats".into()),
+        };
+        match frames {
+            Ok(frames) => {
+                info!("switched to {:?}", frames.stream_info());
+                self.frames_dropped += self.frames.dropped();
+                self.frames = frames;
+            }
+            Err(err) => warn!("format request refused: {}", err),
+        }
+    }
+
+    // Go on with the frames after 'seq', the last one the client got before it lost an
+    // earlier connection, as far as the stream's history reaches back. Frames can't go
+    // out of order, so once audio went out on this connection, what it left out stays
+    // missing; pre-roll usually covers it. A frame that is gone, or from before a
+    // restart of the server, gets a fresh stream info: the numbering starts over.
+    async fn resume(&mut self, seq: u32) -> crate::Result<()> {
+        if !self.frames.holds(seq) {
+            info!("can't resume after frame {}; starting afresh", seq);
+            return self.announce_stream().await;
+        }
+        if let Some(first) = self.first_sent {
+            let missing = first.wrapping_sub(seq.wrapping_add(1));
+            if missing > 0 && missing < u32::MAX / 2 {
+                warn!(
+                    "can't resume after frame {}, {} frames already skipped",
+                    seq, missing
+                );
+            }
+            return Ok(());
+        }
+        self.frames_dropped += self.frames.dropped();
+        self.frames = self.frames.resume_after(seq);
+        // the new subscription has them too
+        if let Some(batch) = &mut self.batch {
+            batch.frames.clear();
+        }
+        info!("resuming after frame {}", seq);
+        Ok(())
+    }
+
+    // Announce a new prefix once the sealer's would have to seal 'seq' with a nonce it
+    // used before; what was batched under the old one goes out first.
+    async fn reseal(&mut self, seq: u32) -> crate::Result<()> {
+        let psk = match (&self.psk, &self.sealer) {
+            (Some(psk), Some(sealer)) if sealer.exhausted(seq) => psk.clone(),
+            _ => return Ok(()),
+        };
+        self.flush().await?;
+        let (prefix, sealer) = psk.sealer()?;
+        self.write(&Frame::nonce(&prefix)).await?;
+        self.sealer = Some(sealer);
+        Ok(())
+    }
+
+    fn transformed(&mut self, frame: Frame) -> crate::Result<Frame> {
+        let frame = self
+            .transforms
+            .iter_mut()
+            .try_fold(frame, |frame, transform| transform.apply(frame))?;
+        match &mut self.sealer {
+            Some(sealer) => sealer.apply(frame),
+            None => Ok(frame),
+        }
+    }
+
+    // Write an audio frame, or add it to the batch and write that once full.
+    async fn send_audio(&mut self, frame: Frame) -> crate::Result<()> {
+        if let Some(batch) = &mut self.batch {
+            if batch.frames.is_empty() {
+                batch.deadline = Instant::now() + batch.max_delay;
+            }
+            batch.frames.push(frame);
+            if batch.frames.len() < batch.len {
+                return Ok(());
+            }
+            return self.flush().await;
+        }
+        let started = Instant::now();
+        self.write(&frame).await?;
+        self.frame_sent(&frame, started.elapsed());
+        Ok(())
+    }
+
+    // Write the batched frames, if any, in one go.
+    async fn flush(&mut self) -> crate::Result<()> {
+        let mut frames = match &mut self.batch {
+            Some(batch) if !batch.frames.is_empty() => std::mem::take(&mut batch.frames),
+            _ => return Ok(()),
+        };
+        let started = Instant::now();
+        let timeout = self.write_timeout();
+        bounded(timeout, self.socket_writer.write_packets(&frames)).await?;
+        let elapsed = started.elapsed();
+        self.charge(frames.iter().map(Frame::encoded_len).sum());
+        for frame in &frames {
+            self.frame_sent(frame, elapsed);
+        }
+        // keeps the allocation
+        frames.clear();
+        if let Some(batch) = &mut self.batch {
+            batch.frames = frames;
+        }
+        Ok(())
+    }
+
+    fn frame_sent(&mut self, frame: &Frame, write_time: Duration) {
+        self.first_sent.get_or_insert(frame.seq);
+        METRICS.frame_sent(&self.ip_addr, frame.encoded_len());
+        self.client.stats().frame_sent(
+            frame.encoded_len(),
+            write_time,
+            self.frames_dropped + self.frames.dropped(),
+        );
+    }
+
+    // Write with the heartbeat timeout as deadline, if there is one; batched audio
+    // goes first, so frames stay in order.
+    async fn write(&mut self, frame: &Frame) -> crate::Result<()> {
+        self.flush().await?;
+        let timeout = self.write_timeout();
+        bounded(timeout, self.socket_writer.write_packet(frame)).await?;
+        self.charge(frame.encoded_len());
+        Ok(())
+    }
+
+    // Count written bytes against the bandwidth limit, holding back audio while over it.
+    fn charge(&mut self, n_bytes: usize) {
+        if let Some(until) = self.bucket.charge(n_bytes) {
+            self.throttled_until = Some(Instant::from_std(until));
+        }
+    }
+
+    fn write_timeout(&self) -> Option<Duration> {
+        self.heartbeat.as_ref().map(|heartbeat| heartbeat.timeout)
+    }
+
+    // Tell the client why it is being disconnected; it may be gone already.
+    async fn goodbye(&mut self, reason: &str) -> crate::Result<()> {
+        let frame = Frame::goodbye(reason);
+        let _ = time::timeout(GOODBYE_TIMEOUT, async {
+            self.flush().await?;
+            self.socket_writer.write_packet(&frame).await?;
+            self.socket_writer.close().await
+        })
+        .await;
+        Ok(())
+    }
+}
+
+// Run a write, failing it once it takes longer than 'timeout'.
+async fn bounded(
+    timeout: Option<Duration>,
+    write: impl Future<Output = crate::Result<()>>,
+) -> crate::Result<()> {
+    match timeout {
+        Some(timeout) => time::timeout(timeout, write)
+            .await
+            .map_err(|_| "client stopped reading")?,
+        None => write.await,
+    }
+}
+
+// Resolves once the batch has waited long enough; never while it is empty.
+async fn batch_due(batch: &Option<Batch>) {
+    match batch {
+        Some(batch) if !batch.frames.is_empty() => time::sleep_until(batch.deadline).await,
+        _ => std::future::pending().await,
+    }
+}
+
+// Resolves once the bandwidth limit lets audio out again; never while it does.
+async fn throttle_over(throttled_until: Option<Instant>) {
+    match throttled_until {
+        Some(until) => time::sleep_until(until).await,
+        None => std::future::pending().await,
+    }
+}
+
+// Seq of the next ping once it is due; never resolves without a heartbeat.
+async fn next_ping(heartbeat: &mut Option<Heartbeat>) -> u32 {
+    match heartbeat {
+        Some(heartbeat) => {
+            heartbeat.ping.tick().await;
+            heartbeat.next_seq = heartbeat.next_seq.wrapping_add(1);
+            heartbeat.next_seq
+        }
+        None => std::future::pending().await,
+    }
+}
+
+pub(crate) fn handle_control(payload: &[u8]) {
+    let command = String::from_utf8_lossy(payload);
+    match CONTROLS.apply(&command) {
+        Ok(()) => info!("control: {}", command),
+        Err(err) => warn!("bad control frame: {}", err),
+    }
+}
+
+// Wait for the client's auth frame; anything else, or nothing within the timeout, fails.
+pub(crate) async fn authenticate(socket_reader: &mut SocketReader, auth: &AuthConfig) -> bool {
+    let timeout = Duration::from_secs(auth.timeout);
+    match time::timeout(timeout, socket_reader.read_frame()).await {
+        Ok(Ok(Some(frame))) => {
+            frame.kind == FrameKind::Auth && constant_time_eq(&frame.payload, auth.token.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+// Compare secrets without leaking the length of the matching prefix through timing.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0_u8, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
 impl Drop for SocketHandler {
     fn drop(&mut self) {
-        println!("{} disconnected", self.ip_addr);
+        METRICS.client_disconnected(&self.ip_addr);
+        info!("disconnected");
     }
 }
 
 // Run tcp server; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+// With 'formats', clients may ask for their own format in place of 'distributor's.
 pub async fn start_server(
-    port: u16,
-    max_clients: u16,
-    notify_data_ready: Arc<Notify>,
-    data_to_send: Arc<ArcSwap<BytesMut>>,
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    formats: Option<FormatChains>,
+    clients: Arc<ClientRegistry>,
+    shutdown: impl Future,
+) {
+    serve(&cfg.tcp, distributor, formats, clients, shutdown).await;
+}
+
+// Like 'start_server', for 'listeners[index]'.
+pub async fn start_listener(
+    cfg: Arc<Config>,
+    index: usize,
+    distributor: Arc<Distributor>,
+    formats: Option<FormatChains>,
+    clients: Arc<ClientRegistry>,
     shutdown: impl Future,
 ) {
-    let mut server = TcpServer::new(port, max_clients, notify_data_ready, data_to_send)
-        .await
-        .unwrap();
+    serve(
+        &cfg.listeners[index],
+        distributor,
+        formats,
+        clients,
+        shutdown,
+    )
+    .await;
+}
+
+async fn serve(
+    tcp: &TcpConfig,
+    distributor: Arc<Distributor>,
+    formats: Option<FormatChains>,
+    clients: Arc<ClientRegistry>,
+    shutdown: impl Future,
+) {
+    let mut server = match TcpServer::new(tcp, distributor, formats, clients).await {
+        Ok(server) => server,
+        Err(err) => {
+            error!(
+                "failed to start tcp server on port {}: {}",
+                tcp.listen_port, err
+            );
+            return;
+        }
+    };
     tokio::select! {
         res = server.run() => {
             if let Err(err) = res {
-                println!("Error! Failed to accept connection. {}", err);
+                error!("failed to accept connection: {}", err);
             }
         }
         _ = shutdown => {
-            println!("cleaning up tcp server");
+            info!("cleaning up tcp server");
         }
     }
 
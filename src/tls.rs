@@ -0,0 +1,19 @@
+use crate::config_file::TlsConfig;
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+pub fn load_server_config(cfg: &TlsConfig) -> crate::Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(&cfg.cert)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(&cfg.key)?;
+    let server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(server_config)
+}
+
+pub fn load_tls_acceptor(cfg: &TlsConfig) -> crate::Result<TlsAcceptor> {
+    Ok(TlsAcceptor::from(Arc::new(load_server_config(cfg)?)))
+}
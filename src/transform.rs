@@ -0,0 +1,157 @@
+// Rewrites of the stream frames of a single connection, after its subscription and
+// before the socket; the client undoes them in reverse order.
+use crate::protocol::Frame;
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+use crate::protocol::FrameKind;
+use serde::{Deserialize, Serialize};
+
+pub trait Transform: Send {
+    fn apply(&mut self, frame: Frame) -> crate::Result<Frame>;
+}
+
+// Lossless compression of audio payloads, one frame at a time so every frame decodes on
+// its own; pays off for pcm, barely for the other codecs.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    None,
+    // needs the 'zstd' feature
+    Zstd,
+    // faster and larger than zstd; needs the 'lz4' feature
+    Lz4,
+}
+
+impl Compression {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+
+    pub(crate) fn from_u8(compression: u8) -> Option<Compression> {
+        match compression {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Zstd),
+            2 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+}
+
+// Compresses the payloads of audio frames; None for no compression.
+pub fn compressor(compression: Compression) -> crate::Result<Option<Box<dyn Transform>>> {
+    match compression {
+        Compression::None => Ok(None),
+        Compression::Zstd => zstd::compressor(),
+        Compression::Lz4 => lz4::compressor(),
+    }
+}
+
+// The other way round, for clients.
+pub fn decompressor(compression: Compression) -> crate::Result<Option<Box<dyn Transform>>> {
+    match compression {
+        Compression::None => Ok(None),
+        Compression::Zstd => zstd::decompressor(),
+        Compression::Lz4 => lz4::decompressor(),
+    }
+}
+
+// Transform of the payloads of audio frames; other frames pass as they are.
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+struct AudioPayload<F>(F);
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+impl<F> Transform for AudioPayload<F>
+where
+    F: FnMut(&[u8]) -> crate::Result<Vec<u8>> + Send,
+{
+    fn apply(&mut self, frame: Frame) -> crate::Result<Frame> {
+        if frame.kind != FrameKind::Audio {
+            return Ok(frame);
+        }
+        let payload = (self.0)(&frame.payload)?.into();
+        Ok(Frame { payload, ..frame })
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+mod zstd {
+    use super::Transform;
+
+    pub fn compressor() -> crate::Result<Option<Box<dyn Transform>>> {
+        Err("zstd needs a build with --features zstd".into())
+    }
+
+    pub fn decompressor() -> crate::Result<Option<Box<dyn Transform>>> {
+        compressor()
+    }
+}
+
+#[cfg(feature = "zstd")]
+mod zstd {
+    use super::{AudioPayload, Transform};
+    use crate::protocol::MAX_PAYLOAD_LEN;
+    use ::zstd::bulk::{Compressor, Decompressor};
+
+    // low enough to keep up with many clients, most of the gain is in the first levels
+    const LEVEL: i32 = 3;
+
+    pub fn compressor() -> crate::Result<Option<Box<dyn Transform>>> {
+        let mut compressor = Compressor::new(LEVEL)?;
+        Ok(Some(Box::new(AudioPayload(move |payload: &[u8]| {
+            Ok(compressor.compress(payload)?)
+        }))))
+    }
+
+    pub fn decompressor() -> crate::Result<Option<Box<dyn Transform>>> {
+        let mut decompressor = Decompressor::new()?;
+        Ok(Some(Box::new(AudioPayload(move |payload: &[u8]| {
+            Ok(decompressor.decompress(payload, MAX_PAYLOAD_LEN)?)
+        }))))
+    }
+}
+
+#[cfg(not(feature = "lz4"))]
+mod lz4 {
+    use super::Transform;
+
+    pub fn compressor() -> crate::Result<Option<Box<dyn Transform>>> {
+        Err("lz4 needs a build with --features lz4".into())
+    }
+
+    pub fn decompressor() -> crate::Result<Option<Box<dyn Transform>>> {
+        compressor()
+    }
+}
+
+#[cfg(feature = "lz4")]
+mod lz4 {
+    use super::{AudioPayload, Transform};
+    use crate::protocol::MAX_PAYLOAD_LEN;
+    use lz4_flex::block::{compress_prepend_size, decompress_size_prepended};
+
+    pub fn compressor() -> crate::Result<Option<Box<dyn Transform>>> {
+        Ok(Some(Box::new(AudioPayload(|payload: &[u8]| {
+            Ok(compress_prepend_size(payload))
+        }))))
+    }
+
+    // The size prefix is checked first, a bogus one would be allocated otherwise.
+    pub fn decompressor() -> crate::Result<Option<Box<dyn Transform>>> {
+        Ok(Some(Box::new(AudioPayload(|payload: &[u8]| {
+            let size = match payload {
+                [a, b, c, d, ..] => u32::from_le_bytes([*a, *b, *c, *d]) as usize,
+                _ => return Err(crate::Error::Protocol("truncated lz4 payload".into())),
+            };
+            if size > MAX_PAYLOAD_LEN {
+                return Err(crate::Error::Protocol(format!(
+                    "lz4 payload of {} bytes",
+                    size
+                )));
+            }
+            Ok(decompress_size_prepended(payload)?)
+        }))))
+    }
+}
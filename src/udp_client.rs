@@ -0,0 +1,318 @@
+use crate::config_file::NatConfig;
+use crate::fec::FecDecoder;
+use crate::nat::{Rendezvous, Role};
+use crate::protocol::{encode_frame, Frame, FrameCodec, FrameKind, StreamInfo};
+use crate::tcp_client::{AudioPacket, ReceiveStats, StreamReceiver};
+use crate::udp_server::{COOKIE_LEN, MAX_NACK_SEQS};
+use bytes::{Bytes, BytesMut};
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+use tokio::time::{self, Duration, Instant};
+use tokio_util::codec::Decoder;
+use tracing::{info, warn};
+
+// well within the server's default client timeout of 10 s
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(2);
+// between registrations sent to the addresses the rendezvous knows the server by, and
+// how long to keep trying before giving up on getting through
+const PUNCH_INTERVAL: Duration = Duration::from_millis(200);
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(30);
+// a datagram holds a single frame
+const MAX_DATAGRAM_LEN: usize = 65536;
+
+// Client for the udp server or a multicast group. Datagrams lost or reordered on the
+// way show up as gaps in the sequence number and are rebuilt from parity frames where
+// the sender adds those, asked for again where the client is set to, concealed as in
+// 'TcpClient' otherwise.
+pub struct UdpClient {
+    socket: UdpSocket,
+    // registered with and kept alive; None for multicast
+    server: Option<SocketAddr>,
+    last_keepalive: Instant,
+    fec: FecDecoder,
+    // how long a gap waits for retransmissions; None to not ask for them
+    max_wait: Option<Duration>,
+    // missing frames asked for, and when the oldest gap is given up on
+    nacked: Vec<u32>,
+    gap_deadline: Option<Instant>,
+    resent: u64,
+    receiver: StreamReceiver,
+    recv_buf: Vec<u8>,
+}
+
+impl UdpClient {
+    // Register with the udp server at 'addr'.
+    pub async fn connect(addr: impl ToSocketAddrs) -> crate::Result<UdpClient> {
+        let server = lookup_host(addr)
+            .await?
+            .next()
+            .ok_or("no address to connect to")?;
+        let local: SocketAddr = match server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => "[::]:0".parse()?,
+        };
+        let socket = UdpSocket::bind(local).await?;
+        // only the server's datagrams get through
+        socket.connect(server).await?;
+        socket.send(&hello()).await?;
+        info!("registered with {}", server);
+        Ok(UdpClient::new(socket, Some(server)))
+    }
+
+    // Register with a udp server behind NAT through the rendezvous of 'nat', sending
+    // registrations to every address it knows the server by until one of them answers.
+    pub async fn rendezvous(nat: &NatConfig) -> crate::Result<UdpClient> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let mut rendezvous = Rendezvous::new(nat, &socket, Role::Client).await?;
+        let deadline = Instant::now() + PUNCH_TIMEOUT;
+        let mut register = time::interval(Duration::from_secs(1));
+        let mut punch = time::interval(PUNCH_INTERVAL);
+        let mut candidates = Vec::new();
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        loop {
+            tokio::select! {
+                _ = time::sleep_until(deadline) => {
+                    return Err(if candidates.is_empty() {
+                        "no server registered at the rendezvous".into()
+                    } else {
+                        "no answer from the server's addresses".into()
+                    });
+                }
+                _ = register.tick() => rendezvous.register(&socket).await,
+                _ = punch.tick() => {
+                    for addr in &candidates {
+                        if let Err(err) = socket.send_to(&hello(), addr).await {
+                            warn!("failed to punch towards {}: {}", addr, err);
+                        }
+                    }
+                }
+                res = socket.recv_from(&mut buf) => {
+                    let (n_bytes, from) = match res {
+                        Ok(res) => res,
+                        Err(err) => {
+                            warn!("udp receive error: {}", err);
+                            continue;
+                        }
+                    };
+                    if let Some(addrs) = rendezvous.peers(&socket, &buf[..n_bytes], from).await {
+                        candidates = addrs;
+                    } else if candidates.contains(&from) {
+                        socket.connect(from).await?;
+                        // what came through is the cookie of a punch or of one of ours
+                        let reply = match decode(&buf[..n_bytes]) {
+                            Some(frame) if frame.kind == FrameKind::Cookie => encode_frame(&frame),
+                            _ => hello(),
+                        };
+                        socket.send(&reply).await?;
+                        info!("registered with {} through rendezvous {}", from, rendezvous.server);
+                        return Ok(UdpClient::new(socket, Some(from)));
+                    }
+                }
+            }
+        }
+    }
+
+    // Receive what the multicast sender sends to 'group', an ipv4 group:port.
+    pub async fn join(group: SocketAddr) -> crate::Result<UdpClient> {
+        let ip = match group {
+            SocketAddr::V4(group) if group.ip().is_multicast() => *group.ip(),
+            _ => return Err(format!("{} is not an ipv4 multicast group", group).into()),
+        };
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, group.port())).await?;
+        socket.join_multicast_v4(ip, Ipv4Addr::UNSPECIFIED)?;
+        info!("joined {}", group);
+        Ok(UdpClient::new(socket, None))
+    }
+
+    fn new(socket: UdpSocket, server: Option<SocketAddr>) -> UdpClient {
+        UdpClient {
+            socket,
+            server,
+            last_keepalive: Instant::now(),
+            fec: FecDecoder::default(),
+            max_wait: None,
+            nacked: Vec::new(),
+            gap_deadline: None,
+            resent: 0,
+            receiver: StreamReceiver::default(),
+            recv_buf: vec![0; MAX_DATAGRAM_LEN],
+        }
+    }
+
+    // Ask the server to resend lost frames and hold back the ones after a gap for up to
+    // 'max_wait' for them; more than the server's 'retransmit_ms' is no use. Only for
+    // clients of the udp server, multicast senders take no requests.
+    pub fn request_retransmits(&mut self, max_wait: Duration) -> crate::Result<()> {
+        if self.server.is_none() {
+            return Err("multicast senders don't retransmit".into());
+        }
+        self.max_wait = Some(max_wait);
+        self.fec.hold();
+        Ok(())
+    }
+
+    // Rate, channels and format of the stream; None until a stream info arrived.
+    pub fn stream_info(&self) -> Option<StreamInfo> {
+        self.receiver.stream_info()
+    }
+
+    // Frames received, lost, concealed, recovered and resent so far.
+    pub fn stats(&self) -> ReceiveStats {
+        ReceiveStats {
+            recovered: self.fec.recovered(),
+            resent: self.resent,
+            ..self.receiver.stats()
+        }
+    }
+
+    // Next packet of the stream; waits for the server as long as it takes.
+    pub async fn next_packet(&mut self) -> crate::Result<AudioPacket> {
+        loop {
+            if let Some(packet) = self.receiver.pop() {
+                return Ok(packet);
+            }
+            self.keep_alive().await;
+            self.chase_gaps().await?;
+            let wait = match self.gap_deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => KEEPALIVE_INTERVAL,
+            };
+            let n_bytes = match time::timeout(
+                wait.min(KEEPALIVE_INTERVAL),
+                self.socket.recv(&mut self.recv_buf),
+            )
+            .await
+            {
+                Ok(Ok(n_bytes)) => n_bytes,
+                // ICMP port unreachable while the server is down surfaces here; the
+                // keepalives register again once it is back
+                Ok(Err(err)) => {
+                    warn!("udp receive error: {}", err);
+                    continue;
+                }
+                Err(_) => continue,
+            };
+            let datagram = Bytes::copy_from_slice(&self.recv_buf[..n_bytes]);
+            let frame = match decode(&datagram) {
+                Some(frame) => frame,
+                None => continue,
+            };
+            let released = match frame.kind {
+                // multicast senders repeat it for late joiners
+                FrameKind::StreamInfo
+                    if Some(StreamInfo::parse(&frame.payload)?) == self.stream_info() =>
+                {
+                    continue
+                }
+                FrameKind::StreamInfo => {
+                    self.receiver.start(&frame)?;
+                    self.fec = FecDecoder::default();
+                    if self.max_wait.is_some() {
+                        self.fec.hold();
+                    }
+                    self.nacked.clear();
+                    self.gap_deadline = None;
+                    continue;
+                }
+                FrameKind::Audio | FrameKind::Silence => {
+                    if self.nacked.contains(&frame.seq) {
+                        self.resent += 1;
+                    }
+                    self.fec.push(frame.seq, datagram)
+                }
+                // after a restart the server sends one for our keepalives
+                FrameKind::Cookie => {
+                    if let Err(err) = self.socket.send(&datagram).await {
+                        warn!("failed to return cookie: {}", err);
+                    }
+                    continue;
+                }
+                FrameKind::Parity => match self.fec.parity(&frame) {
+                    Ok(released) => released,
+                    Err(err) => {
+                        warn!("bad parity frame: {}", err);
+                        continue;
+                    }
+                },
+                kind => {
+                    warn!("unexpected {:?} frame", kind);
+                    continue;
+                }
+            };
+            self.deliver(released)?;
+        }
+    }
+
+    fn deliver(&mut self, released: Vec<Bytes>) -> crate::Result<()> {
+        for frame in released.iter().filter_map(|datagram| decode(datagram)) {
+            self.receiver.push(&frame)?;
+        }
+        Ok(())
+    }
+
+    // Ask for the frames gone missing since the last call; once the oldest gap waited
+    // 'max_wait', give up on all of them.
+    async fn chase_gaps(&mut self) -> crate::Result<()> {
+        let max_wait = match self.max_wait {
+            Some(max_wait) => max_wait,
+            None => return Ok(()),
+        };
+        if self
+            .gap_deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            let released = self.fec.flush();
+            self.deliver(released)?;
+        }
+        let missing = self.fec.missing();
+        if missing.is_empty() {
+            self.nacked.clear();
+            self.gap_deadline = None;
+            return Ok(());
+        }
+        let new: Vec<u32> = missing
+            .iter()
+            .copied()
+            .filter(|seq| !self.nacked.contains(seq))
+            .collect();
+        self.gap_deadline.get_or_insert(Instant::now() + max_wait);
+        for seqs in new.chunks(MAX_NACK_SEQS) {
+            if let Err(err) = self.socket.send(&encode_frame(&Frame::nack(seqs))).await {
+                warn!("failed to send nack: {}", err);
+            }
+        }
+        self.nacked = missing;
+        Ok(())
+    }
+
+    // Keepalives are receive reports.
+    async fn keep_alive(&mut self) {
+        if self.server.is_some() && self.last_keepalive.elapsed() >= KEEPALIVE_INTERVAL {
+            self.last_keepalive = Instant::now();
+            let report = Frame::receive_report(&self.stats().report());
+            if let Err(err) = self.socket.send(&encode_frame(&report)).await {
+                warn!("failed to send keepalive: {}", err);
+            }
+        }
+    }
+}
+
+// What a client sends to be handed a cookie: a cookie frame as long as the answer.
+fn hello() -> Bytes {
+    encode_frame(&Frame::cookie(&[0; COOKIE_LEN]))
+}
+
+// None after logging why for datagrams that aren't a frame.
+fn decode(datagram: &[u8]) -> Option<Frame> {
+    match FrameCodec.decode(&mut BytesMut::from(datagram)) {
+        Ok(Some(frame)) => Some(frame),
+        Ok(None) => {
+            warn!("truncated datagram of {} bytes", datagram.len());
+            None
+        }
+        Err(err) => {
+            warn!("bad datagram: {}", err);
+            None
+        }
+    }
+}
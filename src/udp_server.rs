@@ -0,0 +1,500 @@
+use crate::client_stats::{ClientEntry, ClientRegistry};
+use crate::config_file::Config;
+use crate::distributor::{Distributor, DropPolicy, Subscription};
+use crate::fec::FecEncoder;
+use crate::metrics::METRICS;
+use crate::nat::{self, Rendezvous, Role};
+use crate::protocol::{
+    encode_frame, Frame, FrameCodec, FrameKind, ReceiveReport, FRAME_HEADER_LEN,
+};
+use bytes::{Bytes, BytesMut};
+use ring::hmac;
+use ring::rand::SystemRandom;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration, Instant, MissedTickBehavior};
+use tokio_util::codec::Decoder;
+use tracing::{debug, error, info, warn};
+
+// UDP never blocks on a client, so a short queue is enough to absorb scheduling hiccups.
+const UDP_QUEUE_LEN: usize = 8;
+// multicast receivers can't be greeted, so the stream info is resent after this many
+// frames, a second of pcm
+const STREAM_INFO_INTERVAL: u32 = 100;
+// frames a single nack may ask for; fits the receive buffer
+pub const MAX_NACK_SEQS: usize = 64;
+// the longest nack, or the rendezvous' answers
+const MAX_NACK_LEN: usize = FRAME_HEADER_LEN + 4 * MAX_NACK_SEQS;
+// bytes of a cookie, and seconds one is issued for; it is taken for another window
+pub const COOKIE_LEN: usize = 16;
+const COOKIE_WINDOW: u64 = 60;
+// answers to unregistered addresses are never longer than what they sent, so a spoofed
+// source can't have the server amplify traffic towards it
+pub const COOKIE_FRAME_LEN: usize = FRAME_HEADER_LEN + COOKIE_LEN;
+const MAX_RECV_LEN: usize = if MAX_NACK_LEN > nat::MAX_MESSAGE_LEN {
+    MAX_NACK_LEN
+} else {
+    nat::MAX_MESSAGE_LEN
+};
+
+// Streams every packet as one datagram holding a single protocol frame.
+// A new client sends a datagram of at least 'COOKIE_FRAME_LEN' bytes to the listen port
+// and is answered with a cookie frame; sending that cookie back registers it, which is
+// answered with the stream info. It must send a datagram within 'client_timeout'
+// seconds of the last to keep receiving.
+// A registered client missing frames may send a nack with their sequence numbers; those
+// sent less than 'retransmit_ms' ago are sent to it again. Clients sending receive
+// reports as keepalives have them listed with their stats in 'clients'.
+// With [udp.nat] the server also keeps registered with a rendezvous, and sends cookies
+// to every client address the rendezvous hands it until the client's own datagrams get
+// through and register it.
+pub struct UdpServer {
+    port: u16,
+    socket: UdpSocket,
+    client_timeout: Duration,
+    frames: Subscription,
+    fec: Option<FecEncoder>,
+    // stream frames sent within 'max_age', oldest first
+    history: VecDeque<(u32, Instant, Bytes)>,
+    max_age: Duration,
+    clients: Arc<ClientRegistry>,
+    peers: HashMap<SocketAddr, Peer>,
+    cookies: Cookies,
+    rendezvous: Option<Rendezvous>,
+}
+
+struct Peer {
+    last_seen: Instant,
+    client: ClientEntry,
+}
+
+impl UdpServer {
+    pub async fn new(
+        cfg: Arc<Config>,
+        distributor: Arc<Distributor>,
+        clients: Arc<ClientRegistry>,
+    ) -> crate::Result<UdpServer> {
+        let port = cfg.udp.listen_port;
+        let socket = UdpSocket::bind((cfg.udp.bind_address.as_str(), port))
+            .await
+            .map_err(crate::Error::bind(format!(
+                "{}:{}",
+                cfg.udp.bind_address, port
+            )))?;
+        let rendezvous = match &cfg.udp.nat {
+            Some(nat) => Some(Rendezvous::new(nat, &socket, Role::Server).await?),
+            None => None,
+        };
+
+        let server = UdpServer {
+            port,
+            socket,
+            client_timeout: Duration::from_secs(cfg.udp.client_timeout),
+            frames: distributor.subscribe(UDP_QUEUE_LEN, DropPolicy::DropNewest),
+            fec: FecEncoder::new(cfg.udp.fec_group),
+            history: VecDeque::new(),
+            max_age: Duration::from_millis(cfg.udp.retransmit_ms),
+            clients,
+            peers: HashMap::new(),
+            cookies: Cookies::new()?,
+            rendezvous,
+        };
+        Ok(server)
+    }
+
+    async fn run(&mut self) -> crate::Result<()> {
+        info!("udp listen on port: {}", self.port);
+        let mut recv_buf = [0_u8; MAX_RECV_LEN];
+        let mut register = time::interval(nat::REGISTER_INTERVAL);
+        register.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                frame = self.frames.recv() => {
+                    let frame = match frame {
+                        Some(frame) => frame,
+                        None => return Ok(()),
+                    };
+                    let datagram = encode_frame(&frame);
+                    self.send_to_peers(&datagram).await;
+                    if let Some(parity) = self.fec.as_mut().and_then(|fec| fec.push(&frame, &datagram)) {
+                        self.send_to_peers(&encode_frame(&parity)).await;
+                    }
+                    if matches!(frame.kind, FrameKind::Audio | FrameKind::Silence) {
+                        self.remember(frame.seq, datagram);
+                    }
+                }
+                res = self.socket.recv_from(&mut recv_buf) => match res {
+                    Ok((n_bytes, addr)) => {
+                        let datagram = &recv_buf[..n_bytes];
+                        if let Some(rendezvous) = &mut self.rendezvous {
+                            let server = rendezvous.server;
+                            if let Some(addrs) = rendezvous.peers(&self.socket, datagram, addr).await {
+                                self.punch(&addrs).await;
+                                continue;
+                            }
+                            if addr == server || nat::is_stun(datagram) {
+                                continue;
+                            }
+                        }
+                        match self.peers.get_mut(&addr) {
+                            Some(peer) => {
+                                peer.last_seen = Instant::now();
+                                self.handle(datagram, addr).await;
+                            }
+                            None => self.admit(datagram, addr).await,
+                        }
+                    }
+                    // ICMP port unreachable from a vanished client surfaces here
+                    Err(err) => warn!("udp receive error: {}", err),
+                },
+                _ = register.tick(), if self.rendezvous.is_some() => {
+                    if let Some(rendezvous) = &self.rendezvous {
+                        rendezvous.register(&self.socket).await;
+                    }
+                }
+            }
+        }
+    }
+
+    // Register an address that sent back its cookie, hand any other one a cookie.
+    async fn admit(&mut self, datagram: &[u8], addr: SocketAddr) {
+        let echoed = match FrameCodec.decode(&mut BytesMut::from(datagram)) {
+            Ok(Some(frame)) if frame.kind == FrameKind::Cookie => {
+                self.cookies.valid(&frame.payload, addr)
+            }
+            _ => false,
+        };
+        if echoed {
+            self.register(addr).await;
+        } else if datagram.len() >= COOKIE_FRAME_LEN {
+            self.send_cookie(addr).await;
+        } else {
+            debug!(peer = %addr, "ignoring short datagram from an unregistered address");
+        }
+    }
+
+    async fn register(&mut self, addr: SocketAddr) {
+        // the registry's, which follows reloads and the admin api
+        if self.peers.len() >= self.clients.max_clients() {
+            warn!(peer = %addr, "udp client rejected; max_clients reached");
+            return;
+        }
+        info!(peer = %addr, "udp client registered");
+        METRICS.client_connected(&addr.to_string());
+        let stream_info = encode_frame(&self.frames.stream_info_frame());
+        if let Err(err) = self.socket.send_to(&stream_info, addr).await {
+            warn!(peer = %addr, "failed to send stream info: {}", err);
+        }
+        let peer = Peer {
+            last_seen: Instant::now(),
+            client: self.clients.register(&addr.to_string()),
+        };
+        self.peers.insert(addr, peer);
+    }
+
+    // Open our NAT towards clients the rendezvous introduced; what gets through is
+    // the cookie they would have been handed anyway.
+    async fn punch(&self, addrs: &[SocketAddr]) {
+        for &addr in addrs.iter().filter(|addr| !self.peers.contains_key(addr)) {
+            debug!(peer = %addr, "punching towards udp client");
+            self.send_cookie(addr).await;
+        }
+    }
+
+    async fn send_cookie(&self, addr: SocketAddr) {
+        let cookie = self.cookies.issue(addr);
+        let frame = encode_frame(&Frame::cookie(&cookie));
+        if let Err(err) = self.socket.send_to(&frame, addr).await {
+            warn!(peer = %addr, "failed to send cookie: {}", err);
+        }
+    }
+
+    // What a registered client sends: nacks, receive reports or bare keepalives.
+    async fn handle(&mut self, datagram: &[u8], addr: SocketAddr) {
+        let peer = match self.peers.get(&addr) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let frame = match FrameCodec.decode(&mut BytesMut::from(datagram)) {
+            Ok(Some(frame)) => frame,
+            _ => return,
+        };
+        match frame.kind {
+            FrameKind::Nack => self.retransmit(&frame.payload, addr).await,
+            FrameKind::ReceiveReport => match ReceiveReport::parse(&frame.payload) {
+                Ok(report) => peer.client.stats().receive_report(report),
+                Err(err) => warn!(peer = %addr, "bad receive report: {}", err),
+            },
+            kind => warn!(peer = %addr, "unexpected {:?} frame", kind),
+        }
+    }
+
+    async fn retransmit(&self, nack: &[u8], addr: SocketAddr) {
+        let now = Instant::now();
+        let max_age = self.max_age;
+        for seq in nack.chunks_exact(4).take(MAX_NACK_SEQS) {
+            let seq = u32::from_be_bytes(seq.try_into().unwrap());
+            let datagram = self
+                .history
+                .iter()
+                .find(|(sent, at, _)| *sent == seq && now.duration_since(*at) < max_age);
+            if let Some((_, _, datagram)) = datagram {
+                match self.socket.send_to(datagram, addr).await {
+                    Ok(n_bytes) => METRICS.frame_sent(&addr.to_string(), n_bytes),
+                    Err(err) => warn!(peer = %addr, "failed to resend frame {}: {}", seq, err),
+                }
+            }
+        }
+    }
+
+    fn remember(&mut self, seq: u32, datagram: Bytes) {
+        if self.max_age.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        while let Some((_, at, _)) = self.history.front() {
+            if now.duration_since(*at) < self.max_age {
+                break;
+            }
+            self.history.pop_front();
+        }
+        self.history.push_back((seq, now, datagram));
+    }
+
+    // Say goodbye to the clients kicked through the admin api and forget them.
+    async fn drop_kicked(&mut self) {
+        let kicked: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.client.stats().is_kicked())
+            .map(|(addr, _)| *addr)
+            .collect();
+        if kicked.is_empty() {
+            return;
+        }
+        let goodbye = encode_frame(&Frame::goodbye("kicked"));
+        for addr in kicked {
+            self.peers.remove(&addr);
+            info!(peer = %addr, "udp client kicked");
+            METRICS.client_disconnected(&addr.to_string());
+            if let Err(err) = self.socket.send_to(&goodbye, addr).await {
+                warn!(peer = %addr, "failed to send goodbye: {}", err);
+            }
+        }
+    }
+
+    async fn send_to_peers(&mut self, datagram: &[u8]) {
+        self.drop_kicked().await;
+        let now = Instant::now();
+        let timeout = self.client_timeout;
+        self.peers.retain(|addr, peer| {
+            let alive = now.duration_since(peer.last_seen) < timeout;
+            if !alive {
+                info!(peer = %addr, "udp client timed out");
+                METRICS.client_disconnected(&addr.to_string());
+            }
+            alive
+        });
+
+        for (addr, peer) in &self.peers {
+            let started = Instant::now();
+            match self.socket.send_to(datagram, addr).await {
+                Ok(n_bytes) => {
+                    METRICS.frame_sent(&addr.to_string(), n_bytes);
+                    peer.client.stats().frame_sent(
+                        n_bytes,
+                        started.elapsed(),
+                        self.frames.dropped(),
+                    );
+                }
+                Err(err) => warn!(peer = %addr, "failed to send datagram: {}", err),
+            }
+        }
+    }
+}
+
+// Cookies proving an address receives what is sent to it, which is checked before
+// answering it with more than it sent; the rendezvous uses them too.
+pub(crate) struct Cookies(hmac::Key);
+
+impl Cookies {
+    pub(crate) fn new() -> crate::Result<Cookies> {
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+            .map_err(|_| "no randomness for the cookie key")?;
+        Ok(Cookies(key))
+    }
+
+    pub(crate) fn issue(&self, addr: SocketAddr) -> [u8; COOKIE_LEN] {
+        self.cookie(addr, cookie_window())
+    }
+
+    // A mac of the address and the window it is issued in under a key of this run.
+    fn cookie(&self, addr: SocketAddr, window: u64) -> [u8; COOKIE_LEN] {
+        let tag = hmac::sign(&self.0, &cookie_message(addr, window));
+        tag.as_ref()[..COOKIE_LEN].try_into().unwrap()
+    }
+
+    pub(crate) fn valid(&self, cookie: &[u8], addr: SocketAddr) -> bool {
+        let window = cookie_window();
+        cookie.len() == COOKIE_LEN
+            && [window, window.wrapping_sub(1)].into_iter().any(|window| {
+                // compared in constant time
+                let expected = self.cookie(addr, window);
+                expected
+                    .iter()
+                    .zip(cookie)
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+            })
+    }
+}
+
+fn cookie_message(addr: SocketAddr, window: u64) -> Vec<u8> {
+    let mut message = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    message.extend_from_slice(&addr.port().to_be_bytes());
+    message.extend_from_slice(&window.to_be_bytes());
+    message
+}
+
+fn cookie_window() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() / COOKIE_WINDOW
+}
+
+// Pushes the same datagrams as 'UdpServer' to a multicast group instead, so any number
+// of LAN receivers can join without registering or counting against max_clients.
+pub struct MulticastSender {
+    group: SocketAddr,
+    socket: UdpSocket,
+    frames: Subscription,
+    fec: Option<FecEncoder>,
+}
+
+impl MulticastSender {
+    pub async fn new(
+        cfg: Arc<Config>,
+        distributor: Arc<Distributor>,
+    ) -> crate::Result<MulticastSender> {
+        let group: SocketAddr = cfg.multicast.group.parse()?;
+        if !group.ip().is_multicast() {
+            return Err(format!("{} is not a multicast address", group.ip()).into());
+        }
+        let socket = match group {
+            SocketAddr::V4(_) => {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket.set_multicast_ttl_v4(cfg.multicast.ttl)?;
+                socket.set_multicast_loop_v4(cfg.multicast.loopback)?;
+                socket
+            }
+            SocketAddr::V6(_) => {
+                let socket = UdpSocket::bind("[::]:0").await?;
+                socket.set_multicast_loop_v6(cfg.multicast.loopback)?;
+                socket
+            }
+        };
+
+        Ok(MulticastSender {
+            group,
+            socket,
+            frames: distributor.subscribe(UDP_QUEUE_LEN, DropPolicy::DropNewest),
+            fec: FecEncoder::new(cfg.multicast.fec_group),
+        })
+    }
+
+    async fn run(&mut self) -> crate::Result<()> {
+        info!("multicast to {}", self.group);
+        let group = self.group.to_string();
+        METRICS.client_connected(&group);
+
+        let stream_info = encode_frame(&self.frames.stream_info_frame());
+        let mut n_frames = 0_u32;
+        while let Some(frame) = self.frames.recv().await {
+            if n_frames.is_multiple_of(STREAM_INFO_INTERVAL) {
+                if let Err(err) = self.socket.send_to(&stream_info, self.group).await {
+                    warn!("failed to send stream info: {}", err);
+                }
+            }
+            n_frames = n_frames.wrapping_add(1);
+            let datagram = encode_frame(&frame);
+            self.send(&datagram, &group).await;
+            if let Some(parity) = self
+                .fec
+                .as_mut()
+                .and_then(|fec| fec.push(&frame, &datagram))
+            {
+                self.send(&encode_frame(&parity), &group).await;
+            }
+        }
+        METRICS.client_disconnected(&group);
+        Ok(())
+    }
+
+    async fn send(&self, datagram: &[u8], group: &str) {
+        match self.socket.send_to(datagram, self.group).await {
+            Ok(n_bytes) => METRICS.frame_sent(group, n_bytes),
+            // e.g. no route to the group while the network is down; keep trying
+            Err(err) => warn!("failed to send multicast datagram: {}", err),
+        }
+    }
+}
+
+// Run multicast sender; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_multicast_sender(
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    shutdown: impl Future,
+) {
+    let mut sender = match MulticastSender::new(cfg, distributor).await {
+        Ok(sender) => sender,
+        Err(err) => {
+            error!("failed to start multicast sender: {}", err);
+            return;
+        }
+    };
+    tokio::select! {
+        res = sender.run() => {
+            if let Err(err) = res {
+                error!("multicast sender stopped: {}", err);
+            }
+        }
+        _ = shutdown => {
+            info!("stopping multicast sender");
+        }
+    }
+}
+
+// Run udp server; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_udp_server(
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    clients: Arc<ClientRegistry>,
+    shutdown: impl Future,
+) {
+    let mut server = match UdpServer::new(cfg, distributor, clients).await {
+        Ok(server) => server,
+        Err(err) => {
+            error!("failed to start udp server: {}", err);
+            return;
+        }
+    };
+    tokio::select! {
+        res = server.run() => {
+            if let Err(err) = res {
+                error!("udp server stopped: {}", err);
+            }
+        }
+        _ = shutdown => {
+            info!("cleaning up udp server");
+        }
+    }
+}
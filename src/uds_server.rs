@@ -0,0 +1,172 @@
+// The tcp protocol on a unix domain socket, for consumers on the same machine
+// (e.g. a speech recognizer) that shouldn't go through the network stack.
+use crate::client_stats::{ClientRegistry, ClientSnapshot};
+use crate::config_file::{AuthConfig, Config};
+use crate::connection_errors::CONNECTION_ERRORS;
+use crate::distributor::{frames_in, Distributor, DropPolicy};
+use crate::error::Phase;
+use crate::metrics::{Metrics, METRICS};
+use crate::socket::split_stream;
+use crate::tcp_server::{authenticate, SocketHandler};
+use std::future::Future;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::UnixListener;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{error, info, info_span, warn, Instrument};
+
+pub struct UdsServer {
+    path: PathBuf,
+    listener: UnixListener,
+    distributor: Arc<Distributor>,
+    queue_len: usize,
+    drop_policy: DropPolicy,
+    // frames of pre-roll per new client
+    preroll: usize,
+    auth: Option<Arc<AuthConfig>>,
+    clients: Arc<ClientRegistry>,
+    next_client_id: u64,
+    notify_shutdown: broadcast::Sender<()>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+    shutdown_complete_rx: mpsc::Receiver<()>,
+}
+
+impl UdsServer {
+    pub async fn new(
+        cfg: Arc<Config>,
+        distributor: Arc<Distributor>,
+        clients: Arc<ClientRegistry>,
+    ) -> crate::Result<UdsServer> {
+        let path = PathBuf::from(&cfg.uds.path);
+        // a socket file left behind by a crashed run would make bind fail; anything
+        // else at 'path' is someone's file, so it stays and the server doesn't start
+        match std::fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(&path)?,
+            Ok(_) => return Err(format!("{} exists and isn't a socket", path.display()).into()),
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            Err(_) => {}
+        }
+        let listener = UnixListener::bind(&path).map_err(crate::Error::bind(path.display()))?;
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
+
+        let server = UdsServer {
+            path,
+            listener,
+            distributor,
+            queue_len: cfg.uds.queue_len,
+            drop_policy: cfg.uds.drop_policy,
+            preroll: frames_in(
+                cfg.uds.preroll,
+                cfg.uds.sample_rate.unwrap_or(cfg.mic.sample_rate),
+            ),
+            auth: cfg.uds.auth.clone().map(Arc::new),
+            clients,
+            next_client_id: 0,
+            notify_shutdown,
+            shutdown_complete_tx,
+            shutdown_complete_rx,
+        };
+        Ok(server)
+    }
+
+    pub fn stats(&self) -> Vec<ClientSnapshot> {
+        self.clients.snapshot()
+    }
+
+    async fn run(&mut self) -> crate::Result<()> {
+        info!("listen on {}", self.path.display());
+
+        loop {
+            let permit = self.clients.acquire().await;
+            let (socket, _) = self.listener.accept().await?;
+            Metrics::inc(&METRICS.connections_accepted);
+            // unix peers are usually unnamed, so clients go by their id
+            let client = format!("uds#{}", self.next_client_id);
+            info!("connection {}", client);
+
+            let distributor = self.distributor.clone();
+            let (queue_len, drop_policy, preroll) =
+                (self.queue_len, self.drop_policy, self.preroll);
+            let shutdown_signal = self.notify_shutdown.subscribe();
+            let shutdown_complete = self.shutdown_complete_tx.clone();
+            let auth = self.auth.clone();
+            let clients = self.clients.clone();
+            let span = info_span!("uds_client", id = self.next_client_id);
+            self.next_client_id += 1;
+
+            let connection = async move {
+                let (mut socket_reader, socket_writer) = split_stream(socket);
+                if let Some(auth) = auth {
+                    if !authenticate(&mut socket_reader, &auth).await {
+                        let err = crate::Error::Protocol("failed to authenticate".into());
+                        CONNECTION_ERRORS.report(
+                            "uds",
+                            crate::Error::connection(&client, Phase::Handshake)(err),
+                        );
+                        Metrics::inc(&METRICS.auth_failures);
+                        return;
+                    }
+                }
+
+                let entry = clients.register(&client);
+                let mut handler = SocketHandler::new(
+                    client,
+                    entry,
+                    socket_reader,
+                    socket_writer,
+                    distributor.subscribe_with_preroll(queue_len, drop_policy, preroll),
+                    shutdown_signal,
+                    shutdown_complete,
+                );
+                if let Err(err) = handler.run().await {
+                    CONNECTION_ERRORS.report("uds", err);
+                }
+                drop(permit);
+            };
+            tokio::spawn(connection.instrument(span));
+        }
+    }
+}
+
+// Run unix socket server; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_uds_server(
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    clients: Arc<ClientRegistry>,
+    shutdown: impl Future,
+) {
+    let mut server = match UdsServer::new(cfg, distributor, clients).await {
+        Ok(server) => server,
+        Err(err) => {
+            error!("failed to start unix socket server: {}", err);
+            return;
+        }
+    };
+    tokio::select! {
+        res = server.run() => {
+            if let Err(err) = res {
+                error!("failed to accept unix socket connection: {}", err);
+            }
+        }
+        _ = shutdown => {
+            info!("cleaning up unix socket server");
+        }
+    }
+
+    let UdsServer {
+        path,
+        mut shutdown_complete_rx,
+        shutdown_complete_tx,
+        notify_shutdown,
+        ..
+    } = server;
+    drop(notify_shutdown);
+    drop(shutdown_complete_tx);
+    shutdown_complete_rx.recv().await;
+    if let Err(err) = std::fs::remove_file(&path) {
+        warn!("failed to remove {}: {}", path.display(), err);
+    }
+}
@@ -0,0 +1,369 @@
+// WebRTC output: browsers open http://<bind_address>:<listen_port>/, post an SDP offer
+// to /offer and get the mic as an Opus audio track. ICE is not trickled, the answer is
+// sent once gathering completed. All peers share one track, so the stream is encoded once.
+// The media of every peer goes over udp port <listen_port>, so a single port needs
+// forwarding to reach the server from outside.
+use crate::audio::opus::OpusFramer;
+use crate::config_file::Config;
+use crate::distributor::{Distributor, DropPolicy, Subscription};
+use crate::http::{read_request, write_response};
+use crate::protocol::FrameKind;
+use crate::tcp_client::AudioPacket;
+use crate::tcp_server::accept_with_backoff;
+use ::webrtc::api::interceptor_registry::register_default_interceptors;
+use ::webrtc::api::media_engine::{MediaEngine, MIME_TYPE_OPUS};
+use ::webrtc::api::setting_engine::SettingEngine;
+use ::webrtc::api::{APIBuilder, API};
+use ::webrtc::ice::udp_mux::{UDPMuxDefault, UDPMuxParams};
+use ::webrtc::ice::udp_network::UDPNetwork;
+use ::webrtc::ice_transport::ice_server::RTCIceServer;
+use ::webrtc::interceptor::registry::Registry;
+use ::webrtc::media::Sample;
+use ::webrtc::peer_connection::configuration::RTCConfiguration;
+use ::webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use ::webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use ::webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use ::webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use ::webrtc::track::track_local::TrackLocal;
+use bytes::Bytes;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Duration;
+use tracing::{error, info, info_span, warn, Instrument};
+
+// webrtc opus always runs on a 48 kHz clock
+pub const WEBRTC_SAMPLE_RATE: usize = 48000;
+const WEBRTC_QUEUE_LEN: usize = 8;
+
+pub struct WebrtcServer {
+    cfg: Arc<Config>,
+    api: Arc<API>,
+    track: Arc<TrackLocalStaticSample>,
+    limit_connections: Arc<Semaphore>,
+    next_client_id: u64,
+}
+
+impl WebrtcServer {
+    pub async fn new(cfg: Arc<Config>) -> crate::Result<WebrtcServer> {
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_default_codecs()?;
+        let registry = register_default_interceptors(Registry::new(), &mut media_engine)?;
+        let addr = format!("{}:{}", cfg.webrtc.bind_address, cfg.webrtc.listen_port);
+        let socket = UdpSocket::bind(&addr)
+            .await
+            .map_err(crate::Error::bind(&addr))?;
+        let mut setting_engine = SettingEngine::default();
+        setting_engine.set_udp_network(UDPNetwork::Muxed(UDPMuxDefault::new(UDPMuxParams::new(
+            socket,
+        ))));
+        let api = APIBuilder::new()
+            .with_media_engine(media_engine)
+            .with_interceptor_registry(registry)
+            .with_setting_engine(setting_engine)
+            .build();
+        let track = Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_OPUS.to_string(),
+                clock_rate: WEBRTC_SAMPLE_RATE as u32,
+                channels: 2,
+                ..Default::default()
+            },
+            "audio".to_string(),
+            "mic2net".to_string(),
+        ));
+        Ok(WebrtcServer {
+            limit_connections: Arc::new(Semaphore::new(cfg.webrtc.max_clients.into())),
+            cfg,
+            api: Arc::new(api),
+            track,
+            next_client_id: 0,
+        })
+    }
+
+    async fn run(&mut self, distributor: Arc<Distributor>, n_ch: usize) -> crate::Result<()> {
+        let frames = distributor.subscribe(WEBRTC_QUEUE_LEN, DropPolicy::DropNewest);
+        let mut encoder = TrackEncoder::new(self.cfg.webrtc.n_channel, n_ch, frames)?;
+        let track = self.track.clone();
+        tokio::spawn(async move {
+            if let Err(err) = encoder.run(&track).await {
+                error!("webrtc encoder stopped: {}", err);
+            }
+        });
+
+        let addr = format!(
+            "{}:{}",
+            self.cfg.webrtc.bind_address, self.cfg.webrtc.listen_port
+        );
+        let listener = tokio::net::TcpListener::bind(&addr)
+            .await
+            .map_err(crate::Error::bind(&addr))?;
+        info!("webrtc on http://{}/", addr);
+        loop {
+            let (socket, peer) = accept_with_backoff(&listener).await?;
+            let span = info_span!("webrtc_client", peer = %peer, id = self.next_client_id);
+            self.next_client_id += 1;
+            let signaling = Signaling {
+                cfg: self.cfg.clone(),
+                api: self.api.clone(),
+                track: self.track.clone(),
+                limit_connections: self.limit_connections.clone(),
+            };
+            tokio::spawn(
+                async move {
+                    if let Err(err) = signaling.serve(socket).await {
+                        error!("webrtc signaling error: {}", err);
+                    }
+                }
+                .instrument(span),
+            );
+        }
+    }
+}
+
+fn rtc_config(cfg: &Config) -> RTCConfiguration {
+    let ice_servers = if cfg.webrtc.ice_servers.is_empty() {
+        Vec::new()
+    } else {
+        vec![RTCIceServer {
+            urls: cfg.webrtc.ice_servers.clone(),
+            ..Default::default()
+        }]
+    };
+    RTCConfiguration {
+        ice_servers,
+        ..Default::default()
+    }
+}
+
+type PeerConnection = Arc<::webrtc::peer_connection::RTCPeerConnection>;
+
+// One http request: the player page, or an offer that starts a peer connection.
+struct Signaling {
+    cfg: Arc<Config>,
+    api: Arc<API>,
+    track: Arc<TrackLocalStaticSample>,
+    limit_connections: Arc<Semaphore>,
+}
+
+impl Signaling {
+    async fn serve(self, mut socket: TcpStream) -> crate::Result<()> {
+        let request = match read_request(&mut socket).await? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/") => {
+                let page = player_page(&self.cfg.webrtc.ice_servers);
+                write_response(&mut socket, "200 OK", "text/html", page.as_bytes()).await
+            }
+            ("POST", "/offer") => {
+                let permit = match self.limit_connections.clone().try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        return write_response(
+                            &mut socket,
+                            "503 Service Unavailable",
+                            "text/plain",
+                            b"too many clients\n",
+                        )
+                        .await;
+                    }
+                };
+                let offer = String::from_utf8(request.body)?;
+                let peer_connection =
+                    Arc::new(self.api.new_peer_connection(rtc_config(&self.cfg)).await?);
+                let ended = on_ended(&peer_connection);
+                match self.answer(&peer_connection, offer).await {
+                    Ok(answer) => {
+                        tokio::spawn(
+                            close_when_ended(peer_connection, ended, permit).in_current_span(),
+                        );
+                        write_response(&mut socket, "200 OK", "application/sdp", answer.as_bytes())
+                            .await
+                    }
+                    Err(err) => {
+                        warn!("bad offer: {}", err);
+                        let _ = peer_connection.close().await;
+                        write_response(&mut socket, "400 Bad Request", "text/plain", b"bad offer\n")
+                            .await
+                    }
+                }
+            }
+            _ => write_response(&mut socket, "404 Not Found", "text/plain", b"not found\n").await,
+        }
+    }
+
+    async fn answer(
+        &self,
+        peer_connection: &PeerConnection,
+        offer: String,
+    ) -> crate::Result<String> {
+        let rtp_sender = peer_connection
+            .add_track(self.track.clone() as Arc<dyn TrackLocal + Send + Sync>)
+            .await?;
+        // incoming rtcp has to be read for the interceptors to work
+        tokio::spawn(async move {
+            let mut buf = vec![0_u8; 1500];
+            while rtp_sender.read(&mut buf).await.is_ok() {}
+        });
+
+        peer_connection
+            .set_remote_description(RTCSessionDescription::offer(offer)?)
+            .await?;
+        let answer = peer_connection.create_answer(None).await?;
+        let mut gathering_complete = peer_connection.gathering_complete_promise().await;
+        peer_connection.set_local_description(answer).await?;
+        let _ = gathering_complete.recv().await;
+        let answer = peer_connection
+            .local_description()
+            .await
+            .ok_or("no local description")?;
+        Ok(answer.sdp)
+    }
+}
+
+// Receives the state that ended the connection, once ICE or DTLS gave up or it closed.
+fn on_ended(peer_connection: &PeerConnection) -> mpsc::Receiver<RTCPeerConnectionState> {
+    let (ended_tx, ended_rx) = mpsc::channel(1);
+    peer_connection.on_peer_connection_state_change(Box::new(move |state| {
+        let ended_tx = ended_tx.clone();
+        Box::pin(async move {
+            match state {
+                RTCPeerConnectionState::Connected => info!("peer connected"),
+                RTCPeerConnectionState::Disconnected
+                | RTCPeerConnectionState::Failed
+                | RTCPeerConnectionState::Closed => {
+                    let _ = ended_tx.try_send(state);
+                }
+                _ => {}
+            }
+        })
+    }));
+    ended_rx
+}
+
+// The client slot is free again once the connection is closed.
+async fn close_when_ended(
+    peer_connection: PeerConnection,
+    mut ended: mpsc::Receiver<RTCPeerConnectionState>,
+    permit: OwnedSemaphorePermit,
+) {
+    if let Some(state) = ended.recv().await {
+        info!("peer connection {}", state);
+    }
+    if let Err(err) = peer_connection.close().await {
+        warn!("failed to close peer connection: {}", err);
+    }
+    drop(permit);
+}
+
+// Encodes the first 'n_ch' channels of the stream into opus frames on the shared track.
+struct TrackEncoder {
+    framer: OpusFramer,
+    frames: Subscription,
+}
+
+impl TrackEncoder {
+    fn new(n_ch: usize, stream_n_ch: usize, frames: Subscription) -> crate::Result<TrackEncoder> {
+        let framer = OpusFramer::new(
+            WEBRTC_SAMPLE_RATE,
+            n_ch.min(stream_n_ch),
+            opus::Application::Voip,
+        )?;
+        Ok(TrackEncoder { framer, frames })
+    }
+
+    async fn run(&mut self, track: &TrackLocalStaticSample) -> crate::Result<()> {
+        let frame_duration = Duration::from_micros(
+            (self.framer.frame_len() * 1_000_000 / WEBRTC_SAMPLE_RATE) as u64,
+        );
+        while let Some(frame) = self.frames.recv().await {
+            if frame.kind != FrameKind::Audio {
+                continue;
+            }
+            let packet = match AudioPacket::parse(&frame.payload) {
+                Ok(packet) => packet,
+                Err(_) => continue,
+            };
+            self.framer.push(&packet);
+            while let Some(encoded) = self.framer.next_frame()? {
+                let sample = Sample {
+                    data: Bytes::from(encoded),
+                    duration: frame_duration,
+                    ..Default::default()
+                };
+                // fails only when no peer is bound yet, which is fine
+                let _ = track.write_sample(&sample).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn player_page(ice_servers: &[String]) -> String {
+    let urls: Vec<String> = ice_servers.iter().map(|url| format!("{:?}", url)).collect();
+    PLAYER_PAGE.replace("ICE_SERVERS", &urls.join(","))
+}
+
+const PLAYER_PAGE: &str = r#"<!doctype html>
+<html>
+<head><meta charset="utf-8"><title>mic2net</title></head>
+<body>
+<button id="play">play</button> <span id="state"></span>
+<audio id="audio" autoplay></audio>
+<script>
+document.getElementById("play").onclick = async () => {
+  const urls = [ICE_SERVERS];
+  const pc = new RTCPeerConnection({iceServers: urls.length ? [{urls}] : []});
+  pc.addTransceiver("audio", {direction: "recvonly"});
+  pc.ontrack = (e) => { document.getElementById("audio").srcObject = e.streams[0]; };
+  pc.onconnectionstatechange = () => {
+    document.getElementById("state").textContent = pc.connectionState;
+  };
+  await pc.setLocalDescription(await pc.createOffer());
+  await new Promise((resolve) => {
+    if (pc.iceGatheringState === "complete") return resolve();
+    pc.onicegatheringstatechange = () => {
+      if (pc.iceGatheringState === "complete") resolve();
+    };
+  });
+  const res = await fetch("/offer", {method: "POST", body: pc.localDescription.sdp});
+  if (!res.ok) {
+    document.getElementById("state").textContent = await res.text();
+    return;
+  }
+  await pc.setRemoteDescription({type: "answer", sdp: await res.text()});
+};
+</script>
+</body>
+</html>
+"#;
+
+// Run the webrtc server on 'distributor', which has to carry the stream at
+// 'WEBRTC_SAMPLE_RATE'; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_webrtc_server(
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    n_ch: usize,
+    shutdown: impl Future,
+) {
+    let mut server = match WebrtcServer::new(cfg).await {
+        Ok(server) => server,
+        Err(err) => {
+            error!("failed to start webrtc server: {}", err);
+            return;
+        }
+    };
+    tokio::select! {
+        res = server.run(distributor, n_ch) => {
+            if let Err(err) = res {
+                error!("webrtc server stopped: {}", err);
+            }
+        }
+        _ = shutdown => {
+            info!("cleaning up webrtc server");
+        }
+    }
+}
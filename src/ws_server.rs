@@ -0,0 +1,224 @@
+use crate::config_file::Config;
+use crate::distributor::{frames_in, Distributor, DropPolicy, Subscription};
+use crate::metrics::METRICS;
+use crate::protocol::encode_frame;
+use crate::tcp_server::accept_with_backoff;
+use futures_util::{SinkExt, StreamExt};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::time::{self, Duration};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{error, info, info_span, warn, Instrument};
+
+// for the upgrade request of a new connection
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Serves the packet stream to browsers; every protocol frame is one binary WebSocket message.
+pub struct WsServer {
+    port: u16,
+    listener: TcpListener,
+    limit_connections: Arc<Semaphore>,
+    distributor: Arc<Distributor>,
+    queue_len: usize,
+    drop_policy: DropPolicy,
+    // frames of pre-roll per new client
+    preroll: usize,
+    next_client_id: u64,
+    notify_shutdown: broadcast::Sender<()>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+    shutdown_complete_rx: mpsc::Receiver<()>,
+}
+
+impl WsServer {
+    pub async fn new(cfg: Arc<Config>, distributor: Arc<Distributor>) -> crate::Result<WsServer> {
+        let port = cfg.ws.listen_port;
+        let listener = TcpListener::bind((cfg.ws.bind_address.as_str(), port))
+            .await
+            .map_err(crate::Error::bind(format!(
+                "{}:{}",
+                cfg.ws.bind_address, port
+            )))?;
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
+
+        let server = WsServer {
+            port,
+            listener,
+            limit_connections: Arc::new(Semaphore::new(cfg.ws.max_clients.into())),
+            distributor,
+            queue_len: cfg.ws.queue_len,
+            drop_policy: cfg.ws.drop_policy,
+            preroll: frames_in(
+                cfg.ws.preroll,
+                cfg.ws.sample_rate.unwrap_or(cfg.mic.sample_rate),
+            ),
+            next_client_id: 0,
+            notify_shutdown,
+            shutdown_complete_tx,
+            shutdown_complete_rx,
+        };
+        Ok(server)
+    }
+
+    async fn run(&mut self) -> crate::Result<()> {
+        info!("websocket listen on port: {}", self.port);
+
+        loop {
+            let permit = self
+                .limit_connections
+                .clone()
+                .acquire_owned()
+                .await
+                .unwrap();
+            let (socket, peer) = accept_with_backoff(&self.listener).await?;
+            // the peer may be gone already; that ends just this connection
+            if let Err(err) = socket.set_nodelay(true) {
+                warn!(peer = %peer, "dropping connection: {}", err);
+                continue;
+            }
+            let ip_addr = peer.to_string();
+
+            let frames = self.distributor.subscribe_with_preroll(
+                self.queue_len,
+                self.drop_policy,
+                self.preroll,
+            );
+            let mut shutdown_signal = self.notify_shutdown.subscribe();
+            let shutdown_complete = self.shutdown_complete_tx.clone();
+            let span = info_span!("ws_client", peer = %ip_addr, id = self.next_client_id);
+            self.next_client_id += 1;
+
+            let connection = async move {
+                // handshake in the task so a stalled browser can't block the accept loop
+                let handshake =
+                    time::timeout(HANDSHAKE_TIMEOUT, tokio_tungstenite::accept_async(socket));
+                let ws = tokio::select! {
+                    res = handshake => match res {
+                        Ok(Ok(ws)) => ws,
+                        Ok(Err(err)) => {
+                            warn!("websocket handshake failed: {}", err);
+                            return;
+                        }
+                        Err(_) => {
+                            warn!("websocket handshake timed out");
+                            return;
+                        }
+                    },
+                    _ = shutdown_signal.recv() => return,
+                };
+                let mut handler =
+                    WsHandler::new(ip_addr, ws, frames, shutdown_signal, shutdown_complete);
+                if let Err(err) = handler.run().await {
+                    error!("websocket connection error: {}", err);
+                }
+                drop(permit);
+            };
+            tokio::spawn(connection.instrument(span));
+        }
+    }
+}
+
+pub struct WsHandler {
+    ip_addr: String,
+    ws: WebSocketStream<TcpStream>,
+    frames: Subscription,
+    shutdown_signal: broadcast::Receiver<()>,
+    _shutdown_complete: mpsc::Sender<()>,
+}
+
+impl WsHandler {
+    // Counts as a connected client from here until dropped.
+    pub(crate) fn new(
+        ip_addr: String,
+        ws: WebSocketStream<TcpStream>,
+        frames: Subscription,
+        shutdown_signal: broadcast::Receiver<()>,
+        shutdown_complete: mpsc::Sender<()>,
+    ) -> WsHandler {
+        METRICS.client_connected(&ip_addr);
+        WsHandler {
+            ip_addr,
+            ws,
+            frames,
+            shutdown_signal,
+            _shutdown_complete: shutdown_complete,
+        }
+    }
+
+    pub(crate) async fn run(&mut self) -> crate::Result<()> {
+        let stream_info = self.frames.stream_info_frame();
+        self.ws
+            .send(Message::Binary(encode_frame(&stream_info)))
+            .await?;
+        loop {
+            tokio::select! {
+                frame = self.frames.recv() => match frame {
+                    Some(frame) => {
+                        self.ws.send(Message::Binary(encode_frame(&frame))).await?;
+                        METRICS.frame_sent(&self.ip_addr, frame.encoded_len());
+                    }
+                    None => {
+                        warn!("fell behind the stream");
+                        self.ws.close(None).await?;
+                        return Ok(());
+                    }
+                },
+                msg = self.ws.next() => match msg {
+                    // pings are answered by tungstenite on the next send
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => return Err(err.into()),
+                },
+                _ = self.shutdown_signal.recv() => {
+                    self.ws.close(None).await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+impl Drop for WsHandler {
+    fn drop(&mut self) {
+        METRICS.client_disconnected(&self.ip_addr);
+        info!("disconnected");
+    }
+}
+
+// Run websocket server; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_ws_server(
+    cfg: Arc<Config>,
+    distributor: Arc<Distributor>,
+    shutdown: impl Future,
+) {
+    let mut server = match WsServer::new(cfg, distributor).await {
+        Ok(server) => server,
+        Err(err) => {
+            error!("failed to start websocket server: {}", err);
+            return;
+        }
+    };
+    tokio::select! {
+        res = server.run() => {
+            if let Err(err) = res {
+                error!("failed to accept websocket connection: {}", err);
+            }
+        }
+        _ = shutdown => {
+            info!("cleaning up websocket server");
+        }
+    }
+
+    let WsServer {
+        mut shutdown_complete_rx,
+        shutdown_complete_tx,
+        notify_shutdown,
+        ..
+    } = server;
+    drop(notify_shutdown);
+    drop(shutdown_complete_tx);
+    shutdown_complete_rx.recv().await;
+}
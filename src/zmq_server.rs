@@ -0,0 +1,733 @@
+// A zeromq PUB socket, speaking zmtp 3.0 with the NULL mechanism, so the SUB sockets of
+// existing zeromq pipelines take the stream without a client of their own. Every message
+// is two frames: the topic of a stream and one frame of the tcp protocol. A subscriber
+// gets the stream info frame of each stream its subscriptions match before the audio.
+use crate::config_file::Config;
+use crate::connection_errors::CONNECTION_ERRORS;
+use crate::distributor::{Distributor, DropPolicy, Subscription};
+use crate::error::Phase;
+use crate::metrics::METRICS;
+use crate::protocol::{encode_frame, Frame, MAX_PAYLOAD_LEN};
+use crate::tcp_server::accept_with_backoff;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::future::select_all;
+use futures_util::{SinkExt, StreamExt};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::time::{self, Duration};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tracing::{error, info, info_span, warn, Instrument};
+
+const GREETING_LEN: usize = 64;
+// greeting and READY of a peer that connected
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// frame flags
+const MORE: u8 = 0x01;
+const LONG: u8 = 0x02;
+const COMMAND: u8 = 0x04;
+// distinct topic prefixes one peer may hold; a peer asking for more is dropped
+const MAX_SUBSCRIPTIONS: usize = 64;
+
+// A stream of the socket and the topic its messages go out under.
+pub struct ZmqStream {
+    pub topic: String,
+    pub distributor: Arc<Distributor>,
+}
+
+pub struct ZmqServer {
+    port: u16,
+    listener: TcpListener,
+    limit_connections: Arc<Semaphore>,
+    streams: Arc<Vec<ZmqStream>>,
+    queue_len: usize,
+    drop_policy: DropPolicy,
+    next_client_id: u64,
+    notify_shutdown: broadcast::Sender<()>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+    shutdown_complete_rx: mpsc::Receiver<()>,
+}
+
+impl ZmqServer {
+    pub async fn new(cfg: Arc<Config>, streams: Vec<ZmqStream>) -> crate::Result<ZmqServer> {
+        let port = cfg.zmq.listen_port;
+        let listener = TcpListener::bind((cfg.zmq.bind_address.as_str(), port))
+            .await
+            .map_err(crate::Error::bind(format!(
+                "{}:{}",
+                cfg.zmq.bind_address, port
+            )))?;
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
+
+        let server = ZmqServer {
+            port,
+            listener,
+            limit_connections: Arc::new(Semaphore::new(cfg.zmq.max_clients.into())),
+            streams: Arc::new(streams),
+            queue_len: cfg.zmq.queue_len,
+            drop_policy: cfg.zmq.drop_policy,
+            next_client_id: 0,
+            notify_shutdown,
+            shutdown_complete_tx,
+            shutdown_complete_rx,
+        };
+        Ok(server)
+    }
+
+    async fn run(&mut self) -> crate::Result<()> {
+        info!("zeromq publish on port: {}", self.port);
+
+        loop {
+            let permit = self
+                .limit_connections
+                .clone()
+                .acquire_owned()
+                .await
+                .unwrap();
+            let (socket, peer) = accept_with_backoff(&self.listener).await?;
+            // the peer may be gone already; that ends just this connection
+            if let Err(err) = socket.set_nodelay(true) {
+                warn!(peer = %peer, "dropping connection: {}", err);
+                continue;
+            }
+            let ip_addr = peer.to_string();
+
+            let streams = self.streams.clone();
+            let (queue_len, drop_policy) = (self.queue_len, self.drop_policy);
+            let shutdown_signal = self.notify_shutdown.subscribe();
+            let shutdown_complete = self.shutdown_complete_tx.clone();
+            let span = info_span!("zmq_client", peer = %ip_addr, id = self.next_client_id);
+            self.next_client_id += 1;
+
+            let connection = async move {
+                // in the task so a stalled peer can't block the accept loop
+                let handshake = time::timeout(HANDSHAKE_TIMEOUT, handshake(socket)).await;
+                let socket = match handshake {
+                    Ok(Ok(socket)) => socket,
+                    Ok(Err(err)) => {
+                        let err = crate::Error::connection(&ip_addr, Phase::Handshake)(err);
+                        CONNECTION_ERRORS.report("zmq", err);
+                        return;
+                    }
+                    Err(_) => {
+                        let err = crate::Error::Protocol("handshake timed out".into());
+                        let err = crate::Error::connection(&ip_addr, Phase::Handshake)(err);
+                        CONNECTION_ERRORS.report("zmq", err);
+                        return;
+                    }
+                };
+                METRICS.client_connected(&ip_addr);
+                let mut handler = ZmqHandler {
+                    ip_addr,
+                    socket,
+                    streams: streams
+                        .iter()
+                        .map(|stream| Topic {
+                            topic: Bytes::copy_from_slice(stream.topic.as_bytes()),
+                            distributor: stream.distributor.clone(),
+                            frames: None,
+                        })
+                        .collect(),
+                    subscriptions: Subscriptions::default(),
+                    queue_len,
+                    drop_policy,
+                    shutdown_signal,
+                    _shutdown_complete: shutdown_complete,
+                };
+                if let Err(err) = handler.run().await {
+                    CONNECTION_ERRORS.report("zmq", err);
+                }
+                drop(permit);
+            };
+            tokio::spawn(connection.instrument(span));
+        }
+    }
+}
+
+// Exchange greetings and READY commands with a SUB or XSUB peer.
+async fn handshake<S>(mut socket: S) -> crate::Result<Framed<S, ZmtpCodec>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut greeting = [0; GREETING_LEN];
+    greeting[0] = 0xff;
+    greeting[9] = 0x7f;
+    // version 3.0, so peers send subscriptions as messages
+    greeting[10] = 3;
+    greeting[12..16].copy_from_slice(b"NULL");
+    socket.write_all(&greeting).await?;
+
+    let mut peer = [0; GREETING_LEN];
+    socket.read_exact(&mut peer).await?;
+    if peer[0] != 0xff || peer[9] & 1 == 0 {
+        return Err(crate::Error::Protocol("not a zeromq peer".into()));
+    }
+    if peer[10] < 3 {
+        return Err(crate::Error::Protocol(format!(
+            "unsupported zmtp version {}.{}",
+            peer[10], peer[11]
+        )));
+    }
+    let mechanism = &peer[12..32];
+    if mechanism.split(|&b| b == 0).next() != Some(b"NULL".as_slice()) {
+        return Err(crate::Error::Protocol(
+            "only the NULL security mechanism is supported".into(),
+        ));
+    }
+
+    let mut socket = Framed::new(socket, ZmtpCodec);
+    socket
+        .send(ZmtpFrame::command(ready_command("PUB")))
+        .await?;
+    let frame = socket
+        .next()
+        .await
+        .ok_or_else(|| crate::Error::Protocol("closed during the handshake".into()))??;
+    let (name, body) = frame.command_parts()?;
+    if name != b"READY" {
+        return Err(crate::Error::Protocol("expected a READY command".into()));
+    }
+    match socket_type(body)? {
+        Some(b"SUB") | Some(b"XSUB") => Ok(socket),
+        _ => Err(crate::Error::Protocol(
+            "only SUB and XSUB sockets can connect".into(),
+        )),
+    }
+}
+
+fn ready_command(socket_type: &str) -> Bytes {
+    let mut body = BytesMut::new();
+    body.put_u8(5);
+    body.put_slice(b"READY");
+    body.put_u8(11);
+    body.put_slice(b"Socket-Type");
+    body.put_u32(socket_type.len() as u32);
+    body.put_slice(socket_type.as_bytes());
+    body.freeze()
+}
+
+// The Socket-Type property among the metadata of a READY command.
+fn socket_type(mut metadata: &[u8]) -> crate::Result<Option<&[u8]>> {
+    let bad = || crate::Error::Protocol("bad READY metadata".into());
+    while !metadata.is_empty() {
+        let name_len = metadata.get_u8() as usize;
+        if metadata.len() < name_len + 4 {
+            return Err(bad());
+        }
+        let (name, rest) = metadata.split_at(name_len);
+        metadata = rest;
+        let value_len = metadata.get_u32() as usize;
+        if metadata.len() < value_len {
+            return Err(bad());
+        }
+        let (value, rest) = metadata.split_at(value_len);
+        metadata = rest;
+        if name.eq_ignore_ascii_case(b"Socket-Type") {
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
+struct ZmtpFrame {
+    flags: u8,
+    body: Bytes,
+}
+
+impl ZmtpFrame {
+    fn message(body: Bytes, more: bool) -> ZmtpFrame {
+        let flags = if more { MORE } else { 0 };
+        ZmtpFrame { flags, body }
+    }
+
+    fn command(body: Bytes) -> ZmtpFrame {
+        ZmtpFrame {
+            flags: COMMAND,
+            body,
+        }
+    }
+
+    fn is_command(&self) -> bool {
+        self.flags & COMMAND != 0
+    }
+
+    // Name and data of a command frame.
+    fn command_parts(&self) -> crate::Result<(&[u8], &[u8])> {
+        let bad = || crate::Error::Protocol("bad zmtp command".into());
+        if !self.is_command() {
+            return Err(bad());
+        }
+        let (&name_len, rest) = self.body.split_first().ok_or_else(bad)?;
+        if rest.len() < name_len as usize {
+            return Err(bad());
+        }
+        Ok(rest.split_at(name_len as usize))
+    }
+}
+
+// Encoder/decoder of zmtp 3 frames.
+struct ZmtpCodec;
+
+impl Encoder<ZmtpFrame> for ZmtpCodec {
+    type Error = crate::Error;
+
+    fn encode(&mut self, frame: ZmtpFrame, dst: &mut BytesMut) -> crate::Result<()> {
+        let len = frame.body.len();
+        if len > u8::MAX as usize {
+            dst.reserve(9 + len);
+            dst.put_u8(frame.flags | LONG);
+            dst.put_u64(len as u64);
+        } else {
+            dst.reserve(2 + len);
+            dst.put_u8(frame.flags);
+            dst.put_u8(len as u8);
+        }
+        dst.put_slice(&frame.body);
+        Ok(())
+    }
+}
+
+impl Decoder for ZmtpCodec {
+    type Item = ZmtpFrame;
+    type Error = crate::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> crate::Result<Option<ZmtpFrame>> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+        let flags = src[0];
+        let (header_len, len) = if flags & LONG != 0 {
+            if src.len() < 9 {
+                return Ok(None);
+            }
+            (9, u64::from_be_bytes(src[1..9].try_into().unwrap()))
+        } else {
+            (2, src[1] as u64)
+        };
+        // subscriptions and commands are small; nothing a peer sends comes near this
+        if len > MAX_PAYLOAD_LEN as u64 {
+            return Err(crate::Error::Protocol("zmtp frame too long".into()));
+        }
+        let len = len as usize;
+        if src.len() < header_len + len {
+            src.reserve(header_len + len - src.len());
+            return Ok(None);
+        }
+        src.advance(header_len);
+        let body = src.split_to(len).freeze();
+        Ok(Some(ZmtpFrame { flags, body }))
+    }
+}
+
+// A stream as one peer sees it; subscribed to while a subscription of the peer matches.
+struct Topic {
+    topic: Bytes,
+    distributor: Arc<Distributor>,
+    frames: Option<Subscription>,
+}
+
+pub struct ZmqHandler {
+    ip_addr: String,
+    socket: Framed<TcpStream, ZmtpCodec>,
+    streams: Vec<Topic>,
+    subscriptions: Subscriptions,
+    queue_len: usize,
+    drop_policy: DropPolicy,
+    shutdown_signal: broadcast::Receiver<()>,
+    _shutdown_complete: mpsc::Sender<()>,
+}
+
+impl ZmqHandler {
+    async fn run(&mut self) -> crate::Result<()> {
+        loop {
+            tokio::select! {
+                next = next_frame(&mut self.streams) => match next {
+                    (index, Some(frame)) => {
+                        let topic = self.streams[index].topic.clone();
+                        self.publish(topic, &frame).await?;
+                    }
+                    (_, None) => {
+                        warn!("fell behind the stream");
+                        return Ok(());
+                    }
+                },
+                frame = self.socket.next() => match frame {
+                    Some(Ok(frame)) => self
+                        .received(frame)
+                        .await
+                        .map_err(crate::Error::connection(&self.ip_addr, Phase::Read))?,
+                    Some(Err(err)) => {
+                        return Err(crate::Error::connection(&self.ip_addr, Phase::Read)(err))
+                    }
+                    None => return Ok(()),
+                },
+                _ = self.shutdown_signal.recv() => return Ok(()),
+            }
+        }
+    }
+
+    async fn publish(&mut self, topic: Bytes, frame: &Frame) -> crate::Result<()> {
+        let write = async {
+            self.socket.feed(ZmtpFrame::message(topic, true)).await?;
+            let body = encode_frame(frame);
+            self.socket.send(ZmtpFrame::message(body, false)).await
+        };
+        write
+            .await
+            .map_err(crate::Error::connection(&self.ip_addr, Phase::Write))?;
+        METRICS.frame_sent(&self.ip_addr, frame.encoded_len());
+        Ok(())
+    }
+
+    // Subscriptions come as messages starting with 1 (subscribe) or 0 (cancel) from
+    // zmtp 3.0 peers, as SUBSCRIBE and CANCEL commands from zmtp 3.1 ones.
+    async fn received(&mut self, frame: ZmtpFrame) -> crate::Result<()> {
+        if frame.is_command() {
+            let (name, data) = frame.command_parts()?;
+            if name == b"PING" {
+                return self.pong(data).await;
+            }
+        }
+        let Some((subscribe, prefix)) = subscription(&frame)? else {
+            return Ok(());
+        };
+        if self.subscriptions.update(subscribe, prefix)? {
+            self.follow_subscriptions().await?;
+        }
+        Ok(())
+    }
+
+    // Answer a heartbeat with the context it carries after the ttl.
+    async fn pong(&mut self, ping: &[u8]) -> crate::Result<()> {
+        let context = ping.get(2..).unwrap_or_default();
+        let mut body = BytesMut::with_capacity(5 + context.len());
+        body.put_u8(4);
+        body.put_slice(b"PONG");
+        body.put_slice(context);
+        self.socket.send(ZmtpFrame::command(body.freeze())).await
+    }
+
+    // Subscribe to the streams the peer now wants, starting each with its stream info
+    // frame, and let go of the others.
+    async fn follow_subscriptions(&mut self) -> crate::Result<()> {
+        for index in 0..self.streams.len() {
+            let stream = &self.streams[index];
+            let wanted = self.subscriptions.matches(&stream.topic);
+            match (wanted, stream.frames.is_some()) {
+                (true, false) => {
+                    let frames = stream
+                        .distributor
+                        .subscribe(self.queue_len, self.drop_policy);
+                    let (topic, info) = (stream.topic.clone(), frames.stream_info_frame());
+                    self.streams[index].frames = Some(frames);
+                    info!("subscribed to {}", String::from_utf8_lossy(&topic));
+                    self.publish(topic, &info).await?;
+                }
+                (false, true) => self.streams[index].frames = None,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ZmqHandler {
+    fn drop(&mut self) {
+        METRICS.client_disconnected(&self.ip_addr);
+        info!("disconnected");
+    }
+}
+
+// A subscribe (true) or cancel (false) and its topic prefix, if the frame is one.
+fn subscription(frame: &ZmtpFrame) -> crate::Result<Option<(bool, Bytes)>> {
+    if frame.is_command() {
+        let (name, data) = frame.command_parts()?;
+        return Ok(match name {
+            b"SUBSCRIBE" => Some((true, frame.body.slice_ref(data))),
+            b"CANCEL" => Some((false, frame.body.slice_ref(data))),
+            _ => None,
+        });
+    }
+    Ok(match frame.body.first() {
+        Some(1) => Some((true, frame.body.slice(1..))),
+        Some(0) => Some((false, frame.body.slice(1..))),
+        // anything else an XSUB peer sends is dropped, as zeromq does
+        _ => None,
+    })
+}
+
+// The topic prefixes of a peer, each held once however often it is subscribed.
+#[derive(Default)]
+struct Subscriptions(Vec<Bytes>);
+
+impl Subscriptions {
+    // Add or cancel a prefix; true if the set changed.
+    fn update(&mut self, subscribe: bool, prefix: Bytes) -> crate::Result<bool> {
+        let pos = self.0.iter().position(|sub| *sub == prefix);
+        match (subscribe, pos) {
+            (true, None) if self.0.len() >= MAX_SUBSCRIPTIONS => Err(crate::Error::Protocol(
+                format!("more than {} subscriptions", MAX_SUBSCRIPTIONS),
+            )),
+            (true, None) => {
+                self.0.push(prefix);
+                Ok(true)
+            }
+            (false, Some(pos)) => {
+                self.0.remove(pos);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn matches(&self, topic: &[u8]) -> bool {
+        self.0.iter().any(|prefix| topic.starts_with(prefix))
+    }
+}
+
+// The next frame of any subscribed stream, with the index of the stream; never ready
+// while there is none.
+async fn next_frame(streams: &mut [Topic]) -> (usize, Option<Frame>) {
+    let receiving: Vec<_> = streams
+        .iter_mut()
+        .enumerate()
+        .filter_map(|(index, stream)| {
+            let frames = stream.frames.as_mut()?;
+            Some(Box::pin(async move { (index, frames.recv().await) }))
+        })
+        .collect();
+    if receiving.is_empty() {
+        return std::future::pending().await;
+    }
+    select_all(receiving).await.0
+}
+
+// Run zeromq publisher; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_zmq_server(cfg: Arc<Config>, streams: Vec<ZmqStream>, shutdown: impl Future) {
+    let mut server = match ZmqServer::new(cfg, streams).await {
+        Ok(server) => server,
+        Err(err) => {
+            error!("failed to start zeromq publisher: {}", err);
+            return;
+        }
+    };
+    tokio::select! {
+        res = server.run() => {
+            if let Err(err) = res {
+                error!("failed to accept zeromq connection: {}", err);
+            }
+        }
+        _ = shutdown => {
+            info!("cleaning up zeromq publisher");
+        }
+    }
+
+    let ZmqServer {
+        mut shutdown_complete_rx,
+        shutdown_complete_tx,
+        notify_shutdown,
+        ..
+    } = server;
+    drop(notify_shutdown);
+    drop(shutdown_complete_tx);
+    shutdown_complete_rx.recv().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    // The greeting libzmq sends: signature, version, mechanism, as-server and filler.
+    fn libzmq_greeting(major: u8, minor: u8, mechanism: &[u8]) -> [u8; GREETING_LEN] {
+        let mut greeting = [0; GREETING_LEN];
+        greeting[0] = 0xff;
+        greeting[9] = 0x7f;
+        greeting[10] = major;
+        greeting[11] = minor;
+        greeting[12..12 + mechanism.len()].copy_from_slice(mechanism);
+        greeting
+    }
+
+    // A READY command frame as libzmq writes it, with a property after Socket-Type.
+    fn libzmq_ready(socket_type: &[u8]) -> Vec<u8> {
+        let mut body = b"\x05READY\x0bSocket-Type".to_vec();
+        body.extend_from_slice(&(socket_type.len() as u32).to_be_bytes());
+        body.extend_from_slice(socket_type);
+        body.extend_from_slice(b"\x08Identity\0\0\0\0");
+        let mut frame = vec![COMMAND, body.len() as u8];
+        frame.extend_from_slice(&body);
+        frame
+    }
+
+    fn decode_all(mut bytes: BytesMut) -> Vec<ZmtpFrame> {
+        let mut frames = Vec::new();
+        while let Some(frame) = ZmtpCodec.decode(&mut bytes).unwrap() {
+            frames.push(frame);
+        }
+        assert!(bytes.is_empty());
+        frames
+    }
+
+    async fn handshake_with(peer_bytes: Vec<u8>) -> (crate::Result<()>, Vec<u8>) {
+        let (server, mut client) = duplex(4096);
+        client.write_all(&peer_bytes).await.unwrap();
+        let result = handshake(server).await.map(|_| ());
+        let mut sent = Vec::new();
+        client.read_to_end(&mut sent).await.unwrap();
+        (result, sent)
+    }
+
+    #[test]
+    fn short_frames_round_trip() {
+        let mut bytes = BytesMut::new();
+        let frame = ZmtpFrame::message(Bytes::from_static(b"mic"), true);
+        ZmtpCodec.encode(frame, &mut bytes).unwrap();
+        ZmtpCodec
+            .encode(ZmtpFrame::message(Bytes::new(), false), &mut bytes)
+            .unwrap();
+        assert_eq!(&bytes[..], b"\x01\x03mic\x00\x00");
+
+        let frames = decode_all(bytes);
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].flags, &frames[0].body[..]), (MORE, &b"mic"[..]));
+        assert_eq!((frames[1].flags, frames[1].body.len()), (0, 0));
+    }
+
+    #[test]
+    fn long_frames_round_trip() {
+        let body = Bytes::from(vec![7; 256]);
+        let mut bytes = BytesMut::new();
+        ZmtpCodec
+            .encode(ZmtpFrame::message(body.clone(), false), &mut bytes)
+            .unwrap();
+        // flags, then the size as 8 bytes in network order
+        assert_eq!(&bytes[..9], b"\x02\0\0\0\0\0\0\x01\x00");
+        assert_eq!(bytes.len(), 9 + 256);
+
+        let frames = decode_all(bytes);
+        assert_eq!(frames[0].flags, LONG);
+        assert_eq!(frames[0].body, body);
+    }
+
+    #[test]
+    fn partial_frames_wait_for_the_rest() {
+        let mut bytes = BytesMut::from(&b"\x02\0\0\0\0"[..]);
+        assert!(ZmtpCodec.decode(&mut bytes).unwrap().is_none());
+        let mut bytes = BytesMut::from(&b"\x00\x05sub"[..]);
+        assert!(ZmtpCodec.decode(&mut bytes).unwrap().is_none());
+        bytes.put_slice(b"sc");
+        let frame = ZmtpCodec.decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(&frame.body[..], b"subsc");
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        let mut bytes = BytesMut::from(&b"\x02"[..]);
+        bytes.put_u64(MAX_PAYLOAD_LEN as u64 + 1);
+        assert!(ZmtpCodec.decode(&mut bytes).is_err());
+    }
+
+    #[tokio::test]
+    async fn handshake_with_a_libzmq_sub() {
+        for minor in [0, 1] {
+            let mut peer = libzmq_greeting(3, minor, b"NULL").to_vec();
+            peer.extend(libzmq_ready(b"SUB"));
+            let (result, sent) = handshake_with(peer).await;
+            result.unwrap();
+
+            assert_eq!(sent[..GREETING_LEN], libzmq_greeting(3, 0, b"NULL"));
+            let frames = decode_all(BytesMut::from(&sent[GREETING_LEN..]));
+            assert_eq!(frames.len(), 1);
+            let (name, metadata) = frames[0].command_parts().unwrap();
+            assert_eq!(name, b"READY");
+            assert_eq!(socket_type(metadata).unwrap(), Some(&b"PUB"[..]));
+            assert_eq!(
+                &frames[0].body[..],
+                b"\x05READY\x0bSocket-Type\0\0\0\x03PUB"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_refuses_other_peers() {
+        let mut xsub = libzmq_greeting(3, 1, b"NULL").to_vec();
+        xsub.extend(libzmq_ready(b"XSUB"));
+        assert!(handshake_with(xsub).await.0.is_ok());
+
+        let mut publisher = libzmq_greeting(3, 0, b"NULL").to_vec();
+        publisher.extend(libzmq_ready(b"PUB"));
+        assert!(handshake_with(publisher).await.0.is_err());
+
+        let zmtp2 = libzmq_greeting(2, 0, b"NULL").to_vec();
+        assert!(handshake_with(zmtp2).await.0.is_err());
+
+        let curve = libzmq_greeting(3, 0, b"CURVE").to_vec();
+        assert!(handshake_with(curve).await.0.is_err());
+
+        let mut http = b"GET / HTTP/1.1\r\n".to_vec();
+        http.resize(GREETING_LEN, 0);
+        assert!(handshake_with(http).await.0.is_err());
+    }
+
+    #[test]
+    fn bad_ready_metadata_is_refused() {
+        assert!(socket_type(b"\x0bSocket-Type\0\0\0\x09SUB").is_err());
+        assert!(socket_type(b"\x20Socket").is_err());
+        assert_eq!(socket_type(b"").unwrap(), None);
+    }
+
+    #[test]
+    fn subscriptions_of_zmtp_3_0_and_3_1_peers() {
+        // a 3.0 SUB subscribes with a message, a 3.1 one with a command
+        let bytes = BytesMut::from(&b"\x00\x04\x01mic\x04\x0d\x09SUBSCRIBEmic\x00\x04\x00mic\x04\x0a\x06CANCELmic\x00\x02\x05x"[..]);
+        let frames = decode_all(bytes);
+        let parsed: Vec<_> = frames
+            .iter()
+            .map(|frame| subscription(frame).unwrap())
+            .collect();
+        let mic = Bytes::from_static(b"mic");
+        assert_eq!(
+            parsed,
+            vec![
+                Some((true, mic.clone())),
+                Some((true, mic.clone())),
+                Some((false, mic.clone())),
+                Some((false, mic)),
+                None,
+            ]
+        );
+        let ping = decode_all(BytesMut::from(&b"\x04\x07\x04PING\0\x0a"[..]));
+        assert_eq!(subscription(&ping[0]).unwrap(), None);
+    }
+
+    #[test]
+    fn repeated_subscriptions_are_held_once() {
+        let mut subscriptions = Subscriptions::default();
+        let mic = Bytes::from_static(b"mic");
+        assert!(subscriptions.update(true, mic.clone()).unwrap());
+        assert!(!subscriptions.update(true, mic.clone()).unwrap());
+        assert_eq!(subscriptions.0.len(), 1);
+        assert!(subscriptions.matches(b"mic-left"));
+        assert!(!subscriptions.matches(b"line"));
+
+        assert!(subscriptions.update(false, mic.clone()).unwrap());
+        assert!(!subscriptions.matches(b"mic-left"));
+        assert!(!subscriptions.update(false, mic).unwrap());
+    }
+
+    #[test]
+    fn too_many_subscriptions_are_refused() {
+        let mut subscriptions = Subscriptions::default();
+        for n in 0..MAX_SUBSCRIPTIONS {
+            let prefix = Bytes::from(n.to_string());
+            assert!(subscriptions.update(true, prefix).unwrap());
+        }
+        assert!(subscriptions.update(true, Bytes::from("one more")).is_err());
+        // a prefix already held is still fine
+        assert!(!subscriptions.update(true, Bytes::from("0")).unwrap());
+    }
+}